@@ -0,0 +1,81 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Embeds the identity of the build into the kernel, see `meta::version`.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    // Rebuild on source changes and new commits, but not on every build.
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git_hash = match run("git", &["-C", &manifest_dir, "rev-parse", "--short=12", "HEAD"]) {
+        Some(hash) => {
+            let dirty = run("git", &["-C", &manifest_dir, "status", "--porcelain"])
+                .is_some_and(|status| !status.is_empty());
+            if dirty { format!("{hash}-dirty") } else { hash }
+        }
+        None => String::from("unknown"),
+    };
+
+    // Honor SOURCE_DATE_EPOCH, for reproducible builds.
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    let build_time = format_timestamp(timestamp);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = run(&rustc, &["--version"]).unwrap_or_else(|| String::from("rustc unknown"));
+
+    let build_id = fnv1a(&[&git_hash, &build_time, &rustc_version, env!("CARGO_PKG_VERSION")]);
+
+    println!("cargo:rustc-env=NOCCIOLO_GIT_HASH={git_hash}");
+    println!("cargo:rustc-env=NOCCIOLO_BUILD_TIME={build_time}");
+    println!("cargo:rustc-env=NOCCIOLO_RUSTC_VERSION={rustc_version}");
+    println!("cargo:rustc-env=NOCCIOLO_BUILD_ID={build_id:016x}");
+}
+
+/// The trimmed standard output of the command, if it succeeded.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Format the UNIX timestamp as an ISO 8601 date and time in UTC.
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in parts.iter().flat_map(|part| part.bytes().chain(std::iter::once(0))) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
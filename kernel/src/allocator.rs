@@ -5,7 +5,9 @@ pub mod page;
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use fixed_size_block::FixedSizeBlockAllocator;
+use fixed_size_block::{FixedSizeBlockAllocator, HeapStatistics};
+
+use crate::meta::init::{FrameAllocatorInitialized, HeapInitialized, MapperInitialized};
 
 pub const HEAP_START: u64 = 0x_4444_4444_0000;
 pub const HEAP_SIZE: u64 = 1024 * 1024; // 1 MiB
@@ -35,7 +37,11 @@ use x86_64::{
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+    _: MapperInitialized,
+    _: FrameAllocatorInitialized,
+) -> Result<HeapInitialized, MapToError<Size4KiB>> {
+    let token = HeapInitialized::mark();
+
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
@@ -58,7 +64,25 @@ pub fn init_heap(
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE as usize);
     }
 
-    Ok(())
+    Ok(token)
+}
+
+/// Collect the statistics of the heap, see [`HeapStatistics`].
+pub fn statistics() -> HeapStatistics {
+    ALLOCATOR.lock().statistics()
+}
+
+/// Check the free lists of the heap, see
+/// [`FixedSizeBlockAllocator::check_free_lists`]. Returns `Ok` when the heap
+/// is locked, e.g. by the code that was interrupted.
+///
+/// Must not block or allocate.
+#[cfg(debug_assertions)]
+pub fn try_check_free_lists() -> Result<(), fixed_size_block::FreeListCorruption> {
+    match ALLOCATOR.try_lock() {
+        Some(allocator) => allocator.check_free_lists(),
+        None => Ok(()),
+    }
 }
 
 /// A wrapper around spin::Mutex to permit trait implementations.
@@ -76,6 +100,10 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
 
 /// Align the given address `addr` upwards to alignment `align`.
@@ -11,11 +11,70 @@ struct ListNode {
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The number of size classes, i.e. the block sizes plus the allocations that
+/// are too large for a block and are made by the fallback allocator.
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+
+    /// The number of allocations ever made, per size class.
+    allocations: [usize; SIZE_CLASSES],
+
+    /// The number of allocations currently in use, per size class.
+    live: [usize; SIZE_CLASSES],
+}
+
+/// A snapshot of the state of the heap, see [`FixedSizeBlockAllocator::statistics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStatistics {
+    pub size: usize,
+
+    /// The bytes used by the fallback allocator, including the blocks that
+    /// are cached in the free lists.
+    pub used: usize,
+
+    /// The bytes that are free in the fallback allocator, excluding the
+    /// blocks cached in the free lists.
+    pub free: usize,
+
+    /// The largest allocation the fallback allocator can satisfy.
+    pub largest_free_block: usize,
+
+    /// Per size class, see [`SIZE_CLASSES`].
+    pub allocations: [usize; SIZE_CLASSES],
+    pub live: [usize; SIZE_CLASSES],
+
+    /// The number of cached blocks per block size.
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+}
+
+impl HeapStatistics {
+    /// The percentage of the free memory that can't be used for the largest
+    /// possible allocation, where 0% means all free memory is contiguous.
+    #[must_use]
+    pub const fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            return 0;
+        }
+
+        100 - self.largest_free_block * 100 / self.free
+    }
+}
+
+/// A node in a free list that can't be a free block, found by
+/// [`FixedSizeBlockAllocator::check_free_lists`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeListCorruption {
+    pub block_size: usize,
+
+    /// The number of nodes before it in the list.
+    pub position: usize,
+    pub address: usize,
+    pub reason: &'static str,
 }
 
 impl FixedSizeBlockAllocator {
@@ -25,6 +84,8 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocations: [0; SIZE_CLASSES],
+            live: [0; SIZE_CLASSES],
         }
     }
 
@@ -47,6 +108,84 @@ impl FixedSizeBlockAllocator {
             },
         }
     }
+
+    /// Collect the statistics of the heap. This walks the free lists and
+    /// probes the fallback allocator, so it shouldn't be called in a hot path.
+    pub fn statistics(&mut self) -> HeapStatistics {
+        let mut free_blocks = [0; BLOCK_SIZES.len()];
+        for (count, head) in free_blocks.iter_mut().zip(&self.list_heads) {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                *count += 1;
+                node = current.next.as_deref();
+            }
+        }
+
+        HeapStatistics {
+            size: self.fallback_allocator.size(),
+            used: self.fallback_allocator.used(),
+            free: self.fallback_allocator.free(),
+            largest_free_block: self.largest_free_block(),
+            allocations: self.allocations,
+            live: self.live,
+            free_blocks,
+        }
+    }
+
+    /// Walk the free lists, checking every node is a block of the heap
+    /// before following it, so a corrupted pointer is reported instead of
+    /// dereferenced.
+    pub fn check_free_lists(&self) -> Result<(), FreeListCorruption> {
+        let bottom = self.fallback_allocator.bottom() as usize;
+        let top = self.fallback_allocator.top() as usize;
+
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            // `Option<&ListNode>` is a nullable pointer, so the links can be
+            // read as addresses.
+            let mut address = head.as_deref().map_or(0, |node| node as *const ListNode as usize);
+            let mut position = 0;
+
+            while address != 0 {
+                let corruption = |reason| FreeListCorruption { block_size, position, address, reason };
+                if address < bottom || address + block_size > top {
+                    return Err(corruption("outside of the heap"));
+                }
+                if address % block_size != 0 {
+                    return Err(corruption("misaligned"));
+                }
+                if position > (top - bottom) / block_size {
+                    return Err(corruption("the list has a cycle"));
+                }
+
+                address = unsafe { (address as *const usize).read_volatile() };
+                position += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The heap doesn't expose its holes, so binary search the largest
+    /// allocation that succeeds. Deallocating merges the hole back, so this
+    /// leaves the heap as it was.
+    fn largest_free_block(&mut self) -> usize {
+        let mut low = 0;
+        let mut high = self.fallback_allocator.free();
+
+        while low < high {
+            let size = (low + high + 1) / 2;
+            let layout = Layout::from_size_align(size, mem::align_of::<usize>()).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = size;
+                }
+                Err(()) => high = size - 1,
+            }
+        }
+
+        low
+    }
 }
 
 /// Invoked when the fallback allocator reached its Out Of Memory condition.
@@ -63,10 +202,15 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// The size class of the layout, as an index into the statistics.
+fn size_class(layout: &Layout) -> usize {
+    list_index(layout).unwrap_or(BLOCK_SIZES.len())
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -85,11 +229,21 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            let class = size_class(&layout);
+            allocator.allocations[class] += 1;
+            allocator.live[class] += 1;
         }
+
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.live[size_class(&layout)] -= 1;
+
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -0,0 +1,33 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Architecture-specific code.
+//!
+//! Every architecture provides the same set of modules and functions, which
+//! are re-exported here, so the rest of the kernel can use `crate::arch`
+//! without caring about the architecture it's built for:
+//!
+//! | Item                                 | Purpose                                        |
+//! |--------------------------------------|------------------------------------------------|
+//! | `init`                               | Set up the CPU tables for exception handling   |
+//! | `init_interrupt_controller`          | Set up the (legacy and/or modern) controllers  |
+//! | `enable_interrupts` and friends      | Interrupt control                              |
+//! | `halt`, `wait_for_interrupt`         | Idling the CPU                                 |
+//! | `ticks`, `TICKS_PER_SECOND`          | The periodic timer                             |
+//! | `cycles`                             | A cycle counter for measuring short durations  |
+//! | `cpu_count`, `cpu_index`             | The CPUs running kernel code                   |
+//! | `hardware_random`                    | The random number generator of the CPU         |
+//! | `context`                            | Switching between the stacks of kernel threads |
+//! | `memory`                             | Page tables and the physical frame allocator   |
+//! | `serial`                             | The early console, used by `serial_println!()` |
+//! | `string`                             | Fast fill and copy routines for large buffers  |
+//!
+//! On x86_64, `port` additionally provides the audited I/O port accesses,
+//! `user` runs the programs of the processes in user mode, and `syscall` is
+//! the entry of their system calls.
+
+#[cfg(target_arch = "x86_64")]
+#[path = "x86_64/mod.rs"]
+mod current;
+
+pub use self::current::*;
@@ -0,0 +1,159 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Switching between the stacks of the kernel threads, see
+//! [`crate::task::scheduler`].
+//!
+//! A thread that isn't running is described by its saved stack pointer, which
+//! points to what [`switch_context`] pushed before it switched away:
+//!
+//! | Offset | Saved                                   |
+//! |--------|-----------------------------------------|
+//! | 0x00   | RFLAGS, with the interrupt flag         |
+//! | 0x08   | R15, R14, R13, R12, RBX and RBP         |
+//! | 0x38   | The return address into the thread      |
+//!
+//! The other registers are saved by the caller, according to the System V
+//! ABI, and interrupt handlers save everything they use, so a thread can be
+//! switched away from in the timer interrupt as well. The threads of the
+//! processes have an [`AddressSpaceContext`] of their own besides, and every
+//! thread has an [`FpuState`].
+
+use core::arch::{asm, global_asm};
+
+use x86_64::{registers::control::Cr3, structures::paging::PhysFrame, VirtAddr};
+
+use super::{gdt, memory};
+
+/// RFLAGS of a new thread: only the reserved bit, so interrupts are disabled
+/// until the thread enables them.
+const INITIAL_RFLAGS: usize = 1 << 1;
+
+/// The x87 control word after `fninit`: every exception masked, with double
+/// extended precision.
+const INITIAL_FCW: u16 = 0x037F;
+
+/// MXCSR after a reset: every SSE exception masked, rounding to nearest.
+const INITIAL_MXCSR: u32 = 0x1F80;
+
+global_asm!(r#"
+.global nocciolo_switch_context
+nocciolo_switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#);
+
+extern "C" {
+    fn nocciolo_switch_context(save: *mut usize, load: usize);
+}
+
+/// Save the state of the current thread, storing its stack pointer at `save`,
+/// and continue the thread with the stack pointer `load`. Returns once another
+/// thread switches back.
+///
+/// # Safety
+/// `load` must have been saved by this function, or prepared by
+/// [`prepare_stack`], and interrupts must be disabled.
+pub unsafe fn switch_context(save: *mut usize, load: usize) {
+    nocciolo_switch_context(save, load);
+}
+
+/// What the scheduler switches along with the stack: the level 4 page table,
+/// which is that of a process while its thread runs (see
+/// [`memory::AddressSpace`]), and RSP0 of the TSS, which points into the
+/// stack of the thread once it entered user mode (see [`super::user`]).
+#[derive(Debug, Clone, Copy)]
+pub struct AddressSpaceContext {
+    level_4: PhysFrame,
+    kernel_stack: VirtAddr,
+}
+
+impl AddressSpaceContext {
+    /// The context of a new thread, with the page tables of the kernel.
+    pub fn kernel() -> Self {
+        Self { level_4: memory::kernel_level_4(), kernel_stack: VirtAddr::zero() }
+    }
+
+    /// The context of the running thread.
+    pub fn save() -> Self {
+        Self { level_4: Cr3::read().0, kernel_stack: unsafe { gdt::kernel_stack_slot().read() } }
+    }
+
+    /// Continue with the context, which only reloads CR3 when the page table
+    /// changes, since that flushes the TLB.
+    ///
+    /// # Safety
+    /// The page table must still exist, and interrupts must be disabled.
+    pub unsafe fn restore(&self) {
+        gdt::kernel_stack_slot().write(self.kernel_stack);
+
+        let (active, flags) = Cr3::read();
+        if active != self.level_4 {
+            Cr3::write(self.level_4, flags);
+        }
+    }
+}
+
+/// The x87 and SSE registers of a thread, in the 512 bytes `fxsave` writes.
+/// The kernel doesn't use them itself, since it's built for soft floats, so
+/// they only change in user mode, but the scheduler switches them along with
+/// every thread, so the processes don't see each other's.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The registers of a new thread, which are zero, with the exceptions
+    /// masked.
+    pub fn new() -> Self {
+        let mut area = [0; 512];
+        area[0..2].copy_from_slice(&INITIAL_FCW.to_le_bytes());
+        area[24..28].copy_from_slice(&INITIAL_MXCSR.to_le_bytes());
+        Self(area)
+    }
+
+    /// Save the registers of the running thread.
+    pub fn save(&mut self) {
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags)) };
+    }
+
+    /// Load the registers of the thread that continues.
+    ///
+    /// # Safety
+    /// The state must come from [`Self::new`] or [`Self::save`], since
+    /// `fxrstor` faults on reserved bits of MXCSR.
+    pub unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags, readonly));
+    }
+}
+
+/// Prepare the stack of a new thread, so switching to the returned stack
+/// pointer calls `entry` with interrupts disabled.
+///
+/// # Safety
+/// The stack must be valid and unused, and `top` is its exclusive end.
+pub unsafe fn prepare_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+    let top = top & !0xF;
+
+    // Right after a `call`, the stack pointer is 8 off from the alignment of
+    // 16 bytes, which `entry` expects after the `ret` as well.
+    let frame = [INITIAL_RFLAGS, 0, 0, 0, 0, 0, 0, entry as usize, 0];
+    let rsp = top - core::mem::size_of_val(&frame);
+    (rsp as *mut [usize; 9]).write(frame);
+    rsp
+}
@@ -0,0 +1,85 @@
+use core::{mem::offset_of, ptr::{addr_of, addr_of_mut}};
+
+use x86_64::VirtAddr;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use lazy_static::lazy_static;
+use log::trace;
+use crate::serial_println;
+use crate::meta::stack;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 10;
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// The TSS, which is written to after it's loaded, since RSP0 changes with
+/// the program running in user mode, see [`kernel_stack_slot`].
+pub(super) static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Where RSP0 is in the [`TSS`], for the entry of the system calls, which
+/// has to find the kernel stack before it can call anything.
+pub(super) const KERNEL_STACK_OFFSET: usize = offset_of!(TaskStateSegment, privilege_stack_table);
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.append(Descriptor::kernel_code_segment());
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        // The user data segment comes right before the user code segment,
+        // which is the order SYSRET expects.
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+        (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector })
+    };
+}
+
+struct Selectors {
+    code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+}
+
+/// The selectors of the code and data segments of the kernel.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// The selectors of the code and data segments for user mode, which have
+/// privilege level 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// RSP0 of the TSS: the stack the CPU switches to when an interrupt arrives
+/// in user mode.
+pub fn kernel_stack_slot() -> *mut VirtAddr {
+    unsafe { addr_of_mut!(TSS.privilege_stack_table[0]) }
+}
+
+pub fn init() {
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::instructions::segmentation::{CS, Segment};
+
+
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK));
+        stack::paint_and_register("double fault (IST 0)", stack_start, DOUBLE_FAULT_STACK_SIZE);
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE as u64;
+        (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+    }
+
+    trace!("Loading GDT");
+    GDT.0.load();
+    trace!("Loaded GDT");
+
+    trace!("Loading TSS");
+    unsafe {
+        CS::set_reg(GDT.1.code_selector);
+        load_tss(GDT.1.tss_selector);
+    }
+    trace!("Loaded TSS");
+}
@@ -0,0 +1,509 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+pub mod apic;
+pub mod error_code;
+pub mod fault_hook;
+
+use core::ops::Range;
+
+use volatile::Volatile;
+use x86_64::{structures::idt::{
+    ExceptionVector,
+    InterruptDescriptorTable,
+    InterruptStackFrame,
+    PageFaultErrorCode,
+}, PrivilegeLevel};
+
+use pic8259::ChainedPics;
+use lazy_static::lazy_static;
+use log::{trace, Level};
+
+use crate::{
+    debug,
+    hlt_loop,
+    interrupt_println,
+    irq_log,
+    arch::{interrupts::apic::{IOApic, LocalApic}, memory, user},
+    meta::{counters::Counter, irq_latency::{self, Irq}, symbols::Backtrace},
+    sync::IrqSpinlock,
+};
+
+use self::{
+    error_code::{ControlProtectionDescription, FaultLocation, PageFaultDescription, SelectorErrorCode},
+    fault_hook::FaultKind,
+};
+
+static TIMER_INTERRUPTS: Counter = Counter::new("irq.timer", "Timer interrupts");
+static KEYBOARD_INTERRUPTS: Counter = Counter::new("irq.keyboard", "Keyboard interrupts");
+static SERIAL_INTERRUPTS: Counter = Counter::new("irq.serial", "Interrupts of the first serial port");
+static AGENT_INTERRUPTS: Counter = Counter::new("irq.agent", "Interrupts of the guest agent serial port");
+static MSI_INTERRUPTS: Counter = Counter::new("irq.msi", "Interrupts of PCI devices, message-signaled or routed through the I/O APIC");
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    SecondarySerial = PIC_1_OFFSET + 3,
+    Serial = PIC_1_OFFSET + 4,
+    SpuriousIoApic = 39,
+    SpuriousLocalApic = 40,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The vectors that are handed out for message-signaled interrupts, see
+/// [`allocate_vector`]. These are delivered by the local APIC directly, so
+/// they don't overlap with the PICs or the I/O APIC. The interrupt lines of
+/// PCI devices without them are routed to these vectors through the I/O APIC
+/// as well.
+pub const MSI_VECTORS: Range<u8> = 48..64;
+
+/// The handlers of the allocated [`MSI_VECTORS`].
+static MSI_HANDLERS: IrqSpinlock<[Option<fn()>; MSI_VECTORS.end as usize - MSI_VECTORS.start as usize]> =
+    IrqSpinlock::new([None; MSI_VECTORS.end as usize - MSI_VECTORS.start as usize]);
+
+/// Reserve a vector of [`MSI_VECTORS`] for the handler, which is called with
+/// interrupts disabled, and is followed by the end of interrupt. Returns
+/// `None` when all vectors are taken.
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    let mut handlers = MSI_HANDLERS.lock();
+    let index = handlers.iter().position(Option::is_none)?;
+    handlers[index] = Some(handler);
+    Some(MSI_VECTORS.start + index as u8)
+}
+
+/// Give back a vector of [`allocate_vector`], after the device stopped using
+/// it.
+pub fn free_vector(vector: u8) {
+    if MSI_VECTORS.contains(&vector) {
+        MSI_HANDLERS.lock()[(vector - MSI_VECTORS.start) as usize] = None;
+    }
+}
+
+lazy_static! {
+    pub static ref TIMER: IrqSpinlock<Volatile<usize>> = IrqSpinlock::new(Volatile::new(0));
+
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        x86_64::set_general_handler!(&mut idt, generic_handler);
+
+        idt.divide_error.set_handler_fn(division_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.double_fault.set_handler_fn(double_fault_handler);
+
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_exception_handler);
+        idt.virtualization.set_handler_fn(virtualization_exception_handler);
+        idt.cp_protection_exception.set_handler_fn(control_protection_exception_handler);
+
+        idt.hv_injection_exception.set_handler_fn(hypervisor_injection_exception);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_exception_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
+
+        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::SecondarySerial.as_u8()].set_handler_fn(secondary_serial_interrupt_handler);
+        idt[InterruptIndex::Serial.as_u8()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::SpuriousLocalApic.as_u8()].set_handler_fn(spurious_local_apic_interrupt_handler);
+        idt[InterruptIndex::SpuriousIoApic.as_u8()].set_handler_fn(spurious_io_apic_interrupt_handler);
+
+        // Raised by the programs in user mode, so it's allowed from ring 3.
+        unsafe {
+            idt[user::EXIT_VECTOR].set_handler_addr(user::exit_handler_address())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+
+        idt
+    };
+}
+pub static PICS: IrqSpinlock<ChainedPics> = IrqSpinlock::new(
+    unsafe {
+        ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+    }
+);
+
+
+pub fn init_idt() {
+    trace!("Loading IDT");
+    IDT.load();
+    trace!("Loaded IDT");
+}
+
+#[inline(always)]
+fn interrupt_begin() {
+    irq_log!(Level::Trace, "Interrupt begin");
+}
+
+fn generic_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
+    if MSI_VECTORS.contains(&index) {
+        message_signaled_interrupt_handler(index);
+        return;
+    }
+
+    user::leave_on_interrupt(index, &stack_frame);
+    todo!("handle irq {}", index)
+}
+
+//
+// CPU Interrupts
+//
+
+#[no_mangle]
+extern "x86-interrupt"
+fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    interrupt_begin();
+    panic!("EXCEPTION: DOUBLE FAULT ({_error_code:X})\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    // A kernel mapping that was added after the address space of the process
+    // was created, so the access works once it's copied.
+    if Cr2::read().is_ok_and(memory::sync_kernel_entry) {
+        return;
+    }
+
+    if fault_hook::handle(FaultKind::PageFault, Some(Cr2::read_raw()), error_code.bits(), &mut stack_frame) {
+        return;
+    }
+    user::leave_on_fault(ExceptionVector::Page, &stack_frame);
+
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: PAGE FAULT");
+    interrupt_println!("Accessed Address: {:?}", Cr2::read());
+    interrupt_println!("Error Code: {}", PageFaultDescription(error_code));
+    interrupt_println!("Location: {}", FaultLocation(&stack_frame));
+    interrupt_println!("{:#?}", stack_frame);
+    hlt_loop();
+}
+
+//
+// Hardware Interrupts
+//
+
+#[no_mangle]
+extern "x86-interrupt"
+fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _latency = irq_latency::enter(Irq::Keyboard);
+    interrupt_begin();
+    KEYBOARD_INTERRUPTS.increment();
+    crate::crypto::entropy::add_interrupt_timing();
+
+    crate::device::ps2::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let latency = irq_latency::enter(Irq::Timer);
+    TIMER_INTERRUPTS.increment();
+    crate::meta::bench::record_tick();
+    let ticks = {
+        let mut timer = TIMER.lock();
+        let ticks = timer.read() + 1;
+        timer.write(ticks);
+        ticks
+    };
+
+    crate::task::timer::handle_tick(ticks);
+    crate::meta::System::handle_watchdog_tick(ticks);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    }
+
+    // After the EOI, so the timer keeps running when the check panics.
+    #[cfg(debug_assertions)]
+    crate::meta::integrity::handle_tick(ticks);
+
+    // The measurement ends before switching to another thread, which might
+    // run for a while before we return here.
+    drop(latency);
+    crate::task::scheduler::handle_tick(ticks);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _latency = irq_latency::enter(Irq::Serial);
+    SERIAL_INTERRUPTS.increment();
+    crate::crypto::entropy::add_interrupt_timing();
+    crate::arch::serial::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn secondary_serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _latency = irq_latency::enter(Irq::Agent);
+    AGENT_INTERRUPTS.increment();
+    crate::crypto::entropy::add_interrupt_timing();
+    crate::device::guest_agent::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondarySerial.as_u8());
+    }
+}
+
+/// Called through the [`generic_handler`] for the [`MSI_VECTORS`], of which
+/// the interrupts always come from the local APIC.
+fn message_signaled_interrupt_handler(vector: u8) {
+    let _latency = irq_latency::enter(Irq::Msi);
+    MSI_INTERRUPTS.increment();
+    crate::crypto::entropy::add_interrupt_timing();
+
+    let handler = MSI_HANDLERS.lock()[(vector - MSI_VECTORS.start) as usize];
+    match handler {
+        Some(handler) => handler(),
+        None => irq_log!(Level::Trace, "Message-signaled interrupt on unallocated vector {vector}"),
+    }
+
+    LocalApic::end_of_interrupt();
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn spurious_local_apic_interrupt_handler(stack_frame: InterruptStackFrame) {
+    irq_log!(Level::Trace, "Spurious Local APIC interrupt at {:#x}", stack_frame.instruction_pointer.as_u64());
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn spurious_io_apic_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // All I/O APIC inputs are routed here for now, including the serial ports,
+    // so the handler is measured as both.
+    let _serial_latency = irq_latency::enter(Irq::Serial);
+    let _agent_latency = irq_latency::enter(Irq::Agent);
+    crate::arch::serial::handle_interrupt();
+    crate::device::guest_agent::handle_interrupt();
+
+    irq_log!(Level::Trace, "Spurious I/O APIC interrupt at {:#x}", stack_frame.instruction_pointer.as_u64());
+    breakpoint();
+    IOApic::end_of_interrupt();
+}
+
+#[no_mangle]
+extern "C"
+fn breakpoint() {
+    irq_log!(Level::Trace, "Breakpoint");
+    debug::magic_break!();
+}
+
+//
+// Exceptions
+//
+
+// TODO: reorganize these sections
+
+#[no_mangle]
+extern "x86-interrupt"
+fn division_error_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::Division, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: DIVISION ERROR\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: NMI\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn debug_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::Debug, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn overflow_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::Overflow, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::BoundRange, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    if fault_hook::handle(FaultKind::InvalidOpcode, None, 0, &mut stack_frame) {
+        return;
+    }
+    user::leave_on_fault(ExceptionVector::InvalidOpcode, &stack_frame);
+
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::DeviceNotAvailable, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::leave_on_fault(ExceptionVector::InvalidTss, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: INVALID TSS ({})", SelectorErrorCode::new(error_code));
+    interrupt_println!("Location: {}\n{:#?}", FaultLocation(&stack_frame), stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::leave_on_fault(ExceptionVector::SegmentNotPresent, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: SEGMENT NOT PRESENT ({})", SelectorErrorCode::new(error_code));
+    interrupt_println!("Location: {}\n{:#?}", FaultLocation(&stack_frame), stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::leave_on_fault(ExceptionVector::Stack, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: STACK SEGMENT FAULT ({})", SelectorErrorCode::new(error_code));
+    interrupt_println!("Location: {}\n{:#?}", FaultLocation(&stack_frame), stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn general_protection_fault_handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    if fault_hook::handle(FaultKind::GeneralProtection, None, error_code, &mut stack_frame) {
+        return;
+    }
+    user::leave_on_fault(ExceptionVector::GeneralProtection, &stack_frame);
+
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: GENERAL PROTECTION FAULT ({})", SelectorErrorCode::new(error_code));
+    interrupt_println!("Location: {}\n{:#?}", FaultLocation(&stack_frame), stack_frame);
+
+    hlt_loop();
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::X87FloatingPoint, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: X87 FLOATING POINT EXCEPTION\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::leave_on_fault(ExceptionVector::AlignmentCheck, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: ALIGNMENT CHECK ({error_code}) \n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+    panic!("MACHINE CHECK");
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn simd_floating_point_exception_handler(stack_frame: InterruptStackFrame) {
+    user::leave_on_fault(ExceptionVector::SimdFloatingPoint, &stack_frame);
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: SIMD FLOATING POINT EXCEPTION\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn control_protection_exception_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: CONTROL PROTECTION EXCEPTION ({})", ControlProtectionDescription(error_code));
+    interrupt_println!("Location: {}\n{:#?}", FaultLocation(&stack_frame), stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn virtualization_exception_handler(stack_frame: InterruptStackFrame) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: VIRTUALIZATION EXCEPTION)\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn hypervisor_injection_exception(stack_frame: InterruptStackFrame) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: HYPERVISOR INJECTION EXCEPTION\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn vmm_communication_exception_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: VMM COMMUNICATION EXCEPTION ({error_code})\n{:#?}", stack_frame);
+}
+
+#[no_mangle]
+extern "x86-interrupt"
+fn security_exception_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    interrupt_begin();
+    interrupt_println!("EXCEPTION: SECURITY EXCEPTION ({error_code})\n{:#?}", stack_frame);
+}
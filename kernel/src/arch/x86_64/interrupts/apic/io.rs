@@ -9,7 +9,11 @@ use log::trace;
 use spin::Mutex;
 use x86_64::PhysAddr;
 
-use crate::{device::acpi::{NoccioloAcpiHandler, ACPI_DATA}, interrupts::InterruptIndex};
+use crate::{
+    arch::interrupts::InterruptIndex,
+    device::acpi::{NoccioloAcpiHandler, ACPI_DATA},
+    meta::memory_map::{self, RegionKind},
+};
 
 use super::local::LocalApic;
 
@@ -20,21 +24,19 @@ lazy_static! {
 pub struct IOApic {
     mapping: PhysicalMapping<NoccioloAcpiHandler, [u32; 256]>,
     redirection_entry_count: u8,
-    end_of_interrupt_addr: *mut u32,
 }
 
 impl IOApic {
-    pub fn new(local: &LocalApic) -> Self {
-        let eoi_addr = unsafe { local.offset_to_addr(0xB0) };
+    pub fn new() -> Self {
         let addr = find_io_apic_base().expect("NO IOAPIC FOUND :(");
-        Self::from_addr(addr, eoi_addr)
+        Self::from_addr(addr)
     }
 
+    /// The end of interrupt is signaled to the local APIC, which delivered
+    /// the interrupt.
     pub fn end_of_interrupt() {
         Self::dump_debug_info();
-        Self::with(|this| unsafe {
-            this.end_of_interrupt_addr.write_volatile(0)
-        });
+        LocalApic::end_of_interrupt();
         Self::dump_debug_info();
     }
 
@@ -46,17 +48,17 @@ impl IOApic {
     }
 
     #[must_use]
-    pub fn from_addr(addr: PhysAddr, eoi_addr: *mut u32) -> Self {
+    pub fn from_addr(addr: PhysAddr) -> Self {
         let mapping = unsafe {
             NoccioloAcpiHandler.map_physical_region(addr.as_u64() as _, 0x400)
         };
+        memory_map::register(addr.as_u64(), addr.as_u64() + 0x400, RegionKind::Mmio, "I/O APIC");
 
         assert_eq!(addr.as_u64() % 4096, 0);
 
         let mut this = Self {
             mapping,
             redirection_entry_count: 0,
-            end_of_interrupt_addr: eoi_addr,
         };
 
         let redirection_entry_count = this.read_redirection_entry_count() + 1;
@@ -93,6 +95,36 @@ impl IOApic {
         }
     }
 
+    /// Route the legacy interrupt line of a PCI device to the vector, and
+    /// return the input of the I/O APIC it arrives on. The line is an ISA
+    /// IRQ, which the MADT may move to another input, or give another
+    /// polarity and trigger mode; otherwise, it's level-triggered and
+    /// active-low, like the PCI interrupts are. Returns `None` without an
+    /// I/O APIC, or when the line isn't one of its inputs.
+    pub fn route_pci_interrupt(line: u8, vector: u8) -> Option<u8> {
+        let (input, flags) = find_source_override(line)
+            .unwrap_or((line as u32, 0));
+
+        let mut instance = INSTANCE.lock();
+        let this = instance.as_mut()?;
+        let input = u8::try_from(input).ok().filter(|input| *input < this.redirection_entry_count)?;
+
+        let mut entry = this.read_entry(input);
+        entry.vector = vector;
+        entry.delivery_mode = DeliveryMode::Fixed;
+        entry.polarity = match flags & OVERRIDE_POLARITY_MASK {
+            OVERRIDE_POLARITY_HIGH => InterruptPolarity::HighActive,
+            _ => InterruptPolarity::LowActive,
+        };
+        entry.trigger_mode = match flags & OVERRIDE_TRIGGER_MASK {
+            OVERRIDE_TRIGGER_EDGE => TriggerMode::EdgeSensitive,
+            _ => TriggerMode::LevelSensitive,
+        };
+        entry.mask = InterruptMask::Unmasked;
+        this.write_entry(input, entry);
+        Some(input)
+    }
+
     fn map(&mut self, index: u8, vector: InterruptIndex) {
         let mut entry = self.read_entry(index);
         entry.mask = InterruptMask::Unmasked;
@@ -327,6 +359,26 @@ impl IOApicRegister {
     }
 }
 
+/// The flags of an interrupt source override, of which the bits that aren't
+/// set "conform to the bus", i.e. are those of PCI for the routed lines.
+const OVERRIDE_POLARITY_MASK: u16 = 0b11;
+const OVERRIDE_POLARITY_HIGH: u16 = 0b01;
+const OVERRIDE_TRIGGER_MASK: u16 = 0b11 << 2;
+const OVERRIDE_TRIGGER_EDGE: u16 = 0b01 << 2;
+
+/// The global system interrupt and the flags of the ISA IRQ, when the MADT
+/// overrides it.
+fn find_source_override(irq: u8) -> Option<(u32, u16)> {
+    let acpi_data = ACPI_DATA.lock();
+    let madt = acpi_data.madt.as_ref()?;
+    madt.entries().find_map(|entry| match entry {
+        MadtEntry::InterruptSourceOverride(entry) if entry.irq == irq => {
+            Some((entry.global_system_interrupt, entry.flags))
+        }
+        _ => None,
+    })
+}
+
 fn find_io_apic_base() -> Option<PhysAddr> {
     if let Some(madt) = ACPI_DATA.lock().madt.as_ref() {
         for entry in madt.entries() {
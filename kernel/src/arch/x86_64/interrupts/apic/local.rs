@@ -1,12 +1,32 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+//! The local APIC of the processor, which takes the interrupts of the I/O
+//! APIC, the message-signaled interrupts and the timer. Its registers are
+//! accessed in one of two ways:
+//!
+//! | Mode     | Registers                                       | APIC IDs      |
+//! |----------|-------------------------------------------------|---------------|
+//! | xAPIC    | Memory-mapped, at the base address in the MSR   | 8 bits        |
+//! | x2APIC   | MSRs `0x800` and up, one per 16 bytes of MMIO   | 32 bits       |
+//!
+//! The x2APIC mode is used when CPUID says it's supported, unless `nox2apic`
+//! is passed. Both modes have the same registers (bar a few), so the rest of
+//! the driver is the same, apart from the APIC ID, which is in the upper byte
+//! of the register in xAPIC mode.
+//!
+//! ### References:
+//! - [Intel SDM Volume 3, 11.12 Extended XAPIC (x2APIC)](https://www.intel.com/content/www/us/en/developer/articles/technical/intel-sdm.html)
+//! - [OSDev Wiki: APIC](https://wiki.osdev.org/APIC)
+
 use core::{
-    fmt::Debug,
+    fmt::{Debug, Display, Formatter},
     ptr::{
+        self,
         read_volatile,
         write_volatile,
     },
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, Ordering},
 };
 
 use acpi::{
@@ -18,6 +38,7 @@ use acpi::{
 use bootloader_api::BootInfo;
 use lazy_static::lazy_static;
 use log::{trace, warn};
+use raw_cpuid::CpuId;
 
 use spin::Mutex;
 use x86_64::{
@@ -28,14 +49,51 @@ use x86_64::{
 use crate::{device::acpi::{
     NoccioloAcpiHandler,
     ACPI_DATA,
-}, interrupts::PIC_1_OFFSET, logging::Colorize};
+}, arch::interrupts::PIC_1_OFFSET, logging::Colorize, meta::{memory_map::{self, RegionKind}, BootParameters}};
 
 const IA32_APIC_BASE_MSR: u32 = 0x1B;
 
+/// The flags in the base MSR that switch from the xAPIC to the x2APIC mode.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// The MSR of the first register in x2APIC mode.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
 lazy_static! {
     static ref INSTANCE: Mutex<Option<LocalApic>> = Default::default();
 }
 
+/// The mode of the published local APIC, or [`NOT_PUBLISHED`], and its ID.
+/// These are kept apart from the [`INSTANCE`], so the interrupt handlers don't
+/// have to lock it.
+static PUBLISHED_MODE: AtomicU8 = AtomicU8::new(NOT_PUBLISHED);
+static PUBLISHED_ID: AtomicU32 = AtomicU32::new(0);
+const NOT_PUBLISHED: u8 = u8::MAX;
+
+/// The end of interrupt register of the published local APIC in xAPIC mode.
+static END_OF_INTERRUPT: AtomicPtr<u32> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LocalApicMode {
+    XApic,
+    X2Apic,
+}
+
+impl Display for LocalApicMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::XApic => "xAPIC",
+            Self::X2Apic => "x2APIC",
+        })
+    }
+}
+
+fn supports_x2apic() -> bool {
+    CpuId::new().get_feature_info().is_some_and(|info| info.has_x2apic())
+}
+
 
 fn find_local_apic_base() -> PhysAddr {
     if let Some(madt) = ACPI_DATA.lock().madt.as_ref() {
@@ -64,18 +122,46 @@ fn set_local_apic_base(addr: PhysAddr) {
     }
 }
 
+/// Switch the local APIC to x2APIC mode, which can't be left without
+/// disabling the APIC, so there is no way back.
+fn enable_x2apic_mode() {
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    unsafe {
+        let value = apic_base_msr.read();
+        apic_base_msr.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    }
+}
+
 pub struct LocalApic {
-    mapping: PhysicalMapping<NoccioloAcpiHandler, [u8; 0x800]>,
+    mode: LocalApicMode,
+
+    /// The registers in xAPIC mode. In x2APIC mode, they're accessed through
+    /// the MSRs instead.
+    mapping: Option<PhysicalMapping<NoccioloAcpiHandler, [u8; 0x800]>>,
 }
 
 impl LocalApic {
     #[must_use]
     pub fn new(boot_info: &BootInfo) -> Self {
+        if supports_x2apic() && BootParameters::flag("x2apic") != Some(false) {
+            return Self::new_x2apic();
+        }
+
         let addr = find_local_apic_base();
         verify_in_correct_region(addr, boot_info);
         Self::from_addr(addr)
     }
 
+    #[must_use]
+    pub fn new_x2apic() -> Self {
+        enable_x2apic_mode();
+        trace!("Local APIC is in x2APIC mode");
+        Self {
+            mode: LocalApicMode::X2Apic,
+            mapping: None,
+        }
+    }
+
     #[must_use]
     pub fn from_addr(addr: PhysAddr) -> Self {
         // Section 11.4.1 of 3rd volume of Intel SDM recommends mapping the base
@@ -85,12 +171,14 @@ impl LocalApic {
         let mapping = unsafe {
             NoccioloAcpiHandler.map_physical_region(addr.as_u64() as _, 0x800)
         };
+        memory_map::register(addr.as_u64(), addr.as_u64() + 0x800, RegionKind::Mmio, "local APIC");
 
         trace!("Local APIC is at {addr:?}");
         let this =
 
         Self {
-            mapping
+            mode: LocalApicMode::XApic,
+            mapping: Some(mapping),
         }
 
         ;
@@ -126,8 +214,16 @@ impl LocalApic {
         trace!("LINT1 is set to: 0x{:x}", self.read(LocalApicRegister::LvtLint1));
     }
 
+    pub fn mode(&self) -> LocalApicMode {
+        self.mode
+    }
+
     pub fn id(&self) -> u32 {
-        self.read(LocalApicRegister::Id)
+        let id = self.read(LocalApicRegister::Id);
+        match self.mode {
+            LocalApicMode::XApic => id >> 24,
+            LocalApicMode::X2Apic => id,
+        }
     }
 
     fn set_timer_divide(&mut self, divide: u32) {
@@ -153,18 +249,28 @@ impl LocalApic {
 
     fn read(&self, register: LocalApicRegister) -> u32 {
         assert!(register.is_readable(), "Register {register:?} is {:?}", register.permissions());
+        assert!(register.is_available_in(self.mode), "Register {register:?} isn't available in {} mode", self.mode);
         trace!("Reading from {register:?} ({:X}h)", register as usize);
         unsafe {
-            read_volatile(self.register_to_addr(register))
+            match self.mode {
+                LocalApicMode::XApic => read_volatile(self.register_to_addr(register)),
+                LocalApicMode::X2Apic => register.msr().read() as u32,
+            }
         }
     }
 
     fn write(&mut self, register: LocalApicRegister, value: u32) {
         assert!(register.is_writable(), "Register {register:?} is {:?}", register.permissions());
+        assert!(register.is_available_in(self.mode), "Register {register:?} isn't available in {} mode", self.mode);
         trace!("Writing to {register:?} ({:X}h) with value 0x{value:X}", register as usize);
         unsafe {
-            let addr = self.register_to_addr(register) as *mut u32;
-            write_volatile(addr, value)
+            match self.mode {
+                LocalApicMode::XApic => {
+                    let addr = self.register_to_addr(register) as *mut u32;
+                    write_volatile(addr, value)
+                }
+                LocalApicMode::X2Apic => register.msr().write(value as u64),
+            }
         }
     }
 
@@ -175,15 +281,58 @@ impl LocalApic {
         addr
     }
 
-    pub(super) unsafe fn offset_to_addr(&self, offset: usize) -> *mut u32 {
-        ((&(self.mapping.virtual_start().as_ref())[offset]) as *const u8 as usize - 0x900) as *const u32 as *mut u32
+    /// Only for the xAPIC mode.
+    unsafe fn offset_to_addr(&self, offset: usize) -> *mut u32 {
+        let mapping = self.mapping.as_ref().expect("the registers are mapped in xAPIC mode");
+        ((&(mapping.virtual_start().as_ref())[offset]) as *const u8 as usize - 0x900) as *const u32 as *mut u32
     }
 
     pub fn publish(self) {
+        let end_of_interrupt = match self.mode {
+            LocalApicMode::XApic => unsafe { self.offset_to_addr(LocalApicRegister::EndOfInterrupt as usize) },
+            LocalApicMode::X2Apic => ptr::null_mut(),
+        };
+        END_OF_INTERRUPT.store(end_of_interrupt, Ordering::Release);
+        PUBLISHED_ID.store(self.id(), Ordering::Release);
+        PUBLISHED_MODE.store(self.mode as u8, Ordering::Release);
+
         let mut instance = INSTANCE.lock();
         *instance = Some(self);
     }
 
+    /// The mode of the published local APIC, or `None` before
+    /// [`publish`](Self::publish).
+    pub fn published_mode() -> Option<LocalApicMode> {
+        match PUBLISHED_MODE.load(Ordering::Acquire) {
+            mode if mode == LocalApicMode::XApic as u8 => Some(LocalApicMode::XApic),
+            mode if mode == LocalApicMode::X2Apic as u8 => Some(LocalApicMode::X2Apic),
+            _ => None,
+        }
+    }
+
+    /// The ID of the published local APIC, as used for the destination of
+    /// interrupt messages, or `None` before [`publish`](Self::publish), or
+    /// when an x2APIC ID doesn't fit in a message.
+    pub fn published_id() -> Option<u8> {
+        Self::published_mode()?;
+        u8::try_from(PUBLISHED_ID.load(Ordering::Acquire)).ok()
+    }
+
+    /// Signal the end of the interrupt being handled to the published local
+    /// APIC. This is called from interrupt handlers, so it doesn't log or
+    /// lock.
+    pub fn end_of_interrupt() {
+        match Self::published_mode() {
+            Some(LocalApicMode::XApic) => unsafe {
+                write_volatile(END_OF_INTERRUPT.load(Ordering::Acquire), 0);
+            },
+            Some(LocalApicMode::X2Apic) => unsafe {
+                LocalApicRegister::EndOfInterrupt.msr().write(0);
+            },
+            None => (),
+        }
+    }
+
     fn ensure_safe_addr(&self, addr: *const u32) {
         debug_assert!(addr < self.get_mapped_end());
     }
@@ -191,7 +340,7 @@ impl LocalApic {
     fn get_mapped_end(&self) -> *const u32 {
         let addr = unsafe {
             let addr = self.offset_to_addr(0);
-            (addr as usize) + self.mapping.mapped_length()
+            (addr as usize) + self.mapping.as_ref().map_or(0, PhysicalMapping::mapped_length)
         };
         addr as *const u32
     }
@@ -232,6 +381,20 @@ pub enum LocalApicRegister {
 }
 
 impl LocalApicRegister {
+    /// The x2APIC mode has a single 64-bit interrupt command register, and
+    /// no destination format register.
+    pub const fn is_available_in(&self, mode: LocalApicMode) -> bool {
+        match mode {
+            LocalApicMode::XApic => true,
+            LocalApicMode::X2Apic => !matches!(self, Self::DestinationFormat | Self::InterruptCommand2),
+        }
+    }
+
+    /// The MSR of the register in x2APIC mode.
+    fn msr(&self) -> Msr {
+        Msr::new(X2APIC_MSR_BASE + (*self as usize >> 4) as u32)
+    }
+
     pub const fn permissions(&self) -> ApicRegisterPermissions {
         match self {
             // Actually R/W, but the Intel specification discourages writing
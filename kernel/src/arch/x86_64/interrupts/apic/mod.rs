@@ -8,7 +8,7 @@ mod io;
 mod local;
 
 pub use io::IOApic;
-use local::LocalApic;
+pub use local::LocalApic;
 use x86_64::instructions::interrupts::without_interrupts;
 
 #[derive(Debug, Clone, Copy)]
@@ -23,10 +23,10 @@ pub(crate) fn init(boot_info: &BootInfo) -> Result<(), ApicError> {
     local.initialize();
     local.do_test_stuff();
 
-    trace!("APIC has ID {} and version {:x}", local.id(), local.version());
+    trace!("APIC has ID {} and version {:x} in {} mode", local.id(), local.version(), local.mode());
 
     without_interrupts(|| {
-        let mut io = IOApic::new(&local);
+        let mut io = IOApic::new();
         io.initialize();
         io.publish();
 
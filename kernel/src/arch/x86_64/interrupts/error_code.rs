@@ -0,0 +1,160 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Human-readable decoding of the error codes the CPU pushes for exceptions.
+//!
+//! ### References:
+//! - Intel SDM Volume 3A, Section 6.13 "Error Code"
+//! - Intel SDM Volume 3A, Section 6.15 "Exception and Interrupt Reference"
+
+use core::fmt::{Display, Formatter};
+
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+use crate::meta::symbols;
+
+/// The descriptor table a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of the #TS, #NP, #SS and #GP exceptions, which refers to
+/// the segment selector or IDT vector that caused the exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    #[must_use]
+    pub const fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// The exception originated from an event external to the program, such
+    /// as a hardware interrupt.
+    #[must_use]
+    pub const fn is_external(&self) -> bool {
+        self.0 & 0b1 != 0
+    }
+
+    #[must_use]
+    pub const fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// The index of the descriptor in the table, or the vector number if the
+    /// table is the IDT.
+    #[must_use]
+    pub const fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+}
+
+impl Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        // Most #GP faults aren't segment related and push a zero.
+        if self.0 == 0 {
+            return f.write_str("0 (not segment related)");
+        }
+
+        write!(f, "{:#x} ({:?} ", self.0, self.table())?;
+        match self.table() {
+            DescriptorTable::Idt => write!(f, "vector {}", self.index())?,
+            _ => write!(f, "index {}, selector {:#x}", self.index(), self.0 & 0xFFF8)?,
+        }
+
+        if self.is_external() {
+            f.write_str(", external")?;
+        }
+
+        f.write_str(")")
+    }
+}
+
+/// Describes the bits of the [`PageFaultErrorCode`].
+pub struct PageFaultDescription(pub PageFaultErrorCode);
+
+impl Display for PageFaultDescription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let code = self.0;
+
+        f.write_str(if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        })?;
+
+        f.write_str(if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            " during write"
+        } else {
+            " during read"
+        })?;
+
+        f.write_str(if code.contains(PageFaultErrorCode::USER_MODE) {
+            " in user mode"
+        } else {
+            " in kernel mode"
+        })?;
+
+        let flags = [
+            (PageFaultErrorCode::MALFORMED_TABLE, "reserved bit set in page table"),
+            (PageFaultErrorCode::INSTRUCTION_FETCH, "instruction fetch"),
+            (PageFaultErrorCode::PROTECTION_KEY, "protection key"),
+            (PageFaultErrorCode::SHADOW_STACK, "shadow stack access"),
+            (PageFaultErrorCode::SGX, "SGX violation"),
+            (PageFaultErrorCode::RMP, "RMP violation"),
+        ];
+
+        for (flag, description) in flags {
+            if code.contains(flag) {
+                write!(f, ", {description}")?;
+            }
+        }
+
+        write!(f, " ({:#x})", code.bits())
+    }
+}
+
+/// Describes the error code of a control protection exception (#CP).
+pub struct ControlProtectionDescription(pub u64);
+
+impl Display for ControlProtectionDescription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let description = match self.0 & 0x7FFF {
+            1 => "NEAR-RET",
+            2 => "FAR-RET/IRET",
+            3 => "ENDBRANCH",
+            4 => "RSTORSSP",
+            5 => "SETSSBSY",
+            _ => "unknown",
+        };
+
+        write!(f, "{:#x} ({description}", self.0)?;
+
+        if self.0 & (1 << 15) != 0 {
+            f.write_str(", during enclave execution")?;
+        }
+
+        f.write_str(")")
+    }
+}
+
+/// Describes the location of the faulting instruction, using the symbols of
+/// the kernel image when available.
+pub struct FaultLocation<'a>(pub &'a InterruptStackFrame);
+
+impl Display for FaultLocation<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let ip = self.0.instruction_pointer.as_u64();
+        match symbols::resolve(ip) {
+            Some(symbol) => write!(f, "{ip:#x} in {symbol}"),
+            None => write!(f, "{ip:#x} in <unknown>"),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Expected faults, so the self-tests can provoke a CPU exception and check
+//! how it was reported, without bringing the kernel down.
+//!
+//! Every probe arms a single-shot hook for the fault it expects, and stores
+//! the address right after the faulting instruction as the place to resume.
+//! When the fault arrives, the exception handler hands it to [`handle`]
+//! before anything else, which records the fault and resumes there instead of
+//! halting. Faults that don't match the hook are handled as usual.
+//!
+//! This is meant for tests only: the kernel doesn't recover from its own
+//! faults, but the probes show that e.g. a guard page really faults.
+
+use core::{arch::asm, sync::atomic::{AtomicU64, AtomicU8, Ordering}};
+
+use spin::Mutex;
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// The [`FaultKind`] the hook is armed for, zero when disarmed.
+static EXPECTED: AtomicU8 = AtomicU8::new(0);
+
+/// The page fault address the hook is armed for.
+static EXPECTED_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Where to continue after the expected fault, written by the probe itself.
+static RESUME: AtomicU64 = AtomicU64::new(0);
+
+static CAUGHT: Mutex<Option<Fault>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FaultKind {
+    PageFault = 1,
+    GeneralProtection,
+    InvalidOpcode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+
+    /// The accessed address (CR2) of a page fault.
+    pub address: Option<u64>,
+    pub error_code: u64,
+    pub instruction_pointer: VirtAddr,
+}
+
+/// Called by the exception handlers before anything else, returning whether
+/// the fault was expected, in which case the handler should return right
+/// away. Must not block or allocate.
+pub(super) fn handle(kind: FaultKind, address: Option<u64>, error_code: u64, stack_frame: &mut InterruptStackFrame) -> bool {
+    if EXPECTED.load(Ordering::Acquire) != kind as u8 {
+        return false;
+    }
+
+    if kind == FaultKind::PageFault && address != Some(EXPECTED_ADDRESS.load(Ordering::Relaxed)) {
+        return false;
+    }
+
+    // The probe faulted before storing where to resume.
+    let resume = RESUME.load(Ordering::Acquire);
+    if resume == 0 {
+        return false;
+    }
+
+    let Some(mut caught) = CAUGHT.try_lock() else {
+        return false;
+    };
+
+    EXPECTED.store(0, Ordering::Release);
+    *caught = Some(Fault {
+        kind,
+        address,
+        error_code,
+        instruction_pointer: stack_frame.instruction_pointer,
+    });
+
+    unsafe {
+        stack_frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(resume));
+    }
+    true
+}
+
+fn arm(kind: FaultKind, address: u64) {
+    *CAUGHT.lock() = None;
+    EXPECTED_ADDRESS.store(address, Ordering::Relaxed);
+    RESUME.store(0, Ordering::Relaxed);
+    EXPECTED.store(kind as u8, Ordering::Release);
+}
+
+fn disarm() -> Option<Fault> {
+    EXPECTED.store(0, Ordering::Release);
+    RESUME.store(0, Ordering::Relaxed);
+    CAUGHT.lock().take()
+}
+
+/// A non-canonical address raises a general protection fault instead of a
+/// page fault.
+fn expected_kind(address: u64) -> FaultKind {
+    match VirtAddr::try_new(address) {
+        Ok(_) => FaultKind::PageFault,
+        Err(_) => FaultKind::GeneralProtection,
+    }
+}
+
+/// Read a byte, returning the fault if the read faulted.
+pub fn read(address: u64) -> Result<u8, Fault> {
+    arm(expected_kind(address), address);
+
+    let value: u8;
+    unsafe {
+        asm!(
+            "lea {scratch}, [rip + 2f]",
+            "mov [{resume}], {scratch}",
+            "mov {value}, byte ptr [{address}]",
+            "2:",
+            scratch = out(reg) _,
+            resume = in(reg) RESUME.as_ptr(),
+            address = in(reg) address,
+            value = out(reg_byte) value,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    disarm().map_or(Ok(value), Err)
+}
+
+/// Write a byte, returning the fault if the write faulted.
+pub fn write(address: u64, value: u8) -> Result<(), Fault> {
+    arm(expected_kind(address), address);
+
+    unsafe {
+        asm!(
+            "lea {scratch}, [rip + 2f]",
+            "mov [{resume}], {scratch}",
+            "mov byte ptr [{address}], {value}",
+            "2:",
+            scratch = out(reg) _,
+            resume = in(reg) RESUME.as_ptr(),
+            address = in(reg) address,
+            value = in(reg_byte) value,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    disarm().map_or(Ok(()), Err)
+}
+
+/// Execute `ud2`, which should always fault.
+pub fn invalid_opcode() -> Result<(), Fault> {
+    arm(FaultKind::InvalidOpcode, 0);
+
+    unsafe {
+        asm!(
+            "lea {scratch}, [rip + 2f]",
+            "mov [{resume}], {scratch}",
+            "ud2",
+            "2:",
+            scratch = out(reg) _,
+            resume = in(reg) RESUME.as_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+
+    disarm().map_or(Ok(()), Err)
+}
@@ -0,0 +1,635 @@
+use alloc::vec::Vec;
+use core::{ops::Range, sync::atomic::{AtomicU64, Ordering}};
+
+use bootloader_api::{
+    BootInfo,
+    info::{
+        MemoryRegion,
+        MemoryRegions,
+        MemoryRegionKind,
+    },
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::{control::Cr3, model_specific::{Efer, EferFlags}},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        page_table::PageTableEntry,
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PageTable, FrameAllocator, Size4KiB, PhysFrame, Translate,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+use crate::{arch::memory, meta::init::{FrameAllocatorInitialized, MapperInitialized}};
+
+/// The number of frames that can be excluded, see
+/// [`BootInfoFrameAllocator::exclude`].
+pub const MAX_EXCLUDED_FRAMES: usize = 64;
+
+/// The addresses for the programs in user mode: the 512 GiB of the 128th
+/// entry of the level 4 table, which the kernel doesn't use, and which every
+/// [`AddressSpace`] has its own tables for.
+pub const USER_REGION: Range<u64> = 0x4000_0000_0000..0x4080_0000_0000;
+
+/// The entry of the level 4 table that maps the [`USER_REGION`].
+const USER_LEVEL_4_INDEX: usize = (USER_REGION.start >> 39) as usize;
+
+/// The offset of [`init_mapper`], for the code that can't take the lock of
+/// the [`MAPPER`], e.g. the page fault handler and the frame allocator.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The level 4 table the bootloader set up, which maps the kernel, and which
+/// is active whenever no [`AddressSpace`] is.
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    pub static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+    pub static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+}
+
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+    translate_addr_inner(addr, physical_memory_offset)
+}
+
+/// Private function that is called by `translate_addr`.
+///
+/// This function is safe to limit the scope of `unsafe` because Rust treats
+/// the whole body of unsafe functions as an unsafe block. This function must
+/// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+
+    // traverse the multi-level page table
+    for &index in &table_indexes {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior), which
+/// is checked.
+pub unsafe fn init_mapper(physical_memory_offset: VirtAddr) -> MapperInitialized {
+    let token = MapperInitialized::mark();
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+    token
+}
+
+/// Hand out the usable frames of the memory map, which must be called only
+/// once, since the frames would be handed out twice otherwise (checked).
+pub unsafe fn init_frame_allocator(memory_regions: &'static MemoryRegions) -> FrameAllocatorInitialized {
+    let token = FrameAllocatorInitialized::mark();
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(memory_regions));
+    token
+}
+
+pub fn with_mapper<F: FnOnce(&mut OffsetPageTable<'static>) -> R, R>(f: F) -> R {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("the page table mapper isn't initialized yet");
+    f(mapper)
+}
+
+pub fn with_frame_allocator<F: FnOnce(&mut BootInfoFrameAllocator) -> R, R>(f: F) -> R {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().expect("the frame allocator isn't initialized yet");
+    f(allocator)
+}
+
+/// Translate the address with the active page tables, returning `None` when
+/// it isn't mapped.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Where the physical address is in the mapping of the physical memory, or
+/// `None` when the bootloader didn't map it (e.g. beyond the end of RAM).
+pub fn physical_to_virtual(addr: PhysAddr) -> Option<VirtAddr> {
+    with_mapper(|mapper| {
+        let virt = mapper.phys_offset() + addr.as_u64();
+        (mapper.translate_addr(virt) == Some(addr)).then_some(virt)
+    })
+}
+
+/// Physically contiguous memory for devices to access directly, which is
+/// zeroed, and never freed.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub physical: PhysAddr,
+
+    /// Where the region is mapped, in the mapping of the physical memory.
+    pub virt: VirtAddr,
+}
+
+/// Allocate at least `size` bytes of physically contiguous memory. The frame
+/// allocator hands out the usable frames in order, so the frames are usually
+/// contiguous already; when they aren't (at the end of a region), the frames
+/// before the gap are lost. The frames that were given back aren't used,
+/// since they're scattered.
+pub fn allocate_dma(size: u64) -> Option<DmaRegion> {
+    let count = size.div_ceil(4096).max(1);
+
+    let start = with_frame_allocator(|allocator| {
+        let mut start = allocator.allocate_unused_frame()?;
+        let mut length = 1;
+        while length < count {
+            let frame = allocator.allocate_unused_frame()?;
+            if frame == start + length {
+                length += 1;
+            } else {
+                start = frame;
+                length = 1;
+            }
+        }
+        Some(start.start_address())
+    })?;
+
+    let virt = with_mapper(|mapper| mapper.phys_offset()) + start.as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, (count * 4096) as usize) };
+
+    Some(DmaRegion { physical: start, virt })
+}
+
+/// The kinds of regions of an [`AddressSpace`], which differ in the flags of
+/// their pages. Writable pages aren't executable, when the CPU supports that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserRegion {
+    /// Read-only and executable.
+    Code,
+
+    /// Writable, e.g. the data and the `.bss` of a program.
+    Data,
+
+    /// Writable, like [`Self::Data`].
+    Stack,
+}
+
+impl UserRegion {
+    fn flags(self) -> PageTableFlags {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        match self {
+            Self::Code => flags,
+            Self::Data | Self::Stack => flags | PageTableFlags::WRITABLE | no_execute(),
+        }
+    }
+}
+
+/// The NO_EXECUTE flag, when the CPU has it enabled, since the page tables
+/// are invalid otherwise.
+fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// The page tables of a process: a level 4 table with the entries of the
+/// kernel's table, so the kernel is mapped the same in every address space,
+/// and tables of its own for the [`USER_REGION`]. Dropping it frees those
+/// tables and the frames they map.
+///
+/// The kernel entries are copied when the address space is created, so the
+/// entries the kernel adds later are copied when a page fault finds them
+/// missing, see [`sync_kernel_entry`].
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user region, or `None` when out
+    /// of frames.
+    pub fn new() -> Option<Self> {
+        let frame = with_frame_allocator(|allocator| allocator.allocate_frame())?;
+
+        let table = unsafe { table_at(frame) };
+        let kernel = unsafe { table_at(kernel_level_4()) };
+        for (index, entry) in table.iter_mut().enumerate() {
+            match index {
+                USER_LEVEL_4_INDEX => entry.set_unused(),
+                _ => *entry = kernel[index].clone(),
+            }
+        }
+
+        Some(Self { level_4: frame })
+    }
+
+    /// Map zeroed pages for the range, with the flags of the region. Pages
+    /// that are mapped already (e.g. shared by two segments of a program) keep
+    /// their frame, and are writable or executable when either region is.
+    pub fn map_region(&mut self, range: Range<VirtAddr>, region: UserRegion) -> Result<(), MapToError<Size4KiB>> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        assert!(
+            USER_REGION.start <= range.start.as_u64() && range.end.as_u64() <= USER_REGION.end,
+            "{range:?} is outside the user region"
+        );
+
+        let flags = region.flags();
+        let first = Page::<Size4KiB>::containing_address(range.start);
+        let last = Page::<Size4KiB>::containing_address(range.end - 1u64);
+
+        let mut mapper = unsafe { self.mapper() };
+        with_frame_allocator(|allocator| {
+            for page in Page::range_inclusive(first, last) {
+                if let TranslateResult::Mapped { flags: existing, .. } = mapper.translate(page.start_address()) {
+                    let executable = (existing & flags).intersection(PageTableFlags::NO_EXECUTE);
+                    let merged = (existing | flags).difference(PageTableFlags::NO_EXECUTE) | executable;
+                    unsafe { mapper.update_flags(page, merged).expect("the page is mapped").flush() };
+                    continue;
+                }
+
+                let frame = allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    core::ptr::write_bytes(frame_pointer(frame), 0, Size4KiB::SIZE as usize);
+                    mapper.map_to(page, frame, flags, allocator)?.flush();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Copy the data to the mapped pages at the address, through the mapping
+    /// of the physical memory, so read-only pages can be filled in as well.
+    /// Returns `false` when a page isn't mapped.
+    pub fn write(&mut self, address: VirtAddr, mut data: &[u8]) -> bool {
+        let mapper = unsafe { self.mapper() };
+        let mut address = address;
+        while !data.is_empty() {
+            let Some(physical) = mapper.translate_addr(address) else {
+                return false;
+            };
+
+            let length = data.len().min((Size4KiB::SIZE - u64::from(address.page_offset())) as usize);
+            let destination = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + physical.as_u64();
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), destination as *mut u8, length) };
+            data = &data[length..];
+            address += length as u64;
+        }
+        true
+    }
+
+    /// The mapped ranges of the user region, with the writable and no-execute
+    /// flags of their pages. Neighboring pages with the same flags are merged.
+    pub fn mapped_ranges(&self) -> Vec<(Range<VirtAddr>, PageTableFlags)> {
+        let relevant = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut ranges: Vec<(Range<VirtAddr>, PageTableFlags)> = Vec::new();
+
+        let level_4 = unsafe { table_at(self.level_4) };
+        let Some(level_3) = child_table(&level_4[USER_LEVEL_4_INDEX]) else {
+            return ranges;
+        };
+        for (index_3, entry) in level_3.iter().enumerate() {
+            let Some(level_2) = child_table(entry) else {
+                continue;
+            };
+            for (index_2, entry) in level_2.iter().enumerate() {
+                let Some(level_1) = child_table(entry) else {
+                    continue;
+                };
+                for (index_1, entry) in level_1.iter().enumerate() {
+                    if !entry.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+
+                    let page = ((index_3 * 512 + index_2) * 512 + index_1) as u64;
+                    let address = VirtAddr::new(USER_REGION.start + page * Size4KiB::SIZE);
+                    let flags = entry.flags() & relevant;
+                    match ranges.last_mut() {
+                        Some((range, last)) if range.end == address && *last == flags => range.end += Size4KiB::SIZE,
+                        _ => ranges.push((address..address + Size4KiB::SIZE, flags)),
+                    }
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Make this the address space of the CPU, until another is activated or
+    /// the scheduler switches threads, see [`super::context`].
+    pub fn activate(&self) {
+        if Cr3::read().0 != self.level_4 {
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(self.level_4, flags) };
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4
+    }
+
+    /// The page tables of the address space, which don't have to be active.
+    ///
+    /// # Safety
+    /// There must be one mapper for the address space at a time.
+    unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        OffsetPageTable::new(table_at(self.level_4), offset)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(kernel_level_4(), flags) };
+        }
+
+        with_frame_allocator(|allocator| unsafe {
+            let level_4 = table_at(self.level_4);
+            free_table(&mut level_4[USER_LEVEL_4_INDEX], 3, allocator);
+            allocator.deallocate_frame(self.level_4);
+        });
+    }
+}
+
+/// Free the table of the entry, of the given level, with the tables and
+/// frames below it.
+///
+/// # Safety
+/// The frames must not be used anymore, e.g. by an active page table.
+unsafe fn free_table(entry: &mut PageTableEntry, level: u8, allocator: &mut BootInfoFrameAllocator) {
+    let Ok(frame) = entry.frame() else {
+        return;
+    };
+
+    if level > 0 {
+        for child in table_at(frame).iter_mut() {
+            free_table(child, level - 1, allocator);
+        }
+    }
+    allocator.deallocate_frame(frame);
+    entry.set_unused();
+}
+
+/// The table the entry points to, unless it's unused or maps a huge page.
+fn child_table<'a>(entry: &PageTableEntry) -> Option<&'a PageTable> {
+    match entry.frame() {
+        Ok(frame) => Some(unsafe { table_at(frame) }),
+        Err(_) => None,
+    }
+}
+
+/// The level 4 table of the kernel, see [`KERNEL_LEVEL_4`].
+pub(super) fn kernel_level_4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed)))
+}
+
+/// The page table in the frame, in the mapping of the physical memory.
+///
+/// # Safety
+/// The frame must hold a page table, which isn't referenced elsewhere.
+unsafe fn table_at<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *frame_pointer(frame).cast::<PageTable>()
+}
+
+fn frame_pointer(frame: PhysFrame) -> *mut u8 {
+    (PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + frame.start_address().as_u64()) as *mut u8
+}
+
+/// Copy the entry of the kernel's level 4 table for the address to the active
+/// table of an [`AddressSpace`], when it's missing there. Called by the page
+/// fault handler, which returns when this did, so the access is retried.
+/// Doesn't lock anything.
+pub fn sync_kernel_entry(address: VirtAddr) -> bool {
+    let index = usize::from(address.p4_index());
+    let active = Cr3::read().0;
+    if active == kernel_level_4() || index == USER_LEVEL_4_INDEX {
+        return false;
+    }
+
+    let (table, kernel) = unsafe { (table_at(active), table_at(kernel_level_4())) };
+    if table[index].is_unused() && !kernel[index].is_unused() {
+        table[index] = kernel[index].clone();
+        return true;
+    }
+    false
+}
+
+/// Whether the address is mapped accessible to user mode, and writable if
+/// `write` is set, in the active page table, e.g. to check the pointers of
+/// the system calls.
+pub fn is_user_accessible(address: VirtAddr, write: bool) -> bool {
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut frame = Cr3::read().0;
+    let indexes = [address.p4_index(), address.p3_index(), address.p2_index(), address.p1_index()];
+    for (level, index) in indexes.into_iter().enumerate() {
+        let entry = &unsafe { table_at(frame) }[index];
+        if !entry.flags().contains(required) {
+            return false;
+        }
+        if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    unreachable!()
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+pub struct BootInfoFrameAllocator {
+    memory_regions: &'static [MemoryRegion],
+    next: usize,
+
+    /// Usable memory that isn't handed out, see [`Self::reserve`].
+    reserved: Range<u64>,
+
+    /// The start addresses of the frames that aren't handed out, see
+    /// [`Self::exclude`].
+    excluded: [u64; MAX_EXCLUDED_FRAMES],
+    excluded_count: usize,
+
+    /// The frames that were given back, which are handed out again first.
+    /// Every free frame starts with the physical address of the next one,
+    /// see [`FrameDeallocator::deallocate_frame`].
+    free: Option<PhysFrame>,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
+        BootInfoFrameAllocator {
+            memory_regions: &*memory_regions,
+            next: 0,
+            reserved: 0..0,
+            excluded: [0; MAX_EXCLUDED_FRAMES],
+            excluded_count: 0,
+            free: None,
+        }
+    }
+
+    /// Never hand out the frames in the given physical range, which must be
+    /// done before the first frame is allocated, since the frames are
+    /// counted from the start of the usable memory.
+    pub fn reserve(&mut self, range: Range<u64>) {
+        assert_eq!(self.next, 0, "frames must be reserved before allocating");
+        self.reserved = range;
+    }
+
+    /// Never hand out the given frame, e.g. because it's faulty. Like
+    /// [`Self::reserve`], this must be done before the first allocation.
+    /// Returns `false` when too many frames are excluded already.
+    pub fn exclude(&mut self, frame: PhysFrame) -> bool {
+        assert_eq!(self.next, 0, "frames must be excluded before allocating");
+        if self.excluded_count == MAX_EXCLUDED_FRAMES {
+            return false;
+        }
+
+        self.excluded[self.excluded_count] = frame.start_address().as_u64();
+        self.excluded_count += 1;
+        true
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        // get usable regions from memory map
+        let regions = self.memory_regions.iter();
+        let usable_regions = regions
+            .filter(|r| r.kind == MemoryRegionKind::Usable);
+        // map each region to its address range
+        let addr_ranges = usable_regions
+            .map(|r| r.start..r.end);
+        // transform to an iterator of frame start addresses
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096))
+            .filter(|addr| !self.reserved.contains(addr))
+            .filter(|addr| !self.excluded[..self.excluded_count].contains(addr));
+        // create `PhysFrame` types from the start addresses
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// The frames that were handed out by [`FrameAllocator::allocate_frame`],
+    /// without the ones that were given back since.
+    pub fn allocated_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        let mut free: Vec<PhysFrame> = self.free_frames().collect();
+        free.sort_unstable();
+        self.usable_frames().take(self.next).filter(move |frame| free.binary_search(frame).is_err())
+    }
+
+    /// The frames that were given back, in the order they're handed out again.
+    fn free_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        core::iter::successors(self.free, |frame| unsafe { next_free_frame(*frame) })
+    }
+
+    pub fn allocate_frame_from_physical(&mut self, ptr: PhysAddr) -> Option<PhysFrame> {
+        let ptr = ptr.align_down(4096u64);
+        for frame in self.usable_frames() {
+            if frame.start_address() == ptr {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// Hand out a frame that was never handed out before, skipping the ones
+    /// that were given back.
+    pub fn allocate_unused_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let Some(frame) = self.free else {
+            return self.allocate_unused_frame();
+        };
+
+        self.free = unsafe { next_free_frame(frame) };
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Give back a frame, which must not be in use anymore.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(u64::MAX, |next| next.start_address().as_u64());
+        free_list_entry(frame).write(next);
+        self.free = Some(frame);
+    }
+}
+
+/// Where a free frame keeps the address of the next, in the mapping of the
+/// physical memory.
+fn free_list_entry(frame: PhysFrame) -> *mut u64 {
+    frame_pointer(frame).cast()
+}
+
+/// The frame after the free frame in the free list.
+///
+/// # Safety
+/// The frame must be on the free list.
+unsafe fn next_free_frame(frame: PhysFrame) -> Option<PhysFrame> {
+    let next = free_list_entry(frame).read();
+    (next != u64::MAX).then(|| PhysFrame::containing_address(PhysAddr::new(next)))
+}
@@ -0,0 +1,124 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Support for x86_64 processors.
+
+use bootloader_api::BootInfo;
+use log::trace;
+use x86_64::{
+    instructions,
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+};
+
+pub mod context;
+pub mod gdt;
+pub mod interrupts;
+pub mod memory;
+pub mod port;
+pub mod serial;
+pub mod string;
+pub mod syscall;
+pub mod user;
+
+/// The frequency of the periodic timer, which is the PIT.
+pub const TICKS_PER_SECOND: usize = 1000;
+
+/// Load the GDT and the IDT, enable the system calls and SSE, and detect the
+/// CPU features we care about.
+pub fn init() {
+    gdt::init();
+    interrupts::init_idt();
+    syscall::init();
+    enable_sse();
+    string::init();
+}
+
+/// Let the programs in user mode use the x87 and SSE instructions, of which
+/// the registers are saved with `fxsave` (see [`context::FpuState`]), with
+/// their exceptions raised as #MF and #XM instead of through the PIC.
+fn enable_sse() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// Initialize the legacy PIC, which is used until (or if) the APIC can be
+/// set up.
+pub fn init_legacy_interrupt_controller() {
+    trace!("Initializing the PIC");
+    unsafe { interrupts::PICS.lock().initialize() };
+}
+
+/// Switch to the APIC, which needs the ACPI tables.
+pub fn init_interrupt_controller(boot_info: &BootInfo) {
+    if let Err(e) = interrupts::apic::init(boot_info) {
+        trace!("Failed to initialize APIC: {e:?}, continuing with the PIC");
+    } else {
+        unsafe { interrupts::PICS.lock().disable() };
+    }
+}
+
+pub fn enable_interrupts() {
+    instructions::interrupts::enable();
+}
+
+pub fn disable_interrupts() {
+    instructions::interrupts::disable();
+}
+
+pub fn are_interrupts_enabled() -> bool {
+    instructions::interrupts::are_enabled()
+}
+
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    instructions::interrupts::without_interrupts(f)
+}
+
+/// Halt until the next interrupt.
+pub fn halt() {
+    instructions::hlt();
+}
+
+/// Atomically enable interrupts and halt until the next one, so an interrupt
+/// arriving in between can't be missed.
+pub fn wait_for_interrupt() {
+    instructions::interrupts::enable_and_hlt();
+}
+
+/// The number of timer ticks since the timer was started.
+pub fn ticks() -> usize {
+    interrupts::TIMER.lock().read()
+}
+
+/// A free-running cycle counter for measuring short durations, which is the
+/// time stamp counter.
+pub fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// A random number from the CPU, which is RDRAND, or `None` when the CPU
+/// doesn't have it or it ran out of entropy.
+pub fn hardware_random() -> Option<u64> {
+    instructions::random::RdRand::new()?.get_u64()
+}
+
+/// The number of CPUs that run kernel code, which is only the bootstrap
+/// processor until the others are started.
+pub fn cpu_count() -> usize {
+    1
+}
+
+/// The index of the CPU we're running on, below [`cpu_count`].
+pub fn cpu_index() -> usize {
+    0
+}
+
+/// Like [`ticks`], but returns `None` instead of deadlocking when called
+/// while the timer state is locked, e.g. when panicking in the timer handler.
+pub fn try_ticks() -> Option<usize> {
+    interrupts::TIMER.try_lock().map(|timer| timer.read())
+}
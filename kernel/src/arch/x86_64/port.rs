@@ -0,0 +1,226 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! An audit layer around I/O port accesses, to diagnose misbehaving devices
+//! and to catch AML code touching ports it shouldn't. It is selected with the
+//! `portaudit` boot parameter:
+//!
+//! | Value   | Behavior                                                       |
+//! |---------|----------------------------------------------------------------|
+//! | (unset) | Accesses aren't tracked                                        |
+//! | `count` | Count the reads and writes per port, shown by `ports`          |
+//! | `log`   | Count, and trace every access with the value (target `portio`) |
+//!
+//! Accesses are made from interrupt handlers as well, so the counters are a
+//! fixed table of atomics, which never allocates or locks.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use log::trace;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+use crate::meta::BootParameters;
+
+/// The number of distinct ports that can be counted. Accesses to ports after
+/// the table is full are still logged, but not counted.
+const MAX_TRACKED_PORTS: usize = 64;
+
+const MODE_UNKNOWN: u8 = 0;
+const MODE_OFF: u8 = 1;
+const MODE_COUNT: u8 = 2;
+const MODE_LOG: u8 = 3;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_UNKNOWN);
+
+const EMPTY_ENTRY: PortStatistics = PortStatistics::new();
+static STATISTICS: [PortStatistics; MAX_TRACKED_PORTS] = [EMPTY_ENTRY; MAX_TRACKED_PORTS];
+
+/// The subsystem that accesses a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PortUser {
+    Pit = 1,
+    Pci = 2,
+    Ps2 = 3,
+    Aml = 4,
+    Acpi = 5,
+    Display = 6,
+    Virtio = 7,
+}
+
+impl PortUser {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Pit => "PIT",
+            Self::Pci => "PCI",
+            Self::Ps2 => "PS/2",
+            Self::Aml => "AML",
+            Self::Acpi => "ACPI",
+            Self::Display => "display",
+            Self::Virtio => "virtio",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Pit),
+            2 => Some(Self::Pci),
+            3 => Some(Self::Ps2),
+            4 => Some(Self::Aml),
+            5 => Some(Self::Acpi),
+            6 => Some(Self::Display),
+            7 => Some(Self::Virtio),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// An I/O port of which the accesses are audited.
+pub struct AuditedPort<T> {
+    port: Port<T>,
+    number: u16,
+    user: PortUser,
+}
+
+impl<T> AuditedPort<T> {
+    pub const fn new(number: u16, user: PortUser) -> Self {
+        Self {
+            port: Port::new(number),
+            number,
+            user,
+        }
+    }
+}
+
+impl<T: PortRead + Into<u32> + Copy> AuditedPort<T> {
+    /// ## Safety
+    /// See [`Port::read`].
+    pub unsafe fn read(&mut self) -> T {
+        let value = self.port.read();
+        record(self.number, self.user, Access::Read, value.into());
+        value
+    }
+}
+
+impl<T: PortWrite + Into<u32> + Copy> AuditedPort<T> {
+    /// ## Safety
+    /// See [`Port::write`].
+    pub unsafe fn write(&mut self, value: T) {
+        record(self.number, self.user, Access::Write, value.into());
+        self.port.write(value);
+    }
+}
+
+/// The access counters of a single port.
+pub struct PortStatistics {
+    /// The port number plus one, so zero means the entry is unused.
+    port: AtomicU32,
+    user: AtomicU8,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl PortStatistics {
+    const fn new() -> Self {
+        Self {
+            port: AtomicU32::new(0),
+            user: AtomicU8::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        (self.port.load(Ordering::Relaxed) - 1) as u16
+    }
+
+    /// The subsystem that accessed the port first.
+    pub fn user(&self) -> Option<PortUser> {
+        PortUser::from_u8(self.user.load(Ordering::Relaxed))
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether accesses are tracked at all (the `portaudit` boot parameter).
+pub fn is_enabled() -> bool {
+    mode() != MODE_OFF
+}
+
+/// The ports that were accessed since boot (or the last [`reset`]).
+pub fn statistics() -> impl Iterator<Item = &'static PortStatistics> {
+    STATISTICS.iter().filter(|entry| entry.port.load(Ordering::Relaxed) != 0)
+}
+
+/// Reset the counters of the ports, but keep the table entries.
+pub fn reset() {
+    for entry in &STATISTICS {
+        entry.reads.store(0, Ordering::Relaxed);
+        entry.writes.store(0, Ordering::Relaxed);
+    }
+}
+
+fn mode() -> u8 {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_UNKNOWN => {
+            let mode = match BootParameters::get("portaudit") {
+                Some("count") => MODE_COUNT,
+                Some("log") => MODE_LOG,
+                _ => MODE_OFF,
+            };
+            MODE.store(mode, Ordering::Relaxed);
+            mode
+        }
+        mode => mode,
+    }
+}
+
+fn record(port: u16, user: PortUser, access: Access, value: u32) {
+    let mode = mode();
+    if mode == MODE_OFF {
+        return;
+    }
+
+    if mode == MODE_LOG {
+        trace!(target: "portio", "{} {access:?} 0x{port:04x} value 0x{value:x}", user.name());
+    }
+
+    let Some(entry) = find_or_insert(port, user) else {
+        return;
+    };
+
+    match access {
+        Access::Read => entry.reads.fetch_add(1, Ordering::Relaxed),
+        Access::Write => entry.writes.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+fn find_or_insert(port: u16, user: PortUser) -> Option<&'static PortStatistics> {
+    let key = port as u32 + 1;
+
+    for entry in &STATISTICS {
+        match entry.port.compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => {
+                entry.user.store(user as u8, Ordering::Relaxed);
+                return Some(entry);
+            }
+            Err(existing) if existing == key => return Some(entry),
+            Err(_) => continue,
+        }
+    }
+
+    None
+}
@@ -0,0 +1,270 @@
+//! Output to the first serial port (COM1).
+//!
+//! Writing waits for the UART for every character, which dominates the boot
+//! time when trace logging is enabled. Output is therefore queued in a ring
+//! buffer, which is drained into the transmit FIFO of the UART whenever we
+//! print and from its "transmitter holding register empty" interrupt.
+//!
+//! The buffer is flushed synchronously when printing from interrupt handlers
+//! (so their output doesn't overtake what was printed before), when it's
+//! full, and after [`set_synchronous`] was called, which the panic handler
+//! does since interrupts might never arrive again.
+//!
+//! Input is only received while the shell is routed to the serial port (see
+//! [`Console`](crate::meta::Console)), from the "received data available"
+//! interrupt into another ring buffer, which [`poll_receive`] reads.
+//!
+//! ### References:
+//! - [OSDev Wiki: Serial Ports](https://wiki.osdev.org/Serial_Ports)
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use uart_16550::SerialPort;
+use lazy_static::lazy_static;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::sync::Spinlock;
+
+const COM1: u16 = 0x3F8;
+
+/// The size of the transmit FIFO of the 16550.
+const FIFO_SIZE: usize = 16;
+const TX_BUFFER_SIZE: usize = 16 * 1024;
+const RX_BUFFER_SIZE: usize = 256;
+
+const REGISTER_INTERRUPT_ENABLE: u16 = COM1 + 1;
+const REGISTER_INTERRUPT_IDENTIFICATION: u16 = COM1 + 2;
+const REGISTER_LINE_STATUS: u16 = COM1 + 5;
+const REGISTER_SCRATCH: u16 = COM1 + 7;
+
+const INTERRUPT_ENABLE_RX_AVAILABLE: u8 = 1 << 0;
+const INTERRUPT_ENABLE_TX_EMPTY: u8 = 1 << 1;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TX_EMPTY: u8 = 1 << 5;
+
+static SYNCHRONOUS: AtomicBool = AtomicBool::new(false);
+static RECEIVING: AtomicBool = AtomicBool::new(false);
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+
+lazy_static! {
+    pub static ref SERIAL1: Spinlock<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init();
+        Spinlock::new(serial_port)
+    };
+
+    static ref TX_BUFFER: ArrayQueue<u8> = ArrayQueue::new(TX_BUFFER_SIZE);
+    static ref RX_BUFFER: ArrayQueue<u8> = ArrayQueue::new(RX_BUFFER_SIZE);
+}
+
+/// Writes into the ring buffer, flushing it when it's full.
+struct BufferedWriter;
+
+impl Write for BufferedWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            while TX_BUFFER.push(byte).is_err() {
+                flush();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes directly to the UART, waiting for every character.
+struct SynchronousWriter;
+
+impl Write for SynchronousWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            wait_until_transmitter_empty();
+            unsafe { Port::<u8>::new(COM1).write(byte) };
+        }
+
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        // Make sure the UART is initialized.
+        lazy_static::initialize(&SERIAL1);
+
+        _ = BufferedWriter.write_fmt(args);
+
+        if SYNCHRONOUS.load(Ordering::Relaxed) {
+            flush();
+        } else {
+            fill_fifo();
+        }
+    });
+}
+
+pub fn print_in_interrupt(args: ::core::fmt::Arguments) {
+    lazy_static::initialize(&SERIAL1);
+
+    flush();
+    _ = SynchronousWriter.write_fmt(args);
+}
+
+/// Whether there is a UART at COM1, since reading an absent port always
+/// yields 0xFF, which looks like the transmitter is empty.
+pub fn is_present() -> bool {
+    let mut scratch = Port::<u8>::new(REGISTER_SCRATCH);
+    unsafe {
+        scratch.write(0xAE);
+        scratch.read() == 0xAE
+    }
+}
+
+/// From now on, wait until all output has been transmitted, for when
+/// interrupts can't be relied upon anymore.
+pub fn set_synchronous() {
+    SYNCHRONOUS.store(true, Ordering::Relaxed);
+    flush();
+}
+
+/// Transmit everything in the buffer, waiting for the UART.
+pub fn flush() {
+    interrupts::without_interrupts(|| {
+        while !TX_BUFFER.is_empty() {
+            wait_until_transmitter_empty();
+            fill_fifo();
+        }
+    });
+}
+
+/// Start or stop receiving input, which is dropped otherwise.
+pub fn set_receiving(enabled: bool) {
+    lazy_static::initialize(&SERIAL1);
+    lazy_static::initialize(&RX_BUFFER);
+
+    interrupts::without_interrupts(|| {
+        RECEIVING.store(enabled, Ordering::Relaxed);
+
+        let mut interrupt_enable = Port::<u8>::new(REGISTER_INTERRUPT_ENABLE);
+        unsafe {
+            let value = interrupt_enable.read();
+            interrupt_enable.write(if enabled {
+                value | INTERRUPT_ENABLE_RX_AVAILABLE
+            } else {
+                value & !INTERRUPT_ENABLE_RX_AVAILABLE
+            });
+        }
+    });
+}
+
+/// Take the next byte that was received, see [`set_receiving`].
+pub fn poll_receive(cx: &mut Context) -> Poll<u8> {
+    if let Some(byte) = RX_BUFFER.pop() {
+        return Poll::Ready(byte);
+    }
+
+    RX_WAKER.register(cx.waker());
+    match RX_BUFFER.pop() {
+        Some(byte) => {
+            RX_WAKER.take();
+            Poll::Ready(byte)
+        }
+        None => Poll::Pending,
+    }
+}
+
+/// Called by the interrupt handler of the serial port.
+pub(crate) fn handle_interrupt() {
+    // Reading the identification register acknowledges the interrupt.
+    _ = unsafe { Port::<u8>::new(REGISTER_INTERRUPT_IDENTIFICATION).read() };
+    receive();
+    fill_fifo();
+}
+
+/// Move the received bytes from the UART into the buffer, dropping them when
+/// the buffer is full or input isn't received.
+fn receive() {
+    let mut line_status = Port::<u8>::new(REGISTER_LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    let mut received = false;
+
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        if RECEIVING.load(Ordering::Relaxed) {
+            received |= RX_BUFFER.push(byte).is_ok();
+        }
+    }
+
+    if received {
+        RX_WAKER.wake();
+    }
+}
+
+/// Move as much as the FIFO can hold from the buffer, without waiting. The
+/// interrupt is enabled as long as there is output left, so we get notified
+/// when the FIFO is empty again.
+fn fill_fifo() {
+    if unsafe { Port::<u8>::new(REGISTER_LINE_STATUS).read() } & LINE_STATUS_TX_EMPTY == 0 {
+        return;
+    }
+
+    let mut data = Port::<u8>::new(COM1);
+    for _ in 0..FIFO_SIZE {
+        match TX_BUFFER.pop() {
+            Some(byte) => unsafe { data.write(byte) },
+            None => break,
+        }
+    }
+
+    let mut interrupt_enable = Port::<u8>::new(REGISTER_INTERRUPT_ENABLE);
+    unsafe {
+        let value = interrupt_enable.read();
+        let wanted = if TX_BUFFER.is_empty() {
+            value & !INTERRUPT_ENABLE_TX_EMPTY
+        } else {
+            value | INTERRUPT_ENABLE_TX_EMPTY
+        };
+
+        if wanted != value {
+            interrupt_enable.write(wanted);
+        }
+    }
+}
+
+fn wait_until_transmitter_empty() {
+    let mut line_status = Port::<u8>::new(REGISTER_LINE_STATUS);
+    while unsafe { line_status.read() } & LINE_STATUS_TX_EMPTY == 0 {
+        core::hint::spin_loop();
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::arch::serial::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! interrupt_print {
+    ($($arg:tt)*) => {
+        $crate::arch::serial::print_in_interrupt(format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! interrupt_println {
+    () => ($crate::interrupt_print!("\n"));
+    ($($arg:tt)*) => ($crate::interrupt_print!("{}\n", format_args!($($arg)*)))
+}
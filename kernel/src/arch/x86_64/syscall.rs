@@ -0,0 +1,98 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The entry of the system calls, which the programs in user mode make with
+//! the `syscall` instruction, see [`crate::syscall`] for the calls themselves.
+//!
+//! `syscall` doesn't switch stacks, so the entry saves the stack pointer of
+//! the program, and continues on the stack that RSP0 of the TSS points to,
+//! like an interrupt from user mode would, see [`super::user`]. There it
+//! saves what `sysretq` needs to return, enables interrupts, and calls the
+//! handler with the registers of the calling convention:
+//!
+//! | Register                  | Purpose                                       |
+//! |---------------------------|-----------------------------------------------|
+//! | RAX                       | The number of the call, and the return value  |
+//! | RDI, RSI, RDX, R10 and R8 | The arguments                                 |
+//! | RCX and R11               | Overwritten, with RIP and RFLAGS              |
+//!
+//! The other registers are preserved, apart from R9, which is cleared along
+//! with the argument registers so nothing of the kernel leaks.
+
+use core::{arch::global_asm, sync::atomic::AtomicU64};
+
+use log::trace;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use super::gdt;
+
+/// The stack pointer of the program, only until the entry pushed it onto the
+/// kernel stack, which it does with interrupts disabled.
+static USER_STACK_POINTER: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(r#"
+.global nocciolo_syscall_entry
+nocciolo_syscall_entry:
+    mov [rip + {user_stack}], rsp
+    mov rsp, [rip + {tss} + {kernel_stack}]
+    and rsp, -16
+    push qword ptr [rip + {user_stack}]
+    push r11
+    push rcx
+    sub rsp, 8
+    sti
+
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    call {handler}
+
+    cli
+    xor edi, edi
+    xor esi, esi
+    xor edx, edx
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    add rsp, 8
+    pop rcx
+    pop r11
+    pop rsp
+    sysretq
+"#,
+    user_stack = sym USER_STACK_POINTER,
+    tss = sym gdt::TSS,
+    kernel_stack = const gdt::KERNEL_STACK_OFFSET,
+    handler = sym handle,
+);
+
+extern "C" {
+    fn nocciolo_syscall_entry();
+}
+
+/// Enable `syscall` and `sysretq`, with the segments of the GDT.
+pub fn init() {
+    trace!("Enabling system calls");
+
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data).expect("the GDT has the order SYSRET expects");
+    LStar::write(VirtAddr::from_ptr(nocciolo_syscall_entry as *const ()));
+
+    // Interrupts stay disabled until the entry switched stacks.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn handle(number: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> i64 {
+    crate::syscall::dispatch(number, [a0, a1, a2, a3, a4])
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Running a program in user mode (ring 3), for the
+//! [processes](crate::process).
+//!
+//! [`enter`] saves the registers of the kernel on the stack of the calling
+//! thread, points RSP0 of the TSS right below them, and returns to user mode
+//! with `iretq`. The interrupts that arrive in user mode are therefore handled
+//! on that stack, below what was saved. When the program exits, or faults,
+//! [`leave`] throws away the stack of the handler and restores the saved
+//! registers, so `enter` returns how the program ended:
+//!
+//! | Offset from RSP0 | Saved by `enter`                            |
+//! |------------------|---------------------------------------------|
+//! | 0x00             | RFLAGS, with the interrupt flag             |
+//! | 0x08             | R15, R14, R13, R12, RBX and RBP             |
+//! | 0x38             | The return address into `enter`             |
+//! | 0x40             | Where `leave` stores the frame of a fault   |
+//!
+//! A program exits with `int 0x80` ([`EXIT_VECTOR`]), with the status in RDI,
+//! or with the exit system call, see [`exit`]. Every exception it causes ends
+//! it as well, as does a vector without a handler, see [`leave_on_fault`] and
+//! [`leave_on_interrupt`], which hand the frame the CPU pushed to `enter` as
+//! well, e.g. for a core dump.
+//! The other registers are cleared before entering user mode, so nothing of
+//! the kernel leaks to the program.
+
+use core::{
+    arch::global_asm,
+    fmt::{Display, Formatter},
+};
+
+use x86_64::{
+    structures::idt::{ExceptionVector, InterruptStackFrame, InterruptStackFrameValue},
+    PrivilegeLevel,
+    VirtAddr,
+};
+
+use super::gdt;
+
+/// The vector of the software interrupt that ends the program.
+pub const EXIT_VECTOR: u8 = 0x80;
+
+/// RFLAGS in user mode: the reserved bit and the interrupt flag.
+const USER_RFLAGS: u64 = (1 << 9) | (1 << 1);
+
+/// Where the pointer to the fault frame of [`enter`] is, above RSP0: the
+/// seventh argument of `nocciolo_enter_user`, which the caller passes on the
+/// stack, right above the return address.
+const FAULT_FRAME_OFFSET: u64 = 0x40;
+
+/// How the program in user mode ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserExit {
+    /// The program exited through [`EXIT_VECTOR`] or the exit system call.
+    Exited(u64),
+
+    /// The program caused an exception, at the instruction pointer.
+    Fault { vector: ExceptionVector, instruction_pointer: VirtAddr },
+
+    /// An interrupt without a handler arrived while the program ran, which
+    /// might have been caused by it.
+    UnexpectedInterrupt { vector: u8, instruction_pointer: VirtAddr },
+}
+
+impl Display for UserExit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Exited(status) => write!(f, "exited with status {status}"),
+            Self::Fault { vector, instruction_pointer } => {
+                let name = match vector {
+                    ExceptionVector::Division => "division error",
+                    ExceptionVector::Debug => "debug exception",
+                    ExceptionVector::Overflow => "overflow",
+                    ExceptionVector::BoundRange => "bound range exceeded",
+                    ExceptionVector::InvalidOpcode => "invalid opcode",
+                    ExceptionVector::DeviceNotAvailable => "device not available",
+                    ExceptionVector::InvalidTss => "invalid TSS",
+                    ExceptionVector::SegmentNotPresent => "segment not present",
+                    ExceptionVector::Stack => "stack segment fault",
+                    ExceptionVector::GeneralProtection => "general protection fault",
+                    ExceptionVector::Page => "page fault",
+                    ExceptionVector::X87FloatingPoint => "x87 floating point exception",
+                    ExceptionVector::AlignmentCheck => "alignment check",
+                    ExceptionVector::SimdFloatingPoint => "SIMD floating point exception",
+                    _ => "exception",
+                };
+                write!(f, "killed by a {name} at {:#x}", instruction_pointer.as_u64())
+            }
+            Self::UnexpectedInterrupt { vector, instruction_pointer } => {
+                write!(f, "killed by unexpected interrupt {vector:#x} at {:#x}", instruction_pointer.as_u64())
+            }
+        }
+    }
+}
+
+/// What [`nocciolo_enter_user`] returns in RAX and RDX, see [`leave`].
+#[repr(C)]
+struct RawExit {
+    /// Zero for an exit, the vector plus one for a fault.
+    kind: u64,
+
+    /// The status of an exit, the instruction pointer of a fault.
+    value: u64,
+}
+
+global_asm!(r#"
+.global nocciolo_enter_user
+nocciolo_enter_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    cli
+    mov [rdx], rsp
+
+    push r8
+    push rsi
+    push r9
+    push rcx
+    push rdi
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+.global nocciolo_leave_user
+nocciolo_leave_user:
+    mov rsp, rdx
+    mov rax, rdi
+    mov rdx, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global nocciolo_exit_interrupt
+nocciolo_exit_interrupt:
+    cld
+    jmp {exit}
+"#, exit = sym exit_interrupt);
+
+extern "C" {
+    fn nocciolo_enter_user(
+        entry: u64,
+        stack: u64,
+        kernel_stack: *mut VirtAddr,
+        code: u64,
+        data: u64,
+        rflags: u64,
+        fault_frame: *mut u8,
+    ) -> RawExit;
+    fn nocciolo_leave_user(kind: u64, value: u64, kernel_stack: u64) -> !;
+    fn nocciolo_exit_interrupt();
+}
+
+/// Run the program in user mode from `entry`, with the stack pointer `stack`,
+/// until it exits or faults. When it faults, or an unexpected interrupt ends
+/// it, the frame the CPU pushed is stored in `fault_frame`.
+///
+/// # Safety
+/// The code and the stack must be mapped accessible to user mode, and the
+/// calling thread must not be switched to another program in user mode until
+/// this returns, since RSP0 points into its stack.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr, fault_frame: &mut Option<InterruptStackFrameValue>) -> UserExit {
+    let (code, data) = gdt::user_selectors();
+    let exit = nocciolo_enter_user(
+        entry.as_u64(),
+        stack.as_u64(),
+        gdt::kernel_stack_slot(),
+        code.0 as u64,
+        data.0 as u64,
+        USER_RFLAGS,
+        (fault_frame as *mut Option<InterruptStackFrameValue>).cast(),
+    );
+
+    let instruction_pointer = VirtAddr::new_truncate(exit.value);
+    match exit.kind {
+        0 => UserExit::Exited(exit.value),
+        kind => match ExceptionVector::try_from((kind - 1) as u8) {
+            Ok(vector) => UserExit::Fault { vector, instruction_pointer },
+            Err(_) => UserExit::UnexpectedInterrupt { vector: (kind - 1) as u8, instruction_pointer },
+        },
+    }
+}
+
+/// Return from [`enter`], throwing away the stack of the interrupt handler
+/// that calls this, after storing the frame of a fault.
+fn leave(exit: UserExit, frame: Option<&InterruptStackFrame>) -> ! {
+    let kernel_stack = unsafe { gdt::kernel_stack_slot().read() };
+    if let Some(frame) = frame {
+        unsafe {
+            let fault_frame = (kernel_stack + FAULT_FRAME_OFFSET).as_ptr::<*mut Option<InterruptStackFrameValue>>().read();
+            fault_frame.write(Some(**frame));
+        }
+    }
+
+    let (kind, value) = match exit {
+        UserExit::Exited(status) => (0, status),
+        UserExit::Fault { vector, instruction_pointer } => (vector as u8 as u64 + 1, instruction_pointer.as_u64()),
+        UserExit::UnexpectedInterrupt { vector, instruction_pointer } => (vector as u64 + 1, instruction_pointer.as_u64()),
+    };
+
+    unsafe { nocciolo_leave_user(kind, value, kernel_stack.as_u64()) }
+}
+
+/// Called by the exception handlers, ending the program instead when the
+/// exception happened in user mode.
+pub fn leave_on_fault(vector: ExceptionVector, stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        leave(UserExit::Fault { vector, instruction_pointer: stack_frame.instruction_pointer }, Some(stack_frame));
+    }
+}
+
+/// Called by the handler of the vectors without one of their own, ending the
+/// program instead of the kernel when the interrupt arrived in user mode.
+pub fn leave_on_interrupt(vector: u8, stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        leave(UserExit::UnexpectedInterrupt { vector, instruction_pointer: stack_frame.instruction_pointer }, Some(stack_frame));
+    }
+}
+
+/// End the program from a system call, see [`super::syscall`], which runs on
+/// the stack below RSP0 like the interrupt handlers do.
+pub fn exit(status: u64) -> ! {
+    leave(UserExit::Exited(status), None)
+}
+
+/// The handler of [`EXIT_VECTOR`], jumped to by `nocciolo_exit_interrupt`
+/// with the status the program put in RDI.
+extern "C" fn exit_interrupt(status: u64) -> ! {
+    leave(UserExit::Exited(status), None)
+}
+
+/// The address of the handler of [`EXIT_VECTOR`], which reads RDI before
+/// anything can change it, so it isn't an `x86-interrupt` function.
+pub fn exit_handler_address() -> VirtAddr {
+    VirtAddr::from_ptr(nocciolo_exit_interrupt as *const ())
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The ChaCha20 stream cipher, with a 96-bit nonce and 32-bit block counter.
+//!
+//! ### References:
+//! - [RFC 8439: ChaCha20 and Poly1305 for IETF Protocols](https://www.rfc-editor.org/rfc/rfc8439)
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const BLOCK_SIZE: usize = 64;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646E, 0x79622D32, 0x6B206574];
+
+#[derive(Debug, Clone)]
+pub struct ChaCha20 {
+    state: [u32; 16],
+    keystream: [u8; BLOCK_SIZE],
+
+    /// The number of bytes of the keystream that have been used.
+    position: usize,
+}
+
+impl ChaCha20 {
+    #[must_use]
+    pub fn new(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], counter: u32) -> Self {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CONSTANTS);
+        for (idx, chunk) in key.chunks_exact(4).enumerate() {
+            state[4 + idx] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        state[12] = counter;
+        for (idx, chunk) in nonce.chunks_exact(4).enumerate() {
+            state[13 + idx] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+
+        Self {
+            state,
+            keystream: [0; BLOCK_SIZE],
+            position: BLOCK_SIZE,
+        }
+    }
+
+    /// Produce the keystream block for the current counter and advance it.
+    #[must_use]
+    pub fn next_block(&mut self) -> [u8; BLOCK_SIZE] {
+        let mut working = self.state;
+        for _ in 0..10 {
+            // Column rounds
+            quarter_round(&mut working, 0, 4, 8, 12);
+            quarter_round(&mut working, 1, 5, 9, 13);
+            quarter_round(&mut working, 2, 6, 10, 14);
+            quarter_round(&mut working, 3, 7, 11, 15);
+
+            // Diagonal rounds
+            quarter_round(&mut working, 0, 5, 10, 15);
+            quarter_round(&mut working, 1, 6, 11, 12);
+            quarter_round(&mut working, 2, 7, 8, 13);
+            quarter_round(&mut working, 3, 4, 9, 14);
+        }
+
+        let mut block = [0u8; BLOCK_SIZE];
+        for (idx, chunk) in block.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&working[idx].wrapping_add(self.state[idx]).to_le_bytes());
+        }
+
+        self.state[12] = self.state[12].wrapping_add(1);
+        block
+    }
+
+    /// Encrypt or decrypt the data in place, continuing where the previous
+    /// call left off in the keystream.
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.position == BLOCK_SIZE {
+                self.keystream = self.next_block();
+                self.position = 0;
+            }
+
+            *byte ^= self.keystream[self.position];
+            self.position += 1;
+        }
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
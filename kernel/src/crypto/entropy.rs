@@ -0,0 +1,461 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The entropy pool of the kernel, and the random numbers derived from it for
+//! e.g. ASLR and TCP sequence numbers (see [`fill`]).
+//!
+//! A [`Source`] is declared as a static next to the code that samples it, and
+//! adds its samples to the pool:
+//!
+//! | Source       | Samples                                               | Per byte      |
+//! |--------------|-------------------------------------------------------|---------------|
+//! | `tsc-jitter` | The duration of a memory walk, by the `entropy` task  | Half a bit    |
+//! | `irq-timing` | The time stamp counter at the device interrupts       | Half a bit    |
+//! | `rdrand`     | The random number generator of the CPU                | Two bits      |
+//! | `virtio-rng` | The entropy device of the host                        | Four bits     |
+//!
+//! Every byte of the samples goes through the continuous health tests of NIST
+//! SP 800-90B: the repetition count test and the adaptive proportion test,
+//! with a false positive rate of 2^-20. The first [`STARTUP_SAMPLES`] are
+//! only tested, and a source that fails a test isn't used anymore until the
+//! next boot. The `rngtest` shell command shows how much each source
+//! contributed.
+//!
+//! The samples are hashed into the pool with SHA-256, and once the pool holds
+//! [`RESEED_BITS`] bits of entropy, they're hashed into the key of the
+//! generator, which is ChaCha20 with fast key erasure: the first bytes of
+//! every request become the next key.
+//!
+//! ### References:
+//! - [NIST SP 800-90B: Recommendation for the Entropy Sources Used for Random Bit Generation](https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-90B.pdf)
+//! - [Daniel J. Bernstein: Fast-key-erasure random-number generators](https://blog.cr.yp.to/20170723-random.html)
+
+use alloc::vec::Vec;
+use core::{
+    hint::black_box,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use log::{error, info};
+use spin::Mutex;
+
+use crate::{arch, meta::{counters::Counter, init::HeapInitialized}, sync::Spinlock, task::timer};
+
+use super::{chacha20::{ChaCha20, KEY_SIZE, NONCE_SIZE}, sha256::Sha256};
+
+/// The samples of a source that are tested before the source is used.
+pub const STARTUP_SAMPLES: u64 = 1024;
+
+/// The entropy the pool collects before it's hashed into the key.
+pub const RESEED_BITS: u64 = 256;
+
+/// The samples of the adaptive proportion test.
+const WINDOW_SIZE: u32 = 512;
+
+/// The interrupt timings that can wait for the `entropy` task.
+const INTERRUPT_QUEUE_CAPACITY: usize = 256;
+
+const COLLECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The jitter samples taken every interval.
+const JITTER_SAMPLES: usize = 64;
+
+pub static TSC_JITTER: Source = Source::new("tsc-jitter", "Timing jitter of a memory walk", Estimate::HalfBit);
+pub static IRQ_TIMING: Source = Source::new("irq-timing", "Arrival times of the device interrupts", Estimate::HalfBit);
+pub static RDRAND: Source = Source::new("rdrand", "The RDRAND instruction of the CPU", Estimate::TwoBits);
+
+static INTERRUPT_TIMINGS: OnceCell<ArrayQueue<u64>> = OnceCell::uninit();
+static DROPPED: Counter = Counter::new("entropy.dropped", "Interrupt timings dropped because the entropy task was behind");
+
+static SOURCES: Mutex<Vec<&'static Source>> = Mutex::new(Vec::new());
+static POOL: Spinlock<Pool> = Spinlock::new(Pool::new());
+
+/// The min-entropy a source claims per byte of its samples, which sets the
+/// cutoffs of the health tests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Estimate {
+    HalfBit,
+    TwoBits,
+    FourBits,
+}
+
+impl Estimate {
+    /// The entropy per byte in eighths of a bit.
+    const fn eighths(&self) -> u64 {
+        match self {
+            Self::HalfBit => 4,
+            Self::TwoBits => 16,
+            Self::FourBits => 32,
+        }
+    }
+
+    /// The number of identical bytes in a row that fails the repetition
+    /// count test, which is `1 + ceil(20 / H)`.
+    const fn repetition_cutoff(&self) -> u32 {
+        match self {
+            Self::HalfBit => 41,
+            Self::TwoBits => 11,
+            Self::FourBits => 6,
+        }
+    }
+
+    /// The number of times the first byte of a window may occur in it, which
+    /// is `1 + CRITBINOM(512, 2^-H, 1 - 2^-20)`.
+    const fn proportion_cutoff(&self) -> u32 {
+        match self {
+            Self::HalfBit => 410,
+            Self::TwoBits => 177,
+            Self::FourBits => 62,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SourceState {
+    /// No samples were added yet.
+    Unused = 0,
+
+    /// The first [`STARTUP_SAMPLES`] are being tested.
+    Startup = 1,
+    Healthy = 2,
+
+    /// A health test failed, so the samples are ignored.
+    Failed = 3,
+}
+
+impl SourceState {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Unused => "unused",
+            Self::Startup => "startup",
+            Self::Healthy => "healthy",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Startup,
+            2 => Self::Healthy,
+            3 => Self::Failed,
+            _ => Self::Unused,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthTest {
+    RepetitionCount,
+    AdaptiveProportion,
+}
+
+pub struct Source {
+    name: &'static str,
+    description: &'static str,
+    estimate: Estimate,
+    registered: AtomicBool,
+    state: AtomicU8,
+    samples: AtomicU64,
+
+    /// The entropy credited to the pool, in eighths of a bit.
+    credited: AtomicU64,
+    health: Spinlock<HealthTests>,
+}
+
+impl Source {
+    pub const fn new(name: &'static str, description: &'static str, estimate: Estimate) -> Self {
+        Self {
+            name,
+            description,
+            estimate,
+            registered: AtomicBool::new(false),
+            state: AtomicU8::new(SourceState::Unused as u8),
+            samples: AtomicU64::new(0),
+            credited: AtomicU64::new(0),
+            health: Spinlock::new(HealthTests::new()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    pub fn state(&self) -> SourceState {
+        SourceState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// The number of bytes of samples added so far.
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    /// The entropy credited to the pool so far, in bits.
+    pub fn credited_bits(&self) -> u64 {
+        self.credited.load(Ordering::Relaxed) / 8
+    }
+
+    /// Show the source in `rngtest`, before it adds any samples.
+    pub fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            SOURCES.lock().push(self);
+        }
+    }
+
+    /// Add a timing sample, of which the least significant byte is tested
+    /// and credited, since the others barely change.
+    ///
+    /// Must not be called by interrupt handlers, see [`add_interrupt_timing`].
+    pub fn add_timing(&'static self, sample: u64) {
+        self.add(&sample.to_le_bytes(), 1);
+    }
+
+    /// Add random bytes, of which each is tested and credited.
+    ///
+    /// Must not be called by interrupt handlers.
+    pub fn add_bytes(&'static self, data: &[u8]) {
+        self.add(data, data.len());
+    }
+
+    /// Test the first `tested` bytes of the data, and mix all of it into the
+    /// pool when they pass.
+    fn add(&'static self, data: &[u8], tested: usize) {
+        self.register();
+        if self.state() == SourceState::Failed {
+            return;
+        }
+
+        let result = {
+            let mut health = self.health.lock();
+            data[..tested].iter().try_for_each(|byte| health.test(*byte, self.estimate))
+        };
+        if let Err(test) = result {
+            self.state.store(SourceState::Failed as u8, Ordering::Relaxed);
+            error!("Entropy source `{}` failed the {test:?} test, it's not used anymore", self.name);
+            return;
+        }
+
+        let before = self.samples.fetch_add(tested as u64, Ordering::Relaxed);
+        let credit = if before + tested as u64 > STARTUP_SAMPLES {
+            _ = self.state.compare_exchange(SourceState::Startup as u8, SourceState::Healthy as u8, Ordering::Relaxed, Ordering::Relaxed);
+            self.estimate.eighths() * tested as u64
+        } else {
+            _ = self.state.compare_exchange(SourceState::Unused as u8, SourceState::Startup as u8, Ordering::Relaxed, Ordering::Relaxed);
+            0
+        };
+
+        self.credited.fetch_add(credit, Ordering::Relaxed);
+        POOL.lock().mix(self.name, data, credit);
+    }
+}
+
+/// The state of the continuous health tests of a source.
+struct HealthTests {
+    /// The last byte and the number of times in a row it occurred.
+    last: Option<u8>,
+    repetitions: u32,
+
+    /// The first byte of the window, the number of times it occurred in the
+    /// window, and the number of bytes in the window so far.
+    window_first: u8,
+    window_count: u32,
+    window_length: u32,
+}
+
+impl HealthTests {
+    const fn new() -> Self {
+        Self {
+            last: None,
+            repetitions: 0,
+            window_first: 0,
+            window_count: 0,
+            window_length: 0,
+        }
+    }
+
+    fn test(&mut self, byte: u8, estimate: Estimate) -> Result<(), HealthTest> {
+        if self.last == Some(byte) {
+            self.repetitions += 1;
+            if self.repetitions >= estimate.repetition_cutoff() {
+                return Err(HealthTest::RepetitionCount);
+            }
+        } else {
+            self.last = Some(byte);
+            self.repetitions = 1;
+        }
+
+        if self.window_length == 0 {
+            self.window_first = byte;
+            self.window_count = 1;
+        } else if byte == self.window_first {
+            self.window_count += 1;
+            if self.window_count >= estimate.proportion_cutoff() {
+                return Err(HealthTest::AdaptiveProportion);
+            }
+        }
+
+        self.window_length = (self.window_length + 1) % WINDOW_SIZE;
+        Ok(())
+    }
+}
+
+struct Pool {
+    /// The samples since the last reseed.
+    hash: Sha256,
+
+    /// The entropy of the samples in the hash, in eighths of a bit.
+    pending: u64,
+    key: [u8; KEY_SIZE],
+
+    /// Whether the key was derived from [`RESEED_BITS`] of entropy.
+    seeded: bool,
+    reseeds: u64,
+}
+
+impl Pool {
+    const fn new() -> Self {
+        Self {
+            hash: Sha256::new(),
+            pending: 0,
+            key: [0; KEY_SIZE],
+            seeded: false,
+            reseeds: 0,
+        }
+    }
+
+    fn mix(&mut self, source: &str, data: &[u8], credit: u64) {
+        self.hash.update(source.as_bytes());
+        self.hash.update(data);
+        self.pending += credit;
+
+        if self.pending >= RESEED_BITS * 8 {
+            self.reseed();
+            if !self.seeded {
+                info!("The entropy pool is seeded");
+                self.seeded = true;
+            }
+        }
+    }
+
+    /// Hash the samples into the key.
+    fn reseed(&mut self) {
+        let samples = core::mem::replace(&mut self.hash, Sha256::new()).finish();
+        let mut hash = Sha256::new();
+        hash.update(&self.key);
+        hash.update(&samples);
+        self.key = hash.finish();
+        self.pending = 0;
+        self.reseeds += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatistics {
+    pub seeded: bool,
+    pub reseeds: u64,
+
+    /// The entropy waiting for the next reseed, in bits.
+    pub pending_bits: u64,
+}
+
+/// Set up the queue of the interrupt timings, once the heap is available.
+pub fn init(_: HeapInitialized) {
+    INTERRUPT_TIMINGS.init_once(|| ArrayQueue::new(INTERRUPT_QUEUE_CAPACITY));
+    DROPPED.register();
+    for source in [&TSC_JITTER, &IRQ_TIMING, &RDRAND] {
+        source.register();
+    }
+}
+
+/// Record the arrival of a device interrupt, which the `entropy` task adds to
+/// the pool.
+///
+/// Must not block or allocate.
+pub(crate) fn add_interrupt_timing() {
+    if let Ok(queue) = INTERRUPT_TIMINGS.try_get() {
+        if queue.push(arch::cycles()).is_err() {
+            DROPPED.increment();
+        }
+    }
+}
+
+/// Fill the buffer with random bytes. Before the pool is seeded (see
+/// [`statistics`]), these are derived from whatever was collected so far.
+pub fn fill(buffer: &mut [u8]) {
+    let mut pool = POOL.lock();
+    if !pool.seeded {
+        pool.reseed();
+    }
+
+    let mut cipher = ChaCha20::new(&pool.key, &[0; NONCE_SIZE], 0);
+    let mut next_key = [0; KEY_SIZE];
+    cipher.apply_keystream(&mut next_key);
+    pool.key = next_key;
+    drop(pool);
+
+    buffer.fill(0);
+    cipher.apply_keystream(buffer);
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn statistics() -> PoolStatistics {
+    let pool = POOL.lock();
+    PoolStatistics {
+        seeded: pool.seeded,
+        reseeds: pool.reseeds,
+        pending_bits: pool.pending / 8,
+    }
+}
+
+/// The sources that were registered or added samples, in that order.
+pub fn sources() -> Vec<&'static Source> {
+    SOURCES.lock().clone()
+}
+
+/// The task that adds the samples of the sources without a task of their
+/// own.
+pub async fn run() {
+    loop {
+        if let Ok(queue) = INTERRUPT_TIMINGS.try_get() {
+            while let Some(timing) = queue.pop() {
+                IRQ_TIMING.add_timing(timing);
+            }
+        }
+
+        for _ in 0..JITTER_SAMPLES {
+            TSC_JITTER.add_timing(jitter_sample());
+        }
+
+        if let Some(value) = arch::hardware_random() {
+            RDRAND.add_bytes(&value.to_le_bytes());
+        }
+
+        timer::sleep(COLLECT_INTERVAL).await;
+    }
+}
+
+/// The duration of a walk over a buffer, which varies with the state of the
+/// caches and the pipeline.
+fn jitter_sample() -> u64 {
+    let mut buffer = [0u8; 256];
+    let start = arch::cycles();
+    let mut index = start as usize;
+    for step in 0..64 {
+        index = index.wrapping_mul(31).wrapping_add(step) % buffer.len();
+        buffer[index] = buffer[index].wrapping_add(step as u8);
+    }
+    black_box(&buffer);
+    arch::cycles().wrapping_sub(start)
+}
@@ -0,0 +1,65 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! HMAC using SHA-256.
+//!
+//! ### References:
+//! - [RFC 2104: HMAC](https://www.rfc-editor.org/rfc/rfc2104)
+
+use super::{constant_time_eq, sha256::{Sha256, BLOCK_SIZE, DIGEST_SIZE}};
+
+const INNER_PAD: u8 = 0x36;
+const OUTER_PAD: u8 = 0x5C;
+
+/// An incremental HMAC-SHA-256 calculation.
+#[derive(Debug, Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    #[must_use]
+    pub fn new(key: &[u8]) -> Self {
+        // Keys longer than a block are hashed first.
+        let mut block = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block[..DIGEST_SIZE].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|byte| byte ^ INNER_PAD));
+
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|byte| byte ^ OUTER_PAD));
+
+        Self { inner, outer }
+    }
+
+    /// Calculate the MAC of the data in one go.
+    #[must_use]
+    pub fn mac(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut hmac = Self::new(key);
+        hmac.update(data);
+        hmac.finish()
+    }
+
+    /// Check the MAC of the data in constant time.
+    #[must_use]
+    pub fn verify(key: &[u8], data: &[u8], mac: &[u8]) -> bool {
+        constant_time_eq(&Self::mac(key, data), mac)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    #[must_use]
+    pub fn finish(self) -> [u8; DIGEST_SIZE] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
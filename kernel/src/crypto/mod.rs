@@ -0,0 +1,26 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Small implementations of the cryptographic primitives the kernel needs,
+//! without any dependencies, and the entropy pool the random numbers of the
+//! kernel come from (see [`entropy`]).
+//!
+//! These are straightforward implementations of the specifications and are
+//! not hardened against side channels beyond what the algorithms provide by
+//! design.
+
+pub mod chacha20;
+pub mod entropy;
+pub mod hmac;
+pub mod sha256;
+
+/// Compare two byte strings in constant time with respect to their contents,
+/// which should be used for comparing MACs.
+#[must_use]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,113 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Integration with the debugger of the Bochs emulator.
+//!
+//! The `magic_break!()` macro triggers the magic breakpoint (`xchg bx, bx`),
+//! which requires `magic_break: enabled=1` in the `bochsrc`. The I/O debugger
+//! interface at port `0x8A00` requires Bochs to be built with
+//! `--enable-iodebug`.
+//!
+//! Both are only used when we detected that we're running under Bochs, since
+//! the ports might be used by real hardware.
+//!
+//! ### References:
+//! - [Bochs: Magic Breakpoint](https://bochs.sourceforge.io/doc/docbook/user/internal-debugger.html)
+//! - [OSDev Wiki: Bochs](https://wiki.osdev.org/Bochs)
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::port::PortWriteOnly;
+
+use crate::meta::{HypervisorKind, System};
+
+const IODEBUG_PORT: u16 = 0x8A00;
+
+const DETECTION_UNKNOWN: u8 = 0;
+const DETECTION_BOCHS: u8 = 1;
+const DETECTION_OTHER: u8 = 2;
+
+static DETECTION: AtomicU8 = AtomicU8::new(DETECTION_UNKNOWN);
+
+/// Trigger the Bochs magic breakpoint, but only when running under Bochs.
+macro_rules! magic_break {
+    () => {
+        if $crate::debug::is_bochs() {
+            x86_64::instructions::bochs_breakpoint();
+        }
+    };
+}
+
+pub(crate) use magic_break;
+
+/// Whether we are running under Bochs. The result is cached, since the
+/// detection uses CPUID, which is slow under emulation.
+pub fn is_bochs() -> bool {
+    match DETECTION.load(Ordering::Relaxed) {
+        DETECTION_BOCHS => true,
+        DETECTION_OTHER => false,
+        _ => {
+            let is_bochs = System::detect_hypervisor() == Some(HypervisorKind::Bochs);
+            DETECTION.store(if is_bochs { DETECTION_BOCHS } else { DETECTION_OTHER }, Ordering::Relaxed);
+            is_bochs
+        }
+    }
+}
+
+/// Commands of the I/O debugger interface of Bochs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum BochsDebugCommand {
+    Enable = 0x8A00,
+    ReturnToDebugger = 0x8AE0,
+    DisableInstructionTrace = 0x8AE2,
+    EnableInstructionTrace = 0x8AE3,
+    DisableRegisterTrace = 0x8AE4,
+    EnableRegisterTrace = 0x8AE5,
+    Disable = 0x8AFF,
+}
+
+pub struct BochsDebugger;
+
+impl BochsDebugger {
+    /// Send a command to the debugger. Returns whether the command was sent,
+    /// which is only the case when running under Bochs.
+    pub fn send(command: BochsDebugCommand) -> bool {
+        if !is_bochs() {
+            return false;
+        }
+
+        let mut port = PortWriteOnly::<u16>::new(IODEBUG_PORT);
+        unsafe {
+            // Commands are ignored unless the interface is enabled first, and
+            // it's disabled again so other writes to the port aren't taken as
+            // commands.
+            port.write(BochsDebugCommand::Enable as u16);
+            port.write(command as u16);
+            port.write(BochsDebugCommand::Disable as u16);
+        }
+
+        true
+    }
+
+    /// Drop into the Bochs debugger prompt.
+    pub fn enter_debugger() -> bool {
+        Self::send(BochsDebugCommand::ReturnToDebugger)
+    }
+
+    pub fn set_instruction_trace(enabled: bool) -> bool {
+        Self::send(if enabled {
+            BochsDebugCommand::EnableInstructionTrace
+        } else {
+            BochsDebugCommand::DisableInstructionTrace
+        })
+    }
+
+    pub fn set_register_trace(enabled: bool) -> bool {
+        Self::send(if enabled {
+            BochsDebugCommand::EnableRegisterTrace
+        } else {
+            BochsDebugCommand::DisableRegisterTrace
+        })
+    }
+}
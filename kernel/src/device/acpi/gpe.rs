@@ -0,0 +1,249 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The General Purpose Event blocks of the FADT, through which the chipset
+//! signals events like a lid switch, a hot-plug, or a notification of the
+//! embedded controller or a thermal zone.
+//!
+//! Each block consists of a status register followed by an enable register of
+//! the same length, with one bit per event. The events of GPE0 are numbered
+//! from zero, those of GPE1 from the base in the FADT.
+//!
+//! An event is handled by the AML method `\_GPE._Lxx` (level-triggered) or
+//! `\_GPE._Exx` (edge-triggered), where `xx` is the event number in hex,
+//! and/or by a driver that registered a handler with [`register_handler`].
+//! The events are polled by the `acpi-gpe` task instead of waiting for the
+//! SCI, which isn't routed yet.
+//!
+//! ### References:
+//! - [ACPI: General-Purpose Event Register Blocks](https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#general-purpose-event-register-blocks)
+//! - [ACPI: \_Exx, \_Lxx, and \_Qxx Methods for GPE Processing](https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#exx-lxx-and-qxx-methods-for-gpe-processing)
+
+use alloc::{format, vec::Vec};
+use core::time::Duration;
+
+use acpi::{address::AddressSpace, fadt::Fadt};
+use aml::AmlName;
+use log::{trace, warn};
+
+use crate::{
+    arch::port::{AuditedPort, PortUser},
+    meta::counters::Counter,
+    sync::Spinlock,
+    task::timer,
+};
+
+use super::{NoccioloAmlContext, ACPI_DATA};
+
+/// How often the status registers are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The AML methods only have two hex digits for the event number.
+const MAX_AML_EVENT: u32 = 0xFF;
+
+static BLOCKS: Spinlock<Vec<Block>> = Spinlock::new(Vec::new());
+
+/// The handlers registered by drivers, with the event they handle.
+static HANDLERS: Spinlock<Vec<(u32, fn(u32))>> = Spinlock::new(Vec::new());
+
+static EVENTS: Counter = Counter::new("acpi.gpe", "ACPI General Purpose Events handled");
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    status: u16,
+
+    /// The length of the status and enable registers, in bytes.
+    length: u16,
+
+    /// The number of the event of the first bit.
+    base: u32,
+}
+
+impl Block {
+    fn enable(&self) -> u16 {
+        self.status + self.length
+    }
+
+    fn contains(&self, event: u32) -> bool {
+        (self.base..self.base + self.length as u32 * 8).contains(&event)
+    }
+
+    /// The register offset and bit mask of the event.
+    fn bit(&self, event: u32) -> (u16, u8) {
+        let index = event - self.base;
+        ((index / 8) as u16, 1 << (index % 8))
+    }
+
+    fn set_enabled(&self, event: u32, enabled: bool) {
+        let (offset, mask) = self.bit(event);
+        let mut port = AuditedPort::<u8>::new(self.enable() + offset, PortUser::Acpi);
+        unsafe {
+            let value = port.read();
+            port.write(if enabled { value | mask } else { value & !mask });
+        }
+    }
+
+    /// Clear the status of the event, which is done by writing a one.
+    fn clear(&self, event: u32) {
+        let (offset, mask) = self.bit(event);
+        unsafe { AuditedPort::<u8>::new(self.status + offset, PortUser::Acpi).write(mask) };
+    }
+
+    /// The events that are both enabled and pending.
+    fn pending(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.length).flat_map(move |offset| {
+            let (status, enable) = unsafe {
+                (AuditedPort::<u8>::new(self.status + offset, PortUser::Acpi).read(),
+                 AuditedPort::<u8>::new(self.enable() + offset, PortUser::Acpi).read())
+            };
+            let pending = status & enable;
+            (0..8).filter(move |bit| pending & (1 << bit) != 0)
+                .map(move |bit| self.base + offset as u32 * 8 + bit)
+        })
+    }
+}
+
+/// The register blocks of the FADT, of which both are optional.
+fn blocks(fadt: &Fadt) -> impl Iterator<Item = Block> {
+    let gpe1_base = fadt.gpe1_base as u32;
+    [(fadt.gpe0_block(), 0), (fadt.gpe1_block(), gpe1_base)]
+        .into_iter()
+        .filter_map(|(block, base)| Some((block.ok().flatten()?, base)))
+        .filter_map(|(block, base)| {
+            if block.address_space != AddressSpace::SystemIo || block.address > u16::MAX as u64 {
+                warn!("GPE block not in System I/O Address Space: {block:#x?}");
+                return None;
+            }
+
+            // The status and enable registers share the length of the block.
+            Some(Block {
+                status: block.address as u16,
+                length: block.bit_width as u16 / 16,
+                base,
+            })
+        })
+}
+
+/// Disable every event and clear the pending ones, then enable the events
+/// that the AML has a method for.
+pub fn init(fadt: &Fadt, aml: &NoccioloAmlContext) {
+    disable_all(fadt);
+
+    let blocks: Vec<Block> = blocks(fadt).collect();
+    for block in &blocks {
+        for event in block.base..(block.base + block.length as u32 * 8).min(MAX_AML_EVENT + 1) {
+            if method(aml, event, 'L').is_some() || method(aml, event, 'E').is_some() {
+                trace!("Enabling GPE {event:#x}, which is handled by the AML");
+                block.set_enabled(event, true);
+            }
+        }
+    }
+
+    *BLOCKS.lock() = blocks;
+}
+
+/// Call the handler when the event fires, which is enabled if it wasn't
+/// already. Returns `false` when there is no such event.
+// The embedded controller and thermal zone drivers aren't there yet.
+#[allow(dead_code)]
+pub fn register_handler(event: u32, handler: fn(u32)) -> bool {
+    let Some(block) = BLOCKS.lock().iter().copied().find(|block| block.contains(event)) else {
+        return false;
+    };
+
+    HANDLERS.lock().push((event, handler));
+    block.set_enabled(event, true);
+    true
+}
+
+/// Disable every event and clear the pending ones, so no event can wake the
+/// system or run AML while entering a sleep state.
+pub fn disable_all(fadt: &Fadt) {
+    for block in blocks(fadt) {
+        trace!("Disabling {} GPEs at {:#x}", block.length * 8, block.status);
+
+        for offset in 0..block.length {
+            unsafe {
+                AuditedPort::<u8>::new(block.enable() + offset, PortUser::Acpi).write(0);
+                AuditedPort::<u8>::new(block.status + offset, PortUser::Acpi).write(0xFF);
+            }
+        }
+    }
+}
+
+/// The task that polls for and dispatches the events.
+pub async fn run() {
+    if BLOCKS.lock().is_empty() {
+        return;
+    }
+
+    EVENTS.register();
+    loop {
+        timer::sleep(POLL_INTERVAL).await;
+        poll();
+    }
+}
+
+/// Handle every pending event.
+pub fn poll() {
+    let blocks = BLOCKS.lock().clone();
+    for block in &blocks {
+        let pending: Vec<u32> = block.pending().collect();
+        for event in pending {
+            dispatch(block, event);
+        }
+    }
+}
+
+fn dispatch(block: &Block, event: u32) {
+    EVENTS.increment();
+
+    let (name, edge) = {
+        let data = ACPI_DATA.lock();
+        match data.aml.as_ref() {
+            Some(aml) => match (method(aml, event, 'E'), method(aml, event, 'L')) {
+                (Some(name), _) => (Some(name), true),
+                (None, name) => (name, false),
+            },
+            None => (None, true),
+        }
+    };
+
+    // An edge-triggered event is cleared before it is handled, so a new one
+    // isn't lost. A level-triggered one is cleared afterwards, since it would
+    // fire again until the handler silenced its source.
+    if edge {
+        block.clear(event);
+    }
+
+    if let Some(name) = name {
+        if let Some(aml) = ACPI_DATA.lock().aml.as_mut() {
+            if let Err(e) = aml.invoke_method0(&name) {
+                warn!("Failed to handle GPE {event:#x} with {name}: {e:?}");
+            }
+        }
+    }
+
+    let handlers: Vec<fn(u32)> = HANDLERS.lock().iter()
+        .filter(|(handler_event, _)| *handler_event == event)
+        .map(|(_, handler)| *handler)
+        .collect();
+    for handler in handlers {
+        handler(event);
+    }
+
+    if !edge {
+        block.clear(event);
+    }
+}
+
+/// The `\_GPE._Lxx` or `\_GPE._Exx` method of the event, if the AML has it.
+fn method(aml: &NoccioloAmlContext, event: u32, kind: char) -> Option<AmlName> {
+    if event > MAX_AML_EVENT {
+        return None;
+    }
+
+    let name = AmlName::from_str(&format!("\\_GPE._{kind}{event:02X}")).ok()?;
+    aml.get(&name).ok()?.require_method().ok()?;
+    Some(name)
+}
@@ -3,10 +3,12 @@
 
 use core::ptr::NonNull;
 use acpi::{AcpiHandler, PhysicalMapping};
+use log::trace;
 use x86_64::{PhysAddr, VirtAddr};
-use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
 use crate::allocator::page::PageAllocator;
-use crate::memory::{with_frame_allocator, with_mapper};
+use crate::arch::memory::{with_frame_allocator, with_mapper};
+use crate::meta::memory_map;
 use crate::serial_println;
 
 static LOG_ENABLED: bool = false;
@@ -14,20 +16,64 @@ static LOG_ENABLED: bool = false;
 #[derive(Clone, Copy, Debug)]
 pub struct NoccioloAcpiHandler;
 
-impl AcpiHandler for NoccioloAcpiHandler {
-    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+/// Why a physical region can't be mapped, see
+/// [`NoccioloAcpiHandler::try_map_physical_region`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The size is zero.
+    Empty,
+
+    /// The address isn't aligned for the type it's mapped as.
+    Unaligned { align: usize },
+
+    /// The region wraps around, or lies beyond the physical address space.
+    OutOfRange,
+
+    /// The region overlaps the kernel image or frames of the frame allocator,
+    /// which the mapping would alias.
+    KernelMemory { start: u64, end: u64 },
+
+    /// A page of the mapping is already mapped to another frame.
+    AlreadyMapped { page: u64, frame: u64 },
+
+    /// A page of the mapping lies in a huge page that is already mapped.
+    InHugePage { page: u64 },
+
+    /// No frame could be allocated for a page table.
+    OutOfFrames,
+}
+
+impl NoccioloAcpiHandler {
+    /// Map the physical region uncached, like [`AcpiHandler::map_physical_region`],
+    /// but returning an error for a region that can't (or shouldn't) be
+    /// mapped, e.g. one an AML method or a broken table points to.
+    pub unsafe fn try_map_physical_region<T>(&self, physical_address: usize, size: usize) -> Result<PhysicalMapping<Self, T>, MapError> {
         if LOG_ENABLED {
             serial_println!("Mapping {physical_address:x} size {size:x}");
         }
 
-        let start = PhysAddr::new(physical_address as _).align_down(4096u64);
-        let end = PhysAddr::new((physical_address + size) as _).align_up(4096u64);
+        if size == 0 {
+            return Err(MapError::Empty);
+        }
+
+        if physical_address % align_of::<T>() != 0 {
+            return Err(MapError::Unaligned { align: align_of::<T>() });
+        }
+
+        let end = physical_address.checked_add(size).ok_or(MapError::OutOfRange)?;
+        let start = PhysAddr::try_new(physical_address as _).map_err(|_| MapError::OutOfRange)?.align_down(4096u64);
+        let end = PhysAddr::try_new(end as _).map_err(|_| MapError::OutOfRange)?.align_up(4096u64);
+
+        if let Some(region) = memory_map::kernel_memory_in(physical_address as u64, (physical_address + size) as u64) {
+            return Err(MapError::KernelMemory { start: region.start, end: region.end });
+        }
+
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
 
         let page_count = (end - start) as usize / 4096;
         let virt = PageAllocator::allocate_n(page_count);
 
-        do_map_region(start, end, virt, flags);
+        do_map_region(start, end, virt, flags)?;
 
         let mapped_length = (end.as_u64() - start.as_u64()) as usize;
 
@@ -41,7 +87,18 @@ impl AcpiHandler for NoccioloAcpiHandler {
             serial_println!("Mapped {physical_address:x} {:p} {size:x} {mapped_length:x}", region.virtual_start().as_ptr());
         }
 
-        region
+        Ok(region)
+    }
+}
+
+impl AcpiHandler for NoccioloAcpiHandler {
+    /// The `acpi` crate can't handle an error, so this panics on any, see
+    /// [`Self::try_map_physical_region`] for a fallible version.
+    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+        match self.try_map_physical_region(physical_address, size) {
+            Ok(mapping) => mapping,
+            Err(e) => panic!("Failed to map the physical region 0x{physical_address:x} of {size} bytes: {e:?}"),
+        }
     }
 
     fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
@@ -72,24 +129,66 @@ impl AcpiHandler for NoccioloAcpiHandler {
     }
 }
 
-fn do_map_region(start: PhysAddr, end: PhysAddr, virt_start: VirtAddr, flags: PageTableFlags) {
+/// Map the pages one by one, undoing the mapping when a page fails.
+fn do_map_region(start: PhysAddr, end: PhysAddr, virt_start: VirtAddr, flags: PageTableFlags) -> Result<(), MapError> {
     let mut ptr = start;
     let mut virt = virt_start;
     while ptr < end {
         let page = Page::<Size4KiB>::from_start_address(virt).unwrap();
 
-        with_mapper(|mapper| with_frame_allocator(|allocator| unsafe {
+        let result = with_mapper(|mapper| with_frame_allocator(|allocator| unsafe {
             // let frame = allocator.allocate_frame_from_physical(ptr).expect("Failed to allocate from same phys");
             let frame = PhysFrame::<Size4KiB>::containing_address(ptr);
             if LOG_ENABLED {
                 serial_println!("Did map {page:?}      {frame:?}");
             }
 
-            mapper.map_to(page, frame, flags, allocator).expect("Failed to map").flush();
+            match mapper.map_to(page, frame, flags, allocator) {
+                Ok(flusher) => {
+                    flusher.flush();
+                    Ok(())
+                }
+
+                // Left behind by an earlier mapping, which is just as good.
+                Err(MapToError::PageAlreadyMapped(existing)) if existing == frame => {
+                    trace!("[acpi] Reusing the mapping of {page:?} to {frame:?}");
+                    Ok(())
+                }
+
+                Err(MapToError::PageAlreadyMapped(existing)) => Err(MapError::AlreadyMapped {
+                    page: page.start_address().as_u64(),
+                    frame: existing.start_address().as_u64(),
+                }),
+
+                Err(MapToError::FrameAllocationFailed) => Err(MapError::OutOfFrames),
+
+                Err(MapToError::ParentEntryHugePage) => Err(MapError::InHugePage {
+                    page: page.start_address().as_u64(),
+                }),
+            }
         }));
 
+        if let Err(e) = result {
+            unmap_pages(virt_start, virt);
+            return Err(e);
+        }
 
         ptr += 4096;
         virt += 4096;
     }
+
+    Ok(())
+}
+
+fn unmap_pages(start: VirtAddr, end: VirtAddr) {
+    let mut virt = start;
+    while virt < end {
+        let page = Page::<Size4KiB>::containing_address(virt);
+        with_mapper(|mapper| {
+            if let Ok((_, flusher)) = mapper.unmap(page) {
+                flusher.flush();
+            }
+        });
+        virt += 4096;
+    }
 }
@@ -4,29 +4,40 @@
 use alloc::alloc::Global;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use spin::Mutex;
 use core::any::type_name;
 use core::fmt::Debug;
 use core::mem::size_of;
 use core::ptr::slice_from_raw_parts_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use acpi::{fadt::Fadt, madt::Madt, AcpiHandler, AcpiTables, AmlTable, PciConfigRegions, PhysicalMapping};
-use aml::{value::Args, AmlContext, AmlError, AmlName, AmlValue, Namespace};
+use aml::{value::Args, AmlContext, AmlError, AmlName, AmlValue};
 use bootloader_api::BootInfo;
 use lazy_static::lazy_static;
-use log::{info, trace};
-use x86_64::instructions::port::{Port, PortRead, PortWrite};
-use crate::device::DeviceError;
+use log::{info, trace, warn};
+use x86_64::instructions::port::{PortRead, PortWrite};
+use crate::arch::port::{AuditedPort, PortUser};
+use crate::device::{pci::EcamRegion, registry::{self, Resource}, DeviceError};
+use crate::sync::Spinlock;
 
+pub mod gpe;
 mod handler;
+mod namespace;
 mod rsdp;
+pub mod tables;
+mod value;
 
-pub use self::handler::NoccioloAcpiHandler;
+pub use self::handler::{MapError, NoccioloAcpiHandler};
+pub use self::value::{AmlObject, AmlTypeError};
 
 lazy_static! {
-    pub static ref ACPI_DATA: Mutex<AcpiData> = Mutex::new(AcpiData::default());
+    pub static ref ACPI_DATA: Spinlock<AcpiData> = Spinlock::new(AcpiData::default());
 }
 
+/// Set when the AML context is shut down, after which the accesses of AML to
+/// memory and PCI are refused, as the regions might be unmapped by then.
+static AML_SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
 type AcpiDataTable<T> = Option<PhysicalMapping<NoccioloAcpiHandler, T>>;
 
 #[allow(unused)]
@@ -70,11 +81,18 @@ pub enum SystemState {
     S5 = 5,
 }
 
+impl SystemState {
+    pub const ALL: [Self; 6] = [Self::S0, Self::S1, Self::S2, Self::S3, Self::S4, Self::S5];
+}
+
 #[derive(Debug, Default)]
 pub struct AcpiData {
     pub madt: AcpiDataTable<Madt>,
     pub fadt: AcpiDataTable<Fadt>,
     pub aml: Option<NoccioloAmlContext>,
+
+    /// The entries of the MCFG, for the PCI Express configuration space.
+    pub ecam_regions: Vec<EcamRegion>,
 }
 
 pub(crate) fn init(boot_info: &'static BootInfo) {
@@ -90,6 +108,12 @@ pub(crate) fn init(boot_info: &'static BootInfo) {
         return;
     }
 
+    if rsdp.revision() == 0 {
+        tables::discover(rsdp.rsdt_address() as usize, false);
+    } else {
+        tables::discover(rsdp.xsdt_address() as usize, true);
+    }
+
     let tables = match unsafe { AcpiTables::from_validated_rsdp(NoccioloAcpiHandler, rsdp) } {
         Ok(tables) => tables,
         Err(e) => {
@@ -113,14 +137,32 @@ pub(crate) fn init(boot_info: &'static BootInfo) {
         }
     }
 
+    if let Ok(dsdt) = tables.dsdt() {
+        tables::register(dsdt.address - size_of::<acpi::sdt::SdtHeader>());
+    }
+
     trace!("[acpi] Platform Info: {:#?}", tables.platform_info());
 
     let regions = PciConfigRegions::new(&tables).ok();
+    if let Some(regions) = regions.as_ref() {
+        acpi_data.ecam_regions = regions.iter()
+            .map(|entry| EcamRegion {
+                segment: entry.segment_group,
+                buses: entry.bus_range,
+                base: entry.physical_address as u64,
+            })
+            .collect();
+    }
 
     let mut context = NoccioloAmlContext::new(regions);
     context.load_acpi(&tables).expect("Failed to populate ACPI information");
     context.initialize_objects().expect("Failed to initialize AML objects");
     // context.debug();
+    namespace::export_at_boot(&mut context);
+
+    if let Some(fadt) = acpi_data.fadt.as_ref() {
+        gpe::init(fadt, &context);
+    }
 
     acpi_data.aml = Some(context);
 
@@ -184,8 +226,24 @@ impl NoccioloAmlContext {
             .map_err(|x| DeviceError::aml(x).with_region("initialize_objects"))
     }
 
-    pub fn namespace(&self) -> &Namespace {
-        &self.context.namespace
+    /// The object at the path, to be coerced into what the caller needs.
+    pub fn get<'a>(&'a self, path: &'a AmlName) -> Result<AmlObject<'a>, AmlError> {
+        Ok(AmlObject::new(path, self.context.namespace.get_by_path(path)?))
+    }
+
+    /// The sleep states the platform supports, i.e. those with a `\_Sx_`
+    /// package telling what to write to the sleep type registers.
+    pub fn supported_sleep_states(&self) -> Vec<SystemState> {
+        SystemState::ALL.into_iter()
+            .filter(|state| {
+                let name = alloc::format!("\\_S{}_", *state as u32);
+                AmlName::from_str(&name).is_ok_and(|name| self.context.namespace.get_by_path(&name).is_ok())
+            })
+            .collect()
+    }
+
+    pub fn invoke_method0(&mut self, name: &AmlName) -> Result<AmlValue, AmlError> {
+        self.context.invoke_method(name, Args::EMPTY)
     }
 
     pub fn invoke_method1(&mut self, name: &AmlName, arg: AmlValue) -> Result<AmlValue, AmlError> {
@@ -207,6 +265,47 @@ impl NoccioloAmlContext {
         Ok(())
     }
 
+    /// \_GTS (Going To Sleep), which is optional, and was removed in ACPI 5.0,
+    /// but older firmware might still rely on it. Returns whether the method
+    /// exists.
+    ///
+    /// https://uefi.org/specs/ACPI/4.0a/07_Power_and_Performance_Mgmt.html#gts-going-to-sleep
+    pub fn invoke_going_to_sleep(&mut self, sleeping_state: SystemState) -> Result<bool, AmlError> {
+        self.invoke_optional_method1("\\_GTS", AmlValue::Integer(sleeping_state as _))
+    }
+
+    /// \_BFS (Back From Sleep), the counterpart of \_GTS, which is invoked
+    /// before \_WAK. Returns whether the method exists.
+    ///
+    /// https://uefi.org/specs/ACPI/4.0a/07_Power_and_Performance_Mgmt.html#bfs-back-from-sleep
+    pub fn invoke_back_from_sleep(&mut self, sleeping_state: SystemState) -> Result<bool, AmlError> {
+        self.invoke_optional_method1("\\_BFS", AmlValue::Integer(sleeping_state as _))
+    }
+
+    /// Invoke a method the platform doesn't have to provide, returning
+    /// whether it exists.
+    fn invoke_optional_method1(&mut self, name: &str, arg: AmlValue) -> Result<bool, AmlError> {
+        let method_name = AmlName::from_str(name)?;
+        match self.invoke_method1(&method_name, arg) {
+            Ok(_) => Ok(true),
+            Err(AmlError::ValueDoesNotExist(missing)) if missing == method_name => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stop AML from accessing memory and PCI, right before entering a sleep
+    /// state. Late accesses, e.g. from a method invoked by a stray event, are
+    /// refused and logged instead of touching regions that might be unmapped.
+    pub fn shut_down(&mut self) {
+        trace!("[acpi] [aml] Shutting down the AML context");
+        AML_SHUT_DOWN.store(true, Ordering::Release);
+    }
+
+    /// Undo [`Self::shut_down`], when entering the sleep state failed.
+    pub fn resume(&mut self) {
+        AML_SHUT_DOWN.store(false, Ordering::Release);
+    }
+
     /// \_WAK (System Wake)
     ///
     /// https://uefi.org/specs/ACPI/6.5/07_Power_and_Performance_Mgmt.html#wak-system-wake
@@ -255,7 +354,8 @@ impl NoccioloAmlContext {
         for (name, seg, val) in data {
             let value = self.context.namespace.get(val);
 
-            if let Ok(AmlValue::Device) = &value {
+            let is_device = value.is_ok_and(|value| AmlObject::new(&name, value).require_device().is_ok());
+            if is_device {
                 info!("ACPI Device: {name} {}", seg.as_str());
             }
         }
@@ -356,11 +456,30 @@ impl aml::Handler for NoccioloAmlHandler {
     }
 }
 
+/// Whether an access by AML is refused, because the context is shut down.
+fn is_refused(what: core::fmt::Arguments) -> bool {
+    let refused = AML_SHUT_DOWN.load(Ordering::Acquire);
+    if refused {
+        warn!("[acpi] [aml] Refused {what} after shutting down");
+    }
+    refused
+}
+
 fn aml_read<T>(address: usize) -> T
-        where T: Debug + Copy {
+        where T: Debug + Copy + Default {
     trace!("Reading at address 0x{address:x} type {}", type_name::<T>());
 
-    let mapping = unsafe { NoccioloAcpiHandler.map_physical_region::<T>(address, size_of::<T>()) };
+    if is_refused(format_args!("reading 0x{address:x}")) {
+        return T::default();
+    }
+
+    let mapping = match unsafe { NoccioloAcpiHandler.try_map_physical_region::<T>(address, size_of::<T>()) } {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            warn!("[acpi] AML read of 0x{address:x} refused: {e:?}");
+            return T::default();
+        }
+    };
 
     unsafe { *mapping.virtual_start().as_ptr() }
 }
@@ -369,24 +488,38 @@ fn aml_write<T>(address: usize, value: T)
     where T: Debug + Copy {
     trace!("Writing at address 0x{address:x} type {} value {value:?}", type_name::<T>());
 
-    let mapping = unsafe { NoccioloAcpiHandler.map_physical_region::<T>(address, size_of::<T>()) };
+    if is_refused(format_args!("writing 0x{address:x}")) {
+        return;
+    }
+
+    let mapping = match unsafe { NoccioloAcpiHandler.try_map_physical_region::<T>(address, size_of::<T>()) } {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            warn!("[acpi] AML write of 0x{address:x} refused: {e:?}");
+            return;
+        }
+    };
 
     *unsafe { &mut *mapping.virtual_start().as_ptr() } = value;
 }
 
 fn aml_read_pci<T>(request: PciRequest) -> T
-        where T: Debug + Copy + PortRead {
+        where T: Debug + Copy + Default + PortRead + Into<u32> {
     trace!("Reading PCI {request:?} type {}", type_name::<T>());
 
+    if is_refused(format_args!("reading PCI {request:?}")) {
+        return T::default();
+    }
+
     let address = request.address();
 
     unsafe {
-        let mut port = Port::new(0xCF8);
+        let mut port = AuditedPort::<u32>::new(0xCF8, PortUser::Pci);
         port.write(address);
     }
 
     unsafe {
-        let mut port = Port::new(0xCF8);
+        let mut port = AuditedPort::new(0xCF8, PortUser::Pci);
         port.read()
     }
 }
@@ -396,19 +529,38 @@ fn aml_write_pci<T>(request: PciRequest, value: T)
     trace!("Writing PCI {request:?} type {} value {value:?}", type_name::<T>())
 }
 
+/// Whether the port is claimed by a device, which the kernel drives, so AML
+/// accessing it would interfere with the driver.
+fn is_claimed_port<T>(port: u16, what: core::fmt::Arguments) -> bool {
+    let Some(owner) = registry::owner(&Resource::io_ports(port as u64, size_of::<T>() as u64)) else {
+        return false;
+    };
+
+    warn!("[acpi] [aml] Refused {what}, which is claimed by {}", owner.name());
+    true
+}
+
 fn aml_read_port<T>(port: u16) -> T
-        where T: Debug + Copy + PortRead {
+        where T: Debug + Copy + Default + PortRead + Into<u32> {
     trace!("Reading I/O port 0x{port:x} type {}", type_name::<T>());
 
-    let mut port = Port::new(port);
+    if is_claimed_port::<T>(port, format_args!("reading port 0x{port:x}")) {
+        return T::default();
+    }
+
+    let mut port = AuditedPort::new(port, PortUser::Aml);
     unsafe { port.read() }
 }
 
 fn aml_write_port<T>(port: u16, value: T)
-    where T: Debug + Copy + PortWrite {
+    where T: Debug + Copy + PortWrite + Into<u32> {
     trace!("Writing I/O port 0x{port:x} type {} value {value:?}", type_name::<T>());
 
-    let mut port = Port::new(port);
+    if is_claimed_port::<T>(port, format_args!("writing port 0x{port:x}")) {
+        return;
+    }
+
+    let mut port = AuditedPort::new(port, PortUser::Aml);
     unsafe { port.write(value) }
 }
 
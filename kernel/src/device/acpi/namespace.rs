@@ -0,0 +1,237 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A text snapshot of the AML namespace, to diff the namespaces of different
+//! machines or firmware versions offline. Every object is a line with its
+//! path, type and, for the simple ones, its value, in the order of the
+//! namespace, which is sorted by name:
+//! ```text
+//! \_SB_.PCI0 Device
+//! \_SB_.PCI0._ADR Integer 0x0
+//! \_SB_.PCI0._BBN Method 0
+//! \_S5_ Package [0x5, 0x5, 0x0, 0x0]
+//! ```
+//!
+//! The snapshot is written at boot, after the namespace is initialized, when
+//! the `acpi_namespace` boot parameter names where to:
+//!
+//! | Value        | Written to                                                 |
+//! |--------------|------------------------------------------------------------|
+//! | `serial`     | The serial port, between `@acpi-namespace` marker lines    |
+//! | `debugcon`   | The QEMU debug console (port 0xE9), e.g. `-debugcon file:` |
+//! | `fat:<file>` | The file on the FAT32 file system of the first disk        |
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use aml::{AmlError, AmlName, AmlValue};
+use log::{info, warn};
+use x86_64::instructions::port::Port;
+
+use crate::{fs::fat::{FatError, FatFile, FatVolume}, meta::BootParameters, serial_print, serial_println};
+
+use super::NoccioloAmlContext;
+
+const DEBUGCON_PORT: u16 = 0xE9;
+
+/// Surrounds the snapshot on the serial port, for the host to extract it.
+const SERIAL_MARKER: &str = "@acpi-namespace";
+
+/// The number of bytes of a buffer that are written, and of the elements of
+/// a package.
+const MAX_BUFFER_BYTES: usize = 32;
+const MAX_PACKAGE_ELEMENTS: usize = 16;
+
+/// The bytes written to a file at once.
+const FILE_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamespaceExportError {
+    Aml(AmlError),
+    Fat(FatError),
+
+    /// The sink failed, e.g. because the file system is full.
+    Write,
+}
+
+impl From<FatError> for NamespaceExportError {
+    fn from(value: FatError) -> Self {
+        Self::Fat(value)
+    }
+}
+
+/// Write the snapshot where the `acpi_namespace` boot parameter says.
+pub(super) fn export_at_boot(context: &mut NoccioloAmlContext) {
+    let Some(target) = BootParameters::get("acpi_namespace") else {
+        return;
+    };
+
+    let result = match target {
+        "serial" => {
+            serial_println!("{SERIAL_MARKER} begin");
+            let result = export(context, &mut SerialSink);
+            serial_println!("{SERIAL_MARKER} end");
+            result
+        }
+        "debugcon" => export(context, &mut DebugconSink),
+        _ => match target.strip_prefix("fat:") {
+            Some(name) => export_to_file(context, name),
+            None => {
+                warn!("Invalid acpi_namespace `{target}`, expected `serial`, `debugcon` or `fat:<file>`");
+                return;
+            }
+        },
+    };
+
+    match result {
+        Ok(objects) => info!("[acpi] Exported {objects} objects of the AML namespace to {target}"),
+        Err(e) => warn!("[acpi] Failed to export the AML namespace to {target}: {e:?}"),
+    }
+}
+
+/// Write a line for every object to the sink, returning the number of
+/// objects.
+pub fn export(context: &mut NoccioloAmlContext, sink: &mut impl Write) -> Result<usize, NamespaceExportError> {
+    let namespace = &mut context.context.namespace;
+
+    let mut objects = Vec::new();
+    namespace.traverse(|name, level| {
+        for (seg, handle) in &level.values {
+            objects.push((AmlName::from_name_seg(*seg).resolve(name)?, *handle));
+        }
+        Ok(true)
+    }).map_err(NamespaceExportError::Aml)?;
+
+    for (path, handle) in &objects {
+        let value = namespace.get(*handle).map_err(NamespaceExportError::Aml)?;
+        writeln!(sink, "{path} {}", Object(value)).map_err(|_| NamespaceExportError::Write)?;
+    }
+
+    Ok(objects.len())
+}
+
+fn export_to_file(context: &mut NoccioloAmlContext, name: &str) -> Result<usize, NamespaceExportError> {
+    let mut volume = FatVolume::mount_first()?;
+    let mut file = match volume.open(name) {
+        Err(FatError::NotFound) => volume.create(name)?,
+        file => file?,
+    };
+    volume.truncate(&mut file, 0)?;
+
+    let mut sink = FileSink { volume: &mut volume, file, buffer: String::with_capacity(FILE_CHUNK_SIZE), error: None };
+    let result = export(context, &mut sink);
+    sink.flush();
+    if let Some(e) = sink.error {
+        return Err(e.into());
+    }
+
+    let objects = result?;
+    volume.sync()?;
+    Ok(objects)
+}
+
+/// The type and value of an object, as written in the snapshot.
+struct Object<'a>(&'a AmlValue);
+
+impl fmt::Display for Object<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.type_of())?;
+
+        match self.0 {
+            AmlValue::Boolean(value) => write!(f, " {value}"),
+            AmlValue::Integer(value) => write!(f, " {value:#x}"),
+            AmlValue::String(value) => write!(f, " {value:?}"),
+            AmlValue::OpRegion { region, offset, length, .. } => write!(f, " {region:?} {offset:#x}+{length:#x}"),
+            AmlValue::Field { offset, length, .. } => write!(f, " bits {offset:#x}+{length}"),
+            AmlValue::Method { flags, .. } => write!(f, " {}", flags.arg_count()),
+            AmlValue::Buffer(data) => {
+                let data = data.lock();
+                write!(f, " {} ", data.len())?;
+                for byte in data.iter().take(MAX_BUFFER_BYTES) {
+                    write!(f, "{byte:02x}")?;
+                }
+                if data.len() > MAX_BUFFER_BYTES {
+                    write!(f, "...")?;
+                }
+                Ok(())
+            }
+            AmlValue::BufferField { offset, length, .. } => write!(f, " bits {offset:#x}+{length}"),
+            AmlValue::Processor { id, pblk_address, pblk_len } => write!(f, " {id} {pblk_address:#x}+{pblk_len}"),
+            AmlValue::Mutex { sync_level } => write!(f, " {sync_level}"),
+            AmlValue::Package(elements) => {
+                write!(f, " [")?;
+                for (index, element) in elements.iter().take(MAX_PACKAGE_ELEMENTS).enumerate() {
+                    let separator = if index == 0 { "" } else { ", " };
+                    match element {
+                        AmlValue::Integer(value) => write!(f, "{separator}{value:#x}")?,
+                        AmlValue::String(value) => write!(f, "{separator}{value:?}")?,
+                        other => write!(f, "{separator}{:?}", other.type_of())?,
+                    }
+                }
+                if elements.len() > MAX_PACKAGE_ELEMENTS {
+                    write!(f, ", ...")?;
+                }
+                write!(f, "]")
+            }
+            AmlValue::PowerResource { system_level, resource_order } => write!(f, " {system_level} {resource_order}"),
+            AmlValue::Device | AmlValue::ThermalZone => Ok(()),
+        }
+    }
+}
+
+struct SerialSink;
+
+impl Write for SerialSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{s}");
+        Ok(())
+    }
+}
+
+struct DebugconSink;
+
+impl Write for DebugconSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = Port::<u8>::new(DEBUGCON_PORT);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Appends to a file in chunks, since every write goes through the FAT.
+struct FileSink<'a> {
+    volume: &'a mut FatVolume,
+    file: FatFile,
+    buffer: String,
+    error: Option<FatError>,
+}
+
+impl FileSink<'_> {
+    fn flush(&mut self) {
+        if self.buffer.is_empty() || self.error.is_some() {
+            return;
+        }
+
+        let offset = self.file.size();
+        if let Err(e) = self.volume.write(&mut self.file, offset, self.buffer.as_bytes()) {
+            self.error = Some(e);
+        }
+        self.buffer.clear();
+    }
+}
+
+impl Write for FileSink<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buffer.push_str(s);
+        if self.buffer.len() >= FILE_CHUNK_SIZE {
+            self.flush();
+        }
+
+        match self.error {
+            Some(_) => Err(fmt::Error),
+            None => Ok(()),
+        }
+    }
+}
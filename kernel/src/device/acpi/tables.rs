@@ -0,0 +1,121 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A registry of the ACPI tables the firmware provides, as listed by the
+//! RSDT/XSDT (plus the DSDT, which is referenced by the FADT instead).
+//!
+//! Every table is checksummed when it's discovered; tables with an invalid
+//! checksum are still registered, so they can be inspected with `acpidump`,
+//! which writes them to the serial port in the format of the `acpidump` tool,
+//! so they can be extracted with `acpixtract` and disassembled with `iasl`.
+//!
+//! ### References:
+//! - [ACPI 6.5: 5.2.6 System Description Table Header](https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#system-description-table-header)
+
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr::slice_from_raw_parts};
+
+use acpi::{sdt::SdtHeader, AcpiHandler};
+use log::{trace, warn};
+use spin::Mutex;
+
+use crate::meta::memory_map::{self, RegionKind};
+
+use super::NoccioloAcpiHandler;
+
+static REGISTRY: Mutex<Vec<AcpiTableInfo>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcpiTableInfo {
+    pub signature: [u8; 4],
+    pub address: usize,
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub checksum_valid: bool,
+}
+
+impl AcpiTableInfo {
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    pub fn oem_id(&self) -> &str {
+        core::str::from_utf8(&self.oem_id).unwrap_or("").trim_end()
+    }
+
+    pub fn oem_table_id(&self) -> &str {
+        core::str::from_utf8(&self.oem_table_id).unwrap_or("").trim_end()
+    }
+}
+
+/// Register the root table and the tables it lists.
+pub(super) fn discover(root_address: usize, is_xsdt: bool) {
+    let Some(root) = register(root_address) else {
+        return;
+    };
+
+    let entry_size = if is_xsdt { 8 } else { 4 };
+    let data = read(&root);
+
+    for entry in data[size_of::<SdtHeader>()..].chunks_exact(entry_size) {
+        let mut address = [0; 8];
+        address[..entry_size].copy_from_slice(entry);
+        register(u64::from_le_bytes(address) as usize);
+    }
+}
+
+/// Register a table at the given physical address, returning it.
+pub(super) fn register(address: usize) -> Option<AcpiTableInfo> {
+    if address == 0 {
+        return None;
+    }
+
+    let header = match unsafe { NoccioloAcpiHandler.try_map_physical_region::<SdtHeader>(address, size_of::<SdtHeader>()) } {
+        Ok(header) => header,
+        Err(e) => {
+            warn!("[acpi] Ignoring table at 0x{address:x} that can't be mapped: {e:?}");
+            return None;
+        }
+    };
+    let length = header.length;
+    if (length as usize) < size_of::<SdtHeader>() {
+        warn!("[acpi] Ignoring table at 0x{address:x} with invalid length {length}");
+        return None;
+    }
+
+    let mut info = AcpiTableInfo {
+        signature: header.signature.as_str().as_bytes().try_into().unwrap_or(*b"????"),
+        address,
+        length,
+        revision: header.revision,
+        oem_id: header.oem_id,
+        oem_table_id: header.oem_table_id,
+        checksum_valid: false,
+    };
+    drop(header);
+
+    info.checksum_valid = read(&info).iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0;
+
+    if info.checksum_valid {
+        trace!("[acpi] Found {} at 0x{address:x} ({length} bytes)", info.signature());
+    } else {
+        warn!("[acpi] {} at 0x{address:x} has an invalid checksum", info.signature());
+    }
+
+    memory_map::register(address as u64, address as u64 + length as u64, RegionKind::Acpi, info.signature());
+    REGISTRY.lock().push(info);
+    Some(info)
+}
+
+pub fn tables() -> Vec<AcpiTableInfo> {
+    REGISTRY.lock().clone()
+}
+
+/// Copy the contents of a table, including its header.
+pub fn read(table: &AcpiTableInfo) -> Vec<u8> {
+    let mapping = unsafe { NoccioloAcpiHandler.map_physical_region::<u8>(table.address, table.length as usize) };
+    let data = slice_from_raw_parts(mapping.virtual_start().as_ptr().cast_const(), table.length as usize);
+    unsafe { &*data }.to_vec()
+}
@@ -0,0 +1,130 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Checked access to the values in the AML namespace, which are whatever the
+//! firmware put there. Instead of matching on [`AmlValue`] and inventing an
+//! error for every mismatch, the callers coerce an [`AmlObject`] into what
+//! they need, and get an [`AmlTypeError`] naming the object otherwise:
+//! ```ignore
+//! let [a, b] = aml.get(&AmlName::from_str("\\_S5_")?)?.as_package_n::<2>()?;
+//! let sleep_type_a: u16 = a.as_integer_of()?;
+//! ```
+
+use core::fmt::{Display, Formatter};
+
+use aml::{value::AmlType, AmlName, AmlValue};
+
+/// A value in the namespace, with the path it was found at.
+#[derive(Clone, Copy)]
+pub struct AmlObject<'a> {
+    path: &'a AmlName,
+
+    /// The index in the package at the path, for the elements of a package.
+    element: Option<usize>,
+    value: &'a AmlValue,
+}
+
+impl<'a> AmlObject<'a> {
+    pub fn new(path: &'a AmlName, value: &'a AmlValue) -> Self {
+        Self { path, element: None, value }
+    }
+
+    pub fn as_integer(&self) -> Result<u64, AmlTypeError> {
+        match self.value {
+            AmlValue::Integer(value) => Ok(*value),
+            AmlValue::Boolean(value) => Ok(*value as u64),
+            _ => Err(self.type_error(AmlType::Integer)),
+        }
+    }
+
+    /// The integer, which must fit in the given type.
+    pub fn as_integer_of<T: TryFrom<u64>>(&self) -> Result<T, AmlTypeError> {
+        let value = self.as_integer()?;
+        T::try_from(value).map_err(|_| self.error(AmlTypeErrorKind::OutOfRange {
+            value,
+            bits: core::mem::size_of::<T>() as u32 * 8,
+        }))
+    }
+
+    /// The first `N` elements of the package, which may have more.
+    pub fn as_package_n<const N: usize>(&self) -> Result<[AmlObject<'a>; N], AmlTypeError> {
+        let AmlValue::Package(elements) = self.value else {
+            return Err(self.type_error(AmlType::Package));
+        };
+
+        if elements.len() < N {
+            return Err(self.error(AmlTypeErrorKind::TooShort { expected: N, actual: elements.len() }));
+        }
+
+        Ok(core::array::from_fn(|index| Self {
+            path: self.path,
+            element: Some(index),
+            value: &elements[index],
+        }))
+    }
+
+    #[allow(dead_code)] // Only used by `NoccioloAmlContext::debug` for now.
+    pub fn require_device(&self) -> Result<(), AmlTypeError> {
+        self.require(AmlType::Device)
+    }
+
+    pub fn require_method(&self) -> Result<(), AmlTypeError> {
+        self.require(AmlType::Method)
+    }
+
+    fn require(&self, expected: AmlType) -> Result<(), AmlTypeError> {
+        if self.value.type_of() == expected {
+            Ok(())
+        } else {
+            Err(self.type_error(expected))
+        }
+    }
+
+    fn type_error(&self, expected: AmlType) -> AmlTypeError {
+        self.error(AmlTypeErrorKind::Type { expected, actual: self.value.type_of() })
+    }
+
+    fn error(&self, kind: AmlTypeErrorKind) -> AmlTypeError {
+        AmlTypeError {
+            path: self.path.clone(),
+            element: self.element,
+            kind,
+        }
+    }
+}
+
+/// A value in the namespace isn't what the caller needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmlTypeError {
+    pub path: AmlName,
+    pub element: Option<usize>,
+    pub kind: AmlTypeErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlTypeErrorKind {
+    Type { expected: AmlType, actual: AmlType },
+
+    /// The package has fewer elements than expected.
+    TooShort { expected: usize, actual: usize },
+
+    /// The integer doesn't fit in the given number of bits.
+    OutOfRange { value: u64, bits: u32 },
+}
+
+impl Display for AmlTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(element) = self.element {
+            write!(f, "[{element}]")?;
+        }
+
+        match self.kind {
+            AmlTypeErrorKind::Type { expected, actual } => write!(f, ": expected {expected:?}, found {actual:?}"),
+            AmlTypeErrorKind::TooShort { expected, actual } => {
+                write!(f, ": expected a package of at least {expected} elements, found {actual}")
+            }
+            AmlTypeErrorKind::OutOfRange { value, bits } => write!(f, ": {value:#x} doesn't fit in {bits} bits"),
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A polling driver for the legacy (parallel) ATA controllers, using PIO and
+//! 28-bit LBA addressing. This is what QEMU exposes for `-drive` by default
+//! on the `pc` machine.
+//!
+//! ### References:
+//! - [OSDev Wiki: ATA PIO Mode](https://wiki.osdev.org/ATA_PIO_Mode)
+
+use log::trace;
+use x86_64::instructions::port::Port;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// How many status polls we do before giving up on the drive.
+const POLL_LIMIT: usize = 1_000_000;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// Nothing is attached at this position.
+    NoDevice,
+
+    /// A device is attached, but it isn't an ATA disk (e.g. ATAPI/SATA).
+    NotAta,
+
+    DeviceFault,
+
+    /// The drive reported an error, with the contents of the error register.
+    Error(u8),
+    OutOfRange,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaBus {
+    Primary,
+    Secondary,
+}
+
+impl AtaBus {
+    const fn io_base(&self) -> u16 {
+        match self {
+            Self::Primary => 0x1F0,
+            Self::Secondary => 0x170,
+        }
+    }
+
+    const fn control_base(&self) -> u16 {
+        match self {
+            Self::Primary => 0x3F6,
+            Self::Secondary => 0x376,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtaDrivePosition {
+    pub bus: AtaBus,
+    pub slave: bool,
+}
+
+impl AtaDrivePosition {
+    pub const ALL: [Self; 4] = [
+        Self { bus: AtaBus::Primary, slave: false },
+        Self { bus: AtaBus::Primary, slave: true },
+        Self { bus: AtaBus::Secondary, slave: false },
+        Self { bus: AtaBus::Secondary, slave: true },
+    ];
+}
+
+#[derive(Debug)]
+pub struct AtaDrive {
+    position: AtaDrivePosition,
+    sector_count: u32,
+}
+
+impl AtaDrive {
+    /// Probe the drive at the given position using the IDENTIFY command.
+    pub fn identify(position: AtaDrivePosition) -> Result<Self, AtaError> {
+        let mut ports = AtaPorts::new(position.bus);
+
+        // A floating bus means there is no controller at all.
+        if unsafe { ports.status.read() } == 0xFF {
+            return Err(AtaError::NoDevice);
+        }
+
+        unsafe {
+            ports.drive_select.write(0xA0 | ((position.slave as u8) << 4));
+            ports.delay();
+
+            ports.sector_count.write(0);
+            ports.lba_lo.write(0);
+            ports.lba_mid.write(0);
+            ports.lba_hi.write(0);
+            ports.command.write(COMMAND_IDENTIFY);
+
+            if ports.status.read() == 0 {
+                return Err(AtaError::NoDevice);
+            }
+        }
+
+        ports.wait_not_busy()?;
+
+        // ATAPI and SATA devices set these to a signature instead of zero.
+        if unsafe { ports.lba_mid.read() != 0 || ports.lba_hi.read() != 0 } {
+            return Err(AtaError::NotAta);
+        }
+
+        ports.wait_data_request()?;
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = unsafe { ports.data.read() };
+        }
+
+        let sector_count = (identify[60] as u32) | ((identify[61] as u32) << 16);
+        trace!("[ata] {position:?} has {sector_count} LBA28 sectors");
+
+        Ok(Self {
+            position,
+            sector_count,
+        })
+    }
+
+    pub fn position(&self) -> AtaDrivePosition {
+        self.position
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.sector_count
+    }
+
+    /// Read `buffer.len() / SECTOR_SIZE` sectors starting at `lba`.
+    pub fn read_sectors(&self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let count = self.prepare_transfer(lba, buffer.len(), COMMAND_READ_SECTORS)?;
+        let mut ports = AtaPorts::new(self.position.bus);
+
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE).take(count) {
+            ports.wait_data_request()?;
+
+            for bytes in sector.chunks_exact_mut(2) {
+                let word: u16 = unsafe { ports.data.read() };
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write `buffer.len() / SECTOR_SIZE` sectors starting at `lba`, and
+    /// flush the write cache of the drive afterwards.
+    pub fn write_sectors(&self, lba: u32, buffer: &[u8]) -> Result<(), AtaError> {
+        let count = self.prepare_transfer(lba, buffer.len(), COMMAND_WRITE_SECTORS)?;
+        let mut ports = AtaPorts::new(self.position.bus);
+
+        for sector in buffer.chunks_exact(SECTOR_SIZE).take(count) {
+            ports.wait_data_request()?;
+
+            for bytes in sector.chunks_exact(2) {
+                unsafe { ports.data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+            }
+        }
+
+        unsafe { ports.command.write(COMMAND_CACHE_FLUSH) };
+        ports.wait_not_busy()
+    }
+
+    /// Write the write cache of the drive back to the disk.
+    pub fn flush(&self) -> Result<(), AtaError> {
+        let mut ports = AtaPorts::new(self.position.bus);
+        ports.wait_not_busy()?;
+
+        unsafe {
+            ports.drive_select.write(0xE0 | ((self.position.slave as u8) << 4));
+            ports.delay();
+            ports.command.write(COMMAND_CACHE_FLUSH);
+        }
+        ports.wait_not_busy()
+    }
+
+    fn prepare_transfer(&self, lba: u32, len: usize, command: u8) -> Result<usize, AtaError> {
+        let count = len / SECTOR_SIZE;
+        debug_assert_eq!(len % SECTOR_SIZE, 0, "buffer should be a multiple of the sector size");
+
+        if count == 0 || count > u8::MAX as usize {
+            return Err(AtaError::OutOfRange);
+        }
+
+        match lba.checked_add(count as u32) {
+            Some(end) if end <= self.sector_count && end < (1 << 28) => (),
+            _ => return Err(AtaError::OutOfRange),
+        }
+
+        let mut ports = AtaPorts::new(self.position.bus);
+        ports.wait_not_busy()?;
+
+        unsafe {
+            ports.drive_select.write(0xE0 | ((self.position.slave as u8) << 4) | ((lba >> 24) & 0xF) as u8);
+            ports.delay();
+
+            ports.sector_count.write(count as u8);
+            ports.lba_lo.write(lba as u8);
+            ports.lba_mid.write((lba >> 8) as u8);
+            ports.lba_hi.write((lba >> 16) as u8);
+            ports.command.write(command);
+        }
+
+        Ok(count)
+    }
+}
+
+struct AtaPorts {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_lo: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_hi: Port<u8>,
+    drive_select: Port<u8>,
+    command: Port<u8>,
+    status: Port<u8>,
+    alternate_status: Port<u8>,
+}
+
+impl AtaPorts {
+    fn new(bus: AtaBus) -> Self {
+        let base = bus.io_base();
+        Self {
+            data: Port::new(base),
+            error: Port::new(base + 1),
+            sector_count: Port::new(base + 2),
+            lba_lo: Port::new(base + 3),
+            lba_mid: Port::new(base + 4),
+            lba_hi: Port::new(base + 5),
+            drive_select: Port::new(base + 6),
+            command: Port::new(base + 7),
+            status: Port::new(base + 7),
+            alternate_status: Port::new(bus.control_base()),
+        }
+    }
+
+    /// Reading the alternate status register takes roughly 100ns, and the
+    /// drive needs 400ns to put its status on the bus after a selection.
+    fn delay(&mut self) {
+        for _ in 0..4 {
+            _ = unsafe { self.alternate_status.read() };
+        }
+    }
+
+    fn wait_not_busy(&mut self) -> Result<(), AtaError> {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.status.read() };
+            if status & STATUS_BSY == 0 {
+                return self.check_status(status);
+            }
+        }
+
+        Err(AtaError::Timeout)
+    }
+
+    fn wait_data_request(&mut self) -> Result<(), AtaError> {
+        self.delay();
+
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.status.read() };
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+
+            self.check_status(status)?;
+
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+
+        Err(AtaError::Timeout)
+    }
+
+    fn check_status(&mut self, status: u8) -> Result<(), AtaError> {
+        if status & STATUS_DF != 0 {
+            return Err(AtaError::DeviceFault);
+        }
+
+        if status & STATUS_ERR != 0 {
+            return Err(AtaError::Error(unsafe { self.error.read() }));
+        }
+
+        Ok(())
+    }
+}
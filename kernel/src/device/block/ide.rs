@@ -0,0 +1,110 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The IDE controllers, like the PIIX3 of QEMU's `pc` machine. In
+//! compatibility mode, their channels are the legacy ATA ports, which are
+//! claimed by the platform device `ata`, so the driver only probes the drives
+//! of those channels (see [`ata`]) and registers them as `hda` to `hdd`.
+//!
+//! Channels in native mode have their ports in the BARs of the controller,
+//! which isn't supported.
+//!
+//! ### References:
+//! - [OSDev Wiki: PCI IDE Controller](https://wiki.osdev.org/PCI_IDE_Controller)
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use crate::{
+    dev_info,
+    dev_trace,
+    dev_warn,
+    device::{
+        ata::{self, AtaBus, AtaDrive, AtaDrivePosition, AtaError},
+        pci::{ConfigurationSpaceMechanism, PciClassCode, PciConfigurationSpace, PciDriver},
+    },
+};
+
+use super::{BlockDevice, BlockError};
+
+const SUBCLASS_IDE: u8 = 0x01;
+
+/// The bits of the programming interface that are set when the primary or
+/// secondary channel is in native mode.
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+
+/// The most sectors of a single command.
+const MAX_SECTORS_PER_COMMAND: usize = u8::MAX as usize;
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "ide",
+    matches: |info| info.class == PciClassCode::MassStorageController && info.subclass.value() == SUBCLASS_IDE,
+    probe: |device, info| {
+        let prog_if = PciConfigurationSpace.prog_if(info.address);
+        for position in AtaDrivePosition::ALL {
+            let native = match position.bus {
+                AtaBus::Primary => PROG_IF_PRIMARY_NATIVE,
+                AtaBus::Secondary => PROG_IF_SECONDARY_NATIVE,
+            };
+            if prog_if & native != 0 {
+                dev_warn!(device, "Skipping {position:?}, since its channel is in native mode");
+                continue;
+            }
+
+            match AtaDrive::identify(position) {
+                Ok(drive) => {
+                    let size = drive.sector_count() as u64 * ata::SECTOR_SIZE as u64 / 1024 / 1024;
+                    let name = super::register("hd", Box::new(drive));
+                    dev_info!(device, "Disk {name} of {size} MiB at {position:?}");
+                }
+                Err(AtaError::NoDevice) => (),
+                Err(e) => dev_trace!(device, "No ATA disk at {position:?}: {e:?}"),
+            }
+        }
+        Box::pin(async { Ok(()) })
+    },
+    timeout: Duration::from_secs(1),
+};
+
+impl From<AtaError> for BlockError {
+    fn from(value: AtaError) -> Self {
+        match value {
+            AtaError::OutOfRange => Self::OutOfRange,
+            AtaError::Timeout => Self::Timeout,
+            _ => Self::Io,
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        ata::SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sector_count() as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buffer.len())?;
+        for (index, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * ata::SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.read_sectors(u32::try_from(lba).map_err(|_| BlockError::OutOfRange)?, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.check_request(lba, data.len())?;
+        for (index, chunk) in data.chunks(MAX_SECTORS_PER_COMMAND * ata::SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.write_sectors(u32::try_from(lba).map_err(|_| BlockError::OutOfRange)?, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(AtaDrive::flush(self)?)
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The drivers of disks that are read and written in whole blocks, which are
+//! addressed by their logical block address (LBA) from zero.
+//!
+//! The drivers of the mass-storage controllers are matched against the PCI
+//! devices like any other, and [`register`] the disks they find, which are
+//! named after their kind, like Linux does:
+//!
+//! | Name            | Driver                                 |
+//! |-----------------|----------------------------------------|
+//! | `hda`, `hdb`... | [`ide`], the legacy ATA controllers    |
+//! | `vda`, `vdb`... | [`virtio_blk`]                         |
+//!
+//! Other mass-storage controllers (e.g. the AHCI controller of the `q35`
+//! machine) are reported at boot, see [`init`].
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::fmt::{Display, Formatter};
+
+use log::info;
+
+use crate::sync::Spinlock;
+
+pub mod ide;
+pub mod virtio_blk;
+
+/// The disks the drivers registered, in the order they did.
+static DISKS: Spinlock<Vec<Disk>> = Spinlock::new(Vec::new());
+
+struct Disk {
+    name: String,
+    device: Box<dyn BlockDevice>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks lie (partly) beyond the end of the disk.
+    OutOfRange,
+
+    /// The buffer isn't a whole number of blocks.
+    InvalidLength(usize),
+
+    /// The disk can't be written to.
+    ReadOnly,
+
+    /// The disk didn't finish the request in time.
+    Timeout,
+
+    /// The disk reported the request failed.
+    Io,
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::OutOfRange => f.write_str("the blocks are beyond the end of the disk"),
+            Self::InvalidLength(length) => write!(f, "{length} bytes aren't a whole number of blocks"),
+            Self::ReadOnly => f.write_str("the disk is read-only"),
+            Self::Timeout => f.write_str("the disk didn't respond"),
+            Self::Io => f.write_str("the disk failed the request"),
+        }
+    }
+}
+
+pub trait BlockDevice: Send {
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// The number of blocks on the disk.
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks from `lba` on, as many as fit in the buffer.
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write the blocks from `lba` on, as many as there are in the data.
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<(), BlockError>;
+
+    /// Make the blocks that were written durable, for disks with a write
+    /// cache.
+    fn flush(&mut self) -> Result<(), BlockError>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Check that the buffer of a request holds whole blocks, all on the
+    /// disk, returning the number of blocks.
+    fn check_request(&self, lba: u64, length: usize) -> Result<u64, BlockError> {
+        if length % self.block_size() != 0 {
+            return Err(BlockError::InvalidLength(length));
+        }
+
+        let count = (length / self.block_size()) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+/// Add a disk, named after the prefix of its kind and the number of disks of
+/// that kind before it, e.g. `vdb` for the second virtio disk. Returns the
+/// name.
+pub fn register(prefix: &str, device: Box<dyn BlockDevice>) -> String {
+    let mut disks = DISKS.lock();
+    let index = disks.iter().filter(|disk| disk.name.strip_prefix(prefix).is_some_and(|rest| rest.len() == 1)).count();
+    let name = format!("{prefix}{}", (b'a' + index as u8) as char);
+    disks.push(Disk { name: name.clone(), device });
+    name
+}
+
+/// Log the disks, once the drivers are done probing.
+pub fn init() {
+    let disks = DISKS.lock();
+    for disk in disks.iter() {
+        let size = disk.device.block_count() * disk.device.block_size() as u64;
+        info!("[block] {}: {} MiB in blocks of {} bytes", disk.name, size / 1024 / 1024, disk.device.block_size());
+    }
+    info!("[block] Found {} disks", disks.len());
+}
+
+/// The names of the disks, in the order they were registered.
+pub fn names() -> Vec<String> {
+    DISKS.lock().iter().map(|disk| disk.name.clone()).collect()
+}
+
+/// Run the closure with the disk of the given name. Returns `None` when there
+/// is no such disk.
+pub fn with_disk<R>(name: &str, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+    DISKS.lock().iter_mut().find(|disk| disk.name == name).map(|disk| f(disk.device.as_mut()))
+}
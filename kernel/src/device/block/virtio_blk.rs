@@ -0,0 +1,214 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The virtio block device (`-drive if=virtio`, or `-device virtio-blk-pci`),
+//! through either the legacy or the modern interface. A request is a chain of
+//! buffers on the single queue:
+//!
+//! | Part   | Direction    | Contents                                          |
+//! |--------|--------------|---------------------------------------------------|
+//! | Header | Device reads | The type (in, out, flush), and the first sector   |
+//! | Data   | Either       | The sectors, read by the device for out requests  |
+//! | Status | Device writes| One byte, zero when the request succeeded         |
+//!
+//! The disks are registered as `vda`, `vdb` and so on. The device always counts in sectors of [`SECTOR_SIZE`] bytes, whatever the
+//! block size it advertises, and so does the driver.
+//!
+//! There is no interrupt routing for virtio devices yet, so a request spins
+//! until the device has used it, for at most [`REQUEST_TIMEOUT`]. Requests
+//! are done one at a time, split to fit the buffers of the queue.
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 5.2 Block Device](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+
+use crate::{
+    arch,
+    dev_info,
+    dev_warn,
+    device::{
+        clocksource,
+        pci::{PciDriver, PciVendorId},
+        registry::DeviceId,
+        virtio::{Transport, Virtqueue},
+        DeviceError,
+    },
+    meta::counters::Counter,
+};
+
+use super::{BlockDevice, BlockError};
+
+/// The device IDs of the transitional block device, and of the modern-only
+/// one.
+const DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// The most sectors of a request, such that the header, the data and the
+/// status fit the buffers of the queue.
+const MAX_SECTORS_PER_REQUEST: usize = 8;
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The frequency assumed for the deadline of a request before the TSC is
+/// calibrated.
+const FALLBACK_TSC_FREQUENCY: u64 = 3_000_000_000;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const HEADER_SIZE: usize = 16;
+const STATUS_OK: u8 = 0;
+
+const FEATURE_RO: u32 = 1 << 5;
+
+/// The device has a write cache, which the flush request writes back.
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+const CONFIG_CAPACITY: u16 = 0x00;
+
+static READ: Counter = Counter::new("block.virtio.read", "Sectors read from the virtio disks");
+static WRITTEN: Counter = Counter::new("block.virtio.written", "Sectors written to the virtio disks");
+static ERRORS: Counter = Counter::new("block.virtio.errors", "Requests the virtio disks failed or didn't finish in time");
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: |info| info.vendor_id == PciVendorId::RED_HAT && DEVICE_IDS.contains(&info.device_id.value()),
+    probe: |device, info| {
+        let result = Transport::new(device, info)
+            .and_then(|transport| VirtioBlk::init(device, transport))
+            .map(|disk| {
+                let (size, interface, read_only) = (disk.capacity * SECTOR_SIZE as u64 / 1024 / 1024, disk.transport.name(), disk.read_only);
+                let name = super::register("vd", Box::new(disk));
+                dev_info!(device, "Disk {name} of {size} MiB through the {interface} interface{}",
+                    if read_only { ", read-only" } else { "" });
+            });
+        Box::pin(async move { result })
+    },
+    timeout: Duration::from_millis(500),
+};
+
+pub struct VirtioBlk {
+    device: DeviceId,
+    transport: Transport,
+    queue: Virtqueue,
+
+    /// In sectors.
+    capacity: u64,
+    read_only: bool,
+    has_write_cache: bool,
+}
+
+impl VirtioBlk {
+    fn init(device: DeviceId, mut transport: Transport) -> Result<Self, DeviceError> {
+        let features = transport.negotiate(FEATURE_RO | FEATURE_FLUSH)?;
+        let queue = match transport.setup_queue(0) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        transport.finish();
+
+        let capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        Ok(Self {
+            device,
+            transport,
+            queue,
+            capacity,
+            read_only: features & FEATURE_RO != 0,
+            has_write_cache: features & FEATURE_FLUSH != 0,
+        })
+    }
+
+    /// Push a request and wait for the device to use it, returning what the
+    /// device wrote: the data of an in request, followed by the status.
+    fn request(&mut self, kind: u32, sector: u64, data: &[u8], reply: usize) -> Result<Vec<u8>, BlockError> {
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+
+        let readable: &[&[u8]] = if data.is_empty() { &[&header] } else { &[&header, data] };
+        let writable: &[usize] = if reply == 0 { &[1] } else { &[reply, 1] };
+        if !self.queue.push_chain(readable, writable) {
+            // Only when an earlier request never came back.
+            return Err(BlockError::Timeout);
+        }
+        self.transport.notify(&self.queue);
+
+        let frequency = match clocksource::tsc_frequency() {
+            0 => FALLBACK_TSC_FREQUENCY,
+            frequency => frequency,
+        };
+        let deadline = arch::cycles() + frequency * REQUEST_TIMEOUT.as_secs();
+
+        let mut written = None;
+        while !self.queue.pop_used_chain(|bytes| written = Some(bytes.to_vec())) {
+            if arch::cycles() > deadline {
+                dev_warn!(self.device, "Request for sector {sector} timed out");
+                ERRORS.increment();
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        let written = written.unwrap_or_default();
+        match written.last() {
+            Some(&STATUS_OK) if written.len() == reply + 1 => Ok(written),
+            status => {
+                dev_warn!(self.device, "Request for sector {sector} failed with status {status:?}");
+                ERRORS.increment();
+                Err(BlockError::Io)
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buffer.len())?;
+        for (index, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let sector = lba + (index * MAX_SECTORS_PER_REQUEST) as u64;
+            let written = self.request(REQUEST_IN, sector, &[], chunk.len())?;
+            chunk.copy_from_slice(&written[..chunk.len()]);
+            READ.add((chunk.len() / SECTOR_SIZE) as u64);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+
+        self.check_request(lba, data.len())?;
+        for (index, chunk) in data.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let sector = lba + (index * MAX_SECTORS_PER_REQUEST) as u64;
+            self.request(REQUEST_OUT, sector, chunk, 0)?;
+            WRITTEN.add((chunk.len() / SECTOR_SIZE) as u64);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if self.has_write_cache {
+            self.request(REQUEST_FLUSH, 0, &[], 0)?;
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
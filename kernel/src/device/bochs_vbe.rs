@@ -0,0 +1,105 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The VBE extensions of the display adapter of Bochs and QEMU (`-vga std`),
+//! also known as DISPI, through which the resolution can be changed at
+//! runtime. The linear framebuffer stays at the same address, so the console
+//! keeps drawing into the buffer of the bootloader, as long as the new mode
+//! fits in it.
+//!
+//! ### References:
+//! - [OSDev Wiki: Bochs VBE Extensions](https://wiki.osdev.org/Bochs_VBE_Extensions)
+
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+
+use crate::arch::port::{AuditedPort, PortUser};
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+/// The versions of the interface that support a linear framebuffer.
+const SUPPORTED_IDS: core::ops::RangeInclusive<u16> = 0xB0C2..=0xB0C5;
+
+const ENABLED: u16 = 0x01;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+
+/// Every mode we set has 32-bit pixels, of which the order is BGRX.
+const BITS_PER_PIXEL: u16 = 32;
+const BYTES_PER_PIXEL: usize = BITS_PER_PIXEL as usize / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+enum Register {
+    Id = 0,
+    XResolution = 1,
+    YResolution = 2,
+    BitsPerPixel = 3,
+    Enable = 4,
+    VirtualWidth = 6,
+    XOffset = 8,
+    YOffset = 9,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VbeError {
+    NotPresent,
+
+    /// The framebuffer of the bootloader is too small for the mode.
+    TooLarge,
+
+    /// The adapter didn't accept the mode.
+    Unsupported,
+}
+
+fn read(register: Register) -> u16 {
+    unsafe {
+        AuditedPort::<u16>::new(INDEX_PORT, PortUser::Display).write(register as u16);
+        AuditedPort::<u16>::new(DATA_PORT, PortUser::Display).read()
+    }
+}
+
+fn write(register: Register, value: u16) {
+    unsafe {
+        AuditedPort::<u16>::new(INDEX_PORT, PortUser::Display).write(register as u16);
+        AuditedPort::<u16>::new(DATA_PORT, PortUser::Display).write(value);
+    }
+}
+
+pub fn is_present() -> bool {
+    SUPPORTED_IDS.contains(&read(Register::Id))
+}
+
+/// Switch to the resolution, of which the framebuffer must fit in
+/// `buffer_len` bytes, returning the layout of the new mode.
+pub fn set_mode(width: u16, height: u16, buffer_len: usize) -> Result<FrameBufferInfo, VbeError> {
+    if !is_present() {
+        return Err(VbeError::NotPresent);
+    }
+
+    let byte_len = width as usize * height as usize * BYTES_PER_PIXEL;
+    if byte_len == 0 || byte_len > buffer_len {
+        return Err(VbeError::TooLarge);
+    }
+
+    // The mode can only be changed while disabled.
+    write(Register::Enable, 0);
+    write(Register::XResolution, width);
+    write(Register::YResolution, height);
+    write(Register::BitsPerPixel, BITS_PER_PIXEL);
+    write(Register::Enable, ENABLED | LINEAR_FRAMEBUFFER);
+    write(Register::XOffset, 0);
+    write(Register::YOffset, 0);
+
+    if read(Register::XResolution) != width || read(Register::YResolution) != height {
+        return Err(VbeError::Unsupported);
+    }
+
+    Ok(FrameBufferInfo {
+        byte_len,
+        width: width as usize,
+        height: height as usize,
+        pixel_format: PixelFormat::Bgr,
+        bytes_per_pixel: BYTES_PER_PIXEL,
+        stride: read(Register::VirtualWidth) as usize,
+    })
+}
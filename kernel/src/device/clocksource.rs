@@ -0,0 +1,241 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Selection of the timer that generates the periodic tick.
+//!
+//! Every timer the machine might have is listed in [`SOURCES`], and the ones
+//! that are present are scored on their stability, resolution and the cost of
+//! programming them. The best one is started, unless another is picked with the
+//! `clocksource=` boot parameter.
+//!
+//! Timers can misbehave on real hardware (or stop when the interrupt routing
+//! changes), so the executor regularly compares the ticks with the time stamp
+//! counter (see [`check_stability`]). When a source falls behind, it is marked
+//! as unstable and the next best source takes over.
+//!
+//! Only the PIT has a driver for now, the other sources are detected so they
+//! show up in the `clocksource` shell command.
+//!
+//! ### References:
+//! - [OSDev Wiki: Timer Interrupt Sources](https://wiki.osdev.org/Timer_Interrupt_Sources)
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use log::{info, trace, warn};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+
+use crate::{arch, meta::BootParameters};
+
+use super::{acpi::tables, pit};
+
+const NONE: usize = usize::MAX;
+
+/// How long the ticks are compared with the time stamp counter.
+const CHECK_INTERVAL_SECONDS: u64 = 2;
+const CALIBRATION_MILLISECONDS: u64 = 50;
+
+/// The index in [`SOURCES`] of the running source.
+static CURRENT: AtomicUsize = AtomicUsize::new(NONE);
+
+/// The sources that were found unstable, by their index in [`SOURCES`].
+static UNSTABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// The frequency of the time stamp counter, measured against the first source.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The ticks and cycles at the last stability check.
+static LAST_CHECK: Mutex<Option<(usize, u64)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cost {
+    /// Programmed through MSRs or local registers.
+    Low,
+
+    /// Programmed through I/O ports or uncached MMIO.
+    High,
+}
+
+pub struct ClockSource {
+    pub name: &'static str,
+    pub resolution_ns: u64,
+    pub cost: Cost,
+    pub detect: fn() -> bool,
+
+    /// Whether the rate stays the same across power states.
+    pub is_stable: fn() -> bool,
+
+    /// Start ticking at [`arch::TICKS_PER_SECOND`], `None` when there is no
+    /// driver for the source yet.
+    pub start: Option<fn()>,
+}
+
+impl ClockSource {
+    /// Higher is better: stability trumps cost, which trumps resolution.
+    pub fn score(&self) -> u64 {
+        let stability = if (self.is_stable)() { 10_000 } else { 0 };
+        let cost = match self.cost {
+            Cost::Low => 1_000,
+            Cost::High => 0,
+        };
+
+        stability + cost + 1_000 / self.resolution_ns.max(1)
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.start.is_some() && (self.detect)()
+    }
+}
+
+pub const SOURCES: &[ClockSource] = &[
+    ClockSource {
+        name: "pit",
+        resolution_ns: 838,
+        cost: Cost::High,
+        detect: || true,
+        is_stable: || true,
+        start: Some(pit::init),
+    },
+    ClockSource {
+        name: "hpet",
+        resolution_ns: 100,
+        cost: Cost::High,
+        detect: || tables::tables().iter().any(|table| table.signature() == "HPET"),
+        is_stable: || true,
+        start: None,
+    },
+    ClockSource {
+        name: "apic",
+        resolution_ns: 10,
+        cost: Cost::Low,
+        detect: || CpuId::new().get_feature_info().is_some_and(|info| info.has_apic()),
+
+        // Without an Always Running APIC Timer, it stops in deep C-states.
+        is_stable: || CpuId::new().get_thermal_power_info().is_some_and(|info| info.has_arat()),
+        start: None,
+    },
+    ClockSource {
+        name: "tsc-deadline",
+        resolution_ns: 1,
+        cost: Cost::Low,
+        detect: || CpuId::new().get_feature_info().is_some_and(|info| info.has_tsc_deadline()),
+        is_stable: || CpuId::new().get_advanced_power_mgmt_info().is_some_and(|info| info.has_invariant_tsc()),
+        start: None,
+    },
+];
+
+/// Start the best source available this early, before the heap and ACPI.
+pub fn init() {
+    if let Some(index) = best(None) {
+        switch_to(index);
+    }
+}
+
+/// Select the source again, now that the ACPI tables and boot parameters are
+/// available, and calibrate the time stamp counter against it. Must be called
+/// before switching to the APIC, which stops the legacy interrupts.
+pub fn select() {
+    let requested = BootParameters::get("clocksource");
+    if let Some(name) = requested {
+        match SOURCES.iter().position(|source| source.name == name) {
+            Some(index) if SOURCES[index].is_usable() => switch_to(index),
+            Some(_) => warn!("Clock source `{name}` isn't available, ignoring clocksource="),
+            None => warn!("Unknown clock source `{name}`, ignoring clocksource="),
+        }
+    }
+
+    if requested.is_none() || CURRENT.load(Ordering::Relaxed) == NONE {
+        if let Some(index) = best(None) {
+            switch_to(index);
+        }
+    }
+
+    calibrate();
+}
+
+/// The running source, if any.
+pub fn current() -> Option<&'static ClockSource> {
+    SOURCES.get(CURRENT.load(Ordering::Relaxed))
+}
+
+/// The frequency of the time stamp counter in Hz, or zero before the first
+/// source is selected.
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn is_unstable(source: &ClockSource) -> bool {
+    SOURCES.iter()
+        .position(|candidate| core::ptr::eq(candidate, source))
+        .is_some_and(|index| UNSTABLE.load(Ordering::Relaxed) & (1 << index) != 0)
+}
+
+/// The best usable source that isn't unstable, other than `except`.
+fn best(except: Option<usize>) -> Option<usize> {
+    let unstable = UNSTABLE.load(Ordering::Relaxed);
+    SOURCES.iter()
+        .enumerate()
+        .filter(|(index, source)| Some(*index) != except && unstable & (1 << index) == 0 && source.is_usable())
+        .max_by_key(|(_, source)| source.score())
+        .map(|(index, _)| index)
+}
+
+fn switch_to(index: usize) {
+    if CURRENT.swap(index, Ordering::Relaxed) == index {
+        return;
+    }
+
+    let source = &SOURCES[index];
+    info!("Using clock source `{}`", source.name);
+    if let Some(start) = source.start {
+        start();
+    }
+}
+
+fn calibrate() {
+    let start = arch::cycles();
+    pit::sleep(core::time::Duration::from_millis(CALIBRATION_MILLISECONDS));
+    let frequency = (arch::cycles() - start) * 1000 / CALIBRATION_MILLISECONDS;
+
+    trace!("Time stamp counter runs at {} MHz", frequency / 1_000_000);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Compare the ticks with the time stamp counter, and switch to another source
+/// when the current one falls behind. Called by the executor whenever it wakes
+/// up, so it still runs when the ticks stopped altogether.
+pub fn check_stability() {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    let Some(mut last) = LAST_CHECK.try_lock() else {
+        return;
+    };
+
+    let (ticks, cycles) = (arch::ticks(), arch::cycles());
+    let Some((last_ticks, last_cycles)) = *last else {
+        *last = Some((ticks, cycles));
+        return;
+    };
+
+    let elapsed = cycles - last_cycles;
+    if frequency == 0 || elapsed < frequency * CHECK_INTERVAL_SECONDS {
+        return;
+    }
+    *last = Some((ticks, cycles));
+
+    let expected = elapsed * arch::TICKS_PER_SECOND as u64 / frequency;
+    let actual = (ticks - last_ticks) as u64;
+    if actual >= expected / 2 {
+        return;
+    }
+
+    let index = CURRENT.load(Ordering::Relaxed);
+    if index == NONE || UNSTABLE.fetch_or(1 << index, Ordering::Relaxed) & (1 << index) != 0 {
+        return;
+    }
+
+    warn!("Clock source `{}` is unstable: {actual} ticks where {expected} were expected", SOURCES[index].name);
+    match best(Some(index)) {
+        Some(next) => switch_to(next),
+        None => warn!("No other clock source available, timers will be unreliable"),
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The QEMU firmware configuration device, through which the host can pass
+//! files to the guest.
+//!
+//! The `os` runner passes the `--fw-cfg <name>=<value>` options as the files
+//! `opt/nocciolo/<name>`. The `cmdline` file contains extra boot parameters,
+//! which are appended to the ones baked in with `NOCCIOLO_BOOT_ARGS`; other
+//! files can be used as fixtures for tests, and are listed by the `fwcfg`
+//! shell command.
+//!
+//! Only the I/O port interface is supported, which is what QEMU exposes on
+//! x86. Values in the file directory are big endian, whereas the items
+//! themselves are little endian.
+//!
+//! ### References:
+//! - [QEMU Firmware Configuration (fw_cfg) Device](https://www.qemu.org/docs/master/specs/fw_cfg.html)
+
+use alloc::{string::String, vec, vec::Vec};
+
+use log::{info, trace, warn};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::meta::{containment::Feature, init::HeapInitialized, BootParameters};
+
+use super::registry::{self, Resource};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SELECTOR_SIGNATURE: u16 = 0x0000;
+const SELECTOR_FILE_DIRECTORY: u16 = 0x0019;
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+
+/// The prefix of the files meant for the kernel, as QEMU reserves the names
+/// outside of `opt/`.
+pub const FILE_PREFIX: &str = "opt/nocciolo/";
+
+const FILE_NAME_SIZE: usize = 56;
+
+/// The most files QEMU puts in the directory, so a larger count is garbage.
+const MAX_FILES: usize = 0x1000;
+
+/// Reading the directory is contained, since a bogus one would otherwise take
+/// the boot down with it.
+static FEATURE: Feature = Feature::new("fw_cfg", "QEMU firmware configuration files");
+
+// Selecting an item and reading it must not be interleaved.
+static PORTS: Mutex<FwCfgPorts> = Mutex::new(FwCfgPorts {
+    selector: Port::new(SELECTOR_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+struct FwCfgPorts {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfgPorts {
+    fn read(&mut self, selector: u16, buffer: &mut [u8]) {
+        unsafe {
+            self.selector.write(selector);
+            for byte in buffer {
+                *byte = self.data.read();
+            }
+        }
+    }
+}
+
+/// An entry of the file directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub size: u32,
+    selector: u16,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+enum FwCfgError {
+    TooManyFiles(usize),
+}
+
+pub struct FwCfg;
+
+impl FwCfg {
+    /// Whether the device is present, i.e. we're running under QEMU, and
+    /// hasn't been disabled.
+    pub fn is_present() -> bool {
+        if !FEATURE.is_available() {
+            return false;
+        }
+
+        let mut signature = [0; 4];
+        PORTS.lock().read(SELECTOR_SIGNATURE, &mut signature);
+        &signature == SIGNATURE
+    }
+
+    pub fn files() -> Vec<FwCfgFile> {
+        if !Self::is_present() {
+            return Vec::new();
+        }
+
+        FEATURE.run(Self::read_directory).unwrap_or_default()
+    }
+
+    fn read_directory() -> Result<Vec<FwCfgFile>, FwCfgError> {
+        let mut ports = PORTS.lock();
+
+        // The directory is read in one go, since selecting resets the offset.
+        let mut count = [0; 4];
+        ports.read(SELECTOR_FILE_DIRECTORY, &mut count);
+        let count = u32::from_be_bytes(count) as usize;
+        if count > MAX_FILES {
+            return Err(FwCfgError::TooManyFiles(count));
+        }
+
+        let mut data = vec![0; 4 + count * (8 + FILE_NAME_SIZE)];
+        ports.read(SELECTOR_FILE_DIRECTORY, &mut data);
+
+        let files = data[4..].chunks_exact(8 + FILE_NAME_SIZE)
+            .map(|entry| {
+                let name = &entry[8..];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+
+                FwCfgFile {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    size: u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+                    selector: u16::from_be_bytes([entry[4], entry[5]]),
+                }
+            })
+            .collect();
+        Ok(files)
+    }
+
+    pub fn find(name: &str) -> Option<FwCfgFile> {
+        Self::files().into_iter().find(|file| file.name == name)
+    }
+
+    pub fn read(file: &FwCfgFile) -> Vec<u8> {
+        let mut data = vec![0; file.size as usize];
+        PORTS.lock().read(file.selector, &mut data);
+        data
+    }
+
+    /// Read one of the files passed by the `os` runner, by the name without
+    /// the [`FILE_PREFIX`].
+    pub fn read_file(name: &str) -> Option<Vec<u8>> {
+        let file = Self::find(&alloc::format!("{FILE_PREFIX}{name}"))?;
+        Some(Self::read(&file))
+    }
+}
+
+/// Append the boot parameters of the `cmdline` file, if present.
+pub fn init(_: HeapInitialized) {
+    if !FwCfg::is_present() {
+        trace!("No fw_cfg device present");
+        return;
+    }
+
+    registry::register_io_device(&[Resource::io_ports(SELECTOR_PORT as u64, 2)], "fw_cfg", "QEMU firmware configuration");
+
+    let Some(cmdline) = FwCfg::read_file("cmdline") else {
+        return;
+    };
+
+    match String::from_utf8(cmdline) {
+        Ok(cmdline) => {
+            info!("Boot parameters from fw_cfg: {}", cmdline.trim());
+            BootParameters::extend(cmdline.leak());
+        }
+        Err(e) => warn!("Ignoring fw_cfg command line, it isn't valid UTF-8: {e}"),
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A tiny channel between the kernel and the host, on the second serial port
+//! (COM2), so the `os` runner can query the running machine instead of
+//! sleeping or scraping the log (see `cargo run agent`).
+//!
+//! The protocol is line based: the host sends a command and the kernel answers
+//! with a single line.
+//!
+//! | Command    | Response                                                       |
+//! |------------|----------------------------------------------------------------|
+//! | `ping`     | `pong`                                                         |
+//! | `status`   | `ready uptime_ms=<milliseconds>`, followed by the counters     |
+//! | `bench`    | `ok`, followed by `<name>=<mean>/<stddev>` of every benchmark  |
+//! | `latency`  | `ok`, then `<irq>.<kind>=<p50>/<p90>/<p99>/<max>` in cycles    |
+//! | `report`   | The boot report, a JSON object with the build and the uptime   |
+//! | `help`     | The shell commands and topics as JSON, see [`meta::help`]      |
+//! | `shutdown` | `ok`, after which the machine is powered off                   |
+//!
+//! When the kernel panics, it sends a `panic <message>` line unprompted.
+//!
+//! The same protocol is spoken on the `org.nocciolo.agent` port of the virtio
+//! console (see [`console`]), for machines without a second serial port. The
+//! responses go to the channel the command came from, and panics are only
+//! reported on COM2.
+//!
+//! The commands are handled by a task, which only runs after the kernel
+//! finished booting, so a command that isn't answered means the kernel is
+//! still booting (or hung). When the task starts, it announces this with a
+//! `booted` line.
+
+use alloc::string::String;
+use core::{fmt::Write, sync::atomic::{AtomicBool, Ordering}, task::Poll};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::{info, trace, warn};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    arch,
+    meta::{self, bench, counters, init::HeapInitialized, irq_latency::{self, Irq}, BootParameters, System},
+};
+
+use super::{registry::{self, Resource}, virtio::console};
+
+const COM2: u16 = 0x2F8;
+
+const REGISTER_SCRATCH: u16 = COM2 + 7;
+const REGISTER_LINE_STATUS: u16 = COM2 + 5;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMITTER_EMPTY: u8 = 1 << 5;
+
+/// The size of the receive FIFO of the 16550.
+const FIFO_SIZE: usize = 16;
+
+const QUEUE_CAPACITY: usize = 256;
+const MAX_LINE_LENGTH: usize = 128;
+
+static PORT: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(COM2) });
+
+static RECEIVED: OnceCell<ArrayQueue<(Channel, u8)>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Whether COM2 exists, and is initialized.
+static SERIAL_PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Where a command came from, and its response goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Serial = 0,
+    Virtio = 1,
+}
+
+/// Initialize the port, if present.
+pub fn init(_: HeapInitialized) {
+    RECEIVED.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("guest_agent::init should only be called once");
+
+    if !is_present() {
+        trace!("No second serial port, the guest agent only listens on the virtio console");
+        return;
+    }
+
+    PORT.lock().init();
+    registry::register_io_device(&[Resource::io_ports(COM2 as u64, 8)], "guest-agent", "Serial port (COM2)");
+    SERIAL_PRESENT.store(true, Ordering::Relaxed);
+}
+
+/// Whether there is a UART at COM2, since reading an absent port always
+/// yields 0xFF, which looks like data is ready.
+fn is_present() -> bool {
+    let mut scratch = Port::<u8>::new(REGISTER_SCRATCH);
+    unsafe {
+        scratch.write(0xAE);
+        scratch.read() == 0xAE
+    }
+}
+
+/// Called by the interrupt handler of the second serial port.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_interrupt() {
+    let Ok(queue) = RECEIVED.try_get() else {
+        return;
+    };
+
+    if !SERIAL_PRESENT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut line_status = Port::<u8>::new(REGISTER_LINE_STATUS);
+    let mut data = Port::<u8>::new(COM2);
+
+    // Reading the data acknowledges the interrupt.
+    for _ in 0..FIFO_SIZE {
+        if unsafe { line_status.read() } & LINE_STATUS_DATA_READY == 0 {
+            break;
+        }
+
+        // Commands that don't fit are answered with an error anyway.
+        _ = queue.push((Channel::Serial, unsafe { data.read() }));
+    }
+
+    WAKER.wake();
+    irq_latency::woke_consumer(Irq::Agent);
+}
+
+/// Called by the virtio console with the data received on the agent port.
+pub(crate) fn receive_from_virtio(data: &[u8]) {
+    let Ok(queue) = RECEIVED.try_get() else {
+        return;
+    };
+
+    for byte in data {
+        _ = queue.push((Channel::Virtio, *byte));
+    }
+    WAKER.wake();
+}
+
+/// Answers the commands of the host.
+pub async fn run() {
+    let Ok(queue) = RECEIVED.try_get() else {
+        return;
+    };
+
+    if SERIAL_PRESENT.load(Ordering::Relaxed) {
+        info!("Guest agent listening on COM2");
+        send(Channel::Serial, "booted");
+    } else if console::is_present() {
+        info!("Guest agent listening on the virtio console");
+    } else {
+        return;
+    }
+
+    let mut lines = [String::new(), String::new()];
+    loop {
+        let (channel, byte) = poll_fn(|cx| {
+            if let Some(byte) = queue.pop() {
+                return Poll::Ready(byte);
+            }
+
+            WAKER.register(cx.waker());
+            match queue.pop() {
+                Some(byte) => {
+                    WAKER.take();
+                    Poll::Ready(byte)
+                }
+                None => Poll::Pending,
+            }
+        }).await;
+
+        if channel == Channel::Serial {
+            irq_latency::consumed(Irq::Agent);
+        }
+
+        let line = &mut lines[channel as usize];
+        match byte {
+            b'\n' => {
+                handle_command(line.trim(), channel);
+                line.clear();
+            }
+
+            _ if line.len() < MAX_LINE_LENGTH => line.push(byte as char),
+
+            _ => (),
+        }
+    }
+}
+
+fn handle_command(command: &str, channel: Channel) {
+    trace!("Guest agent command on {channel:?}: {command}");
+    let send = |line: &str| send(channel, line);
+    match command {
+        "" => (),
+
+        "ping" => send("pong"),
+
+        "status" => {
+            let uptime = arch::ticks() * 1000 / arch::TICKS_PER_SECOND;
+            let mut status = alloc::format!("ready uptime_ms={uptime}");
+            for counter in counters::all() {
+                _ = write!(status, " {}={}", counter.name(), counter.value());
+            }
+            send(&status);
+        }
+
+        "report" => send(&boot_report()),
+
+        "help" => send(&crate::task::shell::documentation_json()),
+
+        "bench" => {
+            let mut results = String::from("ok");
+            for benchmark in bench::BENCHMARKS {
+                let result = bench::run(benchmark);
+                _ = write!(results, " {}={}/{}", benchmark.name, result.mean, result.stddev);
+            }
+            send(&results);
+        }
+
+        "latency" => {
+            let mut results = String::from("ok");
+            for irq in Irq::ALL {
+                let statistics = irq_latency::statistics(irq);
+                for (kind, latency) in [("handler", statistics.handler), ("wake", statistics.wake)] {
+                    _ = write!(results, " {}.{kind}={}/{}/{}/{}",
+                        irq.name(), latency.p50, latency.p90, latency.p99, latency.max);
+                }
+            }
+            send(&results);
+        }
+
+        "shutdown" => {
+            send("ok");
+            System::request_shutdown();
+        }
+
+        _ => {
+            warn!("Unknown guest agent command `{command}`");
+            send("error unknown command");
+        }
+    }
+}
+
+/// The build of the kernel and what it booted on, as a single line of JSON.
+pub(crate) fn boot_report() -> String {
+    let version = meta::version();
+    let uptime = arch::ticks() * 1000 / arch::TICKS_PER_SECOND;
+    let hypervisor = System::detect_hypervisor().map(|kind| alloc::format!("{kind:?}"));
+
+    let mut report = String::from("{");
+    for (key, value) in [
+        ("version", Some(version.version)),
+        ("build_id", Some(version.build_id)),
+        ("git", Some(version.git_hash)),
+        ("built", Some(version.build_time)),
+        ("rustc", Some(version.rustc)),
+        ("hypervisor", hypervisor.as_deref()),
+        ("parameters", Some(BootParameters::raw())),
+    ] {
+        match value {
+            Some(value) => _ = write!(report, "\"{key}\":\"{}\",", value.escape_debug()),
+            None => _ = write!(report, "\"{key}\":null,"),
+        }
+    }
+    _ = write!(report, "\"uptime_ms\":{uptime}}}");
+    report
+}
+
+/// Report a panic to the host as a `panic <message>` line, without waiting
+/// for the lock of the port, since the panicking code might hold it.
+///
+/// Must not block or allocate.
+pub(crate) fn send_panic(message: &str) {
+    if !SERIAL_PRESENT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut line_status = Port::<u8>::new(REGISTER_LINE_STATUS);
+    let mut data = Port::<u8>::new(COM2);
+
+    let line = message.bytes().map(|byte| if byte == b'\n' { b' ' } else { byte });
+    for byte in b"panic ".iter().copied().chain(line).chain(core::iter::once(b'\n')) {
+        while unsafe { line_status.read() } & LINE_STATUS_TRANSMITTER_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { data.write(byte) };
+    }
+}
+
+fn send(channel: Channel, line: &str) {
+    if channel == Channel::Virtio {
+        let mut line = String::from(line);
+        line.push('\n');
+        console::write(console::AGENT_PORT, line.as_bytes());
+        return;
+    }
+
+    let mut port = PORT.lock();
+    for byte in line.bytes().chain(core::iter::once(b'\n')) {
+        port.send(byte);
+    }
+}
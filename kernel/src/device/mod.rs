@@ -2,20 +2,65 @@
 // All Rights Reserved.
 
 pub mod acpi;
+pub mod ata;
+pub mod block;
+pub mod bochs_vbe;
+pub mod clocksource;
+pub mod fw_cfg;
+pub mod guest_agent;
+pub mod net;
 pub mod pci;
 pub mod pit;
-mod net;
+pub mod probe;
+pub mod ps2;
+pub mod registry;
+pub mod virtio;
+
+use core::fmt::{Display, Formatter};
 
 use ::acpi::AcpiError;
 use aml::AmlError;
 use bootloader_api::BootInfo;
 
+use crate::meta::init::HeapInitialized;
+
+use self::registry::Resource;
+
+/// The legacy devices at fixed ports the kernel drives itself, with their
+/// driver and description.
+const PLATFORM_DEVICES: &[(&[Resource], &str, &str)] = &[
+    (&[Resource::io_ports(0x20, 2), Resource::io_ports(0xA0, 2)], "pic", "8259 interrupt controllers"),
+    (&[Resource::io_ports(0x40, 4)], "pit", "Programmable interval timer"),
+    (&[Resource::io_ports(0x1F0, 8), Resource::io_ports(0x3F6, 1)], "ata", "ATA controller (primary)"),
+    (&[Resource::io_ports(0x3F8, 8)], "serial", "Serial port (COM1)"),
+    (&[Resource::io_ports(0xCF8, 8)], "pci-config", "PCI configuration mechanism"),
+];
+
+/// Claim the ports of the legacy devices, which must be done before the AML
+/// runs, so it can't touch them.
+pub fn init_platform(_: HeapInitialized) {
+    for (ports, driver, description) in PLATFORM_DEVICES {
+        registry::register_io_device(ports, driver, description);
+    }
+
+    if bochs_vbe::is_present() {
+        registry::register_io_device(&[Resource::io_ports(0x1CE, 2)], "bochs-vbe", "Bochs VBE extensions");
+    }
+}
+
 pub fn init(boot_info: &'static BootInfo) {
     pci::init(boot_info);
+    probe::run_deferred();
+    block::init();
+    pci::verify_expected_devices();
 }
 
 pub trait GenericDevice {
-    fn initialize(&mut self, pci: &impl pci::ConfigurationSpaceMechanism) -> Result<(), DeviceError>;
+    /// Only for the concrete devices, so the traits built on this one can
+    /// be used as trait objects once the devices are set up.
+    fn initialize(&mut self, pci: &impl pci::ConfigurationSpaceMechanism) -> Result<(), DeviceError>
+    where
+        Self: Sized;
 }
 
 #[derive(Debug)]
@@ -45,12 +90,44 @@ impl DeviceError {
             region: "(unknown)",
         }
     }
+
+    /// The device doesn't offer what the driver needs.
+    pub fn unsupported(reason: &'static str) -> Self {
+        DeviceError {
+            kind: DeviceErrorKind::Unsupported(reason),
+            region: "probe",
+        }
+    }
+
+    pub fn timeout() -> Self {
+        DeviceError {
+            kind: DeviceErrorKind::Timeout,
+            region: "probe",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum DeviceErrorKind {
     Acpi(AcpiError),
     Aml(AmlError),
+
+    /// The device didn't finish initializing in time.
+    Timeout,
+
+    /// The device doesn't offer what the driver needs, for the given reason.
+    Unsupported(&'static str),
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match &self.kind {
+            DeviceErrorKind::Acpi(e) => write!(f, "ACPI error in {}: {e:?}", self.region),
+            DeviceErrorKind::Aml(e) => write!(f, "AML error in {}: {e:?}", self.region),
+            DeviceErrorKind::Timeout => f.write_str("timed out"),
+            DeviceErrorKind::Unsupported(reason) => write!(f, "unsupported: {reason}"),
+        }
+    }
 }
 
 impl From<AcpiError> for DeviceError {
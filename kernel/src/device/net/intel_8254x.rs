@@ -1,28 +1,666 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
-use crate::device::{
-    pci::{ConfigurationSpaceMechanism, PciAddress},
-    DeviceError,
-    GenericDevice,
+//! The Intel 8254x gigabit Ethernet controllers (e1000), of which QEMU and
+//! Bochs emulate the 82540EM.
+//!
+//! The registers are in the memory BAR0. Frames are moved through a receive
+//! ring of [`RX_DESCRIPTORS`] and a transmit ring of [`TX_DESCRIPTORS`]
+//! descriptors in DMA memory, each pointing to its own buffer of
+//! [`BUFFER_SIZE`] bytes. The card owns the descriptors from the head up to
+//! the tail of a ring, and sets their "descriptor done" bit when it's
+//! finished with them, after which the driver copies the frame out (receive)
+//! or reuses the buffer (transmit), and moves the tail along.
+//!
+//! The card signals changes of the link status to the `e1000` task with an
+//! interrupt: a message-signaled one when it supports them (see
+//! [`msi`](crate::device::pci::msi)), or else its interrupt line, routed
+//! through the I/O APIC. The line stays asserted until the causes are read,
+//! which the handler then does itself. Without either, its interrupts are
+//! masked, and the task polls the link status.
+//!
+//! ### References:
+//! - [PCI/PCI-X Family of Gigabit Ethernet Controllers Software Developer's Manual](https://www.intel.com/content/dam/doc/manual/pci-pci-x-family-gbe-controllers-software-dev-manual.pdf)
+//! - [OSDev Wiki: Intel Ethernet i217](https://wiki.osdev.org/Intel_Ethernet_i217)
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    ptr,
+    sync::atomic::{fence, AtomicBool, Ordering},
+    task::Poll,
+    time::Duration,
 };
 
-use super::NetworkDevice;
+use acpi::PhysicalMapping;
+use futures_util::{future::poll_fn, task::AtomicWaker};
+
+use crate::{
+    arch::{
+        interrupts::{self, apic::IOApic},
+        memory::{self, DmaRegion},
+    },
+    dev_info,
+    dev_trace,
+    dev_warn,
+    device::{
+        acpi::NoccioloAcpiHandler,
+        pci::{
+            msi::{self, MessageInterrupt},
+            ConfigurationSpaceMechanism,
+            PciAddress,
+            PciBaseAddressType,
+            PciDriver,
+            PciConfigurationSpace,
+            PciVendorId,
+        },
+        registry::{self, DeviceId, Resource},
+        DeviceError,
+        GenericDevice,
+    },
+    meta::{counters::Counter, memory_map::{self, RegionKind}},
+    net::{interface, MacAddress},
+    sync::{IrqSpinlock, Spinlock},
+    task::timer,
+};
+
+use super::{LinkStatus, NetworkDevice, NetworkError, MAX_FRAME_SIZE, MIN_FRAME_SIZE};
+
+/// The device IDs of the 8254x family, the 82540EM (0x100E) being the one
+/// emulated by QEMU and Bochs.
+const DEVICE_IDS: &[u16] = &[
+    0x1000, 0x1001, 0x1004, 0x1008, 0x1009, 0x100C, 0x100D, 0x100E, 0x100F, 0x1010, 0x1011, 0x1012, 0x1013,
+    0x1015, 0x1016, 0x1017, 0x1018, 0x1019, 0x101A, 0x101D, 0x101E, 0x1026, 0x1027, 0x1028, 0x1075, 0x1076,
+    0x1077, 0x1078, 0x1079, 0x107A, 0x107B, 0x107C, 0x108A,
+];
+
+/// The number of descriptors of the rings, of which the size in bytes must
+/// be a multiple of 128.
+pub const RX_DESCRIPTORS: usize = 32;
+pub const TX_DESCRIPTORS: usize = 16;
+
+/// The size of the buffer of every descriptor, as selected in `RCTL`.
+pub const BUFFER_SIZE: usize = 2048;
+
+const DESCRIPTOR_SIZE: usize = 16;
+
+const REGISTER_CTRL: usize = 0x0000;
+const REGISTER_STATUS: usize = 0x0008;
+const REGISTER_EERD: usize = 0x0014;
+const REGISTER_ICR: usize = 0x00C0;
+const REGISTER_IMS: usize = 0x00D0;
+const REGISTER_IMC: usize = 0x00D8;
+const REGISTER_RCTL: usize = 0x0100;
+const REGISTER_TCTL: usize = 0x0400;
+const REGISTER_TIPG: usize = 0x0410;
+const REGISTER_RDBAL: usize = 0x2800;
+const REGISTER_RDBAH: usize = 0x2804;
+const REGISTER_RDLEN: usize = 0x2808;
+const REGISTER_RDH: usize = 0x2810;
+const REGISTER_RDT: usize = 0x2818;
+const REGISTER_TDBAL: usize = 0x3800;
+const REGISTER_TDBAH: usize = 0x3804;
+const REGISTER_TDLEN: usize = 0x3808;
+const REGISTER_TDH: usize = 0x3810;
+const REGISTER_TDT: usize = 0x3818;
+const REGISTER_MPC: usize = 0x4010;
+const REGISTER_MTA: usize = 0x5200;
+const REGISTER_RAL: usize = 0x5400;
+const REGISTER_RAH: usize = 0x5404;
+
+/// The registers up to the receive addresses, which is all the driver uses.
+const REGISTERS_SIZE: usize = 0x6000;
+
+const MTA_ENTRIES: usize = 128;
+
+const CTRL_LRST: u32 = 1 << 3;
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_ILOS: u32 = 1 << 7;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_VME: u32 = 1 << 30;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_FD: u32 = 1 << 0;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+
+/// The interrupt cause of a change of the link status.
+const ICR_LSC: u32 = 1 << 2;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+const EERD_ADDRESS_SHIFT: u32 = 8;
+const EERD_DATA_SHIFT: u32 = 16;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// The inter-packet gap of the copper cards: IPGT 10, IPGR1 8 and IPGR2 6.
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+const TX_COMMAND_EOP: u8 = 1 << 0;
+const TX_COMMAND_IFCS: u8 = 1 << 1;
+const TX_COMMAND_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+/// The interrupt line of a device that the firmware didn't assign one.
+const LINE_UNASSIGNED: u8 = 0xFF;
+
+/// How many times the reset and EEPROM reads are polled, which takes
+/// microseconds on real cards and no time at all in emulators.
+const POLL_ATTEMPTS: usize = 100_000;
+
+const LINK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static RECEIVED: Counter = Counter::new("net.e1000.received", "Frames received by the e1000 cards");
+static TRANSMITTED: Counter = Counter::new("net.e1000.transmitted", "Frames queued for transmission by the e1000 cards");
+static ERRORS: Counter = Counter::new("net.e1000.errors", "Frames the e1000 cards received with errors, or split over descriptors");
+
+/// The cards that were set up, in the order of the bus.
+static DEVICES: Spinlock<Vec<Intel8254xDevice>> = Spinlock::new(Vec::new());
+
+/// Set by the interrupt handlers of the cards, which leave reading the causes
+/// of message-signaled interrupts to the `e1000` task, since the [`DEVICES`]
+/// can't be locked there.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The interrupt cause registers of the cards on an interrupt line, which
+/// the handler reads to deassert the line, since it's level-triggered.
+static LINE_CAUSES: IrqSpinlock<Vec<usize>> = IrqSpinlock::new(Vec::new());
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches: |info| info.vendor_id == PciVendorId::INTEL_CORPORATION && DEVICE_IDS.contains(&info.device_id.value()),
+    probe: |device, info| {
+        let mut nic = Intel8254xDevice::new(info.address, device);
+        let result = nic.initialize(&PciConfigurationSpace);
+        if result.is_ok() {
+            let (mac, link) = (nic.mac_address(), nic.link_status());
+            let index = {
+                let mut devices = DEVICES.lock();
+                devices.push(nic);
+                devices.len() - 1
+            };
+
+            let name = interface::register(mac, index, |index, f| {
+                with_device(index, |nic| f(nic));
+            }, |index, f| {
+                try_with_device(index, |nic| f(nic));
+            });
+            dev_info!(device, "Interface {name} with MAC address {mac}, link {link}");
+        }
+        Box::pin(async move { result })
+    },
+    timeout: Duration::from_secs(2),
+};
+
+/// The receive or transmit ring, with the buffers of its descriptors.
+struct Ring {
+    descriptors: DmaRegion,
+    buffers: DmaRegion,
+    size: usize,
+
+    /// The next descriptor the driver looks at: the oldest received frame, or
+    /// the next free transmit descriptor.
+    next: usize,
+}
+
+impl Ring {
+    fn new(size: usize) -> Option<Self> {
+        Some(Self {
+            descriptors: memory::allocate_dma((size * DESCRIPTOR_SIZE) as u64)?,
+            buffers: memory::allocate_dma((size * BUFFER_SIZE) as u64)?,
+            size,
+            next: 0,
+        })
+    }
+
+    fn descriptor(&self, index: usize) -> *mut u8 {
+        (self.descriptors.virt.as_u64() as usize + index * DESCRIPTOR_SIZE) as *mut u8
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        (self.buffers.virt.as_u64() as usize + index * BUFFER_SIZE) as *mut u8
+    }
+
+    fn buffer_address(&self, index: usize) -> u64 {
+        self.buffers.physical.as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn read_u8(&self, index: usize, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.descriptor(index).add(offset)) }
+    }
+
+    fn read_u16(&self, index: usize, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.descriptor(index).add(offset) as *const u16) }
+    }
+
+    /// Point the descriptor to its buffer, and clear the rest of it.
+    fn reset_descriptor(&mut self, index: usize) {
+        let descriptor = self.descriptor(index);
+        unsafe {
+            ptr::write_volatile(descriptor as *mut u64, self.buffer_address(index));
+            ptr::write_volatile(descriptor.add(8) as *mut u64, 0);
+        }
+    }
+}
 
 pub struct Intel8254xDevice {
     pci_addr: PciAddress,
+    device: DeviceId,
+    registers: Option<PhysicalMapping<NoccioloAcpiHandler, u8>>,
+    mac: MacAddress,
+    rx: Option<Ring>,
+    tx: Option<Ring>,
+
+    /// Whether the link was up when the `e1000` task last looked.
+    link_up: bool,
+    interrupt: Option<CardInterrupt>,
+}
+
+/// How the card interrupts, see [`Intel8254xDevice::interrupt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardInterrupt {
+    Message(MessageInterrupt),
+
+    /// The interrupt line of the card, on the given input of the I/O APIC.
+    Line { line: u8, input: u8, vector: u8 },
+}
+
+impl Display for CardInterrupt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Message(interrupt) => interrupt.fmt(f),
+            Self::Line { line, input, vector } => write!(f, "line {line} on I/O APIC input {input}, vector {vector}"),
+        }
+    }
+}
+
+impl Intel8254xDevice {
+    fn new(pci_addr: PciAddress, device: DeviceId) -> Self {
+        Self {
+            pci_addr,
+            device,
+            registers: None,
+            mac: MacAddress([0; 6]),
+            rx: None,
+            tx: None,
+            link_up: false,
+            interrupt: None,
+        }
+    }
+
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+
+    /// The interrupt of the card, if it has one.
+    pub fn interrupt(&self) -> Option<CardInterrupt> {
+        self.interrupt
+    }
+
+    /// The frames the card dropped because the receive ring was full, since
+    /// this was last called.
+    pub fn take_missed_frames(&mut self) -> u32 {
+        self.read(REGISTER_MPC)
+    }
+
+    /// Let the card signal changes of the link status, through a
+    /// message-signaled interrupt, or else its interrupt line.
+    fn setup_interrupt(&mut self, pci: &impl ConfigurationSpaceMechanism) {
+        let interrupt = match msi::enable(pci, self.pci_addr, handle_interrupt) {
+            Ok(interrupt) => CardInterrupt::Message(interrupt),
+            Err(e) => {
+                dev_trace!(self.device, "No message-signaled interrupts, since {e}");
+                match self.setup_line_interrupt(pci) {
+                    Some(interrupt) => interrupt,
+                    None => {
+                        dev_trace!(self.device, "Polling the link status, since the interrupt line can't be routed");
+                        return;
+                    }
+                }
+            }
+        };
+
+        dev_trace!(self.device, "Using {interrupt}");
+        self.interrupt = Some(interrupt);
+        self.read(REGISTER_ICR);
+        self.write(REGISTER_IMS, ICR_LSC);
+    }
+
+    /// Route the interrupt line, which the firmware assigned, to a vector.
+    fn setup_line_interrupt(&mut self, pci: &impl ConfigurationSpaceMechanism) -> Option<CardInterrupt> {
+        let line = pci.interrupt_line(self.pci_addr);
+        if pci.interrupt_pin(self.pci_addr) == 0 || line == LINE_UNASSIGNED {
+            return None;
+        }
+
+        let vector = interrupts::allocate_vector(handle_line_interrupt)?;
+        let Some(input) = IOApic::route_pci_interrupt(line, vector) else {
+            interrupts::free_vector(vector);
+            return None;
+        };
+
+        LINE_CAUSES.lock().push(self.register(REGISTER_ICR) as usize);
+        Some(CardInterrupt::Line { line, input, vector })
+    }
+
+    fn map_registers(&mut self, pci: &impl ConfigurationSpaceMechanism) -> Result<(), DeviceError> {
+        let bar = pci.bar(self.pci_addr, 0)
+            .filter(|bar| bar.kind == PciBaseAddressType::MemorySpace && bar.size >= REGISTERS_SIZE as u64)
+            .ok_or(DeviceError::unsupported("no register BAR"))?;
+        dev_trace!(self.device, "Registers at {bar}");
+
+        if let Err(owner) = registry::claim(self.device, Resource::memory(bar.address, bar.size)) {
+            dev_warn!(self.device, "Registers {bar} are already claimed by {}", owner.name());
+            return Err(DeviceError::unsupported("registers claimed"));
+        }
+
+        let mapping = unsafe { NoccioloAcpiHandler.try_map_physical_region::<u8>(bar.address as usize, REGISTERS_SIZE) }
+            .map_err(|e| {
+                dev_warn!(self.device, "Failed to map the registers: {e:?}");
+                DeviceError::unsupported("registers can't be mapped")
+            })?;
+        memory_map::register(bar.address, bar.address + bar.size, RegionKind::Mmio, "e1000 registers");
+        self.registers = Some(mapping);
+        Ok(())
+    }
+
+    fn register(&self, offset: usize) -> *mut u32 {
+        let registers = self.registers.as_ref().expect("the registers are mapped before they're used");
+        registers.virtual_start().as_ptr().wrapping_add(offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.register(offset)) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.register(offset), value) }
+    }
+
+    /// Reset the card, and mask its interrupts, which reset enables again.
+    fn reset(&mut self) -> Result<(), DeviceError> {
+        self.write(REGISTER_IMC, u32::MAX);
+
+        let control = self.read(REGISTER_CTRL);
+        self.write(REGISTER_CTRL, control | CTRL_RST);
+        if !(0..POLL_ATTEMPTS).any(|_| self.read(REGISTER_CTRL) & CTRL_RST == 0) {
+            return Err(DeviceError::timeout());
+        }
+
+        self.write(REGISTER_IMC, u32::MAX);
+        self.read(REGISTER_ICR);
+        Ok(())
+    }
+
+    /// The address in the first receive address register, which the card
+    /// loads from the EEPROM, or else from the EEPROM directly.
+    fn read_mac_address(&mut self) -> Option<MacAddress> {
+        let (low, high) = (self.read(REGISTER_RAL), self.read(REGISTER_RAH));
+        if high & RAH_AV != 0 {
+            let [a, b, c, d] = low.to_le_bytes();
+            let [e, f, ..] = high.to_le_bytes();
+            return Some(MacAddress([a, b, c, d, e, f]));
+        }
+
+        let mut mac = [0; 6];
+        for word in 0..3 {
+            let [low, high] = self.read_eeprom(word)?.to_le_bytes();
+            mac[word as usize * 2] = low;
+            mac[word as usize * 2 + 1] = high;
+        }
+        Some(MacAddress(mac))
+    }
+
+    fn read_eeprom(&mut self, word: u32) -> Option<u16> {
+        self.write(REGISTER_EERD, EERD_START | word << EERD_ADDRESS_SHIFT);
+        (0..POLL_ATTEMPTS)
+            .map(|_| self.read(REGISTER_EERD))
+            .find(|value| value & EERD_DONE != 0)
+            .map(|value| (value >> EERD_DATA_SHIFT) as u16)
+    }
+
+    /// Accept the frames sent to the MAC address and broadcasts only.
+    fn setup_addresses(&mut self) {
+        let [a, b, c, d, e, f] = self.mac.0;
+        self.write(REGISTER_RAL, u32::from_le_bytes([a, b, c, d]));
+        self.write(REGISTER_RAH, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+
+        for entry in 0..MTA_ENTRIES {
+            self.write(REGISTER_MTA + entry * 4, 0);
+        }
+    }
+
+    fn setup_receive(&mut self) -> Result<(), DeviceError> {
+        let mut ring = Ring::new(RX_DESCRIPTORS).ok_or(DeviceError::unsupported("out of memory for the rings"))?;
+        for index in 0..RX_DESCRIPTORS {
+            ring.reset_descriptor(index);
+        }
+
+        let address = ring.descriptors.physical.as_u64();
+        self.write(REGISTER_RDBAL, address as u32);
+        self.write(REGISTER_RDBAH, (address >> 32) as u32);
+        self.write(REGISTER_RDLEN, (RX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REGISTER_RDH, 0);
+
+        // The tail is the one descriptor the card doesn't own, so a full ring
+        // can be told apart from an empty one.
+        self.write(REGISTER_RDT, (RX_DESCRIPTORS - 1) as u32);
+
+        // The buffer size bits are left zero for 2048 bytes.
+        self.write(REGISTER_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+        self.rx = Some(ring);
+        Ok(())
+    }
+
+    fn setup_transmit(&mut self) -> Result<(), DeviceError> {
+        let ring = Ring::new(TX_DESCRIPTORS).ok_or(DeviceError::unsupported("out of memory for the rings"))?;
+
+        // All descriptors start out done, so the first round can use them.
+        for index in 0..TX_DESCRIPTORS {
+            unsafe { ptr::write_volatile(ring.descriptor(index).add(12), TX_STATUS_DD) };
+        }
+
+        let address = ring.descriptors.physical.as_u64();
+        self.write(REGISTER_TDBAL, address as u32);
+        self.write(REGISTER_TDBAH, (address >> 32) as u32);
+        self.write(REGISTER_TDLEN, (TX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REGISTER_TDH, 0);
+        self.write(REGISTER_TDT, 0);
+
+        self.write(REGISTER_TIPG, TIPG_COPPER);
+        self.write(REGISTER_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        self.tx = Some(ring);
+        Ok(())
+    }
 }
 
 impl GenericDevice for Intel8254xDevice {
     fn initialize(&mut self, pci: &impl ConfigurationSpaceMechanism) -> Result<(), DeviceError> {
         pci.enable_bus_mastering(self.pci_addr);
+        self.map_registers(pci)?;
+        self.reset()?;
 
-        let bar0 = pci.base_address(self.pci_addr, 0).expect("Should have BAR0");
+        // Let the card negotiate the speed with the PHY, and bring the link up.
+        let control = self.read(REGISTER_CTRL);
+        self.write(REGISTER_CTRL, (control | CTRL_SLU | CTRL_ASDE) & !(CTRL_LRST | CTRL_ILOS | CTRL_VME | CTRL_PHY_RST));
 
+        self.mac = self.read_mac_address().ok_or(DeviceError::unsupported("no MAC address"))?;
+        self.setup_addresses();
+        self.setup_receive()?;
+        self.setup_transmit()?;
+
+        self.link_up = self.link_status().up;
+        self.setup_interrupt(pci);
         Ok(())
     }
 }
 
 impl NetworkDevice for Intel8254xDevice {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        let status = self.read(REGISTER_STATUS);
+        LinkStatus {
+            up: status & STATUS_LU != 0,
+            speed: match (status >> STATUS_SPEED_SHIFT) & 0b11 {
+                0b00 => 10,
+                0b01 => 100,
+                _ => 1000,
+            },
+            full_duplex: status & STATUS_FD != 0,
+        }
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetworkError> {
+        if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+            return Err(NetworkError::InvalidLength(frame.len()));
+        }
+        if !self.link_status().up {
+            return Err(NetworkError::LinkDown);
+        }
+
+        let ring = self.tx.as_mut().expect("the rings are set up before the card is used");
+        let index = ring.next;
+        if ring.read_u8(index, 12) & TX_STATUS_DD == 0 {
+            return Err(NetworkError::QueueFull);
+        }
 
+        unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), ring.buffer(index), frame.len()) };
+        ring.reset_descriptor(index);
+        let descriptor = ring.descriptor(index);
+        unsafe {
+            ptr::write_volatile(descriptor.add(8) as *mut u16, frame.len() as u16);
+            ptr::write_volatile(descriptor.add(11), TX_COMMAND_EOP | TX_COMMAND_IFCS | TX_COMMAND_RS);
+        }
+        ring.next = (index + 1) % ring.size;
+        let tail = ring.next;
+
+        // The card must see the descriptor before the tail that hands it over.
+        fence(Ordering::SeqCst);
+        self.write(REGISTER_TDT, tail as u32);
+        TRANSMITTED.increment();
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let ring = self.rx.as_mut().expect("the rings are set up before the card is used");
+            let index = ring.next;
+            let status = ring.read_u8(index, 12);
+            if status & RX_STATUS_DD == 0 {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+
+            // Frames never span descriptors with the buffers larger than the
+            // largest frame, so anything else is dropped.
+            let length = (ring.read_u16(index, 8) as usize).min(BUFFER_SIZE);
+            let errors = ring.read_u8(index, 13);
+            let frame = (status & RX_STATUS_EOP != 0 && errors == 0).then(|| {
+                let mut frame = Vec::with_capacity(length);
+                frame.extend_from_slice(unsafe { core::slice::from_raw_parts(ring.buffer(index), length) });
+                frame
+            });
+
+            ring.reset_descriptor(index);
+            ring.next = (index + 1) % ring.size;
+            fence(Ordering::SeqCst);
+            self.write(REGISTER_RDT, index as u32);
+
+            match frame {
+                Some(frame) => {
+                    RECEIVED.increment();
+                    return Some(frame);
+                }
+                None => ERRORS.increment(),
+            }
+        }
+    }
+}
+
+/// Run the closure with the card of the given index, e.g. to send a frame.
+/// Returns `None` when there is no such card.
+pub fn with_device<R>(index: usize, f: impl FnOnce(&mut Intel8254xDevice) -> R) -> Option<R> {
+    DEVICES.lock().get_mut(index).map(f)
+}
+
+/// Like [`with_device`], but returns `None` instead of waiting when another
+/// CPU (or the code that panicked) is using the cards.
+pub fn try_with_device<R>(index: usize, f: impl FnOnce(&mut Intel8254xDevice) -> R) -> Option<R> {
+    DEVICES.try_lock()?.get_mut(index).map(f)
+}
+
+/// The number of cards that were set up.
+pub fn device_count() -> usize {
+    DEVICES.lock().len()
+}
+
+fn handle_interrupt() {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    WAKER.wake();
+}
+
+/// The line may be shared, so the task is only woken when one of the cards
+/// has a cause.
+fn handle_line_interrupt() {
+    let causes = LINE_CAUSES.lock().iter()
+        .fold(0, |causes, &register| causes | unsafe { ptr::read_volatile(register as *const u32) });
+    if causes != 0 {
+        handle_interrupt();
+    }
+}
+
+/// The task that logs changes of the link status of the cards, waiting for
+/// their interrupts when all of them have one, or polling otherwise.
+pub async fn run() {
+    if device_count() == 0 {
+        return;
+    }
+
+    let interrupts = DEVICES.lock().iter().all(|nic| nic.interrupt.is_some());
+    loop {
+        if interrupts {
+            poll_fn(|cx| {
+                WAKER.register(cx.waker());
+                if INTERRUPTED.swap(false, Ordering::Relaxed) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }).await;
+        } else {
+            timer::sleep(LINK_POLL_INTERVAL).await;
+        }
+
+        for nic in DEVICES.lock().iter_mut() {
+            if let Some(CardInterrupt::Message(..)) = nic.interrupt {
+                // Reading the causes clears them, so the card can interrupt
+                // again.
+                nic.read(REGISTER_ICR);
+            }
+
+            let status = nic.link_status();
+            if status.up != nic.link_up {
+                nic.link_up = status.up;
+                dev_info!(nic.device, "Link {status}");
+            }
+        }
+    }
 }
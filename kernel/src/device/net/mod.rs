@@ -1,10 +1,80 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+//! The drivers of network cards, which move whole Ethernet frames: the
+//! protocol layers in [`net`](crate::net) build and parse the frames, the
+//! driver only hands them to the card and takes them back.
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use crate::net::MacAddress;
+
 use super::GenericDevice;
 
 pub mod intel_8254x;
 
+/// The largest frame without the frame check sequence, which the cards add
+/// and strip themselves.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// The smallest frame without the frame check sequence, to which shorter
+/// frames are padded by the cards.
+pub const MIN_FRAME_SIZE: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkError {
+    /// There is no link, so the frame would go nowhere.
+    LinkDown,
+
+    /// The frame is shorter than [`MIN_FRAME_SIZE`] or longer than
+    /// [`MAX_FRAME_SIZE`].
+    InvalidLength(usize),
+
+    /// The card hasn't sent the earlier frames yet.
+    QueueFull,
+}
+
+impl Display for NetworkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::LinkDown => f.write_str("the link is down"),
+            Self::InvalidLength(length) => write!(f, "a frame of {length} bytes can't be sent"),
+            Self::QueueFull => f.write_str("the transmit queue is full"),
+        }
+    }
+}
+
+/// The state of the link, as negotiated with the other end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStatus {
+    pub up: bool,
+
+    /// In Mbit/s.
+    pub speed: u32,
+    pub full_duplex: bool,
+}
+
+impl Display for LinkStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if !self.up {
+            return f.write_str("down");
+        }
+
+        write!(f, "up, {} Mbit/s {} duplex", self.speed, if self.full_duplex { "full" } else { "half" })
+    }
+}
+
 pub trait NetworkDevice: GenericDevice {
+    fn mac_address(&self) -> MacAddress;
+
+    fn link_status(&self) -> LinkStatus;
+
+    /// Queue the frame for transmission, from the destination address up to
+    /// the end of the payload.
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetworkError>;
 
+    /// Take the oldest frame the card received, if any, without the frame
+    /// check sequence.
+    fn receive_frame(&mut self) -> Option<Vec<u8>>;
 }
@@ -0,0 +1,52 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The generic driver for bridges, which owns every bridge no other driver
+//! claimed. Host and ISA bridges need nothing from us, but a PCI-to-PCI bridge
+//! only forwards transactions to its secondary bus once the I/O, memory and
+//! bus master bits of its command register are set.
+//!
+//! ### References:
+//! - [OSDev Wiki: PCI-to-PCI Bridge](https://wiki.osdev.org/PCI#PCI-to-PCI_Bridge)
+
+use alloc::{boxed::Box, format};
+use core::time::Duration;
+
+use crate::{
+    dev_info,
+    device::{
+        pci::{ConfigurationSpaceMechanism, PciClassCode, PciCommand, PciDriver, PciHeaderType, PciConfigurationSpace},
+        registry,
+    },
+};
+
+/// The offset of the primary, secondary and subordinate bus numbers.
+const BUS_NUMBERS: u16 = 0x18;
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "pci-bridge",
+    matches: |info| info.class == PciClassCode::Bridge,
+    probe: |device, info| {
+        let pci = PciConfigurationSpace;
+        let addr = info.address;
+
+        if pci.header_type(addr) == PciHeaderType::PciToPciBridge {
+            let numbers = pci.read_dword(addr, BUS_NUMBERS);
+            let secondary = (numbers >> 8) as u8;
+            let subordinate = (numbers >> 16) as u8;
+
+            let command = pci.command(addr);
+            pci.write_command(addr, command | PciCommand::IO_SPACE | PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+
+            dev_info!(device, "Forwarding to buses {secondary:02x}-{subordinate:02x}");
+            registry::register_bus(
+                &format!("pci-bus-{secondary:02x}"),
+                Some(device),
+                &format!("PCI bus behind {addr} (subordinate {subordinate:02x})"),
+            );
+        }
+
+        Box::pin(async { Ok(()) })
+    },
+    timeout: Duration::from_millis(100),
+};
@@ -1,11 +1,27 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::instructions::port::{PortGeneric, ReadWriteAccess, WriteOnlyAccess};
 
-use super::{PciAddress, PciClassCode, PciDeviceId, PciHeaderType, PciSubclass, PciVendorId};
+use crate::arch::port::{AuditedPort, PortUser};
+
+use super::{
+    PciAddress,
+    PciBar,
+    PciBaseAddress,
+    PciBaseAddressType,
+    PciCapability,
+    PciCapabilityId,
+    PciClassCode,
+    PciCommand,
+    PciDeviceId,
+    PciHeaderType,
+    PciStatus,
+    PciSubclass,
+    PciVendorId,
+};
 
 pub const CONFIG_ADDRESS: u16 = 0xCF8;
 pub const CONFIG_DATA: u16 = 0xCFC;
@@ -16,15 +32,15 @@ lazy_static! {
 }
 
 struct PciIOPorts {
-    config_address_port: PortGeneric<u32, WriteOnlyAccess>,
-    config_data_port: PortGeneric<u32, ReadWriteAccess>,
+    config_address_port: AuditedPort<u32>,
+    config_data_port: AuditedPort<u32>,
 }
 
 impl PciIOPorts {
     pub fn new() -> Self {
         Self {
-            config_address_port: PortGeneric::new(CONFIG_ADDRESS),
-            config_data_port: PortGeneric::new(CONFIG_DATA),
+            config_address_port: AuditedPort::new(CONFIG_ADDRESS, PortUser::Pci),
+            config_data_port: AuditedPort::new(CONFIG_DATA, PortUser::Pci),
         }
     }
 }
@@ -33,6 +49,21 @@ pub trait ConfigurationSpaceMechanism {
     fn read_word(&self, addr: PciAddress, offset: u16) -> u16;
     fn read_dword(&self, addr: PciAddress, offset: u16) -> u32;
     fn write_word(&self, addr: PciAddress, offset: u16, value: u16);
+    fn write_dword(&self, addr: PciAddress, offset: u16, value: u32);
+
+    /// The size of the configuration space this mechanism can access, which
+    /// is 256 bytes for the legacy mechanism and 4 KiB for ECAM.
+    fn config_space_size(&self) -> usize {
+        256
+    }
+
+    /// Read the configuration space, starting at offset zero.
+    fn read_config_space(&self, addr: PciAddress, buffer: &mut [u8]) {
+        for (idx, chunk) in buffer.chunks_mut(4).enumerate() {
+            let value = self.read_dword(addr, (idx * 4) as u16).to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
 
     fn vendor_id(&self, addr: PciAddress) -> PciVendorId {
         PciVendorId::new(self.read_word(addr, 0x0))
@@ -54,6 +85,14 @@ pub trait ConfigurationSpaceMechanism {
         self.read_word(addr, 0x6)
     }
 
+    fn decoded_command(&self, addr: PciAddress) -> PciCommand {
+        PciCommand(self.command(addr))
+    }
+
+    fn decoded_status(&self, addr: PciAddress) -> PciStatus {
+        PciStatus(self.status(addr))
+    }
+
     fn revision_id(&self, addr: PciAddress) -> u8 {
         (self.read_word(addr, 0x8) & 0xFF) as u8
     }
@@ -97,6 +136,137 @@ pub trait ConfigurationSpaceMechanism {
             None
         }
     }
+
+    /// Decode the BAR with the given index, including its size. Returns `None`
+    /// if the BAR doesn't exist or isn't implemented by the device.
+    ///
+    /// Decoding is temporarily disabled while the BAR is sized, so this must
+    /// not race with the driver of the device.
+    fn bar(&self, addr: PciAddress, idx: usize) -> Option<PciBar> {
+        let raw = PciBaseAddress::new(self.base_address(addr, idx)?);
+        let offset = 0x10 + (idx * 4) as u16;
+        let is_64_bit = raw.is_64_bit() && idx + 1 < self.header_type(addr).bar_count();
+
+        let command = self.command(addr);
+        self.write_command(addr, command & !(PciCommand::IO_SPACE | PciCommand::MEMORY_SPACE));
+
+        self.write_dword(addr, offset, 0xFFFF_FFFF);
+        let mask_lo = self.read_dword(addr, offset);
+        self.write_dword(addr, offset, raw.value());
+
+        let mut address = raw.actual_address() as u64;
+        let mask = match raw.kind() {
+            PciBaseAddressType::IOSpace => {
+                // The upper 16 bits may be hardwired to zero for I/O BARs.
+                let mask = mask_lo & 0xFFFF_FFFC;
+                let mask = if mask >> 16 == 0 { mask | 0xFFFF_0000 } else { mask };
+                mask as u64 | 0xFFFF_FFFF_0000_0000
+            }
+
+            PciBaseAddressType::MemorySpace if is_64_bit => {
+                let high = self.read_dword(addr, offset + 4);
+                self.write_dword(addr, offset + 4, 0xFFFF_FFFF);
+                let mask_hi = self.read_dword(addr, offset + 4);
+                self.write_dword(addr, offset + 4, high);
+
+                address |= (high as u64) << 32;
+                ((mask_hi as u64) << 32) | (mask_lo & 0xFFFF_FFF0) as u64
+            }
+
+            PciBaseAddressType::MemorySpace => (mask_lo & 0xFFFF_FFF0) as u64 | 0xFFFF_FFFF_0000_0000,
+        };
+
+        self.write_command(addr, command);
+
+        if mask_lo == 0 {
+            return None;
+        }
+
+        Some(PciBar {
+            index: idx,
+            kind: raw.kind(),
+            address,
+            size: (!mask).wrapping_add(1),
+            is_64_bit,
+            is_prefetchable: raw.is_prefetchable(),
+        })
+    }
+
+    /// Decode all implemented BARs of the device.
+    fn bars(&self, addr: PciAddress) -> Vec<PciBar> {
+        let mut bars = Vec::new();
+        let mut idx = 0;
+        while idx < self.header_type(addr).bar_count() {
+            match self.bar(addr, idx) {
+                Some(bar) => {
+                    // The upper half of a 64-bit BAR isn't a BAR by itself.
+                    idx += if bar.is_64_bit { 2 } else { 1 };
+                    bars.push(bar);
+                }
+                None => idx += 1,
+            }
+        }
+        bars
+    }
+
+    fn interrupt_line(&self, addr: PciAddress) -> u8 {
+        (self.read_word(addr, 0x3C) & 0xFF) as u8
+    }
+
+    fn interrupt_pin(&self, addr: PciAddress) -> u8 {
+        (self.read_word(addr, 0x3C) >> 8) as u8
+    }
+
+    /// Walk the linked list of capabilities of the device.
+    fn capabilities<'a>(&'a self, addr: PciAddress) -> impl Iterator<Item = PciCapability> + 'a
+            where Self: Sized {
+        let next = if self.decoded_status(addr).has_capabilities_list() {
+            (self.read_word(addr, 0x34) & 0xFC) as u8
+        } else {
+            0
+        };
+
+        CapabilityIterator {
+            mechanism: self,
+            addr,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+}
+
+/// Each capability takes at least 4 bytes of the 192 bytes after the header,
+/// which limits the length of a well-formed list.
+const MAX_CAPABILITIES: usize = 48;
+
+struct CapabilityIterator<'a, Mechanism: ConfigurationSpaceMechanism> {
+    mechanism: &'a Mechanism,
+    addr: PciAddress,
+    next: u8,
+    remaining: usize,
+}
+
+impl<'a, Mechanism> Iterator for CapabilityIterator<'a, Mechanism>
+        where Mechanism: ConfigurationSpaceMechanism {
+    type Item = PciCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A cyclic list would otherwise loop forever.
+        if self.next == 0 || self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = self.mechanism.read_word(self.addr, offset as u16);
+        self.next = ((header >> 8) & 0xFC) as u8;
+
+        Some(PciCapability {
+            id: PciCapabilityId((header & 0xFF) as u8),
+            offset,
+        })
+    }
 }
 
 struct DeviceEnumerator<'a, Mechanism: ConfigurationSpaceMechanism> {
@@ -159,7 +329,7 @@ impl ConfigurationSpaceMechanism for PciLocalBusConfigurationSpace {
     fn write_word(&self, addr: PciAddress, offset: u16, value: u16) {
         let mut ports = IO_PORTS.lock();
 
-        let address = addr.create_local_bus_address(offset, false);
+        let address = addr.create_local_bus_address(offset, true);
         unsafe {
             ports.config_address_port.write(address);
         }
@@ -173,4 +343,14 @@ impl ConfigurationSpaceMechanism for PciLocalBusConfigurationSpace {
         };
         unsafe { ports.config_data_port.write(data) };
     }
+
+    fn write_dword(&self, addr: PciAddress, offset: u16, value: u32) {
+        let mut ports = IO_PORTS.lock();
+
+        let address = addr.create_local_bus_address(offset, true);
+        unsafe {
+            ports.config_address_port.write(address);
+            ports.config_data_port.write(value);
+        }
+    }
 }
@@ -0,0 +1,51 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The generic driver for display controllers. We draw to the framebuffer set
+//! up by the bootloader, so this driver only claims the ranges the controller
+//! decodes, to keep other drivers from mapping them. A VGA compatible
+//! controller also decodes the legacy VGA registers and video memory.
+//!
+//! ### References:
+//! - [OSDev Wiki: VGA Hardware](https://wiki.osdev.org/VGA_Hardware)
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use crate::{
+    dev_warn,
+    device::{
+        pci::{ConfigurationSpaceMechanism, PciBaseAddressType, PciClassCode, PciDriver, PciConfigurationSpace},
+        registry::{self, Resource},
+    },
+};
+
+/// The subclass of VGA compatible controllers.
+const SUBCLASS_VGA: u8 = 0x0;
+
+/// The ports 0x3B0-0x3DF, and the video memory at 0xA0000-0xBFFFF.
+const LEGACY_VGA: [Resource; 2] = [Resource::io_ports(0x3B0, 0x30), Resource::memory(0xA0000, 0x20000)];
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "display",
+    matches: |info| info.class == PciClassCode::DisplayController,
+    probe: |device, info| {
+        let pci = PciConfigurationSpace;
+
+        let bars = pci.bars(info.address).into_iter().map(|bar| match bar.kind {
+            PciBaseAddressType::IOSpace => Resource::io_ports(bar.address, bar.size),
+            PciBaseAddressType::MemorySpace => Resource::memory(bar.address, bar.size),
+        });
+
+        let legacy = if info.subclass.value() == SUBCLASS_VGA { &LEGACY_VGA[..] } else { &[] };
+
+        for resource in bars.chain(legacy.iter().copied()) {
+            if let Err(owner) = registry::claim(device, resource) {
+                dev_warn!(device, "Range {resource} is already claimed by {}", owner.name());
+            }
+        }
+
+        Box::pin(async { Ok(()) })
+    },
+    timeout: Duration::from_millis(100),
+};
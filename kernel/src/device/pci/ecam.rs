@@ -0,0 +1,190 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The Enhanced Configuration Access Mechanism of PCI Express, which maps the
+//! 4 KiB configuration space of every function to memory, at the addresses
+//! given by the MCFG table of ACPI.
+//!
+//! ECAM is preferred over the I/O ports, as it reaches the extended
+//! configuration space, and doesn't need a lock around two port accesses. Some
+//! firmware has an MCFG that doesn't match the hardware though, so [`verify`]
+//! cross-checks a few header reads against the legacy mechanism first, and
+//! only a verified ECAM is used (see [`super::PciConfigurationSpace`]).
+//!
+//! The pages of a function are mapped the first time it is accessed, and are
+//! kept mapped.
+//!
+//! ### References:
+//! - [OSDev Wiki: PCI Express](https://wiki.osdev.org/PCI_Express)
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::RangeInclusive;
+
+use acpi::{AcpiHandler, PhysicalMapping};
+use log::{error, info, warn};
+use spin::Mutex;
+
+use crate::{
+    device::acpi::NoccioloAcpiHandler,
+    meta::memory_map::{self, RegionKind},
+};
+
+use super::{ConfigurationSpaceMechanism, PciAddress, PciLocalBusConfigurationSpace, PciVendorId};
+
+const FUNCTION_SIZE: usize = 4096;
+
+/// The number of devices found by the legacy mechanism whose headers are
+/// compared, which keeps the check quick on machines with many devices.
+const MAX_VERIFIED_DEVICES: usize = 8;
+
+/// The registers compared by [`verify`]: the vendor and device IDs, the class
+/// and revision, and the subsystem IDs.
+const VERIFIED_OFFSETS: [u16; 3] = [0x00, 0x08, 0x2C];
+
+/// An entry of the MCFG table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcamRegion {
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+
+    /// The physical address of the configuration space of the first bus.
+    pub base: u64,
+}
+
+impl EcamRegion {
+    fn physical_address(&self, addr: PciAddress) -> Option<u64> {
+        if addr.segment != self.segment || !self.buses.contains(&addr.bus) || addr.device >= 32 || addr.function >= 8 {
+            return None;
+        }
+
+        let bus = (addr.bus - self.buses.start()) as u64;
+        Some(self.base + (bus << 20 | (addr.device as u64) << 15 | (addr.function as u64) << 12))
+    }
+
+    fn end(&self) -> u64 {
+        self.base + ((self.buses.end() - self.buses.start()) as u64 + 1) * (1 << 20)
+    }
+}
+
+/// A mismatch between ECAM and the legacy mechanism, found by [`verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub address: PciAddress,
+    pub offset: u16,
+    pub ecam: u32,
+    pub legacy: u32,
+}
+
+pub struct PciEcam {
+    regions: Vec<EcamRegion>,
+
+    /// The mapped configuration space of every function accessed so far.
+    mappings: Mutex<BTreeMap<(u16, u8, u8, u8), PhysicalMapping<NoccioloAcpiHandler, u8>>>,
+}
+
+impl PciEcam {
+    pub fn new(regions: Vec<EcamRegion>) -> Self {
+        Self {
+            regions,
+            mappings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Show the regions in the memory map, once ECAM is selected.
+    pub fn register_regions(&self) {
+        for region in &self.regions {
+            memory_map::register(region.base, region.end(), RegionKind::Mmio, "PCI ECAM");
+        }
+    }
+
+    /// The pointer to the register, or `None` when no region covers the
+    /// function.
+    fn register(&self, addr: PciAddress, offset: u16, size: usize) -> Option<*mut u8> {
+        if offset as usize + size > FUNCTION_SIZE {
+            return None;
+        }
+
+        let physical = self.regions.iter().find_map(|region| region.physical_address(addr))?;
+        let mut mappings = self.mappings.lock();
+        let mapping = mappings
+            .entry((addr.segment, addr.bus, addr.device, addr.function))
+            .or_insert_with(|| unsafe { NoccioloAcpiHandler.map_physical_region(physical as usize, FUNCTION_SIZE) });
+        Some(mapping.virtual_start().as_ptr().wrapping_add(offset as usize))
+    }
+}
+
+impl ConfigurationSpaceMechanism for PciEcam {
+    fn read_word(&self, addr: PciAddress, offset: u16) -> u16 {
+        match self.register(addr, offset & !1, 2) {
+            Some(register) => unsafe { (register as *const u16).read_volatile() },
+            None => 0xFFFF,
+        }
+    }
+
+    fn read_dword(&self, addr: PciAddress, offset: u16) -> u32 {
+        match self.register(addr, offset & !3, 4) {
+            Some(register) => unsafe { (register as *const u32).read_volatile() },
+            None => 0xFFFF_FFFF,
+        }
+    }
+
+    fn write_word(&self, addr: PciAddress, offset: u16, value: u16) {
+        if let Some(register) = self.register(addr, offset & !1, 2) {
+            unsafe { (register as *mut u16).write_volatile(value) };
+        }
+    }
+
+    fn write_dword(&self, addr: PciAddress, offset: u16, value: u32) {
+        if let Some(register) = self.register(addr, offset & !3, 4) {
+            unsafe { (register as *mut u32).write_volatile(value) };
+        }
+    }
+
+    fn config_space_size(&self) -> usize {
+        FUNCTION_SIZE
+    }
+}
+
+/// Compare the headers of the first devices on segment 0 between ECAM and the
+/// legacy mechanism, and check that ECAM doesn't see devices on bus 0 where
+/// the legacy mechanism sees none. Every discrepancy is logged.
+pub fn verify(ecam: &PciEcam) -> Result<(), Vec<Discrepancy>> {
+    let legacy = PciLocalBusConfigurationSpace;
+    let mut discrepancies = Vec::new();
+    let mut compare = |address: PciAddress, offset: u16| {
+        let (ecam, legacy) = (ecam.read_dword(address, offset), legacy.read_dword(address, offset));
+        if ecam != legacy {
+            error!("ECAM reads {ecam:#010x} at {address} offset {offset:#x}, the I/O ports read {legacy:#010x}");
+            discrepancies.push(Discrepancy { address, offset, ecam, legacy });
+        }
+    };
+
+    let devices: Vec<PciAddress> = legacy.enumerate()
+        .map(|(address, _, _)| address)
+        .take(MAX_VERIFIED_DEVICES)
+        .collect();
+    if devices.is_empty() {
+        warn!("The I/O ports find no PCI devices, so ECAM can't be cross-checked");
+        return Ok(());
+    }
+
+    for address in &devices {
+        for offset in VERIFIED_OFFSETS {
+            compare(*address, offset);
+        }
+    }
+
+    for device in 0..32 {
+        let address = PciAddress { segment: 0, bus: 0, device, function: 0 };
+        if legacy.vendor_id(address) == PciVendorId::INVALID {
+            compare(address, 0x00);
+        }
+    }
+
+    if discrepancies.is_empty() {
+        info!("ECAM matches the I/O ports for {} devices", devices.len());
+        Ok(())
+    } else {
+        Err(discrepancies)
+    }
+}
@@ -1,31 +1,145 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+mod bridge;
 mod config;
+mod display;
+mod ecam;
+pub mod msi;
+mod rom;
 mod types;
 
-use log::{info, trace};
+use alloc::{format, string::String, vec::Vec};
+use core::time::Duration;
+
+use log::{error, info, trace, warn};
+use conquer_once::spin::OnceCell;
+
+use crate::{meta::{BootParameters, System}, QemuExitCode};
+
+use super::{acpi::ACPI_DATA, fw_cfg::FwCfg, probe::{self, ProbeFuture}, registry::{self, DeviceId}};
+
+use self::ecam::PciEcam;
 
 pub use self::{
     config::{
         ConfigurationSpaceMechanism,
         PciLocalBusConfigurationSpace,
     },
+    ecam::EcamRegion,
+    rom::{OptionRom, RomBar},
     types::{
         PciAddress,
+        PciBar,
+        PciBaseAddress,
+        PciBaseAddressType,
+        PciCapability,
+        PciCapabilityId,
         PciClassCode,
+        PciCommand,
         PciDeviceId,
         PciHeaderType,
+        PciStatus,
         PciSubclass,
         PciVendorId,
     },
 };
 
+/// Set when ECAM is used, see [`select_mechanism`].
+static ECAM: OnceCell<PciEcam> = OnceCell::uninit();
+
+/// The configuration space of the devices, through ECAM when it passed the
+/// check at boot, or through the I/O ports otherwise. This is what drivers
+/// should use.
+pub struct PciConfigurationSpace;
+
+impl ConfigurationSpaceMechanism for PciConfigurationSpace {
+    fn read_word(&self, addr: PciAddress, offset: u16) -> u16 {
+        match ECAM.try_get() {
+            Ok(ecam) => ecam.read_word(addr, offset),
+            Err(_) => PciLocalBusConfigurationSpace.read_word(addr, offset),
+        }
+    }
+
+    fn read_dword(&self, addr: PciAddress, offset: u16) -> u32 {
+        match ECAM.try_get() {
+            Ok(ecam) => ecam.read_dword(addr, offset),
+            Err(_) => PciLocalBusConfigurationSpace.read_dword(addr, offset),
+        }
+    }
+
+    fn write_word(&self, addr: PciAddress, offset: u16, value: u16) {
+        match ECAM.try_get() {
+            Ok(ecam) => ecam.write_word(addr, offset, value),
+            Err(_) => PciLocalBusConfigurationSpace.write_word(addr, offset, value),
+        }
+    }
+
+    fn write_dword(&self, addr: PciAddress, offset: u16, value: u32) {
+        match ECAM.try_get() {
+            Ok(ecam) => ecam.write_dword(addr, offset, value),
+            Err(_) => PciLocalBusConfigurationSpace.write_dword(addr, offset, value),
+        }
+    }
+
+    fn config_space_size(&self) -> usize {
+        match ECAM.try_get() {
+            Ok(ecam) => ecam.config_space_size(),
+            Err(_) => PciLocalBusConfigurationSpace.config_space_size(),
+        }
+    }
+}
+
+/// The name of the mechanism in use, for the log and the shell.
+pub fn mechanism_name() -> &'static str {
+    if ECAM.is_initialized() { "ECAM" } else { "I/O ports" }
+}
+
+/// The drivers for PCI devices, of which the first that matches is bound.
+/// The generic drivers for a class of devices come last, so a driver for the
+/// specific device wins.
+const DRIVERS: &[PciDriver] = &[
+    super::block::ide::DRIVER,
+    super::block::virtio_blk::DRIVER,
+    super::net::intel_8254x::DRIVER,
+    super::virtio::console::DRIVER,
+    super::virtio::rng::DRIVER,
+    bridge::DRIVER,
+    display::DRIVER,
+];
+
+/// The identity of an enumerated device, which drivers are matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDeviceInfo {
+    pub address: PciAddress,
+    pub vendor_id: PciVendorId,
+    pub device_id: PciDeviceId,
+    pub class: PciClassCode,
+    pub subclass: PciSubclass,
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: fn(&PciDeviceInfo) -> bool,
+
+    /// Initialize the device, which is named after the driver by now, so the
+    /// driver can log with [`dev_info!`](crate::dev_info) etc. The probes of
+    /// all devices run concurrently, see [`probe`].
+    pub probe: fn(DeviceId, &PciDeviceInfo) -> ProbeFuture,
+
+    /// How long the probe may take, after which the device is given up on.
+    pub timeout: Duration,
+}
+
 pub(super) fn init(boot_info: &bootloader_api::BootInfo) {
     _ = boot_info;
 
-    let mechanism = PciLocalBusConfigurationSpace;
-    trace!("Enumerating devices...");
+    select_mechanism();
+
+    let mechanism = PciConfigurationSpace;
+    trace!("Enumerating devices using {}...", mechanism_name());
+
+    let bus = registry::register_bus("pci", None, "PCI local bus");
 
     let mut devices = 0;
     for (addr, vendor_id, device_id) in mechanism.enumerate() {
@@ -42,7 +156,96 @@ pub(super) fn init(boot_info: &bootloader_api::BootInfo) {
         if let Some(vendor_name) = vendor_id.name() {
             info!("  Name: {vendor_name}     {}", device_id.name(vendor_id).unwrap_or_default());
         }
+
+        let description = match (vendor_id.name(), device_id.name(vendor_id)) {
+            (_, Some(device_name)) => device_name.into(),
+            (Some(vendor_name), None) => format!("{vendor_name} {:04x}", device_id.value()),
+            (None, None) => format!("{:04x}:{:04x} {class:?}", vendor_id.value(), device_id.value()),
+        };
+
+        let id = registry::register_device(&format!("pci-{addr}"), Some(bus), &description);
+        let info = PciDeviceInfo {
+            address: addr,
+            vendor_id,
+            device_id,
+            class,
+            subclass,
+        };
+
+        if let Some(driver) = DRIVERS.iter().find(|driver| (driver.matches)(&info)) {
+            registry::bind(id, driver.name);
+            probe::defer(id, driver.timeout, (driver.probe)(id, &info));
+        } else if class == PciClassCode::MassStorageController {
+            warn!("No driver for the {} at {addr}, its disks are unavailable",
+                subclass.name(class).unwrap_or("mass-storage controller"));
+        }
     }
 
     info!("Found {devices} PCI devices");
 }
+
+/// Use ECAM when the MCFG describes it and it agrees with the I/O ports,
+/// unless `pci=legacy` is passed.
+fn select_mechanism() {
+    let regions = ACPI_DATA.lock().ecam_regions.clone();
+    if regions.is_empty() {
+        trace!("No MCFG, using the I/O ports for the PCI configuration space");
+        return;
+    }
+
+    if BootParameters::get("pci") == Some("legacy") {
+        info!("Using the I/O ports for the PCI configuration space (pci=legacy)");
+        return;
+    }
+
+    let ecam = PciEcam::new(regions);
+    match ecam::verify(&ecam) {
+        Ok(()) => {
+            ecam.register_regions();
+            ECAM.init_once(|| ecam);
+        }
+        Err(discrepancies) => {
+            warn!("ECAM disagrees with the I/O ports in {} reads, the MCFG is likely wrong, falling back to the I/O ports",
+                discrepancies.len());
+        }
+    }
+}
+
+/// Check that the devices listed in the `expected_devices` fw_cfg file (see
+/// the `--profile` option of the runner) were found, as comma-separated
+/// `vendor:device` pairs. A device listed twice should be found twice.
+pub(super) fn verify_expected_devices() {
+    let Some(expected) = FwCfg::read_file("expected_devices") else {
+        return;
+    };
+
+    let mut found: Vec<(u16, u16)> = PciConfigurationSpace.enumerate()
+        .map(|(_, vendor_id, device_id)| (vendor_id.value(), device_id.value()))
+        .collect();
+
+    let expected = String::from_utf8_lossy(&expected);
+    let mut missing = Vec::new();
+    for entry in expected.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let Some((vendor, device)) = entry.split_once(':')
+            .and_then(|(vendor, device)| Some((u16::from_str_radix(vendor, 16).ok()?, u16::from_str_radix(device, 16).ok()?))) else {
+            error!("Invalid expected device `{entry}`, expected <vendor>:<device> in hexadecimal");
+            missing.push(entry);
+            continue;
+        };
+
+        match found.iter().position(|ids| *ids == (vendor, device)) {
+            Some(idx) => _ = found.swap_remove(idx),
+            None => missing.push(entry),
+        }
+    }
+
+    if missing.is_empty() {
+        info!("Device self-test passed, all expected devices were found");
+        return;
+    }
+
+    error!("Device self-test failed, missing: {}", missing.join(", "));
+    if System::is_ci_mode() {
+        crate::exit_qemu(QemuExitCode::Failed);
+    }
+}
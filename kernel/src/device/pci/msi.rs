@@ -0,0 +1,380 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Message-signaled interrupts, which a device raises by writing a message to
+//! the local APIC, instead of asserting one of the interrupt lines it shares
+//! with other devices. The message is the vector, written to an address that
+//! selects the destination APIC:
+//!
+//! | Capability  | Messages                       | Where the messages are set up          |
+//! |-------------|--------------------------------|----------------------------------------|
+//! | [`Msi`]     | 1 to 32, with one address      | The capability itself                  |
+//! | [`MsiX`]    | 1 to 2048, each its own        | A table in one of the BARs             |
+//!
+//! Only a single message is set up per device, on a vector of
+//! [`MSI_VECTORS`], using MSI-X when the device has both. The interrupt line
+//! of the device is disabled while messages are used.
+//!
+//! ### References:
+//! - [PCI Local Bus Specification 3.0, 6.8 Message Signaled Interrupts](https://pcisig.com/specifications)
+//! - [Intel SDM Volume 3, 11.11 Message Signalled Interrupts](https://www.intel.com/content/www/us/en/developer/articles/technical/intel-sdm.html)
+//! - [OSDev Wiki: PCI, Message Signaled Interrupts](https://wiki.osdev.org/PCI#Message_Signaled_Interrupts)
+
+use core::{
+    fmt::{Display, Formatter},
+    ptr,
+};
+
+use crate::{
+    arch::interrupts::{self, apic::LocalApic, MSI_VECTORS},
+    device::acpi::NoccioloAcpiHandler,
+};
+
+use super::{
+    ConfigurationSpaceMechanism,
+    PciAddress,
+    PciBaseAddressType,
+    PciCapabilityId,
+    PciCommand,
+};
+
+/// The address of the local APICs, of which bits 12 to 19 select the
+/// destination.
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MESSAGE_DESTINATION_SHIFT: u64 = 12;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_CAPABLE_SHIFT: u16 = 1;
+const MSI_CONTROL_ENABLED_SHIFT: u16 = 4;
+const MSI_CONTROL_COUNT_MASK: u16 = 0b111;
+const MSI_CONTROL_64_BIT: u16 = 1 << 7;
+const MSI_CONTROL_MASKABLE: u16 = 1 << 8;
+
+const MSI_X_CONTROL_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSI_X_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSI_X_CONTROL_ENABLE: u16 = 1 << 15;
+const MSI_X_BIR_MASK: u32 = 0b111;
+
+const MSI_X_ENTRY_SIZE: usize = 16;
+const MSI_X_ENTRY_VECTOR_CONTROL: usize = 12;
+const MSI_X_VECTOR_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has neither an MSI nor an MSI-X capability.
+    NotCapable,
+
+    /// All [`MSI_VECTORS`] are taken.
+    NoVectors,
+
+    /// The local APIC isn't set up, so nothing would receive the messages.
+    NoLocalApic,
+
+    /// The MSI-X table isn't in a memory BAR, or the BAR can't be mapped.
+    TableUnavailable,
+}
+
+impl Display for MsiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::NotCapable => "the device doesn't support message-signaled interrupts",
+            Self::NoVectors => "no interrupt vectors are left",
+            Self::NoLocalApic => "there is no local APIC",
+            Self::TableUnavailable => "the MSI-X table can't be mapped",
+        })
+    }
+}
+
+/// The address and data a device writes to raise an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// The message for a fixed, edge-triggered interrupt with the vector on
+    /// the local APIC with the ID.
+    pub const fn new(apic_id: u8, vector: u8) -> Self {
+        Self {
+            address: MESSAGE_ADDRESS_BASE | (apic_id as u64) << MESSAGE_DESTINATION_SHIFT,
+            data: vector as u32,
+        }
+    }
+}
+
+impl Display for MsiMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "address {:#x}, data {:#06x}", self.address, self.data)
+    }
+}
+
+/// The MSI capability, as it was when it was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    addr: PciAddress,
+    offset: u16,
+    control: u16,
+    message: MsiMessage,
+}
+
+impl Msi {
+    pub fn find(pci: &impl ConfigurationSpaceMechanism, addr: PciAddress) -> Option<Self> {
+        let capability = pci.capabilities(addr).find(|capability| capability.id == PciCapabilityId::MSI)?;
+        Some(Self::read(pci, addr, capability.offset as u16))
+    }
+
+    fn read(pci: &impl ConfigurationSpaceMechanism, addr: PciAddress, offset: u16) -> Self {
+        let control = pci.read_word(addr, offset + 2);
+        let mut this = Self { addr, offset, control, message: MsiMessage { address: 0, data: 0 } };
+
+        let mut address = pci.read_dword(addr, offset + 4) as u64;
+        if this.is_64_bit() {
+            address |= (pci.read_dword(addr, offset + 8) as u64) << 32;
+        }
+        this.message = MsiMessage { address, data: pci.read_word(addr, this.data_offset()) as u32 };
+        this
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control & MSI_CONTROL_ENABLE != 0
+    }
+
+    pub fn is_64_bit(&self) -> bool {
+        self.control & MSI_CONTROL_64_BIT != 0
+    }
+
+    /// Whether each message can be masked by itself.
+    pub fn is_maskable(&self) -> bool {
+        self.control & MSI_CONTROL_MASKABLE != 0
+    }
+
+    /// The number of messages the device would like to send.
+    pub fn requested_messages(&self) -> u8 {
+        1 << ((self.control >> MSI_CONTROL_CAPABLE_SHIFT) & MSI_CONTROL_COUNT_MASK)
+    }
+
+    /// The number of messages the device was allowed to send.
+    pub fn enabled_messages(&self) -> u8 {
+        1 << ((self.control >> MSI_CONTROL_ENABLED_SHIFT) & MSI_CONTROL_COUNT_MASK)
+    }
+
+    fn data_offset(&self) -> u16 {
+        self.offset + if self.is_64_bit() { 0xC } else { 0x8 }
+    }
+
+    /// Let the device send the single message, and enable MSI.
+    pub fn configure(&mut self, pci: &impl ConfigurationSpaceMechanism, message: MsiMessage) {
+        self.disable(pci);
+
+        pci.write_dword(self.addr, self.offset + 4, message.address as u32);
+        if self.is_64_bit() {
+            pci.write_dword(self.addr, self.offset + 8, (message.address >> 32) as u32);
+        }
+        pci.write_word(self.addr, self.data_offset(), message.data as u16);
+        if self.is_maskable() {
+            pci.write_dword(self.addr, self.data_offset() + 4, 0);
+        }
+
+        // A single message, which is encoded as zero.
+        self.control &= !(MSI_CONTROL_COUNT_MASK << MSI_CONTROL_ENABLED_SHIFT);
+        self.control |= MSI_CONTROL_ENABLE;
+        pci.write_word(self.addr, self.offset + 2, self.control);
+        self.message = message;
+    }
+
+    pub fn disable(&mut self, pci: &impl ConfigurationSpaceMechanism) {
+        self.control &= !MSI_CONTROL_ENABLE;
+        pci.write_word(self.addr, self.offset + 2, self.control);
+    }
+}
+
+impl Display for Msi {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}, {}/{} messages, {}-bit{}",
+            if self.is_enabled() { "enabled" } else { "disabled" },
+            self.enabled_messages(),
+            self.requested_messages(),
+            if self.is_64_bit() { 64 } else { 32 },
+            if self.is_maskable() { ", maskable" } else { "" },
+        )?;
+
+        if self.is_enabled() {
+            write!(f, ", {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The MSI-X capability, as it was when it was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    addr: PciAddress,
+    offset: u16,
+    control: u16,
+    table: u32,
+    pending: u32,
+}
+
+impl MsiX {
+    pub fn find(pci: &impl ConfigurationSpaceMechanism, addr: PciAddress) -> Option<Self> {
+        let capability = pci.capabilities(addr).find(|capability| capability.id == PciCapabilityId::MSI_X)?;
+        let offset = capability.offset as u16;
+        Some(Self {
+            addr,
+            offset,
+            control: pci.read_word(addr, offset + 2),
+            table: pci.read_dword(addr, offset + 4),
+            pending: pci.read_dword(addr, offset + 8),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control & MSI_X_CONTROL_ENABLE != 0
+    }
+
+    /// Whether all messages are masked, regardless of their own mask.
+    pub fn is_function_masked(&self) -> bool {
+        self.control & MSI_X_CONTROL_FUNCTION_MASK != 0
+    }
+
+    pub fn table_size(&self) -> u16 {
+        (self.control & MSI_X_CONTROL_TABLE_SIZE_MASK) + 1
+    }
+
+    /// The index of the BAR with the table, and the offset in it.
+    pub fn table(&self) -> (usize, u32) {
+        ((self.table & MSI_X_BIR_MASK) as usize, self.table & !MSI_X_BIR_MASK)
+    }
+
+    /// The index of the BAR with the pending bit array, and the offset in it.
+    pub fn pending_bit_array(&self) -> (usize, u32) {
+        ((self.pending & MSI_X_BIR_MASK) as usize, self.pending & !MSI_X_BIR_MASK)
+    }
+
+    /// Set up the first entry of the table with the message, mask the others,
+    /// and enable MSI-X.
+    pub fn configure(&mut self, pci: &impl ConfigurationSpaceMechanism, message: MsiMessage) -> Result<(), MsiError> {
+        let (bar, offset) = self.table();
+        let bar = pci.bar(self.addr, bar)
+            .filter(|bar| bar.kind == PciBaseAddressType::MemorySpace)
+            .ok_or(MsiError::TableUnavailable)?;
+
+        let size = self.table_size() as usize * MSI_X_ENTRY_SIZE;
+        let table = unsafe { NoccioloAcpiHandler.try_map_physical_region::<u32>(bar.address as usize + offset as usize, size) }
+            .map_err(|_| MsiError::TableUnavailable)?;
+
+        // Mask the whole function while the table is changed.
+        self.control |= MSI_X_CONTROL_ENABLE | MSI_X_CONTROL_FUNCTION_MASK;
+        pci.write_word(self.addr, self.offset + 2, self.control);
+
+        let entry = |index: usize, field: usize| table.virtual_start().as_ptr().wrapping_add((index * MSI_X_ENTRY_SIZE + field) / 4);
+        unsafe {
+            for index in 1..self.table_size() as usize {
+                ptr::write_volatile(entry(index, MSI_X_ENTRY_VECTOR_CONTROL), MSI_X_VECTOR_MASKED);
+            }
+
+            ptr::write_volatile(entry(0, 0), message.address as u32);
+            ptr::write_volatile(entry(0, 4), (message.address >> 32) as u32);
+            ptr::write_volatile(entry(0, 8), message.data);
+            ptr::write_volatile(entry(0, MSI_X_ENTRY_VECTOR_CONTROL), 0);
+        }
+
+        self.control &= !MSI_X_CONTROL_FUNCTION_MASK;
+        pci.write_word(self.addr, self.offset + 2, self.control);
+        Ok(())
+    }
+
+    pub fn disable(&mut self, pci: &impl ConfigurationSpaceMechanism) {
+        self.control &= !MSI_X_CONTROL_ENABLE;
+        pci.write_word(self.addr, self.offset + 2, self.control);
+    }
+}
+
+impl Display for MsiX {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let (table_bar, table_offset) = self.table();
+        let (pending_bar, pending_offset) = self.pending_bit_array();
+        write!(f, "{}{}, {} messages, table in BAR {table_bar} at {table_offset:#x}, pending bits in BAR {pending_bar} at {pending_offset:#x}",
+            if self.is_enabled() { "enabled" } else { "disabled" },
+            if self.is_function_masked() { " (masked)" } else { "" },
+            self.table_size(),
+        )
+    }
+}
+
+/// Which capability the interrupt of a device is sent through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageCapability {
+    Msi(Msi),
+    MsiX(MsiX),
+}
+
+impl MessageCapability {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Msi(..) => "MSI",
+            Self::MsiX(..) => "MSI-X",
+        }
+    }
+}
+
+/// An interrupt of a device that was set up by [`enable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageInterrupt {
+    pub addr: PciAddress,
+    pub vector: u8,
+    pub capability: MessageCapability,
+}
+
+impl Display for MessageInterrupt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} on vector {}", self.capability.name(), self.vector)
+    }
+}
+
+/// Route the interrupt of the device to the handler, through MSI-X when the
+/// device has it, or else MSI, and disable its interrupt line. The handler is
+/// called in the interrupt, see [`interrupts::allocate_vector`].
+pub fn enable(pci: &impl ConfigurationSpaceMechanism, addr: PciAddress, handler: fn()) -> Result<MessageInterrupt, MsiError> {
+    let capability = MsiX::find(pci, addr).map(MessageCapability::MsiX)
+        .or_else(|| Msi::find(pci, addr).map(MessageCapability::Msi))
+        .ok_or(MsiError::NotCapable)?;
+    let apic_id = LocalApic::published_id().ok_or(MsiError::NoLocalApic)?;
+    let vector = interrupts::allocate_vector(handler).ok_or(MsiError::NoVectors)?;
+    debug_assert!(MSI_VECTORS.contains(&vector));
+
+    let message = MsiMessage::new(apic_id, vector);
+    let capability = match capability {
+        MessageCapability::Msi(mut msi) => {
+            msi.configure(pci, message);
+            MessageCapability::Msi(msi)
+        }
+        MessageCapability::MsiX(mut msi_x) => {
+            if let Err(e) = msi_x.configure(pci, message) {
+                interrupts::free_vector(vector);
+                return Err(e);
+            }
+            MessageCapability::MsiX(msi_x)
+        }
+    };
+
+    // The messages are memory writes by the device.
+    pci.enable_bus_mastering(addr);
+    pci.write_command(addr, pci.command(addr) | PciCommand::INTERRUPT_DISABLE);
+
+    Ok(MessageInterrupt { addr, vector, capability })
+}
+
+/// Stop the messages of [`enable`], and give the interrupt line back to the
+/// device.
+#[allow(dead_code)] // For drivers that give up their device
+pub fn disable(pci: &impl ConfigurationSpaceMechanism, interrupt: MessageInterrupt) {
+    match interrupt.capability {
+        MessageCapability::Msi(mut msi) => msi.disable(pci),
+        MessageCapability::MsiX(mut msi_x) => msi_x.disable(pci),
+    }
+
+    pci.write_command(interrupt.addr, pci.command(interrupt.addr) & !PciCommand::INTERRUPT_DISABLE);
+    interrupts::free_vector(interrupt.vector);
+}
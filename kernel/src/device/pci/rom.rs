@@ -0,0 +1,292 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The expansion ROMs of PCI devices (option ROMs), such as the VGA BIOS and
+//! the network boot ROMs QEMU gives its devices, shown by the `optionrom`
+//! shell command.
+//!
+//! A ROM is made of images, each for a different type of code, which start
+//! with the `55 AA` signature and point to a PCI data structure:
+//!
+//! | Offset | Image header                       | PCI data structure (`PCIR`)         |
+//! |--------|------------------------------------|-------------------------------------|
+//! | 0x00   | Signature `55 AA`                  | Signature `PCIR`                    |
+//! | 0x04   |                                    | Vendor ID and device ID             |
+//! | 0x0D   |                                    | Class code                          |
+//! | 0x10   |                                    | Image length in units of 512 bytes  |
+//! | 0x12   |                                    | Revision of the code                |
+//! | 0x14   |                                    | Code type, and whether it's last    |
+//! | 0x18   | Offset of the PCI data structure   |                                     |
+//!
+//! The ROM is only decoded by the device while it's enabled in the ROM BAR,
+//! and the device may share the decoder with its other BARs, so the ROM is
+//! copied while the driver of the device isn't expected to be busy. The ROM
+//! isn't assigned an address by the kernel, so it can only be read when the
+//! firmware did.
+//!
+//! ### References:
+//! - [PCI Firmware Specification 3.0, 5.1 PCI Expansion ROM Contents](https://pcisig.com/specifications)
+//! - [OSDev Wiki: PCI Expansion ROM](https://wiki.osdev.org/PCI_Expansion_ROM)
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use crate::device::acpi::{MapError, NoccioloAcpiHandler};
+
+use super::{ConfigurationSpaceMechanism, PciAddress, PciCommand, PciHeaderType};
+
+/// The largest ROM that is copied, since it's copied to the heap.
+pub const MAX_ROM_SIZE: u32 = 256 * 1024;
+
+const REGISTER_NORMAL: u16 = 0x30;
+const REGISTER_BRIDGE: u16 = 0x38;
+
+const ROM_ENABLE: u32 = 1 << 0;
+const ROM_ADDRESS_MASK: u32 = 0xFFFF_F800;
+
+const IMAGE_SIGNATURE: u16 = 0xAA55;
+const IMAGE_DATA_POINTER: usize = 0x18;
+const IMAGE_UNIT: usize = 512;
+
+const DATA_SIGNATURE: &[u8; 4] = b"PCIR";
+const DATA_SIZE: usize = 0x18;
+const INDICATOR_LAST_IMAGE: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionRomError {
+    /// The device has no ROM BAR, or doesn't implement it.
+    NoRom,
+
+    /// The firmware didn't assign an address to the ROM.
+    Unassigned,
+
+    /// The ROM is larger than [`MAX_ROM_SIZE`].
+    TooLarge(u32),
+
+    /// The address of the ROM can't be mapped, e.g. because the firmware
+    /// assigned it an address in RAM.
+    Map(MapError),
+
+    /// The first image doesn't start with the `55 AA` signature, e.g.
+    /// because the device doesn't decode the ROM.
+    InvalidSignature,
+
+    /// The image at the offset doesn't point to a valid PCI data structure.
+    InvalidDataStructure { offset: usize },
+}
+
+/// The ROM BAR of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomBar {
+    pub address: u32,
+    pub size: u32,
+    pub enabled: bool,
+}
+
+impl RomBar {
+    /// Decode the ROM BAR of the device, including its size. Returns `None`
+    /// if the device doesn't implement it.
+    ///
+    /// Decoding is temporarily disabled while the BAR is sized, so this must
+    /// not race with the driver of the device.
+    pub fn read(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress) -> Option<Self> {
+        let offset = rom_register(mechanism, addr)?;
+        let raw = mechanism.read_dword(addr, offset);
+
+        let command = mechanism.command(addr);
+        mechanism.write_command(addr, command & !PciCommand::MEMORY_SPACE);
+        mechanism.write_dword(addr, offset, ROM_ADDRESS_MASK);
+        let mask = mechanism.read_dword(addr, offset) & ROM_ADDRESS_MASK;
+        mechanism.write_dword(addr, offset, raw);
+        mechanism.write_command(addr, command);
+
+        if mask == 0 {
+            return None;
+        }
+
+        Some(Self {
+            address: raw & ROM_ADDRESS_MASK,
+            size: (!mask).wrapping_add(1),
+            enabled: raw & ROM_ENABLE != 0,
+        })
+    }
+}
+
+impl Display for RomBar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Expansion ROM at {:#x} ({}) [size={:#x}]",
+            self.address,
+            if self.enabled { "enabled" } else { "disabled" },
+            self.size,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomCodeType {
+    X86Bios,
+    OpenFirmware,
+    PaRisc,
+    Efi,
+    Unknown(u8),
+}
+
+impl RomCodeType {
+    pub const fn new(value: u8) -> Self {
+        match value {
+            0 => Self::X86Bios,
+            1 => Self::OpenFirmware,
+            2 => Self::PaRisc,
+            3 => Self::Efi,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl Display for RomCodeType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::X86Bios => f.write_str("x86 BIOS"),
+            Self::OpenFirmware => f.write_str("Open Firmware"),
+            Self::PaRisc => f.write_str("PA-RISC"),
+            Self::Efi => f.write_str("EFI"),
+            Self::Unknown(value) => write!(f, "unknown type {value:#x}"),
+        }
+    }
+}
+
+/// An image of the ROM, as described by its PCI data structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomImage {
+    /// Where the image starts in the ROM, and its length in bytes.
+    pub offset: usize,
+    pub length: usize,
+
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub code_revision: u16,
+    pub code_type: RomCodeType,
+
+    /// Whether the bytes of the image add up to zero, which is only required
+    /// of x86 BIOS images.
+    pub checksum_valid: Option<bool>,
+}
+
+/// A copy of the ROM of a device.
+#[derive(Debug, Clone)]
+pub struct OptionRom {
+    pub bar: RomBar,
+
+    /// The bytes up to the end of the last image.
+    pub data: Vec<u8>,
+    pub images: Vec<RomImage>,
+}
+
+impl OptionRom {
+    /// Copy the ROM of the device and check its images.
+    pub fn read(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress) -> Result<Self, OptionRomError> {
+        let bar = RomBar::read(mechanism, addr).ok_or(OptionRomError::NoRom)?;
+        if bar.address == 0 {
+            return Err(OptionRomError::Unassigned);
+        }
+        if bar.size > MAX_ROM_SIZE {
+            return Err(OptionRomError::TooLarge(bar.size));
+        }
+
+        let mut data = copy(mechanism, addr, &bar)?;
+        let images = parse_images(&data)?;
+        data.truncate(images.last().map_or(0, |image| image.offset + image.length));
+        Ok(Self { bar, data, images })
+    }
+}
+
+/// The offset of the ROM BAR in the configuration space, which depends on the
+/// header type.
+fn rom_register(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress) -> Option<u16> {
+    match mechanism.header_type(addr) {
+        PciHeaderType::Normal => Some(REGISTER_NORMAL),
+        PciHeaderType::PciToPciBridge => Some(REGISTER_BRIDGE),
+        _ => None,
+    }
+}
+
+/// Copy the whole ROM, with the decoding enabled meanwhile.
+fn copy(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress, bar: &RomBar) -> Result<Vec<u8>, OptionRomError> {
+    let offset = rom_register(mechanism, addr).expect("the ROM BAR was decoded");
+    let raw = mechanism.read_dword(addr, offset);
+    let command = mechanism.command(addr);
+    mechanism.write_dword(addr, offset, bar.address | ROM_ENABLE);
+    mechanism.write_command(addr, command | PciCommand::MEMORY_SPACE);
+
+    let result = unsafe {
+        NoccioloAcpiHandler.try_map_physical_region::<u8>(bar.address as usize, bar.size as usize)
+    };
+    let data = result.map(|mapping| {
+        let start = mapping.virtual_start().as_ptr() as *const u8;
+        (0..bar.size as usize)
+            .map(|index| unsafe { core::ptr::read_volatile(start.add(index)) })
+            .collect()
+    });
+
+    mechanism.write_command(addr, command);
+    mechanism.write_dword(addr, offset, raw);
+    data.map_err(OptionRomError::Map)
+}
+
+fn parse_images(data: &[u8]) -> Result<Vec<RomImage>, OptionRomError> {
+    let mut images = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let image = &data[offset..];
+        if read_u16(image, 0) != Some(IMAGE_SIGNATURE) {
+            // The images after the first may be followed by padding.
+            if images.is_empty() {
+                return Err(OptionRomError::InvalidSignature);
+            }
+            break;
+        }
+
+        let invalid = OptionRomError::InvalidDataStructure { offset };
+        let pointer = read_u16(image, IMAGE_DATA_POINTER).ok_or(invalid)? as usize;
+        let structure = image.get(pointer..pointer + DATA_SIZE)
+            .filter(|structure| structure.starts_with(DATA_SIGNATURE))
+            .ok_or(invalid)?;
+
+        let length = read_u16(structure, 0x10).ok_or(invalid)? as usize * IMAGE_UNIT;
+        if length == 0 {
+            return Err(invalid);
+        }
+        let length = length.min(image.len());
+
+        let code_type = RomCodeType::new(structure[0x14]);
+        let checksum_valid = (code_type == RomCodeType::X86Bios)
+            .then(|| image[..length].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0);
+
+        images.push(RomImage {
+            offset,
+            length,
+            vendor_id: read_u16(structure, 0x04).ok_or(invalid)?,
+            device_id: read_u16(structure, 0x06).ok_or(invalid)?,
+            class: structure[0x0F],
+            subclass: structure[0x0E],
+            code_revision: read_u16(structure, 0x12).ok_or(invalid)?,
+            code_type,
+            checksum_valid,
+        });
+
+        offset += length;
+        if structure[0x15] & INDICATOR_LAST_IMAGE != 0 || offset >= data.len() {
+            break;
+        }
+    }
+
+    Ok(images)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
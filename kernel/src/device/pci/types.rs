@@ -1,6 +1,8 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+use core::{fmt::{Display, Formatter}, str::FromStr};
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd)]
 pub struct PciAddress {
     pub segment: u16,
@@ -26,6 +28,40 @@ impl PciAddress {
     }
 }
 
+/// Formats the address in the `segment:bus:device.function` notation.
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{:x}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// Parses the `[segment:]bus:device.function` notation, in hexadecimal.
+impl FromStr for PciAddress {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rest, function) = s.rsplit_once('.').ok_or(())?;
+        let (rest, device) = rest.rsplit_once(':').ok_or(())?;
+        let (segment, bus) = match rest.rsplit_once(':') {
+            Some((segment, bus)) => (segment, bus),
+            None => ("0", rest),
+        };
+
+        let this = Self {
+            segment: u16::from_str_radix(segment, 16).map_err(|_| ())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| ())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| ())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| ())?,
+        };
+
+        if this.device >= 32 || this.function >= 8 {
+            return Err(());
+        }
+
+        Ok(this)
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PciBaseAddress(u32);
 
@@ -52,10 +88,21 @@ impl PciBaseAddress {
     #[must_use]
     pub const fn actual_address(&self) -> u32 {
         match self.kind() {
-            PciBaseAddressType::MemorySpace => self.value() & 0xFFF0,
-            PciBaseAddressType::IOSpace => self.value() & 0xFFFFFFF0,
+            PciBaseAddressType::MemorySpace => self.value() & 0xFFFFFFF0,
+            PciBaseAddressType::IOSpace => self.value() & 0xFFFFFFFC,
         }
     }
+
+    /// Whether this is the lower half of a 64-bit memory BAR.
+    #[must_use]
+    pub const fn is_64_bit(&self) -> bool {
+        matches!(self.kind(), PciBaseAddressType::MemorySpace) && (self.0 >> 1) & 0b11 == 0b10
+    }
+
+    #[must_use]
+    pub const fn is_prefetchable(&self) -> bool {
+        matches!(self.kind(), PciBaseAddressType::MemorySpace) && self.0 & 0b1000 != 0
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -64,6 +111,175 @@ pub enum PciBaseAddressType {
     IOSpace,
 }
 
+/// A decoded Base Address Register, including the size of the region it
+/// decodes, which is found by writing all ones to the register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciBar {
+    pub index: usize,
+    pub kind: PciBaseAddressType,
+    pub address: u64,
+    pub size: u64,
+    pub is_64_bit: bool,
+    pub is_prefetchable: bool,
+}
+
+impl Display for PciBar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            PciBaseAddressType::IOSpace => write!(f, "I/O ports at {:#x}", self.address)?,
+            PciBaseAddressType::MemorySpace => {
+                write!(f, "Memory at {:#x} ({}-bit, ", self.address, if self.is_64_bit { 64 } else { 32 })?;
+                f.write_str(if self.is_prefetchable { "prefetchable)" } else { "non-prefetchable)" })?;
+            }
+        }
+
+        write!(f, " [size={:#x}]", self.size)
+    }
+}
+
+/// The Command register (offset 0x4) of the configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCommand(pub u16);
+
+impl PciCommand {
+    pub const IO_SPACE: u16 = 1 << 0;
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const SPECIAL_CYCLES: u16 = 1 << 3;
+    pub const MEMORY_WRITE_AND_INVALIDATE: u16 = 1 << 4;
+    pub const VGA_PALETTE_SNOOP: u16 = 1 << 5;
+    pub const PARITY_ERROR_RESPONSE: u16 = 1 << 6;
+    pub const SERR: u16 = 1 << 8;
+    pub const FAST_BACK_TO_BACK: u16 = 1 << 9;
+    pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+
+    const NAMES: &'static [(u16, &'static str)] = &[
+        (Self::IO_SPACE, "I/O"),
+        (Self::MEMORY_SPACE, "Mem"),
+        (Self::BUS_MASTER, "BusMaster"),
+        (Self::SPECIAL_CYCLES, "SpecCycle"),
+        (Self::MEMORY_WRITE_AND_INVALIDATE, "MemWINV"),
+        (Self::VGA_PALETTE_SNOOP, "VGASnoop"),
+        (Self::PARITY_ERROR_RESPONSE, "ParErr"),
+        (Self::SERR, "SERR"),
+        (Self::FAST_BACK_TO_BACK, "FastB2B"),
+        (Self::INTERRUPT_DISABLE, "DisINTx"),
+    ];
+}
+
+impl Display for PciCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write_flags(f, self.0, Self::NAMES)
+    }
+}
+
+/// The Status register (offset 0x6) of the configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciStatus(pub u16);
+
+impl PciStatus {
+    pub const INTERRUPT_STATUS: u16 = 1 << 3;
+    pub const CAPABILITIES_LIST: u16 = 1 << 4;
+    pub const CAPABLE_66MHZ: u16 = 1 << 5;
+    pub const FAST_BACK_TO_BACK: u16 = 1 << 7;
+    pub const MASTER_DATA_PARITY_ERROR: u16 = 1 << 8;
+    pub const SIGNALED_TARGET_ABORT: u16 = 1 << 11;
+    pub const RECEIVED_TARGET_ABORT: u16 = 1 << 12;
+    pub const RECEIVED_MASTER_ABORT: u16 = 1 << 13;
+    pub const SIGNALED_SYSTEM_ERROR: u16 = 1 << 14;
+    pub const DETECTED_PARITY_ERROR: u16 = 1 << 15;
+
+    const NAMES: &'static [(u16, &'static str)] = &[
+        (Self::INTERRUPT_STATUS, "INTx"),
+        (Self::CAPABILITIES_LIST, "Cap"),
+        (Self::CAPABLE_66MHZ, "66MHz"),
+        (Self::FAST_BACK_TO_BACK, "FastB2B"),
+        (Self::MASTER_DATA_PARITY_ERROR, "ParErr"),
+        (Self::SIGNALED_TARGET_ABORT, ">TAbort"),
+        (Self::RECEIVED_TARGET_ABORT, "<TAbort"),
+        (Self::RECEIVED_MASTER_ABORT, "<MAbort"),
+        (Self::SIGNALED_SYSTEM_ERROR, ">SERR"),
+        (Self::DETECTED_PARITY_ERROR, "DetParErr"),
+    ];
+
+    #[must_use]
+    pub const fn has_capabilities_list(&self) -> bool {
+        self.0 & Self::CAPABILITIES_LIST != 0
+    }
+
+    /// The DEVSEL timing, in bits 9 and 10.
+    #[must_use]
+    pub const fn devsel_timing(&self) -> &'static str {
+        match (self.0 >> 9) & 0b11 {
+            0b00 => "fast",
+            0b01 => "medium",
+            0b10 => "slow",
+            _ => "reserved",
+        }
+    }
+}
+
+impl Display for PciStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write_flags(f, self.0, Self::NAMES)?;
+        write!(f, " DEVSEL={}", self.devsel_timing())
+    }
+}
+
+/// Write the flags in the `Flag+ Other-` notation that `lspci` uses.
+fn write_flags(f: &mut Formatter<'_>, value: u16, names: &[(u16, &str)]) -> core::fmt::Result {
+    for (idx, (flag, name)) in names.iter().enumerate() {
+        if idx != 0 {
+            f.write_str(" ")?;
+        }
+
+        f.write_str(name)?;
+        f.write_str(if value & flag != 0 { "+" } else { "-" })?;
+    }
+
+    Ok(())
+}
+
+/// An entry in the capability list of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCapability {
+    pub id: PciCapabilityId,
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCapabilityId(pub u8);
+
+impl PciCapabilityId {
+    pub const POWER_MANAGEMENT: Self = Self(0x01);
+    pub const AGP: Self = Self(0x02);
+    pub const VITAL_PRODUCT_DATA: Self = Self(0x03);
+    pub const MSI: Self = Self(0x05);
+    pub const VENDOR_SPECIFIC: Self = Self(0x09);
+    pub const PCI_BRIDGE_SUBSYSTEM_VENDOR_ID: Self = Self(0x0D);
+    pub const PCI_EXPRESS: Self = Self(0x10);
+    pub const MSI_X: Self = Self(0x11);
+    pub const SATA: Self = Self(0x12);
+    pub const ADVANCED_FEATURES: Self = Self(0x13);
+
+    #[must_use]
+    pub const fn name(&self) -> Option<&'static str> {
+        match *self {
+            Self::POWER_MANAGEMENT => Some("Power Management"),
+            Self::AGP => Some("AGP"),
+            Self::VITAL_PRODUCT_DATA => Some("Vital Product Data"),
+            Self::MSI => Some("MSI"),
+            Self::VENDOR_SPECIFIC => Some("Vendor Specific"),
+            Self::PCI_BRIDGE_SUBSYSTEM_VENDOR_ID => Some("Subsystem Vendor ID"),
+            Self::PCI_EXPRESS => Some("PCI Express"),
+            Self::MSI_X => Some("MSI-X"),
+            Self::SATA => Some("SATA"),
+            Self::ADVANCED_FEATURES => Some("Advanced Features"),
+            _ => None,
+        }
+    }
+}
+
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PciClassCode {
@@ -179,6 +395,7 @@ impl PciDeviceId {
         match vendor_id {
             PciVendorId::BOCHS => DeviceNames::get_bochs(self.0),
             PciVendorId::INTEL_CORPORATION => DeviceNames::get_intel(self.0),
+            PciVendorId::RED_HAT => DeviceNames::get_red_hat(self.0),
             _ => None,
         }
     }
@@ -361,6 +578,7 @@ impl PciVendorId {
 
     pub const BOCHS: Self = Self(0x1234);
     pub const INTEL_CORPORATION: Self = Self(0x8086);
+    pub const RED_HAT: Self = Self(0x1AF4);
 
     #[must_use]
     pub const fn new(id: u16) -> Self {
@@ -377,6 +595,7 @@ impl PciVendorId {
         match *self {
             Self::BOCHS => Some("Bochs"),
             Self::INTEL_CORPORATION => Some("Intel Corporation"),
+            Self::RED_HAT => Some("Red Hat, Inc."),
 
             Self::INVALID => Some("INVALID"),
 
@@ -402,4 +621,11 @@ impl DeviceNames {
             _ => None,
         }
     }
+
+    pub const fn get_red_hat(id: u16) -> Option<&'static str> {
+        match id {
+            0x1003 | 0x1043 => Some("Virtio console"),
+            _ => None,
+        }
+    }
 }
@@ -7,17 +7,19 @@ use lazy_static::lazy_static;
 use log::trace;
 use spin::Mutex;
 
-use x86_64::instructions::{
-    hlt,
-    interrupts::without_interrupts,
-    port::{Port, PortWriteOnly},
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    arch::{
+        interrupts::apic::IOApic,
+        port::{AuditedPort, PortUser},
+    },
+    task::{executor::block_on, timer},
 };
 
-use crate::interrupts::{apic::IOApic, TIMER};
-
 lazy_static! {
-    static ref CHANNEL0: Mutex<Port<u8>> = Mutex::new(Port::new(0x40));
-    static ref MODE_COMMAND: Mutex<PortWriteOnly<u8>> = Mutex::new(PortWriteOnly::new(0x43));
+    static ref CHANNEL0: Mutex<AuditedPort<u8>> = Mutex::new(AuditedPort::new(0x40, PortUser::Pit));
+    static ref MODE_COMMAND: Mutex<AuditedPort<u8>> = Mutex::new(AuditedPort::new(0x43, PortUser::Pit));
 }
 
 const BASE_FREQUENCY: usize = 1193182;
@@ -57,24 +59,11 @@ pub fn init() {
     set_frequency(1000);
 }
 
+/// Sleep during initialization, before the executor runs. Tasks should use
+/// [`crate::task::timer::sleep`] instead.
 pub fn sleep(s: Duration) {
-    let ms = s.as_millis() as usize;
-    if ms == 0 {
-        return;
-    }
-
-    let end =  get_pit_uptime() + ms;
-    trace!("Sleeping for {ms} milliseconds (until {end})...");
-
-    while end != get_pit_uptime() {
-        trace!("Halting");
-        // Halting waits for the timer interrupt
-        hlt();
-    }
-}
-
-fn get_pit_uptime() -> usize {
-    TIMER.lock().read()
+    trace!("Sleeping for {} milliseconds...", s.as_millis());
+    block_on(timer::sleep(s));
 }
 
 #[allow(unused)]
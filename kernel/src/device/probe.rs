@@ -0,0 +1,96 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Deferred initialization of devices.
+//!
+//! Instead of initializing a device as soon as it is enumerated, the bus
+//! queues the probe of its driver with [`defer`], and [`run_deferred`] runs
+//! the queued probes concurrently, so a device that is slow to respond doesn't
+//! hold up the others. Devices that depend on each other (or on ACPI) are
+//! ordered by the stages of [`super::init`].
+//!
+//! Every probe has a timeout, after which the device is marked as failed and
+//! the boot continues without it. Probes are futures, so the timeout only
+//! fires while the probe is waiting (e.g. on a timer), not while it is
+//! busy-waiting.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{future::Future, pin::Pin, task::Poll, time::Duration};
+
+use futures_util::future::poll_fn;
+use log::{info, trace};
+use spin::Mutex;
+
+use crate::{
+    arch,
+    dev_warn,
+    task::{executor::block_on, timer::{self, Sleep}},
+};
+
+use super::{registry::{self, DeviceId}, DeviceError};
+
+pub type ProbeFuture = Pin<Box<dyn Future<Output = Result<(), DeviceError>> + Send>>;
+
+static DEFERRED: Mutex<Vec<(DeviceId, Duration, ProbeFuture)>> = Mutex::new(Vec::new());
+
+struct Probe {
+    device: DeviceId,
+    timeout: Duration,
+    deadline: Sleep,
+    future: ProbeFuture,
+}
+
+/// Queue the probe of the driver bound to the device, to be run by
+/// [`run_deferred`].
+pub fn defer(device: DeviceId, timeout: Duration, future: ProbeFuture) {
+    DEFERRED.lock().push((device, timeout, future));
+}
+
+/// Run the queued probes concurrently, until all of them finished or timed
+/// out, recording their results in the registry.
+pub fn run_deferred() {
+    let mut probes: Vec<Probe> = DEFERRED.lock()
+        .drain(..)
+        .map(|(device, timeout, future)| Probe {
+            device,
+            timeout,
+            deadline: timer::sleep(timeout),
+            future,
+        })
+        .collect();
+
+    if probes.is_empty() {
+        return;
+    }
+
+    let count = probes.len();
+    trace!("Probing {count} devices");
+    let start = arch::ticks();
+
+    block_on(poll_fn(|cx| {
+        probes.retain_mut(|probe| {
+            let result = match probe.future.as_mut().poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => match Pin::new(&mut probe.deadline).poll(cx) {
+                    Poll::Ready(()) => {
+                        dev_warn!(probe.device, "Probe timed out after {} ms", probe.timeout.as_millis());
+                        Err(DeviceError::timeout())
+                    }
+                    Poll::Pending => return true,
+                },
+            };
+
+            registry::finish_probe(probe.device, &result);
+            false
+        });
+
+        if probes.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }));
+
+    let elapsed = (arch::ticks() - start) * 1000 / arch::TICKS_PER_SECOND;
+    info!("Probed {count} devices in {elapsed} ms");
+}
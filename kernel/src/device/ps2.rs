@@ -0,0 +1,346 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The PS/2 controller and the keyboard attached to it.
+//!
+//! The keyboard interrupt handler only reads the byte from the data port and
+//! queues it (see [`handle_interrupt`]). Everything else runs in the [`run`]
+//! task: it matches the responses of the keyboard to the commands we sent
+//! (resending them when asked to) and passes the other bytes on as scancodes.
+//! This keeps the interrupt handler short, so no bytes get lost while a state
+//! machine is busy.
+//!
+//! The only exception is the hotkey of the task inspector, which is checked
+//! in the interrupt handler, so the overlay still works when a task hangs.
+//!
+//! Keyboards send scancode set 2, which the controller translates to set 1
+//! by default. We turn the translation off and ask the keyboard for set 2 at
+//! boot, falling back to set 1 when that fails, or when `scancodeset=1` is
+//! passed.
+//!
+//! ### References:
+//! - [OSDev Wiki: PS/2 Keyboard](https://wiki.osdev.org/PS/2_Keyboard)
+//! - [OSDev Wiki: "8042" PS/2 Controller](https://wiki.osdev.org/%228042%22_PS/2_Controller)
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::{trace, warn};
+use spin::Mutex;
+
+use crate::{
+    arch::port::{AuditedPort, PortUser},
+    meta::{counters::Counter, init::HeapInitialized, irq_latency::{self, Irq}, BootParameters},
+    task::{inspector, keyboard},
+};
+
+use super::registry::{self, Resource};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// Set when there is a byte to read from the data port.
+const STATUS_OUTPUT_BUFFER_FULL: u8 = 1 << 0;
+
+/// Set when the controller hasn't consumed the last byte written yet.
+const STATUS_INPUT_BUFFER_FULL: u8 = 1 << 1;
+
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+
+/// Translate the scancodes of the first port to set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// How often to check the status before giving up on writing a byte.
+const WRITE_ATTEMPTS: usize = 10_000;
+
+const RESPONSE_ACK: u8 = 0xFA;
+const RESPONSE_RESEND: u8 = 0xFE;
+
+/// How often to resend a byte when the keyboard asks for it.
+const MAX_RESENDS: u8 = 3;
+
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_SCANCODE_SET: u8 = 0xF0;
+
+const QUEUE_CAPACITY: usize = 256;
+
+static BYTES: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The bytes dropped by the interrupt handler, because the queue was full or
+/// not created yet. Reported by the task, since the interrupt handler
+/// shouldn't log.
+static DROPPED: Counter = Counter::new("ps2.dropped", "Keyboard bytes dropped by the interrupt handler");
+
+/// The commands waiting to be sent by the task.
+static COMMANDS: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+
+/// The scancode set the keyboard sends, after translation by the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+}
+
+impl ScancodeSet {
+    /// The set currently in use.
+    pub fn current() -> Self {
+        match SCANCODE_SET.load(Ordering::Relaxed) {
+            2 => Self::Set2,
+            _ => Self::Set1,
+        }
+    }
+}
+
+/// The keyboard LEDs, see [`set_leds`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    const fn bits(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+/// Negotiate the scancode set and create the byte queue, after which the
+/// interrupt handler stops dropping bytes. Runs with interrupts disabled, so
+/// the responses of the keyboard can be polled.
+pub fn init(_: HeapInitialized) {
+    let set = match BootParameters::get("scancodeset") {
+        Some("1") => ScancodeSet::Set1,
+        _ => negotiate_set2(),
+    };
+    trace!("[ps2] Using scancode set {}", set as u8);
+    SCANCODE_SET.store(set as u8, Ordering::Relaxed);
+
+    BYTES.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("ps2::init should only be called once");
+    registry::register_io_device(
+        &[Resource::io_ports(DATA_PORT as u64, 1), Resource::io_ports(STATUS_PORT as u64, 1)],
+        "ps2",
+        "PS/2 keyboard",
+    );
+}
+
+/// Turn off the translation of the controller and switch the keyboard to set
+/// 2, restoring the translation when the keyboard doesn't cooperate.
+fn negotiate_set2() -> ScancodeSet {
+    // Drop any stale byte, which would be mistaken for a response.
+    _ = read_polled(1);
+
+    let Some(config) = write_controller(CONTROLLER_READ_CONFIG).then(|| read_polled(WRITE_ATTEMPTS)).flatten() else {
+        warn!("[ps2] Couldn't read the controller configuration");
+        return ScancodeSet::Set1;
+    };
+
+    let set_config = |config| write_controller(CONTROLLER_WRITE_CONFIG) && write(config);
+    if !set_config(config & !CONFIG_TRANSLATION) {
+        return ScancodeSet::Set1;
+    }
+
+    let acknowledged = [COMMAND_SCANCODE_SET, ScancodeSet::Set2 as u8]
+        .iter()
+        .all(|byte| write(*byte) && read_polled(WRITE_ATTEMPTS) == Some(RESPONSE_ACK));
+    if acknowledged {
+        return ScancodeSet::Set2;
+    }
+
+    warn!("[ps2] Keyboard doesn't support scancode set 2, using set 1");
+    set_config(config);
+    ScancodeSet::Set1
+}
+
+/// Called by the keyboard interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_interrupt() {
+    let mut port = AuditedPort::new(DATA_PORT, PortUser::Ps2);
+    let byte: u8 = unsafe { port.read() };
+
+    if inspector::handle_scancode(byte, ScancodeSet::current()) {
+        return;
+    }
+
+    match BYTES.try_get() {
+        Ok(queue) if queue.push(byte).is_ok() => {
+            WAKER.wake();
+            irq_latency::woke_consumer(Irq::Keyboard);
+        }
+        _ => DROPPED.increment(),
+    }
+}
+
+/// Queue a command for the keyboard: the command byte followed by its data
+/// bytes, each of which is acknowledged by the keyboard.
+pub fn send_command(bytes: &[u8]) {
+    COMMANDS.lock().push_back(bytes.to_vec());
+    WAKER.wake();
+}
+
+pub fn set_leds(leds: Leds) {
+    send_command(&[COMMAND_SET_LEDS, leds.bits()]);
+}
+
+/// The cleanup of the task when the machine shuts down: drop the commands it
+/// didn't send, and the waker of the interrupt handler.
+pub fn stop() {
+    COMMANDS.lock().clear();
+    WAKER.take();
+}
+
+/// The command being sent, awaiting the acknowledgement of `bytes[index]`.
+struct PendingCommand {
+    bytes: Vec<u8>,
+    index: usize,
+    resends: u8,
+}
+
+/// Interprets the bytes from the keyboard and sends the queued commands.
+pub async fn run() {
+    let mut pending: Option<PendingCommand> = None;
+    let mut reported_dropped = 0;
+
+    loop {
+        if pending.is_none() {
+            pending = COMMANDS.lock().pop_front().and_then(start);
+        }
+
+        let idle = pending.is_none();
+        let Some(byte) = poll_fn(|cx| poll_byte(cx, idle)).await else {
+            continue;
+        };
+        irq_latency::consumed(Irq::Keyboard);
+
+        let dropped = DROPPED.value() - core::mem::replace(&mut reported_dropped, DROPPED.value());
+        if dropped != 0 {
+            warn!("[ps2] Dropped {dropped} byte(s) from the keyboard");
+        }
+
+        match (&mut pending, byte) {
+            (Some(command), RESPONSE_ACK) => {
+                command.index += 1;
+                command.resends = 0;
+                if command.index == command.bytes.len() {
+                    trace!("[ps2] Command 0x{:02x} acknowledged", command.bytes[0]);
+                    pending = None;
+                } else if !write(command.bytes[command.index]) {
+                    pending = None;
+                }
+            }
+
+            (Some(command), RESPONSE_RESEND) => {
+                command.resends += 1;
+                if command.resends > MAX_RESENDS {
+                    warn!("[ps2] Keyboard keeps rejecting command 0x{:02x}, giving up", command.bytes[0]);
+                    pending = None;
+                } else if !write(command.bytes[command.index]) {
+                    pending = None;
+                }
+            }
+
+            // The interrupt of a response polled during `init`.
+            (None, RESPONSE_ACK) => (),
+
+            // Scancodes can arrive while a command is in flight.
+            _ => keyboard::add_scancode(byte),
+        }
+    }
+}
+
+fn start(bytes: Vec<u8>) -> Option<PendingCommand> {
+    if bytes.is_empty() || !write(bytes[0]) {
+        return None;
+    }
+
+    Some(PendingCommand {
+        bytes,
+        index: 0,
+        resends: 0,
+    })
+}
+
+/// Write a byte to the keyboard, once the controller is ready for it.
+fn write(byte: u8) -> bool {
+    let mut status = AuditedPort::<u8>::new(STATUS_PORT, PortUser::Ps2);
+    let mut data = AuditedPort::<u8>::new(DATA_PORT, PortUser::Ps2);
+
+    for _ in 0..WRITE_ATTEMPTS {
+        if unsafe { status.read() } & STATUS_INPUT_BUFFER_FULL == 0 {
+            unsafe { data.write(byte) };
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+
+    warn!("[ps2] Controller didn't accept byte 0x{byte:02x}");
+    false
+}
+
+/// Write a command byte to the controller itself.
+fn write_controller(command: u8) -> bool {
+    let mut status = AuditedPort::<u8>::new(STATUS_PORT, PortUser::Ps2);
+
+    for _ in 0..WRITE_ATTEMPTS {
+        if unsafe { status.read() } & STATUS_INPUT_BUFFER_FULL == 0 {
+            unsafe { status.write(command) };
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+
+    warn!("[ps2] Controller didn't accept command 0x{command:02x}");
+    false
+}
+
+/// Read a byte without the interrupt handler, which is only possible while
+/// interrupts are disabled.
+fn read_polled(attempts: usize) -> Option<u8> {
+    let mut status = AuditedPort::<u8>::new(STATUS_PORT, PortUser::Ps2);
+    let mut data = AuditedPort::<u8>::new(DATA_PORT, PortUser::Ps2);
+
+    for _ in 0..attempts {
+        if unsafe { status.read() } & STATUS_OUTPUT_BUFFER_FULL != 0 {
+            return Some(unsafe { data.read() });
+        }
+        core::hint::spin_loop();
+    }
+
+    None
+}
+
+/// Wait for the next byte from the keyboard, or when `idle`, for a command to
+/// be queued (yielding `None`).
+fn poll_byte(cx: &mut Context, idle: bool) -> Poll<Option<u8>> {
+    let queue = BYTES.try_get().expect("ps2 byte queue not initialized");
+
+    if let Some(byte) = queue.pop() {
+        return Poll::Ready(Some(byte));
+    }
+
+    WAKER.register(cx.waker());
+    if let Some(byte) = queue.pop() {
+        WAKER.take();
+        return Poll::Ready(Some(byte));
+    }
+
+    if idle && !COMMANDS.lock().is_empty() {
+        WAKER.take();
+        return Poll::Ready(None);
+    }
+
+    Poll::Pending
+}
@@ -0,0 +1,255 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The tree of buses and devices, and the drivers bound to them, as shown by
+//! the `devices` shell command.
+//!
+//! Every device gets a stable name, such as `pci-0000:00:03.0-e1000` once a
+//! driver is bound, which the drivers use as the log target through the
+//! [`dev_info!`](crate::dev_info) family of macros, so the messages of a
+//! single device are easy to find (and filter) in the log.
+//!
+//! Devices are never removed, so the names are leaked to give them a static
+//! lifetime, as the logger wants.
+//!
+//! Drivers [`claim`] the I/O ports and memory their device decodes, so two
+//! devices decoding the same range show up as a conflict, instead of both
+//! drivers silently poking at the same registers. The AML consults the claims
+//! as well (see [`owner`]), and isn't allowed to touch the ports of a device
+//! the kernel drives.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::{Display, Formatter};
+
+use log::error;
+use spin::{Mutex, Once};
+
+use super::DeviceError;
+
+static DEVICES: Mutex<Vec<DeviceNode>> = Mutex::new(Vec::new());
+static PLATFORM: Once<DeviceId> = Once::new();
+
+/// A handle to a device in the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+impl DeviceId {
+    /// The name of the device, which is the log target of its driver.
+    pub fn name(&self) -> &'static str {
+        DEVICES.lock()[self.0].name
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Bus,
+    Device,
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Bus => "bus",
+            Self::Device => "device",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceStatus {
+    /// No driver claimed the device.
+    Unbound,
+
+    /// A driver is bound, and is initializing the device.
+    Probing,
+    Bound,
+    Failed(String),
+}
+
+impl Display for DeviceStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Unbound => f.write_str("unbound"),
+            Self::Probing => f.write_str("probing"),
+            Self::Bound => f.write_str("bound"),
+            Self::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    IoPorts,
+    Memory,
+}
+
+/// A range of I/O ports or physical memory decoded by a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Resource {
+    pub const fn io_ports(start: u64, size: u64) -> Self {
+        Self { kind: ResourceKind::IoPorts, start, size }
+    }
+
+    pub const fn memory(start: u64, size: u64) -> Self {
+        Self { kind: ResourceKind::Memory, start, size }
+    }
+
+    pub const fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    pub const fn overlaps(&self, other: &Self) -> bool {
+        self.kind as u8 == other.kind as u8 && self.start < other.end() && other.start < self.end()
+    }
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let kind = match self.kind {
+            ResourceKind::IoPorts => "io",
+            ResourceKind::Memory => "mem",
+        };
+        write!(f, "{kind} {:#x}-{:#x}", self.start, self.end().saturating_sub(1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceNode {
+    pub id: DeviceId,
+    pub name: &'static str,
+    pub parent: Option<DeviceId>,
+    pub kind: DeviceKind,
+    pub description: String,
+    pub driver: Option<&'static str>,
+    pub status: DeviceStatus,
+
+    /// The ranges claimed by the driver.
+    pub resources: Vec<Resource>,
+
+    /// The ranges the driver wanted, but which were already claimed by the
+    /// other device.
+    pub conflicts: Vec<(Resource, DeviceId)>,
+}
+
+pub fn register_bus(name: &str, parent: Option<DeviceId>, description: &str) -> DeviceId {
+    register(DeviceKind::Bus, name, parent, description)
+}
+
+pub fn register_device(name: &str, parent: Option<DeviceId>, description: &str) -> DeviceId {
+    register(DeviceKind::Device, name, parent, description)
+}
+
+/// Register a device at fixed I/O ports (on the platform bus), which is
+/// driven by the kernel itself, and claim the ports. The device is named after
+/// the first range, e.g. `io-0060-ps2`.
+pub fn register_io_device(ports: &[Resource], driver: &'static str, description: &str) -> DeviceId {
+    let platform = *PLATFORM.call_once(|| register_bus("platform", None, "Legacy platform devices"));
+
+    let first = ports.first().map_or(0, |resource| resource.start);
+    let id = register_device(&format!("io-{first:04x}"), Some(platform), description);
+    bind(id, driver);
+
+    // Two drivers for the same ports is a bug of the kernel itself.
+    for resource in ports {
+        if let Err(owner) = claim(id, *resource) {
+            error!(target: id.name(), "Range {resource} is already claimed by {}", owner.name());
+        }
+    }
+
+    finish_probe(id, &Ok(()));
+    id
+}
+
+fn register(kind: DeviceKind, name: &str, parent: Option<DeviceId>, description: &str) -> DeviceId {
+    let mut devices = DEVICES.lock();
+    let id = DeviceId(devices.len());
+    devices.push(DeviceNode {
+        id,
+        name: String::from(name).leak(),
+        parent,
+        kind,
+        description: description.into(),
+        driver: None,
+        status: if kind == DeviceKind::Bus { DeviceStatus::Bound } else { DeviceStatus::Unbound },
+        resources: Vec::new(),
+        conflicts: Vec::new(),
+    });
+    id
+}
+
+/// Bind a driver to the device, which appends the name of the driver to the
+/// name of the device.
+pub fn bind(id: DeviceId, driver: &'static str) {
+    let mut devices = DEVICES.lock();
+    let device = &mut devices[id.0];
+    device.name = format!("{}-{driver}", device.name).leak();
+    device.driver = Some(driver);
+    device.status = DeviceStatus::Probing;
+}
+
+/// Record the result of the initialization by the driver.
+pub fn finish_probe(id: DeviceId, result: &Result<(), DeviceError>) {
+    DEVICES.lock()[id.0].status = match result {
+        Ok(()) => DeviceStatus::Bound,
+        Err(e) => DeviceStatus::Failed(format!("{e}")),
+    };
+}
+
+/// Claim a range for the device, unless another device claimed an overlapping
+/// range already, which is returned instead and recorded as a conflict.
+pub fn claim(id: DeviceId, resource: Resource) -> Result<(), DeviceId> {
+    let mut devices = DEVICES.lock();
+
+    let owner = devices.iter()
+        .find(|device| device.id != id && device.resources.iter().any(|claimed| claimed.overlaps(&resource)))
+        .map(|device| device.id);
+
+    let device = &mut devices[id.0];
+    match owner {
+        Some(owner) => {
+            device.conflicts.push((resource, owner));
+            Err(owner)
+        }
+        None => {
+            device.resources.push(resource);
+            Ok(())
+        }
+    }
+}
+
+/// The device that claimed (part of) the range.
+pub fn owner(resource: &Resource) -> Option<DeviceId> {
+    DEVICES.lock().iter()
+        .find(|device| device.resources.iter().any(|claimed| claimed.overlaps(resource)))
+        .map(|device| device.id)
+}
+
+pub fn devices() -> Vec<DeviceNode> {
+    DEVICES.lock().clone()
+}
+
+#[macro_export]
+macro_rules! dev_trace {
+    ($device:expr, $($arg:tt)*) => (log::trace!(target: $device.name(), $($arg)*));
+}
+
+#[macro_export]
+macro_rules! dev_info {
+    ($device:expr, $($arg:tt)*) => (log::info!(target: $device.name(), $($arg)*));
+}
+
+#[macro_export]
+macro_rules! dev_warn {
+    ($device:expr, $($arg:tt)*) => (log::warn!(target: $device.name(), $($arg)*));
+}
+
+#[macro_export]
+macro_rules! dev_error {
+    ($device:expr, $($arg:tt)*) => (log::error!(target: $device.name(), $($arg)*));
+}
@@ -0,0 +1,382 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The virtio console (virtio-serial), which gives the host character
+//! channels next to the serial ports. With the multiport feature, the device
+//! announces its ports by name over a pair of control queues:
+//!
+//! | Port                  | Use                                                        |
+//! |-----------------------|------------------------------------------------------------|
+//! | `org.nocciolo.agent`  | The guest agent protocol, see [`guest_agent`]              |
+//! | `org.nocciolo.report` | The boot report as JSON, written when the port is set up   |
+//! | The console port      | A copy of the log, when there is no serial port at COM1    |
+//!
+//! The console port is the one of `-device virtconsole`, or the only port when
+//! the device doesn't do multiport.
+//!
+//! There is no interrupt routing for PCI devices yet, so the `virtio-console`
+//! task polls the queues.
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 5.3 Console Device](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{fmt::{Arguments, Write}, time::Duration};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+use crate::{
+    arch::serial,
+    dev_info,
+    dev_trace,
+    device::{
+        guest_agent,
+        pci::{PciDriver, PciVendorId},
+        registry::DeviceId,
+        DeviceError,
+    },
+    sync::Spinlock,
+    task::timer,
+};
+
+use super::{Transport, Virtqueue, BUFFER_SIZE};
+
+pub const AGENT_PORT: &str = "org.nocciolo.agent";
+pub const REPORT_PORT: &str = "org.nocciolo.report";
+
+/// The device IDs of the transitional console, and of the modern-only one.
+const DEVICE_IDS: [u16; 2] = [0x1003, 0x1043];
+
+const FEATURE_MULTIPORT: u32 = 1 << 1;
+
+/// The offset of `max_nr_ports` in the configuration of the device.
+const CONFIG_MAX_PORTS: u16 = 4;
+
+/// The ports beyond these are ignored, since each port has its own queues.
+const MAX_PORTS: u32 = 4;
+
+const EVENT_DEVICE_READY: u16 = 0;
+const EVENT_DEVICE_ADD: u16 = 1;
+const EVENT_DEVICE_REMOVE: u16 = 2;
+const EVENT_PORT_READY: u16 = 3;
+const EVENT_CONSOLE_PORT: u16 = 4;
+const EVENT_PORT_OPEN: u16 = 6;
+const EVENT_PORT_NAME: u16 = 7;
+
+/// The size of a control message without its data: the port, the event and
+/// its value.
+const CONTROL_HEADER_SIZE: usize = 8;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The log output waiting for the console port, when it's the primary
+/// console.
+const MIRROR_CAPACITY: usize = 16 * 1024;
+
+static CONSOLE: Spinlock<Option<Console>> = Spinlock::new(None);
+static MIRROR: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "virtio-console",
+    matches: |info| info.vendor_id == PciVendorId::RED_HAT && DEVICE_IDS.contains(&info.device_id.value()),
+    probe: |device, info| {
+        let result = Transport::new(device, info).and_then(|transport| Console::init(device, transport));
+        if let Ok(console) = result.as_ref() {
+            // Catch the log from now on, the console port is set up later.
+            if !serial::is_present() {
+                dev_info!(device, "No serial port, the console port is the primary console");
+                MIRROR.init_once(|| ArrayQueue::new(MIRROR_CAPACITY));
+            }
+            dev_info!(device, "{} ports", console.ports.len());
+        }
+
+        let result = result.map(|console| *CONSOLE.lock() = Some(console));
+        Box::pin(async move { result })
+    },
+    timeout: Duration::from_millis(100),
+};
+
+struct Port {
+    receive: Virtqueue,
+    transmit: Virtqueue,
+
+    /// Whether the device announced the port, which is always the case without
+    /// multiport.
+    added: bool,
+    name: Option<String>,
+    is_console: bool,
+}
+
+struct Console {
+    device: DeviceId,
+    transport: Transport,
+    ports: Vec<Port>,
+
+    /// The receive and transmit queue of the control messages, with multiport.
+    control: Option<(Virtqueue, Virtqueue)>,
+}
+
+impl Console {
+    fn init(device: DeviceId, mut transport: Transport) -> Result<Self, DeviceError> {
+        let multiport = transport.negotiate(FEATURE_MULTIPORT)? & FEATURE_MULTIPORT != 0;
+        let port_count = if multiport {
+            transport.read_config_u32(CONFIG_MAX_PORTS).clamp(1, MAX_PORTS)
+        } else {
+            1
+        };
+
+        let result = (|| {
+            // Port 0 has queues 0 and 1, the control queues are 2 and 3, and
+            // the other ports follow.
+            let mut ports = Vec::new();
+            let mut control = None;
+            for port in 0..port_count as u16 {
+                let first = if port == 0 { 0 } else { 2 + 2 * port };
+                ports.push(Port {
+                    receive: transport.setup_queue(first)?,
+                    transmit: transport.setup_queue(first + 1)?,
+                    added: !multiport,
+                    name: None,
+                    is_console: !multiport,
+                });
+
+                if port == 0 && multiport {
+                    control = Some((transport.setup_queue(2)?, transport.setup_queue(3)?));
+                }
+            }
+            Ok::<_, DeviceError>((ports, control))
+        })();
+
+        let (ports, control) = match result {
+            Ok(queues) => queues,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+
+        transport.finish();
+
+        let mut console = Self { device, transport, ports, control };
+        for index in 0..console.ports.len() {
+            console.ports[index].receive.push_writable();
+            console.transport.notify(&console.ports[index].receive);
+        }
+
+        if let Some((receive, _)) = console.control.as_mut() {
+            receive.push_writable();
+            console.transport.notify(receive);
+            console.send_control(0, EVENT_DEVICE_READY, 1);
+        }
+
+        Ok(console)
+    }
+
+    fn send_control(&mut self, port: u32, event: u16, value: u16) {
+        let Some((_, transmit)) = self.control.as_mut() else {
+            return;
+        };
+
+        let mut message = [0; CONTROL_HEADER_SIZE];
+        message[0..4].copy_from_slice(&port.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..8].copy_from_slice(&value.to_le_bytes());
+        if transmit.push_readable(&message) {
+            self.transport.notify(transmit);
+        }
+    }
+
+    fn poll(&mut self) {
+        let mut messages = Vec::new();
+        if let Some((receive, transmit)) = self.control.as_mut() {
+            while receive.pop_used(|message| messages.push(message.to_vec())) {}
+            if !messages.is_empty() {
+                receive.push_writable();
+                self.transport.notify(receive);
+            }
+            while transmit.pop_used(|_| ()) {}
+        }
+
+        for message in messages {
+            self.handle_control(&message);
+        }
+
+        for index in 0..self.ports.len() {
+            let port = &mut self.ports[index];
+            while port.transmit.pop_used(|_| ()) {}
+
+            let is_agent = port.name.as_deref() == Some(AGENT_PORT);
+            let mut received = false;
+            while port.receive.pop_used(|data| if is_agent { guest_agent::receive_from_virtio(data) }) {
+                received = true;
+            }
+
+            if received {
+                port.receive.push_writable();
+                self.transport.notify(&self.ports[index].receive);
+            }
+        }
+
+        self.write_mirrored_log();
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        let Some(header) = message.get(..CONTROL_HEADER_SIZE) else {
+            return;
+        };
+
+        let id = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(header[6..8].try_into().unwrap());
+        dev_trace!(self.device, "Control event {event} for port {id} ({value})");
+
+        let Some(port) = self.ports.get_mut(id as usize) else {
+            if event == EVENT_DEVICE_ADD {
+                // Tell the device we can't use it.
+                self.send_control(id, EVENT_PORT_READY, 0);
+            }
+            return;
+        };
+
+        match event {
+            EVENT_DEVICE_ADD => {
+                port.added = true;
+                self.send_control(id, EVENT_PORT_READY, 1);
+            }
+
+            EVENT_DEVICE_REMOVE => {
+                port.added = false;
+                port.name = None;
+            }
+
+            EVENT_CONSOLE_PORT => {
+                port.is_console = true;
+                self.send_control(id, EVENT_PORT_OPEN, 1);
+            }
+
+            EVENT_PORT_NAME => {
+                let name = String::from_utf8_lossy(&message[CONTROL_HEADER_SIZE..]);
+                let name = String::from(name.trim_end_matches('\0'));
+                dev_info!(self.device, "Port {id} is `{name}`");
+
+                let is_report = name == REPORT_PORT;
+                let is_ours = is_report || name == AGENT_PORT;
+                port.name = Some(name);
+                if is_ours {
+                    self.send_control(id, EVENT_PORT_OPEN, 1);
+                }
+
+                if is_report {
+                    let mut report = guest_agent::boot_report();
+                    report.push('\n');
+                    self.write(id as usize, report.as_bytes());
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    /// Write to the port, dropping what doesn't fit in the free buffers.
+    fn write(&mut self, index: usize, data: &[u8]) -> bool {
+        let port = &mut self.ports[index];
+        if !port.added {
+            return false;
+        }
+
+        let mut written = true;
+        for chunk in data.chunks(BUFFER_SIZE) {
+            written &= port.transmit.push_readable(chunk);
+        }
+        self.transport.notify(&self.ports[index].transmit);
+        written
+    }
+
+    fn write_mirrored_log(&mut self) {
+        let Ok(mirror) = MIRROR.try_get() else {
+            return;
+        };
+
+        let Some(index) = self.ports.iter().position(|port| port.added && port.is_console) else {
+            return;
+        };
+
+        let mut chunk = Vec::with_capacity(BUFFER_SIZE);
+        while !mirror.is_empty() {
+            chunk.clear();
+            while chunk.len() < BUFFER_SIZE {
+                match mirror.pop() {
+                    Some(byte) => chunk.push(byte),
+                    None => break,
+                }
+            }
+
+            if !self.write(index, &chunk) {
+                break;
+            }
+        }
+    }
+}
+
+/// Write to the port with the given name, returning `false` when there is no
+/// such port or its buffers are full.
+pub fn write(name: &str, data: &[u8]) -> bool {
+    let mut console = CONSOLE.lock();
+    let Some(console) = console.as_mut() else {
+        return false;
+    };
+
+    match console.ports.iter().position(|port| port.name.as_deref() == Some(name)) {
+        Some(index) => console.write(index, data),
+        None => false,
+    }
+}
+
+/// Write the log output waiting for the console port, e.g. before the machine
+/// is reset.
+pub fn flush() {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.write_mirrored_log();
+    }
+}
+
+pub fn is_present() -> bool {
+    CONSOLE.lock().is_some()
+}
+
+/// Queue log output for the console port, when it is the primary console.
+///
+/// Must not block or allocate.
+pub(crate) fn mirror(args: Arguments) {
+    let Ok(queue) = MIRROR.try_get() else {
+        return;
+    };
+
+    struct Mirror<'a>(&'a ArrayQueue<u8>);
+    impl Write for Mirror<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            // Dropped when the console can't keep up, like the serial FIFO.
+            for byte in s.bytes() {
+                _ = self.0.push(byte);
+            }
+            Ok(())
+        }
+    }
+
+    _ = Mirror(queue).write_fmt(args);
+}
+
+/// The task that polls the device.
+pub async fn run() {
+    if !is_present() {
+        return;
+    }
+
+    loop {
+        timer::sleep(POLL_INTERVAL).await;
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.poll();
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The legacy interface of transitional devices: a block of I/O ports in
+//! BAR0, which QEMU offers next to the modern interface unless
+//! `disable-legacy=on`.
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 4.1.4.8 Legacy Interfaces](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+
+use crate::{
+    arch::port::{AuditedPort, PortUser},
+    device::{
+        pci::{ConfigurationSpaceMechanism, PciBar, PciCommand, PciDeviceInfo, PciConfigurationSpace},
+        registry::{self, DeviceId, Resource},
+        DeviceError,
+    },
+};
+
+use super::{Virtqueue, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED};
+
+const REGISTER_DEVICE_FEATURES: u16 = 0x00;
+const REGISTER_DRIVER_FEATURES: u16 = 0x04;
+const REGISTER_QUEUE_ADDRESS: u16 = 0x08;
+const REGISTER_QUEUE_SIZE: u16 = 0x0C;
+const REGISTER_QUEUE_SELECT: u16 = 0x0E;
+const REGISTER_QUEUE_NOTIFY: u16 = 0x10;
+const REGISTER_DEVICE_STATUS: u16 = 0x12;
+
+/// The configuration of the device type, when MSI-X is disabled.
+const REGISTER_DEVICE_CONFIG: u16 = 0x14;
+
+pub struct LegacyTransport {
+    base: u16,
+}
+
+impl LegacyTransport {
+    /// Claim the ports of the device, and reset it.
+    pub fn new(device: DeviceId, info: &PciDeviceInfo, bar: PciBar) -> Result<Self, DeviceError> {
+        if let Err(owner) = registry::claim(device, Resource::io_ports(bar.address, bar.size)) {
+            crate::dev_warn!(device, "Ports {bar} are already claimed by {}", owner.name());
+            return Err(DeviceError::unsupported("ports claimed"));
+        }
+
+        let pci = PciConfigurationSpace;
+        pci.write_command(info.address, pci.command(info.address) | PciCommand::IO_SPACE | PciCommand::BUS_MASTER);
+
+        let mut transport = Self { base: bar.address as u16 };
+        transport.write_u8(REGISTER_DEVICE_STATUS, 0);
+        transport.write_u8(REGISTER_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    pub fn negotiate(&mut self, supported: u32) -> u32 {
+        let features = self.read_u32(REGISTER_DEVICE_FEATURES) & supported;
+        self.write_u32(REGISTER_DRIVER_FEATURES, features);
+        features
+    }
+
+    pub fn setup_queue(&mut self, index: u16) -> Result<Virtqueue, DeviceError> {
+        self.write_u16(REGISTER_QUEUE_SELECT, index);
+        let size = self.read_u16(REGISTER_QUEUE_SIZE);
+        if size == 0 {
+            return Err(DeviceError::unsupported("queue doesn't exist"));
+        }
+
+        let queue = Virtqueue::new(index, size).ok_or(DeviceError::unsupported("out of memory for the queue"))?;
+        self.write_u32(REGISTER_QUEUE_ADDRESS, (queue.physical_address() / 4096) as u32);
+        Ok(queue)
+    }
+
+    pub fn finish(&mut self) {
+        let status = self.read_u8(REGISTER_DEVICE_STATUS);
+        self.write_u8(REGISTER_DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&mut self) {
+        let status = self.read_u8(REGISTER_DEVICE_STATUS);
+        self.write_u8(REGISTER_DEVICE_STATUS, status | STATUS_FAILED);
+    }
+
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        self.write_u16(REGISTER_QUEUE_NOTIFY, queue.index());
+    }
+
+    pub fn read_config_u32(&mut self, offset: u16) -> u32 {
+        self.read_u32(REGISTER_DEVICE_CONFIG + offset)
+    }
+
+    fn read_u8(&mut self, register: u16) -> u8 {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).read() }
+    }
+
+    fn read_u16(&mut self, register: u16) -> u16 {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).read() }
+    }
+
+    fn read_u32(&mut self, register: u16) -> u32 {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).read() }
+    }
+
+    fn write_u8(&mut self, register: u16, value: u8) {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).write(value) }
+    }
+
+    fn write_u16(&mut self, register: u16, value: u16) {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).write(value) }
+    }
+
+    fn write_u32(&mut self, register: u16, value: u32) {
+        unsafe { AuditedPort::new(self.base + register, PortUser::Virtio).write(value) }
+    }
+}
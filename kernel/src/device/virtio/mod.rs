@@ -0,0 +1,116 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The virtio transport over PCI, through either interface of the device:
+//!
+//! | Interface | Device IDs                            | Registers                                 |
+//! |-----------|---------------------------------------|-------------------------------------------|
+//! | Legacy    | 0x1000 to 0x103F (transitional)       | I/O ports in BAR0, see [`legacy`]         |
+//! | Modern    | All, from 0x1040 on only this one     | Structures in memory BARs, see [`modern`] |
+//!
+//! The legacy interface is used when the device has one, so transitional
+//! devices behave the same whichever their driver. The drivers of the device
+//! types are in the submodules, except for the block device, which is in
+//! [`block`](crate::device::block).
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 4.1 Virtio Over PCI Bus](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+//! - [OSDev Wiki: Virtio](https://wiki.osdev.org/Virtio)
+
+pub mod console;
+mod legacy;
+mod modern;
+mod queue;
+pub mod rng;
+
+use crate::device::{
+    pci::{ConfigurationSpaceMechanism, PciBaseAddressType, PciDeviceInfo, PciConfigurationSpace},
+    registry::DeviceId,
+    DeviceError,
+};
+
+use self::{legacy::LegacyTransport, modern::ModernTransport};
+
+pub use self::queue::{Virtqueue, BUFFER_SIZE};
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// The interface of a device.
+pub enum Transport {
+    Legacy(LegacyTransport),
+    Modern(ModernTransport),
+}
+
+impl Transport {
+    /// Claim the registers of the device, and reset it.
+    pub fn new(device: DeviceId, info: &PciDeviceInfo) -> Result<Self, DeviceError> {
+        let legacy = PciConfigurationSpace.bar(info.address, 0)
+            .filter(|bar| bar.kind == PciBaseAddressType::IOSpace);
+        match legacy {
+            Some(bar) => LegacyTransport::new(device, info, bar).map(Self::Legacy),
+            None => ModernTransport::new(device, info.address).map(Self::Modern),
+        }
+    }
+
+    /// Accept the features the device offers of the given ones, returning the
+    /// accepted features. The device is failed when it refuses them.
+    pub fn negotiate(&mut self, supported: u32) -> Result<u32, DeviceError> {
+        match self {
+            Self::Legacy(transport) => Ok(transport.negotiate(supported)),
+            Self::Modern(transport) => transport.negotiate(supported),
+        }
+    }
+
+    pub fn setup_queue(&mut self, index: u16) -> Result<Virtqueue, DeviceError> {
+        match self {
+            Self::Legacy(transport) => transport.setup_queue(index),
+            Self::Modern(transport) => transport.setup_queue(index),
+        }
+    }
+
+    /// Tell the device the driver is ready, after the queues are set up.
+    pub fn finish(&mut self) {
+        match self {
+            Self::Legacy(transport) => transport.finish(),
+            Self::Modern(transport) => transport.finish(),
+        }
+    }
+
+    /// Tell the device the driver gave up on it.
+    pub fn fail(&mut self) {
+        match self {
+            Self::Legacy(transport) => transport.fail(),
+            Self::Modern(transport) => transport.fail(),
+        }
+    }
+
+    /// Tell the device there are new buffers in the queue.
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        match self {
+            Self::Legacy(transport) => transport.notify(queue),
+            Self::Modern(transport) => transport.notify(queue),
+        }
+    }
+
+    /// Read from the configuration of the device type.
+    pub fn read_config_u32(&mut self, offset: u16) -> u32 {
+        match self {
+            Self::Legacy(transport) => transport.read_config_u32(offset),
+            Self::Modern(transport) => transport.read_config_u32(offset),
+        }
+    }
+
+    pub fn read_config_u64(&mut self, offset: u16) -> u64 {
+        (self.read_config_u32(offset + 4) as u64) << 32 | self.read_config_u32(offset) as u64
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Legacy(..) => "legacy",
+            Self::Modern(..) => "modern",
+        }
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The modern interface, of devices with a device ID from 0x1040 on and of
+//! transitional devices with `disable-legacy=on`. The registers are in
+//! structures in the memory BARs, which are found through vendor-specific
+//! capabilities:
+//!
+//! | Type | Structure            | Contents                                      |
+//! |------|----------------------|-----------------------------------------------|
+//! | 1    | Common configuration | Features, status, and the selected queue      |
+//! | 2    | Notifications        | Where the queues are notified, see below      |
+//! | 3    | ISR status           | Unused, since interrupts aren't routed        |
+//! | 4    | Device configuration | The configuration of the device type          |
+//!
+//! A queue is notified by writing its index at its own offset in the
+//! notification structure: its `queue_notify_off` times the multiplier of the
+//! capability.
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 4.1.4 Virtio Structure PCI Capabilities](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use acpi::PhysicalMapping;
+
+use crate::{
+    dev_warn,
+    device::{
+        acpi::NoccioloAcpiHandler,
+        pci::{ConfigurationSpaceMechanism, PciAddress, PciBaseAddressType, PciCapabilityId, PciCommand, PciConfigurationSpace},
+        registry::{self, DeviceId, Resource},
+        DeviceError,
+    },
+    meta::memory_map::{self, RegionKind},
+};
+
+use super::{Virtqueue, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED};
+
+const CAPABILITY_COMMON: u8 = 1;
+const CAPABILITY_NOTIFY: u8 = 2;
+const CAPABILITY_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+const COMMON_SIZE: usize = 0x38;
+
+/// The device accepted the features, set by the driver and checked after.
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+
+/// `VIRTIO_F_VERSION_1`, bit 32 of the features, without which the device
+/// only offers the legacy interface.
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+const RESET_POLL_LIMIT: usize = 1_000_000;
+
+/// A structure in a BAR, as described by its capability.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    bar: usize,
+    offset: u32,
+    length: u32,
+}
+
+pub struct ModernTransport {
+    common: PhysicalMapping<NoccioloAcpiHandler, u8>,
+    notify: PhysicalMapping<NoccioloAcpiHandler, u8>,
+    notify_multiplier: u32,
+
+    /// Absent for device types without configuration.
+    config: Option<PhysicalMapping<NoccioloAcpiHandler, u8>>,
+
+    /// The `queue_notify_off` of the queues that are set up, by index.
+    notify_offsets: Vec<(u16, u16)>,
+}
+
+impl ModernTransport {
+    /// Find and map the structures, claiming their BARs, and reset the
+    /// device.
+    pub fn new(device: DeviceId, address: PciAddress) -> Result<Self, DeviceError> {
+        let pci = PciConfigurationSpace;
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+        for capability in pci.capabilities(address).filter(|capability| capability.id == PciCapabilityId::VENDOR_SPECIFIC) {
+            let offset = capability.offset as u16;
+            let location = Location {
+                bar: pci.read_dword(address, offset + 4) as u8 as usize,
+                offset: pci.read_dword(address, offset + 8),
+                length: pci.read_dword(address, offset + 12),
+            };
+
+            // Devices may offer a structure more than once, the first being
+            // the preferred one.
+            match (pci.read_dword(address, offset) >> 24) as u8 {
+                CAPABILITY_COMMON if common.is_none() => common = Some(location),
+                CAPABILITY_NOTIFY if notify.is_none() => notify = Some((location, pci.read_dword(address, offset + 16))),
+                CAPABILITY_DEVICE if config.is_none() => config = Some(location),
+                _ => (),
+            }
+        }
+
+        let (Some(common), Some((notify, notify_multiplier))) = (common, notify) else {
+            return Err(DeviceError::unsupported("no modern interface"));
+        };
+        if (common.length as usize) < COMMON_SIZE {
+            return Err(DeviceError::unsupported("common configuration too small"));
+        }
+
+        let mut mapper = Mapper { device, address, claimed: Vec::new() };
+        let mut transport = Self {
+            common: mapper.map(common)?,
+            notify: mapper.map(notify)?,
+            notify_multiplier,
+            config: config.map(|config| mapper.map(config)).transpose()?,
+            notify_offsets: Vec::new(),
+        };
+
+        pci.write_command(address, pci.command(address) | PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+
+        transport.write_u8(COMMON_DEVICE_STATUS, 0);
+        if !(0..RESET_POLL_LIMIT).any(|_| transport.read_u8(COMMON_DEVICE_STATUS) == 0) {
+            return Err(DeviceError::unsupported("device doesn't reset"));
+        }
+        transport.write_u8(COMMON_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    /// Accept the given features of the lower 32 bits and `VERSION_1`, which
+    /// the device must then confirm. The device is failed when it doesn't.
+    pub fn negotiate(&mut self, supported: u32) -> Result<u32, DeviceError> {
+        self.write_u32(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let features = self.read_u32(COMMON_DEVICE_FEATURE) & supported;
+        self.write_u32(COMMON_DEVICE_FEATURE_SELECT, 1);
+        if self.read_u32(COMMON_DEVICE_FEATURE) & FEATURE_VERSION_1 == 0 {
+            self.fail();
+            return Err(DeviceError::unsupported("no VERSION_1 feature"));
+        }
+
+        self.write_u32(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.write_u32(COMMON_DRIVER_FEATURE, features);
+        self.write_u32(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.write_u32(COMMON_DRIVER_FEATURE, FEATURE_VERSION_1);
+
+        let status = self.read_u8(COMMON_DEVICE_STATUS);
+        self.write_u8(COMMON_DEVICE_STATUS, status | STATUS_FEATURES_OK);
+        if self.read_u8(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(DeviceError::unsupported("features refused"));
+        }
+        Ok(features)
+    }
+
+    pub fn setup_queue(&mut self, index: u16) -> Result<Virtqueue, DeviceError> {
+        self.write_u16(COMMON_QUEUE_SELECT, index);
+        let size = self.read_u16(COMMON_QUEUE_SIZE);
+        if size == 0 {
+            return Err(DeviceError::unsupported("queue doesn't exist"));
+        }
+
+        let queue = Virtqueue::new(index, size).ok_or(DeviceError::unsupported("out of memory for the queue"))?;
+        self.write_u64(COMMON_QUEUE_DESC, queue.physical_address());
+        self.write_u64(COMMON_QUEUE_DRIVER, queue.available_address());
+        self.write_u64(COMMON_QUEUE_DEVICE, queue.used_address());
+        self.notify_offsets.push((index, self.read_u16(COMMON_QUEUE_NOTIFY_OFF)));
+        self.write_u16(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+
+    pub fn finish(&mut self) {
+        let status = self.read_u8(COMMON_DEVICE_STATUS);
+        self.write_u8(COMMON_DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&mut self) {
+        let status = self.read_u8(COMMON_DEVICE_STATUS);
+        self.write_u8(COMMON_DEVICE_STATUS, status | STATUS_FAILED);
+    }
+
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        let Some(&(_, notify_off)) = self.notify_offsets.iter().find(|(index, _)| *index == queue.index()) else {
+            return;
+        };
+
+        let offset = notify_off as usize * self.notify_multiplier as usize;
+        if offset + 2 <= self.notify.region_length() {
+            unsafe { ptr::write_volatile(self.notify.virtual_start().as_ptr().wrapping_add(offset) as *mut u16, queue.index()) };
+        }
+    }
+
+    /// Reads beyond the structure read as zero.
+    pub fn read_config_u32(&mut self, offset: u16) -> u32 {
+        match &self.config {
+            Some(config) if offset as usize + 4 <= config.region_length() => unsafe {
+                ptr::read_volatile(config.virtual_start().as_ptr().wrapping_add(offset as usize) as *const u32)
+            },
+            _ => 0,
+        }
+    }
+
+    fn register(&self, offset: usize) -> *mut u8 {
+        self.common.virtual_start().as_ptr().wrapping_add(offset)
+    }
+
+    fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.register(offset)) }
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.register(offset) as *const u16) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.register(offset) as *const u32) }
+    }
+
+    fn write_u8(&mut self, offset: usize, value: u8) {
+        unsafe { ptr::write_volatile(self.register(offset), value) }
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        unsafe { ptr::write_volatile(self.register(offset) as *mut u16, value) }
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.register(offset) as *mut u32, value) }
+    }
+
+    /// The 64-bit registers are written as two halves, the lower one first.
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.write_u32(offset, value as u32);
+        self.write_u32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// Maps the structures, claiming every BAR the first time it's used.
+struct Mapper {
+    device: DeviceId,
+    address: PciAddress,
+    claimed: Vec<usize>,
+}
+
+impl Mapper {
+    fn map(&mut self, location: Location) -> Result<PhysicalMapping<NoccioloAcpiHandler, u8>, DeviceError> {
+        let bar = PciConfigurationSpace.bar(self.address, location.bar)
+            .filter(|bar| bar.kind == PciBaseAddressType::MemorySpace)
+            .filter(|bar| location.offset as u64 + location.length as u64 <= bar.size)
+            .ok_or(DeviceError::unsupported("structure outside its BAR"))?;
+
+        if !self.claimed.contains(&location.bar) {
+            if let Err(owner) = registry::claim(self.device, Resource::memory(bar.address, bar.size)) {
+                dev_warn!(self.device, "Registers {bar} are already claimed by {}", owner.name());
+                return Err(DeviceError::unsupported("registers claimed"));
+            }
+            memory_map::register(bar.address, bar.address + bar.size, RegionKind::Mmio, "virtio registers");
+            self.claimed.push(location.bar);
+        }
+
+        let start = bar.address as usize + location.offset as usize;
+        unsafe { NoccioloAcpiHandler.try_map_physical_region::<u8>(start, location.length as usize) }
+            .map_err(|e| {
+                dev_warn!(self.device, "Failed to map the structure at {start:#x}: {e:?}");
+                DeviceError::unsupported("registers can't be mapped")
+            })
+    }
+}
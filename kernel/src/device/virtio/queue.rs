@@ -0,0 +1,247 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A split virtqueue in the legacy layout: the descriptor table, the ring of
+//! available descriptors, and (on the next page) the ring of used ones.
+//!
+//! Every descriptor points to its own buffer of [`BUFFER_SIZE`] bytes, which
+//! the queue allocates along with the rings, so the drivers copy their data
+//! instead of lending their memory to the device. Only the first
+//! [`MAX_BUFFERS`] descriptors are used, even when the device's queue is
+//! larger.
+//!
+//! Requests that take more than one buffer, like those of the block device,
+//! are pushed as a chain of descriptors with [`Virtqueue::push_chain`]: the
+//! buffers the device reads, followed by those it writes.
+
+use alloc::vec::Vec;
+use core::{
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use crate::arch::memory::{self, DmaRegion};
+
+pub const BUFFER_SIZE: usize = 256;
+const MAX_BUFFERS: u16 = 32;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_FLAG_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_FLAG_WRITE: u16 = 1 << 1;
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    rings: DmaRegion,
+    buffers: DmaRegion,
+
+    /// The offset of the used ring in [`Self::rings`].
+    used_offset: u64,
+
+    /// The descriptors that aren't in use by the device.
+    free: Vec<u16>,
+    next_available: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16) -> Option<Self> {
+        let available_end = DESCRIPTOR_SIZE * size as u64 + 6 + 2 * size as u64;
+        let used_offset = available_end.next_multiple_of(4096);
+        let used_size = 6 + 8 * size as u64;
+
+        let buffers = size.min(MAX_BUFFERS);
+        Some(Self {
+            index,
+            size,
+            rings: memory::allocate_dma(used_offset + used_size)?,
+            buffers: memory::allocate_dma(buffers as u64 * BUFFER_SIZE as u64)?,
+            used_offset,
+            free: (0..buffers).rev().collect(),
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// The address of the descriptor table, at the start of the rings.
+    pub fn physical_address(&self) -> u64 {
+        self.rings.physical.as_u64()
+    }
+
+    pub fn available_address(&self) -> u64 {
+        self.physical_address() + DESCRIPTOR_SIZE * self.size as u64
+    }
+
+    pub fn used_address(&self) -> u64 {
+        self.physical_address() + self.used_offset
+    }
+
+    /// Hand the device a buffer to read, with the data cut off at
+    /// [`BUFFER_SIZE`] bytes. Returns `false` when all buffers are in use.
+    pub fn push_readable(&mut self, data: &[u8]) -> bool {
+        let Some(descriptor) = self.free.pop() else {
+            return false;
+        };
+
+        let length = data.len().min(BUFFER_SIZE);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.buffer(descriptor), length) };
+        self.make_available(descriptor, length as u32, 0);
+        true
+    }
+
+    /// Hand the device all free buffers to write into.
+    pub fn push_writable(&mut self) {
+        while let Some(descriptor) = self.free.pop() {
+            self.make_available(descriptor, BUFFER_SIZE as u32, DESCRIPTOR_FLAG_WRITE);
+        }
+    }
+
+    /// Take back a buffer the device is done with, passing what it wrote (if
+    /// anything) to the closure. Returns `false` when there is none.
+    pub fn pop_used(&mut self, f: impl FnOnce(&[u8])) -> bool {
+        if self.read_used_u16(2) == self.last_used {
+            return false;
+        }
+        fence(Ordering::SeqCst);
+
+        let element = 4 + 8 * (self.last_used % self.size) as u64;
+        let descriptor = self.read_used_u32(element) as u16;
+        let length = (self.read_used_u32(element + 4) as usize).min(BUFFER_SIZE);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        f(unsafe { core::slice::from_raw_parts(self.buffer(descriptor), length) });
+        self.free.push(descriptor);
+        true
+    }
+
+    /// Hand the device a chain of buffers: the segments it reads, followed by
+    /// buffers of the given lengths for it to write. A segment longer than
+    /// [`BUFFER_SIZE`] is split over as many buffers as it takes. Returns
+    /// `false`, without pushing anything, when there aren't enough free
+    /// buffers.
+    pub fn push_chain(&mut self, readable: &[&[u8]], writable: &[usize]) -> bool {
+        let needed = readable.iter().map(|segment| segment.len())
+            .chain(writable.iter().copied())
+            .map(|length| length.div_ceil(BUFFER_SIZE).max(1))
+            .sum::<usize>();
+        if needed > self.free.len() {
+            return false;
+        }
+
+        let descriptors: Vec<u16> = (0..needed).filter_map(|_| self.free.pop()).collect();
+        let mut position = 0;
+        for segment in readable {
+            for start in (0..segment.len().max(1)).step_by(BUFFER_SIZE) {
+                let chunk = &segment[start..(start + BUFFER_SIZE).min(segment.len())];
+                let descriptor = self.link(&descriptors, position, chunk.len(), 0);
+                unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), self.buffer(descriptor), chunk.len()) };
+                position += 1;
+            }
+        }
+
+        for &length in writable {
+            for start in (0..length.max(1)).step_by(BUFFER_SIZE) {
+                self.link(&descriptors, position, (length - start).min(BUFFER_SIZE), DESCRIPTOR_FLAG_WRITE);
+                position += 1;
+            }
+        }
+
+        self.publish(descriptors[0]);
+        true
+    }
+
+    /// Take back a chain the device is done with, passing the bytes of the
+    /// buffers it could write, in order, to the closure. Returns `false` when
+    /// there is none.
+    pub fn pop_used_chain(&mut self, f: impl FnOnce(&[u8])) -> bool {
+        if self.read_used_u16(2) == self.last_used {
+            return false;
+        }
+        fence(Ordering::SeqCst);
+
+        let element = 4 + 8 * (self.last_used % self.size) as u64;
+        let mut descriptor = self.read_used_u32(element) as u16;
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut written = Vec::new();
+        loop {
+            let (length, flags, next) = self.read_descriptor(descriptor);
+            if flags & DESCRIPTOR_FLAG_WRITE != 0 {
+                written.extend_from_slice(unsafe { core::slice::from_raw_parts(self.buffer(descriptor), length) });
+            }
+
+            self.free.push(descriptor);
+            if flags & DESCRIPTOR_FLAG_NEXT == 0 {
+                break;
+            }
+            descriptor = next;
+        }
+
+        f(&written);
+        true
+    }
+
+    fn make_available(&mut self, descriptor: u16, length: u32, flags: u16) {
+        self.write_descriptor(descriptor, length, flags, 0);
+        self.publish(descriptor);
+    }
+
+    /// Write the descriptor at the position in the chain, pointing it to the
+    /// next one, if any.
+    fn link(&mut self, chain: &[u16], position: usize, length: usize, flags: u16) -> u16 {
+        let descriptor = chain[position];
+        match chain.get(position + 1) {
+            Some(&next) => self.write_descriptor(descriptor, length as u32, flags | DESCRIPTOR_FLAG_NEXT, next),
+            None => self.write_descriptor(descriptor, length as u32, flags, 0),
+        }
+        descriptor
+    }
+
+    fn write_descriptor(&mut self, descriptor: u16, length: u32, flags: u16, next: u16) {
+        let address = self.buffers.physical.as_u64() + descriptor as u64 * BUFFER_SIZE as u64;
+        let entry = self.rings.virt.as_u64() + descriptor as u64 * DESCRIPTOR_SIZE;
+        unsafe {
+            ptr::write_volatile(entry as *mut u64, address);
+            ptr::write_volatile((entry + 8) as *mut u32, length);
+            ptr::write_volatile((entry + 12) as *mut u16, flags);
+            ptr::write_volatile((entry + 14) as *mut u16, next);
+        }
+    }
+
+    /// The length, flags and next descriptor of a descriptor.
+    fn read_descriptor(&self, descriptor: u16) -> (usize, u16, u16) {
+        let entry = self.rings.virt.as_u64() + descriptor as u64 * DESCRIPTOR_SIZE;
+        unsafe {
+            let length = ptr::read_volatile((entry + 8) as *const u32) as usize;
+            (length.min(BUFFER_SIZE), ptr::read_volatile((entry + 12) as *const u16), ptr::read_volatile((entry + 14) as *const u16))
+        }
+    }
+
+    /// Put the head of a chain in the available ring.
+    fn publish(&mut self, descriptor: u16) {
+        let available = self.rings.virt.as_u64() + DESCRIPTOR_SIZE * self.size as u64;
+        let slot = available + 4 + 2 * (self.next_available % self.size) as u64;
+        unsafe { ptr::write_volatile(slot as *mut u16, descriptor) };
+
+        // The device must see the descriptor before the index that publishes it.
+        fence(Ordering::SeqCst);
+        self.next_available = self.next_available.wrapping_add(1);
+        unsafe { ptr::write_volatile((available + 2) as *mut u16, self.next_available) };
+    }
+
+    fn buffer(&self, descriptor: u16) -> *mut u8 {
+        (self.buffers.virt.as_u64() + descriptor as u64 * BUFFER_SIZE as u64) as *mut u8
+    }
+
+    fn read_used_u16(&self, offset: u64) -> u16 {
+        unsafe { ptr::read_volatile((self.rings.virt.as_u64() + self.used_offset + offset) as *const u16) }
+    }
+
+    fn read_used_u32(&self, offset: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.rings.virt.as_u64() + self.used_offset + offset) as *const u32) }
+    }
+}
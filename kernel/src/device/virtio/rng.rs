@@ -0,0 +1,116 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The virtio entropy device (`-device virtio-rng-pci`), which passes random
+//! bytes of the host to the entropy pool (see
+//! [`entropy`](crate::crypto::entropy)). It has a single
+//! queue, of which every buffer comes back filled.
+//!
+//! There is no interrupt routing for PCI devices yet, so the `virtio-rng`
+//! task polls the queue.
+//!
+//! ### References:
+//! - [Virtual I/O Device (VIRTIO) Version 1.1, 5.4 Entropy Device](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html)
+
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+
+use crate::{
+    crypto::entropy::{Estimate, Source},
+    dev_info,
+    device::{
+        pci::{PciDriver, PciVendorId},
+        DeviceError,
+    },
+    sync::Spinlock,
+    task::timer,
+};
+
+use super::{Transport, Virtqueue};
+
+/// The device IDs of the transitional entropy device, and of the modern-only one.
+const DEVICE_IDS: [u16; 2] = [0x1005, 0x1044];
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub static SOURCE: Source = Source::new("virtio-rng", "The entropy device of the host", Estimate::FourBits);
+
+static RNG: Spinlock<Option<Rng>> = Spinlock::new(None);
+
+pub const DRIVER: PciDriver = PciDriver {
+    name: "virtio-rng",
+    matches: |info| info.vendor_id == PciVendorId::RED_HAT && DEVICE_IDS.contains(&info.device_id.value()),
+    probe: |device, info| {
+        let result = Transport::new(device, info).and_then(Rng::init);
+        if result.is_ok() {
+            SOURCE.register();
+            dev_info!(device, "Entropy device ready");
+        }
+
+        let result = result.map(|rng| *RNG.lock() = Some(rng));
+        Box::pin(async move { result })
+    },
+    timeout: Duration::from_millis(100),
+};
+
+struct Rng {
+    transport: Transport,
+    queue: Virtqueue,
+}
+
+impl Rng {
+    fn init(mut transport: Transport) -> Result<Self, DeviceError> {
+        transport.negotiate(0)?;
+        let queue = match transport.setup_queue(0) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        transport.finish();
+
+        let mut rng = Self { transport, queue };
+        rng.request();
+        Ok(rng)
+    }
+
+    fn request(&mut self) {
+        self.queue.push_writable();
+        self.transport.notify(&self.queue);
+    }
+
+    /// Take the bytes the device wrote, and hand the buffers back.
+    fn poll(&mut self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while self.queue.pop_used(|data| bytes.extend_from_slice(data)) {}
+        if !bytes.is_empty() {
+            self.request();
+        }
+        bytes
+    }
+}
+
+pub fn is_present() -> bool {
+    RNG.lock().is_some()
+}
+
+/// The task that polls the device.
+pub async fn run() {
+    if !is_present() {
+        return;
+    }
+
+    loop {
+        timer::sleep(POLL_INTERVAL).await;
+        let bytes = match RNG.lock().as_mut() {
+            Some(rng) => rng.poll(),
+            None => continue,
+        };
+
+        // Not while holding the device, since the pool takes its own lock.
+        if !bytes.is_empty() {
+            SOURCE.add_bytes(&bytes);
+        }
+    }
+}
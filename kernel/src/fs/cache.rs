@@ -0,0 +1,97 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A write-back cache of the sectors of a disk, so file systems can update a
+//! few bytes of a sector (e.g. a FAT entry) without a round trip to the disk
+//! every time. Nothing is durable until [`BlockCache::flush`] returns.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use crate::device::ata::{AtaDrive, AtaError, SECTOR_SIZE};
+
+/// The number of sectors kept in memory, after which the least recently used
+/// one is evicted (and written back if dirty).
+const CAPACITY: usize = 64;
+
+struct CachedSector {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+pub struct BlockCache {
+    drive: AtaDrive,
+    sectors: BTreeMap<u32, CachedSector>,
+    clock: u64,
+}
+
+impl BlockCache {
+    pub fn new(drive: AtaDrive) -> Self {
+        Self {
+            drive,
+            sectors: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn read(&mut self, lba: u32) -> Result<&[u8; SECTOR_SIZE], AtaError> {
+        Ok(&self.load(lba, true)?.data)
+    }
+
+    /// The sector for modification, which is written back later.
+    pub fn write(&mut self, lba: u32) -> Result<&mut [u8; SECTOR_SIZE], AtaError> {
+        let sector = self.load(lba, true)?;
+        sector.dirty = true;
+        Ok(&mut sector.data)
+    }
+
+    /// Overwrite the sector with zeroes, without reading it first.
+    pub fn zero(&mut self, lba: u32) -> Result<(), AtaError> {
+        let sector = self.load(lba, false)?;
+        sector.data.fill(0);
+        sector.dirty = true;
+        Ok(())
+    }
+
+    /// Write every dirty sector back to the disk, which flushes its write
+    /// cache as well.
+    pub fn flush(&mut self) -> Result<(), AtaError> {
+        for (lba, sector) in self.sectors.iter_mut().filter(|(_, sector)| sector.dirty) {
+            self.drive.write_sectors(*lba, &sector.data[..])?;
+            sector.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, lba: u32, read: bool) -> Result<&mut CachedSector, AtaError> {
+        self.clock += 1;
+
+        if !self.sectors.contains_key(&lba) {
+            if self.sectors.len() >= CAPACITY {
+                self.evict()?;
+            }
+
+            let mut data = Box::new([0; SECTOR_SIZE]);
+            if read {
+                self.drive.read_sectors(lba, &mut data[..])?;
+            }
+            self.sectors.insert(lba, CachedSector { data, dirty: false, last_used: 0 });
+        }
+
+        let sector = self.sectors.get_mut(&lba).expect("sector was just loaded");
+        sector.last_used = self.clock;
+        Ok(sector)
+    }
+
+    fn evict(&mut self) -> Result<(), AtaError> {
+        let Some((&lba, sector)) = self.sectors.iter().min_by_key(|(_, sector)| sector.last_used) else {
+            return Ok(());
+        };
+
+        if sector.dirty {
+            self.drive.write_sectors(lba, &sector.data[..])?;
+        }
+        self.sectors.remove(&lba);
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The devices as files, mounted at [`MOUNT_POINT`]: the disks of the
+//! [`block`] registry, e.g. `/dev/vda`, whose contents are the bytes of the
+//! disk. Reads and writes don't have to be aligned to the blocks, since the
+//! partial blocks are read, changed and written back, and writes are flushed
+//! before they return.
+//!
+//! Files can't be created or removed, and the disks have a fixed size.
+
+use alloc::{vec, vec::Vec};
+
+use crate::device::block::{self, BlockDevice, BlockError};
+
+use super::vfs::{DirEntry, FileSystem, FileType, Metadata, NodeId, VfsError};
+
+pub const MOUNT_POINT: &str = "/dev";
+
+const ROOT: NodeId = 0;
+
+/// The most blocks that are read or written at once.
+const CHUNK_BLOCKS: usize = 16;
+
+impl From<BlockError> for VfsError {
+    fn from(value: BlockError) -> Self {
+        match value {
+            BlockError::ReadOnly => Self::ReadOnly,
+            BlockError::OutOfRange => Self::NoSpace,
+            BlockError::InvalidLength(..) | BlockError::Timeout | BlockError::Io => Self::Io,
+        }
+    }
+}
+
+/// The disks are numbered from one, in the order of [`block::names`], which
+/// only grows.
+pub struct DevFs;
+
+impl DevFs {
+    fn with_disk<R>(&self, node: NodeId, f: impl FnOnce(&mut dyn BlockDevice) -> Result<R, VfsError>) -> Result<R, VfsError> {
+        if node == ROOT {
+            return Err(VfsError::IsADirectory);
+        }
+
+        let name = block::names().into_iter().nth(node as usize - 1).ok_or(VfsError::NotFound)?;
+        block::with_disk(&name, f).unwrap_or(Err(VfsError::NotFound))
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup(&mut self, directory: NodeId, name: &str) -> Result<NodeId, VfsError> {
+        if directory != ROOT {
+            return Err(VfsError::NotADirectory);
+        }
+
+        block::names().iter()
+            .position(|disk| disk == name)
+            .map(|index| index as NodeId + 1)
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn stat(&mut self, node: NodeId) -> Result<Metadata, VfsError> {
+        if node == ROOT {
+            return Ok(Metadata { node, file_type: FileType::Directory, size: block::names().len() as u64 });
+        }
+
+        let size = self.with_disk(node, |disk| Ok(disk_size(disk)))?;
+        Ok(Metadata { node, file_type: FileType::File, size })
+    }
+
+    fn read(&mut self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.with_disk(node, |disk| {
+            let count = disk_size(disk).saturating_sub(offset).min(buffer.len() as u64) as usize;
+            let block_size = disk.block_size();
+            let mut chunk = vec![0; CHUNK_BLOCKS * block_size];
+
+            let mut done = 0;
+            while done < count {
+                let position = offset + done as u64;
+                let within = (position % block_size as u64) as usize;
+                let length = (chunk.len() - within).min(count - done);
+                let chunk = &mut chunk[..(within + length).next_multiple_of(block_size)];
+
+                disk.read_blocks(position / block_size as u64, chunk)?;
+                buffer[done..done + length].copy_from_slice(&chunk[within..within + length]);
+                done += length;
+            }
+            Ok(count)
+        })
+    }
+
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.with_disk(node, |disk| {
+            if offset.checked_add(data.len() as u64).is_none_or(|end| end > disk_size(disk)) {
+                return Err(VfsError::NoSpace);
+            }
+
+            let block_size = disk.block_size();
+            let mut chunk = vec![0; CHUNK_BLOCKS * block_size];
+
+            let mut done = 0;
+            while done < data.len() {
+                let position = offset + done as u64;
+                let lba = position / block_size as u64;
+                let within = (position % block_size as u64) as usize;
+                let length = (chunk.len() - within).min(data.len() - done);
+                let chunk = &mut chunk[..(within + length).next_multiple_of(block_size)];
+
+                // Keep the rest of the partial blocks at the start and end.
+                if within != 0 || (within + length) % block_size != 0 {
+                    disk.read_blocks(lba, chunk)?;
+                }
+                chunk[within..within + length].copy_from_slice(&data[done..done + length]);
+                disk.write_blocks(lba, chunk)?;
+                done += length;
+            }
+
+            disk.flush()?;
+            Ok(data.len())
+        })
+    }
+
+    fn truncate(&mut self, _: NodeId, _: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn read_dir(&mut self, directory: NodeId) -> Result<Vec<DirEntry>, VfsError> {
+        if directory != ROOT {
+            return Err(VfsError::NotADirectory);
+        }
+
+        Ok(block::names()
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| DirEntry { name, node: index as NodeId + 1, file_type: FileType::File })
+            .collect())
+    }
+
+    fn create(&mut self, _: NodeId, _: &str, _: FileType) -> Result<NodeId, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn remove(&mut self, _: NodeId, _: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+fn disk_size(disk: &dyn BlockDevice) -> u64 {
+    disk.block_count() * disk.block_size() as u64
+}
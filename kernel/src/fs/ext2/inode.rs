@@ -0,0 +1,79 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The inodes, which hold everything about a file but its name: the type, the
+//! size and where its blocks are. The first 12 blocks are listed in the inode
+//! itself, the others in blocks of pointers:
+//!
+//! | Pointer      | Blocks of the file                                  |
+//! |--------------|-----------------------------------------------------|
+//! | 0 to 11      | The first 12, directly                              |
+//! | 12           | The next `n`, through a block of `n` pointers       |
+//! | 13           | The next `n * n`, through two levels of those       |
+//! | 14           | The next `n * n * n`, through three levels          |
+//!
+//! A pointer of zero is a hole, which reads as zeroes.
+
+use super::{u16_at, u32_at};
+
+/// The inode of the root directory.
+pub const ROOT: u32 = 2;
+
+pub const DIRECT_POINTERS: usize = 12;
+const POINTERS: usize = 15;
+
+/// The longest symbolic link that is stored in the pointers instead of a
+/// block.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub mode: u16,
+    pub size: u64,
+
+    /// The number of 512-byte sectors of the blocks, including the blocks of
+    /// pointers.
+    pub sectors: u32,
+    pub pointers: [u32; POINTERS],
+}
+
+impl Inode {
+    pub fn parse(data: &[u8]) -> Self {
+        let mode = u16_at(data, 0);
+
+        // The upper half of the size shares its field with the ACL of a
+        // directory.
+        let size_high = if mode & MODE_TYPE_MASK == MODE_REGULAR { u32_at(data, 108) } else { 0 };
+
+        Self {
+            mode,
+            size: (size_high as u64) << 32 | u32_at(data, 4) as u64,
+            sectors: u32_at(data, 28),
+            pointers: core::array::from_fn(|index| u32_at(data, 40 + index * 4)),
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    /// Whether the target of the symbolic link is stored in the pointers
+    /// themselves, which is done when it's short enough.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK && self.size <= FAST_SYMLINK_MAX && self.sectors == 0
+    }
+
+    /// The pointers as bytes, i.e. the target of a fast symbolic link.
+    pub fn inline_data(&self) -> [u8; POINTERS * 4] {
+        let mut data = [0; POINTERS * 4];
+        for (bytes, pointer) in data.chunks_exact_mut(4).zip(self.pointers) {
+            bytes.copy_from_slice(&pointer.to_le_bytes());
+        }
+        data
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A read-only ext2 file system on an ATA disk, such as the image made by
+//! `cargo run mkext2 <directory>`, which is mounted at [`MOUNT_POINT`].
+//!
+//! The volume is split in block groups, each with a table of inodes. An inode
+//! is found by its number through the block group descriptors, and a path by
+//! looking up the components in the directories, starting at the root inode:
+//!
+//! | Structure                  | Where                                             |
+//! |----------------------------|---------------------------------------------------|
+//! | [`Superblock`]             | Byte 1024, whatever the block size                |
+//! | Block group descriptors    | The block after the superblock, 32 bytes each     |
+//! | [`Inode`]                  | The inode table of its group, see the descriptor  |
+//! | Directory entries          | The blocks of the directory, as a linked list     |
+//!
+//! Only the features of a plain `mke2fs -t ext2` are understood, so volumes
+//! with incompatible features (e.g. the extents of ext4) aren't mounted.
+//! Symbolic links read as their target, and aren't followed.
+//!
+//! ### References:
+//! - [The Second Extended File System](https://www.nongnu.org/ext2-doc/ext2.html)
+//! - [OSDev Wiki: Ext2](https://wiki.osdev.org/Ext2)
+
+mod inode;
+mod superblock;
+
+use alloc::{string::String, vec, vec::Vec};
+
+use log::info;
+
+use crate::device::ata::{AtaDrive, AtaDrivePosition, AtaError, SECTOR_SIZE};
+
+use super::{
+    cache::BlockCache,
+    vfs::{DirEntry, FileSystem, FileType, Metadata, NodeId, VfsError},
+};
+
+use self::{inode::Inode, superblock::Superblock};
+
+/// Where the first ext2 volume is mounted at boot.
+pub const MOUNT_POINT: &str = "/mnt";
+
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+const DIRECTORY_ENTRY_HEADER: usize = 8;
+const FILE_TYPE_DIRECTORY: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    Ata(AtaError),
+
+    /// The disk doesn't contain an ext2 file system.
+    NotExt2,
+
+    /// The file system uses features that change its layout, which are given.
+    UnsupportedFeatures(u32),
+
+    /// A structure points outside the volume, or doesn't make sense.
+    Corrupted,
+}
+
+impl From<AtaError> for Ext2Error {
+    fn from(value: AtaError) -> Self {
+        Self::Ata(value)
+    }
+}
+
+impl From<Ext2Error> for VfsError {
+    fn from(_: Ext2Error) -> Self {
+        Self::Io
+    }
+}
+
+pub struct Ext2Volume {
+    cache: BlockCache,
+    superblock: Superblock,
+}
+
+impl Ext2Volume {
+    /// Mount the first ATA disk that contains an ext2 file system.
+    pub fn mount_first() -> Result<Self, Ext2Error> {
+        AtaDrivePosition::ALL.into_iter()
+            .filter_map(|position| AtaDrive::identify(position).ok())
+            .find_map(|drive| Self::mount(drive).ok())
+            .ok_or(Ext2Error::NotExt2)
+    }
+
+    pub fn mount(drive: AtaDrive) -> Result<Self, Ext2Error> {
+        let mut cache = BlockCache::new(drive);
+
+        let mut data = [0; superblock::SIZE];
+        for (index, sector) in data.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            sector.copy_from_slice(cache.read((superblock::OFFSET / SECTOR_SIZE + index) as u32)?);
+        }
+
+        let superblock = Superblock::parse(&data)?;
+        info!("[ext2] Volume `{}` with {} blocks of {} bytes in {} groups",
+            superblock.volume_name, superblock.blocks_count, superblock.block_size, superblock.group_count());
+        Ok(Self { cache, superblock })
+    }
+
+    /// Read from the disk at the byte offset, through the cache.
+    fn read_bytes(&mut self, mut offset: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let lba = u32::try_from(offset / SECTOR_SIZE as u64).map_err(|_| Ext2Error::Corrupted)?;
+            let start = (offset % SECTOR_SIZE as u64) as usize;
+            let count = (SECTOR_SIZE - start).min(buffer.len() - done);
+
+            buffer[done..done + count].copy_from_slice(&self.cache.read(lba)?[start..start + count]);
+            done += count;
+            offset += count as u64;
+        }
+        Ok(())
+    }
+
+    fn read_u32(&mut self, offset: u64) -> Result<u32, Ext2Error> {
+        let mut bytes = [0; 4];
+        self.read_bytes(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn block_offset(&self, block: u32) -> Result<u64, Ext2Error> {
+        if block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok(block as u64 * self.superblock.block_size as u64)
+    }
+
+    fn read_inode(&mut self, number: u32) -> Result<Inode, Ext2Error> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        let index = number - 1;
+        let group = index / self.superblock.inodes_per_group;
+        let descriptor = self.block_offset(self.superblock.first_data_block + 1)? + group as u64 * GROUP_DESCRIPTOR_SIZE;
+        let table = self.read_u32(descriptor + 8)?;
+
+        let size = self.superblock.inode_size as u64;
+        let offset = self.block_offset(table)? + (index % self.superblock.inodes_per_group) as u64 * size;
+        let mut data = vec![0; size as usize];
+        self.read_bytes(offset, &mut data)?;
+        Ok(Inode::parse(&data))
+    }
+
+    /// The block on the disk of the block with the given index in the file,
+    /// or zero for a hole.
+    fn file_block(&mut self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        let per_block = self.superblock.block_size as u64 / 4;
+        if index < inode::DIRECT_POINTERS as u64 {
+            return Ok(inode.pointers[index as usize]);
+        }
+
+        // Find the level of indirection, and the index within it.
+        let mut index = index - inode::DIRECT_POINTERS as u64;
+        let mut span = per_block;
+        let mut level = 0;
+        while index >= span {
+            index -= span;
+            span *= per_block;
+            level += 1;
+            if level == 3 {
+                return Err(Ext2Error::Corrupted);
+            }
+        }
+
+        let mut block = inode.pointers[inode::DIRECT_POINTERS + level];
+        while block != 0 {
+            span /= per_block;
+            block = self.read_u32(self.block_offset(block)? + (index / span) * 4)?;
+            index %= span;
+            if span == 1 {
+                break;
+            }
+        }
+        Ok(block)
+    }
+
+    fn read_file(&mut self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        let count = (inode.size.saturating_sub(offset) as usize).min(buffer.len());
+        if inode.is_fast_symlink() {
+            let data = inode.inline_data();
+            buffer[..count].copy_from_slice(&data[offset as usize..offset as usize + count]);
+            return Ok(count);
+        }
+
+        let block_size = self.superblock.block_size as u64;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let start = position % block_size;
+            let length = ((block_size - start) as usize).min(count - done);
+            let target = &mut buffer[done..done + length];
+
+            match self.file_block(inode, position / block_size)? {
+                0 => target.fill(0),
+                block => {
+                    let offset = self.block_offset(block)? + start;
+                    self.read_bytes(offset, target)?;
+                }
+            }
+            done += length;
+        }
+        Ok(count)
+    }
+
+    /// The entries of the directory, without `.` and `..`.
+    fn read_directory(&mut self, inode: &Inode) -> Result<Vec<(String, u32, FileType)>, Ext2Error> {
+        let mut data = vec![0; inode.size as usize];
+        self.read_file(inode, 0, &mut data)?;
+
+        let has_file_type = self.superblock.has_incompat(superblock::INCOMPAT_FILETYPE);
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + DIRECTORY_ENTRY_HEADER <= data.len() {
+            let number = u32_at(&data, offset);
+            let record_length = u16_at(&data, offset + 4) as usize;
+            let name_length = data[offset + 6] as usize;
+            let Some(name) = data.get(offset + DIRECTORY_ENTRY_HEADER..offset + DIRECTORY_ENTRY_HEADER + name_length)
+                .filter(|_| record_length >= DIRECTORY_ENTRY_HEADER) else {
+                return Err(Ext2Error::Corrupted);
+            };
+
+            let name = String::from_utf8_lossy(name);
+            if number != 0 && name != "." && name != ".." {
+                let is_dir = if has_file_type {
+                    data[offset + 7] == FILE_TYPE_DIRECTORY
+                } else {
+                    self.read_inode(number)?.is_dir()
+                };
+                let file_type = if is_dir { FileType::Directory } else { FileType::File };
+                entries.push((name.into_owned(), number, file_type));
+            }
+            offset += record_length;
+        }
+        Ok(entries)
+    }
+
+    fn directory(&mut self, node: NodeId) -> Result<Inode, VfsError> {
+        let inode = self.read_inode(inode_number(node)?)?;
+        if !inode.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(inode)
+    }
+}
+
+impl FileSystem for Ext2Volume {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> NodeId {
+        inode::ROOT as NodeId
+    }
+
+    fn lookup(&mut self, directory: NodeId, name: &str) -> Result<NodeId, VfsError> {
+        let inode = self.directory(directory)?;
+        self.read_directory(&inode)?
+            .into_iter()
+            .find(|(entry, ..)| entry == name)
+            .map(|(_, number, _)| number as NodeId)
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn stat(&mut self, node: NodeId) -> Result<Metadata, VfsError> {
+        let inode = self.read_inode(inode_number(node)?)?;
+        if inode.is_dir() {
+            let size = self.read_directory(&inode)?.len() as u64;
+            return Ok(Metadata { node, file_type: FileType::Directory, size });
+        }
+        Ok(Metadata { node, file_type: FileType::File, size: inode.size })
+    }
+
+    fn read(&mut self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let inode = self.read_inode(inode_number(node)?)?;
+        if inode.is_dir() {
+            return Err(VfsError::IsADirectory);
+        }
+        Ok(self.read_file(&inode, offset, buffer)?)
+    }
+
+    fn write(&mut self, _: NodeId, _: u64, _: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, _: NodeId, _: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn read_dir(&mut self, directory: NodeId) -> Result<Vec<DirEntry>, VfsError> {
+        let inode = self.directory(directory)?;
+        Ok(self.read_directory(&inode)?
+            .into_iter()
+            .map(|(name, number, file_type)| DirEntry { name, node: number as NodeId, file_type })
+            .collect())
+    }
+
+    fn create(&mut self, _: NodeId, _: &str, _: FileType) -> Result<NodeId, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&mut self, _: NodeId, _: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+fn inode_number(node: NodeId) -> Result<u32, VfsError> {
+    u32::try_from(node).map_err(|_| VfsError::NotFound)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
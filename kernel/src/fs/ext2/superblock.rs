@@ -0,0 +1,86 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The superblock, which describes the layout of the whole volume, and is
+//! always 1024 bytes at byte offset 1024, regardless of the block size.
+
+use alloc::string::String;
+
+use super::{u16_at, u32_at, Ext2Error};
+
+pub const OFFSET: usize = 1024;
+pub const SIZE: usize = 1024;
+
+const MAGIC: u16 = 0xEF53;
+
+/// Revision 0 has fixed inode sizes and no feature flags.
+const REVISION_DYNAMIC: u32 = 1;
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+/// Directory entries record the type of the inode.
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+
+/// The block groups are packed together, which only moves their metadata.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+
+/// The incompatible features that don't change how a volume is read.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub inode_size: u16,
+    pub feature_incompat: u32,
+    pub volume_name: String,
+}
+
+impl Superblock {
+    pub fn parse(data: &[u8]) -> Result<Self, Ext2Error> {
+        if u16_at(data, 56) != MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let log_block_size = u32_at(data, 24);
+        let (inode_size, feature_incompat) = match u32_at(data, 76) {
+            revision if revision >= REVISION_DYNAMIC => (u16_at(data, 88), u32_at(data, 96)),
+            _ => (GOOD_OLD_INODE_SIZE, 0),
+        };
+
+        let unsupported = feature_incompat & !SUPPORTED_INCOMPAT;
+        if unsupported != 0 {
+            return Err(Ext2Error::UnsupportedFeatures(unsupported));
+        }
+
+        let superblock = Self {
+            inodes_count: u32_at(data, 0),
+            blocks_count: u32_at(data, 4),
+            first_data_block: u32_at(data, 20),
+            block_size: 1024u32.checked_shl(log_block_size).ok_or(Ext2Error::Corrupted)?,
+            blocks_per_group: u32_at(data, 32),
+            inodes_per_group: u32_at(data, 40),
+            inode_size,
+            feature_incompat,
+            volume_name: String::from_utf8_lossy(&data[120..136]).trim_end_matches('\0').into(),
+        };
+
+        if superblock.block_size > 64 * 1024 || superblock.blocks_per_group == 0 || superblock.inodes_per_group == 0
+                || superblock.inode_size < GOOD_OLD_INODE_SIZE || !superblock.inode_size.is_power_of_two() {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        Ok(superblock)
+    }
+
+    pub fn group_count(&self) -> u32 {
+        self.blocks_count.saturating_sub(self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    pub fn has_incompat(&self, feature: u32) -> bool {
+        self.feature_incompat & feature != 0
+    }
+}
@@ -0,0 +1,742 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A FAT32 file system on an ATA disk, with files in the root directory only,
+//! which is enough as a durable target for e.g. crash dumps and logs.
+//!
+//! Files can be read, written, grown up front with [`FatVolume::preallocate`]
+//! (which prefers a contiguous run of clusters, so a crash dump can later be
+//! written without touching the FAT), and shrunk with [`FatVolume::truncate`].
+//! All changes go through the [`BlockCache`], so they are only durable after
+//! [`FatVolume::sync`], like `fsync`.
+//!
+//! Only short (8.3) names are supported. Long file name entries are skipped
+//! when reading the directory, and never written.
+//!
+//! The first volume is mounted in the [`vfs`](super::vfs) at [`MOUNT_POINT`],
+//! where every change is synced before the call returns. The `fat` shell
+//! command and the editor mount the volume themselves, with their own cache,
+//! so the mount may not see their changes until it evicts its copies of the
+//! sectors.
+//!
+//! ### References:
+//! - [Microsoft: FAT32 File System Specification](https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc)
+//! - [OSDev Wiki: FAT](https://wiki.osdev.org/FAT)
+
+use alloc::{string::String, vec::Vec};
+
+use crate::device::ata::{AtaDrive, AtaDrivePosition, AtaError, SECTOR_SIZE};
+
+use super::{
+    cache::BlockCache,
+    vfs::{DirEntry, FileSystem, FileType, Metadata, NodeId, VfsError},
+};
+
+/// Where the first FAT32 volume is mounted at boot.
+pub const MOUNT_POINT: &str = "/boot";
+
+/// The root directory, the only one. Files are identified by the location of
+/// their directory entry, which never moves.
+const ROOT_NODE: NodeId = 0;
+
+/// The bits of a [`NodeId`] with the index of the entry in its sector.
+const NODE_INDEX_BITS: u32 = (SECTOR_SIZE / ENTRY_SIZE).trailing_zeros();
+
+const ENTRY_SIZE: usize = 32;
+
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+/// The upper 4 bits of a FAT entry are reserved, and must be preserved.
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+const CLUSTER_FREE: u32 = 0;
+const CLUSTER_END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+/// Entries at or above this value mark the end of a chain (or a bad cluster).
+const CLUSTER_END_MINIMUM: u32 = 0x0FFF_FFF7;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Ata(AtaError),
+
+    /// The disk doesn't contain a FAT32 file system we understand.
+    NotFat32,
+    NotFound,
+    AlreadyExists,
+    InvalidName,
+    NoSpace,
+
+    /// A cluster chain is longer than the volume, i.e. it loops.
+    Corrupted,
+
+    /// Files are limited to 4 GiB by the 32-bit size in the directory entry.
+    TooLarge,
+}
+
+impl From<AtaError> for FatError {
+    fn from(value: AtaError) -> Self {
+        Self::Ata(value)
+    }
+}
+
+impl From<FatError> for VfsError {
+    fn from(value: FatError) -> Self {
+        match value {
+            FatError::NotFound => Self::NotFound,
+            FatError::AlreadyExists => Self::AlreadyExists,
+            FatError::InvalidName => Self::InvalidPath,
+            FatError::NoSpace | FatError::TooLarge => Self::NoSpace,
+            FatError::Ata(..) | FatError::NotFat32 | FatError::Corrupted => Self::Io,
+        }
+    }
+}
+
+/// Where the directory entry of a file lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryLocation {
+    lba: u32,
+    index: usize,
+}
+
+/// An open file, which is a copy of its directory entry, so it must be used
+/// with the volume it was opened on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatFile {
+    name: [u8; 11],
+    entry: EntryLocation,
+    first_cluster: u32,
+    size: u32,
+}
+
+impl FatFile {
+    pub fn name(&self) -> String {
+        display_name(&self.name)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+pub struct FatVolume {
+    cache: BlockCache,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    fat_count: u32,
+    data_start: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info: Option<u32>,
+
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+}
+
+impl FatVolume {
+    /// Mount the first ATA disk that contains a FAT32 file system.
+    pub fn mount_first() -> Result<Self, FatError> {
+        AtaDrivePosition::ALL.into_iter()
+            .filter_map(|position| AtaDrive::identify(position).ok())
+            .find_map(|drive| Self::mount(drive).ok())
+            .ok_or(FatError::NotFat32)
+    }
+
+    pub fn mount(drive: AtaDrive) -> Result<Self, FatError> {
+        let mut cache = BlockCache::new(drive);
+        let boot = cache.read(0)?;
+
+        let bytes_per_sector = u16_at(boot, 11);
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = u16_at(boot, 14) as u32;
+        let fat_count = boot[16] as u32;
+        let root_entries = u16_at(boot, 17);
+        let total_sectors = match u16_at(boot, 19) {
+            0 => u32_at(boot, 32),
+            count => count as u32,
+        };
+        let fat_sectors = u32_at(boot, 36);
+        let root_cluster = u32_at(boot, 44);
+        let fs_info = u16_at(boot, 48) as u32;
+
+        // FAT32 has no fixed root directory, and a FAT size in the extended
+        // boot record instead.
+        if boot[510..512] != [0x55, 0xAA] || bytes_per_sector as usize != SECTOR_SIZE
+                || !sectors_per_cluster.is_power_of_two() || fat_count == 0 || root_entries != 0
+                || u16_at(boot, 22) != 0 || fat_sectors == 0 {
+            return Err(FatError::NotFat32);
+        }
+
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        let cluster_count = total_sectors.checked_sub(data_start).ok_or(FatError::NotFat32)? / sectors_per_cluster;
+
+        // Fewer clusters make it a FAT12 or FAT16 file system.
+        if cluster_count < 65525 || !(2..cluster_count + 2).contains(&root_cluster) {
+            return Err(FatError::NotFat32);
+        }
+
+        let fs_info = match fs_info {
+            0 | 0xFFFF => None,
+            sector if u32_at(cache.read(sector)?, 0) == FS_INFO_LEAD_SIGNATURE => Some(sector),
+            _ => None,
+        };
+
+        Ok(Self {
+            cache,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info,
+            next_free: 2,
+        })
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    /// The files in the root directory.
+    pub fn files(&mut self) -> Result<Vec<FatFile>, FatError> {
+        let mut files = Vec::new();
+        self.walk_root(|_, file| {
+            if let Some(file) = file {
+                files.push(file);
+            }
+            false
+        })?;
+        Ok(files)
+    }
+
+    pub fn open(&mut self, name: &str) -> Result<FatFile, FatError> {
+        let name = short_name(name)?;
+        let mut found = None;
+        self.walk_root(|_, file| {
+            match file {
+                Some(file) if file.name == name => {
+                    found = Some(file);
+                    true
+                }
+                _ => false,
+            }
+        })?;
+        found.ok_or(FatError::NotFound)
+    }
+
+    /// Create an empty file in the root directory.
+    pub fn create(&mut self, name: &str) -> Result<FatFile, FatError> {
+        match self.open(name) {
+            Ok(_) => return Err(FatError::AlreadyExists),
+            Err(FatError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        let name = short_name(name)?;
+        let mut free = None;
+        self.walk_root(|location, file| {
+            if file.is_none() && location.is_some() {
+                free = location;
+                return true;
+            }
+            false
+        })?;
+
+        let entry = match free {
+            Some(entry) => entry,
+            None => {
+                // The directory is full, so it grows by a cluster.
+                let chain = self.chain(self.root_cluster)?;
+                let cluster = self.allocate(1, chain.last().copied())?[0];
+                EntryLocation { lba: self.cluster_lba(cluster), index: 0 }
+            }
+        };
+
+        let file = FatFile { name, entry, first_cluster: 0, size: 0 };
+        let sector = self.cache.write(entry.lba)?;
+        let bytes = &mut sector[entry.index * ENTRY_SIZE..][..ENTRY_SIZE];
+        bytes.fill(0);
+        bytes[..11].copy_from_slice(&name);
+        bytes[11] = ATTRIBUTE_ARCHIVE;
+        Ok(file)
+    }
+
+    /// Read from the file at the given offset, returning the number of bytes
+    /// read, which is less than the buffer at the end of the file.
+    pub fn read(&mut self, file: &FatFile, offset: u32, buffer: &mut [u8]) -> Result<usize, FatError> {
+        let length = (file.size.saturating_sub(offset) as usize).min(buffer.len());
+        let chain = self.chain(file.first_cluster)?;
+
+        let mut done = 0;
+        while done < length {
+            let position = offset as usize + done;
+            let (lba, within) = self.locate(&chain, position)?;
+            let count = (SECTOR_SIZE - within).min(length - done);
+            let sector = self.cache.read(lba)?;
+            buffer[done..done + count].copy_from_slice(&sector[within..within + count]);
+            done += count;
+        }
+        Ok(length)
+    }
+
+    /// Write to the file at the given offset, growing it when needed. A gap
+    /// between the end of the file and the offset reads back as zeroes.
+    pub fn write(&mut self, file: &mut FatFile, offset: u32, data: &[u8]) -> Result<(), FatError> {
+        let end = (offset as u64 + data.len() as u64).try_into().map_err(|_| FatError::TooLarge)?;
+        if offset > file.size {
+            self.resize(file, offset)?;
+        }
+        if end > file.size {
+            self.reserve(file, end)?;
+        }
+
+        let chain = self.chain(file.first_cluster)?;
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset as usize + done;
+            let (lba, within) = self.locate(&chain, position)?;
+            let count = (SECTOR_SIZE - within).min(data.len() - done);
+            let sector = self.cache.write(lba)?;
+            sector[within..within + count].copy_from_slice(&data[done..done + count]);
+            done += count;
+        }
+
+        if end > file.size {
+            file.size = end;
+            self.update_entry(file)?;
+        }
+        Ok(())
+    }
+
+    /// Grow the file to `size` bytes of zeroes up front (like `fallocate`),
+    /// so writing it later doesn't need to allocate. The clusters are taken
+    /// from a single contiguous run if there is one. Returns whether the whole
+    /// file is contiguous on the disk.
+    pub fn preallocate(&mut self, file: &mut FatFile, size: u32) -> Result<bool, FatError> {
+        if size <= file.size {
+            return self.is_contiguous(file);
+        }
+
+        self.resize(file, size)?;
+        self.is_contiguous(file)
+    }
+
+    /// Shrink (freeing the clusters that aren't needed anymore) or grow (with
+    /// zeroes) the file to the given size.
+    pub fn truncate(&mut self, file: &mut FatFile, size: u32) -> Result<(), FatError> {
+        if size >= file.size {
+            return self.resize(file, size);
+        }
+
+        let chain = self.chain(file.first_cluster)?;
+        let keep = size.div_ceil(self.cluster_size()) as usize;
+        for &cluster in &chain[keep..] {
+            self.set_fat_entry(cluster, CLUSTER_FREE)?;
+        }
+
+        match keep {
+            0 => file.first_cluster = 0,
+            _ => self.set_fat_entry(chain[keep - 1], CLUSTER_END_OF_CHAIN)?,
+        }
+
+        file.size = size;
+        self.update_entry(file)
+    }
+
+    /// Remove the file, freeing its clusters.
+    pub fn remove(&mut self, mut file: FatFile) -> Result<(), FatError> {
+        self.truncate(&mut file, 0)?;
+        self.cache.write(file.entry.lba)?[file.entry.index * ENTRY_SIZE] = ENTRY_DELETED;
+        Ok(())
+    }
+
+    /// Make every change durable, like `fsync`: the FAT, the directory
+    /// entries and the data are written from the cache to the disk, which is
+    /// flushed as well.
+    pub fn sync(&mut self) -> Result<(), FatError> {
+        if let Some(fs_info) = self.fs_info {
+            // We don't keep track of the free clusters, so mark the count as
+            // unknown, which makes the next mount (or fsck) recount them.
+            let next_free = self.next_free;
+            let sector = self.cache.write(fs_info)?;
+            sector[FS_INFO_FREE_COUNT..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+            sector[FS_INFO_NEXT_FREE..][..4].copy_from_slice(&next_free.to_le_bytes());
+        }
+
+        Ok(self.cache.flush()?)
+    }
+
+    /// Grow the file to `size` bytes, filling the new part with zeroes.
+    fn resize(&mut self, file: &mut FatFile, size: u32) -> Result<(), FatError> {
+        if size <= file.size {
+            return Ok(());
+        }
+
+        self.reserve(file, size)?;
+
+        // The tail of the last cluster might contain stale data.
+        let chain = self.chain(file.first_cluster)?;
+        let mut position = file.size as usize;
+        while position < size as usize && position % self.cluster_size() as usize != 0 {
+            let (lba, within) = self.locate(&chain, position)?;
+            let count = (SECTOR_SIZE - within).min(size as usize - position);
+            self.cache.write(lba)?[within..within + count].fill(0);
+            position += count;
+        }
+
+        file.size = size;
+        self.update_entry(file)
+    }
+
+    /// Make sure the chain of the file covers `size` bytes. New clusters are
+    /// zeroed.
+    fn reserve(&mut self, file: &mut FatFile, size: u32) -> Result<(), FatError> {
+        let chain = self.chain(file.first_cluster)?;
+        let needed = size.div_ceil(self.cluster_size()) as usize;
+        if needed <= chain.len() {
+            return Ok(());
+        }
+
+        let clusters = self.allocate(needed - chain.len(), chain.last().copied())?;
+        if file.first_cluster == 0 {
+            file.first_cluster = clusters[0];
+            self.update_entry(file)?;
+        }
+        Ok(())
+    }
+
+    /// Allocate `count` zeroed clusters, linked after `previous`, preferring a
+    /// contiguous run (right after `previous` if possible).
+    fn allocate(&mut self, count: usize, previous: Option<u32>) -> Result<Vec<u32>, FatError> {
+        let start = previous.map_or(self.next_free, |previous| previous + 1);
+        let clusters = match self.find_free_run(start, count)? {
+            Some(first) => (first..first + count as u32).collect(),
+            None => self.find_free(count)?,
+        };
+
+        let mut link = previous;
+        for &cluster in &clusters {
+            self.set_fat_entry(cluster, CLUSTER_END_OF_CHAIN)?;
+            if let Some(link) = link {
+                self.set_fat_entry(link, cluster)?;
+            }
+            link = Some(cluster);
+
+            let lba = self.cluster_lba(cluster);
+            for sector in 0..self.sectors_per_cluster {
+                self.cache.zero(lba + sector)?;
+            }
+        }
+
+        self.next_free = clusters.last().map_or(self.next_free, |last| last + 1);
+        Ok(clusters)
+    }
+
+    /// The first cluster of a run of `count` free clusters, searching from
+    /// `start` and wrapping around.
+    fn find_free_run(&mut self, start: u32, count: usize) -> Result<Option<u32>, FatError> {
+        let end = self.cluster_count + 2;
+        let start = if (2..end).contains(&start) { start } else { 2 };
+
+        let mut run_start = start;
+        let mut run_length = 0;
+        for cluster in (start..end).chain(2..start) {
+            // A run can't wrap around the end of the FAT.
+            if cluster == 2 {
+                run_length = 0;
+            }
+
+            if self.fat_entry(cluster)? != CLUSTER_FREE {
+                run_length = 0;
+                continue;
+            }
+
+            if run_length == 0 {
+                run_start = cluster;
+            }
+            run_length += 1;
+            if run_length == count {
+                return Ok(Some(run_start));
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_free(&mut self, count: usize) -> Result<Vec<u32>, FatError> {
+        let mut clusters = Vec::with_capacity(count);
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == CLUSTER_FREE {
+                clusters.push(cluster);
+                if clusters.len() == count {
+                    return Ok(clusters);
+                }
+            }
+        }
+        Err(FatError::NoSpace)
+    }
+
+    fn is_contiguous(&mut self, file: &FatFile) -> Result<bool, FatError> {
+        let chain = self.chain(file.first_cluster)?;
+        Ok(chain.windows(2).all(|pair| pair[1] == pair[0] + 1))
+    }
+
+    /// The clusters of the chain starting at `first`, zero meaning empty.
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while (2..self.cluster_count + 2).contains(&cluster) {
+            if chain.len() > self.cluster_count as usize {
+                return Err(FatError::Corrupted);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+
+        if cluster != 0 && cluster < CLUSTER_END_MINIMUM {
+            return Err(FatError::Corrupted);
+        }
+        Ok(chain)
+    }
+
+    /// The sector and the offset within it of a position in a file.
+    fn locate(&self, chain: &[u32], position: usize) -> Result<(u32, usize), FatError> {
+        let cluster_size = self.cluster_size() as usize;
+        let cluster = *chain.get(position / cluster_size).ok_or(FatError::Corrupted)?;
+        let within = position % cluster_size;
+        Ok((self.cluster_lba(cluster) + (within / SECTOR_SIZE) as u32, within % SECTOR_SIZE))
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FatError> {
+        let offset = cluster as usize * 4;
+        let sector = self.cache.read(self.fat_start + (offset / SECTOR_SIZE) as u32)?;
+        Ok(u32_at(sector, offset % SECTOR_SIZE) & CLUSTER_MASK)
+    }
+
+    /// Update the entry in every copy of the FAT.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let offset = cluster as usize * 4;
+        for fat in 0..self.fat_count {
+            let lba = self.fat_start + fat * self.fat_sectors + (offset / SECTOR_SIZE) as u32;
+            let bytes = &mut self.cache.write(lba)?[offset % SECTOR_SIZE..][..4];
+            let reserved = u32::from_le_bytes(bytes.try_into().unwrap()) & !CLUSTER_MASK;
+            bytes.copy_from_slice(&(reserved | (value & CLUSTER_MASK)).to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn update_entry(&mut self, file: &FatFile) -> Result<(), FatError> {
+        let sector = self.cache.write(file.entry.lba)?;
+        let bytes = &mut sector[file.entry.index * ENTRY_SIZE..][..ENTRY_SIZE];
+        bytes[20..22].copy_from_slice(&((file.first_cluster >> 16) as u16).to_le_bytes());
+        bytes[26..28].copy_from_slice(&(file.first_cluster as u16).to_le_bytes());
+        bytes[28..32].copy_from_slice(&file.size.to_le_bytes());
+        Ok(())
+    }
+
+    /// Call `visit` for every entry of the root directory, with the file if
+    /// it's a file, and the location if the entry is free. Stops when `visit`
+    /// returns `true`, or after the last entry.
+    fn walk_root(&mut self, mut visit: impl FnMut(Option<EntryLocation>, Option<FatFile>) -> bool) -> Result<(), FatError> {
+        for cluster in self.chain(self.root_cluster)? {
+            let lba = self.cluster_lba(cluster);
+            for lba in lba..lba + self.sectors_per_cluster {
+                let sector = self.cache.read(lba)?;
+                for (index, bytes) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                    let location = EntryLocation { lba, index };
+                    let attributes = bytes[11];
+                    let (free, file) = match bytes[0] {
+                        // Everything after the end marker is free as well,
+                        // so the first one is all we need.
+                        ENTRY_END => {
+                            visit(Some(location), None);
+                            return Ok(());
+                        }
+                        ENTRY_DELETED => (Some(location), None),
+                        _ if attributes == ATTRIBUTE_LONG_NAME => continue,
+                        _ if attributes & (ATTRIBUTE_VOLUME_ID | ATTRIBUTE_DIRECTORY) != 0 => continue,
+                        _ => (None, Some(FatFile {
+                            name: bytes[..11].try_into().unwrap(),
+                            entry: location,
+                            first_cluster: ((u16_at(bytes, 20) as u32) << 16) | u16_at(bytes, 26) as u32,
+                            size: u32_at(bytes, 28),
+                        })),
+                    };
+
+                    if visit(free, file) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FatFile {
+    fn node(&self) -> NodeId {
+        (self.entry.lba as NodeId) << NODE_INDEX_BITS | self.entry.index as NodeId
+    }
+}
+
+impl FatVolume {
+    /// The file of the node, which is looked up again, since the entry may
+    /// have been removed since.
+    fn file_of_node(&mut self, node: NodeId) -> Result<FatFile, VfsError> {
+        if node == ROOT_NODE {
+            return Err(VfsError::IsADirectory);
+        }
+
+        let mut found = None;
+        self.walk_root(|_, file| {
+            match file {
+                Some(file) if file.node() == node => {
+                    found = Some(file);
+                    true
+                }
+                _ => false,
+            }
+        })?;
+        found.ok_or(VfsError::NotFound)
+    }
+}
+
+impl FileSystem for FatVolume {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> NodeId {
+        ROOT_NODE
+    }
+
+    fn lookup(&mut self, directory: NodeId, name: &str) -> Result<NodeId, VfsError> {
+        if directory != ROOT_NODE {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(self.open(name)?.node())
+    }
+
+    fn stat(&mut self, node: NodeId) -> Result<Metadata, VfsError> {
+        if node == ROOT_NODE {
+            let size = self.files()?.len() as u64;
+            return Ok(Metadata { node, file_type: FileType::Directory, size });
+        }
+
+        let file = self.file_of_node(node)?;
+        Ok(Metadata { node, file_type: FileType::File, size: file.size as u64 })
+    }
+
+    fn read(&mut self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let file = self.file_of_node(node)?;
+        match u32::try_from(offset) {
+            Ok(offset) => Ok(FatVolume::read(self, &file, offset, buffer)?),
+            Err(_) => Ok(0),
+        }
+    }
+
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let mut file = self.file_of_node(node)?;
+        let offset = u32::try_from(offset).map_err(|_| VfsError::NoSpace)?;
+        FatVolume::write(self, &mut file, offset, data)?;
+        self.sync()?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, node: NodeId, size: u64) -> Result<(), VfsError> {
+        let mut file = self.file_of_node(node)?;
+        let size = u32::try_from(size).map_err(|_| VfsError::NoSpace)?;
+        FatVolume::truncate(self, &mut file, size)?;
+        Ok(self.sync()?)
+    }
+
+    fn read_dir(&mut self, directory: NodeId) -> Result<Vec<DirEntry>, VfsError> {
+        if directory != ROOT_NODE {
+            return Err(VfsError::NotADirectory);
+        }
+
+        Ok(self.files()?
+            .into_iter()
+            .map(|file| DirEntry { name: file.name(), node: file.node(), file_type: FileType::File })
+            .collect())
+    }
+
+    fn create(&mut self, directory: NodeId, name: &str, file_type: FileType) -> Result<NodeId, VfsError> {
+        if directory != ROOT_NODE {
+            return Err(VfsError::NotADirectory);
+        }
+        if file_type == FileType::Directory {
+            return Err(VfsError::Unsupported);
+        }
+
+        let node = FatVolume::create(self, name)?.node();
+        self.sync()?;
+        Ok(node)
+    }
+
+    fn remove(&mut self, directory: NodeId, name: &str) -> Result<(), VfsError> {
+        if directory != ROOT_NODE {
+            return Err(VfsError::NotADirectory);
+        }
+
+        let file = self.open(name)?;
+        FatVolume::remove(self, file)?;
+        Ok(self.sync()?)
+    }
+}
+
+/// Convert e.g. `log.txt` to the padded, upper case `LOG     TXT`.
+fn short_name(name: &str) -> Result<[u8; 11], FatError> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err(FatError::InvalidName);
+    }
+
+    let mut short = [b' '; 11];
+    for (index, byte) in base.bytes().enumerate() {
+        short[index] = valid_name_byte(byte)?;
+    }
+    for (index, byte) in extension.bytes().enumerate() {
+        short[8 + index] = valid_name_byte(byte)?;
+    }
+    Ok(short)
+}
+
+fn valid_name_byte(byte: u8) -> Result<u8, FatError> {
+    match byte.to_ascii_uppercase() {
+        byte @ (b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'~' | b'!' | b'#' | b'$' | b'%' | b'&') => Ok(byte),
+        _ => Err(FatError::InvalidName),
+    }
+}
+
+fn display_name(name: &[u8; 11]) -> String {
+    let base = core::str::from_utf8(&name[..8]).unwrap_or("?").trim_end();
+    let extension = core::str::from_utf8(&name[8..]).unwrap_or("?").trim_end();
+    if extension.is_empty() {
+        base.into()
+    } else {
+        alloc::format!("{base}.{extension}")
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,130 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The initial ramdisk, which the bootloader loads next to the kernel, and
+//! whose files are unpacked into the [`ramfs`](super::ramfs) at `/` at boot,
+//! so they're there before any disk driver. It's made from the directory given
+//! by `NOCCIOLO_INITRD` when the runner is built, e.g.
+//! `NOCCIOLO_INITRD=path/to/files cargo run uefi`.
+//!
+//! Like the initramfs of Linux, it's a cpio archive in the "newc" format: a
+//! header of ASCII hexadecimal fields per entry, followed by its path and its
+//! data, both padded to four bytes:
+//!
+//! | Offset | Field      | Use                                              |
+//! |--------|------------|--------------------------------------------------|
+//! | 0      | Magic      | `070701`                                         |
+//! | 14     | Mode       | The type (directory, file) and the permissions   |
+//! | 54     | File size  | The size of the data                             |
+//! | 94     | Name size  | The size of the path, including its NUL          |
+//!
+//! The last entry is named `TRAILER!!!`. Other types of entries (e.g. symbolic
+//! links) are skipped, and so are files larger than
+//! [`ramfs::MAX_FILE_SIZE`](super::ramfs::MAX_FILE_SIZE).
+//!
+//! ### References:
+//! - [Linux: ramfs, rootfs and initramfs](https://docs.kernel.org/filesystems/ramfs-rootfs-initramfs.html)
+//! - [Linux: initramfs buffer format](https://docs.kernel.org/driver-api/early-userspace/buffer-format.html)
+
+use alloc::format;
+
+use bootloader_api::{info::Optional, BootInfo};
+use log::{info, warn};
+
+use super::{ramfs, vfs::{self, VfsError}};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const FIELD_MODE: usize = 1;
+const FIELD_FILE_SIZE: usize = 6;
+const FIELD_NAME_SIZE: usize = 11;
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// The archive isn't in the newc format, or is cut off, at the offset.
+    Malformed(usize),
+    Vfs(VfsError),
+}
+
+impl From<VfsError> for InitrdError {
+    fn from(value: VfsError) -> Self {
+        Self::Vfs(value)
+    }
+}
+
+/// Unpack the ramdisk the bootloader loaded, if any, into the root file
+/// system.
+pub fn unpack_from(boot_info: &BootInfo) {
+    let Optional::Some(address) = boot_info.ramdisk_addr else {
+        return;
+    };
+
+    // Mapped by the bootloader, in memory it doesn't hand out.
+    let archive = unsafe { core::slice::from_raw_parts(address as *const u8, boot_info.ramdisk_len as usize) };
+    match unpack(archive) {
+        Ok((files, directories)) => info!("Unpacked {files} files and {directories} directories from the initrd ({} KiB)",
+            archive.len() / 1024),
+        Err(e) => warn!("Failed to unpack the initrd: {e:?}"),
+    }
+}
+
+/// Unpack the archive into the root file system, returning the number of
+/// files and directories.
+fn unpack(archive: &[u8]) -> Result<(usize, usize), InitrdError> {
+    let (mut files, mut directories) = (0, 0);
+    let mut offset = 0;
+    loop {
+        let header = archive.get(offset..offset + HEADER_SIZE)
+            .filter(|header| header.starts_with(MAGIC))
+            .ok_or(InitrdError::Malformed(offset))?;
+        let field = |index: usize| {
+            let start = MAGIC.len() + index * 8;
+            core::str::from_utf8(&header[start..start + 8]).ok()
+                .and_then(|text| u32::from_str_radix(text, 16).ok())
+                .ok_or(InitrdError::Malformed(offset))
+        };
+
+        let mode = field(FIELD_MODE)?;
+        let file_size = field(FIELD_FILE_SIZE)? as usize;
+        let name_size = field(FIELD_NAME_SIZE)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let end = (data_start + file_size).next_multiple_of(4);
+        let (Some(name), Some(data)) = (archive.get(name_start..name_start + name_size), archive.get(data_start..data_start + file_size)) else {
+            return Err(InitrdError::Malformed(offset));
+        };
+
+        let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name)).map_err(|_| InitrdError::Malformed(offset))?;
+        if name == TRAILER {
+            return Ok((files, directories));
+        }
+
+        let path = vfs::normalize(&format!("/{name}"))?;
+        match mode & MODE_TYPE_MASK {
+            _ if path == "/" => (),
+            MODE_DIRECTORY => {
+                match vfs::create_dir(&path) {
+                    Ok(()) | Err(VfsError::AlreadyExists) => directories += 1,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            MODE_REGULAR if data.len() as u64 > ramfs::MAX_FILE_SIZE => {
+                warn!("Skipping {path} of the initrd, which is larger than a ramfs file can be");
+            }
+            MODE_REGULAR => {
+                vfs::write(&path, data)?;
+                files += 1;
+            }
+            _ => (),
+        }
+
+        offset = end;
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The file systems: the [`vfs`] that puts them in a single tree, and the
+//! drivers behind it:
+//!
+//! | Mount point | File system                                  |
+//! |-------------|----------------------------------------------|
+//! | `/`         | [`ramfs`], with a `/tmp` and the [`initrd`]  |
+//! | `/boot`     | The first [`fat`] volume of the ATA disks    |
+//! | `/dev`      | [`devfs`], with the disks                    |
+//! | `/mnt`      | The first [`ext2`] volume, read-only         |
+
+use bootloader_api::BootInfo;
+use log::warn;
+
+use crate::meta::init::HeapInitialized;
+
+pub mod cache;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod ramfs;
+pub mod vfs;
+
+/// Mount a [`ramfs::RamFs`] at `/`, with a `/tmp` directory, and unpack the
+/// initial ramdisk into it.
+pub fn init(boot_info: &BootInfo, _: HeapInitialized) {
+    if let Err(e) = vfs::mount("/", ramfs::RamFs::new()).and_then(|()| vfs::create_dir("/tmp")) {
+        warn!("Failed to set up the root file system: {e}");
+        return;
+    }
+
+    initrd::unpack_from(boot_info);
+}
+
+/// Mount the [`devfs::DevFs`], and the first FAT32 and ext2 volumes of the
+/// ATA disks, if any, once the devices are set up.
+pub fn mount_disks() {
+    mount_at(devfs::MOUNT_POINT, devfs::DevFs);
+
+    match fat::FatVolume::mount_first() {
+        Ok(volume) => mount_at(fat::MOUNT_POINT, volume),
+        Err(fat::FatError::NotFat32) => (),
+        Err(e) => warn!("Failed to mount the FAT32 volume: {e:?}"),
+    }
+
+    match ext2::Ext2Volume::mount_first() {
+        Ok(volume) => mount_at(ext2::MOUNT_POINT, volume),
+        Err(ext2::Ext2Error::NotExt2) => (),
+        Err(e) => warn!("Failed to mount the ext2 volume: {e:?}"),
+    }
+}
+
+/// Mount the file system at a new directory of the root file system.
+fn mount_at(path: &str, fs: impl vfs::FileSystem + 'static) {
+    let fs_name = fs.name();
+    if let Err(e) = vfs::create_dir(path).and_then(|()| vfs::mount(path, fs)) {
+        warn!("Failed to mount {fs_name} at {path}: {e}");
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A file system that lives on the heap, which is mounted at `/` at boot, so
+//! there is somewhere to put files without a disk. Everything is lost on
+//! reboot, and the heap is small, so [`MAX_FILE_SIZE`] limits the files.
+
+use alloc::{collections::BTreeMap, string::{String, ToString}, vec::Vec};
+
+use super::vfs::{DirEntry, FileSystem, FileType, Metadata, NodeId, VfsError};
+
+/// The largest a file can grow, since the heap is shared with the kernel.
+pub const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+/// The longest name of a file or directory.
+const MAX_NAME_LENGTH: usize = 255;
+
+const ROOT: NodeId = 1;
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, NodeId>),
+}
+
+pub struct RamFs {
+    nodes: BTreeMap<NodeId, Node>,
+    next_id: NodeId,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT, Node::Directory(BTreeMap::new()));
+        Self { nodes, next_id: ROOT + 1 }
+    }
+
+    fn node(&self, node: NodeId) -> Result<&Node, VfsError> {
+        self.nodes.get(&node).ok_or(VfsError::NotFound)
+    }
+
+    fn file_mut(&mut self, node: NodeId) -> Result<&mut Vec<u8>, VfsError> {
+        match self.nodes.get_mut(&node).ok_or(VfsError::NotFound)? {
+            Node::File(data) => Ok(data),
+            Node::Directory(..) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn directory_mut(&mut self, node: NodeId) -> Result<&mut BTreeMap<String, NodeId>, VfsError> {
+        match self.nodes.get_mut(&node).ok_or(VfsError::NotFound)? {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(..) => Err(VfsError::NotADirectory),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup(&mut self, directory: NodeId, name: &str) -> Result<NodeId, VfsError> {
+        self.directory_mut(directory)?.get(name).copied().ok_or(VfsError::NotFound)
+    }
+
+    fn stat(&mut self, node: NodeId) -> Result<Metadata, VfsError> {
+        let (file_type, size) = match self.node(node)? {
+            Node::File(data) => (FileType::File, data.len() as u64),
+            Node::Directory(entries) => (FileType::Directory, entries.len() as u64),
+        };
+        Ok(Metadata { node, file_type, size })
+    }
+
+    fn read(&mut self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let data = self.file_mut(node)?;
+        let start = (offset as usize).min(data.len());
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let end = offset.checked_add(data.len() as u64).ok_or(VfsError::NoSpace)?;
+        if end > MAX_FILE_SIZE {
+            return Err(VfsError::NoSpace);
+        }
+
+        let file = self.file_mut(node)?;
+        if file.len() < end as usize {
+            file.resize(end as usize, 0);
+        }
+        file[offset as usize..end as usize].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, node: NodeId, size: u64) -> Result<(), VfsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(VfsError::NoSpace);
+        }
+
+        let file = self.file_mut(node)?;
+        file.resize(size as usize, 0);
+        file.shrink_to_fit();
+        Ok(())
+    }
+
+    fn read_dir(&mut self, directory: NodeId) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = self.directory_mut(directory)?.clone();
+        entries.into_iter()
+            .map(|(name, node)| Ok(DirEntry { name, node, file_type: self.stat(node)?.file_type }))
+            .collect()
+    }
+
+    fn create(&mut self, directory: NodeId, name: &str, file_type: FileType) -> Result<NodeId, VfsError> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('/') {
+            return Err(VfsError::InvalidPath);
+        }
+
+        let node = self.next_id;
+        let entries = self.directory_mut(directory)?;
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        entries.insert(name.to_string(), node);
+
+        self.next_id += 1;
+        self.nodes.insert(node, match file_type {
+            FileType::File => Node::File(Vec::new()),
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+        });
+        Ok(node)
+    }
+
+    fn remove(&mut self, directory: NodeId, name: &str) -> Result<(), VfsError> {
+        let node = self.lookup(directory, name)?;
+        if let Node::Directory(entries) = self.node(node)? {
+            if !entries.is_empty() {
+                return Err(VfsError::NotEmpty);
+            }
+        }
+
+        self.directory_mut(directory)?.remove(name);
+        self.nodes.remove(&node);
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The virtual file system, which puts the mounted file systems in a single
+//! tree, so the users of files don't depend on a specific driver.
+//!
+//! A [`FileSystem`] identifies its files and directories by a [`NodeId`], and
+//! the VFS resolves a path by looking up its components one by one, starting
+//! at the root of the mount with the longest matching path:
+//!
+//! | Function        | Purpose                                                 |
+//! |-----------------|---------------------------------------------------------|
+//! | [`mount`]       | Attach a file system at a directory (or at `/`)         |
+//! | [`open`]        | Open a [`File`] to read or write, see [`OpenOptions`]   |
+//! | [`open_dir`]    | Open a [`Directory`] to list its entries                |
+//! | [`stat`]        | The [`Metadata`] of a file or directory                 |
+//! | [`read_dir`]    | The entries of a directory                              |
+//! | [`create_dir`]  | Create a directory                                      |
+//! | [`remove`]      | Remove a file or an empty directory                     |
+//!
+//! Paths are absolute, separated by `/`, and `.` and `..` are resolved before
+//! looking anything up, so `..` doesn't cross back over a mount point it
+//! didn't come through. A file system is locked while it's used, which isn't
+//! an [`IrqSpinlock`](crate::sync::IrqSpinlock), so files can't be used from
+//! interrupt handlers.
+//!
+//! The drivers only deal in nodes, while the users get an [`Inode`]: an open
+//! [`File`] or [`Directory`], which keeps its file system mounted until it's
+//! dropped.
+
+use alloc::{boxed::Box, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::fmt::{Display, Formatter};
+
+use log::info;
+
+use crate::sync::Spinlock;
+
+/// Identifies a file or directory within its file system.
+pub type NodeId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+
+    /// The directory can't be removed, since it has entries.
+    NotEmpty,
+
+    /// The path isn't absolute, or has an empty or too long name.
+    InvalidPath,
+
+    /// The file system can't be written to, or the file wasn't opened for
+    /// writing.
+    ReadOnly,
+
+    /// The mount point is in use, by another mount or an open file.
+    Busy,
+
+    /// There is no space left on the file system.
+    NoSpace,
+
+    /// The file system can't do this, e.g. create directories on FAT.
+    Unsupported,
+
+    /// The file system failed to read or write its storage.
+    Io,
+}
+
+impl Display for VfsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::NotFound => "no such file or directory",
+            Self::AlreadyExists => "already exists",
+            Self::NotADirectory => "not a directory",
+            Self::IsADirectory => "is a directory",
+            Self::NotEmpty => "directory not empty",
+            Self::InvalidPath => "invalid path",
+            Self::ReadOnly => "read-only",
+            Self::Busy => "busy",
+            Self::NoSpace => "no space left",
+            Self::Unsupported => "not supported by the file system",
+            Self::Io => "input/output error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub node: NodeId,
+    pub file_type: FileType,
+
+    /// The size in bytes, which is the number of entries for directories.
+    pub size: u64,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub node: NodeId,
+    pub file_type: FileType,
+}
+
+/// A driver of a file system, of which the instances are mounted.
+pub trait FileSystem: Send {
+    /// The name of the type of file system, e.g. `ramfs`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> NodeId;
+
+    /// Find the entry with the given name in the directory.
+    fn lookup(&mut self, directory: NodeId, name: &str) -> Result<NodeId, VfsError>;
+
+    fn stat(&mut self, node: NodeId) -> Result<Metadata, VfsError>;
+
+    /// Read from the file at the offset, returning the number of bytes read,
+    /// which is zero at the end of the file.
+    fn read(&mut self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError>;
+
+    /// Write to the file at the offset, growing it as needed, and returning
+    /// the number of bytes written.
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, VfsError>;
+
+    /// Shrink or grow the file, with zeroes.
+    fn truncate(&mut self, node: NodeId, size: u64) -> Result<(), VfsError>;
+
+    fn read_dir(&mut self, directory: NodeId) -> Result<Vec<DirEntry>, VfsError>;
+
+    /// Create an empty file or directory in the directory.
+    fn create(&mut self, directory: NodeId, name: &str, file_type: FileType) -> Result<NodeId, VfsError>;
+
+    /// Remove the file or empty directory from the directory.
+    fn remove(&mut self, directory: NodeId, name: &str) -> Result<(), VfsError>;
+}
+
+type SharedFileSystem = Arc<Spinlock<Box<dyn FileSystem>>>;
+
+struct Mount {
+    /// The normalized path, e.g. `/` or `/tmp`.
+    path: String,
+    fs: SharedFileSystem,
+}
+
+static MOUNTS: Spinlock<Vec<Mount>> = Spinlock::new(Vec::new());
+
+/// A mounted file system, see [`mounts`].
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub path: String,
+    pub fs_name: &'static str,
+}
+
+/// How to open a file, see [`open`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub write: bool,
+
+    /// Create the file when it doesn't exist.
+    pub create: bool,
+
+    /// Empty the file when it's opened.
+    pub truncate: bool,
+
+    /// Start writing at the end of the file.
+    pub append: bool,
+}
+
+impl OpenOptions {
+    pub const fn read_only() -> Self {
+        Self { write: false, create: false, truncate: false, append: false }
+    }
+
+    /// Open for writing, creating or emptying the file.
+    pub const fn create() -> Self {
+        Self { write: true, create: true, truncate: true, append: false }
+    }
+
+    /// Open for writing at the end, creating the file.
+    pub const fn append() -> Self {
+        Self { write: true, create: true, truncate: false, append: true }
+    }
+}
+
+/// An open file or directory.
+pub trait Inode {
+    fn metadata(&self) -> Result<Metadata, VfsError>;
+}
+
+/// An open file, which reads and writes at its position, see [`open`].
+pub trait File: Inode {
+    /// Read at the position, returning the number of bytes read, which is
+    /// zero at the end of the file.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VfsError>;
+
+    /// Write at the position, growing the file as needed.
+    fn write(&mut self, data: &[u8]) -> Result<usize, VfsError>;
+
+    fn position(&self) -> u64;
+
+    fn seek(&mut self, position: u64);
+
+    /// Read from the position until the end of the file.
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let mut data = Vec::new();
+        let mut buffer = [0; 512];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => return Ok(data),
+                count => data.extend_from_slice(&buffer[..count]),
+            }
+        }
+    }
+}
+
+/// An open directory, see [`open_dir`].
+pub trait Directory: Inode {
+    fn entries(&self) -> Result<Vec<DirEntry>, VfsError>;
+
+    /// The metadata of the entry with the given name.
+    fn stat(&self, name: &str) -> Result<Metadata, VfsError>;
+}
+
+/// A [`File`] of a mounted file system.
+pub struct OpenFile {
+    fs: SharedFileSystem,
+    node: NodeId,
+    position: u64,
+    writable: bool,
+}
+
+impl Inode for OpenFile {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        self.fs.lock().stat(self.node)
+    }
+}
+
+impl File for OpenFile {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let count = self.fs.lock().read(self.node, self.position, buffer)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, VfsError> {
+        if !self.writable {
+            return Err(VfsError::ReadOnly);
+        }
+
+        let count = self.fs.lock().write(self.node, self.position, data)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+}
+
+/// A [`Directory`] of a mounted file system.
+pub struct OpenDirectory {
+    fs: SharedFileSystem,
+    node: NodeId,
+}
+
+impl Inode for OpenDirectory {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        self.fs.lock().stat(self.node)
+    }
+}
+
+impl Directory for OpenDirectory {
+    fn entries(&self) -> Result<Vec<DirEntry>, VfsError> {
+        self.fs.lock().read_dir(self.node)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, VfsError> {
+        let mut fs = self.fs.lock();
+        let node = fs.lookup(self.node, name)?;
+        fs.stat(node)
+    }
+}
+
+/// Attach the file system at the path, which must be an existing directory,
+/// unless it's the first mount, at `/`.
+pub fn mount(path: &str, fs: impl FileSystem + 'static) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let fs_name = fs.name();
+
+    if path != "/" && !stat(&path)?.is_dir() {
+        return Err(VfsError::NotADirectory);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::Busy);
+    }
+    if path != "/" && mounts.is_empty() {
+        return Err(VfsError::NotFound);
+    }
+
+    mounts.push(Mount { path: path.clone(), fs: Arc::new(Spinlock::new(Box::new(fs))) });
+    drop(mounts);
+
+    info!("Mounted {fs_name} at {path}");
+    Ok(())
+}
+
+/// Detach the file system at the path, which must not have open files or
+/// other mounts below it.
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    let index = mounts.iter().position(|mount| mount.path == path).ok_or(VfsError::NotFound)?;
+    let nested = mounts.iter().any(|mount| mount.path != path && is_below(&mount.path, &path));
+    if nested || Arc::strong_count(&mounts[index].fs) > 1 {
+        return Err(VfsError::Busy);
+    }
+
+    mounts.remove(index);
+    Ok(())
+}
+
+/// The mounted file systems, in the order they were mounted.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().iter()
+        .map(|mount| MountInfo { path: mount.path.clone(), fs_name: mount.fs.lock().name() })
+        .collect()
+}
+
+pub fn open(path: &str, options: OpenOptions) -> Result<OpenFile, VfsError> {
+    let (fs, node) = match resolve(path) {
+        Ok(resolved) => resolved,
+        Err(VfsError::NotFound) if options.create => {
+            let (fs, directory, name) = resolve_parent(path)?;
+            let node = fs.lock().create(directory, &name, FileType::File)?;
+            (fs, node)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let metadata = fs.lock().stat(node)?;
+    if metadata.is_dir() && options.write {
+        return Err(VfsError::IsADirectory);
+    }
+    if options.truncate && options.write {
+        fs.lock().truncate(node, 0)?;
+    }
+
+    let mut file = OpenFile { fs, node, position: 0, writable: options.write };
+    if options.append {
+        file.seek(file.metadata()?.size);
+    }
+    Ok(file)
+}
+
+pub fn open_dir(path: &str) -> Result<OpenDirectory, VfsError> {
+    let (fs, node) = resolve(path)?;
+    if !fs.lock().stat(node)?.is_dir() {
+        return Err(VfsError::NotADirectory);
+    }
+
+    Ok(OpenDirectory { fs, node })
+}
+
+pub fn stat(path: &str) -> Result<Metadata, VfsError> {
+    let (fs, node) = resolve(path)?;
+    let metadata = fs.lock().stat(node)?;
+    Ok(metadata)
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    open_dir(path)?.entries()
+}
+
+pub fn create_dir(path: &str) -> Result<(), VfsError> {
+    let (fs, directory, name) = resolve_parent(path)?;
+    fs.lock().create(directory, &name, FileType::Directory)?;
+    Ok(())
+}
+
+/// Remove the file or empty directory, which can't be a mount point.
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let normalized = normalize(path)?;
+    if MOUNTS.lock().iter().any(|mount| mount.path == normalized) {
+        return Err(VfsError::Busy);
+    }
+
+    let (fs, directory, name) = resolve_parent(&normalized)?;
+    let result = fs.lock().remove(directory, &name);
+    result
+}
+
+/// Read the whole file.
+pub fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    open(path, OpenOptions::read_only())?.read_to_end()
+}
+
+/// Replace the contents of the file, creating it if needed.
+pub fn write(path: &str, data: &[u8]) -> Result<(), VfsError> {
+    let mut file = open(path, OpenOptions::create())?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(VfsError::NoSpace),
+            count => written += count,
+        }
+    }
+    Ok(())
+}
+
+/// Resolve `.` and `..` and remove the duplicate separators, e.g.
+/// `/a//b/../c/.` becomes `/a/c`.
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => _ = components.pop(),
+            name => components.push(name),
+        }
+    }
+
+    if components.is_empty() {
+        return Ok("/".to_string());
+    }
+
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    Ok(normalized)
+}
+
+/// Whether the normalized path is the mount point or below it.
+fn is_below(path: &str, mount_point: &str) -> bool {
+    mount_point == "/"
+        || path == mount_point
+        || path.strip_prefix(mount_point).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Find the file system the normalized path is on, and the rest of the path
+/// within it.
+fn find_mount(path: &str) -> Result<(SharedFileSystem, String), VfsError> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts.iter()
+        .filter(|mount| is_below(path, &mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .ok_or(VfsError::NotFound)?;
+
+    let rest = match mount.path.as_str() {
+        "/" => path,
+        mount_point => &path[mount_point.len()..],
+    };
+    Ok((mount.fs.clone(), rest.to_string()))
+}
+
+fn resolve(path: &str) -> Result<(SharedFileSystem, NodeId), VfsError> {
+    let path = normalize(path)?;
+    let (fs, rest) = find_mount(&path)?;
+
+    let node = {
+        let mut locked = fs.lock();
+        let mut node = locked.root();
+        for component in rest.split('/').filter(|component| !component.is_empty()) {
+            if !locked.stat(node)?.is_dir() {
+                return Err(VfsError::NotADirectory);
+            }
+            node = locked.lookup(node, component)?;
+        }
+        node
+    };
+
+    Ok((fs, node))
+}
+
+/// Resolve the directory the path is in, returning the name of the last
+/// component.
+fn resolve_parent(path: &str) -> Result<(SharedFileSystem, NodeId, String), VfsError> {
+    let path = normalize(path)?;
+    let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidPath)?;
+    if name.is_empty() {
+        return Err(VfsError::InvalidPath);
+    }
+
+    let parent = if parent.is_empty() { "/" } else { parent };
+    let (fs, directory) = resolve(parent)?;
+    if !fs.lock().stat(directory)?.is_dir() {
+        return Err(VfsError::NotADirectory);
+    }
+
+    Ok((fs, directory, name.to_string()))
+}
@@ -1,17 +1,134 @@
-use core::fmt::{Debug, Display, Formatter, LowerHex, UpperHex, Write};
+use core::{
+    fmt::{Arguments, Debug, Display, Formatter, LowerHex, UpperHex, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
 use log::{Level, LevelFilter, Metadata, Record};
-use crate::serial_println;
+use spin::Mutex;
+use crate::{arch, device::clocksource, meta::{init::{self, Subsystem}, BootParameters}, serial_println};
 
 static LOGGER: Logger = Logger{};
 
+/// The messages per second a single target can log, after its burst is used
+/// up. Zero disables the rate limiting.
+static RATE: AtomicU32 = AtomicU32::new(DEFAULT_RATE);
+
+static BUCKETS: Mutex<[Bucket; BUCKET_COUNT]> = Mutex::new([Bucket::EMPTY; BUCKET_COUNT]);
+
+const DEFAULT_RATE: u32 = 100;
+
+/// How many seconds worth of messages a target can log at once.
+const BURST_SECONDS: u64 = 2;
+
+/// The number of targets tracked at the same time. When more targets are
+/// logging, the one that logged least recently is forgotten.
+const BUCKET_COUNT: usize = 16;
+
 pub(super) fn init() {
+    init::mark(Subsystem::Logging);
     log::set_logger(&LOGGER)
         .expect("Failed to set logger");
     log::set_max_level(LevelFilter::Trace);
+
+    if let Some(rate) = BootParameters::get("lograte") {
+        match rate.parse() {
+            Ok(rate) => RATE.store(rate, Ordering::Relaxed),
+            Err(_) => log::warn!("Invalid lograte `{rate}`, expected messages per second"),
+        }
+    }
 }
 
 struct Logger;
 
+/// A token bucket for a single target, measured in time stamp counter cycles:
+/// every message costs the cycles of its share of a second, and the budget
+/// refills as time passes, up to the burst.
+#[derive(Clone, Copy)]
+struct Bucket {
+    /// The FNV-1a hash of the target, since the target isn't `'static`.
+    target: u64,
+    budget: u64,
+    last_cycles: u64,
+    suppressed: u32,
+}
+
+impl Bucket {
+    const EMPTY: Self = Self { target: 0, budget: 0, last_cycles: 0, suppressed: 0 };
+}
+
+/// What to do with a message, according to the rate limiter.
+enum Verdict {
+    Log,
+
+    /// Log the message, after reporting the messages suppressed before it.
+    LogAfterSuppressed(u32),
+    Suppress,
+}
+
+/// Decide whether the target can log another message. Errors and warnings are
+/// never suppressed, so they aren't hidden by the noise around them, but they
+/// do use up the budget.
+fn rate_limit(target: &str, level: Level) -> Verdict {
+    let rate = RATE.load(Ordering::Relaxed) as u64;
+    let frequency = clocksource::tsc_frequency();
+
+    // Without a calibrated time stamp counter, time can't be measured.
+    if rate == 0 || frequency == 0 {
+        return Verdict::Log;
+    }
+
+    let cost = frequency / rate;
+    let capacity = frequency * BURST_SECONDS;
+    let hash = target.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    });
+
+    // Messages logged from an interrupt handler that interrupted a message
+    // aren't limited, rather than deadlocking.
+    let now = arch::cycles();
+    arch::without_interrupts(|| {
+        let Some(mut buckets) = BUCKETS.try_lock() else {
+            return Verdict::Log;
+        };
+
+        let bucket = match buckets.iter().position(|bucket| bucket.target == hash) {
+            Some(index) => &mut buckets[index],
+            None => {
+                let oldest = buckets.iter_mut().min_by_key(|bucket| bucket.last_cycles).unwrap();
+                *oldest = Bucket { target: hash, budget: capacity, last_cycles: now, suppressed: 0 };
+                oldest
+            }
+        };
+
+        bucket.budget = (bucket.budget + now.saturating_sub(bucket.last_cycles)).min(capacity);
+        bucket.last_cycles = now;
+
+        if bucket.budget < cost && level > Level::Warn {
+            bucket.suppressed += 1;
+            return Verdict::Suppress;
+        }
+
+        bucket.budget = bucket.budget.saturating_sub(cost);
+        match core::mem::take(&mut bucket.suppressed) {
+            0 => Verdict::Log,
+            count => Verdict::LogAfterSuppressed(count),
+        }
+    })
+}
+
+fn write(target: &str, level: Level, args: Arguments) {
+    // Errors and warnings are printed in bold, so they stand out.
+    let emphasis = if level <= Level::Warn { "\x1b[1m" } else { "" };
+
+    serial_println!("[{}] [\x1b[31m{}\x1b[0m] {emphasis}{}\x1b[0m", target.white(), level.stylized(), args);
+    crate::meta::pstore::append(format_args!("[{target}] [{level}] {args}"));
+    crate::device::virtio::console::mirror(format_args!("[{target}] [{level}] {args}\n"));
+
+    // While the boot splash is shown, it owns the framebuffer.
+    if level != Level::Trace && !crate::meta::splash::is_active() {
+        crate::vga_text_buffer::_print(format_args!("[{}] [\x1b[31m{}\x1b[0m] {emphasis}{}\x1b[0m\n", target.white(), level.stylized(), args));
+    }
+}
+
 pub struct Colored<S> {
     color: Color,
     inner: S,
@@ -37,11 +154,17 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &Record) {
-        serial_println!("[{}] [\x1b[31m{}\x1b[0m] {}", record.metadata().target().white(), record.metadata().level().stylized(), record.args());
+        let target = record.metadata().target();
 
-        if record.level() != Level::Trace {
-            crate::vga_text_buffer::_print(format_args!("[{}] [\x1b[31m{}\x1b[0m] {}\n", record.metadata().target().white(), record.metadata().level().stylized(), record.args()));
+        match rate_limit(target, record.level()) {
+            Verdict::Log => (),
+            Verdict::LogAfterSuppressed(count) => {
+                write(target, Level::Warn, format_args!("{count} messages suppressed"));
+            }
+            Verdict::Suppress => return,
         }
+
+        write(target, record.level(), *record.args());
     }
 
     fn flush(&self) {
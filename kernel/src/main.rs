@@ -11,12 +11,16 @@
 #![test_runner(crate::test_runner)]
 
 mod allocator;
+mod arch;
+mod crypto;
+mod debug;
 mod device;
-mod gdt;
-mod interrupts;
-mod memory;
+mod fs;
 mod meta;
-mod serial;
+mod net;
+mod process;
+mod sync;
+mod syscall;
 mod task;
 mod vga_text_buffer;
 mod logging;
@@ -34,9 +38,9 @@ use bootloader_api::{
 
 use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
 use core::{panic::PanicInfo, time::Duration};
-use log::{error, info, trace};
+use log::{info, trace, warn};
 
-use crate::{device::pit, meta::System, task::{executor::Executor, keyboard, Task}};
+use crate::{arch::memory, device::pit, meta::{config, init::HeapInitialized, splash::{self, BootStage}}, task::{executor::Executor, shell, Affinity, Task}};
 use crate::vga_text_buffer::WRITER;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -58,7 +62,7 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 #[no_mangle]
 pub fn hlt_loop() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        arch::halt();
     }
 }
 
@@ -85,15 +89,30 @@ pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     serial_println!("----<[ nocciolo ]>----");
     init(boot_info);
 
-    for i in (0..10).rev() {
-        info!("See you in {i} seconds!");
-        pit::sleep(Duration::from_secs(1));
-    }
-
-    System::request_shutdown();
-
     let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::named("irq-log", meta::irq_log::run()));
+    executor.spawn(Task::named("ps2", device::ps2::run()).with_cleanup(device::ps2::stop));
+    executor.spawn(Task::named("guest-agent", device::guest_agent::run()));
+    executor.spawn(Task::named("acpi-gpe", device::acpi::gpe::run()));
+    executor.spawn(Task::named("virtio-console", device::virtio::console::run()));
+    executor.spawn(Task::named("virtio-rng", device::virtio::rng::run()));
+    executor.spawn(Task::named("e1000", device::net::intel_8254x::run()));
+    executor.spawn(Task::named("net", net::interface::run()));
+    executor.spawn(Task::named("dhcp", net::dhcp::run()));
+    executor.spawn(Task::named("tcp", net::tcp::run()));
+    executor.spawn(Task::named("tcp-client", net::tcp::run_client()));
+    executor.spawn(Task::named("netconsole", net::console::run()).with_affinity(Affinity::BootCpu));
+    executor.spawn(Task::named("ping", net::icmp::run()));
+    executor.spawn(Task::named("crash-report", net::crash::run()));
+    executor.spawn(Task::named("entropy", crypto::entropy::run()));
+    executor.spawn(Task::named("input", task::focus::run()));
+    executor.spawn(Task::named("serial-input", task::serial_input::run()));
+    executor.spawn(Task::named("inspector", task::inspector::run()));
+    executor.spawn(Task::named("reboot", task::reboot::run()));
+    executor.spawn(Task::named("health", meta::health::run()));
+    executor.spawn(Task::named("status-bar", task::status_bar::run()).with_affinity(Affinity::BootCpu));
+    executor.spawn(Task::named("editor", task::editor::run()).with_affinity(Affinity::BootCpu));
+    executor.spawn(Task::named("shell", shell::run()).with_affinity(Affinity::BootCpu));
     executor.run();
 }
 
@@ -105,46 +124,71 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    error!("[PANIC] {info}");
-    hlt_loop();
+    meta::panic::handle(info)
 }
 
 fn init(boot_info: &'static BootInfo) {
     logging::init();
+    meta::stack::init_kernel_stack(BOOTLOADER_CONFIG.kernel_stack_size as usize);
 
     if let Some(fb) = boot_info.framebuffer.as_ref() {
         WRITER.lock().set_fb(fb);
+
+        if let Some(size) = meta::BootParameters::get("fontsize") {
+            match size.parse() {
+                Ok(font_size) => WRITER.lock().set_font_size(font_size),
+                Err(()) => warn!("Ignoring invalid font size `{size}`, expected 16, 24 or 32"),
+            }
+        }
     }
 
-    info!("----<[ nocciolo ]>----");
+    splash::init();
 
-    gdt::init();
-    interrupts::init_idt();
+    info!("----<[ nocciolo ]>----");
+    info!("{}", meta::version());
 
-    trace!("Enabling Interrupts");
+    splash::advance(BootStage::DescriptorTables);
+    arch::init();
+    #[cfg(debug_assertions)]
+    meta::integrity::init();
 
-    trace!("Initializing the PIC");
-    unsafe { interrupts::PICS.lock().initialize() };
+    splash::advance(BootStage::Interrupts);
+    arch::init_legacy_interrupt_controller();
 
-    trace!("Initializing PIT");
-    pit::init();
+    splash::advance(BootStage::Timer);
+    trace!("Initializing Clock Source");
+    device::clocksource::init();
 
+    splash::advance(BootStage::Heap);
     trace!("Initializing Heap");
-    init_heap(boot_info);
+    let heap = init_heap(boot_info);
+    meta::memory_map::init(boot_info, heap);
+    meta::pstore::init(boot_info, heap);
+    device::init_platform(heap);
+    device::fw_cfg::init(heap);
+    meta::irq_log::init(heap);
+    crypto::entropy::init(heap);
+    task::scheduler::init(heap);
+    fs::init(boot_info, heap);
+    device::ps2::init(heap);
+    device::guest_agent::init(heap);
+
+    // The PIT ticks aren't routed through the APIC yet, so wait before
+    // switching to it.
+    if let Some(seconds) = meta::BootParameters::get("bootdelay").and_then(|s| s.parse().ok()) {
+        info!("Waiting {seconds} seconds (bootdelay)");
+        pit::sleep(Duration::from_secs(seconds));
+    }
 
+    splash::advance(BootStage::Acpi);
     trace!("Initializing ACPI");
     device::acpi::init(boot_info);
+    device::clocksource::select();
 
-    if let Err(e) = interrupts::apic::init(boot_info) {
-        trace!("Failed to initialize APIC: {e:?}");
+    splash::advance(BootStage::Apic);
+    arch::init_interrupt_controller(boot_info);
 
-        trace!("Initializing PICS");
-
-    } else {
-        unsafe { interrupts::PICS.lock().disable() };
-    }
-
-    x86_64::instructions::interrupts::enable();
+    arch::enable_interrupts();
     trace!("Interrupts enabled");
 
     // for i in (0..10).rev() {
@@ -152,12 +196,20 @@ fn init(boot_info: &'static BootInfo) {
     //     pit::sleep(Duration::from_secs(1));
     // }
 
+    splash::advance(BootStage::Runtime);
     trace!("Initializing Kernel Runtime");
     meta::init(boot_info);
 
+    splash::advance(BootStage::Devices);
     trace!("Initializing Devices");
     device::init(boot_info);
+    fs::mount_disks();
 
+    splash::advance(BootStage::Configuration);
+    trace!("Loading Configuration");
+    config::init();
+
+    splash::advance(BootStage::Finished);
     info!("Finished Initializing");
 }
 
@@ -168,7 +220,7 @@ pub fn crash_test() {
     println!("Crashed!");
 }
 
-fn init_heap(boot_info: &'static BootInfo) {
+fn init_heap(boot_info: &'static BootInfo) -> HeapInitialized {
     let physical_memory_offset;
     if let bootloader_api::info::Optional::Some(offset) = boot_info.physical_memory_offset {
         physical_memory_offset = offset;
@@ -178,15 +230,16 @@ fn init_heap(boot_info: &'static BootInfo) {
 
     let phys_mem_offset = VirtAddr::new(physical_memory_offset);
 
-    unsafe {
-        memory::init_mapper(phys_mem_offset);
-        memory::init_frame_allocator(&boot_info.memory_regions);
-    }
+    let (mapper_token, frame_allocator_token) = unsafe {
+        (memory::init_mapper(phys_mem_offset), memory::init_frame_allocator(&boot_info.memory_regions))
+    };
+    meta::pstore::reserve(&boot_info.memory_regions, frame_allocator_token);
+    meta::memtest::run(phys_mem_offset, frame_allocator_token);
 
     memory::with_mapper(|mapper| memory::with_frame_allocator(|frame_allocator| {
-        allocator::init_heap(mapper, frame_allocator)
-            .expect("heap initialization failed");
-    }));
+        allocator::init_heap(mapper, frame_allocator, mapper_token, frame_allocator_token)
+            .expect("heap initialization failed")
+    }))
 }
 
 #[lang = "eh_personality"]
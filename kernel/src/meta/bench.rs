@@ -0,0 +1,157 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Micro-benchmarks that run inside the kernel, the companion of the
+//! [self-tests](super::selftest), to get objective numbers before and after
+//! changing e.g. the allocator or the string routines. They are run by the
+//! `bench` shell command, or the `bench` command of the guest agent, which
+//! reports them on a single line for scripts.
+//!
+//! Every benchmark is warmed up first, after which it is measured a number of
+//! times, each sample running the code a number of iterations. The results
+//! are in [`arch::cycles`] per iteration.
+
+use alloc::{boxed::Box, vec};
+use core::{
+    future::Future,
+    hint::black_box,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{arch, task::{executor::block_on, timer}};
+
+/// The number of measured samples, after one for warming up.
+const SAMPLES: usize = 16;
+
+const COPY_SIZE: usize = 4096;
+
+/// The cycle count of the last timer interrupt, see [`record_tick`].
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+
+pub struct Benchmark {
+    /// The name, which is also the key in the report of the guest agent.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub iterations: u64,
+
+    /// Run the code the number of iterations, returning the cycles spent on
+    /// the part that is measured.
+    pub run: fn(iterations: u64) -> u64,
+}
+
+pub const BENCHMARKS: &[Benchmark] = &[
+    Benchmark {
+        name: "alloc.small",
+        description: "Allocate and free 64 bytes",
+        iterations: 1000,
+        run: |iterations| measure(iterations, || drop(black_box(Box::new([0u8; 64])))),
+    },
+    Benchmark {
+        name: "alloc.page",
+        description: "Allocate and free 4 KiB",
+        iterations: 100,
+        run: |iterations| measure(iterations, || drop(black_box(vec![0u8; 4096]))),
+    },
+    Benchmark {
+        name: "copy.builtin",
+        description: "Copy 4 KiB with copy_from_slice",
+        iterations: 100,
+        run: |iterations| {
+            let (mut dst, src) = (vec![0u8; COPY_SIZE], vec![0x5Au8; COPY_SIZE]);
+            measure(iterations, || black_box(&mut dst).copy_from_slice(black_box(&src)))
+        },
+    },
+    Benchmark {
+        name: "copy.arch",
+        description: "Copy 4 KiB with arch::string::copy",
+        iterations: 100,
+        run: |iterations| {
+            let (mut dst, src) = (vec![0u8; COPY_SIZE], vec![0x5Au8; COPY_SIZE]);
+            measure(iterations, || arch::string::copy(black_box(&mut dst), black_box(&src)))
+        },
+    },
+    Benchmark {
+        name: "task.yield",
+        description: "Wake a task and poll it again",
+        iterations: 1000,
+        run: |iterations| {
+            let start = arch::cycles();
+            block_on(Yield { remaining: iterations });
+            arch::cycles() - start
+        },
+    },
+    Benchmark {
+        name: "irq.wake",
+        description: "From the timer interrupt to polling the task it woke",
+        iterations: 4,
+        run: |iterations| {
+            (0..iterations).map(|_| {
+                // Wakes at the next tick.
+                block_on(timer::sleep(Duration::from_millis(1)));
+                arch::cycles().wrapping_sub(LAST_TICK.load(Ordering::Relaxed))
+            }).sum()
+        },
+    },
+];
+
+/// The statistics of the samples, in cycles per iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub mean: u64,
+    pub stddev: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+pub fn run(benchmark: &Benchmark) -> BenchResult {
+    // Warm up the caches, the TLB and the allocator.
+    (benchmark.run)(benchmark.iterations);
+
+    let samples: [u64; SAMPLES] = core::array::from_fn(|_| (benchmark.run)(benchmark.iterations) / benchmark.iterations);
+
+    let mean = samples.iter().sum::<u64>() / SAMPLES as u64;
+    let variance = samples.iter().map(|sample| sample.abs_diff(mean).pow(2)).sum::<u64>() / SAMPLES as u64;
+    BenchResult {
+        mean,
+        stddev: variance.isqrt(),
+        min: samples.iter().copied().min().unwrap_or_default(),
+        max: samples.iter().copied().max().unwrap_or_default(),
+    }
+}
+
+/// Called by the timer interrupt handler, for the `irq.wake` benchmark.
+///
+/// Must not block or allocate.
+pub(crate) fn record_tick() {
+    LAST_TICK.store(arch::cycles(), Ordering::Relaxed);
+}
+
+fn measure(iterations: u64, mut f: impl FnMut()) -> u64 {
+    let start = arch::cycles();
+    for _ in 0..iterations {
+        f();
+    }
+    arch::cycles() - start
+}
+
+/// Yields the given number of times, waking itself every time.
+struct Yield {
+    remaining: u64,
+}
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.remaining == 0 {
+            return Poll::Ready(());
+        }
+
+        self.remaining -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A tiny persistent key-value store for settings that should survive a
+//! reboot, such as the log level.
+//!
+//! The store lives in the first sectors of an ATA disk, which is recognized by
+//! the signature in its header. A disk of which these sectors are all zero,
+//! like the `target/config.img` the `os` runner provides, is only written to
+//! once it's claimed with the `cfgformat` shell command (see [`format`]), so
+//! a blank disk that is meant for something else isn't taken over. Until then,
+//! the settings last until the next reboot.
+//!
+//! Layout of the store (all integers little endian):
+//! ```text
+//! 0x00  [u8; 8]  magic "NCFGSTOR"
+//! 0x08  u16      version
+//! 0x0A  u16      reserved
+//! 0x0C  u32      length of the payload
+//! 0x10  u32      FNV-1a checksum of the payload
+//! 0x14  [u8]     payload: `key=value\n` lines
+//! ```
+
+use alloc::{collections::BTreeMap, string::{String, ToString}, vec, vec::Vec};
+use core::str::FromStr;
+
+use lazy_static::lazy_static;
+use log::{info, trace, warn, LevelFilter};
+use spin::Mutex;
+
+use crate::{
+    device::ata::{AtaDrive, AtaDrivePosition, AtaError, SECTOR_SIZE},
+    net::{interface, ipv4::{Ipv4Address, Ipv4InterfaceAddress}},
+    task::keymap::{self, Layout},
+    vga_text_buffer::{FontSize, WRITER},
+};
+
+use super::help::{Entry, Topic};
+
+pub const KEY_LOG_LEVEL: &str = "log.level";
+pub const KEY_VIDEO_FONT_SIZE: &str = "video.font_size";
+pub const KEY_KEYBOARD_LAYOUT: &str = "keyboard.layout";
+pub const KEY_KEYBOARD_REMAP: &str = "keyboard.remap";
+pub const KEY_KEYBOARD_AUTOPLAY: &str = "keyboard.autoplay";
+pub const KEY_NETCONSOLE_ALLOW: &str = "netconsole.allow";
+pub const KEY_NET_IPV4_ADDRESS: &str = "net.ipv4.address";
+pub const KEY_NET_IPV4_GATEWAY: &str = "net.ipv4.gateway";
+pub const KEY_NET_CRASH_ADDRESS: &str = "net.crash.address";
+pub const KEY_HEALTH_INTERVAL: &str = "health.interval";
+
+/// The keys that are read, shown by `help config`.
+pub const TOPIC: Topic = Topic {
+    name: "config",
+    summary: "Settings kept across reboots, changed with get, set and unset",
+    entries: &[
+        Entry::new(KEY_LOG_LEVEL, "Maximum level of the log: off, error, warn, info, debug or trace"),
+        Entry::new(KEY_VIDEO_FONT_SIZE, "Height of the console font in pixels: 16, 24 or 32"),
+        Entry::new(KEY_KEYBOARD_LAYOUT, "Keyboard layout: us, uk, de, fr, dvorak, colemak or jp"),
+        Entry::new(KEY_KEYBOARD_REMAP, "Keys typing other characters, as <scancode>=<chars>,... (see keymap)"),
+        Entry::new(KEY_KEYBOARD_AUTOPLAY, "Keyboard macro to replay when the shell starts"),
+        Entry::new("keyboard.macro.<name>", "A keyboard macro recorded with `macro record`"),
+        Entry::new(KEY_NETCONSOLE_ALLOW, "Comma-separated IPv4 addresses allowed to use the network console"),
+        Entry::new(KEY_NET_IPV4_ADDRESS, "IPv4 address of eth0 with its prefix length, e.g. 10.0.2.15/24, instead of a DHCP lease"),
+        Entry::new(KEY_NET_IPV4_GATEWAY, "IPv4 router of eth0 to the hosts outside its subnet, e.g. 10.0.2.2"),
+        Entry::new(KEY_NET_CRASH_ADDRESS, "IPv4 address and UDP port to send panic messages to, e.g. 10.0.2.2:5140"),
+        Entry::new(KEY_HEALTH_INTERVAL, "Seconds between the health reports in the log (0: off)"),
+    ],
+};
+
+const MAGIC: &[u8; 8] = b"NCFGSTOR";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 0x14;
+const STORE_SECTORS: usize = 8;
+const STORE_SIZE: usize = STORE_SECTORS * SECTOR_SIZE;
+
+lazy_static! {
+    static ref STORE: Mutex<ConfigStore> = Mutex::new(ConfigStore::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// No disk was found to persist the configuration to.
+    NoBackingStore,
+    InvalidKey,
+    InvalidValue,
+
+    /// The serialized configuration doesn't fit in the reserved sectors.
+    TooLarge,
+    Ata(AtaError),
+}
+
+impl From<AtaError> for ConfigError {
+    fn from(value: AtaError) -> Self {
+        Self::Ata(value)
+    }
+}
+
+#[derive(Default)]
+struct ConfigStore {
+    entries: BTreeMap<String, String>,
+    drive: Option<AtaDrive>,
+
+    /// The first disk of which the sectors of the store are zero, which is
+    /// only used once it's claimed with [`format`].
+    blank: Option<AtaDrive>,
+}
+
+impl ConfigStore {
+    fn load(&mut self) {
+        for position in AtaDrivePosition::ALL {
+            let Ok(drive) = AtaDrive::identify(position) else {
+                continue;
+            };
+
+            let mut data = vec![0u8; STORE_SIZE];
+            if let Err(e) = drive.read_sectors(0, &mut data) {
+                warn!("Failed to read configuration sectors from {position:?}: {e:?}");
+                continue;
+            }
+
+            if data.starts_with(MAGIC) {
+                match parse(&data) {
+                    Some(entries) => self.entries = entries,
+                    None => warn!("Configuration store on {position:?} is corrupt, ignoring its contents"),
+                }
+
+                info!("Loaded {} configuration entries from {position:?}", self.entries.len());
+                self.drive = Some(drive);
+                self.blank = None;
+                return;
+            }
+
+            if self.blank.is_none() && data.iter().all(|b| *b == 0) {
+                trace!("Found blank disk for configuration store at {position:?}");
+                self.blank = Some(drive);
+            }
+        }
+
+        match &self.blank {
+            Some(blank) => warn!("No configuration store, settings won't be persisted until the blank disk at {:?} is claimed with `cfgformat`", blank.position()),
+            None => warn!("No disk found for the configuration store, settings won't be persisted"),
+        }
+    }
+
+    fn persist(&self) -> Result<(), ConfigError> {
+        let Some(drive) = self.drive.as_ref() else {
+            return Err(ConfigError::NoBackingStore);
+        };
+
+        let mut payload = Vec::new();
+        for (key, value) in &self.entries {
+            payload.extend_from_slice(key.as_bytes());
+            payload.push(b'=');
+            payload.extend_from_slice(value.as_bytes());
+            payload.push(b'\n');
+        }
+
+        if HEADER_SIZE + payload.len() > STORE_SIZE {
+            return Err(ConfigError::TooLarge);
+        }
+
+        let mut data = vec![0u8; STORE_SIZE];
+        data[0x00..0x08].copy_from_slice(MAGIC);
+        data[0x08..0x0A].copy_from_slice(&VERSION.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        data[0x10..0x14].copy_from_slice(&fnv1a(&payload).to_le_bytes());
+        data[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(&payload);
+
+        drive.write_sectors(0, &data)?;
+        Ok(())
+    }
+}
+
+/// Load the configuration from disk and apply the known settings.
+pub fn init() {
+    let mut store = STORE.lock();
+    store.load();
+
+    for (key, value) in &store.entries {
+        if let Err(e) = apply(key, value) {
+            warn!("Ignoring invalid configuration entry {key}={value}: {e:?}");
+        }
+    }
+}
+
+/// Claim the blank disk that was found at boot for the store, and write the
+/// settings to it, including the ones changed since. Returns where the disk
+/// is.
+pub fn format() -> Result<AtaDrivePosition, ConfigError> {
+    let mut store = STORE.lock();
+    let drive = store.blank.take().ok_or(ConfigError::NoBackingStore)?;
+    let position = drive.position();
+    store.drive = Some(drive);
+
+    if let Err(e) = store.persist() {
+        store.blank = store.drive.take();
+        return Err(e);
+    }
+    info!("Claimed the blank disk at {position:?} for the configuration store");
+    Ok(position)
+}
+
+/// The blank disk [`format`] claims, if there is one.
+pub fn blank_disk() -> Option<AtaDrivePosition> {
+    STORE.lock().blank.as_ref().map(AtaDrive::position)
+}
+
+pub fn get(key: &str) -> Option<String> {
+    STORE.lock().entries.get(key).cloned()
+}
+
+/// Change a setting, apply it and persist the configuration.
+pub fn set(key: &str, value: &str) -> Result<(), ConfigError> {
+    if key.is_empty() || key.contains(|c: char| c == '=' || c.is_whitespace()) {
+        return Err(ConfigError::InvalidKey);
+    }
+
+    if value.contains('\n') {
+        return Err(ConfigError::InvalidValue);
+    }
+
+    apply(key, value)?;
+
+    let mut store = STORE.lock();
+    store.entries.insert(key.to_string(), value.to_string());
+    store.persist()
+}
+
+/// Remove a setting and persist the configuration. Returns whether the key
+/// was present.
+pub fn remove(key: &str) -> Result<bool, ConfigError> {
+    let mut store = STORE.lock();
+    if store.entries.remove(key).is_none() {
+        return Ok(false);
+    }
+
+    store.persist()?;
+    Ok(true)
+}
+
+pub fn entries() -> Vec<(String, String)> {
+    STORE.lock().entries.iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Apply the settings that take effect immediately. Other keys are stored so
+/// the subsystems can query them when they need to.
+fn apply(key: &str, value: &str) -> Result<(), ConfigError> {
+    match key {
+        KEY_LOG_LEVEL => {
+            let level = LevelFilter::from_str(value).map_err(|_| ConfigError::InvalidValue)?;
+            log::set_max_level(level);
+        }
+
+        KEY_VIDEO_FONT_SIZE => {
+            let font_size = FontSize::from_str(value).map_err(|()| ConfigError::InvalidValue)?;
+            let (columns, rows) = {
+                let mut writer = WRITER.lock();
+                writer.set_font_size(font_size);
+                (writer.columns(), writer.rows())
+            };
+            info!("Console resized to {columns}x{rows} characters");
+        }
+
+        KEY_KEYBOARD_LAYOUT => {
+            let layout = Layout::from_name(value).ok_or(ConfigError::InvalidValue)?;
+            keymap::set_layout(layout);
+        }
+
+        KEY_KEYBOARD_REMAP => {
+            keymap::set_remaps(value).map_err(|_| ConfigError::InvalidValue)?;
+        }
+
+        KEY_HEALTH_INTERVAL => {
+            value.parse::<u64>().map_err(|_| ConfigError::InvalidValue)?;
+        }
+
+        KEY_NET_IPV4_ADDRESS => {
+            let address = value.parse::<Ipv4InterfaceAddress>().map_err(|()| ConfigError::InvalidValue)?;
+            interface::with_interface("eth0", |interface| interface.set_ipv4_address(Some(address)));
+        }
+
+        KEY_NET_IPV4_GATEWAY => {
+            let gateway = value.parse::<Ipv4Address>().map_err(|()| ConfigError::InvalidValue)?;
+            interface::with_interface("eth0", |interface| interface.set_ipv4_gateway(Some(gateway)));
+        }
+
+        KEY_NET_CRASH_ADDRESS => {
+            let (address, port) = value.split_once(':').ok_or(ConfigError::InvalidValue)?;
+            address.parse::<Ipv4Address>().map_err(|()| ConfigError::InvalidValue)?;
+            port.parse::<u16>().map_err(|_| ConfigError::InvalidValue)?;
+        }
+
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn parse(data: &[u8]) -> Option<BTreeMap<String, String>> {
+    let version = u16::from_le_bytes([data[0x08], data[0x09]]);
+    if version != VERSION {
+        return None;
+    }
+
+    let length = u32::from_le_bytes(data[0x0C..0x10].try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(data[0x10..0x14].try_into().ok()?);
+
+    let payload = data.get(HEADER_SIZE..HEADER_SIZE + length)?;
+    if fnv1a(payload) != checksum {
+        return None;
+    }
+
+    let payload = core::str::from_utf8(payload).ok()?;
+    let entries = payload.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+    Some(entries)
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
@@ -1,13 +1,168 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
-use crate::vga_text_buffer::{self, WRITER};
+//! The console the shell reads from and prints to, which is the framebuffer
+//! with the keyboard, the serial port, or both:
+//!
+//! | ConsoleRoute    | Output                      | Input                    |
+//! |----------|-----------------------------|--------------------------|
+//! | `fb`     | Framebuffer                 | Keyboard                 |
+//! | `serial` | Serial port                 | Keyboard and serial port |
+//! | `both`   | Framebuffer and serial port | Keyboard and serial port |
+//!
+//! The route is chosen with the `console=` boot parameter, and defaults to the
+//! serial port when there is no framebuffer, so the same image can be used
+//! headless. The shell changes it with `console`. The log always goes to both
+//! (see [`crate::logging`]), regardless of the route.
+
+use alloc::string::String;
+use core::{fmt::{self, Write}, sync::atomic::{AtomicU8, Ordering}};
+
+use bootloader_api::info::FrameBufferInfo;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::{arch::{self, serial}, task::inspector, vga_text_buffer::{self, WRITER}};
+
+use super::{splash, BootParameters};
+
+/// When set, console output is also appended to this buffer.
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+
+static ROUTE: AtomicU8 = AtomicU8::new(ConsoleRoute::Framebuffer as u8);
+
+/// Where the console output goes, and where its input comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConsoleRoute {
+    Framebuffer = 0,
+    Serial = 1,
+    Both = 2,
+}
+
+impl ConsoleRoute {
+    pub const ALL: [Self; 3] = [Self::Framebuffer, Self::Serial, Self::Both];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Framebuffer => "fb",
+            Self::Serial => "serial",
+            Self::Both => "both",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|route| route.name() == name)
+    }
+
+    #[must_use]
+    pub const fn includes_framebuffer(&self) -> bool {
+        matches!(self, Self::Framebuffer | Self::Both)
+    }
+
+    #[must_use]
+    pub const fn includes_serial(&self) -> bool {
+        matches!(self, Self::Serial | Self::Both)
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Serial,
+            2 => Self::Both,
+            _ => Self::Framebuffer,
+        }
+    }
+}
 
 pub struct Console;
 
 impl Console {
+    /// Choose the route from the `console=` boot parameter.
+    pub fn init() {
+        let route = match BootParameters::get("console") {
+            Some(name) => ConsoleRoute::from_name(name).unwrap_or_else(|| {
+                warn!("Unknown console `{name}`, expected `fb`, `serial` or `both`");
+                ConsoleRoute::Framebuffer
+            }),
+            None if !WRITER.lock().is_available() => ConsoleRoute::Serial,
+            None => ConsoleRoute::Framebuffer,
+        };
+
+        Self::set_route(route);
+    }
+
+    #[must_use]
+    pub fn route() -> ConsoleRoute {
+        ConsoleRoute::from_u8(ROUTE.load(Ordering::Relaxed))
+    }
+
+    /// Move the console to another route. The keyboard is never unrouted,
+    /// since its key presses might be meant for another consumer.
+    pub fn set_route(route: ConsoleRoute) {
+        let previous = ConsoleRoute::from_u8(ROUTE.swap(route as u8, Ordering::Relaxed));
+        serial::set_receiving(route.includes_serial());
+
+        if previous != route {
+            info!("Console routed to {}", route.name());
+        }
+    }
+
     pub fn backspace() {
-        let mut writer = WRITER.lock();
-        writer.backspace();
+        let route = Self::route();
+        if route.includes_framebuffer() {
+            WRITER.lock().backspace();
+        }
+        if route.includes_serial() {
+            serial::_print(format_args!("\x08 \x08"));
+        }
+    }
+
+    /// Switch to another mode of the framebuffer, e.g. after a display driver
+    /// changed the resolution, which draws the text and the overlays again.
+    /// Returns `false` when the mode doesn't fit in the framebuffer.
+    pub fn reconfigure(info: FrameBufferInfo) -> bool {
+        if !WRITER.lock().reconfigure(info) {
+            return false;
+        }
+
+        splash::redraw();
+        inspector::redraw();
+        true
+    }
+
+    /// Run the function and collect everything it prints, which is still shown
+    /// on the screen as well.
+    pub fn capture<F: FnOnce()>(f: F) -> String {
+        arch::without_interrupts(|| {
+            *CAPTURE.lock() = Some(String::new());
+        });
+
+        f();
+
+        arch::without_interrupts(|| CAPTURE.lock().take().unwrap_or_default())
+    }
+
+    /// Called by `print!`.
+    #[doc(hidden)]
+    pub fn _print(args: fmt::Arguments) {
+        let route = Self::route();
+        if route.includes_framebuffer() {
+            vga_text_buffer::_print(args);
+        } else {
+            arch::without_interrupts(|| Self::append_capture(args));
+        }
+
+        if route.includes_serial() {
+            serial::_print(args);
+        }
+    }
+
+    /// Called by `print!` with interrupts disabled.
+    pub(crate) fn append_capture(args: fmt::Arguments) {
+        if let Some(buffer) = CAPTURE.lock().as_mut() {
+            _ = buffer.write_fmt(args);
+        }
     }
 }
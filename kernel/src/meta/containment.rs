@@ -0,0 +1,161 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Containment of the subsystems the kernel can do without, so a bug in one
+//! of them disables that feature with an error in the log, instead of taking
+//! the kernel down with it.
+//!
+//! The kernel is built with `panic = "abort"`, so there is no unwinding to
+//! catch a panic with. Instead, the init function and the work items of such a
+//! subsystem are fallible, and are run through its [`Feature`], which is
+//! declared as a static next to the code:
+//! ```ignore
+//! static FEATURE: Feature = Feature::new("symbols", "Symbols of backtraces");
+//!
+//! FEATURE.init(|| parse_symbols(boot_info));
+//! ```
+//!
+//! | Function            | Runs the closure                   | On an error                   |
+//! |---------------------|------------------------------------|-------------------------------|
+//! | [`Feature::init`]   | Once, at boot                      | Log it and poison the feature |
+//! | [`Feature::run`]    | Unless the feature is poisoned     | Log it and poison the feature |
+//!
+//! A poisoned feature stays off until the next boot, and its state is shown
+//! by the `features` shell command. A panic still halts the kernel, but the
+//! report names the feature that was running (see [`active`]).
+
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    fmt::Debug,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use log::{error, trace};
+use spin::Mutex;
+
+use crate::arch;
+
+use super::counters::MAX_CPUS;
+
+static FEATURES: Mutex<Vec<&'static Feature>> = Mutex::new(Vec::new());
+
+/// The feature whose closure is running on every CPU, the others share the
+/// last slot like the counters.
+static ACTIVE: [AtomicPtr<Feature>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<Feature> = AtomicPtr::new(ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeatureState {
+    /// Neither initialized nor used yet.
+    Unused,
+    Available,
+    Poisoned(String),
+}
+
+pub struct Feature {
+    name: &'static str,
+    description: &'static str,
+    registered: AtomicBool,
+    poisoned: AtomicBool,
+
+    /// Why the feature was poisoned.
+    reason: Mutex<Option<String>>,
+}
+
+impl Feature {
+    pub const fn new(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            registered: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
+            reason: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    pub fn is_available(&self) -> bool {
+        !self.poisoned.load(Ordering::Acquire)
+    }
+
+    pub fn state(&self) -> FeatureState {
+        if let Some(reason) = self.reason.lock().as_ref() {
+            FeatureState::Poisoned(reason.clone())
+        } else if self.registered.load(Ordering::Relaxed) {
+            FeatureState::Available
+        } else {
+            FeatureState::Unused
+        }
+    }
+
+    /// Initialize the feature, poisoning it when that fails. Returns whether
+    /// the feature is available.
+    pub fn init<E: Debug>(&'static self, init: impl FnOnce() -> Result<(), E>) -> bool {
+        trace!("Initializing feature `{}`", self.name);
+        self.run(init).is_some()
+    }
+
+    /// Run a work item of the feature, unless it's poisoned, poisoning it when
+    /// the work item fails.
+    pub fn run<T, E: Debug>(&'static self, work: impl FnOnce() -> Result<T, E>) -> Option<T> {
+        self.register();
+        if !self.is_available() {
+            return None;
+        }
+
+        let slot = &ACTIVE[arch::cpu_index().min(MAX_CPUS - 1)];
+        let previous = slot.swap(self as *const Feature as *mut Feature, Ordering::Relaxed);
+        let result = work();
+        slot.store(previous, Ordering::Relaxed);
+
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.poison(format!("{e:?}"));
+                None
+            }
+        }
+    }
+
+    /// Turn the feature off for the rest of the boot.
+    pub fn poison(&'static self, reason: String) {
+        self.register();
+        error!("Disabling feature `{}` ({}): {reason}", self.name, self.description);
+
+        let mut current = self.reason.lock();
+        if current.is_none() {
+            *current = Some(reason);
+        }
+        self.poisoned.store(true, Ordering::Release);
+    }
+
+    fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            FEATURES.lock().push(self);
+        }
+    }
+}
+
+/// The features that were initialized or used, in that order.
+pub fn all() -> Vec<&'static Feature> {
+    FEATURES.lock().clone()
+}
+
+/// The feature whose closure is running on this CPU, for the panic report.
+///
+/// Must not block or allocate.
+pub fn active() -> Option<&'static Feature> {
+    let feature = ACTIVE[arch::cpu_index().min(MAX_CPUS - 1)].load(Ordering::Relaxed);
+    unsafe { feature.as_ref() }
+}
@@ -0,0 +1,225 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! ELF core dumps, which `gdb` loads next to the executable for debugging a
+//! crash offline:
+//! ```shell
+//! cargo run core target/serial.log target/core
+//! gdb <kernel> target/core
+//! ```
+//!
+//! A dump consists of the memory segments worth keeping (e.g. the stack) and
+//! the register state, as the `NT_PRSTATUS` note that Linux writes. A
+//! [process](crate::process) that faults dumps the memory it had mapped and
+//! the registers the CPU pushed, and the `coredump` shell command dumps the
+//! stack of the kernel itself. Both are streamed over the serial port as
+//! `@core <hex>` lines, like the packet capture, of which the extractor keeps
+//! the last dump.
+//!
+//! ### References:
+//! - [System V ABI: Core Files](https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.intro.html)
+//! - [Linux: `include/uapi/linux/elfcore.h`](https://github.com/torvalds/linux/blob/master/include/uapi/linux/elfcore.h)
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use crate::{net::pcap::Hex, serial_println};
+
+/// The marker of the hex encoded lines on the serial port, which the host-side
+/// extractor looks for.
+pub const SERIAL_MARKER: &str = "@core ";
+
+/// The number of bytes per line on the serial port.
+const SERIAL_CHUNK_SIZE: usize = 64;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_GNU_BUILD_ID: u32 = 3;
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_REGISTERS_OFFSET: usize = 112;
+const PRPSINFO_SIZE: usize = 136;
+
+pub const SEGMENT_READ: u32 = 1 << 2;
+pub const SEGMENT_WRITE: u32 = 1 << 1;
+pub const SEGMENT_EXECUTE: u32 = 1 << 0;
+
+/// The general purpose registers, in the order of `user_regs_struct`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+impl Registers {
+    /// The registers needed for a backtrace at the call site: the instruction,
+    /// stack and frame pointers, read at the same point.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rip, rsp, rbp): (u64, u64, u64);
+        unsafe {
+            asm!("lea {rip}, [rip]", "mov {rsp}, rsp", "mov {rbp}, rbp",
+                 rip = out(reg) rip, rsp = out(reg) rsp, rbp = out(reg) rbp,
+                 options(nomem, nostack, preserves_flags));
+        }
+
+        Self { rip, rsp, rbp, ..Self::default() }
+    }
+
+    fn as_array(&self) -> [u64; 27] {
+        [
+            self.r15, self.r14, self.r13, self.r12, self.rbp, self.rbx, self.r11, self.r10, self.r9,
+            self.r8, self.rax, self.rcx, self.rdx, self.rsi, self.rdi, self.orig_rax, self.rip, self.cs,
+            self.rflags, self.rsp, self.ss, self.fs_base, self.gs_base, self.ds, self.es, self.fs, self.gs,
+        ]
+    }
+}
+
+pub struct Segment<'a> {
+    pub virtual_address: u64,
+    pub data: &'a [u8],
+
+    /// The `SEGMENT_*` permissions.
+    pub flags: u32,
+}
+
+pub struct CoreDump<'a> {
+    /// The name of the program, at most 15 bytes are kept.
+    pub name: &'a str,
+    pub pid: u32,
+
+    /// The signal that terminated the program, zero for a snapshot.
+    pub signal: u32,
+    pub registers: Registers,
+    pub segments: Vec<Segment<'a>>,
+    pub build_id: Option<&'a [u8]>,
+}
+
+impl CoreDump<'_> {
+    /// Write the dump in pieces, so the segments don't have to be copied.
+    pub fn write(&self, sink: &mut dyn FnMut(&[u8])) {
+        let notes = self.notes();
+        let header_count = 1 + self.segments.len();
+        let notes_offset = ELF_HEADER_SIZE + header_count * PROGRAM_HEADER_SIZE;
+
+        let mut headers = Vec::with_capacity(notes_offset);
+        headers.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        headers.resize(16, 0);
+        headers.extend_from_slice(&ET_CORE.to_le_bytes());
+        headers.extend_from_slice(&EM_X86_64.to_le_bytes());
+        headers.extend_from_slice(&1u32.to_le_bytes());
+        headers.extend_from_slice(&0u64.to_le_bytes());
+        headers.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+        headers.extend_from_slice(&0u64.to_le_bytes());
+        headers.extend_from_slice(&0u32.to_le_bytes());
+        headers.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        headers.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        headers.extend_from_slice(&(header_count as u16).to_le_bytes());
+        headers.extend_from_slice(&[0; 6]);
+
+        program_header(&mut headers, PT_NOTE, 0, notes_offset, 0, notes.len());
+
+        let mut offset = notes_offset + notes.len();
+        for segment in &self.segments {
+            program_header(&mut headers, PT_LOAD, segment.flags, offset, segment.virtual_address, segment.data.len());
+            offset += segment.data.len();
+        }
+
+        sink(&headers);
+        sink(&notes);
+        for segment in &self.segments {
+            sink(segment.data);
+        }
+    }
+
+    fn notes(&self) -> Vec<u8> {
+        let mut status = [0u8; PRSTATUS_SIZE];
+        status[0..4].copy_from_slice(&self.signal.to_le_bytes());
+        status[12..14].copy_from_slice(&(self.signal as u16).to_le_bytes());
+        status[32..36].copy_from_slice(&self.pid.to_le_bytes());
+        for (index, register) in self.registers.as_array().iter().enumerate() {
+            let offset = PRSTATUS_REGISTERS_OFFSET + index * 8;
+            status[offset..offset + 8].copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut info = [0u8; PRPSINFO_SIZE];
+        info[24..28].copy_from_slice(&self.pid.to_le_bytes());
+        let name = &self.name.as_bytes()[..self.name.len().min(15)];
+        info[40..40 + name.len()].copy_from_slice(name);
+        info[56..56 + name.len()].copy_from_slice(name);
+
+        let mut notes = Vec::new();
+        note(&mut notes, b"CORE", NT_PRSTATUS, &status);
+        note(&mut notes, b"CORE", NT_PRPSINFO, &info);
+        if let Some(build_id) = self.build_id {
+            note(&mut notes, b"GNU", NT_GNU_BUILD_ID, build_id);
+        }
+        notes
+    }
+
+    /// Stream the dump over the serial port as `@core <hex>` lines.
+    pub fn write_to_serial(&self) -> usize {
+        let mut size = 0;
+        self.write(&mut |data| {
+            for chunk in data.chunks(SERIAL_CHUNK_SIZE) {
+                serial_println!("{SERIAL_MARKER}{}", Hex(chunk));
+            }
+            size += data.len();
+        });
+        size
+    }
+}
+
+fn program_header(headers: &mut Vec<u8>, kind: u32, flags: u32, offset: usize, address: u64, size: usize) {
+    headers.extend_from_slice(&kind.to_le_bytes());
+    headers.extend_from_slice(&flags.to_le_bytes());
+    headers.extend_from_slice(&(offset as u64).to_le_bytes());
+    headers.extend_from_slice(&address.to_le_bytes());
+    headers.extend_from_slice(&0u64.to_le_bytes());
+    headers.extend_from_slice(&(size as u64).to_le_bytes());
+    headers.extend_from_slice(&(size as u64).to_le_bytes());
+    headers.extend_from_slice(&(if kind == PT_LOAD { 4096u64 } else { 4 }).to_le_bytes());
+}
+
+fn note(notes: &mut Vec<u8>, name: &[u8], kind: u32, descriptor: &[u8]) {
+    // The name is NUL-terminated, and both are padded to 4 bytes.
+    notes.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    notes.extend_from_slice(&(descriptor.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&kind.to_le_bytes());
+    notes.extend_from_slice(name);
+    notes.push(0);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+    notes.extend_from_slice(descriptor);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+}
@@ -0,0 +1,112 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Named event counters, e.g. of interrupts or packets.
+//!
+//! A [`Counter`] is declared as a static next to the code it counts, and is
+//! registered the first time it is counted, after which it shows up in the
+//! `counters` shell command and the `status` reply of the guest agent:
+//! ```ignore
+//! static DROPPED: Counter = Counter::new("ps2.dropped", "Bytes dropped by the interrupt handler");
+//!
+//! DROPPED.increment();
+//! ```
+//!
+//! Counting is wait-free, so it can be done from interrupt handlers: every CPU
+//! has its own slot, which is only written by that CPU, and reading a counter
+//! sums the slots. The registered counters form an intrusive linked list,
+//! which is pushed to with a compare-and-swap instead of a lock.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::arch;
+
+/// The number of CPUs that have their own slot, the others share the last.
+pub const MAX_CPUS: usize = 8;
+
+/// The most recently registered counter.
+static HEAD: AtomicPtr<Counter> = AtomicPtr::new(ptr::null_mut());
+
+pub struct Counter {
+    name: &'static str,
+    description: &'static str,
+    per_cpu: [AtomicU64; MAX_CPUS],
+    registered: AtomicBool,
+    next: AtomicPtr<Counter>,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, description: &'static str) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            name,
+            description,
+            per_cpu: [ZERO; MAX_CPUS],
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    /// Must not block or allocate.
+    pub fn add(&'static self, amount: u64) {
+        self.per_cpu[arch::cpu_index().min(MAX_CPUS - 1)].fetch_add(amount, Ordering::Relaxed);
+
+        if !self.registered.load(Ordering::Relaxed) {
+            self.register();
+        }
+    }
+
+    /// Must not block or allocate.
+    pub fn increment(&'static self) {
+        self.add(1);
+    }
+
+    /// The sum of the slots of every CPU.
+    pub fn value(&self) -> u64 {
+        self.per_cpu.iter().map(|slot| slot.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Make the counter show up before it is first counted.
+    pub fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self as *const Self as *mut Self;
+        let mut head = HEAD.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match HEAD.compare_exchange_weak(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// The registered counters, sorted by name.
+pub fn all() -> alloc::vec::Vec<&'static Counter> {
+    let mut counters = alloc::vec::Vec::new();
+
+    let mut current = HEAD.load(Ordering::Acquire);
+    while let Some(counter) = unsafe { current.as_ref() } {
+        counters.push(counter);
+        current = counter.next.load(Ordering::Acquire);
+    }
+
+    counters.sort_by_key(|counter| counter.name);
+    counters
+}
@@ -0,0 +1,191 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Accounting of the CPU time, which attributes the cycles of the CPUs to:
+//!
+//! | Account    | Measured                                                          |
+//! |------------|-------------------------------------------------------------------|
+//! | Tasks      | The polls of every task by the executor                           |
+//! | Interrupts | The handlers measured by [`irq_latency`](super::irq_latency)      |
+//! | Idle       | The executor waiting for an interrupt                             |
+//! | Other      | The rest, e.g. the executor itself and flushing the serial port   |
+//!
+//! The handlers that run during a poll or while idle are subtracted from
+//! those (see [`Interval`]), so no cycle is counted twice. The handler shared
+//! by the serial ports is counted as both interrupts though.
+//!
+//! The totals only grow: [`Usage::between`] turns two [`Snapshot`]s into the
+//! usage of that period, which is shown by the `top` shell command and the
+//! health report (see [`health`](super::health)). The time of tasks that
+//! finished during the period counts as other.
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{arch, task::inspector};
+
+use super::{counters::MAX_CPUS, irq_latency::{self, Irq}};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// The cycles of every CPU spent idle, the other CPUs share the last slot
+/// like the counters.
+static IDLE: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+/// The cycles of every CPU spent in the measured interrupt handlers.
+static HANDLERS: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+/// The number of measured handlers running on every CPU, which is more than
+/// one when a handler measures itself as multiple interrupts.
+static DEPTH: [AtomicUsize; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; MAX_CPUS]
+};
+
+fn slot() -> usize {
+    arch::cpu_index().min(MAX_CPUS - 1)
+}
+
+/// A period on this CPU, without the interrupt handlers that ran meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    start: u64,
+    handlers: u64,
+}
+
+impl Interval {
+    /// Must not block or allocate.
+    pub fn start() -> Self {
+        Self {
+            handlers: HANDLERS[slot()].load(Ordering::Relaxed),
+            start: arch::cycles(),
+        }
+    }
+
+    /// The cycles since the start, minus those of the handlers.
+    ///
+    /// Must not block or allocate.
+    pub fn elapsed(&self) -> u64 {
+        let cycles = arch::cycles().wrapping_sub(self.start);
+        let handlers = HANDLERS[slot()].load(Ordering::Relaxed).wrapping_sub(self.handlers);
+        cycles.saturating_sub(handlers)
+    }
+}
+
+/// Called when a handler starts being measured. Returns whether it's the
+/// outermost one, which should be passed to [`leave_handler`].
+///
+/// Must not block or allocate.
+pub(crate) fn enter_handler() -> bool {
+    DEPTH[slot()].fetch_add(1, Ordering::Relaxed) == 0
+}
+
+/// Must not block or allocate.
+pub(crate) fn leave_handler(outermost: bool, cycles: u64) {
+    let slot = slot();
+    DEPTH[slot].fetch_sub(1, Ordering::Relaxed);
+    if outermost {
+        HANDLERS[slot].fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+/// Called by the executor after waiting for an interrupt.
+///
+/// Must not block or allocate.
+pub(crate) fn add_idle(interval: Interval) {
+    IDLE[slot()].fetch_add(interval.elapsed(), Ordering::Relaxed);
+}
+
+/// The totals of every account at a point in time. The default is when the
+/// machine started.
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    cycles: u64,
+
+    /// The ID, name and cycles of every task.
+    tasks: Vec<(u64, &'static str, u64)>,
+    irqs: [u64; Irq::ALL.len()],
+    idle: u64,
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        cycles: arch::cycles(),
+        tasks: inspector::task_cycles(),
+        irqs: Irq::ALL.map(irq_latency::total_cycles),
+        idle: IDLE.iter().map(|idle| idle.load(Ordering::Relaxed)).sum(),
+    }
+}
+
+/// The cycles of every account during a period, see [`Usage::between`].
+#[derive(Debug, Clone)]
+pub struct Usage {
+    /// The cycles of all CPUs in the period.
+    pub total: u64,
+
+    /// The tasks and interrupts that used any cycles, the most first.
+    pub tasks: Vec<(&'static str, u64)>,
+    pub irqs: Vec<(Irq, u64)>,
+    pub idle: u64,
+}
+
+impl Usage {
+    pub fn between(earlier: &Snapshot, later: &Snapshot) -> Self {
+        let mut tasks: Vec<_> = later.tasks.iter()
+            .map(|(id, name, cycles)| {
+                let before = earlier.tasks.iter()
+                    .find(|(earlier_id, _, _)| earlier_id == id)
+                    .map_or(0, |(_, _, cycles)| *cycles);
+                (*name, cycles - before)
+            })
+            .filter(|(_, cycles)| *cycles != 0)
+            .collect();
+        tasks.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut irqs: Vec<_> = Irq::ALL.into_iter()
+            .map(|irq| (irq, later.irqs[irq as usize] - earlier.irqs[irq as usize]))
+            .filter(|(_, cycles)| *cycles != 0)
+            .collect();
+        irqs.sort_by(|a, b| b.1.cmp(&a.1));
+
+        Self {
+            total: later.cycles.wrapping_sub(earlier.cycles) * arch::cpu_count() as u64,
+            tasks,
+            irqs,
+            idle: later.idle - earlier.idle,
+        }
+    }
+
+    pub fn task_cycles(&self) -> u64 {
+        self.tasks.iter().map(|(_, cycles)| cycles).sum()
+    }
+
+    pub fn irq_cycles(&self) -> u64 {
+        self.irqs.iter().map(|(_, cycles)| cycles).sum()
+    }
+
+    /// The cycles that aren't attributed to any account.
+    pub fn other_cycles(&self) -> u64 {
+        self.total.saturating_sub(self.task_cycles() + self.irq_cycles() + self.idle)
+    }
+
+    /// The cycles as a share of the total.
+    pub fn share(&self, cycles: u64) -> Share {
+        Share((cycles as u128 * 1000 / self.total.max(1) as u128) as u64)
+    }
+}
+
+/// A share of the CPU time in tenths of a percent, shown as e.g. `12.5%`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Share(pub u64);
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&alloc::format!("{}.{}%", self.0 / 10, self.0 % 10))
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A periodic report of the health of the kernel in the log, so a regression
+//! shows up there before the system feels slow:
+//! ```text
+//! Health: heap 212/1024 KiB, CPU idle 97.9%, tasks 1.2% (shell 0.8%, ps2 0.2%, status-bar 0.1%), irq 0.6% (timer 0.5%), other 0.3%
+//! ```
+//!
+//! The CPU usage is of the period since the previous report (see
+//! [`cpu_usage`](super::cpu_usage)). The report is written every
+//! `health.interval` seconds, which is a minute by default, and turned off
+//! with `0`.
+
+use alloc::string::String;
+use core::{fmt::Write, time::Duration};
+
+use log::info;
+
+use crate::{allocator, task::timer};
+
+use super::{config, cpu_usage::{self, Usage}};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of tasks and interrupts that are named in the report.
+const TOP_CONSUMERS: usize = 3;
+
+/// The task that writes the report.
+pub async fn run() {
+    let mut last = cpu_usage::snapshot();
+
+    loop {
+        let interval = interval();
+        timer::sleep(interval.unwrap_or(DEFAULT_INTERVAL)).await;
+        if interval.is_none() {
+            continue;
+        }
+
+        let now = cpu_usage::snapshot();
+        info!("Health: {}", report(&Usage::between(&last, &now)));
+        last = now;
+    }
+}
+
+/// The interval from the configuration, `None` when the report is off.
+fn interval() -> Option<Duration> {
+    match config::get(config::KEY_HEALTH_INTERVAL).and_then(|seconds| seconds.parse().ok()) {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(DEFAULT_INTERVAL),
+    }
+}
+
+fn report(usage: &Usage) -> String {
+    let heap = allocator::statistics();
+
+    let mut text = String::new();
+    _ = write!(text, "heap {}/{} KiB, CPU idle {}, tasks {} (",
+        heap.used / 1024, heap.size / 1024,
+        usage.share(usage.idle),
+        usage.share(usage.task_cycles()));
+    for (index, (name, cycles)) in usage.tasks.iter().take(TOP_CONSUMERS).enumerate() {
+        let separator = if index == 0 { "" } else { ", " };
+        _ = write!(text, "{separator}{name} {}", usage.share(*cycles));
+    }
+
+    _ = write!(text, "), irq {} (", usage.share(usage.irq_cycles()));
+    for (index, (irq, cycles)) in usage.irqs.iter().take(TOP_CONSUMERS).enumerate() {
+        let separator = if index == 0 { "" } else { ", " };
+        _ = write!(text, "{separator}{} {}", irq.name(), usage.share(*cycles));
+    }
+
+    _ = write!(text, "), other {}", usage.share(usage.other_cycles()));
+    text
+}
@@ -0,0 +1,103 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Self-hosted documentation, so the growing set of shell commands and knobs
+//! stays discoverable from within the running system.
+//!
+//! The shell commands describe their arguments next to their handlers (see
+//! [`shell`](crate::task::shell)), and the subsystems describe their knobs
+//! with a [`Topic`] constant, which is listed in [`TOPICS`]. Both are shown by
+//! `help <name>`, and dumped as a single JSON object by `help json` and the
+//! `help` command of the [guest agent](crate::device::guest_agent), for the
+//! host tooling:
+//!
+//! ```json
+//! {"commands":[{"name":"get","usage":"get [key]","description":"…","arguments":[{"name":"key","description":"…"}]}],
+//!  "topics":[{"name":"params","summary":"…","entries":[…]}]}
+//! ```
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::{config, BootParameters};
+
+/// A documented name: an argument of a command, or an entry of a topic.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+impl Entry {
+    pub const fn new(name: &'static str, description: &'static str) -> Self {
+        Self { name, description }
+    }
+}
+
+/// The documentation of a subsystem, e.g. the boot parameters it reads.
+#[derive(Debug, Clone, Copy)]
+pub struct Topic {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub entries: &'static [Entry],
+}
+
+/// The topics shown by `help`, besides the commands.
+pub const TOPICS: &[&Topic] = &[
+    &BootParameters::TOPIC,
+    &config::TOPIC,
+];
+
+#[must_use]
+pub fn find_topic(name: &str) -> Option<&'static Topic> {
+    TOPICS.iter().copied().find(|topic| topic.name == name)
+}
+
+/// Write the string as a JSON string, with the quotes.
+pub fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            character if character.is_control() => _ = write!(out, "\\u{:04x}", character as u32),
+            character => out.push(character),
+        }
+    }
+    out.push('"');
+}
+
+/// Write the entries as a JSON array of `{"name", "description"}` objects.
+pub fn write_entries_json(out: &mut String, entries: &[Entry]) {
+    out.push('[');
+    for (index, entry) in entries.iter().enumerate() {
+        if index != 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        write_json_string(out, entry.name);
+        out.push_str(",\"description\":");
+        write_json_string(out, entry.description);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+/// Write [`TOPICS`] as a JSON array.
+pub fn write_topics_json(out: &mut String) {
+    out.push('[');
+    for (index, topic) in TOPICS.iter().enumerate() {
+        if index != 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        write_json_string(out, topic.name);
+        out.push_str(",\"summary\":");
+        write_json_string(out, topic.summary);
+        out.push_str(",\"entries\":");
+        write_entries_json(out, topic.entries);
+        out.push('}');
+    }
+    out.push(']');
+}
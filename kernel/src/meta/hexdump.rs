@@ -0,0 +1,168 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Hexdumps of memory, for the `hexdump` shell command and for the log:
+//! ```ignore
+//! trace!("Ring buffer:\n{}", HexDump::virt(ring.virt, 64));
+//! ```
+//! Every line of 16 bytes is annotated with the other address of the bytes
+//! (physical for a virtual dump, and the other way around), and within the
+//! kernel image, the symbol they belong to. The pages are probed before they
+//! are read, so a page that isn't mapped is reported instead of faulting.
+//!
+//! Probing uses the page table mapper, so a [`HexDump`] of memory can't be
+//! formatted while holding it. Reading device memory may have side effects,
+//! which is up to the caller.
+
+use core::fmt::{Display, Formatter};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::arch::memory;
+
+use super::symbols;
+
+const BYTES_PER_LINE: u64 = 16;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+enum Source<'a> {
+    Bytes(&'a [u8]),
+    Virtual,
+    Physical,
+}
+
+/// Formats memory as a hexdump, see the [module](self) documentation.
+#[derive(Debug, Clone, Copy)]
+pub struct HexDump<'a> {
+    source: Source<'a>,
+
+    /// The address of the first byte, which for a buffer is just the offset
+    /// that is shown.
+    start: u64,
+    length: usize,
+}
+
+/// Where the bytes of a line can be read.
+struct Line {
+    /// The pointer to the (aligned) start of the line.
+    base: *const u8,
+
+    /// The other address of the start of the line.
+    other: Option<(&'static str, u64)>,
+}
+
+impl<'a> HexDump<'a> {
+    /// Dump a buffer, numbering the bytes from `offset`.
+    pub fn bytes(data: &'a [u8], offset: u64) -> Self {
+        Self { source: Source::Bytes(data), start: offset, length: data.len() }
+    }
+
+    pub fn virt(start: VirtAddr, length: usize) -> Self {
+        Self { source: Source::Virtual, start: start.as_u64(), length }
+    }
+
+    /// Dump physical memory, through the mapping of the physical memory.
+    pub fn physical(start: PhysAddr, length: usize) -> Self {
+        Self { source: Source::Physical, start: start.as_u64(), length }
+    }
+
+    fn end(&self) -> u64 {
+        self.start.saturating_add(self.length as u64)
+    }
+
+    /// Probe the line starting at the address, returning `None` when it isn't
+    /// mapped.
+    fn line(&self, address: u64) -> Option<Line> {
+        match self.source {
+            Source::Bytes(data) => Some(Line {
+                base: data.as_ptr().wrapping_add(address.wrapping_sub(self.start) as usize),
+                other: None,
+            }),
+
+            Source::Virtual => {
+                let virt = VirtAddr::try_new(address).ok()?;
+                let physical = memory::translate(virt)?;
+                Some(Line { base: virt.as_ptr(), other: Some(("phys", physical.as_u64())) })
+            }
+
+            Source::Physical => {
+                let virt = memory::physical_to_virtual(PhysAddr::try_new(address).ok()?)?;
+                Some(Line { base: virt.as_ptr(), other: Some(("virt", virt.as_u64())) })
+            }
+        }
+    }
+
+    /// Find the end of the unmapped pages starting at the address.
+    fn unmapped_until(&self, address: u64) -> u64 {
+        let mut end = address;
+        while end < self.end() && self.line(end).is_none() {
+            end = (end | (PAGE_SIZE - 1)) + 1;
+        }
+        end.min(self.end())
+    }
+}
+
+impl Display for HexDump<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let end = self.end();
+        let first = self.start & !(BYTES_PER_LINE - 1);
+        let mut address = first;
+        let mut last_symbol = None;
+
+        while address < end {
+            if address != first {
+                f.write_str("\n")?;
+            }
+
+            let Some(line) = self.line(address) else {
+                let until = self.unmapped_until(address);
+                write!(f, "{address:#018x}  not mapped until {until:#018x}")?;
+                address = until.next_multiple_of(BYTES_PER_LINE);
+                continue;
+            };
+
+            let in_range = |index: u64| (self.start..end).contains(&(address + index));
+            let read = |index: u64| unsafe { line.base.wrapping_add(index as usize).read_volatile() };
+
+            write!(f, "{address:#018x} ")?;
+            for index in 0..BYTES_PER_LINE {
+                if index == BYTES_PER_LINE / 2 {
+                    f.write_str(" ")?;
+                }
+                if in_range(index) {
+                    write!(f, " {:02x}", read(index))?;
+                } else {
+                    f.write_str("   ")?;
+                }
+            }
+
+            f.write_str("  |")?;
+            for index in 0..BYTES_PER_LINE {
+                let c = match in_range(index).then(|| read(index)) {
+                    Some(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                    Some(_) => '.',
+                    None => ' ',
+                };
+                write!(f, "{c}")?;
+            }
+            f.write_str("|")?;
+
+            if let Some((name, other)) = line.other {
+                write!(f, "  {name} {other:#x}")?;
+            }
+
+            if let Source::Virtual = self.source {
+                let symbol = symbols::resolve_containing(address.max(self.start));
+                if let Some(symbol) = symbol.filter(|symbol| Some(symbol.name) != last_symbol) {
+                    write!(f, "  <{symbol}>")?;
+                }
+                last_symbol = symbol.map(|symbol| symbol.name);
+            }
+
+            address += BYTES_PER_LINE;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Keeps track of which subsystems are initialized, so calling an init
+//! function twice (or in the wrong order) is reported right away, naming the
+//! subsystem, instead of showing up later as a confusing `unwrap` or as
+//! aliasing page tables.
+//!
+//! The subsystems that others depend on hand out a token when initialized,
+//! such as [`HeapInitialized`], which the init functions of the dependents
+//! take as an argument. A token can only be created by marking its subsystem
+//! as initialized, so calling a dependent too early doesn't compile, and
+//! creating a token anywhere else panics, since the subsystem was marked
+//! already.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+static INITIALIZED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Subsystem {
+    Logging,
+    Mapper,
+    FrameAllocator,
+    Heap,
+    MemoryMap,
+    ScancodeQueue,
+}
+
+impl Subsystem {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Logging => "the logger",
+            Self::Mapper => "the page table mapper",
+            Self::FrameAllocator => "the frame allocator",
+            Self::Heap => "the heap",
+            Self::MemoryMap => "the memory map",
+            Self::ScancodeQueue => "the scancode queue",
+        }
+    }
+
+    const fn bit(&self) -> u32 {
+        1 << *self as u32
+    }
+}
+
+/// Proof that the page table mapper is initialized.
+#[derive(Debug, Clone, Copy)]
+pub struct MapperInitialized(());
+
+/// Proof that the frame allocator is initialized.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameAllocatorInitialized(());
+
+/// Proof that the heap is initialized, i.e. that `alloc` can be used.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapInitialized(());
+
+impl MapperInitialized {
+    #[track_caller]
+    pub fn mark() -> Self {
+        mark(Subsystem::Mapper);
+        Self(())
+    }
+}
+
+impl FrameAllocatorInitialized {
+    #[track_caller]
+    pub fn mark() -> Self {
+        mark(Subsystem::FrameAllocator);
+        Self(())
+    }
+}
+
+impl HeapInitialized {
+    #[track_caller]
+    pub fn mark() -> Self {
+        mark(Subsystem::Heap);
+        Self(())
+    }
+}
+
+/// Record that a subsystem is being initialized, panicking when it already
+/// was.
+#[track_caller]
+pub fn mark(subsystem: Subsystem) {
+    let bit = subsystem.bit();
+    if INITIALIZED.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+        panic!("{} is initialized twice", subsystem.name());
+    }
+}
+
+#[must_use]
+pub fn is_initialized(subsystem: Subsystem) -> bool {
+    INITIALIZED.load(Ordering::Acquire) & subsystem.bit() != 0
+}
+
+/// Panic with a clear message when a subsystem isn't initialized yet, for the
+/// dependents that can't take a token.
+#[track_caller]
+pub fn require(subsystem: Subsystem, user: &str) {
+    if !is_initialized(subsystem) {
+        panic!("{user} needs {}, which isn't initialized yet", subsystem.name());
+    }
+}
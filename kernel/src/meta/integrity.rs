@@ -0,0 +1,109 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Periodic integrity checks in debug builds, driven by the timer. Memory
+//! corruption usually shows up as a fault long after (and far away from) the
+//! write that caused it, so the timer checks the structures that are easy to
+//! validate, and halts at the first one that's corrupted:
+//!
+//! | Check             | Corrupted when                                            |
+//! |-------------------|-----------------------------------------------------------|
+//! | Heap free lists   | A node is outside of the heap, misaligned, or in a cycle   |
+//! | Stack canaries    | The bottom of a registered stack lost its watermark       |
+//! | GDT and IDT       | The table moved, or its contents changed since [`init`]   |
+//!
+//! The tasks share the kernel stack, so the registered stacks are the kernel
+//! stack and the interrupt stacks, see [`super::stack`].
+//!
+//! The heap and the stacks are skipped on the ticks that interrupted the code
+//! holding their locks.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use x86_64::{instructions::tables, structures::DescriptorTablePointer};
+
+use crate::{allocator, meta::stack};
+
+/// Check ten times per second, since walking the free lists isn't cheap.
+const INTERVAL_TICKS: usize = crate::arch::TICKS_PER_SECOND / 10;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static GDT: TableChecksum = TableChecksum::new();
+static IDT: TableChecksum = TableChecksum::new();
+
+/// The location and checksum of a descriptor table, when [`init`] was called.
+struct TableChecksum {
+    base: AtomicU64,
+    limit: AtomicU32,
+    checksum: AtomicU32,
+}
+
+impl TableChecksum {
+    const fn new() -> Self {
+        Self {
+            base: AtomicU64::new(0),
+            limit: AtomicU32::new(0),
+            checksum: AtomicU32::new(0),
+        }
+    }
+
+    fn record(&self, pointer: DescriptorTablePointer) {
+        self.base.store(pointer.base.as_u64(), Ordering::Relaxed);
+        self.limit.store(pointer.limit as u32, Ordering::Relaxed);
+        self.checksum.store(checksum(&pointer), Ordering::Relaxed);
+    }
+
+    fn verify(&self, name: &str, pointer: DescriptorTablePointer) {
+        let base = self.base.load(Ordering::Relaxed);
+        let limit = self.limit.load(Ordering::Relaxed);
+        if pointer.base.as_u64() != base || pointer.limit as u32 != limit {
+            panic!("Integrity check failed: the {name} moved from {base:#x} (limit {limit:#x}) to {:#x} (limit {:#x})",
+                pointer.base.as_u64(), pointer.limit);
+        }
+
+        let expected = self.checksum.load(Ordering::Relaxed);
+        let actual = checksum(&pointer);
+        if actual != expected {
+            panic!("Integrity check failed: the {name} at {base:#x} changed, checksum {actual:#010x} instead of {expected:#010x}");
+        }
+    }
+}
+
+/// Record the descriptor tables, after they are loaded, and start checking.
+pub fn init() {
+    GDT.record(tables::sgdt());
+    IDT.record(tables::sidt());
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// Called by the timer interrupt handler with the new tick count. Panics
+/// when something is corrupted.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_tick(ticks: usize) {
+    if ticks % INTERVAL_TICKS != 0 || !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    if let Err(e) = allocator::try_check_free_lists() {
+        panic!("Integrity check failed: node {} of the free list of {}-byte blocks at {:#x} is {}",
+            e.position, e.block_size, e.address, e.reason);
+    }
+
+    if let Some((stack, address)) = stack::try_find_damaged_canary() {
+        panic!("Integrity check failed: the canary of the {} stack ({:?}..{:?}) is overwritten at {address:?}, found {:#018x}",
+            stack.name(), stack.top() - stack.size() as u64, stack.top(),
+            unsafe { address.as_ptr::<u64>().read_volatile() });
+    }
+
+    GDT.verify("GDT", tables::sgdt());
+    IDT.verify("IDT", tables::sidt());
+}
+
+/// The FNV-1a hash of the table.
+fn checksum(pointer: &DescriptorTablePointer) -> u32 {
+    let table = unsafe {
+        core::slice::from_raw_parts(pointer.base.as_ptr::<u8>(), pointer.limit as usize + 1)
+    };
+    table.iter().fold(0x811C_9DC5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
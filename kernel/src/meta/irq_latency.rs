@@ -0,0 +1,215 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The latency of the device interrupts, measured with the time stamp counter,
+//! to check that changes to the interrupt paths actually pay off:
+//!
+//! | Latency   | From                      | To                                                      |
+//! |-----------|---------------------------|---------------------------------------------------------|
+//! | `handler` | The entry of the handler  | The end of the handler                                  |
+//! | `wake`    | The entry of the handler  | The task consuming the data running, e.g. `ps2`         |
+//!
+//! A handler calls [`enter`] first, and [`woke_consumer`] when it woke the
+//! task, which calls [`consumed`] once it got the data. When the task is
+//! behind, the wake latency is of the oldest interrupt it hadn't consumed.
+//!
+//! The last [`SAMPLES`] of either are kept per interrupt, and are shown by the
+//! `irqstat` shell command and the `latency` command of the guest agent.
+//! Recording is wait-free, so it's safe in the interrupt handlers.
+//!
+//! The total time in each handler is kept as well, for the
+//! [`cpu_usage`](super::cpu_usage) accounting.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::arch;
+
+use super::cpu_usage;
+
+pub const SAMPLES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irq {
+    Timer,
+    Keyboard,
+    Serial,
+
+    /// The serial port of the guest agent.
+    Agent,
+
+    /// The message-signaled interrupts of PCI devices, together.
+    Msi,
+}
+
+impl Irq {
+    pub const ALL: [Irq; 5] = [Irq::Timer, Irq::Keyboard, Irq::Serial, Irq::Agent, Irq::Msi];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Timer => "timer",
+            Self::Keyboard => "keyboard",
+            Self::Serial => "serial",
+            Self::Agent => "agent",
+            Self::Msi => "msi",
+        }
+    }
+
+    fn latency(&self) -> &'static IrqLatency {
+        &LATENCIES[*self as usize]
+    }
+}
+
+static LATENCIES: [IrqLatency; Irq::ALL.len()] = [IrqLatency::new(), IrqLatency::new(), IrqLatency::new(), IrqLatency::new(), IrqLatency::new()];
+
+struct IrqLatency {
+    /// The cycle count at the entry of the handler that is running, or ran
+    /// last.
+    entry: AtomicU64,
+
+    /// The entry of the oldest interrupt the task hasn't consumed, or zero.
+    pending: AtomicU64,
+
+    /// The cycles spent in the handler so far.
+    total: AtomicU64,
+
+    handler: Samples,
+    wake: Samples,
+}
+
+impl IrqLatency {
+    const fn new() -> Self {
+        Self {
+            entry: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            handler: Samples::new(),
+            wake: Samples::new(),
+        }
+    }
+}
+
+/// A ring of the last [`SAMPLES`] latencies, in cycles.
+struct Samples {
+    /// The number of samples ever recorded.
+    count: AtomicUsize,
+    cycles: [AtomicU64; SAMPLES],
+}
+
+impl Samples {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            count: AtomicUsize::new(0),
+            cycles: [ZERO; SAMPLES],
+        }
+    }
+
+    fn record(&self, cycles: u64) {
+        let index = self.count.fetch_add(1, Ordering::Relaxed) % SAMPLES;
+        self.cycles[index].store(cycles, Ordering::Relaxed);
+    }
+
+    fn statistics(&self) -> LatencyStatistics {
+        let count = self.count.load(Ordering::Relaxed).min(SAMPLES);
+        let mut samples: Vec<u64> = self.cycles[..count].iter()
+            .map(|cycles| cycles.load(Ordering::Relaxed))
+            .collect();
+        samples.sort_unstable();
+
+        // The nearest-rank method.
+        let percentile = |percent: usize| match count {
+            0 => 0,
+            _ => samples[(count * percent).div_ceil(100) - 1],
+        };
+
+        LatencyStatistics {
+            samples: count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// The percentiles of the recent latencies, in cycles.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStatistics {
+    pub samples: usize,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStatistics {
+    pub handler: LatencyStatistics,
+    pub wake: LatencyStatistics,
+}
+
+/// Measures the handler until it's dropped, see [`enter`].
+pub(crate) struct HandlerMeasurement {
+    irq: Irq,
+    entry: u64,
+
+    /// Whether no other handler was being measured on this CPU.
+    outermost: bool,
+}
+
+impl Drop for HandlerMeasurement {
+    fn drop(&mut self) {
+        let cycles = arch::cycles() - self.entry;
+        let latency = self.irq.latency();
+        latency.handler.record(cycles);
+        latency.total.fetch_add(cycles, Ordering::Relaxed);
+        cpu_usage::leave_handler(self.outermost, cycles);
+    }
+}
+
+/// Called first by the interrupt handler, which is measured until the result
+/// is dropped.
+///
+/// Must not block or allocate.
+#[must_use]
+pub(crate) fn enter(irq: Irq) -> HandlerMeasurement {
+    let outermost = cpu_usage::enter_handler();
+    let entry = arch::cycles();
+    irq.latency().entry.store(entry, Ordering::Relaxed);
+    HandlerMeasurement { irq, entry, outermost }
+}
+
+/// Called by the interrupt handler after waking the task that consumes the
+/// data.
+///
+/// Must not block or allocate.
+pub(crate) fn woke_consumer(irq: Irq) {
+    let latency = irq.latency();
+    let entry = latency.entry.load(Ordering::Relaxed);
+    _ = latency.pending.compare_exchange(0, entry, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Called by the consuming task when it got the data of the interrupt.
+pub fn consumed(irq: Irq) {
+    let entry = irq.latency().pending.swap(0, Ordering::Relaxed);
+    if entry != 0 {
+        irq.latency().wake.record(arch::cycles() - entry);
+    }
+}
+
+/// The cycles spent in the handler of the interrupt so far.
+///
+/// Must not block or allocate.
+pub fn total_cycles(irq: Irq) -> u64 {
+    irq.latency().total.load(Ordering::Relaxed)
+}
+
+pub fn statistics(irq: Irq) -> IrqStatistics {
+    IrqStatistics {
+        handler: irq.latency().handler.statistics(),
+        wake: irq.latency().wake.statistics(),
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Logging from interrupt handlers, which can't take the locks of the logger
+//! or format into the serial port without clobbering what the interrupted
+//! code was writing.
+//!
+//! The message is a static string, and the values for its placeholders are
+//! plain numbers, so [`irq_log!`](crate::irq_log) only pushes a record into a
+//! lock-free queue. The `irq-log` task formats the records and hands them to
+//! the logger, in task context:
+//! ```ignore
+//! irq_log!(Level::Warn, "Spurious interrupt at {:#x}", stack_frame.instruction_pointer.as_u64());
+//! ```
+//!
+//! The placeholders are `{}` for decimal and `{:x}` or `{:#x}` for hexadecimal
+//! values. When the queue is full, or before it's initialized, the record is
+//! dropped and counted by the `irqlog.dropped` counter.
+
+use core::{
+    fmt::{Display, Formatter},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::Level;
+
+use super::{counters::Counter, init::HeapInitialized};
+
+/// The records waiting to be logged, which are small, so it can hold a burst.
+const QUEUE_CAPACITY: usize = 256;
+
+/// The number of values a single message can have.
+pub const MAX_VALUES: usize = 3;
+
+static RECORDS: OnceCell<ArrayQueue<Record>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static DROPPED: Counter = Counter::new("irqlog.dropped", "Interrupt log messages dropped because the queue was full");
+
+/// A message logged by [`irq_log!`](crate::irq_log), which is a static.
+pub struct Message {
+    pub level: Level,
+    pub target: &'static str,
+    pub text: &'static str,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    message: &'static Message,
+    values: [u64; MAX_VALUES],
+}
+
+pub fn init(_: HeapInitialized) {
+    RECORDS.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("irq_log::init should only be called once");
+}
+
+/// Queue a message, which is what [`irq_log!`](crate::irq_log) expands to.
+/// Values beyond [`MAX_VALUES`] are ignored.
+///
+/// Must not block or allocate.
+pub fn push(message: &'static Message, values: &[u64]) {
+    let mut record = Record { message, values: [0; MAX_VALUES] };
+    let count = values.len().min(MAX_VALUES);
+    record.values[..count].copy_from_slice(&values[..count]);
+
+    match RECORDS.try_get() {
+        Ok(queue) if queue.push(record).is_ok() => WAKER.wake(),
+        _ => DROPPED.increment(),
+    }
+}
+
+/// The task that formats and logs the queued messages.
+pub async fn run() {
+    DROPPED.register();
+    let mut dropped = DROPPED.value();
+
+    loop {
+        let record = poll_fn(poll_record).await;
+
+        let lost = DROPPED.value() - dropped;
+        if lost != 0 {
+            dropped += lost;
+            log::warn!("{lost} interrupt log messages were dropped");
+        }
+
+        log::log!(target: record.message.target, record.message.level, "{}", Formatted(&record));
+    }
+}
+
+fn poll_record(cx: &mut Context) -> Poll<Record> {
+    let queue = RECORDS.try_get().expect("irq_log queue not initialized");
+
+    if let Some(record) = queue.pop() {
+        return Poll::Ready(record);
+    }
+
+    WAKER.register(cx.waker());
+    match queue.pop() {
+        Some(record) => {
+            WAKER.take();
+            Poll::Ready(record)
+        }
+        None => Poll::Pending,
+    }
+}
+
+/// The message of a record, with the placeholders substituted.
+struct Formatted<'a>(&'a Record);
+
+impl Display for Formatted<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut values = self.0.values.iter();
+        let mut rest = self.0.message.text;
+
+        while let Some(start) = rest.find('{') {
+            let Some(length) = rest[start..].find('}') else {
+                break;
+            };
+
+            f.write_str(&rest[..start])?;
+            let value = values.next().copied().unwrap_or_default();
+            match &rest[start..=start + length] {
+                "{:x}" => write!(f, "{value:x}")?,
+                "{:#x}" => write!(f, "{value:#x}")?,
+                _ => write!(f, "{value}")?,
+            }
+            rest = &rest[start + length + 1..];
+        }
+
+        f.write_str(rest)
+    }
+}
+
+/// Log from an interrupt handler, see [`crate::meta::irq_log`].
+#[macro_export]
+macro_rules! irq_log {
+    ($level:expr, $text:literal $(, $value:expr)* $(,)?) => {{
+        static MESSAGE: $crate::meta::irq_log::Message = $crate::meta::irq_log::Message {
+            level: $level,
+            target: module_path!(),
+            text: $text,
+        };
+        $crate::meta::irq_log::push(&MESSAGE, &[$(($value) as u64),*]);
+    }};
+}
@@ -0,0 +1,198 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A registry of the regions of the physical address space and what they are
+//! used for, rendered by the `memmap` shell command.
+//!
+//! The memory map of the bootloader is registered at boot, after which the
+//! subsystems add the regions they claim (the ACPI tables, the APIC registers,
+//! etc.). The frames handed out by the frame allocator aren't registered, but
+//! are collected when the regions are queried, since they change all the time.
+
+use alloc::{string::String, vec::Vec};
+
+use bootloader_api::{info::MemoryRegionKind, BootInfo};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::arch::memory;
+
+use super::init::{self, HeapInitialized, Subsystem};
+
+static REGIONS: Mutex<Vec<Region>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    Usable,
+    Firmware,
+    Bootloader,
+
+    /// Frames handed out by the frame allocator, e.g. for the heap or page
+    /// tables.
+    Allocated,
+
+    /// Usable frames withheld from the frame allocator.
+    Reserved,
+    Kernel,
+    Acpi,
+    Mmio,
+}
+
+impl RegionKind {
+    pub const ALL: [Self; 8] = [
+        Self::Usable, Self::Firmware, Self::Bootloader, Self::Allocated, Self::Reserved, Self::Kernel, Self::Acpi,
+        Self::Mmio,
+    ];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Usable => "Usable",
+            Self::Firmware => "Firmware",
+            Self::Bootloader => "Bootloader",
+            Self::Allocated => "Allocated",
+            Self::Reserved => "Reserved",
+            Self::Kernel => "Kernel",
+            Self::Acpi => "ACPI",
+            Self::Mmio => "MMIO",
+        }
+    }
+
+    /// Whether the region comes from the memory map of the bootloader, as
+    /// opposed to being claimed by the kernel.
+    #[must_use]
+    pub const fn is_boot_region(&self) -> bool {
+        matches!(self, Self::Usable | Self::Firmware | Self::Bootloader)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+
+    /// Exclusive.
+    pub end: u64,
+    pub kind: RegionKind,
+    pub name: String,
+}
+
+impl Region {
+    #[must_use]
+    pub const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// Register the memory map of the bootloader, the kernel image and the
+/// framebuffer.
+pub fn init(boot_info: &'static BootInfo, _: HeapInitialized) {
+    init::mark(Subsystem::MemoryMap);
+
+    for region in boot_info.memory_regions.iter() {
+        let (kind, name) = match region.kind {
+            MemoryRegionKind::Usable => (RegionKind::Usable, "usable"),
+            MemoryRegionKind::Bootloader => (RegionKind::Bootloader, "bootloader"),
+            MemoryRegionKind::UnknownUefi(..) => (RegionKind::Firmware, "UEFI"),
+            MemoryRegionKind::UnknownBios(..) => (RegionKind::Firmware, "BIOS"),
+            _ => (RegionKind::Firmware, "unknown"),
+        };
+        register(region.start, region.end, kind, name);
+    }
+
+    register(boot_info.kernel_addr, boot_info.kernel_addr + boot_info.kernel_len, RegionKind::Kernel, "kernel image");
+
+    if let (Some(fb), Some(offset)) = (boot_info.framebuffer.as_ref(), boot_info.physical_memory_offset.as_ref()) {
+        let virt = VirtAddr::new(fb.buffer().as_ptr() as u64);
+        if let Some(phys) = unsafe { memory::translate_addr(virt, VirtAddr::new(*offset)) } {
+            let start = phys.as_u64();
+            register(start, start + fb.info().byte_len as u64, RegionKind::Mmio, "framebuffer");
+        }
+    }
+}
+
+/// Register a region of the physical address space, from `start` up to (but
+/// excluding) `end`.
+pub fn register(start: u64, end: u64, kind: RegionKind, name: &str) {
+    REGIONS.lock().push(Region {
+        start,
+        end,
+        kind,
+        name: name.into(),
+    });
+}
+
+/// The registered regions plus the allocated frames, sorted by address.
+pub fn regions() -> Vec<Region> {
+    let mut regions = REGIONS.lock().clone();
+
+    let mut allocated: Option<Region> = None;
+    memory::with_frame_allocator(|allocator| {
+        for frame in allocator.allocated_frames() {
+            let start = frame.start_address().as_u64();
+            let end = start + frame.size();
+
+            match allocated.as_mut() {
+                Some(region) if region.end == start => region.end = end,
+                _ => {
+                    regions.extend(allocated.take());
+                    allocated = Some(Region {
+                        start,
+                        end,
+                        kind: RegionKind::Allocated,
+                        name: "allocated frames".into(),
+                    });
+                }
+            }
+        }
+    });
+    regions.extend(allocated);
+
+    regions.sort_by_key(|region| (region.start, region.kind));
+    regions
+}
+
+/// The memory of the kernel that overlaps the range, i.e. the kernel image or
+/// the frames handed out by the frame allocator, which a mapping of the range
+/// would alias.
+pub fn kernel_memory_in(start: u64, end: u64) -> Option<Region> {
+    let kernel = REGIONS.lock().iter()
+        .find(|region| region.kind == RegionKind::Kernel && region.overlaps(start, end))
+        .cloned();
+    if kernel.is_some() {
+        return kernel;
+    }
+
+    memory::with_frame_allocator(|allocator| {
+        allocator.allocated_frames()
+            .map(|frame| (frame.start_address().as_u64(), frame.start_address().as_u64() + frame.size()))
+            .find(|(frame_start, frame_end)| *frame_start < end && start < *frame_end)
+            .map(|(start, end)| Region {
+                start,
+                end,
+                kind: RegionKind::Allocated,
+                name: "allocated frame".into(),
+            })
+    })
+}
+
+/// The regions claimed by the kernel that overlap memory the bootloader
+/// reported as usable, i.e. which the frame allocator might hand out again.
+pub fn conflicts(regions: &[Region]) -> Vec<(&Region, &Region)> {
+    let mut conflicts = Vec::new();
+
+    for claimed in regions.iter().filter(|region| !region.kind.is_boot_region()) {
+        // Allocated and reserved frames come from usable memory by
+        // definition.
+        if matches!(claimed.kind, RegionKind::Allocated | RegionKind::Reserved) {
+            continue;
+        }
+
+        for usable in regions.iter().filter(|region| region.kind == RegionKind::Usable) {
+            if claimed.overlaps(usable.start, usable.end) {
+                conflicts.push((claimed, usable));
+            }
+        }
+    }
+
+    conflicts
+}
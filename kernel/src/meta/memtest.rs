@@ -0,0 +1,141 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The memory test of the `memtest` boot parameter, which runs over the usable
+//! memory before the heap takes it, so the faulty frames can be excluded from
+//! the frame allocator:
+//!
+//! | Mode    | Tests                                                           |
+//! |---------|-----------------------------------------------------------------|
+//! | `quick` | Address-in-address, and its inverse                             |
+//! | `full`  | The above, and walking ones over every word (64 times slower)   |
+//!
+//! Address-in-address writes every word with its own address, and only checks
+//! them after the whole memory is written, which catches address lines that
+//! are stuck or shorted, as the writes to one address end up at another.
+//! Walking ones fills a frame with every single-bit pattern in turn, which
+//! catches data lines that are stuck or shorted.
+//!
+//! ### References:
+//! - [Memtest86+](https://www.memtest.org/)
+
+use core::ptr;
+
+use log::{info, warn};
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+use crate::arch::memory::{self, MAX_EXCLUDED_FRAMES};
+
+use super::{init::FrameAllocatorInitialized, BootParameters};
+
+const WORDS_PER_FRAME: usize = 4096 / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Quick,
+    Full,
+}
+
+/// The faulty frames found so far.
+struct BadFrames {
+    frames: [u64; MAX_EXCLUDED_FRAMES],
+    count: usize,
+
+    /// Faulty frames that didn't fit in the list.
+    overflow: usize,
+}
+
+impl BadFrames {
+    fn report(&mut self, address: u64, expected: u64, actual: u64) {
+        let frame = address & !0xFFF;
+        if self.frames[..self.count].contains(&frame) {
+            return;
+        }
+
+        warn!("Bad memory at {address:#x}: wrote {expected:#018x}, read {actual:#018x}");
+        if self.count == MAX_EXCLUDED_FRAMES {
+            self.overflow += 1;
+            return;
+        }
+
+        self.frames[self.count] = frame;
+        self.count += 1;
+    }
+}
+
+/// Test the usable memory if the `memtest` boot parameter asks for it, and
+/// exclude the faulty frames from the allocator.
+pub fn run(physical_memory_offset: VirtAddr, _: FrameAllocatorInitialized) {
+    let mode = match BootParameters::get("memtest") {
+        None => return,
+        Some("quick") => Mode::Quick,
+        Some("full") => Mode::Full,
+        Some(other) => {
+            warn!("Invalid memtest `{other}`, expected `quick` or `full`");
+            return;
+        }
+    };
+
+    let mut bad = BadFrames { frames: [0; MAX_EXCLUDED_FRAMES], count: 0, overflow: 0 };
+    let tested = memory::with_frame_allocator(|allocator| {
+        let offset = physical_memory_offset.as_u64();
+        let frames = || allocator.usable_frames().map(|frame| frame.start_address().as_u64());
+        info!("Testing {} MiB of memory ({mode:?})...", frames().count() * 4096 / 1024 / 1024);
+
+        for invert in [false, true] {
+            let pattern = |address: u64| if invert { !address } else { address };
+            for frame in frames() {
+                for word in 0..WORDS_PER_FRAME as u64 {
+                    let address = frame + word * 8;
+                    unsafe { ptr::write_volatile((offset + address) as *mut u64, pattern(address)) };
+                }
+            }
+
+            for frame in frames() {
+                for word in 0..WORDS_PER_FRAME as u64 {
+                    let address = frame + word * 8;
+                    let actual = unsafe { ptr::read_volatile((offset + address) as *const u64) };
+                    if actual != pattern(address) {
+                        bad.report(address, pattern(address), actual);
+                    }
+                }
+            }
+        }
+
+        if mode == Mode::Full {
+            for frame in frames() {
+                walking_ones(frame, offset, &mut bad);
+            }
+        }
+
+        frames().count()
+    });
+
+    memory::with_frame_allocator(|allocator| {
+        for &frame in &bad.frames[..bad.count] {
+            allocator.exclude(PhysFrame::containing_address(PhysAddr::new(frame)));
+        }
+    });
+
+    if bad.overflow != 0 {
+        warn!("{} more bad frames couldn't be excluded, the memory is unreliable", bad.overflow);
+    }
+    info!("Tested {tested} frames, excluded {} bad frames", bad.count);
+}
+
+fn walking_ones(frame: u64, offset: u64, bad: &mut BadFrames) {
+    let words = (offset + frame) as *mut u64;
+    for bit in 0..64 {
+        let pattern = 1u64 << bit;
+        for word in 0..WORDS_PER_FRAME {
+            unsafe { ptr::write_volatile(words.add(word), pattern) };
+        }
+
+        for word in 0..WORDS_PER_FRAME {
+            let actual = unsafe { ptr::read_volatile(words.add(word)) };
+            if actual != pattern {
+                bad.report(frame + word as u64 * 8, pattern, actual);
+            }
+        }
+    }
+}
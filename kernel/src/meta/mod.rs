@@ -3,13 +3,39 @@
 
 use bootloader_api::BootInfo;
 
+pub mod bench;
+pub mod config;
 mod console;
+pub mod containment;
+pub mod coredump;
+pub mod cpu_usage;
+pub mod hexdump;
+pub mod counters;
+pub mod health;
+pub mod help;
+pub mod init;
+#[cfg(debug_assertions)]
+pub mod integrity;
+pub mod irq_latency;
+pub mod irq_log;
+pub mod memory_map;
+pub mod memtest;
+pub mod panic;
+mod params;
+pub mod pstore;
+pub mod selftest;
+pub mod splash;
+pub mod stack;
 pub mod symbols;
 mod system;
+mod version;
 
-pub use self::console::Console;
-pub use self::system::System;
+pub use self::console::{Console, ConsoleRoute};
+pub use self::params::BootParameters;
+pub use self::system::{HypervisorKind, ShutdownKind, System};
+pub use self::version::version;
 
 pub fn init(boot_info: &'static BootInfo) {
     self::symbols::init(boot_info);
+    self::console::Console::init();
 }
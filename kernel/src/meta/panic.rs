@@ -0,0 +1,184 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! What to do after a kernel panic, selected with the `panic` boot parameter:
+//!
+//! | Value    | Behavior                                                      |
+//! |----------|---------------------------------------------------------------|
+//! | `halt`   | Halt forever (the default)                                    |
+//! | `reboot` | Reboot after `panic_delay` seconds (default 5)                |
+//! | `debug`  | Break into the debugger and park the CPU for inspection       |
+//!
+//! The panic message is written to every output we have (see [`SINKS`]),
+//! formatted once into a static buffer, since the heap (or the lock of an
+//! output) might be what caused the panic. It is also kept in the persistent
+//! store, to be reported by the next boot after a warm reboot.
+
+use core::{fmt::Write, panic::PanicInfo, sync::atomic::{AtomicUsize, Ordering}};
+
+use log::error;
+use spin::Mutex;
+
+use crate::{arch::{self, serial}, debug, device::guest_agent, hlt_loop, interrupt_println, net, vga_text_buffer};
+
+use super::{containment, pstore, splash, BootParameters, System};
+
+const DEFAULT_REBOOT_DELAY_SECONDS: usize = 5;
+
+const REPORT_CAPACITY: usize = 1024;
+
+/// The formatted panic message, shared by the sinks.
+static REPORT: Mutex<Report> = Mutex::new(Report {
+    bytes: [0; REPORT_CAPACITY],
+    len: 0,
+});
+
+/// The index of the sink being written to, to tell which one panicked.
+static CURRENT_SINK: AtomicUsize = AtomicUsize::new(0);
+
+/// An output the panic message is written to, which must not allocate and
+/// must give up instead of waiting for a lock.
+struct PanicSink {
+    name: &'static str,
+    write: fn(&str),
+}
+
+const SINKS: &[PanicSink] = &[
+    PanicSink {
+        name: "serial",
+        write: |message| {
+            interrupt_println!("[PANIC] {message}");
+        },
+    },
+    PanicSink {
+        name: "framebuffer",
+        write: |message| _ = vga_text_buffer::print_to_framebuffer(format_args!("[PANIC] {message}\n")),
+    },
+    PanicSink {
+        name: "guest agent",
+        write: guest_agent::send_panic,
+    },
+    PanicSink {
+        name: "network",
+        write: net::crash::send_panic,
+    },
+    PanicSink {
+        name: "persistent store",
+        write: pstore::record_panic,
+    },
+];
+
+/// A fixed-size buffer, which silently truncates what doesn't fit.
+struct Report {
+    bytes: [u8; REPORT_CAPACITY],
+    len: usize,
+}
+
+impl Report {
+    fn as_str(&self) -> &str {
+        // Only whole characters are appended.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut end = s.len().min(REPORT_CAPACITY - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    Halt,
+    Reboot { delay_seconds: usize },
+    Debug,
+}
+
+impl PanicPolicy {
+    pub fn from_boot_parameters() -> Self {
+        match BootParameters::get("panic") {
+            Some("reboot") => Self::Reboot {
+                delay_seconds: BootParameters::get("panic_delay")
+                    .and_then(|delay| delay.parse().ok())
+                    .unwrap_or(DEFAULT_REBOOT_DELAY_SECONDS),
+            },
+            Some("debug") => Self::Debug,
+            _ => Self::Halt,
+        }
+    }
+}
+
+/// Report the panic and carry out the configured policy.
+pub fn handle(info: &PanicInfo) -> ! {
+    // Make sure the panic is visible instead of hidden behind the splash,
+    // and don't rely on interrupts to transmit the serial output.
+    splash::finish();
+    serial::set_synchronous();
+
+    report(info);
+
+    match PanicPolicy::from_boot_parameters() {
+        PanicPolicy::Halt => {
+            error!("System halted (panic=halt)");
+            hlt_loop();
+        }
+
+        PanicPolicy::Reboot { delay_seconds } => {
+            error!("Rebooting in {delay_seconds} seconds (panic=reboot)");
+            wait(delay_seconds);
+            System::reboot();
+        }
+
+        PanicPolicy::Debug => {
+            error!("Waiting for a debugger (panic=debug), attach using `cargo run gdb` or `cargo run lldb`");
+            debug::magic_break!();
+
+            arch::disable_interrupts();
+            hlt_loop();
+        }
+    }
+}
+
+/// Write the panic message to every sink.
+fn report(info: &PanicInfo) {
+    // We panicked while reporting a panic, so only use the serial port.
+    let Some(mut report) = REPORT.try_lock() else {
+        let sink = SINKS[CURRENT_SINK.load(Ordering::Relaxed)].name;
+        interrupt_println!("[PANIC] (while writing to the {sink} sink) {info}");
+        return;
+    };
+
+    report.len = 0;
+    _ = write!(report, "{info}\n  in {}", super::version());
+    if let Some(feature) = containment::active() {
+        _ = write!(report, "\n  while running feature `{}`", feature.name());
+    }
+
+    for (index, sink) in SINKS.iter().enumerate() {
+        CURRENT_SINK.store(index, Ordering::Relaxed);
+        (sink.write)(report.as_str());
+    }
+}
+
+/// Wait using the PIT ticks, which doesn't use `pit::sleep` since that needs
+/// the timer wakers, which might be locked by the code that panicked.
+fn wait(seconds: usize) {
+    arch::enable_interrupts();
+
+    let Some(start) = arch::try_ticks() else {
+        // We panicked while holding the timer, so it won't advance anymore.
+        return;
+    };
+
+    let end = start + seconds * arch::TICKS_PER_SECOND;
+    while arch::ticks() < end {
+        arch::halt();
+    }
+}
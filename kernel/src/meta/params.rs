@@ -0,0 +1,113 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Boot parameters.
+//!
+//! The bootloader doesn't hand us a command line, so the parameters are baked
+//! into the image at build time using the `NOCCIOLO_BOOT_ARGS` environment
+//! variable, e.g.:
+//! ```shell
+//! NOCCIOLO_BOOT_ARGS="nosplash loglevel=debug" cargo run uefi
+//! ```
+//!
+//! Parameters are separated by whitespace and are either a `key=value` pair or
+//! a bare flag. A flag can be negated by prefixing it with `no`.
+//!
+//! Under QEMU, more parameters can be passed at run time through fw_cfg (see
+//! [`crate::device::fw_cfg`]). Those are only available once the heap is
+//! initialized, and take precedence over the baked-in parameters.
+
+use conquer_once::spin::OnceCell;
+
+use super::help::{Entry, Topic};
+
+static EXTRA_ARGS: OnceCell<&'static str> = OnceCell::uninit();
+
+const BOOT_ARGS: &str = match option_env!("NOCCIOLO_BOOT_ARGS") {
+    Some(args) => args,
+    None => "",
+};
+
+pub struct BootParameters;
+
+impl BootParameters {
+    /// The parameters that are read, shown by `help params`.
+    pub const TOPIC: Topic = Topic {
+        name: "params",
+        summary: "Boot parameters, from NOCCIOLO_BOOT_ARGS or the fw_cfg cmdline file",
+        entries: &[
+            Entry::new("ci", "Unattended: no confirmation prompts, and a shutdown watchdog"),
+            Entry::new("acpi_namespace=", "Export the AML namespace: serial, debugcon or fat:<file>"),
+            Entry::new("nosplash", "Don't show the boot splash, but log the initialization stages only"),
+            Entry::new("bootdelay=", "Seconds to wait during early initialization, e.g. to attach to it"),
+            Entry::new("console=", "Where the shell runs: fb (default), serial or both"),
+            Entry::new("clocksource=", "Timer for the tick, instead of the best one (see clocksource)"),
+            Entry::new("executor_threads=", "Worker loops of the task executor (default: one per CPU)"),
+            Entry::new("fontsize=", "Height of the console font in pixels: 16 (default), 24 or 32"),
+            Entry::new("lograte=", "Messages per second a module can log after a burst (0: no limit)"),
+            Entry::new("memtest=", "Test the memory before using it: quick or full (much slower)"),
+            Entry::new("nox2apic", "Keep the local APIC in xAPIC mode, even if x2APIC is supported"),
+            Entry::new("panic=", "What to do after a panic: halt (default), reboot or debug"),
+            Entry::new("panic_delay=", "Seconds to wait before rebooting with panic=reboot (default 5)"),
+            Entry::new("pcap=", "Capture the network packets: ring, serial or debugcon"),
+            Entry::new("pci=legacy", "Use the I/O ports for PCI, instead of ECAM (used if it checks out)"),
+            Entry::new("scancodeset=", "PS/2 scancode set: 2 (default, falls back to 1 if unsupported)"),
+            Entry::new("portaudit=", "Track I/O port accesses: count (see ports) or log (trace)"),
+            Entry::new("shutdown_timeout=", "Seconds until a stalled shutdown is forced (default 10 with ci)"),
+        ],
+    };
+
+    /// The raw, unparsed parameter string, which are the baked-in
+    /// parameters only.
+    pub fn raw() -> &'static str {
+        BOOT_ARGS
+    }
+
+    /// Append parameters that were received at run time. Can only be done
+    /// once.
+    pub fn extend(args: &'static str) {
+        if EXTRA_ARGS.try_init_once(|| args).is_err() {
+            log::warn!("Boot parameters were already extended, ignoring `{args}`");
+        }
+    }
+
+    pub fn iter() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+        let extra = EXTRA_ARGS.try_get().copied().unwrap_or("");
+
+        BOOT_ARGS.split_whitespace()
+            .chain(extra.split_whitespace())
+            .map(|param| match param.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (param, None),
+            })
+    }
+
+    /// Get the value of the last `key=value` parameter with the given key.
+    pub fn get(key: &str) -> Option<&'static str> {
+        Self::iter()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    /// Query a boolean flag. `splash`, `splash=1` and `splash=on` enable the
+    /// flag, while `nosplash`, `splash=0` and `splash=off` disable it. Returns
+    /// `None` if the flag wasn't specified.
+    pub fn flag(name: &str) -> Option<bool> {
+        let mut result = None;
+
+        for (key, value) in Self::iter() {
+            if key == name {
+                result = match value {
+                    None | Some("1" | "on" | "yes" | "true") => Some(true),
+                    Some("0" | "off" | "no" | "false") => Some(false),
+                    Some(..) => result,
+                };
+            } else if key.strip_prefix("no") == Some(name) && value.is_none() {
+                result = Some(false);
+            }
+        }
+
+        result
+    }
+}
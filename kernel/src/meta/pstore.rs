@@ -0,0 +1,218 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A persistent store for the log and the panic report, which survives a warm
+//! reboot, like `pstore` of Linux.
+//!
+//! A few frames at the end of the highest usable region are withheld from the
+//! frame allocator, which is the same place every boot, as long as the memory
+//! map doesn't change. Every log line is appended to a ring buffer in there,
+//! and a panic writes its report next to it, after which the record is sealed
+//! with a checksum. The next boot finds the sealed record and logs the panic
+//! and the tail of the log, which helps when the panic took the serial
+//! connection down before its output was flushed.
+//!
+//! The memory is only preserved by a warm reset, such as `panic=reboot` or
+//! `system_reset` in QEMU. After a cold boot, the record is garbage, which
+//! the magic number and the checksum reject.
+//!
+//! ### References:
+//! - [Linux: Persistent storage for a kernel's dying breath](https://docs.kernel.org/admin-guide/pstore-blk.html)
+
+use alloc::string::String;
+use core::{
+    fmt::{Arguments, Write},
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader_api::{info::{MemoryRegionKind, MemoryRegions}, BootInfo};
+use log::{error, info, trace, warn};
+use spin::Mutex;
+
+use crate::arch::{self, memory};
+
+use super::{
+    init::{FrameAllocatorInitialized, HeapInitialized},
+    memory_map::{self, RegionKind},
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"NOCCPSTR");
+
+const REGION_SIZE: usize = 4 * 4096;
+const PANIC_CAPACITY: usize = 1024;
+const HEADER_SIZE: usize = 32;
+const LOG_CAPACITY: usize = REGION_SIZE - HEADER_SIZE - PANIC_CAPACITY;
+
+/// The number of lines of the previous log that are logged again.
+const PREVIOUS_LINES: usize = 32;
+
+const STATE_LOGGING: u32 = 1;
+const STATE_PANICKED: u32 = 2;
+
+/// The physical address of the region, or zero when none was reserved.
+static REGION: AtomicU64 = AtomicU64::new(0);
+
+static RECORD: Mutex<Option<&'static mut Record>> = Mutex::new(None);
+
+#[repr(C)]
+struct Record {
+    magic: u64,
+
+    /// Covers everything after this field, only valid in [`STATE_PANICKED`].
+    checksum: u64,
+    state: u32,
+
+    /// Where the next log byte is written.
+    head: u32,
+    wrapped: u32,
+    panic_len: u32,
+    panic: [u8; PANIC_CAPACITY],
+    log: [u8; LOG_CAPACITY],
+}
+
+const _: () = assert!(size_of::<Record>() == REGION_SIZE);
+
+impl Record {
+    fn compute_checksum(&self) -> u64 {
+        let header = [self.state, self.head, self.wrapped, self.panic_len];
+        header.iter()
+            .flat_map(|field| field.to_le_bytes())
+            .chain(self.panic.iter().copied())
+            .chain(self.log.iter().copied())
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    /// Whether this is a sealed record of a panic.
+    fn is_sealed(&self) -> bool {
+        self.magic == MAGIC
+            && self.state == STATE_PANICKED
+            && (self.head as usize) < LOG_CAPACITY
+            && (self.panic_len as usize) <= PANIC_CAPACITY
+            && self.checksum == self.compute_checksum()
+    }
+
+    fn reset(&mut self) {
+        self.magic = MAGIC;
+        self.checksum = 0;
+        self.state = STATE_LOGGING;
+        self.head = 0;
+        self.wrapped = 0;
+        self.panic_len = 0;
+    }
+
+    /// The log in chronological order.
+    fn log_parts(&self) -> (&[u8], &[u8]) {
+        let head = self.head as usize;
+        if self.wrapped != 0 {
+            (&self.log[head..], &self.log[..head])
+        } else {
+            (&self.log[..head], &[])
+        }
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.log[self.head as usize] = byte;
+            self.head += 1;
+            if self.head as usize == LOG_CAPACITY {
+                self.head = 0;
+                self.wrapped = 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Withhold the region from the frame allocator, which must be done before it
+/// hands out any frame.
+pub fn reserve(memory_regions: &MemoryRegions, _: FrameAllocatorInitialized) {
+    let Some(region) = memory_regions.iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable && region.end - region.start >= REGION_SIZE as u64)
+        .max_by_key(|region| region.end)
+    else {
+        warn!("No usable memory for the persistent store");
+        return;
+    };
+
+    let start = region.end - REGION_SIZE as u64;
+    memory::with_frame_allocator(|allocator| allocator.reserve(start..region.end));
+    REGION.store(start, Ordering::Relaxed);
+}
+
+/// Report the record of the previous boot, if it panicked, and start
+/// recording this boot.
+pub fn init(boot_info: &'static BootInfo, _: HeapInitialized) {
+    let start = REGION.load(Ordering::Relaxed);
+    let Some(offset) = boot_info.physical_memory_offset.as_ref() else {
+        return;
+    };
+    if start == 0 {
+        return;
+    }
+
+    memory_map::register(start, start + REGION_SIZE as u64, RegionKind::Reserved, "persistent store");
+
+    let record = unsafe { &mut *((offset + start) as *mut Record) };
+    if record.is_sealed() {
+        report_previous(record);
+    } else {
+        trace!("No panic recorded by the previous boot");
+    }
+
+    record.reset();
+    arch::without_interrupts(|| *RECORD.lock() = Some(record));
+}
+
+fn report_previous(record: &Record) {
+    let panic = String::from_utf8_lossy(&record.panic[..record.panic_len as usize]);
+    error!("Previous kernel {panic}");
+
+    let (first, second) = record.log_parts();
+    let mut log = String::from_utf8_lossy(first).into_owned();
+    log.push_str(&String::from_utf8_lossy(second));
+
+    // The oldest line is likely cut off by the ring buffer.
+    let lines: alloc::vec::Vec<&str> = log.lines().skip(record.wrapped as usize).collect();
+    info!("Last {} lines of the previous log:", lines.len().min(PREVIOUS_LINES));
+    for line in lines.iter().skip(lines.len().saturating_sub(PREVIOUS_LINES)) {
+        info!("| {line}");
+    }
+}
+
+/// Append a line to the log. Must not block or allocate.
+pub fn append(args: Arguments) {
+    arch::without_interrupts(|| {
+        let Some(mut record) = RECORD.try_lock() else {
+            return;
+        };
+
+        if let Some(record) = record.as_mut().filter(|record| record.state == STATE_LOGGING) {
+            _ = record.write_fmt(args);
+            _ = record.write_char('\n');
+        }
+    });
+}
+
+/// Store the panic report and seal the record, after which the log is no
+/// longer appended to. Must not block or allocate.
+pub fn record_panic(message: &str) {
+    let Some(mut record) = RECORD.try_lock() else {
+        return;
+    };
+    let Some(record) = record.as_mut().filter(|record| record.state == STATE_LOGGING) else {
+        return;
+    };
+
+    let mut len = message.len().min(PANIC_CAPACITY);
+    while !message.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    record.panic[..len].copy_from_slice(&message.as_bytes()[..len]);
+    record.panic_len = len as u32;
+    record.state = STATE_PANICKED;
+    record.checksum = record.compute_checksum();
+}
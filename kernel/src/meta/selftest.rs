@@ -0,0 +1,566 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Tests that run inside the kernel, with the `selftest` shell command, for
+//! behavior that can't be tested on the host, such as how CPU exceptions are
+//! reported, when the timers fire, how the kernel threads are scheduled, how
+//! the VFS resolves paths and how the processes are kept apart.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use elf::abi::{ELFCLASS64, ELFDATA2LSB, ELFMAGIC, EM_X86_64, ET_EXEC, EV_CURRENT, PF_R, PF_X, PT_LOAD};
+use futures_util::future::join3;
+use x86_64::{structures::idt::{ExceptionVector, PageFaultErrorCode}, VirtAddr};
+
+use crate::{
+    arch::{
+        self,
+        interrupts::fault_hook::{self, Fault, FaultKind},
+        memory::{AddressSpace, UserRegion, USER_REGION},
+        user::UserExit,
+    },
+    crypto::{hmac::HmacSha256, sha256::Sha256},
+    fs::{ramfs::RamFs, vfs::{self, File, OpenOptions, VfsError}},
+    meta::symbols,
+    net::{ipv6::{self, Ipv6Address, Ipv6Header}, udp, IpAddress, MacAddress},
+    process::{self, ProcessState},
+    syscall::{self, SyscallError},
+    task::{
+        executor::block_on,
+        scheduler::{self, ThreadId, ThreadState},
+        timer::{self, Elapsed},
+    },
+};
+
+pub struct SelfTest {
+    pub name: &'static str,
+    pub run: fn() -> Result<(), String>,
+}
+
+pub const TESTS: &[SelfTest] = &[
+    SelfTest {
+        name: "page fault on an unmapped page",
+        run: || {
+            let fault = expect_fault(fault_hook::read(0), FaultKind::PageFault)?;
+            check_page_fault(&fault, PageFaultErrorCode::empty())
+        },
+    },
+    SelfTest {
+        name: "page fault on a write to kernel code",
+        run: || {
+            let address = run_all as *const () as u64;
+
+            // Write back the same byte, in case the page turns out writable.
+            let byte = fault_hook::read(address).map_err(|fault| format!("reading the code faulted: {fault:?}"))?;
+            let fault = expect_fault(fault_hook::write(address, byte), FaultKind::PageFault)?;
+            check_page_fault(&fault, PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        },
+    },
+    SelfTest {
+        name: "general protection fault on a non-canonical address",
+        run: || expect_fault(fault_hook::read(0x8000_0000_0000_0000), FaultKind::GeneralProtection).map(|_| ()),
+    },
+    SelfTest {
+        name: "invalid opcode",
+        run: || expect_fault(fault_hook::invalid_opcode(), FaultKind::InvalidOpcode).map(|_| ()),
+    },
+    SelfTest {
+        name: "no fault on mapped memory",
+        run: || {
+            let value = 0x5Au8;
+            match fault_hook::read(&value as *const u8 as u64) {
+                Ok(0x5A) => Ok(()),
+                Ok(other) => Err(format!("read {other:#x} instead of 0x5a")),
+                Err(fault) => Err(format!("unexpected {fault:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "symbols resolve an address inside a function",
+        run: || {
+            match symbols::resolve(run_all as *const () as u64 + 1) {
+                Some(symbol) if symbol.name.contains("run_all") && symbol.offset == 1 => Ok(()),
+                other => Err(format!("resolved to {other:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "symbols don't contain an address past the last",
+        run: || {
+            // The end of the address space, far past the kernel image.
+            let address = u64::MAX - 4095;
+            match (symbols::resolve(address), symbols::resolve_containing(address)) {
+                (Some(_), None) => Ok(()),
+                other => Err(format!("resolved to {other:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "symbols don't resolve an address before the first",
+        run: || match symbols::resolve(0x1000) {
+            None => Ok(()),
+            Some(symbol) => Err(format!("resolved to {symbol}")),
+        },
+    },
+    SelfTest {
+        name: "sha-256 matches the examples of FIPS 180-4",
+        run: || {
+            let one_block = Sha256::digest(b"abc");
+
+            // Fed in pieces, so the padding spills into a second block.
+            let mut hasher = Sha256::new();
+            for piece in b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".chunks(7) {
+                hasher.update(piece);
+            }
+            let two_blocks = hasher.finish();
+
+            check_bytes(&one_block, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")?;
+            check_bytes(&two_blocks, "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        },
+    },
+    SelfTest {
+        name: "hmac-sha-256 matches the test cases of RFC 4231",
+        run: || {
+            let short_key = HmacSha256::mac(b"Jefe", b"what do ya want for nothing?");
+            check_bytes(&short_key, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")?;
+
+            // A key longer than a block, which is hashed first.
+            let key = [0xAA; 131];
+            let mut hmac = HmacSha256::new(&key);
+            hmac.update(b"Test Using Larger Than Block-Size Key - ");
+            hmac.update(b"Hash Key First");
+            let mut long_key = hmac.finish();
+            check_bytes(&long_key, "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")?;
+
+            let data = b"Test Using Larger Than Block-Size Key - Hash Key First";
+            if !HmacSha256::verify(&key, data, &long_key) {
+                return Err("verify rejected the right MAC".into());
+            }
+            long_key[31] ^= 1;
+            match HmacSha256::verify(&key, data, &long_key) {
+                true => Err("verify accepted a wrong MAC".into()),
+                false => Ok(()),
+            }
+        },
+    },
+    SelfTest {
+        name: "timeout drops a future that doesn't complete in time",
+        run: || {
+            let start = arch::ticks();
+            match block_on(timer::timeout(Duration::from_millis(20), timer::sleep(Duration::from_secs(1)))) {
+                Err(Elapsed) => check_elapsed(start, 20..30),
+                Ok(()) => Err("the future completed".into()),
+            }
+        },
+    },
+    SelfTest {
+        name: "timeout passes the output of a future that completes in time",
+        run: || {
+            let start = arch::ticks();
+            let future = async {
+                timer::sleep(Duration::from_millis(10)).await;
+                42
+            };
+            match block_on(timer::timeout(Duration::from_secs(1), future)) {
+                Ok(42) => check_elapsed(start, 10..20),
+                other => Err(format!("expected Ok(42), got {other:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "timers fire in the order of their deadlines",
+        run: || {
+            let order = RefCell::new(Vec::new());
+            let timer = |milliseconds, id| {
+                let order = &order;
+                async move {
+                    timer::sleep(Duration::from_millis(milliseconds)).await;
+                    order.borrow_mut().push(id);
+                }
+            };
+
+            block_on(join3(timer(30, 3), timer(10, 1), timer(20, 2)));
+            match order.into_inner().as_slice() {
+                [1, 2, 3] => Ok(()),
+                order => Err(format!("fired in the order {order:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "timeout unregisters the sleep it cancels",
+        run: || {
+            let sleep = timer::sleep(Duration::from_secs(10));
+            let deadline = sleep.deadline();
+            _ = block_on(timer::timeout(Duration::from_millis(5), sleep));
+            match timer::is_registered(deadline) {
+                true => Err("the sleep is still registered".into()),
+                false => Ok(()),
+            }
+        },
+    },
+    SelfTest {
+        name: "interval skips the ticks that were missed",
+        run: || {
+            let start = arch::ticks();
+            let mut interval = timer::interval(Duration::from_millis(10));
+            block_on(interval.tick());
+            check_elapsed(start, 10..20)?;
+
+            // Miss the ticks at 20 and 30, so the next is at 40.
+            block_on(timer::sleep(Duration::from_millis(25)));
+            block_on(interval.tick());
+            check_elapsed(start, 40..50)
+        },
+    },
+    SelfTest {
+        name: "threads are preempted while they spin",
+        run: || {
+            let spins = Arc::new(AtomicU64::new(0));
+            let stop = Arc::new(AtomicBool::new(false));
+            let id = {
+                let (spins, stop) = (spins.clone(), stop.clone());
+                scheduler::spawn("selftest-spin", move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        spins.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            }.map_err(|e| format!("spawning failed: {e:?}"))?;
+
+            // Spin without yielding, so the thread only runs when we're
+            // preempted.
+            let start = arch::ticks();
+            while spins.load(Ordering::Relaxed) == 0 && arch::ticks() - start < 100 * arch::TICKS_PER_SECOND / 1000 {
+                core::hint::spin_loop();
+            }
+            let result = match spins.load(Ordering::Relaxed) {
+                0 => Err("the thread didn't run within 100 ms".into()),
+                _ => Ok(()),
+            };
+
+            stop.store(true, Ordering::Relaxed);
+            wait_for_exit(id)?;
+            result
+        },
+    },
+    SelfTest {
+        name: "sleeping threads wake up after their duration",
+        run: || {
+            let woken = Arc::new(AtomicUsize::new(0));
+            let start = arch::ticks();
+            let id = {
+                let woken = woken.clone();
+                scheduler::spawn("selftest-sleep", move || {
+                    scheduler::sleep(Duration::from_millis(20));
+                    woken.store(arch::ticks(), Ordering::Relaxed);
+                })
+            }.map_err(|e| format!("spawning failed: {e:?}"))?;
+
+            wait_for_exit(id)?;
+            let milliseconds = (woken.load(Ordering::Relaxed) - start) * 1000 / arch::TICKS_PER_SECOND;
+            match milliseconds {
+                20..30 => Ok(()),
+                _ => Err(format!("woke up after {milliseconds} ms, expected 20..30 ms")),
+            }
+        },
+    },
+    SelfTest {
+        name: "vfs reads back what was written to the ramfs",
+        run: || {
+            let result = (|| {
+                vfs::create_dir("/selftest")?;
+                vfs::write("/selftest/a", b"hello")?;
+                let mut file = vfs::open("/selftest/./a", OpenOptions::append())?;
+                file.write(b" world")?;
+                let position = file.position();
+                vfs::read("/selftest/../selftest/a").map(|data| (data, position))
+            })();
+
+            let cleanup = vfs::remove("/selftest/a").and_then(|()| vfs::remove("/selftest"));
+            match (result, cleanup) {
+                (Ok((data, 11)), Ok(())) if data == b"hello world" => Ok(()),
+                (Ok((data, position)), Ok(())) => {
+                    Err(format!("read {:?}, ended at {position}", String::from_utf8_lossy(&data)))
+                }
+                (Err(e), _) | (_, Err(e)) => Err(format!("failed: {e}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "vfs resolves paths to the innermost mount",
+        run: || {
+            vfs::create_dir("/selftest-mnt").map_err(|e| format!("mkdir failed: {e}"))?;
+            let result = (|| -> Result<_, VfsError> {
+                vfs::mount("/selftest-mnt", RamFs::new())?;
+                vfs::write("/selftest-mnt/inner", b"")?;
+                let inner = vfs::read_dir("/selftest-mnt")?;
+                vfs::unmount("/selftest-mnt")?;
+                let outer = vfs::read_dir("/selftest-mnt")?;
+                Ok((inner.len(), outer.len()))
+            })();
+
+            _ = vfs::unmount("/selftest-mnt");
+            _ = vfs::remove("/selftest-mnt");
+            match result {
+                Ok((1, 0)) => Ok(()),
+                Ok((inner, outer)) => Err(format!("{inner} entries in the mount, {outer} below it")),
+                Err(e) => Err(format!("failed: {e}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "udp sockets take datagrams over ipv6 with a checksum",
+        run: || {
+            let socket = udp::bind(0).map_err(|e| format!("bind failed: {e}"))?;
+            let source = Ipv6Address::link_local(MacAddress([0x52, 0x54, 0, 0, 0, 1]));
+            let destination = Ipv6Address::link_local(MacAddress([0x52, 0x54, 0, 0, 0, 2]));
+            let header = Ipv6Header {
+                payload_length: 0,
+                next_header: ipv6::NEXT_HEADER_UDP,
+                hop_limit: 64,
+                source,
+                destination,
+            };
+
+            // Over IPv6, a datagram without a checksum is dropped.
+            let mut datagram = udp::build_ipv6(source, 1234, destination, socket.port(), b"hello");
+            datagram[6..8].fill(0);
+            udp::handle_datagram_ipv6(&header, &datagram);
+            let datagram = udp::build_ipv6(source, 1234, destination, socket.port(), b"world");
+            udp::handle_datagram_ipv6(&header, &datagram);
+
+            match block_on(timer::timeout(Duration::from_millis(10), socket.recv_from())) {
+                Ok(datagram) if datagram.source == IpAddress::V6(source) && datagram.source_port == 1234 && datagram.data == b"world" => Ok(()),
+                Ok(datagram) => Err(format!("received {datagram:?}")),
+                Err(Elapsed) => Err("nothing was received".into()),
+            }
+        },
+    },
+    SelfTest {
+        name: "a fault in user mode ends only its process",
+        run: || {
+            let address = run_all as *const () as u64;
+            let byte = fault_hook::read(address).map_err(|fault| format!("reading the code faulted: {fault:?}"))?;
+
+            // mov rax, run_all; mov byte [rax], 0
+            let faulting = [&[0x48, 0xB8][..], &address.to_le_bytes(), &[0xC6, 0x00, 0x00]].concat();
+            // mov edi, 7; mov eax, EXIT; syscall
+            let exiting = [&[0xBF, 7, 0, 0, 0, 0xB8][..], &(syscall::EXIT as u32).to_le_bytes(), &[0x0F, 0x05]].concat();
+
+            let exits = run_programs(&[faulting, exiting])?;
+            let faulted_at = VirtAddr::new(program_entry() + 10);
+            match exits.as_slice() {
+                [UserExit::Fault { vector: ExceptionVector::Page, instruction_pointer }, UserExit::Exited(7)]
+                    if *instruction_pointer == faulted_at => {}
+                exits => return Err(format!("the programs ended with {exits:?}")),
+            }
+            match fault_hook::read(address) {
+                Ok(after) if after == byte => Ok(()),
+                after => Err(format!("the kernel code changed to {after:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "write with a kernel pointer returns BadAddress",
+        run: || {
+            // mov rdi, run_all; mov esi, 1; mov eax, WRITE; syscall
+            // mov rdi, rax; mov eax, EXIT; syscall
+            let program = [
+                &[0x48, 0xBF][..], &(run_all as *const () as u64).to_le_bytes(),
+                &[0xBE, 1, 0, 0, 0, 0xB8], &(syscall::WRITE as u32).to_le_bytes(), &[0x0F, 0x05],
+                &[0x48, 0x89, 0xC7, 0xB8], &(syscall::EXIT as u32).to_le_bytes(), &[0x0F, 0x05],
+            ].concat();
+
+            let expected = -(SyscallError::BadAddress as i64) as u64;
+            match run_programs(&[program])?.as_slice() {
+                [UserExit::Exited(status)] if *status == expected => Ok(()),
+                exits => Err(format!("the program ended with {exits:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "read into the code of the program returns BadAddress",
+        run: || {
+            // mov rdi, program_entry; mov esi, 1; mov eax, READ; syscall
+            // mov rdi, rax; mov eax, EXIT; syscall
+            let program = [
+                &[0x48, 0xBF][..], &program_entry().to_le_bytes(),
+                &[0xBE, 1, 0, 0, 0, 0xB8], &(syscall::READ as u32).to_le_bytes(), &[0x0F, 0x05],
+                &[0x48, 0x89, 0xC7, 0xB8], &(syscall::EXIT as u32).to_le_bytes(), &[0x0F, 0x05],
+            ].concat();
+
+            let expected = -(SyscallError::BadAddress as i64) as u64;
+            match run_programs(&[program])?.as_slice() {
+                [UserExit::Exited(status)] if *status == expected => Ok(()),
+                exits => Err(format!("the program ended with {exits:?}")),
+            }
+        },
+    },
+    SelfTest {
+        name: "address spaces don't see each other's pages",
+        run: || {
+            let shared = VirtAddr::new(USER_REGION.start);
+            let own = shared + 4096u64;
+            let (mut first, mut second) = AddressSpace::new()
+                .zip(AddressSpace::new())
+                .ok_or_else(|| String::from("out of frames"))?;
+
+            first.map_region(shared..own, UserRegion::Data).map_err(|e| format!("mapping failed: {e:?}"))?;
+            second.map_region(shared..own + 4096u64, UserRegion::Data).map_err(|e| format!("mapping failed: {e:?}"))?;
+            first.write(shared, &[1]);
+            second.write(shared, &[2]);
+            second.write(own, &[3]);
+
+            first.activate();
+            let in_first = (fault_hook::read(shared.as_u64()), fault_hook::read(own.as_u64()));
+            second.activate();
+            let in_second = (fault_hook::read(shared.as_u64()), fault_hook::read(own.as_u64()));
+
+            // Dropping the active one switches back to the kernel's.
+            drop(second);
+            drop(first);
+            match (in_first, in_second) {
+                ((Ok(1), Err(Fault { kind: FaultKind::PageFault, .. })), (Ok(2), Ok(3))) => Ok(()),
+                reads => Err(format!("read {reads:?}")),
+            }
+        },
+    },
+];
+
+/// Run every test, returning the number of failures.
+pub fn run_all(report: &mut dyn FnMut(&SelfTest, &Result<(), String>)) -> usize {
+    let mut failures = 0;
+    for test in TESTS {
+        let result = (test.run)();
+        if result.is_err() {
+            failures += 1;
+        }
+        report(test, &result);
+    }
+    failures
+}
+
+fn expect_fault<T>(result: Result<T, Fault>, kind: FaultKind) -> Result<Fault, String> {
+    match result {
+        Err(fault) if fault.kind == kind => Ok(fault),
+        Err(fault) => Err(format!("expected a {kind:?}, got {fault:?}")),
+        Ok(_) => Err(format!("expected a {kind:?}, but nothing faulted")),
+    }
+}
+
+fn check_page_fault(fault: &Fault, expected: PageFaultErrorCode) -> Result<(), String> {
+    // The other bits depend on the kind of access, e.g. instruction fetches.
+    let relevant = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code);
+    if error_code & relevant == expected {
+        Ok(())
+    } else {
+        Err(format!("unexpected error code {error_code:?}"))
+    }
+}
+
+/// Compare the bytes to the hexadecimal digits of the expected ones.
+fn check_bytes(bytes: &[u8], expected: &str) -> Result<(), String> {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    match hex == expected {
+        true => Ok(()),
+        false => Err(format!("got {hex}, expected {expected}")),
+    }
+}
+
+/// Let the other threads run until the thread exited, for up to a second.
+fn wait_for_exit(id: ThreadId) -> Result<(), String> {
+    let start = arch::ticks();
+    while scheduler::threads().iter().any(|thread| thread.id == id && thread.state != ThreadState::Exited) {
+        if arch::ticks() - start >= arch::TICKS_PER_SECOND {
+            return Err(format!("thread {} didn't exit within a second", id.as_u64()));
+        }
+        scheduler::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+/// An executable of the machine code, which is loaded along with the headers
+/// before it, at the start of the user region.
+fn program(code: &[u8]) -> Vec<u8> {
+    const HEADER_SIZE: u16 = 64;
+    const PROGRAM_HEADER_SIZE: u16 = 56;
+    let size = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64 + code.len() as u64;
+
+    let mut file = Vec::new();
+    file.extend_from_slice(&ELFMAGIC);
+    file.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    for half in [ET_EXEC, EM_X86_64] {
+        file.extend_from_slice(&half.to_le_bytes());
+    }
+    file.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    for word in [program_entry(), HEADER_SIZE as u64, 0] {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+    file.extend_from_slice(&0u32.to_le_bytes());
+    for half in [HEADER_SIZE, PROGRAM_HEADER_SIZE, 1, 64, 0, 0] {
+        file.extend_from_slice(&half.to_le_bytes());
+    }
+
+    for word in [PT_LOAD, PF_R | PF_X] {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+    for word in [0, USER_REGION.start, USER_REGION.start, size, size, 4096] {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+
+    file.extend_from_slice(code);
+    file
+}
+
+/// Where the code of a [`program`] starts, after the headers.
+fn program_entry() -> u64 {
+    USER_REGION.start + 64 + 56
+}
+
+/// Run the programs as processes side by side, and return how they ended,
+/// waiting for up to a second.
+fn run_programs(programs: &[Vec<u8>]) -> Result<Vec<UserExit>, String> {
+    let mut ids = Vec::new();
+    for (index, program) in programs.iter().enumerate() {
+        let path = format!("/selftest-program-{index}");
+        vfs::write(&path, &self::program(program)).map_err(|e| format!("writing {path} failed: {e}"))?;
+        let id = process::spawn(&path);
+        _ = vfs::remove(&path);
+        ids.push(id.map_err(|e| format!("running {path} failed: {e}"))?);
+    }
+
+    let start = arch::ticks();
+    loop {
+        let processes = process::processes();
+        let exits = ids.iter()
+            .map(|id| match processes.iter().find(|process| process.id == *id)?.state {
+                ProcessState::Ended(exit) => Some(exit),
+                ProcessState::Running => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(exits) = exits {
+            return Ok(exits);
+        }
+
+        if arch::ticks() - start >= arch::TICKS_PER_SECOND {
+            return Err("the programs didn't end within a second".into());
+        }
+        scheduler::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Check that the milliseconds since the start tick are in the range, which
+/// leaves some room for the ticks to be late.
+fn check_elapsed(start: usize, expected: core::ops::Range<usize>) -> Result<(), String> {
+    let milliseconds = (arch::ticks() - start) * 1000 / arch::TICKS_PER_SECOND;
+    if expected.contains(&milliseconds) {
+        Ok(())
+    } else {
+        Err(format!("took {milliseconds} ms, expected {expected:?} ms"))
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The boot splash shows the progress of the kernel initialization on the
+//! framebuffer, which is handy as a sign of life when no serial console is
+//! attached. It can be disabled using the `nosplash` boot parameter, and falls
+//! back to plain log messages when there is no framebuffer.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use log::{info, trace};
+
+use crate::{arch::without_interrupts, vga_text_buffer::{Color, Writer, WRITER}};
+
+use super::BootParameters;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The last stage that was reached, to draw the progress again.
+static STAGE: AtomicU8 = AtomicU8::new(u8::MAX);
+
+const TITLE: &str = "nocciolo";
+const PROGRESS_BAR_HEIGHT: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BootStage {
+    DescriptorTables,
+    Interrupts,
+    Timer,
+    Heap,
+    Acpi,
+    Apic,
+    Runtime,
+    Devices,
+    Configuration,
+    Finished,
+}
+
+impl BootStage {
+    pub const COUNT: usize = Self::Finished as usize + 1;
+
+    const ALL: [Self; Self::COUNT] = [
+        Self::DescriptorTables,
+        Self::Interrupts,
+        Self::Timer,
+        Self::Heap,
+        Self::Acpi,
+        Self::Apic,
+        Self::Runtime,
+        Self::Devices,
+        Self::Configuration,
+        Self::Finished,
+    ];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::DescriptorTables => "Loading descriptor tables",
+            Self::Interrupts => "Initializing interrupt controllers",
+            Self::Timer => "Initializing timer",
+            Self::Heap => "Initializing heap",
+            Self::Acpi => "Initializing ACPI",
+            Self::Apic => "Initializing APIC",
+            Self::Runtime => "Initializing kernel runtime",
+            Self::Devices => "Initializing devices",
+            Self::Configuration => "Loading configuration",
+            Self::Finished => "Finished",
+        }
+    }
+
+    /// The 1-based number of this stage.
+    #[must_use]
+    pub const fn number(&self) -> usize {
+        *self as usize + 1
+    }
+}
+
+/// Draw the splash screen, if enabled and a framebuffer is available.
+pub fn init() {
+    if BootParameters::flag("splash") == Some(false) {
+        trace!("Boot splash disabled by boot parameter");
+        return;
+    }
+
+    let drawn = without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        if !writer.is_available() {
+            return false;
+        }
+
+        draw_title(&mut writer);
+        true
+    });
+
+    if !drawn {
+        trace!("No framebuffer available; boot splash falls back to logging");
+    }
+
+    ACTIVE.store(drawn, Ordering::SeqCst);
+}
+
+/// Whether the splash screen currently owns the framebuffer.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Report that the initialization reached the given stage.
+pub fn advance(stage: BootStage) {
+    if !is_active() {
+        info!("[{}/{}] {}", stage.number(), BootStage::COUNT, stage.name());
+        return;
+    }
+
+    trace!("Boot stage {}/{}: {}", stage.number(), BootStage::COUNT, stage.name());
+    STAGE.store(stage as u8, Ordering::Relaxed);
+
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        draw_progress(&mut writer, stage);
+    });
+
+    if stage == BootStage::Finished {
+        finish();
+    }
+}
+
+/// Hide the splash and hand the framebuffer back to the console.
+pub fn finish() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    WRITER.lock().clear();
+}
+
+/// Draw the splash again, after the framebuffer was reconfigured.
+pub fn redraw() {
+    if !is_active() {
+        return;
+    }
+
+    let mut writer = WRITER.lock();
+    draw_title(&mut writer);
+    if let Some(stage) = BootStage::ALL.get(STAGE.load(Ordering::Relaxed) as usize) {
+        draw_progress(&mut writer, *stage);
+    }
+}
+
+fn draw_title(writer: &mut Writer) {
+    writer.clear();
+
+    let x = center(writer.width(), writer.text_width(TITLE));
+    let y = title_y(writer);
+    writer.draw_str_at(x, y, TITLE, Color::White);
+}
+
+fn draw_progress(writer: &mut Writer, stage: BootStage) {
+    let bar_width = writer.width() / 2;
+    let bar_x = center(writer.width(), bar_width);
+    let bar_y = title_y(writer) + writer.text_height() * 2;
+
+    // Outline, then the empty and filled parts of the bar.
+    writer.fill_rect(bar_x, bar_y, bar_width, PROGRESS_BAR_HEIGHT, Color::White);
+    let inner_width = bar_width.saturating_sub(2);
+    writer.fill_rect(bar_x + 1, bar_y + 1, inner_width, PROGRESS_BAR_HEIGHT - 2, Color::Black);
+
+    let filled = inner_width * stage.number() / BootStage::COUNT;
+    writer.fill_rect(bar_x + 1, bar_y + 1, filled, PROGRESS_BAR_HEIGHT - 2, Color::Green);
+
+    let label_y = bar_y + PROGRESS_BAR_HEIGHT + writer.text_height();
+    let label_height = writer.text_height();
+    let width = writer.width();
+    writer.fill_rect(0, label_y, width, label_height, Color::Black);
+
+    let label_x = center(width, writer.text_width(stage.name()));
+    writer.draw_str_at(label_x, label_y, stage.name(), Color::White);
+}
+
+fn title_y(writer: &Writer) -> usize {
+    (writer.height() / 2).saturating_sub(writer.text_height() * 2)
+}
+
+fn center(total: usize, size: usize) -> usize {
+    total.saturating_sub(size) / 2
+}
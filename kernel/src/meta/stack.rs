@@ -0,0 +1,154 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Stack watermarking.
+//!
+//! Stacks are painted with a known pattern when they are created. Since stacks
+//! grow downwards, the lowest word that no longer contains the pattern tells
+//! us how deep the stack has been used at most, which helps with tuning the
+//! stack sizes. In debug builds, the lowest words of the pattern also serve as
+//! a canary, see [`super::integrity`].
+
+use core::arch::asm;
+
+use log::{trace, warn};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+pub const STACK_PATTERN: u64 = 0xC0FF_EE57_AC4C_0FFE;
+
+/// The number of words at the bottom of a stack that must still contain the
+/// pattern, or the stack has overflowed (or was written to by a stray
+/// pointer).
+const CANARY_WORDS: usize = 8;
+
+/// The number of bytes below the stack pointer that are left alone when
+/// painting the stack that is currently in use.
+const ACTIVE_STACK_MARGIN: u64 = 512;
+
+const MAX_STACKS: usize = 16;
+
+static STACKS: Mutex<[Option<StackRegion>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackRegion {
+    name: &'static str,
+    bottom: VirtAddr,
+    size: usize,
+}
+
+impl StackRegion {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The exclusive end of the stack, since it grows downwards.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.size as u64
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.bottom..self.top()).contains(&address)
+    }
+
+    /// The maximum number of bytes that were ever in use.
+    pub fn max_usage(&self) -> usize {
+        let words = self.size / 8;
+        let bottom = self.bottom.as_ptr::<u64>();
+
+        let untouched = (0..words)
+            .take_while(|idx| unsafe { bottom.add(*idx).read_volatile() } == STACK_PATTERN)
+            .count();
+
+        (words - untouched) * 8
+    }
+
+    /// The address of the first word of the canary that was overwritten, if
+    /// any.
+    pub fn damaged_canary(&self) -> Option<VirtAddr> {
+        let bottom = self.bottom.as_ptr::<u64>();
+        (0..CANARY_WORDS.min(self.size / 8))
+            .find(|idx| unsafe { bottom.add(*idx).read_volatile() } != STACK_PATTERN)
+            .map(|idx| self.bottom + idx as u64 * 8)
+    }
+}
+
+/// Fill the given stack with the watermark pattern and register it.
+///
+/// # Safety
+/// The region must be a valid, unused stack.
+pub unsafe fn paint_and_register(name: &'static str, bottom: VirtAddr, size: usize) {
+    paint(bottom, size);
+    register(StackRegion { name, bottom, size });
+}
+
+/// Stop tracking the stack starting at `bottom`, before it's freed.
+pub fn unregister(bottom: VirtAddr) {
+    let mut stacks = STACKS.lock();
+    if let Some(slot) = stacks.iter_mut().find(|slot| slot.is_some_and(|stack| stack.bottom == bottom)) {
+        *slot = None;
+    }
+}
+
+/// Paint the unused part of the stack we're running on, which was set up by
+/// the bootloader with the given size.
+pub fn init_kernel_stack(size: usize) {
+    let rsp = VirtAddr::new(read_rsp());
+
+    // The bootloader puts the top of the stack on a page boundary, and we are
+    // only a couple of frames deep at this point.
+    let top = rsp.align_up(4096u64);
+    let bottom = top - size as u64;
+    let paint_end = rsp - ACTIVE_STACK_MARGIN;
+
+    trace!("Kernel stack is {bottom:?}..{top:?}, painting up to {paint_end:?}");
+
+    unsafe { paint(bottom, (paint_end - bottom) as usize) };
+    register(StackRegion { name: "kernel", bottom, size });
+}
+
+/// Collect the registered stacks.
+pub fn stacks() -> impl Iterator<Item = StackRegion> {
+    let stacks = *STACKS.lock();
+    stacks.into_iter().flatten()
+}
+
+/// Find a stack with a damaged canary, see [`StackRegion::damaged_canary`].
+/// Returns `None` when the stacks are locked.
+///
+/// Must not block or allocate.
+#[cfg(debug_assertions)]
+pub fn try_find_damaged_canary() -> Option<(StackRegion, VirtAddr)> {
+    let stacks = *STACKS.try_lock()?;
+    stacks.into_iter()
+        .flatten()
+        .find_map(|stack| Some((stack, stack.damaged_canary()?)))
+}
+
+unsafe fn paint(bottom: VirtAddr, size: usize) {
+    let ptr = bottom.as_mut_ptr::<u64>();
+    for idx in 0..size / 8 {
+        ptr.add(idx).write_volatile(STACK_PATTERN);
+    }
+}
+
+fn register(region: StackRegion) {
+    let mut stacks = STACKS.lock();
+    match stacks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(region),
+        None => warn!("Too many stacks registered, not tracking {}", region.name),
+    }
+}
+
+#[inline(always)]
+fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov %rsp, {0}", out(reg) rsp, options(att_syntax, nomem, nostack));
+    }
+    rsp
+}
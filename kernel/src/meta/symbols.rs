@@ -1,28 +1,64 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
-use core::{arch::asm, ptr::slice_from_raw_parts};
+//! Resolving addresses to the symbols of the kernel image, for backtraces and
+//! fault reports.
+//!
+//! The kernel is loaded as a position-independent executable, so the
+//! addresses are relative to where the bootloader put it, while the symbol
+//! table has the addresses of the ELF file.
+
+use core::{
+    arch::asm,
+    fmt::{Display, Formatter},
+    ptr::slice_from_raw_parts,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use bootloader_api::BootInfo;
 use conquer_once::spin::OnceCell;
-use elf::{endian::NativeEndian, ElfBytes};
+use elf::{abi::{ET_DYN, STT_FILE, STT_SECTION}, endian::NativeEndian, ElfBytes, ParseError};
 use lazy_static::lazy_static;
-use log::warn;
+use log::info;
+
+use super::containment::Feature;
 
 lazy_static! {
     static ref ELF: OnceCell<Option<ElfBytes<'static, NativeEndian>>> = OnceCell::uninit();
 }
 
+/// The difference between the addresses at runtime and those in the ELF file.
+static LOAD_BIAS: AtomicU64 = AtomicU64::new(0);
+
+static FEATURE: Feature = Feature::new("symbols", "Symbols of the kernel image, for backtraces");
+
+#[allow(unused)]
+#[derive(Debug)]
+enum SymbolsError {
+    /// The bootloader didn't map the kernel ELF.
+    NotMapped,
+    Parse(ParseError),
+}
+
+/// Parse the kernel ELF, without which the backtraces only have addresses.
 pub(super) fn init(boot_info: &'static BootInfo) {
-    let data = match ElfBytes::<NativeEndian>::minimal_parse(get_elf_slice(boot_info)) {
-        Ok(data) => data,
-        Err(e) => {
-            warn!("Failed to parse ELF: {e}");
-            return;
-        }
-    };
+    FEATURE.init(|| parse(boot_info));
+}
+
+fn parse(boot_info: &'static BootInfo) -> Result<(), SymbolsError> {
+    let slice = get_elf_slice(boot_info).ok_or(SymbolsError::NotMapped)?;
+    let data = ElfBytes::<NativeEndian>::minimal_parse(slice).map_err(SymbolsError::Parse)?;
+
+    if data.ehdr.e_type == ET_DYN {
+        LOAD_BIAS.store(boot_info.kernel_image_offset, Ordering::Relaxed);
+    }
+
+    // Printed for `cargo run symbolize`, to map the addresses in the log back
+    // to the ELF file.
+    info!("Kernel load bias {:#x}", LOAD_BIAS.load(Ordering::Relaxed));
 
     ELF.init_once(|| Some(data));
+    Ok(())
 }
 
 pub struct Backtrace;
@@ -79,40 +115,90 @@ impl BacktraceFrame {
         self.ptr
     }
 
-    pub fn symbol(&self) -> Option<&'static str> {
-        resolve(self.ptr as _)
+    /// The address the function of this frame returns to, which is stored
+    /// right above the saved frame pointer.
+    pub fn return_address(&self) -> u64 {
+        unsafe { *self.ptr.add(1) }
+    }
+
+    pub fn symbol(&self) -> Option<Symbol> {
+        resolve(self.return_address())
     }
 }
 
-pub fn resolve(offset: u64) -> Option<&'static str> {
-    let elf = ELF.get()?.as_ref()?;
+/// A resolved address: the symbol it is in (or after), and how far into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if self.offset == 0 {
+            f.write_str(self.name)
+        } else {
+            write!(f, "{}+{:#x}", self.name, self.offset)
+        }
+    }
+}
+
+/// Find the symbol containing the address, including local symbols. When the
+/// address isn't inside any symbol (e.g. in a label of hand-written assembly,
+/// which has no size), the closest symbol before it is used.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    lookup(address).map(|(_, symbol)| symbol)
+}
 
+/// Find the symbol containing the address, but unlike [`resolve`], not the
+/// one preceding it, so addresses outside of the kernel image aren't
+/// attributed to its last symbol.
+pub fn resolve_containing(address: u64) -> Option<Symbol> {
+    lookup(address).filter(|(contains, _)| *contains).map(|(_, symbol)| symbol)
+}
+
+/// The best symbol for the address, and whether it contains the address.
+fn lookup(address: u64) -> Option<(bool, Symbol)> {
+    let elf = ELF.get()?.as_ref()?;
     let (sym_tab, str_tab) = elf.symbol_table().ok()??;
 
-    for sym in sym_tab {
-        if sym.st_value < offset {
+    // Addresses below the image would otherwise wrap around to past its end.
+    let address = address.checked_sub(LOAD_BIAS.load(Ordering::Relaxed))?;
+
+    // The innermost symbol containing the address wins over one that merely
+    // precedes it, and among either, the one starting closest to it.
+    let mut best: Option<(bool, u64, u32)> = None;
+    for sym in sym_tab.iter() {
+        if sym.st_name == 0 || sym.is_undefined() || matches!(sym.st_symtype(), STT_SECTION | STT_FILE) {
             continue;
         }
 
-        if sym.st_value + sym.st_size > offset {
+        if sym.st_value > address {
             continue;
         }
 
-        if sym.st_name == 0 {
-            return None;
+        let contains = address - sym.st_value < sym.st_size;
+        let candidate = (contains, sym.st_value, sym.st_name);
+        if best.map_or(true, |(best_contains, best_value, _)| (contains, sym.st_value) > (best_contains, best_value)) {
+            best = Some(candidate);
         }
-
-        return str_tab.get(sym.st_name as usize).ok();
     }
 
-    None
+    let (contains, value, name) = best?;
+    Some((contains, Symbol {
+        name: str_tab.get(name as usize).ok()?,
+        offset: address - value,
+    }))
 }
 
-fn get_elf_slice(boot_info: &'static BootInfo) -> &'static [u8] {
-    let data = boot_info.kernel_image_offset as *const u8;
+/// The ELF file of the kernel, which the bootloader leaves in (physical)
+/// memory, as opposed to the loaded image, which lacks the symbol table.
+fn get_elf_slice(boot_info: &'static BootInfo) -> Option<&'static [u8]> {
+    let physical_memory_offset = boot_info.physical_memory_offset.into_option()?;
+    let data = (physical_memory_offset + boot_info.kernel_addr) as *const u8;
     let len = boot_info.kernel_len as usize;
 
-    unsafe {
+    Some(unsafe {
         &*slice_from_raw_parts(data, len)
-    }
+    })
 }
@@ -1,50 +1,211 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
+//! Shutting down and rebooting the machine.
+//!
+//! In CI mode (the `ci` boot parameter) or when `shutdown_timeout` is given, a
+//! watchdog guards the shutdown: when the machine is still running after the
+//! timeout (10 seconds by default), e.g. because the AML of `_PTS` hangs, the
+//! stage that stalled is reported over serial and the machine is powered off
+//! using the hypervisor-specific ports instead. The watchdog is driven by the
+//! timer interrupt, so it can't catch a stall with interrupts disabled.
+//!
+//! A shutdown or reboot that is requested stops the tasks of the executor
+//! first (see [`executor::stop`]), so no driver is still running while the
+//! devices are torn down. After that, the [`SHUTDOWN_HOOKS`] are run, e.g. to
+//! get the buffered log out.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
 use acpi::{address::{AddressSpace, GenericAddress}, AcpiError};
-use aml::{AmlError, AmlName, AmlValue};
+use aml::{AmlError, AmlName};
 use log::{error, info, trace};
 use raw_cpuid::CpuId;
-use x86_64::instructions::port::Port;
+use x86_64::{instructions::port::Port, structures::DescriptorTablePointer, VirtAddr};
+
+use crate::{
+    arch::{self, serial},
+    device::{acpi::{gpe, AcpiData, AmlObject, AmlTypeError, SystemState, ACPI_DATA}, virtio},
+    interrupt_println,
+    task::executor,
+};
 
-use crate::device::acpi::{SystemState, ACPI_DATA};
+use super::BootParameters;
 
 /// Defined in ACPI section 7.1
 const ACPI_SLP_EN: u16 = 1 << 13;
 
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: usize = 10;
+
+/// The tick at which the watchdog fires, or zero when it isn't armed.
+static WATCHDOG_DEADLINE: AtomicUsize = AtomicUsize::new(0);
+static SHUTDOWN_STAGE: AtomicU8 = AtomicU8::new(ShutdownStage::Requested as u8);
+
+/// The hypervisor-specific poweroff ports and the value to write.
+const HYPERVISOR_POWEROFF_PORTS: &[(HypervisorKind, u16, u16)] = &[
+    (HypervisorKind::Bochs, 0xB004, 0x2000),
+    (HypervisorKind::QemuOld, 0x604, 0x2000),
+    (HypervisorKind::VirtualBox, 0x4004, 0x3400),
+];
+
+/// Run in order before the machine is powered off or rebooted on request.
+pub struct ShutdownHook {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+pub const SHUTDOWN_HOOKS: &[ShutdownHook] = &[
+    ShutdownHook {
+        name: "virtio-console",
+        run: virtio::console::flush,
+    },
+    ShutdownHook {
+        // Last, so the output of the other hooks is transmitted as well.
+        name: "serial",
+        run: serial::set_synchronous,
+    },
+];
+
+static SHUTDOWN_HOOKS_RUN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ShutdownKind {
+    PowerOff = 1,
+    Reboot = 2,
+}
+
+impl ShutdownKind {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::PowerOff),
+            2 => Some(Self::Reboot),
+            _ => None,
+        }
+    }
+}
+
 pub struct System;
 
 impl System {
+    /// Stop the tasks, run the shutdown hooks and power off. When called from
+    /// a task, this returns and the executor shuts down once the tasks are
+    /// stopped.
     pub fn request_shutdown() {
-        let hypervisor = Self::detect_hypervisor();
-        info!("Requesting shutdown (hypervisor={hypervisor:?})");
-        match hypervisor {
-            Some(HypervisorKind::Bochs) => unsafe {
-                Port::new(0xB004).write(0x2000u16)
-            }
-            Some(HypervisorKind::QemuOld) => unsafe {
-                Port::new(0x604).write(0x2000u16)
+        info!("Requesting shutdown");
+        arm_watchdog();
+        set_stage(ShutdownStage::StoppingTasks);
+        if !executor::stop(ShutdownKind::PowerOff) {
+            Self::shut_down(ShutdownKind::PowerOff);
+        }
+    }
+
+    /// Like [`request_shutdown`](Self::request_shutdown), but rebooting.
+    pub fn request_reboot() {
+        info!("Requesting reboot");
+        if !executor::stop(ShutdownKind::Reboot) {
+            Self::shut_down(ShutdownKind::Reboot);
+        }
+    }
+
+    /// Run the shutdown hooks and power off or reboot, once the tasks are
+    /// stopped.
+    pub(crate) fn shut_down(kind: ShutdownKind) {
+        match kind {
+            ShutdownKind::PowerOff => Self::power_off(),
+            ShutdownKind::Reboot => {
+                run_shutdown_hooks();
+                Self::reboot();
             }
-            // Some(HypervisorKind::QemuNew) => unsafe {
-            //     Port::new(0xf4).write(0x11u32)
-            // }
-            Some(HypervisorKind::VirtualBox) => unsafe {
-                Port::new(0x4004).write(0x3400u16)
+        }
+    }
+
+    fn power_off() {
+        let hypervisor = Self::detect_hypervisor();
+        info!("Powering off (hypervisor={hypervisor:?})");
+        set_stage(ShutdownStage::ShutdownHooks);
+        run_shutdown_hooks();
+
+        let powered_off = hypervisor.is_some_and(|hypervisor| {
+            set_stage(ShutdownStage::HypervisorPort);
+            power_off_using_hypervisor(hypervisor)
+        });
+
+        if !powered_off {
+            shutdown_using_acpi().expect("Failed to shutdown using ACPI");
+        }
+    }
+
+    /// Whether we're running unattended (the `ci` boot parameter), so there
+    /// is nobody to answer prompts.
+    pub fn is_ci_mode() -> bool {
+        BootParameters::flag("ci").unwrap_or(false)
+    }
+
+    /// Called by the timer interrupt handler with the new tick count, to
+    /// force the poweroff when the shutdown stalls.
+    ///
+    /// Must not block or allocate.
+    pub(crate) fn handle_watchdog_tick(ticks: usize) {
+        let deadline = WATCHDOG_DEADLINE.load(Ordering::Relaxed);
+        if deadline == 0 || ticks < deadline {
+            return;
+        }
+
+        WATCHDOG_DEADLINE.store(0, Ordering::Relaxed);
+
+        let stage = ShutdownStage::from_u8(SHUTDOWN_STAGE.load(Ordering::Relaxed));
+        interrupt_println!("[CRITICAL] [shutdown] Watchdog expired, the shutdown stalled in: {}", stage.description());
+
+        for (_, port, value) in HYPERVISOR_POWEROFF_PORTS {
+            unsafe { Port::new(*port).write(*value) };
+        }
+
+        // Exit with a failure code when QEMU has the isa-debug-exit device.
+        crate::exit_qemu(crate::QemuExitCode::Failed);
+        interrupt_println!("[CRITICAL] [shutdown] Forced poweroff failed");
+    }
+
+    /// Reset the machine using the keyboard controller, falling back to a
+    /// triple fault.
+    pub fn reboot() -> ! {
+        info!("Rebooting");
+        x86_64::instructions::interrupts::disable();
+
+        unsafe {
+            let mut status = Port::<u8>::new(0x64);
+
+            // Wait until the input buffer of the controller is empty.
+            for _ in 0..0x10000 {
+                if status.read() & 0b10 == 0 {
+                    break;
+                }
             }
 
-            _ => shutdown_using_acpi().expect("Failed to shutdown using ACPI"),
+            // Pulse the CPU reset line.
+            status.write(0xFE);
         }
+
+        error!("Keyboard controller reset failed, triple faulting");
+        unsafe {
+            let idt = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+            x86_64::instructions::tables::lidt(&idt);
+            x86_64::instructions::interrupts::int3();
+        }
+
+        crate::hlt_loop();
     }
 
     pub fn detect_hypervisor() -> Option<HypervisorKind> {
         let cpu: CpuId = CpuId::default();
         let cpu = cpu.get_processor_brand_string()?;
         trace!("CPU brand string: \"{}\"", cpu.as_str());
-        match cpu.as_str() {
+        match cpu.as_str().trim() {
+            "EMU_BOCHS" => Some(HypervisorKind::Bochs),
             "TCGTCGTCGTCG" => Some(HypervisorKind::QemuOld),
             "QEMU Virtual CPU version 2.5+" => Some(HypervisorKind::QemuNew),
             "VMwareVMware" => Some(HypervisorKind::VMWare),
-            "VBoxVBoxVBox" => Some(HypervisorKind::VMWare),
+            "VBoxVBoxVBox" => Some(HypervisorKind::VirtualBox),
             _ => None,
         }
     }
@@ -61,9 +222,9 @@ enum AcpiShutdownErrorKind {
 
     PmControlAddressNotInIoPortRange(u64),
     PmControlBlockNotInSystemIoSpace(AddressSpace),
-    S5PathNotPackage,
-    S5ValueNotInteger,
-    S5ValueOutsideWordSize(u64),
+
+    /// `\_S5_` isn't a package of the sleep types.
+    S5(AmlTypeError),
 }
 
 impl From<AcpiError> for AcpiShutdownErrorKind {
@@ -78,6 +239,92 @@ impl From<AmlError> for AcpiShutdownErrorKind {
     }
 }
 
+impl From<AmlTypeError> for AcpiShutdownErrorKind {
+    fn from(value: AmlTypeError) -> Self {
+        Self::S5(value)
+    }
+}
+
+/// The part of the shutdown that's in progress, reported by the watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum ShutdownStage {
+    Requested = 0,
+    HypervisorPort = 1,
+    PrepareToSleep = 2,
+    GoingToSleep = 3,
+    EnterSleepState = 4,
+    Recover = 5,
+    StoppingTasks = 6,
+    ShutdownHooks = 7,
+}
+
+impl ShutdownStage {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::HypervisorPort,
+            2 => Self::PrepareToSleep,
+            3 => Self::GoingToSleep,
+            4 => Self::EnterSleepState,
+            5 => Self::Recover,
+            6 => Self::StoppingTasks,
+            7 => Self::ShutdownHooks,
+            _ => Self::Requested,
+        }
+    }
+
+    const fn description(&self) -> &'static str {
+        match self {
+            Self::Requested => "requested",
+            Self::StoppingTasks => "stopping the tasks",
+            Self::ShutdownHooks => "running the shutdown hooks",
+            Self::HypervisorPort => "hypervisor poweroff port",
+            Self::PrepareToSleep => "AML \\_PTS (prepare to sleep)",
+            Self::GoingToSleep => "AML \\_GTS (going to sleep) and disabling GPEs",
+            Self::EnterSleepState => "entering S5 using the PM1 control block",
+            Self::Recover => "AML \\_BFS and \\_WAK (recovering from a failed shutdown)",
+        }
+    }
+}
+
+fn run_shutdown_hooks() {
+    if SHUTDOWN_HOOKS_RUN.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    for hook in SHUTDOWN_HOOKS {
+        trace!("Running shutdown hook {}", hook.name);
+        (hook.run)();
+    }
+}
+
+fn set_stage(stage: ShutdownStage) {
+    trace!("Shutdown stage: {}", stage.description());
+    SHUTDOWN_STAGE.store(stage as u8, Ordering::Relaxed);
+}
+
+fn arm_watchdog() {
+    let timeout = BootParameters::get("shutdown_timeout").and_then(|timeout| timeout.parse().ok());
+    if timeout.is_none() && !System::is_ci_mode() {
+        return;
+    }
+
+    let timeout = timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS);
+    trace!("Shutdown watchdog armed for {timeout} seconds");
+
+    set_stage(ShutdownStage::Requested);
+    WATCHDOG_DEADLINE.store(arch::ticks() + timeout * arch::TICKS_PER_SECOND, Ordering::Relaxed);
+}
+
+fn power_off_using_hypervisor(hypervisor: HypervisorKind) -> bool {
+    let Some((_, port, value)) = HYPERVISOR_POWEROFF_PORTS.iter().find(|(kind, _, _)| *kind == hypervisor) else {
+        return false;
+    };
+
+    unsafe { Port::new(*port).write(*value) };
+    true
+}
+
 fn shutdown_using_acpi() -> Result<(), AcpiShutdownErrorKind> {
     trace!("Shutdown mechanism is ACPI");
 
@@ -96,6 +343,7 @@ fn shutdown_using_acpi() -> Result<(), AcpiShutdownErrorKind> {
 }
 
 fn before_acpi_shutdown() -> Result<(), AcpiShutdownErrorKind> {
+    set_stage(ShutdownStage::PrepareToSleep);
     let mut acpi = ACPI_DATA.lock();
 
     if let Some(aml) = acpi.aml.as_mut() {
@@ -127,49 +375,56 @@ fn recover_acpi_shutdown() {
     };
 
     trace!("Recovering from invalid Shutdown");
+    set_stage(ShutdownStage::Recover);
+    aml.resume();
+    _ = aml.invoke_back_from_sleep(SystemState::S5);
     _ = aml.invoke_system_wake(SystemState::S5);
 }
 
 fn do_shutdown_using_acpi() -> Result<(), AcpiShutdownErrorKind> {
-    let acpi = ACPI_DATA.lock();
+    set_stage(ShutdownStage::GoingToSleep);
+    let mut acpi = ACPI_DATA.lock();
+    let AcpiData { aml, fadt, .. } = &mut *acpi;
 
-    let Some(aml) = acpi.aml.as_ref() else {
+    let Some(aml) = aml.as_mut() else {
         return Err(AcpiShutdownErrorKind::NoAml);
     };
 
-    let Some(fadt) = acpi.fadt.as_ref() else {
+    let Some(fadt) = fadt.as_ref() else {
         return Err(AcpiShutdownErrorKind::NoFadt);
     };
 
     let s5_path = AmlName::from_str("\\_S5_")?;
-    let s5_value = aml.namespace().get_by_path(&s5_path)?;
-    let AmlValue::Package(s5_pkg) = s5_value else {
-        error!("S5 value is not a package: {s5_value:#?}");
-        return Err(AcpiShutdownErrorKind::S5PathNotPackage);
-    };
+    let (sleep_type_a, sleep_type_b) = sleep_types(aml.get(&s5_path)?)
+        .inspect_err(|e| error!("Invalid sleep types: {e}"))?;
+
+    if aml.invoke_going_to_sleep(SystemState::S5)? {
+        trace!("Invoked GoingToSleep");
+    }
+
+    // No event may run AML from here on, as the context is shut down next.
+    gpe::disable_all(fadt);
+    aml.shut_down();
+
+    set_stage(ShutdownStage::EnterSleepState);
 
     let pm1a_control_block = fadt.pm1a_control_block()?;
-    perform_acpi_sleep(&s5_pkg[0], pm1a_control_block)?;
+    perform_acpi_sleep(sleep_type_a, pm1a_control_block)?;
 
     if let Some(pm1b_control_block) = fadt.pm1b_control_block()? {
-        perform_acpi_sleep(&s5_pkg[1], pm1b_control_block)?;
+        perform_acpi_sleep(sleep_type_b, pm1b_control_block)?;
     }
 
     Ok(())
 }
 
-fn perform_acpi_sleep(s5_value: &AmlValue, control_block: GenericAddress) -> Result<(), AcpiShutdownErrorKind> {
-    let AmlValue::Integer(sleep_type) = s5_value else {
-        return Err(AcpiShutdownErrorKind::S5ValueNotInteger);
-    };
-
-    let sleep_type = *sleep_type;
-    if sleep_type > u16::MAX as u64 {
-        return Err(AcpiShutdownErrorKind::S5ValueOutsideWordSize(sleep_type));
-    }
-
-    let sleep_type = sleep_type as u16;
+/// The values of `SLP_TYPa` and `SLP_TYPb` in the `\_S5_` package.
+fn sleep_types(s5: AmlObject) -> Result<(u16, u16), AmlTypeError> {
+    let [a, b] = s5.as_package_n::<2>()?;
+    Ok((a.as_integer_of()?, b.as_integer_of()?))
+}
 
+fn perform_acpi_sleep(sleep_type: u16, control_block: GenericAddress) -> Result<(), AcpiShutdownErrorKind> {
     if control_block.address_space != AddressSpace::SystemIo {
         error!("PM control block not in System I/O Address Space: {control_block:#x?}");
         return Err(AcpiShutdownErrorKind::PmControlBlockNotInSystemIoSpace(control_block.address_space));
@@ -0,0 +1,66 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The identity of the running build, embedded by the build script.
+//!
+//! The build id is a hash of the other fields, which tells builds apart even
+//! when they're made from the same commit. It's printed in the boot banner, so
+//! `cargo run symbolize` can check that the log belongs to the kernel ELF it
+//! resolves the addresses with, which finds the id through [`BUILD_ID_MARKER`].
+
+use core::fmt::{Display, Formatter};
+
+/// Precedes the build id in the kernel image, and in the boot banner.
+pub const BUILD_ID_PREFIX: &str = "build-id ";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Version {
+    /// The version of the kernel crate.
+    pub version: &'static str,
+    pub build_id: &'static str,
+
+    /// The abbreviated commit, with `-dirty` when there were uncommitted
+    /// changes.
+    pub git_hash: &'static str,
+
+    /// When the kernel was built, in ISO 8601 in UTC.
+    pub build_time: &'static str,
+    pub rustc: &'static str,
+}
+
+static VERSION: Version = Version {
+    version: env!("CARGO_PKG_VERSION"),
+    build_id: env!("NOCCIOLO_BUILD_ID"),
+    git_hash: env!("NOCCIOLO_GIT_HASH"),
+    build_time: env!("NOCCIOLO_BUILD_TIME"),
+    rustc: env!("NOCCIOLO_RUSTC_VERSION"),
+};
+
+const MARKER: &str = concat!("NOCCIOLO ", "build-id ", env!("NOCCIOLO_BUILD_ID"), "\0");
+
+/// The build id in a form the host can find in the ELF file.
+#[used]
+static BUILD_ID_MARKER: [u8; MARKER.len()] = {
+    let mut bytes = [0; MARKER.len()];
+    let mut index = 0;
+    while index < MARKER.len() {
+        bytes[index] = MARKER.as_bytes()[index];
+        index += 1;
+    }
+    bytes
+};
+
+#[must_use]
+pub fn version() -> &'static Version {
+    // Referenced so the linker doesn't discard the marker with the unused
+    // sections.
+    core::hint::black_box(&BUILD_ID_MARKER);
+    &VERSION
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "nocciolo {} ({BUILD_ID_PREFIX}{}, git {}, built {}, {})",
+            self.version, self.build_id, self.git_hash, self.build_time, self.rustc)
+    }
+}
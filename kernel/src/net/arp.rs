@@ -0,0 +1,244 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The Address Resolution Protocol, which finds the MAC address of an IPv4
+//! address on the link. A request is broadcast, and answered by the owner of
+//! the address:
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 2    | Hardware type, 1 for Ethernet                            |
+//! | 2      | 2    | Protocol type, the EtherType of IPv4                     |
+//! | 4      | 1    | Hardware address length, 6                               |
+//! | 5      | 1    | Protocol address length, 4                               |
+//! | 6      | 2    | Operation, 1 for a request and 2 for a reply             |
+//! | 8      | 6+4  | Sender MAC and IPv4 address                              |
+//! | 18     | 6+4  | Target MAC (zero in requests) and IPv4 address           |
+//!
+//! The answers are kept in a cache for [`CACHE_LIFETIME`], which every packet
+//! of the sender refreshes, as do requests for our address, since the sender
+//! will likely talk to us next. [`resolve`] waits for the reply when the
+//! address isn't cached, asking [`REQUEST_ATTEMPTS`] times.
+//!
+//! ### References:
+//! - [RFC 826: An Ethernet Address Resolution Protocol](https://www.rfc-editor.org/rfc/rfc826)
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt::{Display, Formatter}, task::{Poll, Waker}, time::Duration};
+
+use futures_util::future::poll_fn;
+use log::trace;
+
+use crate::{arch, meta::counters::Counter, sync::Spinlock, task::timer};
+
+use super::{
+    ethernet,
+    interface,
+    ipv4::{Ipv4Address, Ipv4InterfaceAddress},
+    MacAddress,
+    OutgoingPacket,
+};
+
+pub const PACKET_SIZE: usize = 28;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+pub const CACHE_LIFETIME: Duration = Duration::from_secs(60);
+
+pub const REQUEST_ATTEMPTS: usize = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+static REQUESTS: Counter = Counter::new("net.arp.requests", "ARP requests sent");
+static REPLIES: Counter = Counter::new("net.arp.replies", "ARP requests for our address answered");
+
+/// The MAC addresses, with the tick count at which they expire.
+static CACHE: Spinlock<BTreeMap<Ipv4Address, (MacAddress, usize)>> = Spinlock::new(BTreeMap::new());
+
+/// The [`resolve`]s waiting for a reply.
+static WAITERS: Spinlock<Vec<(Ipv4Address, Waker)>> = Spinlock::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpError {
+    /// No interface has an IPv4 address to ask from.
+    NoInterface,
+
+    /// Nobody answered the requests.
+    Timeout,
+}
+
+impl Display for ArpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoInterface => f.write_str("no interface has an IPv4 address"),
+            Self::Timeout => f.write_str("nobody answered"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    /// Parse a packet of Ethernet and IPv4 addresses, ignoring the padding
+    /// of the frame after it.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let packet = packet.get(..PACKET_SIZE)?;
+        let hardware = u16::from_be_bytes([packet[0], packet[1]]);
+        let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+        if hardware != HARDWARE_ETHERNET || protocol != ethernet::ETHER_TYPE_IPV4 || packet[4] != 6 || packet[5] != 4 {
+            return None;
+        }
+
+        Some(Self {
+            operation: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: MacAddress(packet[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Address(packet[14..18].try_into().unwrap()),
+            target_mac: MacAddress(packet[18..24].try_into().unwrap()),
+            target_ip: Ipv4Address(packet[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        packet.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        packet.extend_from_slice(&ethernet::ETHER_TYPE_IPV4.to_be_bytes());
+        packet.extend_from_slice(&[6, 4]);
+        packet.extend_from_slice(&self.operation.to_be_bytes());
+        packet.extend_from_slice(&self.sender_mac.0);
+        packet.extend_from_slice(&self.sender_ip.0);
+        packet.extend_from_slice(&self.target_mac.0);
+        packet.extend_from_slice(&self.target_ip.0);
+        packet
+    }
+}
+
+/// The request for the MAC address of the target, from the interface with
+/// the given addresses.
+pub fn request(mac: MacAddress, address: Ipv4InterfaceAddress, target: Ipv4Address) -> OutgoingPacket {
+    REQUESTS.increment();
+    let packet = ArpPacket {
+        operation: OPERATION_REQUEST,
+        sender_mac: mac,
+        sender_ip: address.address,
+        target_mac: MacAddress([0; 6]),
+        target_ip: target,
+    };
+    OutgoingPacket { destination: MacAddress::BROADCAST, data: packet.build() }
+}
+
+/// Learn from a received packet, and answer it when it's a request for our
+/// address.
+pub fn handle_packet(mac: MacAddress, address: Option<Ipv4InterfaceAddress>, packet: &[u8]) -> Option<OutgoingPacket> {
+    let packet = ArpPacket::parse(packet)?;
+    if packet.sender_ip.is_unspecified() || packet.sender_mac.is_multicast() {
+        // Probes of Address Conflict Detection, or bogus.
+        return None;
+    }
+
+    let ours = address.is_some_and(|address| address.address == packet.target_ip);
+    let known = CACHE.lock().contains_key(&packet.sender_ip);
+    if ours || known {
+        insert(packet.sender_ip, packet.sender_mac);
+    }
+
+    if !ours || packet.operation != OPERATION_REQUEST {
+        return None;
+    }
+
+    REPLIES.increment();
+    let reply = ArpPacket {
+        operation: OPERATION_REPLY,
+        sender_mac: mac,
+        sender_ip: packet.target_ip,
+        target_mac: packet.sender_mac,
+        target_ip: packet.sender_ip,
+    };
+    Some(OutgoingPacket { destination: packet.sender_mac, data: reply.build() })
+}
+
+fn insert(ip: Ipv4Address, mac: MacAddress) {
+    let expires = arch::ticks() + CACHE_LIFETIME.as_secs() as usize * arch::TICKS_PER_SECOND;
+    if CACHE.lock().insert(ip, (mac, expires)).map(|(previous, _)| previous) != Some(mac) {
+        trace!("ARP: {ip} is at {mac}");
+    }
+
+    WAITERS.lock().retain(|(waiting, waker)| {
+        if *waiting != ip {
+            return true;
+        }
+        waker.wake_by_ref();
+        false
+    });
+}
+
+/// The cached MAC address of the IPv4 address, if it hasn't expired.
+pub fn lookup(ip: Ipv4Address) -> Option<MacAddress> {
+    if ip.is_broadcast() {
+        return Some(MacAddress::BROADCAST);
+    }
+
+    let mut cache = CACHE.lock();
+    match cache.get(&ip) {
+        Some(&(mac, expires)) if arch::ticks() < expires => Some(mac),
+        Some(..) => {
+            cache.remove(&ip);
+            None
+        }
+        None => None,
+    }
+}
+
+/// The entries of the cache, with the time until they expire.
+pub fn entries() -> Vec<(Ipv4Address, MacAddress, Duration)> {
+    let now = arch::ticks();
+    CACHE.lock().iter()
+        .filter(|(_, (_, expires))| *expires > now)
+        .map(|(ip, (mac, expires))| {
+            (*ip, *mac, Duration::from_millis(((expires - now) * 1000 / arch::TICKS_PER_SECOND) as u64))
+        })
+        .collect()
+}
+
+/// Find the MAC address of an IPv4 address on the link of one of the
+/// interfaces, asking for it when it isn't cached.
+pub async fn resolve(ip: Ipv4Address) -> Result<MacAddress, ArpError> {
+    for _ in 0..REQUEST_ATTEMPTS {
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+
+        interface::with_ipv4_route(ip, |interface| interface.request_arp(ip))
+            .ok_or(ArpError::NoInterface)?;
+
+        let mut registered: Option<Waker> = None;
+        let reply = poll_fn(|cx| {
+            if let Some(mac) = lookup(ip) {
+                return Poll::Ready(mac);
+            }
+            if !registered.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+                WAITERS.lock().push((ip, cx.waker().clone()));
+                registered = Some(cx.waker().clone());
+            }
+            Poll::Pending
+        });
+        let result = timer::timeout(REQUEST_TIMEOUT, reply).await;
+
+        // Unless the reply took it already.
+        if let Some(registered) = registered {
+            WAITERS.lock().retain(|(waiting, waker)| *waiting != ip || !waker.will_wake(&registered));
+        }
+        if let Ok(mac) = result {
+            return Ok(mac);
+        }
+    }
+
+    Err(ArpError::Timeout)
+}
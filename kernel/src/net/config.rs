@@ -0,0 +1,106 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The network configuration that was learned from the network, i.e. the
+//! lease the [`dhcp`](super::dhcp) client obtained, as opposed to the
+//! `net.ipv4.*` entries of the [configuration](crate::meta::config), which
+//! are set by hand. Setting the lease applies its address and gateway to the
+//! interface.
+
+use alloc::{string::String, vec::Vec};
+use core::time::Duration;
+
+use crate::{arch, sync::Spinlock};
+
+use super::{
+    interface,
+    ipv4::{Ipv4Address, Ipv4InterfaceAddress},
+};
+
+static LEASE: Spinlock<Option<Ipv4Lease>> = Spinlock::new(None);
+
+/// An IPv4 address with the parameters that came with it, which may be used
+/// until it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv4Lease {
+    pub interface: String,
+    pub address: Ipv4InterfaceAddress,
+    pub gateway: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+
+    /// The DHCP server that handed out the lease.
+    pub server: Ipv4Address,
+
+    /// The tick count at which the lease was obtained or last renewed, from
+    /// which the times below count.
+    pub obtained: usize,
+    pub duration: Duration,
+
+    /// When to ask the server to extend the lease (T1).
+    pub renewal: Duration,
+
+    /// When to ask any server to extend the lease, since the server didn't
+    /// answer (T2).
+    pub rebinding: Duration,
+}
+
+impl Ipv4Lease {
+    pub fn renewal_deadline(&self) -> usize {
+        self.deadline(self.renewal)
+    }
+
+    pub fn rebinding_deadline(&self) -> usize {
+        self.deadline(self.rebinding)
+    }
+
+    pub fn expiry_deadline(&self) -> usize {
+        self.deadline(self.duration)
+    }
+
+    /// The time until the lease expires.
+    pub fn remaining(&self) -> Duration {
+        let ticks = self.expiry_deadline().saturating_sub(arch::ticks());
+        Duration::from_millis((ticks * 1000 / arch::TICKS_PER_SECOND) as u64)
+    }
+
+    fn deadline(&self, after: Duration) -> usize {
+        self.obtained.saturating_add(after.as_secs() as usize * arch::TICKS_PER_SECOND)
+    }
+}
+
+/// The current lease, if any.
+pub fn lease() -> Option<Ipv4Lease> {
+    LEASE.lock().clone()
+}
+
+/// The DNS servers of the lease.
+pub fn dns_servers() -> Vec<Ipv4Address> {
+    LEASE.lock().as_ref().map(|lease| lease.dns_servers.clone()).unwrap_or_default()
+}
+
+/// Replace the lease, configuring its interface with the new address and
+/// gateway, or removing the old ones without a new lease.
+pub fn set_lease(lease: Option<Ipv4Lease>) {
+    let mut current = LEASE.lock();
+    match &lease {
+        Some(lease) => {
+            interface::with_interface(&lease.interface, |interface| {
+                interface.set_ipv4_address(Some(lease.address));
+                interface.set_ipv4_gateway(lease.gateway);
+            });
+        }
+        None => if let Some(previous) = current.as_ref() {
+            interface::with_interface(&previous.interface, |interface| {
+                interface.set_ipv4_address(None);
+                interface.set_ipv4_gateway(None);
+            });
+        }
+    }
+    *current = lease;
+}
+
+/// Forget the lease without touching the interface, of which the address was
+/// configured otherwise.
+pub fn forget_lease() {
+    LEASE.lock().take();
+}
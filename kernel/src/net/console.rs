@@ -0,0 +1,203 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A telnet-style network console, which gives remote clients access to the
+//! kernel shell on [TCP](super::tcp) port [`PORT`].
+//!
+//! Only clients in the `netconsole.allow` configuration entry (a comma
+//! separated list of IPv4 addresses) are accepted; when the entry isn't set,
+//! the console refuses everyone. The `netconsole` task (see [`run`]) serves
+//! one client at a time, while the next ones wait in the backlog of the
+//! listener. Telnet option negotiation is ignored, so clients stay in their
+//! default line mode with local echo.
+//!
+//! ### References:
+//! - [RFC 854: Telnet Protocol Specification](https://www.rfc-editor.org/rfc/rfc854)
+
+use alloc::{string::String, vec::Vec};
+
+use log::{info, warn};
+
+use crate::{meta::{config, Console}, task::shell};
+
+use super::{ipv4::Ipv4Address, tcp::{TcpListener, TcpStream}};
+
+pub const PORT: u16 = 23;
+
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+
+const PROMPT: &[u8] = b"> ";
+
+/// Whether the client is in the `netconsole.allow` list.
+pub fn is_allowed(peer: &Ipv4Address) -> bool {
+    let Some(allowed) = config::get(config::KEY_NETCONSOLE_ALLOW) else {
+        return false;
+    };
+
+    allowed.split(',')
+        .map(str::trim)
+        .filter_map(|entry| match entry.parse::<Ipv4Address>() {
+            Ok(address) => Some(address),
+            Err(()) => {
+                warn!("Ignoring invalid address `{entry}` in {}", config::KEY_NETCONSOLE_ALLOW);
+                None
+            }
+        })
+        .any(|address| address == *peer)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    Command,
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// The state of a single client connection, which is fed the received bytes
+/// and produces the bytes to send back.
+pub struct NetConsoleSession {
+    peer: Ipv4Address,
+    line: String,
+    state: TelnetState,
+    last_was_cr: bool,
+}
+
+impl NetConsoleSession {
+    /// Accept a client, returning the session and the greeting to send, or
+    /// `None` if the client isn't allowed.
+    pub fn accept(peer: Ipv4Address) -> Option<(Self, Vec<u8>)> {
+        if !is_allowed(&peer) {
+            warn!("Refusing network console connection from {peer}");
+            return None;
+        }
+
+        info!("Network console connection from {peer}");
+
+        let mut greeting = Vec::from(b"Nocciolo kernel shell\r\n".as_slice());
+        greeting.extend_from_slice(PROMPT);
+
+        Some((Self {
+            peer,
+            line: String::new(),
+            state: TelnetState::Data,
+            last_was_cr: false,
+        }, greeting))
+    }
+
+    pub fn peer(&self) -> Ipv4Address {
+        self.peer
+    }
+
+    /// Process received data, running the complete lines as shell commands
+    /// and returning their output.
+    pub fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        for byte in data.iter().copied() {
+            self.state = match (self.state, byte) {
+                (TelnetState::Data, IAC) => TelnetState::Command,
+                (TelnetState::Data, _) => {
+                    self.feed(byte, &mut output);
+                    TelnetState::Data
+                }
+
+                // An escaped 0xFF data byte, which isn't valid in a command line.
+                (TelnetState::Command, IAC) => TelnetState::Data,
+                (TelnetState::Command, SB) => TelnetState::Subnegotiation,
+                (TelnetState::Command, WILL..=DONT) => TelnetState::Option,
+                (TelnetState::Command, _) => TelnetState::Data,
+                (TelnetState::Option, _) => TelnetState::Data,
+
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationCommand,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationCommand, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationCommand, _) => TelnetState::Subnegotiation,
+            };
+        }
+
+        output
+    }
+
+    fn feed(&mut self, byte: u8, output: &mut Vec<u8>) {
+        // Lines end in CR LF or CR NUL, but accept a bare LF too.
+        let was_cr = core::mem::replace(&mut self.last_was_cr, byte == b'\r');
+        match byte {
+            b'\r' => self.execute_line(output),
+            b'\n' | 0 if was_cr => (),
+            b'\n' => self.execute_line(output),
+            0x08 | 0x7F => {
+                self.line.pop();
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => self.line.push(byte as char),
+            _ => (),
+        }
+    }
+
+    fn execute_line(&mut self, output: &mut Vec<u8>) {
+        let line = core::mem::take(&mut self.line);
+        info!("netconsole {}: {line}", self.peer);
+
+        let captured = Console::capture(|| shell::execute(&line));
+
+        // Telnet clients expect CR LF line endings.
+        for byte in captured.bytes() {
+            if byte == b'\n' {
+                output.push(b'\r');
+            }
+            output.push(byte);
+        }
+
+        output.extend_from_slice(PROMPT);
+    }
+}
+
+/// The task that accepts the clients of the network console.
+pub async fn run() {
+    let listener = match TcpListener::bind(PORT) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Network console unavailable: {e}");
+            return;
+        }
+    };
+
+    loop {
+        let stream = listener.accept().await;
+        serve(stream).await;
+    }
+}
+
+/// Serve a client until it disconnects. Dropping the stream closes the
+/// connection, including when the client isn't allowed.
+async fn serve(stream: TcpStream) {
+    let Some((mut session, greeting)) = NetConsoleSession::accept(stream.peer().0) else {
+        return;
+    };
+
+    let mut output = greeting;
+    let mut buffer = [0; 512];
+    loop {
+        if let Err(e) = stream.write_all(&output).await {
+            warn!("netconsole {}: {e}", session.peer());
+            return;
+        }
+
+        let length = match stream.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(length) => length,
+            Err(e) => {
+                warn!("netconsole {}: {e}", session.peer());
+                return;
+            }
+        };
+        output = session.receive(&buffer[..length]);
+    }
+
+    info!("Network console connection from {} closed", session.peer());
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Crash reports over the network: when the kernel panics, the message is
+//! sent as a single UDP datagram to the `net.crash.address` configuration
+//! entry (e.g. `10.0.2.2:5140`, received on the host with `nc -ul 5140`).
+//!
+//! A panic can't wait for ARP, allocate or wait for a lock, so the
+//! `crash-report` task (see [`run`]) keeps the frame prepared in a static
+//! buffer: the Ethernet, IPv4 and UDP headers for the current route and the
+//! MAC address of the next hop. The panic handler only copies the message in,
+//! fixes the lengths and the checksum of the IPv4 header, and hands the frame
+//! to the card, unless the card is in use. The UDP checksum is left zero,
+//! which means "no checksum" over IPv4.
+//!
+//! This is best effort: a panic before the first preparation, or on a card
+//! that's busy, isn't reported, and neither is one that doesn't fit a frame,
+//! of which only the start is sent.
+
+use core::time::Duration;
+
+use crate::{
+    device::net::MAX_FRAME_SIZE,
+    meta::config,
+    sync::Spinlock,
+    task::timer,
+};
+
+use super::{
+    arp,
+    ethernet::{self, EthernetFrame},
+    interface::{self, WithDevice},
+    ipv4::{self, Ipv4Address, Ipv4Header},
+    udp,
+    InternetChecksum,
+};
+
+/// How often the frame is prepared again, e.g. for a new lease, which also
+/// keeps the MAC address of the next hop in the ARP cache.
+const PREPARE_INTERVAL: Duration = Duration::from_secs(10);
+
+const IPV4_OFFSET: usize = ethernet::HEADER_SIZE;
+const UDP_OFFSET: usize = IPV4_OFFSET + ipv4::HEADER_SIZE;
+const DATA_OFFSET: usize = UDP_OFFSET + udp::HEADER_SIZE;
+
+/// The prepared frame, of which only the headers are filled in until the
+/// panic.
+static FRAME: Spinlock<CrashFrame> = Spinlock::new(CrashFrame {
+    bytes: [0; MAX_FRAME_SIZE],
+    device: None,
+});
+
+struct CrashFrame {
+    bytes: [u8; MAX_FRAME_SIZE],
+
+    /// The card to send the frame with and the function that lends it out
+    /// without waiting, `None` while nothing is prepared.
+    device: Option<(usize, WithDevice)>,
+}
+
+/// Send the panic message to the configured address, if a frame is prepared.
+///
+/// Must not block or allocate.
+pub(crate) fn send_panic(message: &str) {
+    let Some(mut frame) = FRAME.try_lock() else {
+        return;
+    };
+    let Some((card, try_with_device)) = frame.device else {
+        return;
+    };
+
+    let length = message.len().min(MAX_FRAME_SIZE - DATA_OFFSET);
+    frame.bytes[DATA_OFFSET..DATA_OFFSET + length].copy_from_slice(&message.as_bytes()[..length]);
+
+    let ip_length = (DATA_OFFSET - IPV4_OFFSET + length) as u16;
+    frame.bytes[IPV4_OFFSET + 2..IPV4_OFFSET + 4].copy_from_slice(&ip_length.to_be_bytes());
+    frame.bytes[IPV4_OFFSET + 10..IPV4_OFFSET + 12].fill(0);
+    let mut checksum = InternetChecksum::new();
+    checksum.add(&frame.bytes[IPV4_OFFSET..UDP_OFFSET]);
+    frame.bytes[IPV4_OFFSET + 10..IPV4_OFFSET + 12].copy_from_slice(&checksum.finish().to_be_bytes());
+
+    let udp_length = (DATA_OFFSET - UDP_OFFSET + length) as u16;
+    frame.bytes[UDP_OFFSET + 4..UDP_OFFSET + 6].copy_from_slice(&udp_length.to_be_bytes());
+    frame.bytes[UDP_OFFSET + 6..UDP_OFFSET + 8].fill(0);
+
+    let frame = &frame.bytes[..DATA_OFFSET + length];
+    try_with_device(card, &mut |device| _ = device.send_frame(frame));
+}
+
+/// The task that keeps the frame of [`send_panic`] prepared.
+pub async fn run() {
+    loop {
+        let prepared = match destination() {
+            Some((address, port)) => prepare(address, port).await,
+            None => None,
+        };
+
+        {
+            let mut frame = FRAME.lock();
+            match prepared {
+                Some((headers, device)) => {
+                    frame.bytes[..DATA_OFFSET].copy_from_slice(&headers[..DATA_OFFSET]);
+                    frame.device = Some(device);
+                }
+                None => frame.device = None,
+            }
+        }
+
+        timer::sleep(PREPARE_INTERVAL).await;
+    }
+}
+
+/// The address and port of the `net.crash.address` entry.
+fn destination() -> Option<(Ipv4Address, u16)> {
+    let value = config::get(config::KEY_NET_CRASH_ADDRESS)?;
+    let (address, port) = value.split_once(':')?;
+    Some((address.parse().ok()?, port.parse().ok()?))
+}
+
+/// Build the headers of a datagram without data to the destination, from
+/// the interface that routes to it, together with its card.
+async fn prepare(destination: Ipv4Address, port: u16) -> Option<([u8; DATA_OFFSET], (usize, WithDevice))> {
+    let (mac, source, next_hop, device) = interface::with_ipv4_route(destination, |interface| {
+        Some((
+            interface.mac_address(),
+            interface.ipv4_address()?.address,
+            interface.ipv4_next_hop(&destination)?,
+            interface.try_device(),
+        ))
+    }).flatten()?;
+    let next_hop_mac = arp::resolve(next_hop).await.ok()?;
+
+    let datagram = udp::build(source, port, destination, port, &[]);
+    let packet = Ipv4Header {
+        identification: 0,
+        ttl: ipv4::DEFAULT_TTL,
+        protocol: ipv4::PROTOCOL_UDP,
+        source,
+        destination,
+    }.build(&datagram);
+    let frame = EthernetFrame {
+        destination: next_hop_mac,
+        source: mac,
+        ether_type: ethernet::ETHER_TYPE_IPV4,
+        payload: &packet,
+    }.build();
+
+    Some((frame.try_into().ok()?, device))
+}
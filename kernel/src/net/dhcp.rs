@@ -0,0 +1,376 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The DHCP client of `eth0`, which leases an IPv4 address with its subnet
+//! mask, gateway and DNS servers from the network, e.g. from QEMU's user
+//! networking, and stores the lease in [`config`](super::config). The `dhcp`
+//! task (see [`run`]) goes through the usual exchange:
+//!
+//! | Client         | Server      | Meaning                                  |
+//! |----------------|-------------|------------------------------------------|
+//! | `DHCPDISCOVER` |             | Broadcast for the servers                |
+//! |                | `DHCPOFFER` | An address a server is willing to lease  |
+//! | `DHCPREQUEST`  |             | Broadcast which offer was taken          |
+//! |                | `DHCPACK`   | The lease, or `DHCPNAK` when it's gone   |
+//!
+//! Without an address, the requests come from `0.0.0.0`, and the broadcast
+//! flag asks the server to broadcast its answers as well. The lease is
+//! renewed with the server halfway (T1), with any server from 7/8 of the
+//! duration on (T2), and given up when it expires, after which the client
+//! starts over. The client leaves `eth0` alone when the `net.ipv4.address`
+//! configuration entry is set.
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 1    | Operation, 1 for requests and 2 for replies              |
+//! | 1      | 1+1  | Hardware type (1) and address length (6)                 |
+//! | 4      | 4    | Transaction ID, chosen by the client                     |
+//! | 10     | 2    | Flags, of which the top bit asks for broadcast replies   |
+//! | 12     | 4    | Client address, when renewing                            |
+//! | 16     | 4    | "Your" address, the one offered by the server            |
+//! | 28     | 16   | Client hardware address                                  |
+//! | 236    | 4    | The magic cookie, followed by the options                |
+//!
+//! ### References:
+//! - [RFC 2131: Dynamic Host Configuration Protocol](https://www.rfc-editor.org/rfc/rfc2131)
+//! - [RFC 2132: DHCP Options and BOOTP Vendor Extensions](https://www.rfc-editor.org/rfc/rfc2132)
+
+use alloc::{string::String, vec::Vec};
+use core::time::Duration;
+
+use log::{info, trace, warn};
+
+use crate::{arch, crypto::entropy, meta::counters::Counter, task::timer};
+
+use super::{
+    config::{self, Ipv4Lease},
+    interface,
+    ipv4::{Ipv4Address, Ipv4InterfaceAddress},
+    udp::{self, UdpSocket},
+    MacAddress,
+};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// The interface the client configures.
+const INTERFACE: &str = "eth0";
+
+const OPERATION_REQUEST: u8 = 1;
+const OPERATION_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 1 << 15;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// The size of the message up to the options.
+const FIXED_SIZE: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_HOST_NAME: u8 = 12;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const TYPE_DISCOVER: u8 = 1;
+const TYPE_OFFER: u8 = 2;
+const TYPE_REQUEST: u8 = 3;
+const TYPE_ACK: u8 = 5;
+const TYPE_NAK: u8 = 6;
+
+const HOST_NAME: &[u8] = b"nocciolo";
+
+/// The first time to wait for an answer, which doubles with every attempt up
+/// to [`MAX_RETRANSMISSION`].
+const INITIAL_RETRANSMISSION: Duration = Duration::from_secs(4);
+const MAX_RETRANSMISSION: Duration = Duration::from_secs(64);
+
+/// The number of times a `DHCPREQUEST` for an offer is sent before starting
+/// over.
+const REQUEST_ATTEMPTS: u32 = 4;
+
+/// The least time between the renewal attempts.
+const MIN_RENEWAL_RETRY: Duration = Duration::from_secs(60);
+
+static TRANSMITTED: Counter = Counter::new("net.dhcp.transmitted", "DHCP messages sent");
+static LEASES: Counter = Counter::new("net.dhcp.leases", "DHCP leases obtained or renewed");
+
+/// The fields of a received message the client cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DhcpMessage {
+    operation: u8,
+    transaction: u32,
+    your_address: Ipv4Address,
+    client: MacAddress,
+    message_type: Option<u8>,
+    server: Option<Ipv4Address>,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl DhcpMessage {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < FIXED_SIZE || data[1] != HARDWARE_ETHERNET || data[2] != 6 || data[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let address = |value: &[u8]| value.get(..4).map(|value| Ipv4Address(value.try_into().unwrap()));
+        let seconds = |value: &[u8]| value.get(..4).map(|value| u32::from_be_bytes(value.try_into().unwrap()));
+
+        let mut message = Self {
+            operation: data[0],
+            transaction: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            your_address: address(&data[16..20])?,
+            client: MacAddress(data[28..34].try_into().unwrap()),
+            message_type: None,
+            server: None,
+            subnet_mask: None,
+            router: None,
+            dns_servers: Vec::new(),
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        };
+
+        let mut options = &data[FIXED_SIZE..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => (),
+            }
+
+            let (&length, rest) = rest.split_first()?;
+            let value = rest.get(..length as usize)?;
+            options = &rest[length as usize..];
+
+            match *code {
+                OPTION_MESSAGE_TYPE => message.message_type = value.first().copied(),
+                OPTION_SERVER_IDENTIFIER => message.server = address(value),
+                OPTION_SUBNET_MASK => message.subnet_mask = address(value),
+                OPTION_ROUTER => message.router = address(value),
+                OPTION_DNS_SERVERS => message.dns_servers = value.chunks_exact(4).filter_map(address).collect(),
+                OPTION_LEASE_TIME => message.lease_time = seconds(value),
+                OPTION_RENEWAL_TIME => message.renewal_time = seconds(value),
+                OPTION_REBINDING_TIME => message.rebinding_time = seconds(value),
+                _ => (),
+            }
+        }
+
+        Some(message)
+    }
+
+    /// The lease of a `DHCPACK`, counting from now.
+    fn lease(&self) -> Option<Ipv4Lease> {
+        let duration = Duration::from_secs(self.lease_time? as u64);
+
+        // A missing mask leaves only the router on the link.
+        let prefix_length = self.subnet_mask.map_or(32, |mask| u32::from_be_bytes(mask.0).leading_ones() as u8);
+        Some(Ipv4Lease {
+            interface: String::from(INTERFACE),
+            address: Ipv4InterfaceAddress { address: self.your_address, prefix_length },
+            gateway: self.router,
+            dns_servers: self.dns_servers.clone(),
+            server: self.server?,
+            obtained: arch::ticks(),
+            duration,
+            renewal: self.renewal_time.map_or(duration / 2, |time| Duration::from_secs(time as u64)),
+            rebinding: self.rebinding_time.map_or(duration * 7 / 8, |time| Duration::from_secs(time as u64)),
+        })
+    }
+}
+
+/// Build a request of the given type, with the client address when renewing,
+/// and the offered address and its server when taking an offer.
+fn build(message_type: u8, transaction: u32, mac: MacAddress, client_address: Ipv4Address, offer: Option<(Ipv4Address, Ipv4Address)>) -> Vec<u8> {
+    let mut message = Vec::with_capacity(FIXED_SIZE + 32);
+    message.extend_from_slice(&[OPERATION_REQUEST, HARDWARE_ETHERNET, 6, 0]);
+    message.extend_from_slice(&transaction.to_be_bytes());
+    message.extend_from_slice(&[0, 0]);
+
+    // Renewals are unicast, and so are their answers.
+    let flags = if client_address.is_unspecified() { FLAG_BROADCAST } else { 0 };
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&client_address.0);
+    message.resize(28, 0);
+    message.extend_from_slice(&mac.0);
+    message.resize(236, 0);
+    message.extend_from_slice(&MAGIC_COOKIE);
+
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+    if let Some((address, server)) = offer {
+        message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+        message.extend_from_slice(&address.0);
+        message.extend_from_slice(&[OPTION_SERVER_IDENTIFIER, 4]);
+        message.extend_from_slice(&server.0);
+    }
+    message.extend_from_slice(&[OPTION_HOST_NAME, HOST_NAME.len() as u8]);
+    message.extend_from_slice(HOST_NAME);
+    message.extend_from_slice(&[
+        OPTION_PARAMETER_REQUEST_LIST, 5,
+        OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVERS, OPTION_RENEWAL_TIME, OPTION_REBINDING_TIME,
+    ]);
+    message.push(OPTION_END);
+    message
+}
+
+/// Wait for the reply to the transaction, ignoring the other datagrams.
+async fn receive(socket: &UdpSocket, transaction: u32, mac: MacAddress, wait: Duration) -> Option<DhcpMessage> {
+    timer::timeout(wait, async {
+        loop {
+            let datagram = socket.recv_from().await;
+            let Some(message) = DhcpMessage::parse(&datagram.data) else {
+                continue;
+            };
+            if message.operation == OPERATION_REPLY && message.transaction == transaction && message.client == mac {
+                return message;
+            }
+        }
+    }).await.ok()
+}
+
+fn broadcast(socket: &UdpSocket, message: &[u8]) {
+    match socket.broadcast(INTERFACE, message, SERVER_PORT) {
+        Ok(()) => TRANSMITTED.increment(),
+        Err(e) => trace!("DHCP: failed to broadcast: {e}"),
+    }
+}
+
+/// Discover the servers and take the first offer, until one is acknowledged.
+async fn acquire(socket: &UdpSocket, mac: MacAddress) -> Ipv4Lease {
+    let mut wait = INITIAL_RETRANSMISSION;
+    loop {
+        let transaction = entropy::random_u64() as u32;
+        broadcast(socket, &build(TYPE_DISCOVER, transaction, mac, Ipv4Address::UNSPECIFIED, None));
+
+        let offer = receive(socket, transaction, mac, wait).await
+            .filter(|message| message.message_type == Some(TYPE_OFFER))
+            .and_then(|offer| Some((offer.your_address, offer.server?)));
+        wait = (wait * 2).min(MAX_RETRANSMISSION);
+        let Some((address, server)) = offer else {
+            continue;
+        };
+
+        trace!("DHCP: {server} offered {address}");
+        let request = build(TYPE_REQUEST, transaction, mac, Ipv4Address::UNSPECIFIED, Some((address, server)));
+        for _ in 0..REQUEST_ATTEMPTS {
+            broadcast(socket, &request);
+            let Some(reply) = receive(socket, transaction, mac, INITIAL_RETRANSMISSION).await else {
+                continue;
+            };
+
+            match reply.message_type {
+                Some(TYPE_ACK) => if let Some(lease) = reply.lease() {
+                    return lease;
+                },
+                Some(TYPE_NAK) => trace!("DHCP: {server} took back the offer of {address}"),
+                _ => continue,
+            }
+            break;
+        }
+    }
+}
+
+/// Extend the lease until it can't be, returning `false` when the address
+/// of the interface was changed by hand, which ends the client.
+async fn maintain(socket: &UdpSocket, mac: MacAddress, mut lease: Ipv4Lease) -> bool {
+    loop {
+        timer::sleep_until(lease.renewal_deadline()).await;
+
+        loop {
+            let current = interface::with_interface(INTERFACE, |interface| interface.ipv4_address()).flatten();
+            if current != Some(lease.address) {
+                info!("DHCP: the address of {INTERFACE} was configured by hand, giving up the lease");
+                return false;
+            }
+
+            let now = arch::ticks();
+            if now >= lease.expiry_deadline() {
+                warn!("DHCP: the lease of {} expired", lease.address);
+                return true;
+            }
+
+            // Ask the server, or anyone after T2, every half of the time
+            // that is left until the next of them.
+            let rebinding = now >= lease.rebinding_deadline();
+            let transaction = entropy::random_u64() as u32;
+            let request = build(TYPE_REQUEST, transaction, mac, lease.address.address, None);
+            if rebinding {
+                broadcast(socket, &request);
+            } else {
+                match socket.send_to(&request, lease.server.into(), SERVER_PORT).await {
+                    Ok(()) => TRANSMITTED.increment(),
+                    Err(e) => trace!("DHCP: failed to send to {}: {e}", lease.server),
+                }
+            }
+
+            let next = if rebinding { lease.expiry_deadline() } else { lease.rebinding_deadline() };
+            let wait = Duration::from_millis(((next.saturating_sub(now) / 2) * 1000 / arch::TICKS_PER_SECOND) as u64)
+                .max(MIN_RENEWAL_RETRY)
+                .min(lease.remaining());
+            match receive(socket, transaction, mac, wait).await {
+                Some(reply) if reply.message_type == Some(TYPE_ACK) => if let Some(renewed) = reply.lease() {
+                    LEASES.increment();
+                    trace!("DHCP: renewed {} for {}s", renewed.address, renewed.duration.as_secs());
+                    lease = renewed;
+                    config::set_lease(Some(lease.clone()));
+                    break;
+                },
+                Some(reply) if reply.message_type == Some(TYPE_NAK) => {
+                    warn!("DHCP: the server refused to extend the lease of {}", lease.address);
+                    return true;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The task of the client, which keeps `eth0` configured.
+pub async fn run() {
+    let Some((mac, address)) = interface::with_interface(INTERFACE, |interface| {
+        (interface.mac_address(), interface.ipv4_address())
+    }) else {
+        return;
+    };
+
+    if let Some(address) = address {
+        info!("DHCP: {INTERFACE} is configured with {address}, not asking for a lease");
+        return;
+    }
+
+    let socket = match udp::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("DHCP: {e}");
+            return;
+        }
+    };
+
+    loop {
+        let lease = acquire(&socket, mac).await;
+        LEASES.increment();
+        info!("DHCP: leased {} from {} for {}s", lease.address, lease.server, lease.duration.as_secs());
+        config::set_lease(Some(lease.clone()));
+
+        if !maintain(&socket, mac, lease).await {
+            config::forget_lease();
+            return;
+        }
+        config::set_lease(None);
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Ethernet II frames, in which the packets of the protocols are sent over
+//! the network cards:
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 6    | Destination MAC address                                  |
+//! | 6      | 6    | Source MAC address                                       |
+//! | 12     | 2    | EtherType, the protocol of the payload                   |
+//! | 14     | ...  | Payload, of at most 1500 bytes                           |
+//!
+//! The cards add the preamble and the frame check sequence, and strip them
+//! from the frames they receive, and they pad short frames. IEEE 802.3 frames,
+//! which have a length instead of the EtherType, and VLAN tags aren't
+//! supported, so those frames are handed up as an unknown EtherType.
+//!
+//! ### References:
+//! - [IEEE 802.3](https://standards.ieee.org/ieee/802.3/10422/)
+//! - [IANA: IEEE 802 Numbers](https://www.iana.org/assignments/ieee-802-numbers/ieee-802-numbers.xhtml)
+
+use alloc::vec::Vec;
+
+use super::MacAddress;
+
+pub const HEADER_SIZE: usize = 14;
+
+/// The largest payload, the MTU of the protocols on top.
+pub const MAX_PAYLOAD_SIZE: usize = 1500;
+
+pub const ETHER_TYPE_IPV4: u16 = 0x0800;
+pub const ETHER_TYPE_ARP: u16 = 0x0806;
+pub const ETHER_TYPE_IPV6: u16 = 0x86DD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetFrame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ether_type: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Parse a frame as received from a card, without the frame check
+    /// sequence. The payload may contain the padding of short frames, which
+    /// the protocols on top know to ignore from their own length fields.
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < HEADER_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            destination: MacAddress(frame[0..6].try_into().unwrap()),
+            source: MacAddress(frame[6..12].try_into().unwrap()),
+            ether_type: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[HEADER_SIZE..],
+        })
+    }
+
+    /// Whether a card with the given address should take the frame: sent to
+    /// it, or to a broadcast or multicast address.
+    #[must_use]
+    pub fn is_for(&self, mac: MacAddress) -> bool {
+        self.destination == mac || self.destination.is_multicast()
+    }
+
+    /// Build the frame to hand to a card, which adds the padding when it's
+    /// short.
+    pub fn build(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        frame.extend_from_slice(&self.destination.0);
+        frame.extend_from_slice(&self.source.0);
+        frame.extend_from_slice(&self.ether_type.to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame
+    }
+}
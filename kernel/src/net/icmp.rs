@@ -0,0 +1,229 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! ICMP for IPv4. Echo requests to our address are answered, and [`ping`]
+//! sends one and waits for the reply. The `ping` shell command hands its
+//! pings to the `ping` task (see [`run`]), since the shell can't wait.
+//!
+//! ### References:
+//! - [RFC 792: Internet Control Message Protocol](https://www.rfc-editor.org/rfc/rfc792)
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU16, Ordering},
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::{future::poll_fn, task::AtomicWaker};
+
+use crate::{arch, println, sync::Spinlock, task::timer};
+
+use super::{
+    ipv4::{self, Ipv4Address, Ipv4Error, Ipv4Header},
+    InternetChecksum,
+};
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+pub const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The data of the echo requests, of the same size as those of `ping` on
+/// Linux.
+const PING_DATA: &[u8; 56] = b"nocciolo ping: the quick brown fox jumps over a lazy dog";
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The identifier of the next [`ping`], so concurrent pings of the same host
+/// don't take each other's replies.
+static IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+/// The echo requests that are waiting for their reply.
+static PENDING: Spinlock<Vec<PendingEcho>> = Spinlock::new(Vec::new());
+
+/// The destination and the number of pings the `ping` shell command asked
+/// for, until the task finished them.
+static REQUEST: Spinlock<Option<(Ipv4Address, u16)>> = Spinlock::new(None);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// A received ICMP message, of which the checksum has been verified.
+#[derive(Debug, Clone, Copy)]
+pub struct IcmpMessage<'a> {
+    pub kind: u8,
+    pub code: u8,
+
+    /// The message body after the type, code and checksum.
+    pub body: &'a [u8],
+}
+
+impl<'a> IcmpMessage<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let mut checksum = InternetChecksum::new();
+        checksum.add(data);
+        if data.len() < 4 || checksum.finish() != 0 {
+            return None;
+        }
+
+        Some(Self {
+            kind: data[0],
+            code: data[1],
+            body: &data[4..],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingError {
+    Send(Ipv4Error),
+
+    /// No reply came within [`PING_TIMEOUT`].
+    Timeout,
+}
+
+impl Display for PingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Send(e) => write!(f, "failed to send: {e}"),
+            Self::Timeout => f.write_str("timed out"),
+        }
+    }
+}
+
+struct PendingEcho {
+    destination: Ipv4Address,
+    identifier: u16,
+    sequence: u16,
+    replied: bool,
+    waker: Option<Waker>,
+}
+
+fn build(kind: u8, code: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(body);
+
+    let mut checksum = InternetChecksum::new();
+    checksum.add(&message);
+    message[2..4].copy_from_slice(&checksum.finish().to_be_bytes());
+    message
+}
+
+/// The "port unreachable" error for a packet, which carries its header and
+/// the first 8 bytes of its payload, so the sender can tell which it was.
+pub fn port_unreachable(packet: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + packet.len());
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(packet);
+    build(TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, &body)
+}
+
+/// Process a received message, returning the reply to send, if any.
+pub fn handle_message(header: &Ipv4Header, message: IcmpMessage) -> Option<Vec<u8>> {
+    match message.kind {
+        // The body (identifier, sequence number and data) is echoed.
+        TYPE_ECHO_REQUEST if message.code == 0 => Some(build(TYPE_ECHO_REPLY, 0, message.body)),
+
+        TYPE_ECHO_REPLY if message.code == 0 && message.body.len() >= 4 => {
+            let identifier = u16::from_be_bytes([message.body[0], message.body[1]]);
+            let sequence = u16::from_be_bytes([message.body[2], message.body[3]]);
+            let mut pending = PENDING.lock();
+            let echo = pending.iter_mut().find(|echo| {
+                echo.destination == header.source && echo.identifier == identifier && echo.sequence == sequence
+            })?;
+            echo.replied = true;
+            if let Some(waker) = echo.waker.take() {
+                waker.wake();
+            }
+            None
+        }
+
+        _ => None,
+    }
+}
+
+/// Send an echo request, and wait for the reply, returning the round-trip
+/// time, which includes the time it took to resolve the next hop.
+pub async fn ping(destination: Ipv4Address, sequence: u16) -> Result<Duration, PingError> {
+    let identifier = IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    PENDING.lock().push(PendingEcho { destination, identifier, sequence, replied: false, waker: None });
+    let is_ours = |echo: &PendingEcho| echo.identifier == identifier && echo.sequence == sequence;
+
+    let mut body = Vec::with_capacity(4 + PING_DATA.len());
+    body.extend_from_slice(&identifier.to_be_bytes());
+    body.extend_from_slice(&sequence.to_be_bytes());
+    body.extend_from_slice(PING_DATA);
+
+    let start = arch::ticks();
+    let result = match ipv4::send(destination, ipv4::PROTOCOL_ICMP, &build(TYPE_ECHO_REQUEST, 0, &body)).await {
+        Ok(()) => {
+            let reply = poll_fn(|cx| {
+                let mut pending = PENDING.lock();
+                let Some(echo) = pending.iter_mut().find(|echo| is_ours(echo)) else {
+                    return Poll::Ready(());
+                };
+                if echo.replied {
+                    return Poll::Ready(());
+                }
+                echo.waker = Some(cx.waker().clone());
+                Poll::Pending
+            });
+            timer::timeout(PING_TIMEOUT, reply).await
+                .map(|()| Duration::from_millis(((arch::ticks() - start) * 1000 / arch::TICKS_PER_SECOND) as u64))
+                .map_err(|_| PingError::Timeout)
+        }
+        Err(e) => Err(PingError::Send(e)),
+    };
+
+    PENDING.lock().retain(|echo| !is_ours(echo));
+    result
+}
+
+/// Let the `ping` task ping the destination, returning `false` when it's
+/// still busy with an earlier request.
+pub fn request_ping(destination: Ipv4Address, count: u16) -> bool {
+    let mut request = REQUEST.lock();
+    if request.is_some() {
+        return false;
+    }
+
+    *request = Some((destination, count));
+    WAKER.wake();
+    true
+}
+
+/// The task that runs the pings of the `ping` shell command, printing the
+/// replies to the console.
+pub async fn run() {
+    loop {
+        let (destination, count) = poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            match *REQUEST.lock() {
+                Some(request) => Poll::Ready(request),
+                None => Poll::Pending,
+            }
+        }).await;
+
+        let mut replies = 0;
+        for sequence in 0..count {
+            if sequence != 0 {
+                timer::sleep(PING_INTERVAL).await;
+            }
+
+            match ping(destination, sequence).await {
+                Ok(time) => {
+                    replies += 1;
+                    println!("Reply from {destination}: sequence {sequence}, time {} ms", time.as_millis());
+                }
+                Err(e) => println!("No reply from {destination}: sequence {sequence}, {e}"),
+            }
+        }
+
+        println!("{replies} of {count} pings to {destination} answered");
+        REQUEST.lock().take();
+    }
+}
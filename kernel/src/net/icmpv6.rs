@@ -0,0 +1,174 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! ICMPv6 messages, including those of the Neighbor Discovery Protocol.
+//!
+//! ### References:
+//! - [RFC 4443: ICMPv6](https://www.rfc-editor.org/rfc/rfc4443)
+//! - [RFC 4861: Neighbor Discovery for IPv6](https://www.rfc-editor.org/rfc/rfc4861)
+
+use alloc::vec::Vec;
+
+use super::{ipv6::{self, Ipv6Address, NEXT_HEADER_ICMPV6}, MacAddress};
+
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+pub const TYPE_ECHO_REQUEST: u8 = 128;
+pub const TYPE_ECHO_REPLY: u8 = 129;
+pub const TYPE_ROUTER_SOLICITATION: u8 = 133;
+pub const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+pub const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+pub const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+pub const CODE_PORT_UNREACHABLE: u8 = 4;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+const ADVERTISEMENT_FLAG_SOLICITED: u8 = 0x40;
+const ADVERTISEMENT_FLAG_OVERRIDE: u8 = 0x20;
+
+/// A received ICMPv6 message, of which the checksum has been verified.
+#[derive(Debug, Clone, Copy)]
+pub struct Icmpv6Message<'a> {
+    pub kind: u8,
+    pub code: u8,
+
+    /// The message body after the type, code and checksum.
+    pub body: &'a [u8],
+}
+
+impl<'a> Icmpv6Message<'a> {
+    pub fn parse(source: Ipv6Address, destination: Ipv6Address, data: &'a [u8]) -> Option<Self> {
+        if data.len() < 4 || checksum(source, destination, data) != 0 {
+            return None;
+        }
+
+        Some(Self {
+            kind: data[0],
+            code: data[1],
+            body: &data[4..],
+        })
+    }
+}
+
+/// The information of a Prefix Information option of a Router Advertisement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixInformation {
+    pub prefix: Ipv6Address,
+    pub prefix_length: u8,
+    pub on_link: bool,
+
+    /// The prefix can be used for stateless address autoconfiguration.
+    pub autonomous: bool,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+/// Iterate over the options of a Neighbor Discovery message as
+/// `(type, data)`, where `data` excludes the type and length bytes.
+pub fn options(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        if data.len() < 2 {
+            return None;
+        }
+
+        // The length is in units of 8 bytes, and a length of zero is invalid.
+        let length = data[1] as usize * 8;
+        if length == 0 || length > data.len() {
+            return None;
+        }
+
+        let (option, rest) = data.split_at(length);
+        data = rest;
+        Some((option[0], &option[2..]))
+    })
+}
+
+pub fn link_layer_address_option(options_data: &[u8], source: bool) -> Option<MacAddress> {
+    let wanted = if source { OPTION_SOURCE_LINK_LAYER_ADDRESS } else { OPTION_TARGET_LINK_LAYER_ADDRESS };
+    options(options_data)
+        .find(|(kind, data)| *kind == wanted && data.len() >= 6)
+        .map(|(_, data)| MacAddress(data[..6].try_into().unwrap()))
+}
+
+pub fn prefix_information_options(options_data: &[u8]) -> impl Iterator<Item = PrefixInformation> + '_ {
+    options(options_data)
+        .filter(|(kind, data)| *kind == OPTION_PREFIX_INFORMATION && data.len() >= 30)
+        .map(|(_, data)| PrefixInformation {
+            prefix_length: data[0],
+            on_link: data[1] & PREFIX_FLAG_ON_LINK != 0,
+            autonomous: data[1] & PREFIX_FLAG_AUTONOMOUS != 0,
+            valid_lifetime: u32::from_be_bytes(data[2..6].try_into().unwrap()),
+            preferred_lifetime: u32::from_be_bytes(data[6..10].try_into().unwrap()),
+            prefix: Ipv6Address(data[14..30].try_into().unwrap()),
+        })
+}
+
+/// Build an Echo Reply for the body (identifier, sequence number and data) of
+/// an Echo Request.
+pub fn echo_reply(source: Ipv6Address, destination: Ipv6Address, request_body: &[u8]) -> Vec<u8> {
+    build(source, destination, TYPE_ECHO_REPLY, 0, request_body)
+}
+
+/// The "port unreachable" error for as much of a packet as fits the minimum
+/// MTU, so the sender can tell which it was.
+pub fn port_unreachable(source: Ipv6Address, destination: Ipv6Address, packet: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + packet.len());
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(packet);
+    build(source, destination, TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, &body)
+}
+
+pub fn router_solicitation(source: Ipv6Address, destination: Ipv6Address, mac: MacAddress) -> Vec<u8> {
+    let mut body = Vec::with_capacity(12);
+    body.extend_from_slice(&[0; 4]);
+    push_link_layer_option(&mut body, OPTION_SOURCE_LINK_LAYER_ADDRESS, mac);
+    build(source, destination, TYPE_ROUTER_SOLICITATION, 0, &body)
+}
+
+pub fn neighbor_solicitation(source: Ipv6Address, destination: Ipv6Address, target: Ipv6Address, mac: MacAddress) -> Vec<u8> {
+    let mut body = Vec::with_capacity(28);
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(&target.0);
+    push_link_layer_option(&mut body, OPTION_SOURCE_LINK_LAYER_ADDRESS, mac);
+    build(source, destination, TYPE_NEIGHBOR_SOLICITATION, 0, &body)
+}
+
+pub fn neighbor_advertisement(source: Ipv6Address, destination: Ipv6Address, target: Ipv6Address, mac: MacAddress, solicited: bool) -> Vec<u8> {
+    let mut flags = ADVERTISEMENT_FLAG_OVERRIDE;
+    if solicited {
+        flags |= ADVERTISEMENT_FLAG_SOLICITED;
+    }
+
+    let mut body = Vec::with_capacity(28);
+    body.extend_from_slice(&[flags, 0, 0, 0]);
+    body.extend_from_slice(&target.0);
+    push_link_layer_option(&mut body, OPTION_TARGET_LINK_LAYER_ADDRESS, mac);
+    build(source, destination, TYPE_NEIGHBOR_ADVERTISEMENT, 0, &body)
+}
+
+fn push_link_layer_option(body: &mut Vec<u8>, kind: u8, mac: MacAddress) {
+    body.extend_from_slice(&[kind, 1]);
+    body.extend_from_slice(&mac.0);
+}
+
+/// Build an ICMPv6 message, including its checksum.
+fn build(source: Ipv6Address, destination: Ipv6Address, kind: u8, code: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(body);
+
+    let checksum = checksum(source, destination, &message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// Calculate the checksum over the pseudo header and the message. When the
+/// message contains a valid checksum, the result is zero.
+fn checksum(source: Ipv6Address, destination: Ipv6Address, message: &[u8]) -> u16 {
+    ipv6::pseudo_header_checksum(source, destination, NEXT_HEADER_ICMPV6, message)
+}
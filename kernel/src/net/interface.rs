@@ -0,0 +1,286 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The network interfaces, which bind the network cards to the protocol
+//! layers. The `net` task takes the frames a card received, unwraps them, and
+//! hands the payload to the layer of the EtherType; the packets the layers
+//! produce are wrapped in [`EthernetFrame`]s and sent by the same card:
+//!
+//! | EtherType | Layer             |
+//! |-----------|-------------------|
+//! | `0x0800`  | [`ipv4`]          |
+//! | `0x0806`  | [`arp`]           |
+//! | `0x86DD`  | [`ipv6`]          |
+//!
+//! Frames of other types, or for other cards, are dropped. The cards stay
+//! owned by their drivers, which register them as `eth0`, `eth1` and so on,
+//! with a function that lends out the card of an index.
+//!
+//! The IPv4 address of `eth0` is the `net.ipv4.address` configuration entry,
+//! e.g. `10.0.2.15/24`, and its gateway the `net.ipv4.gateway` entry, which
+//! are applied as the configuration is loaded or changed. Without the entry,
+//! the [`dhcp`](super::dhcp) client asks the network for them, while the
+//! other interfaces don't have one. Interfaces without an address drop the
+//! IPv4 packets other than the UDP broadcasts. Every interface has a
+//! link-local IPv6 address, and the others its router advertises.
+
+use alloc::{format, string::String, vec::Vec};
+use core::time::Duration;
+
+use log::{info, trace};
+
+use crate::{
+    device::net::{NetworkDevice, NetworkError},
+    meta::counters::Counter,
+    sync::Spinlock,
+    task::timer,
+};
+
+use super::{
+    arp,
+    ethernet::{self, EthernetFrame},
+    ipv4::{self, Ipv4Address, Ipv4InterfaceAddress},
+    ipv6::{Ipv6Address, Ipv6Error, Ipv6Interface},
+    pcap,
+    MacAddress,
+    OutgoingPacket,
+};
+
+/// How often the cards are asked for the frames they received, since only
+/// their link changes interrupt.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static RECEIVED: Counter = Counter::new("net.ethernet.received", "Frames received by the interfaces");
+static TRANSMITTED: Counter = Counter::new("net.ethernet.transmitted", "Frames sent by the interfaces");
+static DROPPED: Counter = Counter::new("net.ethernet.dropped", "Received frames that were malformed, for another card, or of an unsupported type");
+
+static INTERFACES: Spinlock<Vec<Interface>> = Spinlock::new(Vec::new());
+
+/// Runs the closure with the card of the index, as long as the driver has a
+/// card of that index.
+pub type WithDevice = fn(usize, &mut dyn FnMut(&mut dyn NetworkDevice));
+
+pub struct Interface {
+    name: String,
+    mac: MacAddress,
+
+    /// The index of the card for [`Self::with_device`].
+    card: usize,
+    with_device: WithDevice,
+
+    /// Like [`Self::with_device`], but skips the closure instead of waiting
+    /// when the card is in use, for the panic handler.
+    try_with_device: WithDevice,
+
+    ipv4: Option<Ipv4InterfaceAddress>,
+
+    /// The router to the hosts outside the subnet of [`Self::ipv4`].
+    ipv4_gateway: Option<Ipv4Address>,
+    ipv6: Ipv6Interface,
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    pub fn ipv4_address(&self) -> Option<Ipv4InterfaceAddress> {
+        self.ipv4
+    }
+
+    pub fn set_ipv4_address(&mut self, address: Option<Ipv4InterfaceAddress>) {
+        if self.ipv4 != address {
+            match address {
+                Some(address) => info!("{}: IPv4 address {address}", self.name),
+                None => info!("{}: no IPv4 address", self.name),
+            }
+            self.ipv4 = address;
+        }
+    }
+
+    pub fn ipv4_gateway(&self) -> Option<Ipv4Address> {
+        self.ipv4_gateway
+    }
+
+    pub fn set_ipv4_gateway(&mut self, gateway: Option<Ipv4Address>) {
+        if self.ipv4_gateway != gateway {
+            match gateway {
+                Some(gateway) => info!("{}: IPv4 gateway {gateway}", self.name),
+                None => info!("{}: no IPv4 gateway", self.name),
+            }
+            self.ipv4_gateway = gateway;
+        }
+    }
+
+    /// The host on the link to send a packet to the destination to: the
+    /// destination itself when it's on the subnet, or else the gateway.
+    pub fn ipv4_next_hop(&self, destination: &Ipv4Address) -> Option<Ipv4Address> {
+        let address = self.ipv4?;
+        if address.contains(destination) || destination.is_broadcast() {
+            return Some(*destination);
+        }
+        self.ipv4_gateway
+    }
+
+    /// The card, with the function that lends it out without waiting, for
+    /// the [crash reports](super::crash).
+    pub fn try_device(&self) -> (usize, WithDevice) {
+        (self.card, self.try_with_device)
+    }
+
+    pub fn ipv6(&self) -> &Ipv6Interface {
+        &self.ipv6
+    }
+
+    /// Ask the link for the MAC address of the IPv4 address, which
+    /// [`arp::resolve`] then waits for. Does nothing without an IPv4
+    /// address.
+    pub fn request_arp(&self, target: Ipv4Address) {
+        if let Some(address) = self.ipv4 {
+            self.send_packet(ethernet::ETHER_TYPE_ARP, arp::request(self.mac, address, target));
+        }
+    }
+
+    /// Send the payload to the IPv6 destination, returning `false` when the
+    /// MAC address of the next hop isn't known yet, for which a Neighbor
+    /// Solicitation is sent when `solicit` is set.
+    pub fn send_ipv6(&self, destination: Ipv6Address, next_header: u8, payload: &[u8], solicit: bool) -> Result<bool, Ipv6Error> {
+        let next_hop = self.ipv6.next_hop(&destination).ok_or(Ipv6Error::NoRoute)?;
+        let Some(mac) = self.ipv6.neighbor(&next_hop) else {
+            if solicit {
+                self.send_packet(ethernet::ETHER_TYPE_IPV6, self.ipv6.neighbor_solicitation(next_hop));
+            }
+            return Ok(false);
+        };
+
+        let packet = self.ipv6.packet_to(destination, next_header, payload, mac);
+        self.send(packet.destination, ethernet::ETHER_TYPE_IPV6, &packet.data).map_err(Ipv6Error::Link)?;
+        Ok(true)
+    }
+
+    /// Wrap the payload in a frame from this interface, and send it.
+    pub fn send(&self, destination: MacAddress, ether_type: u16, payload: &[u8]) -> Result<(), NetworkError> {
+        let frame = EthernetFrame { destination, source: self.mac, ether_type, payload }.build();
+
+        let mut result = Err(NetworkError::LinkDown);
+        (self.with_device)(self.card, &mut |device| result = device.send_frame(&frame));
+        if result.is_ok() {
+            pcap::capture(pcap::Direction::Transmitted, &frame);
+            TRANSMITTED.increment();
+        }
+        result
+    }
+
+    fn send_packet(&self, ether_type: u16, packet: OutgoingPacket) {
+        if let Err(e) = self.send(packet.destination, ether_type, &packet.data) {
+            trace!("{}: failed to send a frame to {}: {e}", self.name, packet.destination);
+        }
+    }
+
+    /// Handle the frames the card received since the last time.
+    fn poll(&mut self) {
+        let mut frames = Vec::new();
+        (self.with_device)(self.card, &mut |device| frames.extend(core::iter::from_fn(|| device.receive_frame())));
+
+        for frame in frames {
+            pcap::capture(pcap::Direction::Received, &frame);
+            RECEIVED.increment();
+            self.handle_frame(&frame);
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let Some(frame) = EthernetFrame::parse(frame).filter(|frame| frame.is_for(self.mac)) else {
+            DROPPED.increment();
+            return;
+        };
+
+        match frame.ether_type {
+            ethernet::ETHER_TYPE_IPV4 => {
+                if let Some(reply) = ipv4::handle_packet(self.ipv4, frame.source, frame.payload) {
+                    self.send_packet(ethernet::ETHER_TYPE_IPV4, reply);
+                }
+            }
+
+            ethernet::ETHER_TYPE_ARP => {
+                if let Some(reply) = arp::handle_packet(self.mac, self.ipv4, frame.payload) {
+                    self.send_packet(ethernet::ETHER_TYPE_ARP, reply);
+                }
+            }
+
+            ethernet::ETHER_TYPE_IPV6 => {
+                if let Some(reply) = self.ipv6.handle_packet(frame.source, frame.payload) {
+                    self.send_packet(ethernet::ETHER_TYPE_IPV6, reply);
+                }
+            }
+
+            _ => DROPPED.increment(),
+        }
+    }
+}
+
+/// Bind a card of a driver to the protocol layers, returning the name of its
+/// interface.
+pub fn register(mac: MacAddress, card: usize, with_device: WithDevice, try_with_device: WithDevice) -> String {
+    let mut interfaces = INTERFACES.lock();
+    let name = format!("eth{}", interfaces.len());
+    interfaces.push(Interface {
+        name: name.clone(),
+        mac,
+        card,
+        with_device,
+        try_with_device,
+        ipv4: None,
+        ipv4_gateway: None,
+        ipv6: Ipv6Interface::new(mac),
+    });
+    name
+}
+
+/// Run the closure with the interface of the given name, if there is one.
+pub fn with_interface<R>(name: &str, f: impl FnOnce(&mut Interface) -> R) -> Option<R> {
+    INTERFACES.lock().iter_mut().find(|interface| interface.name == name).map(f)
+}
+
+pub fn names() -> Vec<String> {
+    INTERFACES.lock().iter().map(|interface| interface.name.clone()).collect()
+}
+
+/// Run the closure with the interface to send to the IPv4 address from: the
+/// one with the address on its subnet, or else the first with an address.
+pub fn with_ipv4_route<R>(destination: Ipv4Address, f: impl FnOnce(&mut Interface) -> R) -> Option<R> {
+    let mut interfaces = INTERFACES.lock();
+    let index = interfaces.iter()
+        .position(|interface| interface.ipv4.is_some_and(|address| address.contains(&destination)))
+        .or_else(|| interfaces.iter().position(|interface| interface.ipv4.is_some()))?;
+    Some(f(&mut interfaces[index]))
+}
+
+/// Run the closure with the interface to send to the IPv6 address from: the
+/// first one with the address on its link or with a router.
+pub fn with_ipv6_route<R>(destination: Ipv6Address, f: impl FnOnce(&mut Interface) -> R) -> Option<R> {
+    INTERFACES.lock().iter_mut().find(|interface| interface.ipv6.next_hop(&destination).is_some()).map(f)
+}
+
+/// The task that moves the frames between the cards and the protocol layers.
+pub async fn run() {
+    if INTERFACES.lock().is_empty() {
+        return;
+    }
+
+    // Start the address autoconfiguration.
+    for interface in INTERFACES.lock().iter() {
+        interface.send_packet(ethernet::ETHER_TYPE_IPV6, interface.ipv6.router_solicitation());
+    }
+
+    loop {
+        for interface in INTERFACES.lock().iter_mut() {
+            interface.poll();
+        }
+        timer::sleep(POLL_INTERVAL).await;
+    }
+}
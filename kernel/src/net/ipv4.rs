@@ -0,0 +1,393 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! IPv4, with the addresses assigned to the interfaces, which are either
+//! configured with the `net.ipv4.address` entry (e.g. `10.0.2.15/24`, QEMU's
+//! user networking) or leased with [`dhcp`](super::dhcp). Packets are sent directly to the hosts on
+//! the subnet of the interface, and through the gateway of the
+//! `net.ipv4.gateway` entry (e.g. `10.0.2.2`) to the others, after resolving
+//! the MAC address of the next hop with [`arp`](super::arp).
+//!
+//! The header is the 20 bytes without options; received packets with options
+//! are accepted, but fragmented packets aren't, and neither are sent:
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 1    | Version (4) and header length in words                   |
+//! | 2      | 2    | Total length, of the header and the payload              |
+//! | 4      | 2    | Identification, for reassembling fragments               |
+//! | 6      | 2    | Flags and fragment offset                                |
+//! | 8      | 1    | Time to live                                             |
+//! | 9      | 1    | Protocol of the payload                                  |
+//! | 10     | 2    | Checksum of the header                                   |
+//! | 12     | 4+4  | Source and destination address                           |
+//!
+//! ### References:
+//! - [RFC 791: Internet Protocol](https://www.rfc-editor.org/rfc/rfc791)
+//! - [RFC 1122: Requirements for Internet Hosts](https://www.rfc-editor.org/rfc/rfc1122)
+//! - [RFC 4632: Classless Inter-domain Routing (CIDR)](https://www.rfc-editor.org/rfc/rfc4632)
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use log::trace;
+
+use crate::{device::net::NetworkError, meta::counters::Counter};
+
+use super::{
+    arp::{self, ArpError},
+    ethernet,
+    icmp::{self, IcmpMessage},
+    interface,
+    InternetChecksum,
+    MacAddress,
+    OutgoingPacket,
+    tcp,
+    udp,
+};
+
+static RECEIVED: Counter = Counter::new("net.ipv4.received", "IPv4 packets received");
+static TRANSMITTED: Counter = Counter::new("net.ipv4.transmitted", "IPv4 packets built for transmission");
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+
+/// The largest payload, which fits a frame without fragmenting.
+pub const MAX_PAYLOAD_SIZE: usize = ethernet::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+/// The identification of the next packet, which only has to differ between
+/// the packets that are in flight at the same time.
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    pub const BROADCAST: Self = Self([0xFF; 4]);
+
+    #[must_use]
+    pub const fn is_unspecified(&self) -> bool {
+        u32::from_be_bytes(self.0) == 0
+    }
+
+    #[must_use]
+    pub const fn is_broadcast(&self) -> bool {
+        u32::from_be_bytes(self.0) == u32::MAX
+    }
+
+    /// Whether the first `length` bits of both addresses are equal.
+    #[must_use]
+    pub const fn matches_prefix(&self, prefix: &Ipv4Address, length: u8) -> bool {
+        let mask = prefix_mask(length);
+        u32::from_be_bytes(self.0) & mask == u32::from_be_bytes(prefix.0) & mask
+    }
+}
+
+const fn prefix_mask(length: u8) -> u32 {
+    match length {
+        0 => 0,
+        length if length >= 32 => u32::MAX,
+        length => u32::MAX << (32 - length as u32),
+    }
+}
+
+impl Display for Ipv4Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 4];
+        let mut parts = s.split('.');
+        for byte in &mut bytes {
+            *byte = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+
+        match parts.next() {
+            Some(..) => Err(()),
+            None => Ok(Self(bytes)),
+        }
+    }
+}
+
+/// An address assigned to an interface, with the length of the prefix of its
+/// subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4InterfaceAddress {
+    pub address: Ipv4Address,
+    pub prefix_length: u8,
+}
+
+impl Ipv4InterfaceAddress {
+    /// Whether the address is on the subnet, i.e. directly reachable.
+    #[must_use]
+    pub const fn contains(&self, address: &Ipv4Address) -> bool {
+        address.matches_prefix(&self.address, self.prefix_length)
+    }
+
+    /// The broadcast address of the subnet.
+    #[must_use]
+    pub const fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address((u32::from_be_bytes(self.address.0) | !prefix_mask(self.prefix_length)).to_be_bytes())
+    }
+}
+
+impl Display for Ipv4InterfaceAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+/// Parses the CIDR notation, e.g. `10.0.2.15/24`.
+impl FromStr for Ipv4InterfaceAddress {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = s.split_once('/').ok_or(())?;
+        let prefix_length = prefix_length.parse().ok().filter(|length| *length <= 32).ok_or(())?;
+        Ok(Self { address: address.parse()?, prefix_length })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4Error {
+    /// No interface has an address, or the destination isn't on a subnet and
+    /// there is no gateway.
+    NoRoute,
+
+    /// The payload doesn't fit a frame, and fragmentation isn't supported.
+    TooLarge(usize),
+
+    /// The MAC address of the next hop couldn't be found.
+    Resolve(ArpError),
+
+    /// The card didn't take the frame.
+    Link(NetworkError),
+}
+
+impl Display for Ipv4Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoRoute => f.write_str("no route to the host"),
+            Self::TooLarge(length) => write!(f, "a payload of {length} bytes needs fragmentation"),
+            Self::Resolve(e) => write!(f, "the next hop can't be resolved: {e}"),
+            Self::Link(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub identification: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+}
+
+impl Ipv4Header {
+    /// Parse the header, of which the checksum is verified, returning it
+    /// together with the payload without the padding of the frame. Fragments
+    /// are refused.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_length = (packet[0] & 0x0F) as usize * 4;
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_length < HEADER_SIZE || total_length < header_length {
+            return None;
+        }
+
+        let mut checksum = InternetChecksum::new();
+        checksum.add(packet.get(..header_length)?);
+        if checksum.finish() != 0 {
+            return None;
+        }
+
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+            return None;
+        }
+
+        let header = Self {
+            identification: u16::from_be_bytes([packet[4], packet[5]]),
+            ttl: packet[8],
+            protocol: packet[9],
+            source: Ipv4Address(packet[12..16].try_into().unwrap()),
+            destination: Ipv4Address(packet[16..20].try_into().unwrap()),
+        };
+        Some((header, packet.get(header_length..total_length)?))
+    }
+
+    /// Build a packet with this header and the given payload, which must fit
+    /// [`MAX_PAYLOAD_SIZE`].
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.identification.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        packet.extend_from_slice(&self.source.0);
+        packet.extend_from_slice(&self.destination.0);
+
+        let mut checksum = InternetChecksum::new();
+        checksum.add(&packet);
+        packet[10..12].copy_from_slice(&checksum.finish().to_be_bytes());
+
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// The checksum of the transport layers, which covers the addresses of the
+/// packet as well, through a pseudo header, so misdelivered packets are
+/// caught. Verifying a received segment sums to zero.
+pub fn pseudo_header_checksum(source: Ipv4Address, destination: Ipv4Address, protocol: u8, segment: &[u8]) -> u16 {
+    let mut checksum = InternetChecksum::new();
+    checksum.add(&source.0);
+    checksum.add(&destination.0);
+    checksum.add_word(protocol as u16);
+    checksum.add_word(segment.len() as u16);
+    checksum.add(segment);
+    checksum.finish()
+}
+
+/// Send the payload to the destination, from the interface that routes to it.
+pub async fn send(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), Ipv4Error> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(Ipv4Error::TooLarge(payload.len()));
+    }
+
+    let (name, address, next_hop) = interface::with_ipv4_route(destination, |interface| {
+        let address = interface.ipv4_address()?;
+        Some((String::from(interface.name()), address, interface.ipv4_next_hop(&destination)?))
+    }).flatten().ok_or(Ipv4Error::NoRoute)?;
+
+    let mac = if destination.is_broadcast() || destination == address.broadcast() {
+        MacAddress::BROADCAST
+    } else {
+        arp::resolve(next_hop).await.map_err(Ipv4Error::Resolve)?
+    };
+
+    let packet = build_packet(address.address, destination, protocol, payload);
+    interface::with_interface(&name, |interface| interface.send(mac, ethernet::ETHER_TYPE_IPV4, &packet))
+        .ok_or(Ipv4Error::NoRoute)?
+        .map_err(Ipv4Error::Link)
+}
+
+/// Broadcast the payload on the link of the interface, from its address, or
+/// from the unspecified address while it doesn't have one, e.g. for DHCP.
+pub fn send_broadcast(interface: &str, protocol: u8, payload: &[u8]) -> Result<(), Ipv4Error> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(Ipv4Error::TooLarge(payload.len()));
+    }
+
+    interface::with_interface(interface, |interface| {
+        let source = interface.ipv4_address().map_or(Ipv4Address::UNSPECIFIED, |address| address.address);
+        let packet = build_packet(source, Ipv4Address::BROADCAST, protocol, payload);
+        interface.send(MacAddress::BROADCAST, ethernet::ETHER_TYPE_IPV4, &packet)
+    }).ok_or(Ipv4Error::NoRoute)?.map_err(Ipv4Error::Link)
+}
+
+fn build_packet(source: Ipv4Address, destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    TRANSMITTED.increment();
+    Ipv4Header {
+        identification: IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+        ttl: DEFAULT_TTL,
+        protocol,
+        source,
+        destination,
+    }.build(payload)
+}
+
+/// Process a packet received by the interface with the given address,
+/// returning the reply to send, if any. Replies go straight back to the MAC
+/// address the packet came from, so they don't wait for ARP.
+///
+/// Interfaces without an address only take UDP broadcasts, which is how the
+/// DHCP server answers before there is one.
+pub fn handle_packet(address: Option<Ipv4InterfaceAddress>, source_mac: MacAddress, packet: &[u8]) -> Option<OutgoingPacket> {
+    RECEIVED.increment();
+
+    let (header, payload) = Ipv4Header::parse(packet)?;
+    let Some(address) = address else {
+        if header.destination.is_broadcast() && header.protocol == PROTOCOL_UDP {
+            udp::handle_datagram(&header, payload);
+        }
+        return None;
+    };
+
+    let broadcast = header.destination != address.address
+        && (header.destination.is_broadcast() || header.destination == address.broadcast());
+    if header.destination != address.address && !broadcast {
+        return None;
+    }
+
+    match header.protocol {
+        PROTOCOL_ICMP => {
+            let message = IcmpMessage::parse(payload)?;
+            let reply = icmp::handle_message(&header, message)?;
+
+            // Echo requests to a broadcast address aren't answered, which
+            // keeps us out of amplification attacks.
+            if broadcast {
+                return None;
+            }
+            Some(OutgoingPacket {
+                destination: source_mac,
+                data: build_packet(address.address, header.source, PROTOCOL_ICMP, &reply),
+            })
+        }
+
+        PROTOCOL_TCP => {
+            if broadcast {
+                return None;
+            }
+            let reply = tcp::handle_segment(&header, payload)?;
+            Some(OutgoingPacket {
+                destination: source_mac,
+                data: build_packet(address.address, header.source, PROTOCOL_TCP, &reply),
+            })
+        }
+
+        PROTOCOL_UDP => {
+            // Nobody listening on the port is reported to the sender, but not
+            // for broadcasts, which every host would answer.
+            if udp::handle_datagram(&header, payload) || broadcast {
+                return None;
+            }
+            let header_length = ((packet[0] & 0x0F) as usize) * 4;
+            let message = icmp::port_unreachable(&packet[..header_length + payload.len().min(8)]);
+            Some(OutgoingPacket {
+                destination: source_mac,
+                data: build_packet(address.address, header.source, PROTOCOL_ICMP, &message),
+            })
+        }
+
+        other => {
+            trace!("Dropping IPv4 packet with unsupported protocol {other}");
+            None
+        }
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! IPv6, with Neighbor Discovery for resolving link-layer addresses and
+//! Stateless Address Autoconfiguration (SLAAC) for obtaining addresses from
+//! Router Advertisements.
+//!
+//! Packets are sent directly to the destinations on the link, i.e. link-local
+//! ones and those on the prefix of an address, and through the router of the
+//! last advertisement to the others. The MAC address of the next hop is asked
+//! for with a Neighbor Solicitation when it isn't in the neighbor cache.
+//!
+//! Duplicate Address Detection isn't performed, since the interface
+//! identifiers are derived from the (unique) MAC address.
+//!
+//! ### References:
+//! - [RFC 8200: IPv6 Specification](https://www.rfc-editor.org/rfc/rfc8200)
+//! - [RFC 4291: IPv6 Addressing Architecture](https://www.rfc-editor.org/rfc/rfc4291)
+//! - [RFC 4862: IPv6 Stateless Address Autoconfiguration](https://www.rfc-editor.org/rfc/rfc4862)
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt::{Display, Formatter}, str::FromStr, time::Duration};
+
+use log::{info, trace};
+
+use crate::{device::net::NetworkError, meta::counters::Counter, task::timer};
+
+use super::{
+    ethernet,
+    icmpv6::{self, Icmpv6Message},
+    interface,
+    udp,
+    InternetChecksum,
+    MacAddress,
+    OutgoingPacket,
+};
+
+static RECEIVED: Counter = Counter::new("net.ipv6.received", "IPv6 packets received");
+static TRANSMITTED: Counter = Counter::new("net.ipv6.transmitted", "IPv6 packets built for transmission");
+
+pub const NEXT_HEADER_UDP: u8 = 17;
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+pub const HEADER_SIZE: usize = 40;
+
+/// The largest payload, which fits a frame without fragmenting.
+pub const MAX_PAYLOAD_SIZE: usize = ethernet::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+
+/// The smallest MTU of IPv6, which error messages must fit, with the part of
+/// the packet that caused them.
+const MIN_MTU: usize = 1280;
+
+/// Neighbor Discovery messages must have this hop limit, so we know they
+/// originate from the link itself.
+const NDP_HOP_LIMIT: u8 = 255;
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+const SOLICITATION_ATTEMPTS: usize = 3;
+const SOLICITATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the neighbor cache is checked for the answer to a solicitation.
+const NEIGHBOR_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv6Address(pub [u8; 16]);
+
+impl Ipv6Address {
+    pub const ALL_NODES: Self = Self([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+    pub const ALL_ROUTERS: Self = Self([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+    /// Combine a /64 prefix with the modified EUI-64 interface identifier
+    /// derived from the MAC address.
+    #[must_use]
+    pub const fn from_prefix_and_mac(prefix: &Ipv6Address, mac: MacAddress) -> Self {
+        let [a, b, c, d, e, f] = mac.0;
+        let p = prefix.0;
+        Self([
+            p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7],
+            a ^ 0x02, b, c, 0xFF, 0xFE, d, e, f,
+        ])
+    }
+
+    #[must_use]
+    pub const fn link_local(mac: MacAddress) -> Self {
+        Self::from_prefix_and_mac(&Self([0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), mac)
+    }
+
+    /// The multicast address a Neighbor Solicitation for this address is
+    /// sent to.
+    #[must_use]
+    pub const fn solicited_node(&self) -> Self {
+        Self([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xFF, self.0[13], self.0[14], self.0[15]])
+    }
+
+    /// The MAC address multicast packets to this address are sent to.
+    #[must_use]
+    pub const fn multicast_mac(&self) -> MacAddress {
+        MacAddress([0x33, 0x33, self.0[12], self.0[13], self.0[14], self.0[15]])
+    }
+
+    #[must_use]
+    pub const fn is_unspecified(&self) -> bool {
+        u128::from_be_bytes(self.0) == 0
+    }
+
+    #[must_use]
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] == 0xFF
+    }
+
+    #[must_use]
+    pub const fn is_link_local(&self) -> bool {
+        self.0[0] == 0xFE && self.0[1] & 0xC0 == 0x80
+    }
+
+    #[must_use]
+    pub fn segments(&self) -> [u16; 8] {
+        core::array::from_fn(|idx| u16::from_be_bytes([self.0[idx * 2], self.0[idx * 2 + 1]]))
+    }
+
+    /// Whether the first `length` bits of both addresses are equal.
+    #[must_use]
+    pub fn matches_prefix(&self, prefix: &Ipv6Address, length: u8) -> bool {
+        let length = length.min(128) as u32;
+        if length == 0 {
+            return true;
+        }
+
+        let mask = u128::MAX << (128 - length);
+        u128::from_be_bytes(self.0) & mask == u128::from_be_bytes(prefix.0) & mask
+    }
+}
+
+/// Formats the address in the canonical text representation of RFC 5952,
+/// where the longest run of zero segments is compressed to `::`.
+impl Display for Ipv6Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let segments = self.segments();
+
+        let mut longest = (0, 0);
+        let mut current = (0, 0);
+        for (idx, segment) in segments.iter().enumerate() {
+            if *segment == 0 {
+                if current.1 == 0 {
+                    current.0 = idx;
+                }
+                current.1 += 1;
+                if current.1 > longest.1 {
+                    longest = current;
+                }
+            } else {
+                current = (0, 0);
+            }
+        }
+
+        if longest.1 < 2 {
+            longest = (usize::MAX, 0);
+        }
+
+        let mut idx = 0;
+        while idx < segments.len() {
+            if idx == longest.0 {
+                f.write_str("::")?;
+                idx += longest.1;
+                continue;
+            }
+
+            if idx != 0 && idx != longest.0 + longest.1 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:x}", segments[idx])?;
+            idx += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Ipv6Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_segments(s: &str, out: &mut Vec<u16>) -> Result<(), ()> {
+            if s.is_empty() {
+                return Ok(());
+            }
+
+            for segment in s.split(':') {
+                if segment.is_empty() || segment.len() > 4 {
+                    return Err(());
+                }
+                out.push(u16::from_str_radix(segment, 16).map_err(|_| ())?);
+            }
+
+            Ok(())
+        }
+
+        let mut head = Vec::new();
+        let mut tail = Vec::new();
+        match s.split_once("::") {
+            Some((first, second)) => {
+                parse_segments(first, &mut head)?;
+                parse_segments(second, &mut tail)?;
+                if head.len() + tail.len() > 7 {
+                    return Err(());
+                }
+            }
+            None => {
+                parse_segments(s, &mut head)?;
+                if head.len() != 8 {
+                    return Err(());
+                }
+            }
+        }
+
+        let mut bytes = [0; 16];
+        let segments = head.iter().enumerate()
+            .chain(tail.iter().enumerate().map(|(idx, segment)| (8 - tail.len() + idx, segment)));
+        for (idx, segment) in segments {
+            bytes[idx * 2..idx * 2 + 2].copy_from_slice(&segment.to_be_bytes());
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6Error {
+    /// No interface routes to the destination: it isn't on the link, and no
+    /// router advertised itself.
+    NoRoute,
+
+    /// The payload doesn't fit a frame, and fragmentation isn't supported.
+    TooLarge(usize),
+
+    /// The next hop didn't answer the Neighbor Solicitations.
+    Unresolved,
+
+    /// The card didn't take the frame.
+    Link(NetworkError),
+}
+
+impl Display for Ipv6Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoRoute => f.write_str("no route to the host"),
+            Self::TooLarge(length) => write!(f, "a payload of {length} bytes needs fragmentation"),
+            Self::Unresolved => f.write_str("the next hop didn't answer the neighbor solicitations"),
+            Self::Link(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source: Ipv6Address,
+    pub destination: Ipv6Address,
+}
+
+impl Ipv6Header {
+    /// Parse the fixed header, returning it together with the payload.
+    /// Extension headers aren't supported and are left to the caller as the
+    /// `next_header`.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 6 {
+            return None;
+        }
+
+        let header = Self {
+            payload_length: u16::from_be_bytes([packet[4], packet[5]]),
+            next_header: packet[6],
+            hop_limit: packet[7],
+            source: Ipv6Address(packet[8..24].try_into().unwrap()),
+            destination: Ipv6Address(packet[24..40].try_into().unwrap()),
+        };
+
+        let payload = packet.get(HEADER_SIZE..HEADER_SIZE + header.payload_length as usize)?;
+        Some((header, payload))
+    }
+
+    /// Build a packet with this header and the given payload.
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.extend_from_slice(&[0x60, 0, 0, 0]);
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.push(self.next_header);
+        packet.push(self.hop_limit);
+        packet.extend_from_slice(&self.source.0);
+        packet.extend_from_slice(&self.destination.0);
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// The checksum of the transport layers, which covers the addresses of the
+/// packet as well, through a pseudo header. Verifying a received segment sums
+/// to zero.
+pub fn pseudo_header_checksum(source: Ipv6Address, destination: Ipv6Address, next_header: u8, segment: &[u8]) -> u16 {
+    let mut checksum = InternetChecksum::new();
+    checksum.add(&source.0);
+    checksum.add(&destination.0);
+    checksum.add(&(segment.len() as u32).to_be_bytes());
+    checksum.add(&[0, 0, 0, next_header]);
+    checksum.add(segment);
+    checksum.finish()
+}
+
+/// Send the payload to the destination, from the interface that routes to
+/// it, soliciting the MAC address of the next hop when it isn't known yet.
+pub async fn send(destination: Ipv6Address, next_header: u8, payload: &[u8]) -> Result<(), Ipv6Error> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(Ipv6Error::TooLarge(payload.len()));
+    }
+
+    let send = |solicit| interface::with_ipv6_route(destination, |interface| {
+        interface.send_ipv6(destination, next_header, payload, solicit)
+    }).unwrap_or(Err(Ipv6Error::NoRoute));
+
+    for _ in 0..SOLICITATION_ATTEMPTS {
+        if send(true)? {
+            return Ok(());
+        }
+
+        for _ in 0..SOLICITATION_TIMEOUT.as_millis() / NEIGHBOR_POLL_INTERVAL.as_millis() {
+            timer::sleep(NEIGHBOR_POLL_INTERVAL).await;
+            if send(false)? {
+                return Ok(());
+            }
+        }
+    }
+
+    Err(Ipv6Error::Unresolved)
+}
+
+/// An address assigned to the interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6InterfaceAddress {
+    pub address: Ipv6Address,
+    pub prefix_length: u8,
+}
+
+impl Display for Ipv6InterfaceAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+/// The IPv6 state of a network interface.
+pub struct Ipv6Interface {
+    mac: MacAddress,
+    addresses: Vec<Ipv6InterfaceAddress>,
+    neighbors: BTreeMap<Ipv6Address, MacAddress>,
+    router: Option<Ipv6Address>,
+}
+
+impl Ipv6Interface {
+    /// Create the interface, which immediately has a link-local address.
+    pub fn new(mac: MacAddress) -> Self {
+        let link_local = Ipv6Address::link_local(mac);
+        info!("IPv6 link-local address of {mac} is {link_local}");
+
+        Self {
+            mac,
+            addresses: alloc::vec![Ipv6InterfaceAddress { address: link_local, prefix_length: 64 }],
+            neighbors: BTreeMap::new(),
+            router: None,
+        }
+    }
+
+    pub fn addresses(&self) -> &[Ipv6InterfaceAddress] {
+        &self.addresses
+    }
+
+    pub fn link_local_address(&self) -> Ipv6Address {
+        self.addresses[0].address
+    }
+
+    pub fn router(&self) -> Option<Ipv6Address> {
+        self.router
+    }
+
+    pub fn neighbor(&self, address: &Ipv6Address) -> Option<MacAddress> {
+        if address.is_multicast() {
+            return Some(address.multicast_mac());
+        }
+
+        self.neighbors.get(address).copied()
+    }
+
+    /// Whether packets to this address should be accepted.
+    pub fn accepts(&self, address: &Ipv6Address) -> bool {
+        *address == Ipv6Address::ALL_NODES
+            || self.addresses.iter().any(|entry| {
+                entry.address == *address || entry.address.solicited_node() == *address
+            })
+    }
+
+    /// The host on the link to send a packet to the destination to: the
+    /// destination itself when it's on the link, or else the router.
+    pub fn next_hop(&self, destination: &Ipv6Address) -> Option<Ipv6Address> {
+        let on_link = destination.is_link_local()
+            || destination.is_multicast()
+            || self.addresses[1..].iter().any(|entry| destination.matches_prefix(&entry.address, entry.prefix_length));
+        match on_link {
+            true => Some(*destination),
+            false => self.router,
+        }
+    }
+
+    /// The source address to use when sending to the given destination.
+    pub fn source_for(&self, destination: &Ipv6Address) -> Ipv6Address {
+        if destination.is_link_local() || destination.is_multicast() {
+            return self.link_local_address();
+        }
+
+        self.addresses.iter()
+            .find(|entry| destination.matches_prefix(&entry.address, entry.prefix_length))
+            .or_else(|| self.addresses.get(1))
+            .map_or(self.link_local_address(), |entry| entry.address)
+    }
+
+    /// Ask the routers on the link to advertise themselves, which starts the
+    /// address autoconfiguration.
+    pub fn router_solicitation(&self) -> OutgoingPacket {
+        let source = self.link_local_address();
+        let destination = Ipv6Address::ALL_ROUTERS;
+        let message = icmpv6::router_solicitation(source, destination, self.mac);
+        self.packet(source, destination, NDP_HOP_LIMIT, NEXT_HEADER_ICMPV6, &message, destination.multicast_mac())
+    }
+
+    /// Resolve the link-layer address of a neighbor. The answer ends up in
+    /// the neighbor cache.
+    pub fn neighbor_solicitation(&self, target: Ipv6Address) -> OutgoingPacket {
+        let source = self.source_for(&target);
+        let destination = target.solicited_node();
+        let message = icmpv6::neighbor_solicitation(source, destination, target, self.mac);
+        self.packet(source, destination, NDP_HOP_LIMIT, NEXT_HEADER_ICMPV6, &message, destination.multicast_mac())
+    }
+
+    /// Build a packet to the destination, sent to the MAC address of its next
+    /// hop.
+    pub fn packet_to(&self, destination: Ipv6Address, next_header: u8, payload: &[u8], mac: MacAddress) -> OutgoingPacket {
+        self.packet(self.source_for(&destination), destination, DEFAULT_HOP_LIMIT, next_header, payload, mac)
+    }
+
+    /// Process a received packet, returning the reply to send, if any.
+    pub fn handle_packet(&mut self, source_mac: MacAddress, packet: &[u8]) -> Option<OutgoingPacket> {
+        RECEIVED.increment();
+
+        let (header, payload) = Ipv6Header::parse(packet)?;
+        if !self.accepts(&header.destination) {
+            return None;
+        }
+
+        match header.next_header {
+            NEXT_HEADER_ICMPV6 => {
+                let message = Icmpv6Message::parse(header.source, header.destination, payload)?;
+                self.handle_icmpv6(&header, source_mac, message)
+            }
+
+            NEXT_HEADER_UDP => {
+                // Nobody listening on the port is reported to the sender, but
+                // not for multicasts, which every host would answer.
+                if udp::handle_datagram_ipv6(&header, payload) || header.destination.is_multicast() {
+                    return None;
+                }
+                let invoking = &packet[..(HEADER_SIZE + payload.len()).min(MIN_MTU - HEADER_SIZE - 8)];
+                let message = icmpv6::port_unreachable(header.destination, header.source, invoking);
+                Some(self.packet(header.destination, header.source, DEFAULT_HOP_LIMIT, NEXT_HEADER_ICMPV6, &message, source_mac))
+            }
+
+            other => {
+                trace!("Dropping IPv6 packet with unsupported next header {other}");
+                None
+            }
+        }
+    }
+
+    fn handle_icmpv6(&mut self, header: &Ipv6Header, source_mac: MacAddress, message: Icmpv6Message) -> Option<OutgoingPacket> {
+        // The messages we handle all have code 0, and those of Neighbor
+        // Discovery with another code must be ignored.
+        if message.code != 0 {
+            return None;
+        }
+
+        match message.kind {
+            icmpv6::TYPE_ECHO_REQUEST => {
+                let source = if header.destination.is_multicast() {
+                    self.source_for(&header.source)
+                } else {
+                    header.destination
+                };
+
+                let reply = icmpv6::echo_reply(source, header.source, message.body);
+                Some(self.packet(source, header.source, DEFAULT_HOP_LIMIT, NEXT_HEADER_ICMPV6, &reply, source_mac))
+            }
+
+            icmpv6::TYPE_NEIGHBOR_SOLICITATION if header.hop_limit == NDP_HOP_LIMIT && message.body.len() >= 20 => {
+                let target = Ipv6Address(message.body[4..20].try_into().unwrap());
+                if !self.addresses.iter().any(|entry| entry.address == target) {
+                    return None;
+                }
+
+                // A solicitation from the unspecified address is Duplicate
+                // Address Detection, which is answered to all nodes.
+                let (destination, solicited, mac) = if header.source.is_unspecified() {
+                    (Ipv6Address::ALL_NODES, false, Ipv6Address::ALL_NODES.multicast_mac())
+                } else {
+                    let mac = icmpv6::link_layer_address_option(&message.body[20..], true).unwrap_or(source_mac);
+                    self.neighbors.insert(header.source, mac);
+                    (header.source, true, mac)
+                };
+
+                let reply = icmpv6::neighbor_advertisement(target, destination, target, self.mac, solicited);
+                Some(self.packet(target, destination, NDP_HOP_LIMIT, NEXT_HEADER_ICMPV6, &reply, mac))
+            }
+
+            icmpv6::TYPE_NEIGHBOR_ADVERTISEMENT if header.hop_limit == NDP_HOP_LIMIT && message.body.len() >= 20 => {
+                let target = Ipv6Address(message.body[4..20].try_into().unwrap());
+                let mac = icmpv6::link_layer_address_option(&message.body[20..], false).unwrap_or(source_mac);
+                trace!("Neighbor {target} is at {mac}");
+                self.neighbors.insert(target, mac);
+                None
+            }
+
+            icmpv6::TYPE_ROUTER_ADVERTISEMENT
+                if header.hop_limit == NDP_HOP_LIMIT && header.source.is_link_local() && message.body.len() >= 12 => {
+                self.handle_router_advertisement(header.source, source_mac, &message.body[12..]);
+                None
+            }
+
+            _ => None,
+        }
+    }
+
+    fn handle_router_advertisement(&mut self, router: Ipv6Address, source_mac: MacAddress, options: &[u8]) {
+        let mac = icmpv6::link_layer_address_option(options, true).unwrap_or(source_mac);
+        self.neighbors.insert(router, mac);
+
+        if self.router != Some(router) {
+            info!("IPv6 router {router} at {mac}");
+            self.router = Some(router);
+        }
+
+        for prefix in icmpv6::prefix_information_options(options) {
+            // SLAAC is only defined for /64 prefixes on Ethernet.
+            if !prefix.autonomous || prefix.prefix_length != 64 || prefix.valid_lifetime == 0 || prefix.prefix.is_link_local() {
+                continue;
+            }
+
+            let address = Ipv6Address::from_prefix_and_mac(&prefix.prefix, self.mac);
+            if self.addresses.iter().any(|entry| entry.address == address) {
+                continue;
+            }
+
+            info!("IPv6 address {address}/{} configured using SLAAC", prefix.prefix_length);
+            self.addresses.push(Ipv6InterfaceAddress {
+                address,
+                prefix_length: prefix.prefix_length,
+            });
+        }
+    }
+
+    fn packet(&self, source: Ipv6Address, destination: Ipv6Address, hop_limit: u8, next_header: u8, payload: &[u8], mac: MacAddress) -> OutgoingPacket {
+        let header = Ipv6Header {
+            payload_length: payload.len() as u16,
+            next_header,
+            hop_limit,
+            source,
+            destination,
+        };
+
+        TRANSMITTED.increment();
+        OutgoingPacket {
+            destination: mac,
+            data: header.build(payload),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The network stack.
+//!
+//! The protocol layers are independent of the network devices: they consume
+//! received packets and produce the packets to transmit, which the link layer
+//! wraps in frames for the device. The [`interface`]s bind the devices to the
+//! layers.
+
+use core::fmt::{Display, Formatter};
+
+use ipv4::Ipv4Address;
+use ipv6::Ipv6Address;
+
+pub mod arp;
+pub mod config;
+pub mod console;
+pub mod crash;
+pub mod ethernet;
+pub mod dhcp;
+pub mod icmp;
+pub mod icmpv6;
+pub mod interface;
+pub mod ipv4;
+pub mod ipv6;
+pub mod pcap;
+pub mod tcp;
+pub mod udp;
+
+/// An IEEE 802 MAC address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+
+    #[must_use]
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+}
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// An address of either IP version, e.g. of the peer of a [`udp`] socket,
+/// which takes both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IpAddress {
+    V4(Ipv4Address),
+    V6(Ipv6Address),
+}
+
+impl From<Ipv4Address> for IpAddress {
+    fn from(address: Ipv4Address) -> Self {
+        Self::V4(address)
+    }
+}
+
+impl From<Ipv6Address> for IpAddress {
+    fn from(address: Ipv6Address) -> Self {
+        Self::V6(address)
+    }
+}
+
+impl Display for IpAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::V4(address) => address.fmt(f),
+            Self::V6(address) => address.fmt(f),
+        }
+    }
+}
+
+/// A packet produced by a protocol layer, together with the link-layer
+/// address it should be sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutgoingPacket {
+    pub destination: MacAddress,
+    pub data: alloc::vec::Vec<u8>,
+}
+
+/// The one's complement sum used by the Internet checksum, which can be fed
+/// in parts, e.g. for pseudo headers.
+///
+/// ### References:
+/// - [RFC 1071](https://www.rfc-editor.org/rfc/rfc1071)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InternetChecksum {
+    sum: u32,
+}
+
+impl InternetChecksum {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { sum: 0 }
+    }
+
+    /// Add data to the checksum. Only the last part may have an odd length.
+    pub fn add(&mut self, data: &[u8]) {
+        let mut chunks = data.chunks_exact(2);
+        for chunk in &mut chunks {
+            self.add_word(u16::from_be_bytes([chunk[0], chunk[1]]));
+        }
+
+        if let [last] = chunks.remainder() {
+            self.add_word(u16::from_be_bytes([*last, 0]));
+        }
+    }
+
+    pub fn add_word(&mut self, word: u16) {
+        self.sum += word as u32;
+        self.sum = (self.sum & 0xFFFF) + (self.sum >> 16);
+    }
+
+    #[must_use]
+    pub fn finish(&self) -> u16 {
+        !(self.sum as u16)
+    }
+}
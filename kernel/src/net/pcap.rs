@@ -0,0 +1,217 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Packet capture, for debugging the protocols without guessing from the log.
+//!
+//! The packets handled by the stack are mirrored into a ring buffer with the
+//! time they were captured, and can be streamed in the pcap format as they
+//! arrive, or dumped afterwards with the `pcap` shell command. Capturing is
+//! enabled with the `pcap` boot parameter:
+//!
+//! | Value      | Behavior                                                     |
+//! |------------|--------------------------------------------------------------|
+//! | `ring`     | Capture into the ring buffer only                            |
+//! | `serial`   | Also stream over the serial port, as `@pcap <hex>` lines     |
+//! | `debugcon` | Also stream over the QEMU debug console (port 0xE9), as is   |
+//!
+//! The debug console output is a valid pcap file as is, e.g. with the
+//! `-debugcon file:target/capture.pcap` QEMU option. Since the serial port is
+//! shared with the log, the records are hex encoded lines there instead,
+//! which `cargo run pcap <log>` extracts into a pcap file.
+//!
+//! The packets are captured as the Ethernet frames the interfaces receive
+//! and send (see [`interface`](super::interface)), so the capture also has
+//! the frames of the protocols the stack doesn't understand.
+//!
+//! ### References:
+//! - [pcap Capture File Format](https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-03.html)
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt::{Display, Formatter};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::{arch, meta::BootParameters, serial_println};
+
+/// The marker of the hex encoded lines on the serial port, which the host-side
+/// extractor looks for.
+pub const SERIAL_MARKER: &str = "@pcap ";
+
+const DEBUGCON_PORT: u16 = 0xE9;
+
+const RING_CAPACITY: usize = 128;
+const SNAPSHOT_LENGTH: usize = 2048;
+
+const MAGIC: u32 = 0xA1B2_C3D4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Capture> = Mutex::new(Capture::from_boot_parameters());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Transmitted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSink {
+    Serial,
+    Debugcon,
+}
+
+impl CaptureSink {
+    fn write(&self, data: &[u8]) {
+        match self {
+            Self::Serial => serial_println!("{SERIAL_MARKER}{}", Hex(data)),
+            Self::Debugcon => {
+                let mut port = Port::<u8>::new(DEBUGCON_PORT);
+                for byte in data {
+                    unsafe { port.write(*byte) };
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// The time of capture, in [`arch::ticks`].
+    pub ticks: usize,
+    pub direction: Direction,
+
+    /// The length of the packet, which might be longer than the captured
+    /// data.
+    pub length: usize,
+    pub data: Vec<u8>,
+}
+
+impl CapturedPacket {
+    fn record(&self) -> Vec<u8> {
+        let micros = self.ticks as u64 * 1_000_000 / arch::TICKS_PER_SECOND as u64;
+
+        let mut record = Vec::with_capacity(16 + self.data.len());
+        record.extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(self.length as u32).to_le_bytes());
+        record.extend_from_slice(&self.data);
+        record
+    }
+}
+
+struct Capture {
+    enabled: bool,
+    stream: Option<CaptureSink>,
+    ring: VecDeque<CapturedPacket>,
+
+    /// Whether the file header was written to the stream.
+    streaming: bool,
+}
+
+impl Capture {
+    fn from_boot_parameters() -> Self {
+        let (enabled, stream) = match BootParameters::get("pcap") {
+            Some("ring") => (true, None),
+            Some("serial") => (true, Some(CaptureSink::Serial)),
+            Some("debugcon") => (true, Some(CaptureSink::Debugcon)),
+            _ => (false, None),
+        };
+
+        Self {
+            enabled,
+            stream,
+            ring: VecDeque::with_capacity(RING_CAPACITY),
+            streaming: false,
+        }
+    }
+}
+
+/// Mirror a packet into the capture, if enabled.
+pub fn capture(direction: Direction, data: &[u8]) {
+    let mut capture = CAPTURE.lock();
+    if !capture.enabled {
+        return;
+    }
+
+    let packet = CapturedPacket {
+        ticks: arch::ticks(),
+        direction,
+        length: data.len(),
+        data: data[..data.len().min(SNAPSHOT_LENGTH)].to_vec(),
+    };
+
+    if let Some(sink) = capture.stream {
+        if !capture.streaming {
+            sink.write(&file_header());
+            capture.streaming = true;
+        }
+        sink.write(&packet.record());
+    }
+
+    if capture.ring.len() == RING_CAPACITY {
+        capture.ring.pop_front();
+    }
+    capture.ring.push_back(packet);
+}
+
+pub fn is_enabled() -> bool {
+    CAPTURE.lock().enabled
+}
+
+pub fn set_enabled(enabled: bool) {
+    CAPTURE.lock().enabled = enabled;
+}
+
+/// The packets in the ring buffer, oldest first.
+pub fn packets() -> Vec<CapturedPacket> {
+    CAPTURE.lock().ring.iter().cloned().collect()
+}
+
+pub fn clear() {
+    CAPTURE.lock().ring.clear();
+}
+
+/// Write the ring buffer as a complete pcap file.
+pub fn dump(sink: CaptureSink) -> usize {
+    let capture = CAPTURE.lock();
+
+    sink.write(&file_header());
+    for packet in &capture.ring {
+        sink.write(&packet.record());
+    }
+
+    capture.ring.len()
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+    header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+
+    // The time zone offset and the accuracy of the timestamps.
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+
+    header.extend_from_slice(&(SNAPSHOT_LENGTH as u32).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// Formats bytes as lowercase hexadecimal, without separators.
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
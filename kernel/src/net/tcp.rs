@@ -0,0 +1,1034 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! TCP over IPv4, with streams for the tasks: [`TcpListener::bind`] takes a
+//! port of which [`TcpListener::accept`] waits for the connections, and
+//! [`TcpStream::connect`] opens one, after which [`TcpStream::read`] and
+//! [`TcpStream::write`] wait for the data and for room in the buffers.
+//! Dropping a stream closes it, after the data that was written. The
+//! `tcp connect` shell command hands its connection to the `tcp-client` task
+//! (see [`run_client`]), since the shell can't wait.
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 2+2  | Source and destination port                              |
+//! | 4      | 4    | Sequence number of the first byte                        |
+//! | 8      | 4    | Acknowledgment number, the next byte expected            |
+//! | 12     | 1    | Header length in words                                   |
+//! | 13     | 1    | Flags, e.g. `SYN`, `ACK`, `FIN` and `RST`                |
+//! | 14     | 2    | Window, the room in the receive buffer                   |
+//! | 16     | 2    | Checksum, including the addresses of the IPv4 header     |
+//! | 18     | 2    | Urgent pointer, unused                                   |
+//!
+//! Received segments are answered right away by the `net` task, while the
+//! `tcp` task (see [`run`]) sends the data, and runs the retransmission
+//! timer on the ticks of the timer interrupt. This is a simplified
+//! implementation:
+//! - Segments out of order are dropped and acknowledged again, so the peer
+//!   retransmits from the first missing byte.
+//! - The retransmission timeout starts at [`INITIAL_RTO`] and doubles with
+//!   every retransmission, without measuring the round-trip time, and all
+//!   unacknowledged data is resent (go-back-N).
+//! - There is no congestion control, only the window of the peer, and no
+//!   delayed acknowledgments.
+//! - `TIME-WAIT` lasts [`TIME_WAIT`] instead of twice the segment lifetime.
+//!
+//! ### References:
+//! - [RFC 9293: Transmission Control Protocol](https://www.rfc-editor.org/rfc/rfc9293)
+//! - [RFC 6298: Computing TCP's Retransmission Timer](https://www.rfc-editor.org/rfc/rfc6298)
+
+use alloc::{collections::{BTreeMap, VecDeque}, string::String, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::trace;
+
+use crate::{arch, crypto::entropy, meta::counters::Counter, println, sync::Spinlock, task::timer};
+
+use super::{
+    interface,
+    ipv4::{self, Ipv4Address, Ipv4Header},
+};
+
+pub const HEADER_SIZE: usize = 20;
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The largest segment the peer sends when it doesn't say otherwise.
+const DEFAULT_MSS: usize = 536;
+
+/// The largest segment we receive, which fits a single IPv4 packet.
+const LOCAL_MSS: usize = ipv4::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+
+/// The size of the receive buffer, and so the largest window.
+pub const RECEIVE_BUFFER_SIZE: usize = 16384;
+pub const SEND_BUFFER_SIZE: usize = 16384;
+
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// The number of retransmissions of a segment before the connection is given
+/// up.
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+pub const TIME_WAIT: Duration = Duration::from_secs(10);
+
+/// The number of connections a listener holds until they're accepted.
+const BACKLOG: usize = 8;
+
+/// How often the `tcp` task checks the timers, when nothing wakes it.
+const TIMER_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The longest a connection of the `tcp connect` shell command lasts.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The ports that [`TcpStream::connect`] connects from.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+static RECEIVED: Counter = Counter::new("net.tcp.received", "TCP segments received");
+static TRANSMITTED: Counter = Counter::new("net.tcp.transmitted", "TCP segments sent");
+static RETRANSMISSIONS: Counter = Counter::new("net.tcp.retransmissions", "TCP retransmission timeouts");
+static RESETS: Counter = Counter::new("net.tcp.resets", "TCP resets sent to segments without a connection");
+
+static CONNECTIONS: Spinlock<Vec<Connection>> = Spinlock::new(Vec::new());
+
+/// The listening ports, with the waker of the task accepting on it. Locked
+/// after [`CONNECTIONS`].
+static LISTENERS: Spinlock<BTreeMap<u16, Option<Waker>>> = Spinlock::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Whether the `tcp` task has segments to send.
+static PENDING: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The destination, port and text of the `tcp connect` shell command, until
+/// the `tcp-client` task finished the connection.
+static CLIENT_REQUEST: Spinlock<Option<(Ipv4Address, u16, String)>> = Spinlock::new(None);
+static CLIENT_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpError {
+    PortInUse(u16),
+    NoPortsLeft,
+
+    /// No interface has an IPv4 address to connect from.
+    NoRoute,
+
+    /// The peer reset the connection while it was being opened.
+    Refused,
+
+    /// The peer reset the connection.
+    Reset,
+
+    /// The peer stopped acknowledging.
+    TimedOut,
+
+    /// The stream was closed for writing.
+    Closed,
+}
+
+impl Display for TcpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::PortInUse(port) => write!(f, "port {port} is already in use"),
+            Self::NoPortsLeft => f.write_str("all ephemeral ports are in use"),
+            Self::NoRoute => f.write_str("no interface has an IPv4 address"),
+            Self::Refused => f.write_str("connection refused"),
+            Self::Reset => f.write_str("connection reset by peer"),
+            Self::TimedOut => f.write_str("connection timed out"),
+            Self::Closed => f.write_str("the stream is closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl TcpState {
+    /// Whether the peer's data is still welcome.
+    const fn receives(&self) -> bool {
+        matches!(self, Self::Established | Self::FinWait1 | Self::FinWait2)
+    }
+
+    /// Whether the application can still write.
+    const fn sends(&self) -> bool {
+        matches!(self, Self::Established | Self::CloseWait)
+    }
+}
+
+impl Display for TcpState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::SynSent => "SYN-SENT",
+            Self::SynReceived => "SYN-RECEIVED",
+            Self::Established => "ESTABLISHED",
+            Self::FinWait1 => "FIN-WAIT-1",
+            Self::FinWait2 => "FIN-WAIT-2",
+            Self::CloseWait => "CLOSE-WAIT",
+            Self::Closing => "CLOSING",
+            Self::LastAck => "LAST-ACK",
+            Self::TimeWait => "TIME-WAIT",
+            Self::Closed => "CLOSED",
+        })
+    }
+}
+
+/// `a` comes before `b` in the sequence space, which wraps around.
+const fn sequence_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+const fn sequence_le(a: u32, b: u32) -> bool {
+    a == b || sequence_lt(a, b)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence: u32,
+    pub acknowledgment: u32,
+    pub flags: u8,
+    pub window: u16,
+
+    /// The maximum segment size option, of `SYN` segments.
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Parse the header of a segment in the packet, of which the checksum is
+    /// verified, returning it together with the payload.
+    pub fn parse<'a>(header: &Ipv4Header, segment: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        if segment.len() < HEADER_SIZE
+            || ipv4::pseudo_header_checksum(header.source, header.destination, ipv4::PROTOCOL_TCP, segment) != 0 {
+            return None;
+        }
+
+        let header_length = (segment[12] >> 4) as usize * 4;
+        if header_length < HEADER_SIZE || header_length > segment.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[HEADER_SIZE..header_length];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => {
+                    options = rest;
+                    continue;
+                }
+                _ => (),
+            }
+
+            let length = *rest.first()? as usize;
+            let value = options.get(2..length.max(2))?;
+            if *kind == OPTION_MSS && value.len() == 2 {
+                mss = Some(u16::from_be_bytes([value[0], value[1]]));
+            }
+            options = &options[length.max(2)..];
+        }
+
+        let word = |offset: usize| u32::from_be_bytes(segment[offset..offset + 4].try_into().unwrap());
+        let header = Self {
+            source_port: u16::from_be_bytes([segment[0], segment[1]]),
+            destination_port: u16::from_be_bytes([segment[2], segment[3]]),
+            sequence: word(4),
+            acknowledgment: word(8),
+            flags: segment[13],
+            window: u16::from_be_bytes([segment[14], segment[15]]),
+            mss,
+        };
+        Some((header, &segment[header_length..]))
+    }
+
+    /// Build a segment with this header and the given payload, between the
+    /// addresses the checksum covers.
+    pub fn build(&self, source: Ipv4Address, destination: Ipv4Address, payload: &[u8]) -> Vec<u8> {
+        let header_length = HEADER_SIZE + if self.mss.is_some() { 4 } else { 0 };
+        let mut segment = Vec::with_capacity(header_length + payload.len());
+        segment.extend_from_slice(&self.source_port.to_be_bytes());
+        segment.extend_from_slice(&self.destination_port.to_be_bytes());
+        segment.extend_from_slice(&self.sequence.to_be_bytes());
+        segment.extend_from_slice(&self.acknowledgment.to_be_bytes());
+        segment.extend_from_slice(&[(header_length / 4) as u8 * 16, self.flags]);
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            segment.extend_from_slice(&[OPTION_MSS, 4]);
+            segment.extend_from_slice(&mss.to_be_bytes());
+        }
+        segment.extend_from_slice(payload);
+
+        let checksum = ipv4::pseudo_header_checksum(source, destination, ipv4::PROTOCOL_TCP, &segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        segment
+    }
+
+    /// The length of the segment in the sequence space, in which `SYN` and
+    /// `FIN` count as a byte.
+    fn sequence_length(&self, payload: &[u8]) -> u32 {
+        payload.len() as u32 + (self.flags & FLAG_SYN != 0) as u32 + (self.flags & FLAG_FIN != 0) as u32
+    }
+}
+
+struct Connection {
+    id: u64,
+    state: TcpState,
+    local: (Ipv4Address, u16),
+    remote: (Ipv4Address, u16),
+
+    /// The port of the listener, until the connection is accepted.
+    listener: Option<u16>,
+
+    /// Whether the stream was dropped, after which the connection is removed
+    /// once it's closed.
+    dropped: bool,
+    error: Option<TcpError>,
+
+    initial_sequence: u32,
+
+    /// The first byte the peer didn't acknowledge, which is the first byte
+    /// of the send buffer.
+    send_unacknowledged: u32,
+    send_next: u32,
+    send_window: u32,
+    send_buffer: VecDeque<u8>,
+    mss: usize,
+
+    /// Whether the application closed the stream, for a `FIN` after the send
+    /// buffer.
+    fin_queued: bool,
+    fin_sent: bool,
+
+    receive_next: u32,
+    receive_buffer: VecDeque<u8>,
+    fin_received: bool,
+
+    /// Whether an acknowledgment should be sent without data, e.g. since the
+    /// window opened.
+    ack_pending: bool,
+
+    /// The tick count at which the unacknowledged segments are sent again.
+    retransmit_deadline: Option<usize>,
+    rto: Duration,
+    retransmissions: u32,
+    time_wait_deadline: usize,
+
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: TcpState, local: (Ipv4Address, u16), remote: (Ipv4Address, u16)) -> Self {
+        let initial_sequence = entropy::random_u64() as u32;
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            state,
+            local,
+            remote,
+            listener: None,
+            dropped: false,
+            error: None,
+            initial_sequence,
+            send_unacknowledged: initial_sequence,
+            send_next: initial_sequence,
+            send_window: 0,
+            send_buffer: VecDeque::new(),
+            mss: DEFAULT_MSS,
+            fin_queued: false,
+            fin_sent: false,
+            receive_next: 0,
+            receive_buffer: VecDeque::new(),
+            fin_received: false,
+            ack_pending: false,
+            retransmit_deadline: None,
+            rto: INITIAL_RTO,
+            retransmissions: 0,
+            time_wait_deadline: 0,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER_SIZE - self.receive_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn segment(&mut self, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let header = TcpHeader {
+            source_port: self.local.1,
+            destination_port: self.remote.1,
+            sequence,
+            acknowledgment: if flags & FLAG_ACK != 0 { self.receive_next } else { 0 },
+            flags,
+            window: self.window(),
+            mss: (flags & FLAG_SYN != 0).then_some(LOCAL_MSS as u16),
+        };
+        self.ack_pending = false;
+        header.build(self.local.0, self.remote.0, payload)
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    fn fail(&mut self, error: TcpError) {
+        trace!("TCP {}:{}: {error}", self.remote.0, self.remote.1);
+        self.state = TcpState::Closed;
+        self.error = Some(error);
+        if self.listener.is_some() {
+            // Nobody will accept it anymore.
+            self.dropped = true;
+        }
+        self.wake();
+    }
+
+    /// Close the sending side, after the data that was written.
+    fn close(&mut self) {
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => self.state = TcpState::Closed,
+            TcpState::Established | TcpState::CloseWait => self.fin_queued = true,
+            _ => (),
+        }
+        self.wake();
+        poke();
+    }
+
+    fn arm_timer(&mut self, now: usize) {
+        if self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(now + self.rto.as_millis() as usize * arch::TICKS_PER_SECOND / 1000);
+        }
+    }
+
+    /// Take the acknowledgment of our `SYN`, which completes the handshake.
+    fn synchronize(&mut self, header: &TcpHeader) {
+        self.send_unacknowledged = header.acknowledgment;
+        self.send_window = header.window as u32;
+        self.rto = INITIAL_RTO;
+        self.retransmissions = 0;
+        self.retransmit_deadline = None;
+    }
+
+    /// Process the acknowledgment of a segment in a synchronized state.
+    fn acknowledge(&mut self, header: &TcpHeader, now: usize) {
+        let acknowledgment = header.acknowledgment;
+        if sequence_lt(self.send_unacknowledged, acknowledgment) && sequence_le(acknowledgment, self.send_next) {
+            let acknowledged = acknowledgment.wrapping_sub(self.send_unacknowledged) as usize;
+            let data = acknowledged.min(self.send_buffer.len());
+            self.send_buffer.drain(..data);
+            self.send_unacknowledged = acknowledgment;
+
+            self.rto = INITIAL_RTO;
+            self.retransmissions = 0;
+            self.retransmit_deadline = None;
+            if self.send_unacknowledged != self.send_next {
+                self.arm_timer(now);
+            }
+            if let Some(waker) = self.writer.take() {
+                waker.wake();
+            }
+        }
+        self.send_window = header.window as u32;
+        if self.send_window == 0 {
+            // Still alive, just not reading, so the window probes don't
+            // count towards giving up.
+            self.retransmissions = 0;
+        }
+
+        let fin_acknowledged = self.fin_sent && self.send_unacknowledged == self.send_next;
+        if fin_acknowledged {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => self.state = TcpState::Closed,
+                _ => (),
+            }
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: usize) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_deadline = None;
+        self.time_wait_deadline = now + TIME_WAIT.as_secs() as usize * arch::TICKS_PER_SECOND;
+    }
+
+    /// Process a segment of the connection, returning the answer to send.
+    fn receive(&mut self, header: &TcpHeader, payload: &[u8]) -> Option<Vec<u8>> {
+        let now = arch::ticks();
+
+        if self.state == TcpState::SynSent {
+            let acceptable = header.acknowledgment == self.send_next;
+            if header.flags & FLAG_ACK != 0 && !acceptable {
+                return (header.flags & FLAG_RST == 0).then(|| reset_for(self.local.0, header, self.remote.0, payload));
+            }
+            if header.flags & FLAG_RST != 0 {
+                if acceptable {
+                    self.fail(TcpError::Refused);
+                }
+                return None;
+            }
+            if header.flags & (FLAG_SYN | FLAG_ACK) != FLAG_SYN | FLAG_ACK {
+                return None;
+            }
+
+            self.receive_next = header.sequence.wrapping_add(1);
+            self.mss = header.mss.map_or(DEFAULT_MSS, |mss| (mss as usize).min(LOCAL_MSS));
+            self.state = TcpState::Established;
+            self.synchronize(header);
+            self.wake();
+            return Some(self.segment(self.send_next, FLAG_ACK, &[]));
+        }
+
+        // Skip the bytes we have, of retransmissions that overlap new data.
+        let mut payload = payload;
+        let mut sequence = header.sequence;
+        if sequence_lt(sequence, self.receive_next) {
+            let known = self.receive_next.wrapping_sub(sequence) as usize;
+            if known < payload.len() {
+                payload = &payload[known..];
+                sequence = self.receive_next;
+            }
+        }
+
+        if sequence != self.receive_next {
+            // Out of order, or old: ask for the next byte again.
+            if header.flags & FLAG_RST != 0 {
+                return None;
+            }
+            return Some(self.segment(self.send_next, FLAG_ACK, &[]));
+        }
+
+        if header.flags & FLAG_RST != 0 {
+            if self.state == TcpState::SynReceived {
+                self.state = TcpState::Closed;
+                self.dropped = true;
+            } else {
+                self.fail(TcpError::Reset);
+            }
+            return None;
+        }
+
+        if header.flags & FLAG_SYN != 0 {
+            self.fail(TcpError::Reset);
+            return Some(self.segment(self.send_next, FLAG_RST, &[]));
+        }
+
+        if header.flags & FLAG_ACK == 0 {
+            return None;
+        }
+
+        if self.state == TcpState::SynReceived {
+            if header.acknowledgment != self.send_next {
+                return Some(reset_for(self.local.0, header, self.remote.0, payload));
+            }
+            self.state = TcpState::Established;
+            self.synchronize(header);
+            if let Some(Some(waker)) = self.listener.and_then(|port| LISTENERS.lock().get_mut(&port).map(Option::take)) {
+                waker.wake();
+            }
+        }
+
+        if sequence_lt(self.send_next, header.acknowledgment) {
+            // Acknowledges what we didn't send.
+            return Some(self.segment(self.send_next, FLAG_ACK, &[]));
+        }
+        self.acknowledge(header, now);
+
+        let mut answer = false;
+        if !payload.is_empty() && self.state.receives() {
+            let taken = if self.dropped {
+                // Nobody reads it anymore.
+                payload.len()
+            } else {
+                let taken = payload.len().min(RECEIVE_BUFFER_SIZE - self.receive_buffer.len());
+                self.receive_buffer.extend(&payload[..taken]);
+                taken
+            };
+            self.receive_next = self.receive_next.wrapping_add(taken as u32);
+            payload = &payload[taken..];
+            answer = true;
+            if let Some(waker) = self.reader.take() {
+                waker.wake();
+            }
+        }
+
+        // The FIN counts when the data before it was all taken.
+        if header.flags & FLAG_FIN != 0 && payload.is_empty() && !self.fin_received {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.fin_received = true;
+            answer = true;
+            match self.state {
+                TcpState::SynReceived | TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => (),
+            }
+            self.wake();
+        }
+
+        answer.then(|| self.segment(self.send_next, FLAG_ACK, &[]))
+    }
+
+    /// Produce the segments to send: the handshake, the data that fits the
+    /// window and the `FIN`, or else a pending acknowledgment, resending the
+    /// unacknowledged ones when the timer expired.
+    fn transmit(&mut self, now: usize, outgoing: &mut Vec<(Ipv4Address, Vec<u8>)>) {
+        if self.state == TcpState::TimeWait && now >= self.time_wait_deadline {
+            self.state = TcpState::Closed;
+        }
+        if self.state == TcpState::Closed {
+            return;
+        }
+
+        if self.retransmit_deadline.is_some_and(|deadline| now >= deadline) {
+            RETRANSMISSIONS.increment();
+            self.retransmissions += 1;
+            if self.retransmissions > MAX_RETRANSMISSIONS {
+                self.fail(TcpError::TimedOut);
+                return;
+            }
+
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.retransmit_deadline = None;
+            self.send_next = self.send_unacknowledged;
+            self.fin_sent = false;
+        }
+
+        let remote = self.remote.0;
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived if self.send_next == self.initial_sequence => {
+                let flags = if self.state == TcpState::SynSent { FLAG_SYN } else { FLAG_SYN | FLAG_ACK };
+                outgoing.push((remote, self.segment(self.initial_sequence, flags, &[])));
+                self.send_next = self.initial_sequence.wrapping_add(1);
+                self.arm_timer(now);
+                return;
+            }
+            TcpState::SynSent | TcpState::SynReceived => return,
+            _ => (),
+        }
+
+        // The FIN follows the data, so the part of the buffer that was sent is
+        // the sent part of the sequence space, without it.
+        let sent = |connection: &Self| {
+            (connection.send_next.wrapping_sub(connection.send_unacknowledged) as usize).min(connection.send_buffer.len())
+        };
+
+        let sent_before = outgoing.len();
+        loop {
+            let in_flight = sent(self);
+            let unsent = self.send_buffer.len() - in_flight;
+            if unsent == 0 {
+                break;
+            }
+
+            // Probe a closed window with a single byte, so its opening isn't
+            // missed when that acknowledgment gets lost.
+            let window = match self.send_window as usize {
+                0 if in_flight == 0 => 1,
+                window => window.saturating_sub(in_flight),
+            };
+            let length = unsent.min(window).min(self.mss);
+            if length == 0 {
+                break;
+            }
+
+            let data: Vec<u8> = self.send_buffer.range(in_flight..in_flight + length).copied().collect();
+            outgoing.push((remote, self.segment(self.send_next, FLAG_ACK | FLAG_PSH, &data)));
+            self.send_next = self.send_next.wrapping_add(length as u32);
+            self.arm_timer(now);
+        }
+
+        if self.fin_queued && !self.fin_sent && sent(self) == self.send_buffer.len() {
+            outgoing.push((remote, self.segment(self.send_next, FLAG_FIN | FLAG_ACK, &[])));
+            self.send_next = self.send_next.wrapping_add(1);
+            self.fin_sent = true;
+            self.arm_timer(now);
+            match self.state {
+                TcpState::Established => self.state = TcpState::FinWait1,
+                TcpState::CloseWait => self.state = TcpState::LastAck,
+                _ => (),
+            }
+        }
+
+        if self.ack_pending && outgoing.len() == sent_before {
+            outgoing.push((remote, self.segment(self.send_next, FLAG_ACK, &[])));
+        }
+    }
+}
+
+/// The reset for a segment that doesn't belong to a connection, which has the
+/// sequence number the segment acknowledged, or else acknowledges it.
+fn reset_for(local: Ipv4Address, header: &TcpHeader, remote: Ipv4Address, payload: &[u8]) -> Vec<u8> {
+    RESETS.increment();
+    let reset = if header.flags & FLAG_ACK != 0 {
+        TcpHeader {
+            source_port: header.destination_port,
+            destination_port: header.source_port,
+            sequence: header.acknowledgment,
+            acknowledgment: 0,
+            flags: FLAG_RST,
+            window: 0,
+            mss: None,
+        }
+    } else {
+        TcpHeader {
+            source_port: header.destination_port,
+            destination_port: header.source_port,
+            sequence: 0,
+            acknowledgment: header.sequence.wrapping_add(header.sequence_length(payload)),
+            flags: FLAG_RST | FLAG_ACK,
+            window: 0,
+            mss: None,
+        }
+    };
+    reset.build(local, remote, &[])
+}
+
+/// Let the `tcp` task send what the connections have.
+fn poke() {
+    PENDING.store(true, Ordering::Release);
+    WAKER.wake();
+}
+
+/// Process a received segment, returning the answer to send back, if any.
+pub fn handle_segment(ip: &Ipv4Header, segment: &[u8]) -> Option<Vec<u8>> {
+    RECEIVED.increment();
+    let (header, payload) = TcpHeader::parse(ip, segment)?;
+    let local = (ip.destination, header.destination_port);
+    let remote = (ip.source, header.source_port);
+
+    let mut connections = CONNECTIONS.lock();
+    if let Some(connection) = connections.iter_mut()
+        .find(|connection| connection.state != TcpState::Closed && connection.local == local && connection.remote == remote) {
+        let answer = connection.receive(&header, payload);
+        poke();
+        return answer;
+    }
+
+    if header.flags & FLAG_RST != 0 {
+        return None;
+    }
+
+    let listening = LISTENERS.lock().contains_key(&local.1);
+    let backlog = connections.iter().filter(|connection| connection.listener == Some(local.1)).count();
+    if header.flags & (FLAG_SYN | FLAG_ACK) != FLAG_SYN || !listening || backlog >= BACKLOG {
+        return Some(reset_for(local.0, &header, remote.0, payload));
+    }
+
+    let mut connection = Connection::new(TcpState::SynReceived, local, remote);
+    connection.listener = Some(local.1);
+    connection.receive_next = header.sequence.wrapping_add(1);
+    connection.send_window = header.window as u32;
+    connection.mss = header.mss.map_or(DEFAULT_MSS, |mss| (mss as usize).min(LOCAL_MSS));
+
+    let mut outgoing = Vec::new();
+    connection.transmit(arch::ticks(), &mut outgoing);
+    connections.push(connection);
+    outgoing.pop().map(|(_, segment)| segment)
+}
+
+fn with_connection<R>(id: u64, f: impl FnOnce(&mut Connection) -> R) -> R {
+    f(CONNECTIONS.lock().iter_mut().find(|connection| connection.id == id).expect("the stream keeps its connection"))
+}
+
+/// A port that accepts connections, see [`TcpListener::bind`].
+#[derive(Debug)]
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<Self, TcpError> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(TcpError::PortInUse(port));
+        }
+
+        listeners.insert(port, None);
+        Ok(Self { port })
+    }
+
+    /// Wait for the next connection that completed its handshake.
+    pub async fn accept(&self) -> TcpStream {
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let accepted = connections.iter_mut().find(|connection| {
+                connection.listener == Some(self.port) && !matches!(connection.state, TcpState::SynReceived | TcpState::Closed)
+            });
+
+            match accepted {
+                Some(connection) => {
+                    connection.listener = None;
+                    Poll::Ready(TcpStream { id: connection.id, remote: connection.remote })
+                }
+                None => {
+                    if let Some(waker) = LISTENERS.lock().get_mut(&self.port) {
+                        *waker = Some(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        }).await
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.port);
+        for connection in CONNECTIONS.lock().iter_mut().filter(|connection| connection.listener == Some(self.port)) {
+            connection.dropped = true;
+            connection.close();
+        }
+    }
+}
+
+/// A connection, see [`TcpStream::connect`] and [`TcpListener::accept`].
+#[derive(Debug)]
+pub struct TcpStream {
+    id: u64,
+    remote: (Ipv4Address, u16),
+}
+
+impl TcpStream {
+    /// Open a connection to the port of the destination.
+    pub async fn connect(destination: Ipv4Address, port: u16) -> Result<Self, TcpError> {
+        let source = interface::with_ipv4_route(destination, |interface| interface.ipv4_address())
+            .flatten()
+            .ok_or(TcpError::NoRoute)?
+            .address;
+
+        let stream = {
+            let mut connections = CONNECTIONS.lock();
+            let listeners = LISTENERS.lock();
+            let local_port = EPHEMERAL_PORTS.clone()
+                .find(|port| !listeners.contains_key(port) && connections.iter().all(|connection| connection.local.1 != *port))
+                .ok_or(TcpError::NoPortsLeft)?;
+
+            let connection = Connection::new(TcpState::SynSent, (source, local_port), (destination, port));
+            let stream = Self { id: connection.id, remote: connection.remote };
+            connections.push(connection);
+            stream
+        };
+        poke();
+
+        poll_fn(|cx| with_connection(stream.id, |connection| {
+            if let Some(error) = connection.error {
+                return Poll::Ready(Err(error));
+            }
+            if connection.state != TcpState::SynSent {
+                return Poll::Ready(Ok(()));
+            }
+            connection.writer = Some(cx.waker().clone());
+            Poll::Pending
+        })).await?;
+        Ok(stream)
+    }
+
+    pub fn peer(&self) -> (Ipv4Address, u16) {
+        self.remote
+    }
+
+    /// Wait for data, returning the number of bytes read, which is 0 once
+    /// the peer closed its side.
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, TcpError> {
+        poll_fn(|cx| with_connection(self.id, |connection| {
+            if !connection.receive_buffer.is_empty() {
+                let window = connection.window() as usize;
+                let length = buffer.len().min(connection.receive_buffer.len());
+                for (target, byte) in buffer.iter_mut().zip(connection.receive_buffer.drain(..length)) {
+                    *target = byte;
+                }
+
+                // Tell the peer the window opened, unless it was wide enough.
+                if window < RECEIVE_BUFFER_SIZE / 2 {
+                    connection.ack_pending = true;
+                    poke();
+                }
+                return Poll::Ready(Ok(length));
+            }
+
+            if connection.fin_received {
+                return Poll::Ready(Ok(0));
+            }
+            if let Some(error) = connection.error {
+                return Poll::Ready(Err(error));
+            }
+            connection.reader = Some(cx.waker().clone());
+            Poll::Pending
+        })).await
+    }
+
+    /// Wait for room in the send buffer, returning the number of bytes
+    /// written to it.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, TcpError> {
+        poll_fn(|cx| with_connection(self.id, |connection| {
+            if let Some(error) = connection.error {
+                return Poll::Ready(Err(error));
+            }
+            if !connection.state.sends() || connection.fin_queued {
+                return Poll::Ready(Err(TcpError::Closed));
+            }
+
+            let room = SEND_BUFFER_SIZE - connection.send_buffer.len();
+            if room == 0 {
+                connection.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let length = data.len().min(room);
+            connection.send_buffer.extend(&data[..length]);
+            poke();
+            Poll::Ready(Ok(length))
+        })).await
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), TcpError> {
+        while !data.is_empty() {
+            let length = self.write(data).await?;
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Close the sending side after the data that was written, while the
+    /// peer can still send.
+    pub fn shutdown(&self) {
+        with_connection(self.id, Connection::close);
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        with_connection(self.id, |connection| {
+            connection.dropped = true;
+            connection.receive_buffer.clear();
+            connection.close();
+        });
+    }
+}
+
+/// The connections, with their local and remote address and port.
+pub fn connections() -> Vec<((Ipv4Address, u16), (Ipv4Address, u16), TcpState)> {
+    CONNECTIONS.lock().iter()
+        .map(|connection| (connection.local, connection.remote, connection.state))
+        .collect()
+}
+
+pub fn listeners() -> Vec<u16> {
+    LISTENERS.lock().keys().copied().collect()
+}
+
+/// The task that sends the data of the connections, and runs their timers.
+pub async fn run() {
+    loop {
+        let woken = poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            match PENDING.swap(false, Ordering::AcqRel) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        });
+        _ = timer::timeout(TIMER_INTERVAL, woken).await;
+
+        let now = arch::ticks();
+        let mut outgoing = Vec::new();
+        {
+            let mut connections = CONNECTIONS.lock();
+            for connection in connections.iter_mut() {
+                connection.transmit(now, &mut outgoing);
+            }
+            connections.retain(|connection| !(connection.dropped && connection.state == TcpState::Closed));
+        }
+
+        for (destination, segment) in outgoing {
+            TRANSMITTED.increment();
+            if let Err(e) = ipv4::send(destination, ipv4::PROTOCOL_TCP, &segment).await {
+                trace!("TCP: failed to send to {destination}: {e}");
+            }
+        }
+    }
+}
+
+/// Let the `tcp-client` task send the text to the port of the destination,
+/// returning `false` when it's still busy with an earlier request.
+pub fn request_connect(destination: Ipv4Address, port: u16, text: String) -> bool {
+    let mut request = CLIENT_REQUEST.lock();
+    if request.is_some() {
+        return false;
+    }
+
+    *request = Some((destination, port, text));
+    CLIENT_WAKER.wake();
+    true
+}
+
+/// The task that runs the connections of the `tcp connect` shell command: it
+/// sends the text, closes its side and prints what the peer sends back until
+/// it closes the connection too.
+pub async fn run_client() {
+    loop {
+        let (destination, port, text) = poll_fn(|cx| {
+            CLIENT_WAKER.register(cx.waker());
+            match CLIENT_REQUEST.lock().clone() {
+                Some(request) => Poll::Ready(request),
+                None => Poll::Pending,
+            }
+        }).await;
+
+        match timer::timeout(CLIENT_TIMEOUT, exchange(destination, port, text.as_bytes())).await {
+            Ok(Ok(received)) => println!("Connection to {destination}:{port} closed, {received} bytes received"),
+            Ok(Err(e)) => println!("Connection to {destination}:{port} failed: {e}"),
+            Err(_) => println!("Connection to {destination}:{port} closed after {}s", CLIENT_TIMEOUT.as_secs()),
+        }
+        CLIENT_REQUEST.lock().take();
+    }
+}
+
+async fn exchange(destination: Ipv4Address, port: u16, text: &[u8]) -> Result<usize, TcpError> {
+    let stream = TcpStream::connect(destination, port).await?;
+    stream.write_all(text).await?;
+    stream.shutdown();
+
+    let mut buffer = [0; 512];
+    let mut received = 0;
+    loop {
+        let length = stream.read(&mut buffer).await?;
+        if length == 0 {
+            return Ok(received);
+        }
+
+        received += length;
+        println!("{}", String::from_utf8_lossy(&buffer[..length]));
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! UDP over IPv4 and IPv6, with sockets for the tasks: [`bind`] takes a port,
+//! after which [`UdpSocket::recv_from`] waits for the datagrams to it over
+//! either version, and [`UdpSocket::send_to`] sends from it to an address of
+//! either version. The port is released when the socket is dropped.
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 2    | Source port                                              |
+//! | 2      | 2    | Destination port                                         |
+//! | 4      | 2    | Length, of the header and the data                       |
+//! | 6      | 2    | Checksum, including the addresses of the IP header       |
+//!
+//! The checksum is optional over IPv4 (zero when there is none), but not over
+//! IPv6. Every socket queues at most [`QUEUE_LIMIT`] datagrams; later ones are
+//! dropped until the task takes them. Datagrams to ports without a socket are
+//! answered with an ICMP or ICMPv6 "port unreachable".
+//!
+//! ### References:
+//! - [RFC 768: User Datagram Protocol](https://www.rfc-editor.org/rfc/rfc768)
+//! - [RFC 8200: IPv6 Specification, section 8.1](https://www.rfc-editor.org/rfc/rfc8200#section-8.1)
+
+use alloc::{collections::{BTreeMap, VecDeque}, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    task::{Poll, Waker},
+};
+
+use futures_util::future::poll_fn;
+
+use crate::{meta::counters::Counter, sync::Spinlock};
+
+use super::{
+    interface,
+    ipv4::{self, Ipv4Address, Ipv4Error, Ipv4Header},
+    ipv6::{self, Ipv6Address, Ipv6Error, Ipv6Header},
+    IpAddress,
+};
+
+pub const HEADER_SIZE: usize = 8;
+
+/// The largest datagram, which fits a single IPv4 packet.
+pub const MAX_DATA_SIZE: usize = ipv4::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+
+pub const QUEUE_LIMIT: usize = 32;
+
+/// The ports that [`bind`] hands out for port 0.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+static RECEIVED: Counter = Counter::new("net.udp.received", "UDP datagrams delivered to a socket");
+static TRANSMITTED: Counter = Counter::new("net.udp.transmitted", "UDP datagrams sent");
+static DROPPED: Counter = Counter::new("net.udp.dropped", "UDP datagrams without a socket, with a bad checksum, or to a full queue");
+
+static SOCKETS: Spinlock<BTreeMap<u16, SocketState>> = Spinlock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    PortInUse(u16),
+    NoPortsLeft,
+    TooLarge(usize),
+    Send(Ipv4Error),
+    SendIpv6(Ipv6Error),
+}
+
+impl Display for UdpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::PortInUse(port) => write!(f, "port {port} is already bound"),
+            Self::NoPortsLeft => f.write_str("all ephemeral ports are bound"),
+            Self::TooLarge(length) => write!(f, "a datagram of {length} bytes doesn't fit a packet"),
+            Self::Send(e) => write!(f, "{e}"),
+            Self::SendIpv6(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub source: IpAddress,
+    pub source_port: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct SocketState {
+    queue: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+/// A bound port, see [`bind`].
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+}
+
+/// Take the port for a new socket, or the first free ephemeral port for
+/// port 0.
+pub fn bind(port: u16) -> Result<UdpSocket, UdpError> {
+    let mut sockets = SOCKETS.lock();
+    let port = match port {
+        0 => EPHEMERAL_PORTS.clone().find(|port| !sockets.contains_key(port)).ok_or(UdpError::NoPortsLeft)?,
+        port if sockets.contains_key(&port) => return Err(UdpError::PortInUse(port)),
+        port => port,
+    };
+
+    sockets.insert(port, SocketState::default());
+    Ok(UdpSocket { port })
+}
+
+impl UdpSocket {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send the data to the port of the destination.
+    pub async fn send_to(&self, data: &[u8], destination: IpAddress, port: u16) -> Result<(), UdpError> {
+        if data.len() > MAX_DATA_SIZE {
+            return Err(UdpError::TooLarge(data.len()));
+        }
+
+        match destination {
+            IpAddress::V4(destination) => {
+                let source = interface::with_ipv4_route(destination, |interface| interface.ipv4_address())
+                    .flatten()
+                    .ok_or(UdpError::Send(Ipv4Error::NoRoute))?
+                    .address;
+                let datagram = build(source, self.port, destination, port, data);
+                ipv4::send(destination, ipv4::PROTOCOL_UDP, &datagram).await.map_err(UdpError::Send)?;
+            }
+
+            IpAddress::V6(destination) => {
+                let source = interface::with_ipv6_route(destination, |interface| interface.ipv6().source_for(&destination))
+                    .ok_or(UdpError::SendIpv6(Ipv6Error::NoRoute))?;
+                let datagram = build_ipv6(source, self.port, destination, port, data);
+                ipv6::send(destination, ipv6::NEXT_HEADER_UDP, &datagram).await.map_err(UdpError::SendIpv6)?;
+            }
+        }
+
+        TRANSMITTED.increment();
+        Ok(())
+    }
+
+    /// Broadcast the data to the port on the link of the interface, which
+    /// doesn't need an address, see [`ipv4::send_broadcast`].
+    pub fn broadcast(&self, interface: &str, data: &[u8], port: u16) -> Result<(), UdpError> {
+        if data.len() > MAX_DATA_SIZE {
+            return Err(UdpError::TooLarge(data.len()));
+        }
+
+        let source = interface::with_interface(interface, |interface| interface.ipv4_address())
+            .ok_or(UdpError::Send(Ipv4Error::NoRoute))?
+            .map_or(Ipv4Address::UNSPECIFIED, |address| address.address);
+        let datagram = build(source, self.port, Ipv4Address::BROADCAST, port, data);
+        ipv4::send_broadcast(interface, ipv4::PROTOCOL_UDP, &datagram).map_err(UdpError::Send)?;
+        TRANSMITTED.increment();
+        Ok(())
+    }
+
+    /// Wait for the next datagram to the port.
+    pub async fn recv_from(&self) -> Datagram {
+        poll_fn(|cx| {
+            let mut sockets = SOCKETS.lock();
+            let state = sockets.get_mut(&self.port).expect("the socket keeps its port bound");
+            match state.queue.pop_front() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }).await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Build a datagram over IPv4, of which the checksum covers the addresses.
+pub fn build(source: Ipv4Address, source_port: u16, destination: Ipv4Address, destination_port: u16, data: &[u8]) -> Vec<u8> {
+    build_with_checksum(source_port, destination_port, data, |datagram| {
+        ipv4::pseudo_header_checksum(source, destination, ipv4::PROTOCOL_UDP, datagram)
+    })
+}
+
+/// Build a datagram over IPv6, of which the checksum covers the addresses.
+pub fn build_ipv6(source: Ipv6Address, source_port: u16, destination: Ipv6Address, destination_port: u16, data: &[u8]) -> Vec<u8> {
+    build_with_checksum(source_port, destination_port, data, |datagram| {
+        ipv6::pseudo_header_checksum(source, destination, ipv6::NEXT_HEADER_UDP, datagram)
+    })
+}
+
+fn build_with_checksum(source_port: u16, destination_port: u16, data: &[u8], checksum: impl FnOnce(&[u8]) -> u16) -> Vec<u8> {
+    let length = (HEADER_SIZE + data.len()) as u16;
+    let mut datagram = Vec::with_capacity(length as usize);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&length.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+
+    // Zero means "no checksum", so that one is sent as all ones.
+    let checksum = match checksum(&datagram) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// Parse a received datagram, returning the source port, the destination
+/// port and the data. Without `checksum_required`, a zero checksum means
+/// there is none.
+fn parse(datagram: &[u8], checksum_required: bool, checksum: impl FnOnce(&[u8]) -> u16) -> Option<(u16, u16, &[u8])> {
+    if datagram.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let datagram = datagram.get(..length).filter(|_| length >= HEADER_SIZE)?;
+    let sent_checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if (sent_checksum != 0 || checksum_required) && checksum(datagram) != 0 {
+        return None;
+    }
+
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    Some((source_port, destination_port, &datagram[HEADER_SIZE..]))
+}
+
+/// Queue a datagram received over IPv4 for the socket of its port, returning
+/// whether there is one.
+pub fn handle_datagram(header: &Ipv4Header, datagram: &[u8]) -> bool {
+    let parsed = parse(datagram, false, |datagram| {
+        ipv4::pseudo_header_checksum(header.source, header.destination, ipv4::PROTOCOL_UDP, datagram)
+    });
+    deliver(header.source.into(), parsed)
+}
+
+/// Queue a datagram received over IPv6 for the socket of its port, returning
+/// whether there is one.
+pub fn handle_datagram_ipv6(header: &Ipv6Header, datagram: &[u8]) -> bool {
+    let parsed = parse(datagram, true, |datagram| {
+        ipv6::pseudo_header_checksum(header.source, header.destination, ipv6::NEXT_HEADER_UDP, datagram)
+    });
+    deliver(header.source.into(), parsed)
+}
+
+fn deliver(source: IpAddress, parsed: Option<(u16, u16, &[u8])>) -> bool {
+    let Some((source_port, port, data)) = parsed else {
+        DROPPED.increment();
+        // Not for a missing socket, so no ICMP error either.
+        return true;
+    };
+
+    let mut sockets = SOCKETS.lock();
+    let Some(state) = sockets.get_mut(&port) else {
+        DROPPED.increment();
+        return false;
+    };
+
+    if state.queue.len() >= QUEUE_LIMIT {
+        DROPPED.increment();
+        return true;
+    }
+
+    state.queue.push_back(Datagram { source, source_port, data: data.to_vec() });
+    if let Some(waker) = state.waker.take() {
+        waker.wake();
+    }
+    RECEIVED.increment();
+    true
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Loading static ELF executables into the
+//! [user region](arch::memory::USER_REGION).
+//!
+//! Only the `PT_LOAD` segments are looked at: they're mapped as
+//! [code](UserRegion::Code), or as [data](UserRegion::Data) when writable,
+//! in an address space of their own, after which the file contents are copied
+//! in. The rest of each segment (the `.bss`) stays zero. There is no dynamic
+//! linking, so the executable must be linked at addresses within the user
+//! region, e.g. with `-Ttext-segment=0x400000000000`.
+//!
+//! ### References:
+//! - [System V ABI, AMD64 Architecture Processor Supplement](https://gitlab.com/x86-psABIs/x86-64-ABI)
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use elf::{
+    abi::{EM_X86_64, ET_EXEC, PF_W, PT_LOAD},
+    endian::NativeEndian,
+    file::Class,
+    ElfBytes,
+    ParseError,
+};
+use x86_64::{structures::paging::{mapper::MapToError, Size4KiB}, VirtAddr};
+
+use crate::arch::memory::{AddressSpace, UserRegion, USER_REGION};
+
+#[derive(Debug)]
+pub enum ElfError {
+    Parse(ParseError),
+
+    /// The file isn't a 64-bit executable for x86_64, e.g. a shared object.
+    NotExecutable,
+
+    /// A segment or the entry point is outside the user region.
+    OutsideUserRegion,
+
+    /// A segment refers to data past the end of the file.
+    Truncated,
+
+    Map(MapToError<Size4KiB>),
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "invalid ELF file: {e}"),
+            Self::NotExecutable => f.write_str("not a static x86_64 executable"),
+            Self::OutsideUserRegion => f.write_str("a segment is outside the user region"),
+            Self::Truncated => f.write_str("a segment is past the end of the file"),
+            Self::Map(e) => write!(f, "failed to map a page: {e:?}"),
+        }
+    }
+}
+
+/// An executable in memory, ready to run from its entry point.
+#[derive(Debug)]
+pub struct Image {
+    pub entry: VirtAddr,
+    pub address_space: AddressSpace,
+}
+
+/// Map the segments of the executable into a new address space, and copy
+/// them in.
+pub fn load(data: &[u8]) -> Result<Image, ElfError> {
+    let file = ElfBytes::<NativeEndian>::minimal_parse(data).map_err(ElfError::Parse)?;
+    if file.ehdr.class != Class::ELF64 || file.ehdr.e_type != ET_EXEC || file.ehdr.e_machine != EM_X86_64 {
+        return Err(ElfError::NotExecutable);
+    }
+
+    let segments: Vec<_> = file.segments()
+        .ok_or(ElfError::NotExecutable)?
+        .iter()
+        .filter(|segment| segment.p_type == PT_LOAD && segment.p_memsz != 0)
+        .collect();
+
+    for segment in &segments {
+        let end = segment.p_vaddr.checked_add(segment.p_memsz).ok_or(ElfError::OutsideUserRegion)?;
+        if segment.p_vaddr < USER_REGION.start || end > USER_REGION.end {
+            return Err(ElfError::OutsideUserRegion);
+        }
+
+        let file_end = segment.p_offset.checked_add(segment.p_filesz).ok_or(ElfError::Truncated)?;
+        if segment.p_filesz > segment.p_memsz || file_end > data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+    }
+
+    if !USER_REGION.contains(&file.ehdr.e_entry) {
+        return Err(ElfError::OutsideUserRegion);
+    }
+
+    let mut address_space = AddressSpace::new().ok_or(ElfError::Map(MapToError::FrameAllocationFailed))?;
+    for segment in &segments {
+        let start = VirtAddr::new(segment.p_vaddr);
+        let region = match segment.p_flags & PF_W {
+            0 => UserRegion::Code,
+            _ => UserRegion::Data,
+        };
+        address_space.map_region(start..start + segment.p_memsz, region).map_err(ElfError::Map)?;
+
+        let contents = &data[segment.p_offset as usize..(segment.p_offset + segment.p_filesz) as usize];
+        let written = address_space.write(start, contents);
+        debug_assert!(written, "the segment was just mapped");
+    }
+
+    Ok(Image { entry: VirtAddr::new(file.ehdr.e_entry), address_space })
+}
@@ -0,0 +1,273 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Processes: programs that run in user mode, each on a kernel thread of its
+//! own, started by the `run` shell command.
+//!
+//! [`spawn`] reads a static ELF executable from the [VFS](crate::fs::vfs),
+//! which has the files of the initrd as well, and [loads](elf::load) it into
+//! the [user region](arch::memory::USER_REGION) of an
+//! [address space](arch::memory::AddressSpace) of its own, with a stack below
+//! its end:
+//!
+//! | Address                          | Contents                                |
+//! |----------------------------------|-----------------------------------------|
+//! | Those of the `PT_LOAD` segments  | The code and data of the program        |
+//! | [`STACK_TOP`] - [`STACK_SIZE`]   | The stack, which starts zeroed          |
+//!
+//! The thread then [enters user mode](arch::user::enter) at the entry point,
+//! until the program exits (with the [exit system call](crate::syscall), or
+//! `int 0x80` with the status in RDI) or faults, after which the address space
+//! is torn down again. A program that faults leaves a
+//! [core dump](crate::meta::coredump) on the serial port first, with the
+//! registers of the fault and the memory it had mapped, apart from the unused
+//! part of the stack. The scheduler switches the address space and the
+//! x87/SSE registers along with the thread, so any number of processes can
+//! run side by side.
+
+pub mod elf;
+
+use alloc::{string::{String, ToString}, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    structures::{idt::{ExceptionVector, InterruptStackFrameValue}, paging::{PageTableFlags, Size4KiB, PageSize}},
+    VirtAddr,
+};
+
+use crate::{
+    arch::{
+        self,
+        memory::{AddressSpace, UserRegion},
+        user::UserExit,
+    },
+    fs::vfs::{self, VfsError},
+    meta::coredump::{self, CoreDump, Registers, Segment},
+    println,
+    sync::Spinlock,
+    syscall,
+    task::scheduler::{self, SpawnError, ThreadId},
+};
+
+use self::elf::ElfError;
+
+/// The end of the stack, which is the end of the user region.
+pub const STACK_TOP: u64 = arch::memory::USER_REGION.end;
+
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+/// What the stack pointer starts below [`STACK_TOP`]: zeroes, which the
+/// System V ABI reads as no arguments, environment variables or auxiliary
+/// vector entries.
+const INITIAL_STACK_FRAME: u64 = 64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The running processes, and the ones that ended, in the order they started.
+static PROCESSES: Spinlock<Vec<ProcessInfo>> = Spinlock::new(Vec::new());
+
+/// The number of ended processes [`PROCESSES`] remembers.
+const HISTORY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Ended(UserExit),
+}
+
+/// A process, see [`processes`].
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub id: ProcessId,
+    pub path: String,
+    pub thread: Option<ThreadId>,
+    pub state: ProcessState,
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    Read(VfsError),
+    Load(ElfError),
+    Spawn(SpawnError),
+}
+
+impl Display for ProcessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "failed to read the executable: {e}"),
+            Self::Load(e) => write!(f, "failed to load the executable: {e}"),
+            Self::Spawn(e) => write!(f, "failed to start a thread: {e:?}"),
+        }
+    }
+}
+
+/// Load the executable at the path, and start running it on a thread of its
+/// own.
+pub fn spawn(path: &str) -> Result<ProcessId, ProcessError> {
+    let id = ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    PROCESSES.lock().push(ProcessInfo { id, path: path.to_string(), thread: None, state: ProcessState::Running });
+
+    if let Err(e) = start(id, path) {
+        PROCESSES.lock().retain(|process| process.id != id);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+fn start(id: ProcessId, path: &str) -> Result<ThreadId, ProcessError> {
+    let data = vfs::read(path).map_err(ProcessError::Read)?;
+    let mut image = elf::load(&data).map_err(ProcessError::Load)?;
+    drop(data);
+    let stack = VirtAddr::new(STACK_TOP - STACK_SIZE)..VirtAddr::new(STACK_TOP);
+    image.address_space.map_region(stack, UserRegion::Stack).map_err(|e| ProcessError::Load(ElfError::Map(e)))?;
+
+    let entry = image.entry;
+    let address_space = image.address_space;
+    scheduler::spawn("process", move || run(id, entry, address_space)).map_err(ProcessError::Spawn)
+}
+
+/// The thread of the process, which records itself before the program runs,
+/// since the program might end before [`scheduler::spawn`] returns.
+fn run(id: ProcessId, entry: VirtAddr, address_space: AddressSpace) {
+    if let Some(thread) = scheduler::current() {
+        set_thread(id, thread);
+    }
+
+    address_space.activate();
+    let mut fault_frame = None;
+    let exit = unsafe { arch::user::enter(entry, VirtAddr::new(STACK_TOP - INITIAL_STACK_FRAME), &mut fault_frame) };
+
+    let path = {
+        let mut processes = PROCESSES.lock();
+        let Some(process) = processes.iter_mut().find(|process| process.id == id) else {
+            return;
+        };
+        process.state = ProcessState::Ended(exit);
+        process.path.clone()
+    };
+    syscall::release_input(id);
+
+    // The memory of the program is read through the address space, which is
+    // still active.
+    let core_size = fault_frame.map(|frame| dump_core(id, &path, &exit, &frame, &address_space));
+    drop(address_space);
+
+    match core_size {
+        Some(size) => println!("Process {} ({path}) {exit}, core dumped to the serial port ({size} bytes)", id.as_u64()),
+        None => println!("Process {} ({path}) {exit}", id.as_u64()),
+    }
+    forget_ended();
+}
+
+/// Write the core dump of the program that faulted to the serial port,
+/// returning its size. The address space must be active.
+fn dump_core(id: ProcessId, path: &str, exit: &UserExit, frame: &InterruptStackFrameValue, address_space: &AddressSpace) -> usize {
+    let stack_pointer = frame.stack_pointer.align_down(Size4KiB::SIZE);
+    let segments = address_space.mapped_ranges()
+        .into_iter()
+        .map(|(mut range, flags)| {
+            // Below the stack pointer, the stack is unused.
+            if range.contains(&stack_pointer) {
+                range.start = stack_pointer;
+            }
+
+            let mut segment_flags = coredump::SEGMENT_READ;
+            if flags.contains(PageTableFlags::WRITABLE) {
+                segment_flags |= coredump::SEGMENT_WRITE;
+            }
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                segment_flags |= coredump::SEGMENT_EXECUTE;
+            }
+
+            Segment {
+                virtual_address: range.start.as_u64(),
+                data: unsafe { core::slice::from_raw_parts(range.start.as_ptr::<u8>(), (range.end - range.start) as usize) },
+                flags: segment_flags,
+            }
+        })
+        .collect();
+
+    let dump = CoreDump {
+        name: path.rsplit('/').next().unwrap_or(path),
+        pid: id.as_u64() as u32,
+        signal: signal(exit),
+        registers: Registers {
+            rip: frame.instruction_pointer.as_u64(),
+            cs: frame.code_segment.0 as u64,
+            rflags: frame.cpu_flags.bits(),
+            rsp: frame.stack_pointer.as_u64(),
+            ss: frame.stack_segment.0 as u64,
+            ..Registers::default()
+        },
+        segments,
+        build_id: None,
+    };
+    dump.write_to_serial()
+}
+
+/// The signal Linux sends for the exception, which is what `gdb` shows as
+/// the reason the program stopped.
+fn signal(exit: &UserExit) -> u32 {
+    const SIGILL: u32 = 4;
+    const SIGTRAP: u32 = 5;
+    const SIGBUS: u32 = 7;
+    const SIGFPE: u32 = 8;
+    const SIGSEGV: u32 = 11;
+
+    match exit {
+        UserExit::Fault { vector, .. } => match vector {
+            ExceptionVector::InvalidOpcode => SIGILL,
+            ExceptionVector::Debug | ExceptionVector::Breakpoint => SIGTRAP,
+            ExceptionVector::Stack | ExceptionVector::AlignmentCheck => SIGBUS,
+            ExceptionVector::Division | ExceptionVector::X87FloatingPoint | ExceptionVector::SimdFloatingPoint => SIGFPE,
+            _ => SIGSEGV,
+        },
+        _ => SIGSEGV,
+    }
+}
+
+fn set_thread(id: ProcessId, thread: ThreadId) {
+    if let Some(process) = PROCESSES.lock().iter_mut().find(|process| process.id == id) {
+        process.thread = Some(thread);
+    }
+}
+
+/// Drop the oldest ended processes beyond the [`HISTORY`].
+fn forget_ended() {
+    let mut processes = PROCESSES.lock();
+    let ended = processes.iter().filter(|process| process.state != ProcessState::Running).count();
+    let mut excess = ended.saturating_sub(HISTORY);
+    processes.retain(|process| {
+        if excess > 0 && process.state != ProcessState::Running {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+/// The running processes and the ones that ended recently, in the order they
+/// started.
+pub fn processes() -> Vec<ProcessInfo> {
+    PROCESSES.lock().clone()
+}
+
+/// The process the calling thread runs.
+pub fn current() -> Option<ProcessId> {
+    let thread = scheduler::current()?;
+    PROCESSES.lock().iter()
+        .find(|process| process.thread == Some(thread) && process.state == ProcessState::Running)
+        .map(|process| process.id)
+}
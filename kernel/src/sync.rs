@@ -0,0 +1,115 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The lock types, which state whether the data is shared with interrupt
+//! handlers.
+//!
+//! An interrupt handler that takes a lock held by the code it interrupted
+//! spins forever. An [`IrqSpinlock`] prevents this by disabling interrupts
+//! while it's held, so the data can be shared with interrupt handlers. A
+//! [`Spinlock`] leaves interrupts alone, and must not be taken by interrupt
+//! handlers, which is why it's cheaper. Choosing the type at the declaration
+//! means the callers can't forget a `without_interrupts`.
+//!
+//! Interrupt handlers should prefer `try_lock`, and skip their work when the
+//! lock is busy, since the lock might be held by another CPU for a while.
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::arch;
+
+/// A lock of data that's shared with interrupt handlers, which keeps
+/// interrupts disabled while held.
+pub struct IrqSpinlock<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_were_enabled = arch::are_interrupts_enabled();
+        arch::disable_interrupts();
+
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let interrupts_were_enabled = arch::are_interrupts_enabled();
+        arch::disable_interrupts();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+            None => {
+                if interrupts_were_enabled {
+                    arch::enable_interrupts();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Restores the interrupt state of before the lock was taken, after releasing
+/// the lock.
+pub struct IrqSpinlockGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.interrupts_were_enabled {
+            arch::enable_interrupts();
+        }
+    }
+}
+
+/// A lock of data that interrupt handlers never touch, which leaves the
+/// interrupts alone.
+pub struct Spinlock<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.inner.lock()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The system calls of the [processes](crate::process), which they make with
+//! the `syscall` instruction: the number of the call in RAX, and the arguments
+//! in RDI, RSI, RDX, R10 and R8, like on Linux. The result comes back in RAX,
+//! and RCX and R11 are overwritten (see [`arch::syscall`]).
+//!
+//! | Number | Call                          | Returns                           |
+//! |--------|-------------------------------|-----------------------------------|
+//! | 0      | `exit(status)`                | Doesn't                           |
+//! | 1      | `write(pointer, length)`      | The number of bytes written       |
+//! | 2      | `sleep(milliseconds)`         | Zero                              |
+//! | 3      | `time()`                      | The milliseconds since boot       |
+//! | 4      | `read(pointer, length)`       | The number of bytes read          |
+//!
+//! `write` prints the bytes to the console, as UTF-8, and writes at most
+//! [`WRITE_LIMIT`] bytes at once, so longer writes have to be repeated for
+//! the rest. A negative result is a [`SyscallError`]. The numbers and the
+//! error codes don't change, so programs can be built against them, e.g.
+//! with the runtime in `user/runtime`.
+//!
+//! `read` waits for the keys typed at the console, and returns them as UTF-8,
+//! with Return as a line feed, and Ctrl with a letter as its control
+//! character, e.g. 4 for Ctrl+D. The keys aren't echoed. The first `read`
+//! takes the keyboard [focus](crate::task::focus) for the processes, which
+//! goes back to the shell when the process that took it ends; `Alt+Tab`
+//! switches between them meanwhile. A thread can't wait for a task, so `read`
+//! checks for keys every [`READ_POLL_INTERVAL`].
+
+use alloc::{collections::VecDeque, string::String};
+use core::time::Duration;
+
+use pc_keyboard::DecodedKey;
+use x86_64::VirtAddr;
+
+use crate::{
+    arch::{self, memory::USER_REGION},
+    meta::counters::Counter,
+    print,
+    process::{self, ProcessId},
+    sync::Spinlock,
+    task::{focus::{self, Consumer, Input}, keyboard::KeyPress, scheduler},
+};
+
+pub const EXIT: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const SLEEP: u64 = 2;
+pub const TIME: u64 = 3;
+pub const READ: u64 = 4;
+
+/// The most bytes a single `write` prints.
+pub const WRITE_LIMIT: usize = 4096;
+
+/// The longest `sleep`, a day.
+pub const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often `read` checks for key presses while there are none.
+pub const READ_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static CALLS: Counter = Counter::new("syscall.calls", "System calls made by the processes");
+static FAILED: Counter = Counter::new("syscall.failed", "System calls that returned an error");
+
+/// The keyboard input of the processes, opened by the first `read`.
+static INPUT: Spinlock<Option<ProcessInput>> = Spinlock::new(None);
+
+struct ProcessInput {
+    /// The process that opened the input, which closes it when it ends.
+    owner: ProcessId,
+    input: Input,
+
+    /// The bytes of the keys that didn't fit in the buffer of a `read`.
+    pending: VecDeque<u8>,
+}
+
+/// The errors of the system calls, which are returned negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// There is no call with the number.
+    UnknownCall = 1,
+
+    /// A pointer argument isn't mapped in the user region, or a buffer to
+    /// read into isn't writable.
+    BadAddress = 2,
+
+    /// An argument is out of range.
+    InvalidArgument = 3,
+}
+
+/// Run the system call, returning its result, or the negated error.
+pub fn dispatch(number: u64, arguments: [u64; 5]) -> i64 {
+    CALLS.increment();
+
+    let result = match number {
+        EXIT => arch::user::exit(arguments[0]),
+        WRITE => write(arguments[0], arguments[1]),
+        SLEEP => sleep(arguments[0]),
+        TIME => Ok(time()),
+        READ => read(arguments[0], arguments[1]),
+        _ => Err(SyscallError::UnknownCall),
+    };
+
+    result.unwrap_or_else(|e| {
+        FAILED.increment();
+        -(e as i64)
+    })
+}
+
+fn write(pointer: u64, length: u64) -> Result<i64, SyscallError> {
+    let length = (length as usize).min(WRITE_LIMIT);
+    let bytes = user_bytes(pointer, length)?;
+    print!("{}", String::from_utf8_lossy(bytes));
+    Ok(length as i64)
+}
+
+fn read(pointer: u64, length: u64) -> Result<i64, SyscallError> {
+    let buffer = user_bytes_mut(pointer, length as usize)?;
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+
+    let process = process::current().expect("system call outside of a process");
+    loop {
+        {
+            let mut input = INPUT.lock();
+            let input = input.get_or_insert_with(|| open_input(process));
+            while let Some(press) = input.input.try_next_event() {
+                if let Some(character) = key_character(&press) {
+                    input.pending.extend(character.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+
+            if !input.pending.is_empty() {
+                let count = buffer.len().min(input.pending.len());
+                for (byte, pending) in buffer.iter_mut().zip(input.pending.drain(..count)) {
+                    *byte = pending;
+                }
+                return Ok(count as i64);
+            }
+        }
+
+        scheduler::sleep(READ_POLL_INTERVAL);
+    }
+}
+
+/// Take the keyboard focus for the processes, discarding the keys left from
+/// an earlier process.
+fn open_input(owner: ProcessId) -> ProcessInput {
+    let mut input = Input::new(Consumer::Process);
+    while input.try_next_event().is_some() {}
+
+    focus::switch(Consumer::Process);
+    ProcessInput { owner, input, pending: VecDeque::new() }
+}
+
+/// What the key press reads as, if anything.
+fn key_character(press: &KeyPress) -> Option<char> {
+    match press.decoded? {
+        DecodedKey::Unicode(character) if press.modifiers.ctrl && character.is_ascii_alphabetic() => {
+            Some(char::from(character.to_ascii_lowercase() as u8 - b'a' + 1))
+        }
+        DecodedKey::Unicode(character) => Some(character),
+        DecodedKey::RawKey(_) => None,
+    }
+}
+
+/// Close the keyboard input if the process that ended opened it, giving the
+/// focus back.
+pub fn release_input(process: ProcessId) {
+    let mut input = INPUT.lock();
+    if input.as_ref().is_some_and(|input| input.owner == process) {
+        *input = None;
+        if focus::current() == Consumer::Process {
+            focus::restore();
+        }
+    }
+}
+
+fn sleep(milliseconds: u64) -> Result<i64, SyscallError> {
+    let duration = Duration::from_millis(milliseconds);
+    if duration > MAX_SLEEP {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    scheduler::sleep(duration);
+    Ok(0)
+}
+
+fn time() -> i64 {
+    (arch::ticks() * 1000 / arch::TICKS_PER_SECOND) as i64
+}
+
+/// The bytes of the program at the pointer, which must be mapped in the user
+/// region of the calling process. Its address space is only torn down once
+/// its thread returned from user mode, so they stay mapped while the call
+/// uses them.
+fn user_bytes<'a>(pointer: u64, length: usize) -> Result<&'a [u8], SyscallError> {
+    if length == 0 {
+        return Ok(&[]);
+    }
+
+    check_user_range(pointer, length, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(pointer as *const u8, length) })
+}
+
+/// Like [`user_bytes`], for a buffer the program gave to be written to, which
+/// must be mapped writable.
+fn user_bytes_mut<'a>(pointer: u64, length: usize) -> Result<&'a mut [u8], SyscallError> {
+    if length == 0 {
+        return Ok(&mut []);
+    }
+
+    check_user_range(pointer, length, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(pointer as *mut u8, length) })
+}
+
+fn check_user_range(pointer: u64, length: usize, write: bool) -> Result<(), SyscallError> {
+    let end = pointer.checked_add(length as u64).ok_or(SyscallError::BadAddress)?;
+    if pointer < USER_REGION.start || end > USER_REGION.end {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let mut page = VirtAddr::new(pointer).align_down(4096u64);
+    while page.as_u64() < end {
+        if !arch::memory::is_user_accessible(page, write) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += 4096u64;
+    }
+    Ok(())
+}
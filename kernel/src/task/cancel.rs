@@ -0,0 +1,122 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Cancellation tokens, to tell tasks to stop.
+//!
+//! Every task is cancelled by the [`shutdown`] token, which the executor
+//! cancels before the machine is powered off or rebooted (see
+//! [`executor::stop`](super::executor::stop)): the next time the task is
+//! polled, its future is dropped instead. A task that has to finish what it's
+//! doing first can check [`CancellationToken::is_cancelled`] between steps,
+//! as long as it does so before the executor's timeout.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use futures_util::future::poll_fn;
+
+use crate::sync::Spinlock;
+
+static SHUTDOWN: CancellationToken = CancellationToken::new();
+
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+
+    /// The tasks waiting for the token, see [`Cancelled`].
+    wakers: Spinlock<Vec<Waker>>,
+}
+
+impl CancellationToken {
+    pub const fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            wakers: Spinlock::new(Vec::new()),
+        }
+    }
+
+    /// Cancel the token, waking the tasks waiting for it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        for waker in core::mem::take(&mut *self.wakers.lock()) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Wait until the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self, waker: None }
+    }
+}
+
+/// A future that completes when the token is cancelled, see
+/// [`CancellationToken::cancelled`].
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+
+    /// The waker registered with the token, which is removed when dropped.
+    waker: Option<Waker>,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        if !self.waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            let mut wakers = self.token.wakers.lock();
+            if let Some(previous) = self.waker.take() {
+                wakers.retain(|waker| !waker.will_wake(&previous));
+            }
+            wakers.push(cx.waker().clone());
+            drop(wakers);
+            self.waker = Some(cx.waker().clone());
+        }
+
+        // Cancelled meanwhile, after which the waker wouldn't be woken.
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled<'_> {
+    fn drop(&mut self) {
+        if let Some(registered) = self.waker.take() {
+            self.token.wakers.lock().retain(|waker| !waker.will_wake(&registered));
+        }
+    }
+}
+
+/// The token that is cancelled when the machine shuts down.
+pub fn shutdown() -> &'static CancellationToken {
+    &SHUTDOWN
+}
+
+/// Run the future until it completes, or until the token is cancelled, in
+/// which case the future is dropped and `None` is returned.
+pub async fn until_cancelled<F: Future>(token: &CancellationToken, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut cancelled = pin!(token.cancelled());
+
+    poll_fn(|cx| {
+        if cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+
+        future.as_mut().poll(cx).map(Some)
+    }).await
+}
@@ -0,0 +1,392 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A full-screen editor for the text files of the FAT volume, opened with the
+//! `edit <file>` shell command. A file that doesn't exist yet is created when
+//! it's saved.
+//!
+//! The `editor` task takes the input focus (see [`focus`](super::focus)) and
+//! the screen while a file is open: the console keeps what is printed
+//! meanwhile, and draws it again when the editor closes (see
+//! [`Writer::hold`]). The status bar is hidden as well.
+//!
+//! | Key                     | Action                                              |
+//! |-------------------------|-----------------------------------------------------|
+//! | Arrows, `Home`, `End`   | Move the cursor                                     |
+//! | `PgUp`, `PgDn`          | Move the cursor by a screen                         |
+//! | `Backspace`, `Delete`   | Remove the character before or under the cursor     |
+//! | `Ctrl+S`                | Save the file                                       |
+//! | `Ctrl+Q`, `Esc`         | Close the editor, twice to discard unsaved changes  |
+//!
+//! Files are limited to [`MAX_FILE_SIZE`], and must be UTF-8. Lines are saved
+//! with `\n` endings, so `\r\n` endings are converted.
+
+use alloc::{format, string::String, vec, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+use crate::{
+    fs::fat::{FatError, FatVolume},
+    println,
+    vga_text_buffer::{Color, Writer, WRITER},
+};
+
+use super::{focus::{self, Consumer, Input}, keyboard::KeyPress};
+
+/// The largest file that is opened, since every character takes 4 bytes of
+/// the heap.
+pub const MAX_FILE_SIZE: u32 = 64 * 1024;
+
+const PADDING: usize = 8;
+const TAB_WIDTH: usize = 4;
+
+/// The file to open, see [`open`].
+static REQUEST: Mutex<Option<String>> = Mutex::new(None);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Whether a file is requested or open.
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorError {
+    Fat(FatError),
+
+    /// The file is larger than [`MAX_FILE_SIZE`].
+    TooLarge,
+
+    /// The file isn't UTF-8.
+    NotText,
+}
+
+impl From<FatError> for EditorError {
+    fn from(value: FatError) -> Self {
+        Self::Fat(value)
+    }
+}
+
+/// Open the file in the editor. Returns `false` when another file is open.
+pub fn open(name: &str) -> bool {
+    if OPEN.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+
+    *REQUEST.lock() = Some(name.into());
+    WAKER.wake();
+    true
+}
+
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::Relaxed)
+}
+
+/// The task that edits the files requested with [`open`].
+pub async fn run() {
+    loop {
+        let name = poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            match REQUEST.lock().take() {
+                Some(name) => Poll::Ready(name),
+                None => Poll::Pending,
+            }
+        }).await;
+
+        match Editor::load(&name) {
+            Ok(editor) => edit(editor).await,
+            Err(e) => println!("edit: {name}: {e:?}"),
+        }
+
+        OPEN.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Take the screen and the keyboard until the editor is closed.
+async fn edit(mut editor: Editor) {
+    let mut input = Input::new(Consumer::Editor);
+    focus::switch(Consumer::Editor);
+    {
+        let mut writer = WRITER.lock();
+        writer.hold();
+        editor.draw(&mut writer);
+    }
+
+    while let Some(press) = input.next_event().await {
+        if !editor.handle(press) {
+            break;
+        }
+        editor.draw(&mut WRITER.lock());
+    }
+
+    drop(input);
+    focus::restore();
+    WRITER.lock().release();
+}
+
+struct Editor {
+    volume: FatVolume,
+    name: String,
+    lines: Vec<Vec<char>>,
+
+    /// The position of the cursor, as a line and a character within it.
+    row: usize,
+    column: usize,
+
+    /// The first line and column on the screen.
+    top: usize,
+    left: usize,
+
+    modified: bool,
+
+    /// Set when closing was refused because of unsaved changes, so closing
+    /// again discards them.
+    discard: bool,
+
+    /// Shown in the status line until the next key press.
+    message: Option<String>,
+}
+
+impl Editor {
+    fn load(name: &str) -> Result<Self, EditorError> {
+        let mut volume = FatVolume::mount_first()?;
+
+        let (mut lines, message): (Vec<Vec<char>>, _) = match volume.open(name) {
+            Ok(file) => {
+                if file.size() > MAX_FILE_SIZE {
+                    return Err(EditorError::TooLarge);
+                }
+
+                let mut buffer = vec![0; file.size() as usize];
+                let length = volume.read(&file, 0, &mut buffer)?;
+                buffer.truncate(length);
+                let text = String::from_utf8(buffer).map_err(|_| EditorError::NotText)?;
+                (text.lines().map(|line| line.chars().collect()).collect(), None)
+            }
+            Err(FatError::NotFound) => (Vec::new(), Some("New file".into())),
+            Err(e) => return Err(e.into()),
+        };
+
+        // There is always a line for the cursor to be on.
+        if lines.is_empty() {
+            lines.push(Vec::new());
+        }
+
+        Ok(Self {
+            volume,
+            name: name.into(),
+            lines,
+            row: 0,
+            column: 0,
+            top: 0,
+            left: 0,
+            modified: false,
+            discard: false,
+            message,
+        })
+    }
+
+    fn save(&mut self) -> Result<usize, FatError> {
+        let mut text = String::new();
+        if self.lines.iter().any(|line| !line.is_empty()) || self.lines.len() > 1 {
+            for line in &self.lines {
+                text.extend(line);
+                text.push('\n');
+            }
+        }
+
+        let mut file = match self.volume.open(&self.name) {
+            Err(FatError::NotFound) => self.volume.create(&self.name)?,
+            file => file?,
+        };
+        self.volume.write(&mut file, 0, text.as_bytes())?;
+        self.volume.truncate(&mut file, text.len() as u32)?;
+        self.volume.sync()?;
+        Ok(text.len())
+    }
+
+    /// Handle a key press, returning `false` when the editor closes.
+    fn handle(&mut self, press: KeyPress) -> bool {
+        // Pressing Ctrl before the Q mustn't reset the confirmation.
+        if press.decoded.is_none() {
+            return true;
+        }
+
+        self.message = None;
+        let discard = core::mem::take(&mut self.discard);
+
+        if press.is_ctrl(KeyCode::S) {
+            self.message = Some(match self.save() {
+                Ok(length) => {
+                    self.modified = false;
+                    format!("Saved {length} bytes")
+                }
+                Err(e) => format!("Failed to save: {e:?}"),
+            });
+            return true;
+        }
+
+        if press.is_ctrl(KeyCode::Q) || press.code == KeyCode::Escape {
+            if !self.modified || discard {
+                return false;
+            }
+
+            self.discard = true;
+            self.message = Some("Unsaved changes, press again to discard them".into());
+            return true;
+        }
+
+        let page = self.text_rows().max(1);
+        match press.code {
+            KeyCode::ArrowUp => self.row = self.row.saturating_sub(1),
+            KeyCode::ArrowDown => self.row = (self.row + 1).min(self.lines.len() - 1),
+            KeyCode::PageUp => self.row = self.row.saturating_sub(page),
+            KeyCode::PageDown => self.row = (self.row + page).min(self.lines.len() - 1),
+            KeyCode::Home => self.column = 0,
+            KeyCode::End => self.column = self.lines[self.row].len(),
+            KeyCode::ArrowLeft => self.move_left(),
+            KeyCode::ArrowRight => self.move_right(),
+            KeyCode::Backspace => {
+                if self.column > 0 || self.row > 0 {
+                    self.move_left();
+                    self.delete();
+                }
+            }
+            KeyCode::Delete => self.delete(),
+            KeyCode::Return | KeyCode::NumpadEnter => self.split_line(),
+            KeyCode::Tab => {
+                for _ in 0..TAB_WIDTH - self.column % TAB_WIDTH {
+                    self.insert(' ');
+                }
+            }
+            _ if press.modifiers.ctrl => (),
+            _ => match press.decoded {
+                Some(DecodedKey::Unicode(character)) if !character.is_control() => self.insert(character),
+                _ => (),
+            },
+        }
+
+        self.column = self.column.min(self.lines[self.row].len());
+        true
+    }
+
+    fn move_left(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.column = self.lines[self.row].len();
+        }
+    }
+
+    fn move_right(&mut self) {
+        if self.column < self.lines[self.row].len() {
+            self.column += 1;
+        } else if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.column = 0;
+        }
+    }
+
+    fn insert(&mut self, character: char) {
+        self.lines[self.row].insert(self.column, character);
+        self.column += 1;
+        self.modified = true;
+    }
+
+    /// Remove the character under the cursor, which joins the next line at
+    /// the end of the line.
+    fn delete(&mut self) {
+        if self.column < self.lines[self.row].len() {
+            self.lines[self.row].remove(self.column);
+        } else if self.row + 1 < self.lines.len() {
+            let next = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(next);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+
+    fn split_line(&mut self) {
+        let rest = self.lines[self.row].split_off(self.column);
+        self.lines.insert(self.row + 1, rest);
+        self.row += 1;
+        self.column = 0;
+        self.modified = true;
+    }
+
+    /// The number of lines of text on the screen, which is a line less than
+    /// fits, for the status line.
+    fn text_rows(&self) -> usize {
+        let writer = WRITER.lock();
+        let line_height = writer.text_height() + 2;
+        (writer.height().saturating_sub(PADDING * 2) / line_height).saturating_sub(1)
+    }
+
+    /// Scroll so the cursor is on the screen.
+    fn scroll(&mut self, rows: usize, columns: usize) {
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + rows {
+            self.top = self.row + 1 - rows;
+        }
+
+        if self.column < self.left {
+            self.left = self.column;
+        } else if self.column >= self.left + columns {
+            self.left = self.column + 1 - columns;
+        }
+    }
+
+    fn draw(&mut self, writer: &mut Writer) {
+        if !writer.is_available() {
+            return;
+        }
+
+        let line_height = writer.text_height() + 2;
+        let char_width = writer.text_width(" ").max(1);
+        let rows = (writer.height().saturating_sub(PADDING * 2) / line_height).saturating_sub(1).max(1);
+        let columns = (writer.width().saturating_sub(PADDING * 2) / char_width).max(1);
+        self.scroll(rows, columns);
+
+        let mut text = String::with_capacity(columns);
+        for screen_row in 0..rows {
+            let y = PADDING + screen_row * line_height;
+            writer.fill_rect(0, y, writer.width(), line_height, Color::Black);
+
+            let Some(line) = self.lines.get(self.top + screen_row) else {
+                continue;
+            };
+
+            text.clear();
+            text.extend(line.iter().skip(self.left).take(columns));
+            writer.draw_str_at(PADDING, y, &text, Color::White);
+        }
+
+        // The cursor is the character under it, inverted.
+        let x = PADDING + (self.column - self.left) * char_width;
+        let y = PADDING + (self.row - self.top) * line_height;
+        let under = self.lines[self.row].get(self.column).copied().unwrap_or(' ');
+        writer.fill_rect(x, y, char_width, line_height, Color::LightGray);
+        writer.draw_str_at(x, y, under.encode_utf8(&mut [0; 4]), Color::Black);
+
+        let y = PADDING + rows * line_height;
+        let status = match &self.message {
+            Some(message) => format!(" {}  {message}", self.name),
+            None => format!(
+                " {}{}  line {}/{}, column {}  ^S save  ^Q close",
+                self.name,
+                if self.modified { " [modified]" } else { "" },
+                self.row + 1,
+                self.lines.len(),
+                self.column + 1,
+            ),
+        };
+        writer.fill_rect(0, y, writer.width(), writer.height().saturating_sub(y), Color::DarkGray);
+        writer.draw_str_at(PADDING, y, &status, Color::White);
+    }
+}
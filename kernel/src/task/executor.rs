@@ -1,25 +1,50 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{cancel, inspector::{self, TaskStatistics}, scheduler, Affinity, Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use alloc::task::Wake;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use core::task::Waker;
+use core::time::Duration;
 use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll};
-use crate::serial_println;
+use log::{trace, warn};
+use crate::{arch, meta::{cpu_usage::{self, Interval}, BootParameters, ShutdownKind, System}, serial_println};
+
+/// How long the tasks get to stop after the shutdown token is cancelled,
+/// before they're dropped anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The [`ShutdownKind`] asked for by [`stop`], or zero.
+static STOP: AtomicU8 = AtomicU8::new(0);
+
+/// Set once the tasks are dropped, after which waking them does nothing.
+static STOPPED: AtomicBool = AtomicBool::new(false);
 
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    statistics: Arc<TaskStatistics>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, statistics: Arc<TaskStatistics>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            statistics,
         }))
     }
 
     fn wake_task(&self) {
+        // An interrupt handler might still hold the waker of a dropped task.
+        if STOPPED.load(Ordering::Acquire) {
+            return;
+        }
+
+        self.statistics.mark_ready();
         self.task_queue.push(self.task_id).expect("task_queue full");
     }
 }
@@ -34,51 +59,115 @@ impl Wake for TaskWaker {
     }
 }
 
+/// The executor runs a worker loop on every CPU, up to the `executor_threads`
+/// boot parameter, each with a run queue of its own. A task is woken onto the
+/// queue of the CPU it was spawned on (or the boot CPU if it is pinned there),
+/// and an idle worker steals the unpinned tasks of the others.
+///
+/// The executor runs on the boot thread of the [`scheduler`], and yields to
+/// the other kernel threads when it runs out of ready tasks.
+///
+/// When the machine shuts down (see [`stop`]), the tasks are cancelled and
+/// polled until they're gone, after which the cleanups of the tasks run and
+/// the executor hands over to [`System::shut_down`].
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+
+    /// The cleanups of the spawned tasks, with the name of the task.
+    cleanups: Vec<(&'static str, fn())>,
+
+    /// The run queue of every worker, indexed by CPU.
+    queues: Vec<Arc<ArrayQueue<TaskId>>>,
     waker_cache: BTreeMap<TaskId, Waker>,
 }
 
 impl Executor {
     pub fn new() -> Self {
+        let threads = worker_threads();
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            cleanups: Vec::new(),
+            queues: (0..threads).map(|_| Arc::new(ArrayQueue::new(100))).collect(),
             waker_cache: BTreeMap::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let queue = self.home_queue(task.affinity);
+        inspector::register(task_id, task.name, task.statistics.clone());
+        if let Some(cleanup) = task.cleanup {
+            self.cleanups.push((task.name, cleanup));
+        }
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        queue.push(task_id).expect("queue full");
+    }
+
+    /// The queue a task is woken onto.
+    fn home_queue(&self, affinity: Affinity) -> Arc<ArrayQueue<TaskId>> {
+        let worker = match affinity {
+            Affinity::Any => arch::cpu_index().min(self.queues.len() - 1),
+            Affinity::BootCpu => 0,
+        };
+        self.queues[worker].clone()
+    }
+
+    /// Take a task from the queue of another worker, which must be unpinned.
+    fn steal(&self, worker: usize) -> Option<TaskId> {
+        for (victim, queue) in self.queues.iter().enumerate().filter(|(victim, _)| *victim != worker) {
+            let Some(task_id) = queue.pop() else {
+                continue;
+            };
+
+            match self.tasks.get(&task_id) {
+                Some(task) if task.affinity == Affinity::BootCpu && worker != 0 => {
+                    self.queues[victim].push(task_id).expect("queue full");
+                }
+                _ => return Some(task_id),
+            }
+        }
+        None
+    }
+
+    fn next_ready_task(&self, worker: usize) -> Option<TaskId> {
+        self.queues[worker].pop().or_else(|| self.steal(worker))
     }
 
     fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+        let worker = arch::cpu_index().min(self.queues.len() - 1);
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
+        while let Some(task_id) = self.next_ready_task(worker) {
+            let affinity = match self.tasks.get(&task_id) {
+                Some(task) => task.affinity,
                 None => continue, // task no longer exists
             };
+            let home_queue = self.home_queue(affinity);
+
+            // destructure `self` to avoid borrow checker errors
+            let Self {
+                tasks,
+                waker_cache,
+                ..
+            } = self;
+
+            let task = tasks.get_mut(&task_id).expect("task should exist");
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task_id, home_queue, task.statistics.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+
+            let start = task.statistics.begin_poll();
+            let result = task.poll(&mut context);
+            task.statistics.end_poll(start);
+
+            match result {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    inspector::unregister(task_id);
                 }
                 Poll::Pending => {}
             }
@@ -86,20 +175,152 @@ impl Executor {
     }
 
     pub fn run(&mut self) -> ! {
+        RUNNING.store(true, Ordering::Release);
+
         loop {
             self.run_ready_tasks();
+
+            if let Some(kind) = ShutdownKind::from_u8(STOP.load(Ordering::Acquire)) {
+                self.stop_tasks();
+                System::shut_down(kind);
+                crate::hlt_loop();
+            }
+
+            crate::device::clocksource::check_stability();
+
+            // The kernel threads get the rest of the time slice, before we
+            // halt until the next interrupt.
+            if !scheduler::yield_now() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    /// Poll the cancelled tasks until they're gone or the timeout expires,
+    /// drop the stragglers, and run the cleanups.
+    fn stop_tasks(&mut self) {
+        trace!("Stopping {} task(s)", self.tasks.len());
+
+        let timeout = STOP_TIMEOUT.as_millis() as usize * arch::TICKS_PER_SECOND / 1000;
+        let deadline = arch::ticks() + timeout;
+        while !self.tasks.is_empty() && arch::ticks() < deadline {
+            self.run_ready_tasks();
             self.sleep_if_idle();
         }
+
+        for (task_id, task) in core::mem::take(&mut self.tasks) {
+            warn!("Task `{}` didn't stop within {STOP_TIMEOUT:?}, dropping it", task.name);
+            inspector::unregister(task_id);
+        }
+        self.waker_cache.clear();
+        STOPPED.store(true, Ordering::Release);
+
+        for (name, cleanup) in core::mem::take(&mut self.cleanups) {
+            trace!("Running the cleanup of task `{name}`");
+            cleanup();
+        }
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        // The serial interrupt might not be routed to us, so make sure the
+        // output is written before we go to sleep.
+        arch::serial::flush();
+
+        arch::disable_interrupts();
+        if self.queues.iter().all(|queue| queue.is_empty()) {
+            let idle = Interval::start();
+            arch::wait_for_interrupt();
+            cpu_usage::add_idle(idle);
+        } else {
+            arch::enable_interrupts();
+        }
+    }
+}
+
+/// Stop the executor for the shutdown or reboot: the tasks are cancelled, and
+/// once they're gone, the executor carries out the shutdown. Returns `false`
+/// when the executor isn't running, so the caller should shut down itself.
+pub fn stop(kind: ShutdownKind) -> bool {
+    if !RUNNING.load(Ordering::Acquire) {
+        return false;
+    }
+
+    // The first request wins.
+    if STOP.compare_exchange(0, kind as u8, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        cancel::shutdown().cancel();
+    }
+    true
+}
+
+/// The number of worker loops to run, which is one per CPU unless the
+/// `executor_threads` boot parameter asks for fewer.
+fn worker_threads() -> usize {
+    let cpus = arch::cpu_count();
+    let Some(threads) = BootParameters::get("executor_threads") else {
+        return cpus;
+    };
+
+    match threads.parse::<usize>() {
+        Ok(0) | Err(_) => {
+            log::warn!("Invalid executor_threads `{threads}`, expected a number of threads");
+            cpus
+        }
+        Ok(threads) if threads > cpus => {
+            log::warn!("Only {cpus} CPU(s) available, running {cpus} executor thread(s) instead of {threads}");
+            cpus
+        }
+        Ok(threads) => threads,
+    }
+}
+
+/// Wakes [`block_on`], which might happen from an interrupt handler, so it only
+/// sets a flag.
+struct BlockOnWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
+}
+
+/// Run a future to completion on the current CPU, halting until an interrupt
+/// wakes it up. This is meant for the initialization code, which runs before
+/// the executor, to wait for asynchronous drivers instead of busy-waiting.
+///
+/// Interrupts are enabled while waiting, and restored to their previous state
+/// afterwards. Calling this from a task blocks the executor as a whole, so
+/// tasks should `.await` instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let interrupts_were_enabled = arch::are_interrupts_enabled();
+    let mut future = pin!(future);
+
+    let state = Arc::new(BlockOnWaker { woken: AtomicBool::new(true) });
+    let waker = Waker::from(state.clone());
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if state.woken.swap(false, Ordering::Acquire) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                if !interrupts_were_enabled {
+                    arch::disable_interrupts();
+                }
+                return output;
+            }
+        }
 
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+        // Check the flag with interrupts disabled, so a wake-up arriving right
+        // before halting isn't missed.
+        arch::disable_interrupts();
+        if state.woken.load(Ordering::Acquire) {
+            arch::enable_interrupts();
         } else {
-            interrupts::enable();
+            arch::wait_for_interrupt();
         }
     }
 }
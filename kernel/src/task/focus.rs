@@ -0,0 +1,284 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Routes the keyboard input to the consumer that has the focus, so the shell
+//! doesn't see the keys meant for the task inspector, and vice versa.
+//!
+//! The `input` task is the only reader of the scancodes: it decodes them with
+//! a single [`KeyStream`], so the modifier and lock key state stays consistent
+//! across focus changes, and pushes every key press into the queue of the
+//! focused [`Consumer`]. Consumers read their queue through an [`Input`].
+//!
+//! The focus moves with [`switch`], and with these hotkeys:
+//!
+//! | Hotkey    | Action                                                             |
+//! |-----------|--------------------------------------------------------------------|
+//! | `F12`     | Open or close the task inspector, which has the focus while open   |
+//! | `Alt+Tab` | Cycle through the consumers with an open [`Input`]                 |
+//!
+//! Ctrl+Alt+Del never reaches a consumer either, see [`reboot`].
+//!
+//! When the console is routed to the serial port, the bytes it receives are
+//! turned into key presses as well, see [`serial_input`](super::serial_input).
+//!
+//! F12 is detected by the keyboard interrupt handler (see
+//! [`inspector`](super::inspector)), which is why the focus is an atomic and
+//! [`switch`] doesn't block. There is no mouse driver yet; its events should
+//! be routed the same way once there is.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::info;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::meta::counters::Counter;
+
+use super::{keyboard::{KeyPress, KeyStream}, reboot};
+
+/// The key presses a consumer can fall behind by.
+const QUEUE_CAPACITY: usize = 64;
+
+static DROPPED: Counter = Counter::new("input.dropped", "Key presses dropped because the focused consumer wasn't reading");
+
+/// Something that takes keyboard input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Consumer {
+    Shell = 0,
+
+    /// The task inspector overlay, which only has the focus while it's open.
+    Inspector = 1,
+
+    /// The editor, which only has the focus while a file is open.
+    Editor = 2,
+
+    /// The processes, which take the focus by reading the console (see
+    /// [`syscall`](crate::syscall)).
+    Process = 3,
+}
+
+impl Consumer {
+    pub const ALL: [Self; 4] = [Self::Shell, Self::Inspector, Self::Editor, Self::Process];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Shell => "shell",
+            Self::Inspector => "inspector",
+            Self::Editor => "editor",
+            Self::Process => "process",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|consumer| consumer.name() == name)
+    }
+
+    /// Whether the consumer is an overlay, which takes the focus by its own
+    /// hotkey and is skipped by `Alt+Tab`.
+    #[must_use]
+    pub const fn is_overlay(&self) -> bool {
+        matches!(self, Self::Inspector)
+    }
+
+    /// Whether the consumer draws over the whole screen while it has the
+    /// focus, which `Alt+Tab` doesn't move away from, since the others
+    /// wouldn't be visible.
+    #[must_use]
+    pub const fn is_full_screen(&self) -> bool {
+        matches!(self, Self::Editor)
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Inspector,
+            2 => Self::Editor,
+            3 => Self::Process,
+            _ => Self::Shell,
+        }
+    }
+
+    fn slot(&self) -> &'static Slot {
+        &SLOTS[*self as usize]
+    }
+}
+
+struct Slot {
+    queue: OnceCell<ArrayQueue<KeyPress>>,
+    waker: AtomicWaker,
+
+    /// Whether an [`Input`] of this consumer exists.
+    open: AtomicBool,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            open: AtomicBool::new(false),
+        }
+    }
+}
+
+static SLOTS: [Slot; Consumer::ALL.len()] = [Slot::new(), Slot::new(), Slot::new(), Slot::new()];
+
+static FOCUS: AtomicU8 = AtomicU8::new(Consumer::Shell as u8);
+
+/// The consumer that had the focus before the last [`switch`], which
+/// [`restore`] returns to.
+static PREVIOUS: AtomicU8 = AtomicU8::new(Consumer::Shell as u8);
+
+#[must_use]
+pub fn current() -> Consumer {
+    Consumer::from_u8(FOCUS.load(Ordering::Relaxed))
+}
+
+/// Give the focus to the consumer. Key presses already queued for the
+/// previous consumer stay there.
+///
+/// Must not block or allocate.
+pub fn switch(to: Consumer) {
+    let from = FOCUS.swap(to as u8, Ordering::Relaxed);
+    if from != to as u8 {
+        PREVIOUS.store(from, Ordering::Relaxed);
+    }
+}
+
+/// Give the focus back to the consumer that had it before the last
+/// [`switch`], e.g. when an overlay closes.
+///
+/// Must not block or allocate.
+pub fn restore() {
+    let previous = PREVIOUS.load(Ordering::Relaxed);
+    FOCUS.store(previous, Ordering::Relaxed);
+}
+
+/// Whether the consumer has an [`Input`] to read its key presses with.
+#[must_use]
+pub fn is_open(consumer: Consumer) -> bool {
+    consumer.slot().open.load(Ordering::Relaxed)
+}
+
+/// The key presses routed to one consumer.
+pub struct Input {
+    consumer: Consumer,
+}
+
+impl Input {
+    /// Start receiving the key presses of the consumer. There can only be one
+    /// [`Input`] per consumer at a time.
+    pub fn new(consumer: Consumer) -> Self {
+        let slot = consumer.slot();
+        slot.queue.init_once(|| ArrayQueue::new(QUEUE_CAPACITY));
+
+        let was_open = slot.open.swap(true, Ordering::Relaxed);
+        assert!(!was_open, "the input of the {} is already open", consumer.name());
+
+        Self { consumer }
+    }
+
+    /// Wait for the next key press while this consumer has the focus.
+    pub async fn next_event(&mut self) -> Option<KeyPress> {
+        let slot = self.consumer.slot();
+        Some(poll_fn(|cx| poll_queue(slot, cx)).await)
+    }
+
+    /// Take the next key press without waiting, for the threads that can't
+    /// await one.
+    pub fn try_next_event(&mut self) -> Option<KeyPress> {
+        self.consumer.slot().queue.try_get().ok()?.pop()
+    }
+
+    /// Wait for the next key press that the layout translates.
+    #[allow(dead_code)] // For consumers that only take text.
+    pub async fn next_key(&mut self) -> Option<DecodedKey> {
+        loop {
+            if let Some(key) = self.next_event().await?.decoded {
+                return Some(key);
+            }
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        self.consumer.slot().open.store(false, Ordering::Relaxed);
+    }
+}
+
+fn poll_queue(slot: &Slot, cx: &mut Context) -> Poll<KeyPress> {
+    let queue = slot.queue.try_get().expect("input queue not initialized");
+
+    if let Some(press) = queue.pop() {
+        return Poll::Ready(press);
+    }
+
+    slot.waker.register(cx.waker());
+    match queue.pop() {
+        Some(press) => {
+            slot.waker.take();
+            Poll::Ready(press)
+        }
+        None => Poll::Pending,
+    }
+}
+
+/// The task that decodes the keyboard input and routes it to the focused
+/// consumer.
+pub async fn run() {
+    DROPPED.register();
+    let mut keys = KeyStream::new();
+
+    while let Some(press) = keys.next_event().await {
+        if press.modifiers.alt && press.code == KeyCode::Tab {
+            cycle();
+            continue;
+        }
+
+        if reboot::is_ctrl_alt_del(&press) {
+            reboot::handle_ctrl_alt_del();
+            continue;
+        }
+
+        deliver(press);
+    }
+}
+
+/// Push the key press into the queue of the focused consumer, e.g. for the
+/// key presses received on the serial port (see
+/// [`serial_input`](super::serial_input)).
+pub(crate) fn deliver(press: KeyPress) {
+    let slot = current().slot();
+    match slot.queue.try_get() {
+        Ok(queue) if slot.open.load(Ordering::Relaxed) && queue.push(press).is_ok() => slot.waker.wake(),
+        _ => DROPPED.increment(),
+    }
+}
+
+/// Move the focus to the next consumer that has an [`Input`], skipping the
+/// overlays.
+fn cycle() {
+    if current().is_full_screen() {
+        return;
+    }
+
+    let start = current() as usize;
+    let next = (1..=Consumer::ALL.len())
+        .map(|offset| Consumer::ALL[(start + offset) % Consumer::ALL.len()])
+        .find(|consumer| !consumer.is_overlay() && is_open(*consumer));
+
+    if let Some(next) = next {
+        if next != current() {
+            info!("Input focus moved to the {}", next.name());
+        }
+        switch(next);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! An overlay listing the tasks of the executor, toggled with F12.
+//!
+//! The hotkey is detected by the keyboard interrupt handler itself and the
+//! overlay is drawn from there as well, so it keeps working when a task hangs
+//! and the executor no longer gets to decode keyboard input. Everything this
+//! module touches from the interrupt handler is therefore either atomic or
+//! acquired with `try_lock`, and nothing is allocated.
+//!
+//! While the overlay is open it has the input focus (see
+//! [`focus`](super::focus)), so the keys typed meanwhile don't end up in the
+//! shell.
+//!
+//! Since the framebuffer isn't double buffered, closing the overlay clears
+//! the area it was drawn on.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{fmt::Write, sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering}};
+
+use spin::Mutex;
+
+use pc_keyboard::KeyCode;
+
+use crate::{arch, device::ps2::ScancodeSet, meta::cpu_usage::Interval, vga_text_buffer::{Color, Writer, WRITER}};
+
+use super::{focus::{self, Consumer, Input}, TaskId};
+
+/// The make code of F12 in scancode set 1 and 2.
+const HOTKEY_SET1: u8 = 0x58;
+const HOTKEY_SET2: u8 = 0x07;
+
+/// Precedes the make code of a released key in scancode set 2.
+const SET2_BREAK_PREFIX: u8 = 0xF0;
+
+/// Whether the previous scancode was [`SET2_BREAK_PREFIX`].
+static AFTER_BREAK_PREFIX: AtomicBool = AtomicBool::new(false);
+
+const MAX_ROWS: usize = 24;
+const LINE_CAPACITY: usize = 64;
+const PADDING: usize = 8;
+
+static REGISTRY: Mutex<Vec<(TaskId, &'static str, Arc<TaskStatistics>)>> = Mutex::new(Vec::new());
+static VISIBLE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Waiting to be woken up.
+    Pending = 0,
+
+    /// Woken up and waiting in the queue of the executor.
+    Ready = 1,
+
+    /// Currently being polled.
+    Running = 2,
+}
+
+impl TaskState {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Ready => "ready",
+            Self::Running => "running",
+        }
+    }
+}
+
+/// Counters the executor maintains for every task. These are atomics, since
+/// they are updated by wakers, which might run in interrupt context.
+#[derive(Debug)]
+pub struct TaskStatistics {
+    state: AtomicU8,
+    polls: AtomicU64,
+    last_poll_cycles: AtomicU64,
+    total_cycles: AtomicU64,
+}
+
+impl TaskStatistics {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            last_poll_cycles: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> TaskState {
+        match self.state.load(Ordering::Relaxed) {
+            0 => TaskState::Pending,
+            1 => TaskState::Ready,
+            _ => TaskState::Running,
+        }
+    }
+
+    pub fn polls(&self) -> u64 {
+        self.polls.load(Ordering::Relaxed)
+    }
+
+    /// The duration of the last poll in [`arch::cycles`], without the
+    /// interrupt handlers that ran meanwhile.
+    pub fn last_poll_cycles(&self) -> u64 {
+        self.last_poll_cycles.load(Ordering::Relaxed)
+    }
+
+    /// The duration of all polls so far, like [`Self::last_poll_cycles`].
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn mark_ready(&self) {
+        self.state.store(TaskState::Ready as u8, Ordering::Relaxed);
+    }
+
+    /// Called by the executor right before polling the task. The returned
+    /// value should be passed to [`Self::end_poll`].
+    pub(super) fn begin_poll(&self) -> Interval {
+        self.state.store(TaskState::Running as u8, Ordering::Relaxed);
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        Interval::start()
+    }
+
+    pub(super) fn end_poll(&self, poll: Interval) {
+        let cycles = poll.elapsed();
+        self.last_poll_cycles.store(cycles, Ordering::Relaxed);
+        self.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+
+        // If the task woke itself up during the poll, it is ready already.
+        _ = self.state.compare_exchange(
+            TaskState::Running as u8,
+            TaskState::Pending as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+pub(super) fn register(id: TaskId, name: &'static str, statistics: Arc<TaskStatistics>) {
+    arch::without_interrupts(|| {
+        REGISTRY.lock().push((id, name, statistics));
+    });
+}
+
+/// The ID, name and [`TaskStatistics::total_cycles`] of every task.
+pub fn task_cycles() -> Vec<(u64, &'static str, u64)> {
+    arch::without_interrupts(|| {
+        REGISTRY.lock().iter()
+            .map(|(id, name, statistics)| (id.0, *name, statistics.total_cycles()))
+            .collect()
+    })
+}
+
+pub(super) fn unregister(id: TaskId) {
+    arch::without_interrupts(|| {
+        REGISTRY.lock().retain(|(task_id, _, _)| *task_id != id);
+    });
+}
+
+/// Called by the keyboard interrupt handler for every scancode. Returns
+/// whether the scancode was the hotkey, in which case it shouldn't be passed
+/// on to the tasks.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_scancode(scancode: u8, set: ScancodeSet) -> bool {
+    let hotkey = match set {
+        ScancodeSet::Set1 => HOTKEY_SET1,
+        ScancodeSet::Set2 => {
+            // Releasing F12 is `F0 07`, which shouldn't toggle again.
+            if AFTER_BREAK_PREFIX.swap(scancode == SET2_BREAK_PREFIX, Ordering::Relaxed) {
+                return false;
+            }
+            HOTKEY_SET2
+        }
+    };
+
+    if scancode != hotkey {
+        return false;
+    }
+
+    let visible = !VISIBLE.load(Ordering::Relaxed);
+    VISIBLE.store(visible, Ordering::Relaxed);
+    if visible {
+        focus::switch(Consumer::Inspector);
+    } else {
+        focus::restore();
+    }
+
+    // The lock is held when the interrupt arrived in the middle of printing,
+    // so just skip this time, the user can press the hotkey again.
+    if let Some(mut writer) = WRITER.try_lock() {
+        draw(&mut writer, visible);
+    }
+    true
+}
+
+/// The task that takes the keyboard input while the overlay has the focus,
+/// which closes it with Escape.
+pub async fn run() {
+    let mut input = Input::new(Consumer::Inspector);
+
+    while let Some(press) = input.next_event().await {
+        if press.code == KeyCode::Escape && VISIBLE.swap(false, Ordering::Relaxed) {
+            focus::restore();
+            draw(&mut WRITER.lock(), false);
+        }
+    }
+}
+
+pub fn is_visible() -> bool {
+    VISIBLE.load(Ordering::Relaxed)
+}
+
+/// Draw the overlay again if it's open, after the framebuffer was
+/// reconfigured.
+pub fn redraw() {
+    if VISIBLE.load(Ordering::Relaxed) {
+        draw(&mut WRITER.lock(), true);
+    }
+}
+
+/// Draw the overlay, or clear its area when it's closed.
+fn draw(writer: &mut Writer, visible: bool) {
+    if !writer.is_available() {
+        return;
+    }
+
+    let line_height = writer.text_height() + 2;
+    let width = writer.text_width(" ") * LINE_CAPACITY + PADDING * 2;
+    let height = line_height * (MAX_ROWS + 2) + PADDING * 2;
+    let x = writer.width().saturating_sub(width + PADDING);
+    let y = PADDING;
+
+    if !visible {
+        writer.fill_rect(x, y, width, height, Color::Black);
+        return;
+    }
+
+    writer.fill_rect(x, y, width, height, Color::Blue);
+
+    let mut line = Line::new();
+    _ = write!(line, "Tasks (F12 or Esc to close)");
+    writer.draw_str_at(x + PADDING, y + PADDING, line.as_str(), Color::Yellow);
+
+    line.clear();
+    _ = write!(line, "{:>4} {:<20} {:<8} {:>10} {:>12}", "ID", "NAME", "STATE", "POLLS", "LAST (kcyc)");
+    writer.draw_str_at(x + PADDING, y + PADDING + line_height, line.as_str(), Color::White);
+
+    let Some(registry) = REGISTRY.try_lock() else {
+        writer.draw_str_at(x + PADDING, y + PADDING + line_height * 2, "<registry busy>", Color::LightRed);
+        return;
+    };
+
+    for (row, (id, name, statistics)) in registry.iter().take(MAX_ROWS).enumerate() {
+        line.clear();
+        _ = write!(
+            line,
+            "{:>4} {:<20.20} {:<8} {:>10} {:>12}",
+            id.0,
+            name,
+            statistics.state().name(),
+            statistics.polls(),
+            statistics.last_poll_cycles() / 1000,
+        );
+
+        let color = match statistics.state() {
+            TaskState::Running => Color::LightRed,
+            _ => Color::White,
+        };
+        writer.draw_str_at(x + PADDING, y + PADDING + line_height * (row + 2), line.as_str(), color);
+    }
+}
+
+/// A fixed-size line buffer, since the overlay is drawn from interrupt
+/// context and can't allocate.
+struct Line {
+    data: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self { data: [0; LINE_CAPACITY], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Truncate instead of failing, a cut-off line is still useful.
+        for c in s.chars() {
+            let mut buf = [0; 4];
+            let encoded = c.encode_utf8(&mut buf);
+            if self.len + encoded.len() > LINE_CAPACITY {
+                return Ok(());
+            }
+
+            self.data[self.len..self.len + encoded.len()].copy_from_slice(encoded.as_bytes());
+            self.len += encoded.len();
+        }
+
+        Ok(())
+    }
+}
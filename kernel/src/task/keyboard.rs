@@ -5,8 +5,10 @@ use futures_util::stream::Stream;
 
 use futures_util::stream::StreamExt;
 use log::warn;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use crate::{meta::Console, print};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1, ScancodeSet2};
+use crate::{device::ps2::{self, Leds, ScancodeSet}, meta::init::{self, Subsystem}};
+
+use super::keymap::{self, ActiveKeymap};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 use futures_util::task::AtomicWaker;
@@ -19,8 +21,9 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+        init::mark(Subsystem::ScancodeQueue);
+        init::require(Subsystem::Heap, "ScancodeStream::new");
+        SCANCODE_QUEUE.init_once(|| ArrayQueue::new(100));
         ScancodeStream { _private: () }
     }
 }
@@ -49,9 +52,8 @@ impl Stream for ScancodeStream {
     }
 }
 
-/// Called by the keyboard interrupt handler
-///
-/// Must not block or allocate.
+/// Called by the PS/2 driver task with the bytes that aren't responses to its
+/// commands.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
@@ -75,23 +77,158 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
     */
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::<layouts::Us104Key, ScancodeSet1>::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode('\u{0008}') => Console::backspace(),
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+/// The modifier keys held down during a [`KeyPress`], without distinguishing
+/// left and right.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+/// A key being pressed, including the keys without a character, such as the
+/// navigation and multimedia keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub code: KeyCode,
+
+    /// The key as translated by the layout, `None` for keys that only change
+    /// the state of the decoder, such as the modifiers.
+    pub decoded: Option<DecodedKey>,
+    pub modifiers: Modifiers,
+}
+
+impl KeyPress {
+    /// Whether Ctrl is held down together with the given key.
+    pub fn is_ctrl(&self, code: KeyCode) -> bool {
+        self.modifiers.ctrl && self.code == code
+    }
+
+    /// The keys of the extended block and the arrows, which have no character.
+    pub fn is_navigation(&self) -> bool {
+        matches!(self.code,
+            KeyCode::Insert | KeyCode::Delete | KeyCode::Home | KeyCode::End |
+            KeyCode::PageUp | KeyCode::PageDown | KeyCode::ArrowUp |
+            KeyCode::ArrowDown | KeyCode::ArrowLeft | KeyCode::ArrowRight)
+    }
+
+    /// The multimedia keys, which only exist as extended scancodes.
+    pub fn is_multimedia(&self) -> bool {
+        matches!(self.code,
+            KeyCode::PrevTrack | KeyCode::NextTrack | KeyCode::Play | KeyCode::Stop |
+            KeyCode::Mute | KeyCode::VolumeDown | KeyCode::VolumeUp |
+            KeyCode::Calculator | KeyCode::WWWHome)
+    }
+}
+
+/// The name of the layout the key presses are translated with, see
+/// [`keymap`](super::keymap).
+pub fn layout_name() -> &'static str {
+    keymap::layout().name()
+}
+
+/// The decoder for the scancode set negotiated by the PS/2 driver.
+enum Decoder {
+    Set1(Keyboard<ActiveKeymap, ScancodeSet1>),
+    Set2(Keyboard<ActiveKeymap, ScancodeSet2>),
+}
+
+impl Decoder {
+    fn new(set: ScancodeSet) -> Self {
+        match set {
+            ScancodeSet::Set1 => Self::Set1(Keyboard::new(ScancodeSet1::new(), ActiveKeymap, HandleControl::Ignore)),
+            ScancodeSet::Set2 => Self::Set2(Keyboard::new(ScancodeSet2::new(), ActiveKeymap, HandleControl::Ignore)),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        match self {
+            Self::Set1(keyboard) => keyboard.add_byte(byte),
+            Self::Set2(keyboard) => keyboard.add_byte(byte),
+        }.ok().flatten()
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Self::Set1(keyboard) => keyboard.process_keyevent(event),
+            Self::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+/// Decodes the scancodes from the keyboard into keys.
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    decoder: Decoder,
+    leds: Leds,
+    modifiers: Modifiers,
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        Self {
+            scancodes: ScancodeStream::new(),
+            decoder: Decoder::new(ScancodeSet::current()),
+            // Matches the initial state of the decoder.
+            leds: Leds {
+                num_lock: true,
+                ..Leds::default()
+            },
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    /// Wait for the next key press. The scancodes of a macro being replayed
+    /// take precedence over the keyboard.
+    pub async fn next_event(&mut self) -> Option<KeyPress> {
+        loop {
+            let scancode = match super::macros::next_replayed() {
+                Some(scancode) => scancode,
+                None => self.scancodes.next().await?,
+            };
+
+            super::macros::record(scancode);
+
+            let Some(key_event) = self.decoder.add_byte(scancode) else {
+                continue;
+            };
+
+            self.update_leds(&key_event);
+            self.update_modifiers(&key_event);
+
+            let code = key_event.code;
+            let is_down = key_event.state == KeyState::Down;
+            let decoded = self.decoder.process_keyevent(key_event);
+            if is_down {
+                return Some(KeyPress { code, decoded, modifiers: self.modifiers });
             }
         }
     }
+
+    fn update_modifiers(&mut self, event: &KeyEvent) {
+        let modifier = match event.code {
+            KeyCode::LShift | KeyCode::RShift => &mut self.modifiers.shift,
+            KeyCode::LControl | KeyCode::RControl | KeyCode::RControl2 => &mut self.modifiers.ctrl,
+            KeyCode::LAlt | KeyCode::RAltGr | KeyCode::RAlt2 => &mut self.modifiers.alt,
+            _ => return,
+        };
+
+        *modifier = event.state == KeyState::Down;
+    }
+
+    /// Keep the LEDs in sync with the lock keys.
+    fn update_leds(&mut self, event: &KeyEvent) {
+        if event.state != KeyState::Down {
+            return;
+        }
+
+        match event.code {
+            KeyCode::CapsLock => self.leds.caps_lock = !self.leds.caps_lock,
+            KeyCode::NumpadLock => self.leds.num_lock = !self.leds.num_lock,
+            KeyCode::ScrollLock => self.leds.scroll_lock = !self.leds.scroll_lock,
+            _ => return,
+        }
+
+        ps2::set_leds(self.leds);
+    }
 }
@@ -0,0 +1,256 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The keyboard layout that the [`KeyStream`] translates keys with, which can
+//! be switched at runtime with [`set_layout`], and is selected at boot by the
+//! `keyboard.layout` configuration entry:
+//!
+//! | Name      | Layout                           |
+//! |-----------|----------------------------------|
+//! | `us`      | US 104-key (default)             |
+//! | `uk`      | UK 105-key                       |
+//! | `de`      | German 105-key (QWERTZ)          |
+//! | `fr`      | French (AZERTY)                  |
+//! | `dvorak`  | US Dvorak                        |
+//! | `colemak` | Colemak                          |
+//! | `jp`      | Japanese 109-key                 |
+//!
+//! On top of the layout, single keys can be remapped to other characters, by
+//! their scancode in set 1 (whatever set the keyboard uses), e.g. `1e=qQ`
+//! makes the A key type `q`, and `Q` with Shift. A third character is typed
+//! with AltGr. The remappings are kept in the `keyboard.remap` configuration
+//! entry as a comma-separated table, such as `1e=qQ,10=aA`.
+//!
+//! [`KeyStream`]: super::keyboard::KeyStream
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use pc_keyboard::{
+    layouts::{AnyLayout, Azerty, Colemak, De105Key, Dvorak104Key, Jis109Key, Uk105Key, Us104Key},
+    DecodedKey,
+    HandleControl,
+    KeyCode,
+    KeyState,
+    KeyboardLayout,
+    Modifiers,
+    ScancodeSet,
+    ScancodeSet1,
+};
+use spin::Mutex;
+
+/// The most keys that can be remapped at once.
+pub const MAX_REMAPS: usize = 64;
+
+static KEYMAP: Mutex<Keymap> = Mutex::new(Keymap {
+    layout: Layout::Us,
+    remaps: Vec::new(),
+});
+
+struct Keymap {
+    layout: Layout,
+    remaps: Vec<Remap>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Fr,
+    Dvorak,
+    Colemak,
+    Jp,
+}
+
+impl Layout {
+    pub const ALL: [Self; 7] = [Self::Us, Self::Uk, Self::De, Self::Fr, Self::Dvorak, Self::Colemak, Self::Jp];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Us => "us",
+            Self::Uk => "uk",
+            Self::De => "de",
+            Self::Fr => "fr",
+            Self::Dvorak => "dvorak",
+            Self::Colemak => "colemak",
+            Self::Jp => "jp",
+        }
+    }
+
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Us => "US 104-key",
+            Self::Uk => "UK 105-key",
+            Self::De => "German 105-key (QWERTZ)",
+            Self::Fr => "French (AZERTY)",
+            Self::Dvorak => "US Dvorak",
+            Self::Colemak => "Colemak",
+            Self::Jp => "Japanese 109-key",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    fn translator(&self) -> AnyLayout {
+        match self {
+            Self::Us => AnyLayout::Us104Key(Us104Key),
+            Self::Uk => AnyLayout::Uk105Key(Uk105Key),
+            Self::De => AnyLayout::De105Key(De105Key),
+            Self::Fr => AnyLayout::Azerty(Azerty),
+            Self::Dvorak => AnyLayout::Dvorak104Key(Dvorak104Key),
+            Self::Colemak => AnyLayout::Colemak(Colemak),
+            Self::Jp => AnyLayout::Jis109Key(Jis109Key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    /// The scancode isn't hexadecimal, or isn't the make code of a key in
+    /// set 1.
+    UnknownScancode,
+
+    /// The remapping doesn't have one to three characters.
+    InvalidCharacters,
+    TooManyRemaps,
+}
+
+impl Display for KeymapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::UnknownScancode => "not the set 1 scancode of a key",
+            Self::InvalidCharacters => "expected one to three characters",
+            Self::TooManyRemaps => "too many keys are remapped",
+        })
+    }
+}
+
+/// The characters a key types instead of those of the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Remap {
+    pub code: KeyCode,
+    pub normal: char,
+
+    /// With Shift, or with Caps Lock if `normal` is a letter.
+    pub shifted: Option<char>,
+    pub alt_gr: Option<char>,
+}
+
+impl Remap {
+    /// Parse a remapping as `<scancode>=<characters>`, e.g. `1e=qQ` or
+    /// `e035=/`.
+    pub fn parse(text: &str) -> Result<Self, KeymapError> {
+        let (scancode, characters) = text.split_once('=').ok_or(KeymapError::InvalidCharacters)?;
+        let code = keycode_of_scancode(scancode.trim()).ok_or(KeymapError::UnknownScancode)?;
+
+        let mut characters = characters.chars();
+        let normal = characters.next().ok_or(KeymapError::InvalidCharacters)?;
+        let shifted = characters.next();
+        let alt_gr = characters.next();
+        if characters.next().is_some() {
+            return Err(KeymapError::InvalidCharacters);
+        }
+
+        Ok(Self { code, normal, shifted, alt_gr })
+    }
+
+    fn character(&self, modifiers: &Modifiers) -> char {
+        if modifiers.alt_gr {
+            if let Some(character) = self.alt_gr {
+                return character;
+            }
+        }
+
+        let shift = modifiers.is_shifted() ^ (modifiers.capslock && self.normal.is_alphabetic());
+        match self.shifted {
+            Some(character) if shift => character,
+            _ => self.normal,
+        }
+    }
+}
+
+impl Display for Remap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?} -> {}", self.code, self.normal)?;
+        for character in [self.shifted, self.alt_gr].into_iter().flatten() {
+            write!(f, " {character}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The layout the decoder of the [`KeyStream`](super::keyboard::KeyStream)
+/// is given, which looks up the current keymap for every key, so switching
+/// takes effect at the next key.
+pub struct ActiveKeymap;
+
+impl KeyboardLayout for ActiveKeymap {
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        let keymap = KEYMAP.lock();
+        match keymap.remaps.iter().find(|remap| remap.code == keycode) {
+            Some(remap) => DecodedKey::Unicode(remap.character(modifiers)),
+            None => keymap.layout.translator().map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+pub fn layout() -> Layout {
+    KEYMAP.lock().layout
+}
+
+pub fn set_layout(layout: Layout) {
+    KEYMAP.lock().layout = layout;
+}
+
+pub fn remaps() -> Vec<Remap> {
+    KEYMAP.lock().remaps.clone()
+}
+
+/// Remap the key, replacing an earlier remapping of it.
+pub fn remap(remap: Remap) -> Result<(), KeymapError> {
+    let mut keymap = KEYMAP.lock();
+    keymap.remaps.retain(|existing| existing.code != remap.code);
+    if keymap.remaps.len() == MAX_REMAPS {
+        return Err(KeymapError::TooManyRemaps);
+    }
+
+    keymap.remaps.push(remap);
+    Ok(())
+}
+
+/// Replace all remappings with those of the table, a comma-separated list
+/// of remappings (see [`Remap::parse`]). Nothing changes when the table is
+/// invalid.
+pub fn set_remaps(table: &str) -> Result<(), KeymapError> {
+    let remaps = table.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(Remap::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if remaps.len() > MAX_REMAPS {
+        return Err(KeymapError::TooManyRemaps);
+    }
+
+    KEYMAP.lock().remaps = remaps;
+    Ok(())
+}
+
+/// The key of a make code in scancode set 1, given in hexadecimal, with the
+/// `e0` prefix for the extended keys.
+fn keycode_of_scancode(scancode: &str) -> Option<KeyCode> {
+    if scancode.is_empty() || scancode.len() % 2 != 0 || scancode.len() > 4 {
+        return None;
+    }
+
+    let mut set = ScancodeSet1::new();
+    let mut event = None;
+    for index in (0..scancode.len()).step_by(2) {
+        let byte = u8::from_str_radix(scancode.get(index..index + 2)?, 16).ok()?;
+        event = set.advance_state(byte).ok()?;
+    }
+
+    event.filter(|event| event.state == KeyState::Down).map(|event| event.code)
+}
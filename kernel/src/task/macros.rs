@@ -0,0 +1,187 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Recording and replaying of keyboard input, so interactive flows such as
+//! shell scenarios can be scripted inside QEMU.
+//!
+//! Macros are recorded as raw scancodes when the [`KeyStream`] consumes them,
+//! and replayed by feeding them back through the same decoder, so a replay
+//! behaves exactly like the original input (including the modifiers). They are
+//! stored in the configuration store as `keyboard.macro.<name>`, with the
+//! scancodes encoded in hexadecimal, so they survive reboots and can be
+//! provided by a prepared configuration disk. Macros recorded in scancode set
+//! 2 are prefixed with `set2:`, since they can't be replayed in set 1.
+//!
+//! When the `keyboard.autoplay` entry names a macro, it is replayed as soon
+//! as the shell starts.
+//!
+//! [`KeyStream`]: super::keyboard::KeyStream
+
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::{device::ps2::ScancodeSet, meta::config::{self, ConfigError}};
+
+const KEY_PREFIX: &str = "keyboard.macro.";
+const SET2_PREFIX: &str = "set2:";
+
+static STATE: Mutex<MacroState> = Mutex::new(MacroState {
+    recording: None,
+    checkpoint: 0,
+    playback: VecDeque::new(),
+});
+
+struct MacroState {
+    recording: Option<(String, Vec<u8>)>,
+
+    /// The length of the recording at the start of the current shell line,
+    /// used to leave the command that stops the recording out.
+    checkpoint: usize,
+    playback: VecDeque<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroError {
+    AlreadyRecording,
+    NotRecording,
+    InvalidName,
+    NotFound,
+
+    /// The stored macro isn't valid hexadecimal.
+    Corrupt,
+
+    /// The macro was recorded in another scancode set than the keyboard uses.
+    ScancodeSetMismatch,
+    Config(ConfigError),
+}
+
+impl From<ConfigError> for MacroError {
+    fn from(value: ConfigError) -> Self {
+        Self::Config(value)
+    }
+}
+
+/// Start recording the keyboard input under the given name.
+pub fn start_recording(name: &str) -> Result<(), MacroError> {
+    if name.is_empty() || name.contains(|c: char| c == '=' || c.is_whitespace()) {
+        return Err(MacroError::InvalidName);
+    }
+
+    let mut state = STATE.lock();
+    if state.recording.is_some() {
+        return Err(MacroError::AlreadyRecording);
+    }
+
+    state.recording = Some((String::from(name), Vec::new()));
+    state.checkpoint = 0;
+    Ok(())
+}
+
+/// Stop recording and store the macro, returning its name and the number of
+/// recorded scancodes.
+pub fn stop_recording() -> Result<(String, usize), MacroError> {
+    let (name, scancodes) = {
+        let mut state = STATE.lock();
+        let checkpoint = state.checkpoint;
+        let Some(recording) = state.recording.take() else {
+            return Err(MacroError::NotRecording);
+        };
+
+        let (name, mut scancodes) = recording;
+        scancodes.truncate(checkpoint);
+        (name, scancodes)
+    };
+
+    let mut encoded = String::with_capacity(SET2_PREFIX.len() + scancodes.len() * 2);
+    if ScancodeSet::current() == ScancodeSet::Set2 {
+        encoded.push_str(SET2_PREFIX);
+    }
+    for scancode in &scancodes {
+        _ = write!(encoded, "{scancode:02x}");
+    }
+
+    let count = scancodes.len();
+    info!("Recorded keyboard macro `{name}` with {count} scancodes");
+    match config::set(&format!("{KEY_PREFIX}{name}"), &encoded) {
+        // The macro can still be played during this boot.
+        Ok(()) | Err(ConfigError::NoBackingStore) => Ok((name, count)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn is_recording() -> bool {
+    STATE.lock().recording.is_some()
+}
+
+/// Queue the scancodes of a stored macro for replay.
+pub fn play(name: &str) -> Result<usize, MacroError> {
+    let encoded = config::get(&format!("{KEY_PREFIX}{name}")).ok_or(MacroError::NotFound)?;
+    let (set, encoded) = match encoded.strip_prefix(SET2_PREFIX) {
+        Some(encoded) => (ScancodeSet::Set2, encoded),
+        None => (ScancodeSet::Set1, encoded.as_str()),
+    };
+    if set != ScancodeSet::current() {
+        return Err(MacroError::ScancodeSetMismatch);
+    }
+
+    let scancodes = decode(encoded).ok_or(MacroError::Corrupt)?;
+
+    let count = scancodes.len();
+    STATE.lock().playback.extend(scancodes);
+    Ok(count)
+}
+
+/// The names of the stored macros.
+pub fn list() -> Vec<String> {
+    config::entries()
+        .into_iter()
+        .filter_map(|(key, _)| key.strip_prefix(KEY_PREFIX).map(String::from))
+        .collect()
+}
+
+/// Play the macro named by the `keyboard.autoplay` entry, if any.
+pub fn autoplay() {
+    let Some(name) = config::get(config::KEY_KEYBOARD_AUTOPLAY) else {
+        return;
+    };
+
+    match play(&name) {
+        Ok(count) => info!("Replaying keyboard macro `{name}` ({count} scancodes)"),
+        Err(e) => warn!("Failed to replay keyboard macro `{name}` from {}: {e:?}", config::KEY_KEYBOARD_AUTOPLAY),
+    }
+}
+
+/// Mark the start of a new shell line.
+pub(super) fn checkpoint() {
+    let mut state = STATE.lock();
+    if let Some((_, scancodes)) = &state.recording {
+        state.checkpoint = scancodes.len();
+    }
+}
+
+/// Take the next scancode to replay, if a macro is playing.
+pub(super) fn next_replayed() -> Option<u8> {
+    STATE.lock().playback.pop_front()
+}
+
+/// Called for every scancode the [`KeyStream`](super::keyboard::KeyStream)
+/// consumes.
+pub(super) fn record(scancode: u8) {
+    if let Some((_, scancodes)) = &mut STATE.lock().recording {
+        scancodes.push(scancode);
+    }
+}
+
+fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 2 != 0 {
+        return None;
+    }
+
+    encoded.chunks(2)
+        .map(|pair| u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
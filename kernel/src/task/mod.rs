@@ -1,11 +1,23 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use core::{future::Future, pin::Pin};
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 
+pub mod cancel;
+pub mod editor;
 pub mod executor;
+pub mod focus;
+pub mod inspector;
 pub mod keyboard;
+pub mod keymap;
+pub mod macros;
+pub mod reboot;
+pub mod scheduler;
+pub mod serial_input;
+pub mod shell;
 pub mod simple_executor;
+pub mod status_bar;
+pub mod timer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -17,19 +29,60 @@ impl TaskId {
     }
 }
 
+/// The CPUs a task may run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    /// Any worker may run the task, e.g. after stealing it from a busy one.
+    Any,
+
+    /// Only the bootstrap processor runs the task, e.g. for the console,
+    /// which expects to be drawn from a single CPU.
+    BootCpu,
+}
+
 pub struct Task {
     id: TaskId, // new
+    name: &'static str,
+    affinity: Affinity,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    statistics: Arc<inspector::TaskStatistics>,
+
+    /// Run when the machine shuts down, see [`Task::with_cleanup`].
+    cleanup: Option<fn()>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    /// Create a task with a name, which is shown in the task inspector.
+    ///
+    /// The task is stopped when the machine shuts down, by dropping the future
+    /// the next time it's polled (see [`cancel::shutdown`]).
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(), // new
-            future: Box::pin(future),
+            name,
+            affinity: Affinity::Any,
+            future: Box::pin(async move {
+                _ = cancel::until_cancelled(cancel::shutdown(), future).await;
+            }),
+            statistics: Arc::new(inspector::TaskStatistics::new()),
+            cleanup: None,
         }
     }
 
+    /// Pin the task to the given CPUs.
+    pub fn with_affinity(mut self, affinity: Affinity) -> Task {
+        self.affinity = affinity;
+        self
+    }
+
+    /// Run the function when the machine shuts down, after the tasks stopped
+    /// and before the shutdown hooks, e.g. to quiesce the device the task
+    /// drives. It also runs when the task already returned.
+    pub fn with_cleanup(mut self, cleanup: fn()) -> Task {
+        self.cleanup = Some(cleanup);
+        self
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -0,0 +1,65 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Ctrl+Alt+Del, which reboots the machine after a grace period that is
+//! counted down on the console. Pressing it again during the countdown resets
+//! the machine right away, skipping the shutdown hooks (see
+//! [`System::request_reboot`]).
+//!
+//! The keys are detected by the `input` task (see [`focus`](super::focus)),
+//! regardless of which consumer has the focus.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use log::warn;
+use pc_keyboard::KeyCode;
+
+use crate::{meta::System, println};
+
+use super::{keyboard::KeyPress, timer};
+
+const GRACE_PERIOD_SECONDS: u64 = 5;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Whether the key press is Ctrl+Alt+Del, with Del on the numeric keypad
+/// counting as well.
+pub fn is_ctrl_alt_del(press: &KeyPress) -> bool {
+    press.modifiers.ctrl && press.modifiers.alt
+        && matches!(press.code, KeyCode::Delete | KeyCode::NumpadPeriod)
+}
+
+/// Start the countdown, or reset the machine when it's already counting down.
+pub fn handle_ctrl_alt_del() {
+    if REQUESTED.swap(true, Ordering::Relaxed) {
+        warn!("Ctrl+Alt+Del pressed again, resetting now");
+        System::reboot();
+    }
+
+    WAKER.wake();
+}
+
+/// The task that counts down and reboots after Ctrl+Alt+Del.
+pub async fn run() {
+    poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        if REQUESTED.load(Ordering::Relaxed) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }).await;
+
+    for remaining in (1..=GRACE_PERIOD_SECONDS).rev() {
+        println!("Rebooting in {remaining} seconds, press Ctrl+Alt+Del again to reboot now");
+        timer::sleep(Duration::from_secs(1)).await;
+    }
+
+    System::request_reboot();
+}
@@ -0,0 +1,433 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Kernel threads, which are preempted by the timer interrupt, unlike the
+//! tasks of the [`executor`](super::executor), which run until they yield.
+//!
+//! Every [`Thread`] has a stack of its own, and the threads that are ready
+//! take turns in a round-robin run queue, each running for up to
+//! [`TIME_SLICE_TICKS`] before the timer interrupt switches to the next. The
+//! code that runs before the scheduler is initialized, which ends up running
+//! the executor, becomes the `boot` thread, so the executor is just one of
+//! the threads.
+//!
+//! | Function       | Purpose                                                     |
+//! |----------------|-------------------------------------------------------------|
+//! | [`spawn`]      | Start a thread, running a closure on a stack of its own     |
+//! | [`yield_now`]  | Give the rest of the time slice to the next ready thread    |
+//! | [`sleep`]      | Leave the run queue until the duration has passed           |
+//! | [`exit`]       | Stop the current thread, which returning from it does too   |
+//! | [`threads`]    | The threads with their state, shown by `threads`            |
+//!
+//! A thread can't be switched away from while it holds an
+//! [`IrqSpinlock`](crate::sync::IrqSpinlock), since interrupts are disabled,
+//! but it can be while it holds a [`Spinlock`](crate::sync::Spinlock). Taking
+//! a `Spinlock` with interrupts disabled might therefore spin forever, and
+//! the scheduler doesn't allocate or free while its lock is held for the same
+//! reason: the stacks of the exited threads are freed by the next thread that
+//! yields or starts.
+//!
+//! Switching threads switches the address space and the x87/SSE registers
+//! too, so the threads of the [processes](crate::process) each run with the
+//! page tables and the registers of their own.
+//!
+//! Only the boot CPU runs threads.
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::time::Duration;
+
+use x86_64::VirtAddr;
+
+use crate::{
+    arch::{self, context::{AddressSpaceContext, FpuState}},
+    meta::{counters::Counter, init::HeapInitialized, stack},
+    sync::IrqSpinlock,
+};
+
+/// The ticks a thread runs for before it's preempted by the next one.
+pub const TIME_SLICE_TICKS: usize = arch::TICKS_PER_SECOND / 100;
+
+/// The size of the stacks of the spawned threads.
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// The threads that can exist at once, including the boot thread, so the run
+/// queue never has to grow while the scheduler is locked.
+const MAX_THREADS: usize = 16;
+
+static SWITCHES: Counter = Counter::new("sched.switches", "Switches between kernel threads");
+static PREEMPTIONS: Counter = Counter::new("sched.preemptions", "Kernel threads switched away from by the timer");
+
+static SCHEDULER: IrqSpinlock<Option<Scheduler>> = IrqSpinlock::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The thread that initialized the scheduler.
+    pub const BOOT: Self = Self(0);
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+
+    /// Waiting in the run queue until the tick count reaches `until`.
+    Sleeping { until: usize },
+
+    /// Waiting for its stack to be freed.
+    Exited,
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Ready => "ready",
+            Self::Sleeping { .. } => "sleeping",
+            Self::Exited => "exited",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnError {
+    /// [`init`] wasn't called yet.
+    Uninitialized,
+
+    /// There are already as many threads as can exist at once.
+    TooManyThreads,
+}
+
+/// A kernel thread, with the state to continue it while it isn't running.
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+
+    /// The stack pointer saved by [`arch::context::switch_context`].
+    stack_pointer: usize,
+
+    /// The page table and such, for the threads of the processes.
+    address_space: AddressSpaceContext,
+
+    /// The x87 and SSE registers, which only the processes use.
+    fpu: FpuState,
+
+    /// The stack, which is `None` for the boot thread, whose stack was set up
+    /// by the bootloader.
+    stack: Option<Box<[u8]>>,
+
+    /// The closure to run, taken when the thread starts.
+    entry: Option<Box<dyn FnOnce() + Send>>,
+
+    /// The number of times the thread was switched to.
+    switches: u64,
+}
+
+impl Thread {
+    fn stack_bottom(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(|stack| VirtAddr::from_ptr(stack.as_ptr()))
+    }
+
+    fn is_runnable(&self, now: usize) -> bool {
+        match self.state {
+            ThreadState::Ready => true,
+            ThreadState::Sleeping { until } => now >= until,
+            ThreadState::Running | ThreadState::Exited => false,
+        }
+    }
+}
+
+/// A snapshot of a thread, see [`threads`].
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub switches: u64,
+
+    /// The bottom of the stack, to look it up in [`stack::stacks`].
+    pub stack: Option<VirtAddr>,
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    run_queue: VecDeque<Box<Thread>>,
+
+    /// The threads that exited, of which the stacks still have to be freed.
+    exited: Vec<Box<Thread>>,
+    next_id: u64,
+
+    /// The ticks since the current thread was switched to.
+    slice_ticks: usize,
+}
+
+impl Scheduler {
+    /// Take the next runnable thread out of the run queue, keeping the order
+    /// of the others.
+    fn take_next(&mut self, now: usize) -> Option<Box<Thread>> {
+        let index = self.run_queue.iter().position(|thread| thread.is_runnable(now))?;
+        self.run_queue.remove(index)
+    }
+
+    fn thread_count(&self) -> usize {
+        1 + self.run_queue.len() + self.exited.len()
+    }
+}
+
+/// What happens to the current thread when it's switched away from.
+enum Leave {
+    Ready,
+    Sleep { until: usize },
+    Exit,
+}
+
+/// Turn the code that is running into the boot thread.
+pub fn init(_: HeapInitialized) {
+    SWITCHES.register();
+    PREEMPTIONS.register();
+
+    let boot = Box::new(Thread {
+        id: ThreadId::BOOT,
+        name: "boot",
+        state: ThreadState::Running,
+        stack_pointer: 0,
+        address_space: AddressSpaceContext::save(),
+        fpu: FpuState::new(),
+        stack: None,
+        entry: None,
+        switches: 0,
+    });
+
+    *SCHEDULER.lock() = Some(Scheduler {
+        current: boot,
+        run_queue: VecDeque::with_capacity(MAX_THREADS),
+        exited: Vec::with_capacity(MAX_THREADS),
+        next_id: 1,
+        slice_ticks: 0,
+    });
+}
+
+/// Start a thread that runs the closure, and exits when it returns.
+pub fn spawn<F>(name: &'static str, f: F) -> Result<ThreadId, SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+
+    // Allocated before taking the lock, see the module documentation.
+    let stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let bottom = VirtAddr::from_ptr(stack.as_ptr());
+    let stack_pointer = unsafe {
+        stack::paint_and_register(name, bottom, STACK_SIZE);
+        arch::context::prepare_stack(bottom.as_u64() as usize + STACK_SIZE, thread_start)
+    };
+
+    let mut thread = Box::new(Thread {
+        id: ThreadId(0),
+        name,
+        state: ThreadState::Ready,
+        stack_pointer,
+        address_space: AddressSpaceContext::kernel(),
+        fpu: FpuState::new(),
+        stack: Some(stack),
+        entry: Some(Box::new(f)),
+        switches: 0,
+    });
+
+    let mut guard = SCHEDULER.lock();
+    let error = match guard.as_mut() {
+        None => SpawnError::Uninitialized,
+        Some(scheduler) if scheduler.thread_count() >= MAX_THREADS => SpawnError::TooManyThreads,
+        Some(scheduler) => {
+            let id = ThreadId(scheduler.next_id);
+            scheduler.next_id += 1;
+            thread.id = id;
+            scheduler.run_queue.push_back(thread);
+            return Ok(id);
+        }
+    };
+    drop(guard);
+
+    stack::unregister(bottom);
+    drop(thread);
+    Err(error)
+}
+
+/// Switch to the next ready thread, if there is one. Returns whether another
+/// thread ran in the meantime.
+pub fn yield_now() -> bool {
+    let switched = arch::without_interrupts(|| switch(Leave::Ready, arch::ticks()));
+    reap();
+    switched
+}
+
+/// Let the other threads run until the duration has passed. Halts when none
+/// of them is ready.
+pub fn sleep(duration: Duration) {
+    let ticks = (duration.as_millis() as usize * arch::TICKS_PER_SECOND).div_ceil(1000);
+    let until = arch::ticks() + ticks;
+
+    while arch::ticks() < until {
+        if !arch::without_interrupts(|| switch(Leave::Sleep { until }, arch::ticks())) {
+            arch::halt();
+        }
+        reap();
+    }
+}
+
+/// Stop the current thread, which can't be the boot thread.
+pub fn exit() -> ! {
+    assert_ne!(current(), Some(ThreadId::BOOT), "the boot thread can't exit");
+
+    arch::disable_interrupts();
+    switch(Leave::Exit, arch::ticks());
+    unreachable!("an exited thread was switched to");
+}
+
+/// The thread that is running, or `None` before [`init`].
+pub fn current() -> Option<ThreadId> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current.id)
+}
+
+/// The threads that exist, starting with the current one.
+pub fn threads() -> Vec<ThreadInfo> {
+    // Allocated before taking the lock, see the module documentation.
+    let mut threads = Vec::with_capacity(MAX_THREADS);
+
+    let guard = SCHEDULER.lock();
+    let Some(scheduler) = guard.as_ref() else {
+        return threads;
+    };
+
+    let all = core::iter::once(&scheduler.current)
+        .chain(scheduler.run_queue.iter())
+        .chain(scheduler.exited.iter());
+    for thread in all {
+        threads.push(ThreadInfo {
+            id: thread.id,
+            name: thread.name,
+            state: thread.state,
+            switches: thread.switches,
+            stack: thread.stack_bottom(),
+        });
+    }
+
+    threads
+}
+
+/// Called by the timer interrupt handler, as the last thing it does, since it
+/// might switch to another thread, which only returns here once this thread
+/// is switched back to.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_tick(ticks: usize) {
+    let preempt = {
+        let Some(mut guard) = SCHEDULER.try_lock() else {
+            return;
+        };
+        let Some(scheduler) = guard.as_mut() else {
+            return;
+        };
+
+        scheduler.slice_ticks += 1;
+
+        // A thread that finished sleeping doesn't wait for the time slice to
+        // end, otherwise sleeps would be rounded up to it.
+        let woken = scheduler.run_queue.iter()
+            .any(|thread| matches!(thread.state, ThreadState::Sleeping { until } if ticks >= until));
+        let expired = scheduler.slice_ticks >= TIME_SLICE_TICKS
+            && scheduler.run_queue.iter().any(|thread| thread.is_runnable(ticks));
+        woken || expired
+    };
+
+    if preempt && switch(Leave::Ready, ticks) {
+        PREEMPTIONS.increment();
+    }
+}
+
+/// Switch from the current thread to the next runnable one, returning whether
+/// there was one. Must be called with interrupts disabled.
+fn switch(leave: Leave, now: usize) -> bool {
+    let (save, load, address_space) = {
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            return false;
+        };
+        let Some(mut next) = scheduler.take_next(now) else {
+            return false;
+        };
+
+        next.state = ThreadState::Running;
+        next.switches += 1;
+        let mut previous = core::mem::replace(&mut scheduler.current, next);
+        scheduler.slice_ticks = 0;
+
+        // The thread stays in the same box, so the pointer stays valid after
+        // moving the box into the queue.
+        let save = &mut previous.stack_pointer as *mut usize;
+        previous.address_space = AddressSpaceContext::save();
+        previous.fpu.save();
+        match leave {
+            Leave::Ready => {
+                previous.state = ThreadState::Ready;
+                scheduler.run_queue.push_back(previous);
+            }
+            Leave::Sleep { until } => {
+                previous.state = ThreadState::Sleeping { until };
+                scheduler.run_queue.push_back(previous);
+            }
+            Leave::Exit => {
+                previous.state = ThreadState::Exited;
+                scheduler.exited.push(previous);
+            }
+        }
+
+        // The kernel doesn't touch these registers, so they can be loaded
+        // before the stack is switched.
+        unsafe { scheduler.current.fpu.restore() };
+        (save, scheduler.current.stack_pointer, scheduler.current.address_space)
+    };
+
+    SWITCHES.increment();
+    unsafe {
+        address_space.restore();
+        arch::context::switch_context(save, load);
+    }
+    true
+}
+
+/// Free the stacks of the threads that exited. Must be called with interrupts
+/// enabled, see the module documentation.
+fn reap() {
+    // Moved out one by one, since the vector of the scheduler must keep its
+    // capacity.
+    loop {
+        let Some(thread) = SCHEDULER.lock().as_mut().and_then(|scheduler| scheduler.exited.pop()) else {
+            return;
+        };
+
+        if let Some(bottom) = thread.stack_bottom() {
+            stack::unregister(bottom);
+        }
+        drop(thread);
+    }
+}
+
+/// Where a new thread starts, see [`arch::context::prepare_stack`].
+extern "C" fn thread_start() -> ! {
+    arch::enable_interrupts();
+    reap();
+
+    let entry = SCHEDULER.lock().as_mut().and_then(|scheduler| scheduler.current.entry.take());
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
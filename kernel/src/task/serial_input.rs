@@ -0,0 +1,224 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Turns the bytes received on the serial port into key presses, so the shell
+//! can be used from a terminal when the console is routed to it (see
+//! [`Console`](crate::meta::Console)).
+//!
+//! The terminal sends characters instead of keys, which are mapped back to the
+//! keys of the US layout, while the characters are passed on as they are, so
+//! the [`keymap`](super::keymap) doesn't apply:
+//!
+//! | Received                 | Key press                                   |
+//! |--------------------------|---------------------------------------------|
+//! | `CR`, `LF` or `CR LF`    | Return                                      |
+//! | `DEL` or `BS`            | Backspace                                   |
+//! | `0x01` to `0x1A`         | Ctrl with the letter, e.g. `0x03` is Ctrl+C |
+//! | `ESC [ A` to `ESC [ D`   | The arrow keys                              |
+//! | `ESC [ H`, `ESC [ F`     | Home and End                                |
+//! | `ESC [ n ~`              | Insert, Delete, Home, End, Page Up and Down |
+//! | Printable ASCII          | The key with that character, with Shift     |
+//!
+//! A lone `ESC` is only delivered once the next byte arrives, since it can't
+//! be told apart from the start of a sequence before that.
+//!
+//! ### References:
+//! - [ECMA-48: Control Functions for Coded Character Sets](https://ecma-international.org/publications-and-standards/standards/ecma-48/)
+//! - [XTerm Control Sequences, PC-Style Function Keys](https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-PC-Style-Function-Keys)
+
+use futures_util::future::poll_fn;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::arch::serial;
+
+use super::{focus, keyboard::{KeyPress, Modifiers}};
+
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+const BACKSPACE: u8 = 0x08;
+
+const LETTERS: [KeyCode; 26] = [
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F,
+    KeyCode::G, KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L,
+    KeyCode::M, KeyCode::N, KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R,
+    KeyCode::S, KeyCode::T, KeyCode::U, KeyCode::V, KeyCode::W, KeyCode::X,
+    KeyCode::Y, KeyCode::Z,
+];
+
+/// The keys of the other characters: unshifted, shifted.
+const SYMBOLS: [(KeyCode, char, char); 21] = [
+    (KeyCode::Key1, '1', '!'),
+    (KeyCode::Key2, '2', '@'),
+    (KeyCode::Key3, '3', '#'),
+    (KeyCode::Key4, '4', '$'),
+    (KeyCode::Key5, '5', '%'),
+    (KeyCode::Key6, '6', '^'),
+    (KeyCode::Key7, '7', '&'),
+    (KeyCode::Key8, '8', '*'),
+    (KeyCode::Key9, '9', '('),
+    (KeyCode::Key0, '0', ')'),
+    (KeyCode::Oem8, '`', '~'),
+    (KeyCode::OemMinus, '-', '_'),
+    (KeyCode::OemPlus, '=', '+'),
+    (KeyCode::Oem4, '[', '{'),
+    (KeyCode::Oem6, ']', '}'),
+    (KeyCode::Oem5, '\\', '|'),
+    (KeyCode::Oem1, ';', ':'),
+    (KeyCode::Oem3, '\'', '"'),
+    (KeyCode::OemComma, ',', '<'),
+    (KeyCode::OemPeriod, '.', '>'),
+    (KeyCode::Oem2, '/', '?'),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+
+    /// The previous byte was a carriage return, so a line feed is part of the
+    /// same line ending.
+    CarriageReturn,
+
+    Escape,
+
+    /// In a control sequence (`ESC [`), with its numeric parameter so far.
+    ControlSequence(u8),
+}
+
+/// The state of the decoder between the received bytes.
+pub struct SerialDecoder {
+    state: State,
+}
+
+impl SerialDecoder {
+    pub const fn new() -> Self {
+        Self { state: State::Ground }
+    }
+
+    /// Decode the next byte, calling the function for each key press it
+    /// completes.
+    pub fn feed(&mut self, byte: u8, mut emit: impl FnMut(KeyPress)) {
+        match self.state {
+            State::Escape if byte == b'[' => {
+                self.state = State::ControlSequence(0);
+                return;
+            }
+
+            State::Escape => {
+                emit(press(KeyCode::Escape, Some(DecodedKey::Unicode('\u{1b}')), Modifiers::default()));
+            }
+
+            State::ControlSequence(parameter) => {
+                match byte {
+                    b'0'..=b'9' => {
+                        let parameter = parameter.saturating_mul(10).saturating_add(byte - b'0');
+                        self.state = State::ControlSequence(parameter);
+                    }
+
+                    // Intermediate and parameter bytes we don't use.
+                    0x20..=0x3F => (),
+
+                    _ => {
+                        self.state = State::Ground;
+                        if let Some(code) = control_sequence_key(parameter, byte) {
+                            emit(press(code, Some(DecodedKey::RawKey(code)), Modifiers::default()));
+                        }
+                    }
+                }
+                return;
+            }
+
+            State::CarriageReturn if byte == b'\n' => {
+                self.state = State::Ground;
+                return;
+            }
+
+            State::Ground | State::CarriageReturn => (),
+        }
+
+        self.state = State::Ground;
+        match byte {
+            ESCAPE => self.state = State::Escape,
+
+            b'\r' | b'\n' => {
+                if byte == b'\r' {
+                    self.state = State::CarriageReturn;
+                }
+                emit(press(KeyCode::Return, Some(DecodedKey::Unicode('\n')), Modifiers::default()));
+            }
+
+            DELETE | BACKSPACE => {
+                emit(press(KeyCode::Backspace, Some(DecodedKey::Unicode('\u{8}')), Modifiers::default()));
+            }
+
+            b'\t' => emit(press(KeyCode::Tab, Some(DecodedKey::Unicode('\t')), Modifiers::default())),
+
+            0x01..=0x1A => {
+                let letter = byte - 1;
+                let modifiers = Modifiers { ctrl: true, ..Modifiers::default() };
+                let character = char::from(b'a' + letter);
+                emit(press(LETTERS[letter as usize], Some(DecodedKey::Unicode(character)), modifiers));
+            }
+
+            _ => {
+                if let Some((code, shift)) = printable_key(byte) {
+                    let modifiers = Modifiers { shift, ..Modifiers::default() };
+                    emit(press(code, Some(DecodedKey::Unicode(char::from(byte))), modifiers));
+                }
+            }
+        }
+    }
+}
+
+fn press(code: KeyCode, decoded: Option<DecodedKey>, modifiers: Modifiers) -> KeyPress {
+    KeyPress { code, decoded, modifiers }
+}
+
+/// The key of a printable character, and whether Shift is held for it.
+fn printable_key(byte: u8) -> Option<(KeyCode, bool)> {
+    match byte {
+        b' ' => Some((KeyCode::Spacebar, false)),
+        b'a'..=b'z' => Some((LETTERS[(byte - b'a') as usize], false)),
+        b'A'..=b'Z' => Some((LETTERS[(byte - b'A') as usize], true)),
+        _ => {
+            let character = char::from(byte);
+            SYMBOLS.iter().find_map(|(code, unshifted, shifted)| {
+                if character == *unshifted {
+                    Some((*code, false))
+                } else if character == *shifted {
+                    Some((*code, true))
+                } else {
+                    None
+                }
+            })
+        }
+    }
+}
+
+/// The key of the control sequence with the given parameter and final byte.
+fn control_sequence_key(parameter: u8, final_byte: u8) -> Option<KeyCode> {
+    match (final_byte, parameter) {
+        (b'A', _) => Some(KeyCode::ArrowUp),
+        (b'B', _) => Some(KeyCode::ArrowDown),
+        (b'C', _) => Some(KeyCode::ArrowRight),
+        (b'D', _) => Some(KeyCode::ArrowLeft),
+        (b'H', _) | (b'~', 1 | 7) => Some(KeyCode::Home),
+        (b'F', _) | (b'~', 4 | 8) => Some(KeyCode::End),
+        (b'~', 2) => Some(KeyCode::Insert),
+        (b'~', 3) => Some(KeyCode::Delete),
+        (b'~', 5) => Some(KeyCode::PageUp),
+        (b'~', 6) => Some(KeyCode::PageDown),
+        _ => None,
+    }
+}
+
+/// The task that delivers the key presses received on the serial port to the
+/// focused consumer. Nothing is received unless the console is routed to the
+/// serial port.
+pub async fn run() {
+    let mut decoder = SerialDecoder::new();
+
+    loop {
+        let byte = poll_fn(serial::poll_receive).await;
+        decoder.feed(byte, focus::deliver);
+    }
+}
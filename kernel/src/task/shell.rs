@@ -0,0 +1,2139 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The interactive kernel shell.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use acpi::madt::MadtEntry;
+use spin::Mutex;
+
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    allocator::{self, fixed_size_block::BLOCK_SIZES},
+    arch::{self, interrupts::apic::LocalApic, port},
+    crypto::entropy,
+    debug::BochsDebugger,
+    device::{
+        acpi::{tables, ACPI_DATA},
+        block,
+        bochs_vbe,
+        clocksource,
+        fw_cfg::FwCfg,
+        net::{intel_8254x, NetworkDevice},
+        registry::{self, DeviceId, DeviceNode},
+        pci::{msi::{Msi, MsiX}, ConfigurationSpaceMechanism, OptionRom, PciAddress, PciCapabilityId, PciConfigurationSpace, RomBar},
+    },
+    fs::{
+        fat::{FatError, FatVolume},
+        vfs::{self, Directory, File, FileType, OpenOptions, VfsError},
+    },
+    meta::{
+        bench,
+        config::{self, ConfigError},
+        containment::{self, FeatureState},
+        coredump::{self, CoreDump, Registers as CoreRegisters, Segment},
+        counters,
+        cpu_usage::{self, Share, Snapshot, Usage},
+        help::{self, Entry},
+        hexdump::HexDump,
+        irq_latency::{self, Irq},
+        memory_map::{self, RegionKind},
+        selftest,
+        stack,
+        Console,
+        ConsoleRoute,
+        System,
+    },
+    net::{arp, config as net_config, icmp, interface, ipv4::Ipv4Address, pcap::{self, CaptureSink, Direction}, tcp, MacAddress},
+    print,
+    println,
+    process::{self, ProcessState},
+    serial_print,
+    serial_println,
+    vga_text_buffer::WRITER,
+};
+
+use super::{
+    editor,
+    focus::{self, Consumer, Input},
+    keymap::{self, Layout, Remap},
+    macros::{self, MacroError},
+    scheduler,
+    status_bar,
+};
+
+const PROMPT: &str = "> ";
+
+/// The action waiting for the user to answer `y`, see [`confirm`].
+static CONFIRMATION: Mutex<Option<fn()>> = Mutex::new(None);
+
+/// When `top` ran last, which it shows the usage since.
+static TOP_SNAPSHOT: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+
+    /// The arguments of the usage, shown by `help <command>`.
+    arguments: &'static [Entry],
+    handler: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help [name]",
+        description: "List the available commands, or describe a command or topic",
+        arguments: &[
+            Entry::new("name", "A command or topic to describe, or `json` to dump everything as JSON"),
+        ],
+        handler: command_help,
+    },
+    Command {
+        name: "get",
+        usage: "get [key]",
+        description: "Show a configuration entry, or all of them",
+        arguments: &[
+            Entry::new("key", "The entry to show, instead of all of them (see `help config`)"),
+        ],
+        handler: command_get,
+    },
+    Command {
+        name: "set",
+        usage: "set <key> <value>",
+        description: "Change and persist a configuration entry",
+        arguments: &[
+            Entry::new("key", "The entry to change (see `help config`)"),
+            Entry::new("value", "The new value, which may contain spaces"),
+        ],
+        handler: command_set,
+    },
+    Command {
+        name: "unset",
+        usage: "unset <key>",
+        description: "Remove a configuration entry",
+        arguments: &[
+            Entry::new("key", "The entry to remove"),
+        ],
+        handler: command_unset,
+    },
+    Command {
+        name: "cfgformat",
+        usage: "cfgformat [-y]",
+        description: "Keep the configuration on the blank disk found at boot",
+        arguments: &[
+            Entry::new("-y", "Don't ask for confirmation"),
+        ],
+        handler: command_cfgformat,
+    },
+    Command {
+        name: "acpi",
+        usage: "acpi",
+        description: "Summarize the ACPI tables, interrupt controllers and sleep states",
+        arguments: &[],
+        handler: command_acpi,
+    },
+    Command {
+        name: "acpidump",
+        usage: "acpidump [signature]",
+        description: "List the ACPI tables, or dump one over serial",
+        arguments: &[
+            Entry::new("signature", "The table to dump, e.g. `FACP`, instead of listing them"),
+        ],
+        handler: command_acpidump,
+    },
+    Command {
+        name: "clocksource",
+        usage: "clocksource",
+        description: "Show the timers that can generate the tick, and which one does",
+        arguments: &[],
+        handler: command_clocksource,
+    },
+    Command {
+        name: "devices",
+        usage: "devices",
+        description: "Show the tree of buses, devices and their drivers",
+        arguments: &[],
+        handler: command_devices,
+    },
+    Command {
+        name: "edit",
+        usage: "edit <file>",
+        description: "Edit a file of the FAT32 file system in a full-screen editor",
+        arguments: &[
+            Entry::new("file", "The file on the FAT volume, which is created when saved"),
+        ],
+        handler: command_edit,
+    },
+    Command {
+        name: "fat",
+        usage: "fat <ls|cat <file>|write <file> <text>|alloc <file> <size>|truncate <file> <size>|rm <file>>",
+        description: "Use the FAT32 file system of the first disk that has one",
+        arguments: &[
+            Entry::new("ls", "List the files"),
+            Entry::new("cat <file>", "Print the file"),
+            Entry::new("write <file> <text>", "Replace the contents of the file"),
+            Entry::new("alloc <file> <size>", "Create a file of the size in bytes"),
+            Entry::new("truncate <file> <size>", "Shrink or grow the file"),
+            Entry::new("rm <file>", "Remove the file"),
+        ],
+        handler: command_fat,
+    },
+    Command {
+        name: "fs",
+        usage: "fs <mounts|ls [path]|cat <path>|write <path> <text>|mkdir <path>|rm <path>>",
+        description: "Use the files of the virtual file system",
+        arguments: &[
+            Entry::new("mounts", "List the mounted file systems"),
+            Entry::new("ls [path]", "List the directory, / by default"),
+            Entry::new("cat <path>", "Print the file"),
+            Entry::new("write <path> <text>", "Append a line to the file, creating it"),
+            Entry::new("mkdir <path>", "Create a directory"),
+            Entry::new("rm <path>", "Remove the file or empty directory"),
+        ],
+        handler: command_fs,
+    },
+    Command {
+        name: "fbmode",
+        usage: "fbmode <width>x<height>",
+        description: "Change the resolution of the framebuffer (Bochs/QEMU display only)",
+        arguments: &[
+            Entry::new("width", "Horizontal resolution in pixels"),
+            Entry::new("height", "Vertical resolution in pixels"),
+        ],
+        handler: command_fbmode,
+    },
+    Command {
+        name: "fwcfg",
+        usage: "fwcfg [file]",
+        description: "List the QEMU fw_cfg files, or show one",
+        arguments: &[
+            Entry::new("file", "The file to print, instead of listing them"),
+        ],
+        handler: command_fwcfg,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci [-v] [address]",
+        description: "List PCI devices, or show the details of one",
+        arguments: &[
+            Entry::new("-v", "Also show the BARs and capabilities"),
+            Entry::new("address", "Only show the device at [segment:]bus:device.function"),
+        ],
+        handler: command_lspci,
+    },
+    Command {
+        name: "optionrom",
+        usage: "optionrom <address> [file]",
+        description: "Show the expansion ROM of a PCI device, or save it to a file",
+        arguments: &[
+            Entry::new("address", "The device, as [segment:]bus:device.function"),
+            Entry::new("file", "Save the ROM to this file on the FAT volume"),
+        ],
+        handler: command_optionrom,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        description: "Show how much physical memory is usable, allocated and free, and the heap usage",
+        arguments: &[],
+        handler: command_meminfo,
+    },
+    Command {
+        name: "memmap",
+        usage: "memmap [-l] [start end]",
+        description: "Show a map of the physical memory, or list the regions",
+        arguments: &[
+            Entry::new("-l", "List the regions instead of drawing the map"),
+            Entry::new("start end", "The physical range to show, in hexadecimal"),
+        ],
+        handler: command_memmap,
+    },
+    Command {
+        name: "hexdump",
+        usage: "hexdump [-p] <address> [length]",
+        description: "Dump the memory at a virtual (or with -p, physical) address",
+        arguments: &[
+            Entry::new("-p", "The address is physical instead of virtual"),
+            Entry::new("address", "Where to start, in hexadecimal"),
+            Entry::new("length", "The number of bytes, in hexadecimal (default 0x100)"),
+        ],
+        handler: command_hexdump,
+    },
+    Command {
+        name: "pcap",
+        usage: "pcap <on|off|list|clear|dump <serial|debugcon>>",
+        description: "Control the packet capture",
+        arguments: &[
+            Entry::new("on|off", "Start or stop capturing"),
+            Entry::new("list", "List the captured packets"),
+            Entry::new("clear", "Forget the captured packets"),
+            Entry::new("dump <serial|debugcon>", "Write the capture as a pcap file to the port"),
+        ],
+        handler: command_pcap,
+    },
+    Command {
+        name: "nic",
+        usage: "nic [send <index>|receive <index>]",
+        description: "Show the network cards, or send and receive test frames",
+        arguments: &[
+            Entry::new("send <index>", "Broadcast a test frame from the card"),
+            Entry::new("receive <index>", "Take the frames the card received, and show their headers"),
+        ],
+        handler: command_nic,
+    },
+    Command {
+        name: "ping",
+        usage: "ping <address> [count]",
+        description: "Send ICMP echo requests to an IPv4 address, and show the replies",
+        arguments: &[
+            Entry::new("count", "The number of requests, one per second (default: 4)"),
+        ],
+        handler: command_ping,
+    },
+    Command {
+        name: "arp",
+        usage: "arp",
+        description: "Show the cached MAC addresses of the IPv4 addresses on the link",
+        arguments: &[],
+        handler: command_arp,
+    },
+    Command {
+        name: "ip",
+        usage: "ip",
+        description: "Show the addresses of the network interfaces, their routers and the DNS servers",
+        arguments: &[],
+        handler: command_ip,
+    },
+    Command {
+        name: "dhcp",
+        usage: "dhcp",
+        description: "Show the IPv4 lease of eth0 from the DHCP server",
+        arguments: &[],
+        handler: command_dhcp,
+    },
+    Command {
+        name: "tcp",
+        usage: "tcp [connect <address> <port> [text]]",
+        description: "Show the listening TCP ports and the connections, or connect to a port",
+        arguments: &[
+            Entry::new("connect <address> <port> [text]", "Send the text and a newline, and show the answer"),
+        ],
+        handler: command_tcp,
+    },
+    Command {
+        name: "blk",
+        usage: "blk [read <disk> <lba>|write <disk> <lba> <text>|flush <disk>]",
+        description: "Show the disks, or read and write their blocks",
+        arguments: &[
+            Entry::new("read <disk> <lba>", "Dump the block of the disk, e.g. `blk read vda 0`"),
+            Entry::new("write <disk> <lba> <text>", "Write the text to the block, padded with zeroes"),
+            Entry::new("flush <disk>", "Write the cache of the disk back"),
+        ],
+        handler: command_blk,
+    },
+    Command {
+        name: "heap",
+        usage: "heap",
+        description: "Show the heap usage, fragmentation and allocation sizes",
+        arguments: &[],
+        handler: command_heap,
+    },
+    Command {
+        name: "coredump",
+        usage: "coredump",
+        description: "Write a core dump of the shell's stack to the serial port",
+        arguments: &[],
+        handler: command_coredump,
+    },
+    Command {
+        name: "counters",
+        usage: "counters",
+        description: "List the event counters",
+        arguments: &[],
+        handler: command_counters,
+    },
+    Command {
+        name: "features",
+        usage: "features",
+        description: "List the optional features and whether they were disabled",
+        arguments: &[],
+        handler: command_features,
+    },
+    Command {
+        name: "irqstat",
+        usage: "irqstat",
+        description: "Show the latency percentiles of the device interrupts",
+        arguments: &[],
+        handler: command_irqstat,
+    },
+    Command {
+        name: "memperf",
+        usage: "memperf",
+        description: "Compare the fill and copy routines on a framebuffer-sized buffer",
+        arguments: &[],
+        handler: command_memperf,
+    },
+    Command {
+        name: "ports",
+        usage: "ports [reset]",
+        description: "Show the I/O port access counters (portaudit=count)",
+        arguments: &[
+            Entry::new("reset", "Clear the statistics"),
+        ],
+        handler: command_ports,
+    },
+    Command {
+        name: "stacks",
+        usage: "stacks",
+        description: "Show the maximum usage of the kernel stacks",
+        arguments: &[],
+        handler: command_stacks,
+    },
+    Command {
+        name: "threads",
+        usage: "threads",
+        description: "Show the kernel threads, with their state and stack usage",
+        arguments: &[],
+        handler: command_threads,
+    },
+    Command {
+        name: "run",
+        usage: "run <path>",
+        description: "Run a static ELF executable in user mode, as a process",
+        arguments: &[],
+        handler: command_run,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        description: "Show the running process and the ones that ended recently",
+        arguments: &[],
+        handler: command_ps,
+    },
+    Command {
+        name: "top",
+        usage: "top",
+        description: "Show the CPU usage of the tasks and interrupts since the last time",
+        arguments: &[],
+        handler: command_top,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime",
+        description: "Show how long the system has been running",
+        arguments: &[],
+        handler: command_uptime,
+    },
+    Command {
+        name: "rngtest",
+        usage: "rngtest",
+        description: "Show the entropy sources and test the random numbers",
+        arguments: &[],
+        handler: command_rngtest,
+    },
+    Command {
+        name: "statusbar",
+        usage: "statusbar [on|off]",
+        description: "Show or hide the status bar at the top of the screen",
+        arguments: &[
+            Entry::new("on|off", "Show or hide it, instead of showing the state"),
+        ],
+        handler: command_statusbar,
+    },
+    Command {
+        name: "bochs",
+        usage: "bochs <break|itrace|rtrace> [on|off]",
+        description: "Control the Bochs debugger",
+        arguments: &[
+            Entry::new("break", "Enter the Bochs debugger"),
+            Entry::new("itrace", "Trace the instructions"),
+            Entry::new("rtrace", "Trace the registers"),
+            Entry::new("on|off", "Enable or disable the trace"),
+        ],
+        handler: command_bochs,
+    },
+    Command {
+        name: "console",
+        usage: "console [fb|serial|both]",
+        description: "Show or change where the shell reads from and prints to",
+        arguments: &[
+            Entry::new("fb|serial|both", "Move the shell there, instead of listing the routes"),
+        ],
+        handler: command_console,
+    },
+    Command {
+        name: "focus",
+        usage: "focus [consumer]",
+        description: "Show the keyboard input consumers, or move the focus to one",
+        arguments: &[
+            Entry::new("consumer", "Give the keyboard focus to it, instead of listing them"),
+        ],
+        handler: command_focus,
+    },
+    Command {
+        name: "keymap",
+        usage: "keymap [layout|map <scancode>=<chars>|reset]",
+        description: "Show or switch the keyboard layout, or remap keys",
+        arguments: &[
+            Entry::new("layout", "Switch to the layout: us, uk, de, fr, dvorak, colemak or jp"),
+            Entry::new("map <scancode>=<chars>", "Make the key (set 1 scancode in hex) type the characters"),
+            Entry::new("reset", "Forget the remapped keys"),
+        ],
+        handler: command_keymap,
+    },
+    Command {
+        name: "macro",
+        usage: "macro <record <name>|stop|play <name>|list>",
+        description: "Record and replay keyboard input",
+        arguments: &[
+            Entry::new("record <name>", "Start recording the keyboard input"),
+            Entry::new("stop", "Finish the recording"),
+            Entry::new("play <name>", "Replay a recorded macro"),
+            Entry::new("list", "List the recorded macros"),
+        ],
+        handler: command_macro,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot [-y]",
+        description: "Restart the machine",
+        arguments: &[
+            Entry::new("-y", "Don't ask for confirmation"),
+        ],
+        handler: command_reboot,
+    },
+    Command {
+        name: "bench",
+        usage: "bench [name]",
+        description: "Run the micro-benchmarks, or only the given one",
+        arguments: &[
+            Entry::new("name", "Only run this benchmark"),
+        ],
+        handler: command_bench,
+    },
+    Command {
+        name: "selftest",
+        usage: "selftest",
+        description: "Run the tests inside the kernel, such as provoking CPU exceptions",
+        arguments: &[],
+        handler: command_selftest,
+    },
+    Command {
+        name: "shutdown",
+        usage: "shutdown [-y]",
+        description: "Power off the machine",
+        arguments: &[
+            Entry::new("-y", "Don't ask for confirmation"),
+        ],
+        handler: command_shutdown,
+    },
+];
+
+pub async fn run() {
+    let mut keys = Input::new(Consumer::Shell);
+    let mut line = String::new();
+
+    print!("{PROMPT}");
+    macros::autoplay();
+
+    while let Some(press) = keys.next_event().await {
+        if press.is_ctrl(KeyCode::C) {
+            println!("^C");
+            line.clear();
+            CONFIRMATION.lock().take();
+            print!("{PROMPT}");
+            macros::checkpoint();
+            continue;
+        }
+
+        if press.is_ctrl(KeyCode::L) {
+            WRITER.lock().clear();
+            print!("{PROMPT}{line}");
+            continue;
+        }
+
+        // There is no cursor to move and nothing to play yet.
+        if press.is_navigation() || press.is_multimedia() {
+            continue;
+        }
+
+        let Some(key) = press.decoded else {
+            continue;
+        };
+
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                match CONFIRMATION.lock().take() {
+                    Some(action) if line.trim().eq_ignore_ascii_case("y") => action(),
+                    Some(_) => println!("Cancelled"),
+                    None => execute(&line),
+                }
+                line.clear();
+
+                // Otherwise the question is the prompt.
+                if CONFIRMATION.lock().is_none() {
+                    print!("{PROMPT}");
+                }
+                macros::checkpoint();
+            }
+
+            DecodedKey::Unicode('\u{0008}') => {
+                if line.pop().is_some() {
+                    Console::backspace();
+                }
+            }
+
+            DecodedKey::Unicode(character) if !character.is_control() => {
+                line.push(character);
+                print!("{character}");
+            }
+
+            _ => (),
+        }
+    }
+}
+
+/// Execute a single command line.
+pub fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = args.split_first() else {
+        return;
+    };
+
+    match COMMANDS.iter().find(|command| command.name == *name) {
+        Some(command) => (command.handler)(args),
+        None => println!("Unknown command `{name}`, try `help`"),
+    }
+}
+
+fn command_help(args: &[&str]) {
+    match args {
+        [] => {
+            for command in COMMANDS {
+                println!("{:<20} {}", command.usage, command.description);
+            }
+
+            println!();
+            for topic in help::TOPICS {
+                println!("{:<20} {}", alloc::format!("help {}", topic.name), topic.summary);
+            }
+        }
+
+        ["json"] => println!("{}", documentation_json()),
+
+        [name] => {
+            if let Some(command) = COMMANDS.iter().find(|command| command.name == *name) {
+                println!("Usage: {}", command.usage);
+                println!("{}", command.description);
+                print_entries(command.arguments);
+            } else if let Some(topic) = help::find_topic(name) {
+                println!("{}", topic.summary);
+                print_entries(topic.entries);
+            } else {
+                println!("Unknown command or topic `{name}`");
+            }
+        }
+
+        _ => println!("Usage: help [name]"),
+    }
+}
+
+fn print_entries(entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+
+    println!();
+    let width = entries.iter().map(|entry| entry.name.len()).max().unwrap_or(0);
+    for entry in entries {
+        println!("  {:<width$}  {}", entry.name, entry.description);
+    }
+}
+
+/// The commands and the topics as a JSON object, see [`help`].
+pub fn documentation_json() -> String {
+    let mut out = String::from("{\"commands\":[");
+    for (index, command) in COMMANDS.iter().enumerate() {
+        if index != 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        help::write_json_string(&mut out, command.name);
+        out.push_str(",\"usage\":");
+        help::write_json_string(&mut out, command.usage);
+        out.push_str(",\"description\":");
+        help::write_json_string(&mut out, command.description);
+        out.push_str(",\"arguments\":");
+        help::write_entries_json(&mut out, command.arguments);
+        out.push('}');
+    }
+
+    out.push_str("],\"topics\":");
+    help::write_topics_json(&mut out);
+    out.push('}');
+    out
+}
+
+fn command_get(args: &[&str]) {
+    match args {
+        [] => {
+            for (key, value) in config::entries() {
+                println!("{key}={value}");
+            }
+        }
+
+        [key] => match config::get(key) {
+            Some(value) => println!("{key}={value}"),
+            None => println!("`{key}` is not set"),
+        },
+
+        _ => println!("Usage: get [key]"),
+    }
+}
+
+fn command_set(args: &[&str]) {
+    let [key, value @ ..] = args else {
+        println!("Usage: set <key> <value>");
+        return;
+    };
+
+    if value.is_empty() {
+        println!("Usage: set <key> <value>");
+        return;
+    }
+
+    let value = value.join(" ");
+    match config::set(key, &value) {
+        Ok(()) => (),
+        Err(ConfigError::NoBackingStore) => println!("`{key}` changed, but won't be persisted: no configuration disk (see cfgformat)"),
+        Err(e) => println!("Failed to set `{key}`: {e:?}"),
+    }
+}
+
+fn command_unset(args: &[&str]) {
+    let [key] = args else {
+        println!("Usage: unset <key>");
+        return;
+    };
+
+    match config::remove(key) {
+        Ok(true) => (),
+        Ok(false) => println!("`{key}` is not set"),
+        Err(ConfigError::NoBackingStore) => println!("`{key}` removed, but won't be persisted: no configuration disk (see cfgformat)"),
+        Err(e) => println!("Failed to remove `{key}`: {e:?}"),
+    }
+}
+
+fn command_cfgformat(args: &[&str]) {
+    let Some(position) = config::blank_disk() else {
+        println!("No blank disk to keep the configuration on");
+        return;
+    };
+
+    confirm(args, &alloc::format!("Write the configuration store to the disk at {position:?}?"), || match config::format() {
+        Ok(position) => println!("The configuration is kept on the disk at {position:?}"),
+        Err(e) => println!("Failed to write the configuration store: {e:?}"),
+    });
+}
+
+fn command_acpi(_: &[&str]) {
+    let signatures: Vec<_> = tables::tables().iter().map(|table| String::from(table.signature())).collect();
+    if signatures.is_empty() {
+        println!("No ACPI tables");
+        return;
+    }
+    println!("Tables: {}", signatures.join(" "));
+
+    let data = ACPI_DATA.lock();
+    match data.fadt.as_ref() {
+        Some(fadt) => {
+            let (sci, flags) = (fadt.sci_interrupt, fadt.flags);
+            println!("Power profile: {:?}, SCI on IRQ {sci}{}", fadt.power_profile(),
+                if flags.system_is_hw_reduced_acpi() { ", hardware-reduced" } else { "" });
+        }
+        None => println!("No FADT"),
+    }
+
+    if let Some(madt) = data.madt.as_ref() {
+        let (mut processors, mut io_apics, mut overrides) = (0, 0, 0);
+        for entry in madt.entries() {
+            match entry {
+                MadtEntry::LocalApic(..) | MadtEntry::LocalX2Apic(..) => processors += 1,
+                MadtEntry::IoApic(..) => io_apics += 1,
+                MadtEntry::InterruptSourceOverride(..) => overrides += 1,
+                _ => (),
+            }
+        }
+        println!("Interrupts: {processors} local APICs, {io_apics} I/O APICs, {overrides} source overrides");
+        if let Some(mode) = LocalApic::published_mode() {
+            println!("The local APIC is in {mode} mode");
+        }
+    }
+
+    match data.aml.as_ref() {
+        Some(aml) => {
+            let states: Vec<_> = aml.supported_sleep_states().iter().map(|state| alloc::format!("{state:?}")).collect();
+            println!("Sleep states: {}", states.join(" "));
+        }
+        None => println!("The AML isn't loaded"),
+    }
+}
+
+fn command_acpidump(args: &[&str]) {
+    match args {
+        [] => {
+            println!("{:<4} {:>18} {:>8} {:>3} {:<6} {:<8} {}", "SIG", "ADDRESS", "LENGTH", "REV", "OEM", "TABLE", "CHECKSUM");
+            for table in tables::tables() {
+                println!("{:<4} {:>#18x} {:>8} {:>3} {:<6} {:<8} {}",
+                    table.signature(),
+                    table.address,
+                    table.length,
+                    table.revision,
+                    table.oem_id(),
+                    table.oem_table_id(),
+                    if table.checksum_valid { "ok" } else { "INVALID" },
+                );
+            }
+        }
+
+        [signature] => {
+            let matches: Vec<_> = tables::tables().into_iter()
+                .filter(|table| table.signature().eq_ignore_ascii_case(signature))
+                .collect();
+
+            if matches.is_empty() {
+                println!("No ACPI table `{signature}`, see `acpidump`");
+                return;
+            }
+
+            // The format of the acpidump tool, which acpixtract understands.
+            for table in &matches {
+                serial_println!("{} @ {:#018x}", table.signature(), table.address);
+                for (idx, line) in tables::read(table).chunks(16).enumerate() {
+                    serial_print!("    {:04X}:", idx * 16);
+                    for byte in line {
+                        serial_print!(" {byte:02X}");
+                    }
+                    for _ in line.len()..16 {
+                        serial_print!("   ");
+                    }
+                    serial_print!("  ");
+                    for byte in line {
+                        let c = if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' };
+                        serial_print!("{c}");
+                    }
+                    serial_println!();
+                }
+                serial_println!();
+            }
+
+            println!("Dumped {} table(s) over serial", matches.len());
+        }
+
+        _ => println!("Usage: acpidump [signature]"),
+    }
+}
+
+fn command_clocksource(_: &[&str]) {
+    println!("{:<14} {:>8} {:>10} {:>6} {:>7}  {}", "NAME", "PRESENT", "RESOLUTION", "STABLE", "SCORE", "STATUS");
+    for source in clocksource::SOURCES {
+        let status = if clocksource::current().is_some_and(|current| core::ptr::eq(current, source)) {
+            "current"
+        } else if clocksource::is_unstable(source) {
+            "unstable"
+        } else if source.start.is_none() {
+            "no driver"
+        } else {
+            ""
+        };
+
+        println!("{:<14} {:>8} {:>8}ns {:>6} {:>7}  {status}",
+            source.name,
+            if (source.detect)() { "yes" } else { "no" },
+            source.resolution_ns,
+            if (source.is_stable)() { "yes" } else { "no" },
+            source.score(),
+        );
+    }
+}
+
+fn command_devices(_: &[&str]) {
+    fn print_children(devices: &[DeviceNode], parent: Option<DeviceId>, depth: usize) {
+        for device in devices.iter().filter(|device| device.parent == parent) {
+            println!("{:indent$}{:<width$} {:<6} {:<12} {}",
+                "",
+                device.name,
+                device.kind,
+                device.status,
+                device.description,
+                indent = depth * 2,
+                width = 32 - depth * 2,
+            );
+            for resource in &device.resources {
+                println!("{:indent$}{resource}", "", indent = depth * 2 + 2);
+            }
+            for (resource, owner) in &device.conflicts {
+                println!("{:indent$}{resource} conflicts with {}", "", owner.name(), indent = depth * 2 + 2);
+            }
+            print_children(devices, Some(device.id), depth + 1);
+        }
+    }
+
+    print_children(&registry::devices(), None, 0);
+}
+
+fn command_edit(args: &[&str]) {
+    let [name] = args else {
+        println!("Usage: edit <file>");
+        return;
+    };
+
+    if !editor::open(name) {
+        println!("The editor is already open");
+    }
+}
+
+fn command_fs(args: &[&str]) {
+    let result = match args {
+        ["mounts"] => {
+            for mount in vfs::mounts() {
+                println!("{:<20} {}", mount.path, mount.fs_name);
+            }
+            Ok(())
+        }
+
+        ["ls"] => list_directory("/"),
+        ["ls", path] => list_directory(path),
+
+        ["cat", path] => vfs::read(path).map(|data| println!("{}", String::from_utf8_lossy(&data))),
+
+        ["write", path, text @ ..] => vfs::open(path, OpenOptions::append())
+            .and_then(|mut file| file.write(alloc::format!("{}\n", text.join(" ")).as_bytes()))
+            .map(|_| ()),
+
+        ["mkdir", path] => vfs::create_dir(path),
+        ["rm", path] => vfs::remove(path),
+
+        _ => {
+            println!("Usage: fs <mounts|ls [path]|cat <path>|write <path> <text>|mkdir <path>|rm <path>>");
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        println!("fs: {e}");
+    }
+}
+
+fn list_directory(path: &str) -> Result<(), VfsError> {
+    let directory = vfs::open_dir(path)?;
+    for entry in directory.entries()? {
+        match entry.file_type {
+            FileType::Directory => println!("{:<24} {:>10}", alloc::format!("{}/", entry.name), "-"),
+            FileType::File => {
+                let size = directory.stat(&entry.name).map_or(0, |metadata| metadata.size);
+                println!("{:<24} {:>10}", entry.name, size);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn command_fat(args: &[&str]) {
+    const USAGE: &str = "Usage: fat <ls|cat <file>|write <file> <text>|alloc <file> <size>|truncate <file> <size>|rm <file>>";
+
+    let parse_size = |size: &str| size.parse::<u32>().ok();
+    let valid = match args {
+        ["alloc" | "truncate", _, size] => parse_size(size).is_some(),
+        ["ls"] | ["cat" | "rm", _] | ["write", _, ..] => true,
+        _ => false,
+    };
+    if !valid {
+        println!("{USAGE}");
+        return;
+    }
+
+    let mut volume = match FatVolume::mount_first() {
+        Ok(volume) => volume,
+        Err(e) => {
+            println!("No FAT32 file system found: {e:?}");
+            return;
+        }
+    };
+
+    let result = (|| -> Result<(), FatError> {
+        match args {
+            ["ls"] => {
+                for file in volume.files()? {
+                    println!("{:<12} {:>10}", file.name(), file.size());
+                }
+            }
+
+            ["cat", name] => {
+                let file = volume.open(name)?;
+                let mut buffer = vec![0; file.size() as usize];
+                let length = volume.read(&file, 0, &mut buffer)?;
+                println!("{}", String::from_utf8_lossy(&buffer[..length]));
+            }
+
+            ["write", name, text @ ..] => {
+                let mut file = match volume.open(name) {
+                    Err(FatError::NotFound) => volume.create(name)?,
+                    file => file?,
+                };
+                let text = alloc::format!("{}\n", text.join(" "));
+                let offset = file.size();
+                volume.write(&mut file, offset, text.as_bytes())?;
+            }
+
+            ["alloc", name, size] => {
+                let mut file = match volume.open(name) {
+                    Err(FatError::NotFound) => volume.create(name)?,
+                    file => file?,
+                };
+                let contiguous = volume.preallocate(&mut file, parse_size(size).unwrap_or_default())?;
+                println!("{} is {} bytes, {}", file.name(), file.size(), if contiguous { "contiguous" } else { "fragmented" });
+            }
+
+            ["truncate", name, size] => {
+                let mut file = volume.open(name)?;
+                volume.truncate(&mut file, parse_size(size).unwrap_or_default())?;
+            }
+
+            ["rm", name] => {
+                let file = volume.open(name)?;
+                volume.remove(file)?;
+            }
+
+            _ => unreachable!(),
+        }
+
+        volume.sync()
+    })();
+
+    if let Err(e) = result {
+        println!("fat: {e:?}");
+    }
+}
+
+fn command_fbmode(args: &[&str]) {
+    let Some((width, height)) = args.first()
+        .and_then(|mode| mode.split_once('x'))
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+    else {
+        println!("Usage: fbmode <width>x<height>");
+        return;
+    };
+
+    let buffer_len = WRITER.lock().buffer_len();
+    match bochs_vbe::set_mode(width, height, buffer_len) {
+        Ok(info) => {
+            if !Console::reconfigure(info) {
+                println!("The console doesn't fit in the new mode");
+            }
+        }
+        Err(e) => println!("Failed to switch to {width}x{height}: {e:?}"),
+    }
+}
+
+fn command_fwcfg(args: &[&str]) {
+    if !FwCfg::is_present() {
+        println!("No fw_cfg device, not running under QEMU");
+        return;
+    }
+
+    match args {
+        [] => {
+            for file in FwCfg::files() {
+                println!("{:>8} {}", file.size, file.name);
+            }
+        }
+
+        [name] => {
+            let Some(file) = FwCfg::find(name) else {
+                println!("No fw_cfg file `{name}`");
+                return;
+            };
+
+            let data = FwCfg::read(&file);
+            match core::str::from_utf8(&data) {
+                Ok(text) => println!("{text}"),
+                Err(_) => println!("<{} bytes of binary data>", data.len()),
+            }
+        }
+
+        _ => println!("Usage: fwcfg [file]"),
+    }
+}
+
+fn command_lspci(args: &[&str]) {
+    let verbose = args.contains(&"-v");
+    let mut address = None;
+
+    for arg in args.iter().filter(|arg| **arg != "-v") {
+        match arg.parse::<PciAddress>() {
+            Ok(addr) => address = Some(addr),
+            Err(()) => {
+                println!("Invalid PCI address `{arg}`, expected [segment:]bus:device.function");
+                return;
+            }
+        }
+    }
+
+    let mechanism = PciConfigurationSpace;
+
+    if let Some(addr) = address {
+        if mechanism.vendor_id(addr).value() == 0xFFFF {
+            println!("No device at {addr}");
+            return;
+        }
+
+        print_pci_device(&mechanism, addr, verbose);
+        if verbose {
+            print_pci_config_space(&mechanism, addr);
+        }
+        return;
+    }
+
+    for (addr, _, _) in mechanism.enumerate() {
+        print_pci_device(&mechanism, addr, verbose);
+    }
+}
+
+fn print_pci_device(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress, verbose: bool) {
+    let vendor_id = mechanism.vendor_id(addr);
+    let device_id = mechanism.device_id(addr);
+    let class = mechanism.class_code(addr);
+    let subclass = mechanism.subclass(addr);
+
+    println!("{addr} {} [{:02x}{:02x}]: {} {} [{:04x}:{:04x}] (rev {:02x})",
+        subclass.name(class).unwrap_or("Unknown"),
+        class.value(),
+        subclass.value(),
+        vendor_id.name().unwrap_or("Unknown vendor"),
+        device_id.name(vendor_id).unwrap_or("Unknown device"),
+        vendor_id.value(),
+        device_id.value(),
+        mechanism.revision_id(addr),
+    );
+
+    if !verbose {
+        return;
+    }
+
+    println!("    Header: {:?}, Prog IF: {:02x}", mechanism.header_type(addr), mechanism.prog_if(addr));
+    println!("    Command: {}", mechanism.decoded_command(addr));
+    println!("    Status: {}", mechanism.decoded_status(addr));
+
+    let pin = mechanism.interrupt_pin(addr);
+    if pin != 0 {
+        let pin = (b'A' + pin - 1) as char;
+        println!("    Interrupt: pin {pin} routed to IRQ {}", mechanism.interrupt_line(addr));
+    }
+
+    for bar in mechanism.bars(addr) {
+        println!("    Region {}: {bar}", bar.index);
+    }
+    if let Some(rom) = RomBar::read(mechanism, addr) {
+        println!("    {rom}");
+    }
+
+    let mut capabilities = mechanism.capabilities(addr).peekable();
+    if capabilities.peek().is_some() {
+        println!("    Capabilities:");
+    }
+
+    for capability in capabilities {
+        println!("        [{:02x}] {} ({:02x})",
+            capability.offset,
+            capability.id.name().unwrap_or("Unknown"),
+            capability.id.0,
+        );
+
+        match capability.id {
+            PciCapabilityId::MSI => if let Some(msi) = Msi::find(mechanism, addr) {
+                println!("             {msi}");
+            },
+            PciCapabilityId::MSI_X => if let Some(msi_x) = MsiX::find(mechanism, addr) {
+                println!("             {msi_x}");
+            },
+            _ => (),
+        }
+    }
+}
+
+fn print_pci_config_space(mechanism: &impl ConfigurationSpaceMechanism, addr: PciAddress) {
+    let mut data = vec![0u8; mechanism.config_space_size()];
+    mechanism.read_config_space(addr, &mut data);
+    println!("{}", HexDump::bytes(&data, 0));
+}
+
+fn command_optionrom(args: &[&str]) {
+    /// The bytes of the ROM that are dumped when it isn't saved.
+    const PREVIEW_LENGTH: usize = 128;
+
+    let (address, file) = match args {
+        [address] => (address, None),
+        [address, file] => (address, Some(*file)),
+        _ => {
+            println!("Usage: optionrom <address> [file]");
+            return;
+        }
+    };
+
+    let Ok(addr) = address.parse::<PciAddress>() else {
+        println!("Invalid PCI address `{address}`, expected [segment:]bus:device.function");
+        return;
+    };
+
+    let rom = match OptionRom::read(&PciConfigurationSpace, addr) {
+        Ok(rom) => rom,
+        Err(e) => {
+            println!("Failed to read the ROM of {addr}: {e:?}");
+            return;
+        }
+    };
+
+    println!("{}", rom.bar);
+    for (index, image) in rom.images.iter().enumerate() {
+        println!("Image {index} at {:#x}: {}, {:#x} bytes, [{:04x}:{:04x}] class {:02x}{:02x}, revision {:#x}{}",
+            image.offset,
+            image.code_type,
+            image.length,
+            image.vendor_id,
+            image.device_id,
+            image.class,
+            image.subclass,
+            image.code_revision,
+            match image.checksum_valid {
+                Some(true) => ", checksum ok",
+                Some(false) => ", checksum INVALID",
+                None => "",
+            },
+        );
+    }
+
+    let Some(name) = file else {
+        let length = rom.data.len().min(PREVIEW_LENGTH);
+        println!("{}", HexDump::bytes(&rom.data[..length], 0));
+        return;
+    };
+
+    let result = (|| -> Result<(), FatError> {
+        let mut volume = FatVolume::mount_first()?;
+        let mut file = match volume.open(name) {
+            Err(FatError::NotFound) => volume.create(name)?,
+            file => file?,
+        };
+        volume.write(&mut file, 0, &rom.data)?;
+        volume.truncate(&mut file, rom.data.len() as u32)?;
+        volume.sync()
+    })();
+
+    match result {
+        Ok(()) => println!("Saved {} bytes to {name}", rom.data.len()),
+        Err(e) => println!("Failed to save the ROM to {name}: {e:?}"),
+    }
+}
+
+fn command_hexdump(args: &[&str]) {
+    const DEFAULT_LENGTH: usize = 256;
+    const MAX_LENGTH: usize = 64 * 1024;
+
+    let (physical, args) = match args {
+        ["-p", args @ ..] => (true, args),
+        _ => (false, args),
+    };
+
+    let parse = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    let (address, length) = match args {
+        [address] => (parse(address), Some(DEFAULT_LENGTH)),
+        [address, length] => (parse(address), parse(length).map(|length| length as usize)),
+        _ => {
+            println!("Usage: hexdump [-p] <address> [length]");
+            return;
+        }
+    };
+
+    let (Some(address), Some(length)) = (address, length) else {
+        println!("Invalid address or length, expected hexadecimal numbers");
+        return;
+    };
+
+    if length > MAX_LENGTH {
+        println!("The length is limited to {MAX_LENGTH:#x} bytes");
+        return;
+    }
+
+    if physical {
+        match PhysAddr::try_new(address) {
+            Ok(address) => println!("{}", HexDump::physical(address, length)),
+            Err(_) => println!("{address:#x} isn't a valid physical address"),
+        }
+    } else {
+        match VirtAddr::try_new(address) {
+            Ok(address) => println!("{}", HexDump::virt(address, length)),
+            Err(_) => println!("{address:#x} isn't a canonical virtual address"),
+        }
+    }
+}
+
+fn command_pcap(args: &[&str]) {
+    match args {
+        [state @ ("on" | "off")] => pcap::set_enabled(*state == "on"),
+
+        ["list"] => {
+            if !pcap::is_enabled() {
+                println!("Capture is disabled, enable with `pcap on`");
+            }
+
+            for packet in pcap::packets() {
+                let direction = match packet.direction {
+                    Direction::Received => "RX",
+                    Direction::Transmitted => "TX",
+                };
+                println!("{:>10} {direction} {} bytes", packet.ticks, packet.length);
+            }
+        }
+
+        ["clear"] => pcap::clear(),
+
+        ["dump", sink] => {
+            let sink = match *sink {
+                "serial" => CaptureSink::Serial,
+                "debugcon" => CaptureSink::Debugcon,
+                _ => {
+                    println!("Usage: pcap dump <serial|debugcon>");
+                    return;
+                }
+            };
+
+            let count = pcap::dump(sink);
+            println!("Dumped {count} packets");
+        }
+
+        _ => println!("Usage: pcap <on|off|list|clear|dump <serial|debugcon>>"),
+    }
+}
+
+/// The EtherType for local experiments, which the test frames use.
+const TEST_ETHER_TYPE: u16 = 0x88B5;
+
+fn command_nic(args: &[&str]) {
+    let index = match args {
+        [] => {
+            if intel_8254x::device_count() == 0 {
+                println!("No network cards");
+            }
+
+            for index in 0..intel_8254x::device_count() {
+                intel_8254x::with_device(index, |nic| {
+                    println!("{index}: {} {} link {}, {} frames missed",
+                        nic.device().name(), nic.mac_address(), nic.link_status(), nic.take_missed_frames());
+                    match nic.interrupt() {
+                        Some(interrupt) => println!("   Interrupts: {interrupt}"),
+                        None => println!("   Interrupts: none, polling"),
+                    }
+                });
+            }
+            return;
+        }
+
+        [_, index] => match index.parse::<usize>() {
+            Ok(index) if index < intel_8254x::device_count() => index,
+            _ => {
+                println!("No network card {index}, see `nic`");
+                return;
+            }
+        },
+
+        _ => {
+            println!("Usage: nic [send <index>|receive <index>]");
+            return;
+        }
+    };
+
+    match args[0] {
+        "send" => {
+            let result = intel_8254x::with_device(index, |nic| {
+                let mut frame = Vec::new();
+                frame.extend_from_slice(&MacAddress::BROADCAST.0);
+                frame.extend_from_slice(&nic.mac_address().0);
+                frame.extend_from_slice(&TEST_ETHER_TYPE.to_be_bytes());
+                frame.extend_from_slice(b"nocciolo test frame");
+                nic.send_frame(&frame)
+            });
+
+            match result {
+                Some(Ok(())) => println!("Sent a test frame"),
+                Some(Err(e)) => println!("Failed to send: {e}"),
+                None => println!("The card is gone"),
+            }
+        }
+
+        "receive" => {
+            let frames: Vec<Vec<u8>> = intel_8254x::with_device(index, |nic| core::iter::from_fn(|| nic.receive_frame()).collect())
+                .unwrap_or_default();
+            for frame in &frames {
+                let address = |offset: usize| MacAddress(frame[offset..offset + 6].try_into().unwrap());
+                let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+                println!("{} -> {} type {ether_type:#06x}, {} bytes", address(6), address(0), frame.len());
+            }
+            println!("{} frames", frames.len());
+        }
+
+        _ => println!("Usage: nic [send <index>|receive <index>]"),
+    }
+}
+
+fn command_ping(args: &[&str]) {
+    const DEFAULT_COUNT: u16 = 4;
+
+    let (destination, count) = match args {
+        [destination] => (destination.parse::<Ipv4Address>(), Ok(DEFAULT_COUNT)),
+        [destination, count] => (destination.parse::<Ipv4Address>(), count.parse::<u16>()),
+        _ => {
+            println!("Usage: ping <address> [count]");
+            return;
+        }
+    };
+
+    match (destination, count) {
+        (Ok(destination), Ok(count)) => {
+            if !icmp::request_ping(destination, count) {
+                println!("Still pinging, try again later");
+            }
+        }
+        (Err(()), _) => println!("Invalid IPv4 address `{}`", args[0]),
+        (_, Err(_)) => println!("Invalid count `{}`", args[1]),
+    }
+}
+
+fn command_arp(_: &[&str]) {
+    let entries = arp::entries();
+    for (ip, mac, expires) in &entries {
+        println!("{ip:<16} {mac}  expires in {}s", expires.as_secs());
+    }
+    println!("{} entries", entries.len());
+}
+
+fn command_ip(_: &[&str]) {
+    let names = interface::names();
+    if names.is_empty() {
+        println!("No network interfaces");
+    }
+
+    for name in names {
+        interface::with_interface(&name, |interface| {
+            println!("{name}: {}", interface.mac_address());
+            match interface.ipv4_address() {
+                Some(address) => println!("  inet {address}"),
+                None => println!("  inet none"),
+            }
+            if let Some(gateway) = interface.ipv4_gateway() {
+                println!("  IPv4 gateway {gateway}");
+            }
+            for address in interface.ipv6().addresses() {
+                println!("  inet6 {address}");
+            }
+            if let Some(router) = interface.ipv6().router() {
+                println!("  IPv6 router {router}");
+            }
+        });
+    }
+
+    for server in net_config::dns_servers() {
+        println!("DNS server: {server}");
+    }
+}
+
+fn command_dhcp(_: &[&str]) {
+    let Some(lease) = net_config::lease() else {
+        println!("No lease");
+        return;
+    };
+
+    println!("{}: {} from {}, expires in {}s", lease.interface, lease.address, lease.server, lease.remaining().as_secs());
+    if let Some(gateway) = lease.gateway {
+        println!("Gateway: {gateway}");
+    }
+    for server in &lease.dns_servers {
+        println!("DNS server: {server}");
+    }
+}
+
+fn command_tcp(args: &[&str]) {
+    match args {
+        [] => (),
+        ["connect", destination, port, text @ ..] => {
+            let (Ok(destination), Ok(port)) = (destination.parse::<Ipv4Address>(), port.parse::<u16>()) else {
+                println!("Invalid address `{destination}` or port `{port}`");
+                return;
+            };
+
+            let mut text = text.join(" ");
+            text.push_str("\r\n");
+            if !tcp::request_connect(destination, port, text) {
+                println!("Still connected, try again later");
+            }
+            return;
+        }
+        _ => {
+            println!("Usage: tcp [connect <address> <port> [text]]");
+            return;
+        }
+    }
+
+    for port in tcp::listeners() {
+        println!("Listening on port {port}");
+    }
+
+    let connections = tcp::connections();
+    for ((local, local_port), (remote, remote_port), state) in &connections {
+        println!("{:<21} {:<21} {state}", alloc::format!("{local}:{local_port}"), alloc::format!("{remote}:{remote_port}"));
+    }
+    println!("{} connections", connections.len());
+}
+
+fn command_blk(args: &[&str]) {
+    const USAGE: &str = "Usage: blk [read <disk> <lba>|write <disk> <lba> <text>|flush <disk>]";
+
+    let result = match args {
+        [] => {
+            let names = block::names();
+            if names.is_empty() {
+                println!("No disks");
+            }
+
+            for name in names {
+                block::with_disk(&name, |disk| {
+                    println!("{name}: {} blocks of {} bytes{}",
+                        disk.block_count(), disk.block_size(), if disk.is_read_only() { ", read-only" } else { "" });
+                });
+            }
+            return;
+        }
+
+        ["read", name, lba] => {
+            let Ok(lba) = lba.parse::<u64>() else {
+                println!("Invalid block `{lba}`");
+                return;
+            };
+
+            block::with_disk(name, |disk| {
+                let mut block = vec![0; disk.block_size()];
+                disk.read_blocks(lba, &mut block).map(|()| println!("{}", HexDump::bytes(&block, lba * block.len() as u64)))
+            })
+        }
+
+        ["write", name, lba, text @ ..] if !text.is_empty() => {
+            let Ok(lba) = lba.parse::<u64>() else {
+                println!("Invalid block `{lba}`");
+                return;
+            };
+
+            block::with_disk(name, |disk| {
+                let mut block = vec![0; disk.block_size()];
+                let text = text.join(" ");
+                let length = text.len().min(block.len());
+                block[..length].copy_from_slice(&text.as_bytes()[..length]);
+                disk.write_blocks(lba, &block).map(|()| println!("Wrote {length} bytes to block {lba}"))
+            })
+        }
+
+        ["flush", name] => block::with_disk(name, |disk| disk.flush()),
+
+        _ => {
+            println!("{USAGE}");
+            return;
+        }
+    };
+
+    match result {
+        Some(Ok(())) => (),
+        Some(Err(e)) => println!("Failed: {e}"),
+        None => println!("No such disk, see `blk`"),
+    }
+}
+
+fn command_heap(_: &[&str]) {
+    let stats = allocator::statistics();
+    println!("Heap: {} of {} KiB used, {} KiB free, largest free block {} KiB, {}% fragmented",
+        stats.used / 1024,
+        stats.size / 1024,
+        stats.free / 1024,
+        stats.largest_free_block / 1024,
+        stats.fragmentation(),
+    );
+
+    println!("{:<8} {:>10} {:>8} {:>8}", "SIZE", "ALLOCS", "LIVE", "CACHED");
+    for (idx, size) in BLOCK_SIZES.iter().enumerate() {
+        println!("{:<8} {:>10} {:>8} {:>8}", size, stats.allocations[idx], stats.live[idx], stats.free_blocks[idx]);
+    }
+
+    let larger = BLOCK_SIZES.len();
+    println!("{:<8} {:>10} {:>8} {:>8}", alloc::format!(">{}", BLOCK_SIZES[larger - 1]), stats.allocations[larger], stats.live[larger], "-");
+}
+
+fn command_statusbar(args: &[&str]) {
+    match args {
+        [] => println!("The status bar is {}", if status_bar::is_enabled() { "on" } else { "off" }),
+        [state @ ("on" | "off")] => status_bar::set_enabled(*state == "on"),
+        _ => println!("Usage: statusbar [on|off]"),
+    }
+}
+
+fn command_coredump(_: &[&str]) {
+    let registers = CoreRegisters::capture();
+    let rsp = x86_64::VirtAddr::new(registers.rsp);
+    let Some(stack) = stack::stacks().find(|stack| stack.contains(rsp)) else {
+        println!("The stack we're running on isn't registered");
+        return;
+    };
+
+    // Copied first, since writing the dump uses the stack below us.
+    let data = unsafe { core::slice::from_raw_parts(rsp.as_ptr::<u8>(), (stack.top() - rsp) as usize) }.to_vec();
+
+    let dump = CoreDump {
+        name: "shell",
+        pid: 1,
+        signal: 0,
+        registers,
+        segments: vec![Segment {
+            virtual_address: rsp.as_u64(),
+            data: &data,
+            flags: coredump::SEGMENT_READ | coredump::SEGMENT_WRITE,
+        }],
+        build_id: None,
+    };
+
+    let size = dump.write_to_serial();
+    println!("Wrote a core dump of {} to the serial port, extract it with `cargo run core <log>`", Size(size as u64));
+}
+
+fn command_selftest(_: &[&str]) {
+    let failures = selftest::run_all(&mut |test, result| match result {
+        Ok(()) => println!("\x1b[32mPASS\x1b[0m {}", test.name),
+        Err(reason) => println!("\x1b[31mFAIL\x1b[0m {}: {reason}", test.name),
+    });
+
+    println!("{} of {} tests passed", selftest::TESTS.len() - failures, selftest::TESTS.len());
+}
+
+fn command_bench(args: &[&str]) {
+    let benchmarks: Vec<_> = bench::BENCHMARKS.iter()
+        .filter(|benchmark| args.first().map_or(true, |name| benchmark.name == *name))
+        .collect();
+    if benchmarks.is_empty() {
+        println!("Unknown benchmark, expected one of:");
+        for benchmark in bench::BENCHMARKS {
+            println!("  {:<14} {}", benchmark.name, benchmark.description);
+        }
+        return;
+    }
+
+    println!("{:<14} {:>10} {:>10} {:>10} {:>10}", "NAME", "MEAN", "STDDEV", "MIN", "MAX");
+    for benchmark in benchmarks {
+        let result = bench::run(benchmark);
+        println!("{:<14} {:>10} {:>10} {:>10} {:>10}", benchmark.name, result.mean, result.stddev, result.min, result.max);
+    }
+    println!("(cycles per iteration)");
+}
+
+fn command_counters(_: &[&str]) {
+    for counter in counters::all() {
+        println!("{:<24} {:>12}  {}", counter.name(), counter.value(), counter.description());
+    }
+}
+
+fn command_features(_: &[&str]) {
+    for feature in containment::all() {
+        let state = match feature.state() {
+            FeatureState::Unused => "unused".into(),
+            FeatureState::Available => "available".into(),
+            FeatureState::Poisoned(reason) => alloc::format!("disabled: {reason}"),
+        };
+        println!("{:<16} {:<40} {state}", feature.name(), feature.description());
+    }
+}
+
+fn command_irqstat(_: &[&str]) {
+    let frequency = clocksource::tsc_frequency();
+    let unit = if frequency == 0 { "cycles" } else { "ns" };
+    let convert = |cycles: u64| match frequency {
+        0 => cycles,
+        _ => (cycles as u128 * 1_000_000_000 / frequency as u128) as u64,
+    };
+
+    println!("{:<10} {:<8} {:>7} {:>10} {:>10} {:>10} {:>10}  (in {unit}, of the last {} samples)",
+        "IRQ", "LATENCY", "SAMPLES", "P50", "P90", "P99", "MAX", irq_latency::SAMPLES);
+    for irq in Irq::ALL {
+        let statistics = irq_latency::statistics(irq);
+        for (kind, latency) in [("handler", statistics.handler), ("wake", statistics.wake)] {
+            if latency.samples == 0 {
+                continue;
+            }
+
+            println!("{:<10} {:<8} {:>7} {:>10} {:>10} {:>10} {:>10}",
+                irq.name(), kind, latency.samples,
+                convert(latency.p50), convert(latency.p90), convert(latency.p99), convert(latency.max));
+        }
+    }
+}
+
+fn command_top(_: &[&str]) {
+    let now = cpu_usage::snapshot();
+    let usage = Usage::between(&TOP_SNAPSHOT.lock().replace(now.clone()).unwrap_or_default(), &now);
+
+    let frequency = clocksource::tsc_frequency();
+    let period = usage.total / arch::cpu_count() as u64;
+    match frequency {
+        0 => println!("CPU usage of the last {period} cycles:"),
+        _ => println!("CPU usage of the last {} ms:", period as u128 * 1000 / frequency as u128),
+    }
+
+    println!("{:<20} {:>7}", "idle", usage.share(usage.idle));
+    println!("{:<20} {:>7}", "other", usage.share(usage.other_cycles()));
+    for (name, cycles) in &usage.tasks {
+        println!("{:<20} {:>7}", alloc::format!("task {name}"), usage.share(*cycles));
+    }
+    for (irq, cycles) in &usage.irqs {
+        println!("{:<20} {:>7}", alloc::format!("irq {}", irq.name()), usage.share(*cycles));
+    }
+}
+
+fn command_uptime(_: &[&str]) {
+    let ticks = arch::ticks();
+    let seconds = ticks / arch::TICKS_PER_SECOND;
+    println!("Up {} days, {}:{:02}:{:02} ({ticks} ticks at {} Hz)",
+        seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60, arch::TICKS_PER_SECOND);
+}
+
+fn command_rngtest(_: &[&str]) {
+    /// The bits of the FIPS 140-2 monobit test, and the number of ones that
+    /// passes it.
+    const MONOBIT_BITS: usize = 20000;
+    const MONOBIT_PASS: core::ops::RangeInclusive<u32> = 9726..=10274;
+
+    let sources = entropy::sources();
+    let total: u64 = sources.iter().map(|source| source.credited_bits()).sum();
+
+    println!("{:<12} {:<8} {:>10} {:>12} {:>7}  {}", "SOURCE", "STATE", "SAMPLES", "CREDITED", "SHARE", "DESCRIPTION");
+    for source in &sources {
+        println!("{:<12} {:<8} {:>10} {:>12} {:>7}  {}",
+            source.name(),
+            source.state().name(),
+            source.samples(),
+            alloc::format!("{} bits", source.credited_bits()),
+            Share(source.credited_bits() * 1000 / total.max(1)),
+            source.description(),
+        );
+    }
+
+    let pool = entropy::statistics();
+    println!("Pool: {}, {} reseeds, {} bits pending",
+        if pool.seeded { "seeded" } else { "not seeded" },
+        pool.reseeds,
+        pool.pending_bits,
+    );
+
+    let mut output = [0u8; MONOBIT_BITS / 8];
+    entropy::fill(&mut output);
+    let ones: u32 = output.iter().map(|byte| byte.count_ones()).sum();
+    println!("Monobit test of {MONOBIT_BITS} bits: {ones} ones, {}",
+        if MONOBIT_PASS.contains(&ones) { "passed" } else { "FAILED" });
+}
+
+fn command_memperf(_: &[&str]) {
+    const SIZE: usize = 4 * 1024 * 1024;
+    const ROUNDS: u64 = 8;
+
+    fn measure(mut f: impl FnMut()) -> u64 {
+        // Warm up the caches and the TLB first.
+        f();
+
+        let start = arch::cycles();
+        for _ in 0..ROUNDS {
+            f();
+        }
+        (arch::cycles() - start) / ROUNDS
+    }
+
+    let mut dst = vec![0u8; SIZE];
+    let src = vec![0x5Au8; SIZE];
+
+    println!("Buffer of {}, enhanced REP MOVSB/STOSB: {}",
+        Size(SIZE as u64),
+        if arch::string::has_enhanced_rep_movsb() { "yes" } else { "no" },
+    );
+    println!("{:<8} {:>14} {:>14}", "", "BUILTIN", "ARCH");
+    println!("{:<8} {:>14} {:>14}", "fill",
+        measure(|| dst.fill(0x11)),
+        measure(|| arch::string::fill(&mut dst, 0x22)),
+    );
+    println!("{:<8} {:>14} {:>14}", "copy",
+        measure(|| dst.copy_from_slice(&src)),
+        measure(|| arch::string::copy(&mut dst, &src)),
+    );
+    println!("(cycles per {}, average of {ROUNDS} rounds)", Size(SIZE as u64));
+}
+
+fn command_meminfo(_: &[&str]) {
+    let regions = memory_map::regions();
+    let total = |kind: RegionKind| regions.iter()
+        .filter(|region| region.kind == kind)
+        .map(|region| region.end - region.start)
+        .sum::<u64>();
+
+    // The allocated and reserved frames are carved out of the usable memory.
+    let usable = total(RegionKind::Usable);
+    let allocated = total(RegionKind::Allocated);
+    let reserved = total(RegionKind::Reserved);
+    println!("Physical: {} usable, {} allocated, {} reserved, {} free",
+        Size(usable), Size(allocated), Size(reserved), Size(usable.saturating_sub(allocated + reserved)));
+    println!("Kernel image: {}, memory-mapped I/O: {}", Size(total(RegionKind::Kernel)), Size(total(RegionKind::Mmio)));
+
+    let heap = allocator::statistics();
+    println!("Heap: {} used of {}, largest free block {}",
+        Size(heap.used as u64), Size(heap.size as u64), Size(heap.largest_free_block as u64));
+}
+
+fn command_memmap(args: &[&str]) {
+    const COLUMNS: u64 = 64;
+    const ROWS: u64 = 16;
+
+    let regions = memory_map::regions();
+
+    let (list, args) = match args {
+        ["-l", args @ ..] => (true, args),
+        _ => (false, args),
+    };
+
+    let parse = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    let (start, end) = match args {
+        [] => (0, regions.iter().map(|region| region.end).max().unwrap_or(0)),
+        [start, end] => match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => {
+                println!("Invalid range, expected two hexadecimal addresses");
+                return;
+            }
+        },
+        _ => {
+            println!("Usage: memmap [-l] [start end]");
+            return;
+        }
+    };
+
+    if list {
+        println!("{:<18} {:<18} {:>10} {:<10} {}", "START", "END", "SIZE", "KIND", "NAME");
+        for region in regions.iter().filter(|region| region.overlaps(start, end)) {
+            println!("{:#018x} {:#018x} {:>10} {:<10} {}",
+                region.start, region.end, Size(region.end - region.start), region.kind.name(), region.name);
+        }
+        return;
+    }
+
+    // Every character is a cell of the range, showing the most important
+    // kind of region in it.
+    let cell_size = (end - start).div_ceil(COLUMNS * ROWS).max(1);
+    for row in 0..ROWS {
+        let row_start = start + row * COLUMNS * cell_size;
+        if row_start >= end {
+            break;
+        }
+
+        print!("{row_start:#012x} ");
+        let mut current = None;
+        for column in 0..COLUMNS {
+            let cell_start = row_start + column * cell_size;
+            let kind = regions.iter()
+                .filter(|region| region.overlaps(cell_start, cell_start + cell_size))
+                .map(|region| region.kind)
+                .max();
+
+            if kind != current {
+                print!("{}", memmap_color(kind));
+                current = kind;
+            }
+            print!("{}", memmap_symbol(kind));
+        }
+        println!("\x1b[0m");
+    }
+
+    println!("Each cell is {}", Size(cell_size));
+    for kind in RegionKind::ALL {
+        let total: u64 = regions.iter()
+            .filter(|region| region.kind == kind)
+            .map(|region| region.end.min(end).saturating_sub(region.start.max(start)))
+            .sum();
+        println!("{}{}\x1b[0m {:<10} {:>10}", memmap_color(Some(kind)), memmap_symbol(Some(kind)), kind.name(), Size(total));
+    }
+
+    for (claimed, usable) in memory_map::conflicts(&regions) {
+        println!("Warning: {} {} ({:#x}..{:#x}) overlaps usable memory ({:#x}..{:#x})",
+            claimed.kind.name(), claimed.name, claimed.start, claimed.end, usable.start, usable.end);
+    }
+}
+
+fn memmap_symbol(kind: Option<RegionKind>) -> char {
+    match kind {
+        None => ' ',
+        Some(RegionKind::Usable) => '.',
+        Some(RegionKind::Firmware) => 'F',
+        Some(RegionKind::Bootloader) => 'B',
+        Some(RegionKind::Allocated) => '#',
+        Some(RegionKind::Reserved) => 'R',
+        Some(RegionKind::Kernel) => 'K',
+        Some(RegionKind::Acpi) => 'A',
+        Some(RegionKind::Mmio) => 'M',
+    }
+}
+
+fn memmap_color(kind: Option<RegionKind>) -> &'static str {
+    match kind {
+        None => "\x1b[0m",
+        Some(RegionKind::Usable) => "\x1b[32m",
+        Some(RegionKind::Firmware) => "\x1b[34m",
+        Some(RegionKind::Bootloader) => "\x1b[36m",
+        Some(RegionKind::Allocated) => "\x1b[33m",
+        Some(RegionKind::Reserved) => "\x1b[91m",
+        Some(RegionKind::Kernel) => "\x1b[35m",
+        Some(RegionKind::Acpi) => "\x1b[31m",
+        Some(RegionKind::Mmio) => "\x1b[37m",
+    }
+}
+
+/// A size in bytes, formatted with a binary unit.
+struct Size(u64);
+
+impl core::fmt::Display for Size {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+        let mut value = self.0;
+        let mut unit = 0;
+        while value >= 1024 && value % 1024 == 0 && unit < UNITS.len() - 1 {
+            value /= 1024;
+            unit += 1;
+        }
+
+        // Pad the combined string, since the callers align it.
+        f.pad(&alloc::format!("{value} {}", UNITS[unit]))
+    }
+}
+
+fn command_ports(args: &[&str]) {
+    if !port::is_enabled() {
+        println!("Port auditing is disabled, boot with portaudit=count or portaudit=log");
+        return;
+    }
+
+    match args {
+        [] => {
+            println!("{:<6} {:<6} {:>10} {:>10}", "PORT", "USER", "READS", "WRITES");
+            for entry in port::statistics() {
+                println!("{:04x}   {:<6} {:>10} {:>10}",
+                    entry.port(),
+                    entry.user().map_or("?", |user| user.name()),
+                    entry.reads(),
+                    entry.writes(),
+                );
+            }
+        }
+
+        ["reset"] => port::reset(),
+
+        _ => println!("Usage: ports [reset]"),
+    }
+}
+
+fn command_stacks(_: &[&str]) {
+    println!("{:<24} {:>10} {:>10} {:>6}", "STACK", "SIZE", "MAX USED", "USED");
+    for stack in stack::stacks() {
+        let used = stack.max_usage();
+        println!("{:<24} {:>10} {:>10} {:>5}%", stack.name(), stack.size(), used, used * 100 / stack.size());
+    }
+}
+
+fn command_threads(_: &[&str]) {
+    let stacks: Vec<_> = stack::stacks().collect();
+
+    println!("{:>4} {:<20} {:<9} {:>10} {:>10}", "ID", "THREAD", "STATE", "SWITCHES", "MAX USED");
+    for thread in scheduler::threads() {
+        let used = thread.stack
+            .and_then(|bottom| stacks.iter().find(|stack| stack.contains(bottom)))
+            .map_or(String::from("-"), |stack| alloc::format!("{}", stack.max_usage()));
+        println!("{:>4} {:<20} {:<9} {:>10} {:>10}", thread.id.as_u64(), thread.name, thread.state.name(), thread.switches, used);
+    }
+}
+
+fn command_run(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: run <path>");
+        return;
+    };
+
+    match process::spawn(path) {
+        Ok(id) => println!("Started process {}", id.as_u64()),
+        Err(e) => println!("Failed to run {path}: {e}"),
+    }
+}
+
+fn command_ps(_: &[&str]) {
+    println!("{:>4} {:>6} {:<30} {}", "ID", "THREAD", "PATH", "STATE");
+    for process in process::processes() {
+        let thread = process.thread.map_or(String::from("-"), |thread| alloc::format!("{}", thread.as_u64()));
+        let state = match process.state {
+            ProcessState::Running => String::from("running"),
+            ProcessState::Ended(exit) => alloc::format!("{exit}"),
+        };
+        println!("{:>4} {:>6} {:<30} {state}", process.id.as_u64(), thread, process.path);
+    }
+}
+
+fn command_bochs(args: &[&str]) {
+    let sent = match args {
+        ["break"] => BochsDebugger::enter_debugger(),
+        ["itrace", state @ ("on" | "off")] => BochsDebugger::set_instruction_trace(*state == "on"),
+        ["rtrace", state @ ("on" | "off")] => BochsDebugger::set_register_trace(*state == "on"),
+        _ => {
+            println!("Usage: bochs <break|itrace|rtrace> [on|off]");
+            return;
+        }
+    };
+
+    if !sent {
+        println!("Not running under Bochs");
+    }
+}
+
+fn command_console(args: &[&str]) {
+    match args {
+        [] => {
+            for route in ConsoleRoute::ALL {
+                let marker = if route == Console::route() { '*' } else { ' ' };
+                println!("{marker} {}", route.name());
+            }
+        }
+
+        [name] => match ConsoleRoute::from_name(name) {
+            Some(route) => Console::set_route(route),
+            None => println!("Unknown console `{name}`"),
+        },
+
+        _ => println!("Usage: console [fb|serial|both]"),
+    }
+}
+
+fn command_focus(args: &[&str]) {
+    match args {
+        [] => {
+            for consumer in Consumer::ALL {
+                let marker = if consumer == focus::current() { '*' } else { ' ' };
+                let state = if focus::is_open(consumer) { "open" } else { "closed" };
+                println!("{marker} {:<12} {state}", consumer.name());
+            }
+        }
+
+        [name] => match Consumer::from_name(name) {
+            Some(consumer) if consumer.is_overlay() => println!("The {name} takes the focus with its own hotkey"),
+            Some(consumer) if !focus::is_open(consumer) => println!("The {name} isn't reading input"),
+            Some(consumer) => focus::switch(consumer),
+            None => println!("Unknown consumer `{name}`"),
+        },
+
+        _ => println!("Usage: focus [consumer]"),
+    }
+}
+
+fn command_keymap(args: &[&str]) {
+    match args {
+        [] => {
+            for layout in Layout::ALL {
+                let marker = if layout == keymap::layout() { '*' } else { ' ' };
+                println!("{marker} {:<8} {}", layout.name(), layout.description());
+            }
+
+            for remap in keymap::remaps() {
+                println!("  {remap}");
+            }
+        }
+
+        ["map", remap] => match Remap::parse(remap).and_then(keymap::remap) {
+            Ok(()) => println!("Remapped until reboot, use `set {}` to keep it", config::KEY_KEYBOARD_REMAP),
+            Err(e) => println!("Invalid remapping `{remap}`: {e}"),
+        },
+
+        ["reset"] => {
+            _ = keymap::set_remaps("");
+        }
+
+        [name] => match Layout::from_name(name) {
+            Some(layout) => keymap::set_layout(layout),
+            None => println!("Unknown layout `{name}`, see `keymap`"),
+        },
+
+        _ => println!("Usage: keymap [layout|map <scancode>=<chars>|reset]"),
+    }
+}
+
+fn command_macro(args: &[&str]) {
+    match args {
+        ["record", name] => match macros::start_recording(name) {
+            Ok(()) => println!("Recording `{name}`, finish with `macro stop`"),
+            Err(e) => println!("Failed to start recording: {e:?}"),
+        },
+
+        ["stop"] => match macros::stop_recording() {
+            Ok((name, count)) => println!("Saved `{name}` ({count} scancodes)"),
+            Err(MacroError::NotRecording) => println!("Not recording"),
+            Err(e) => println!("Failed to save the recording: {e:?}"),
+        },
+
+        ["play", name] => {
+            if macros::is_recording() {
+                println!("Can't play a macro while recording");
+                return;
+            }
+
+            if let Err(e) = macros::play(name) {
+                println!("Failed to play `{name}`: {e:?}");
+            }
+        }
+
+        ["list"] => {
+            for name in macros::list() {
+                println!("{name}");
+            }
+        }
+
+        _ => println!("Usage: macro <record <name>|stop|play <name>|list>"),
+    }
+}
+
+fn command_reboot(args: &[&str]) {
+    confirm(args, "Reboot the machine?", System::request_reboot);
+}
+
+fn command_shutdown(args: &[&str]) {
+    confirm(args, "Power off the machine?", System::request_shutdown);
+}
+
+/// Ask the user to confirm the action, unless `-y` was passed or we're in CI
+/// mode. The next line answers the question.
+fn confirm(args: &[&str], question: &str, action: fn()) {
+    if args.contains(&"-y") || System::is_ci_mode() {
+        action();
+        return;
+    }
+
+    print!("{question} [y/N] ");
+    *CONFIRMATION.lock() = Some(action);
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! A status bar at the top of the framebuffer console, showing the uptime, the
+//! heap usage, the rate of interrupts and the keyboard layout. It's off by
+//! default, and toggled with the `statusbar` shell command.
+//!
+//! The `status-bar` task redraws it every second, into a buffer that is copied
+//! to the framebuffer at once (see [`Writer::draw_buffered`]). It never waits
+//! for the console: when the writer is busy, the bar is drawn the next second.
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    allocator,
+    arch,
+    meta::counters,
+    vga_text_buffer::{Color, Writer, WRITER},
+};
+
+use super::{editor, inspector, keyboard, timer};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+const PADDING: usize = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Show or hide the status bar, which moves the text of the console.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+
+    let mut writer = WRITER.lock();
+    let height = if enabled { height(&writer) } else { 0 };
+    writer.reserve_top(height);
+}
+
+/// The task that draws the status bar.
+pub async fn run() {
+    let mut buffer = Vec::new();
+    let mut last = (arch::ticks(), interrupts());
+
+    loop {
+        timer::sleep(INTERVAL).await;
+
+        let now = (arch::ticks(), interrupts());
+        let elapsed_ticks = (now.0 - last.0).max(1) as u64;
+        let rate = (now.1 - last.1) * arch::TICKS_PER_SECOND as u64 / elapsed_ticks;
+        last = now;
+
+        // The overlay of the inspector covers the top of the screen as well,
+        // and the editor the whole screen.
+        if !is_enabled() || inspector::is_visible() || editor::is_open() {
+            continue;
+        }
+
+        let text = text(now.0, rate);
+        if let Some(mut writer) = WRITER.try_lock() {
+            draw(&mut writer, &text, &mut buffer);
+        }
+    }
+}
+
+fn height(writer: &Writer) -> usize {
+    writer.text_height() + PADDING * 2
+}
+
+/// The number of interrupts of the devices so far, which are counted by the
+/// `irq.` counters.
+fn interrupts() -> u64 {
+    counters::all().iter()
+        .filter(|counter| counter.name().starts_with("irq."))
+        .map(|counter| counter.value())
+        .sum()
+}
+
+fn text(ticks: usize, interrupt_rate: u64) -> String {
+    let seconds = ticks / arch::TICKS_PER_SECOND;
+    let heap = allocator::statistics();
+
+    let mut text = String::new();
+    _ = write!(text, "up {}:{:02}:{:02} | heap {}/{} KiB | {} irq/s | {}",
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+        heap.used / 1024, heap.size / 1024,
+        interrupt_rate,
+        keyboard::layout_name());
+    text
+}
+
+fn draw(writer: &mut Writer, text: &str, buffer: &mut Vec<u8>) {
+    if !writer.is_available() {
+        return;
+    }
+
+    // The font size might have changed.
+    let height = height(writer);
+    if writer.reserved_top() != height {
+        writer.reserve_top(height);
+    }
+
+    let width = writer.width();
+    writer.draw_buffered(buffer, height, |writer| {
+        writer.fill_rect(0, 0, width, height, Color::Black);
+        writer.draw_str_at(PADDING, PADDING, text, Color::LightCyan);
+        writer.fill_rect(0, height - 1, width, 1, Color::DarkGray);
+    });
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Timer futures, woken by the timer interrupt instead of polling the tick
+//! count:
+//!
+//! | Function     | Use                                                          |
+//! |--------------|--------------------------------------------------------------|
+//! | [`sleep`]    | Wait for a duration                                          |
+//! | [`timeout`]  | Give up on a future after a duration, e.g. a ping reply      |
+//! | [`interval`] | Run something periodically without drifting, e.g. retries   |
+//!
+//! Dropping a timer, e.g. because the [`timeout`] future completed first,
+//! unregisters it, so the timer interrupt doesn't wake the task for nothing.
+
+use alloc::vec::Vec;
+use core::{future::Future, pin::{pin, Pin}, task::{Context, Poll, Waker}, time::Duration};
+
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+use crate::{arch, meta::irq_latency::{self, Irq}};
+
+/// The wakers of the pending [`Sleep`]s with their deadline in ticks. Locked
+/// with interrupts disabled, since the timer interrupt wakes them.
+static SLEEPERS: Mutex<Vec<(usize, Waker)>> = Mutex::new(Vec::new());
+
+/// A future that completes after a duration, see [`sleep`].
+pub struct Sleep {
+    deadline: usize,
+
+    /// The waker registered in [`SLEEPERS`], which is removed when dropped.
+    waker: Option<Waker>,
+}
+
+/// The [`timeout`] passed before the future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Wait for (at least) the given duration, rounded up to the next tick.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(arch::ticks() + duration_to_ticks(duration))
+}
+
+/// Wait until the tick count reaches the deadline.
+pub fn sleep_until(deadline: usize) -> Sleep {
+    Sleep { deadline, waker: None }
+}
+
+fn duration_to_ticks(duration: Duration) -> usize {
+    (duration.as_millis() as usize * arch::TICKS_PER_SECOND).div_ceil(1000)
+}
+
+impl Sleep {
+    /// The tick count at which the sleep completes.
+    pub fn deadline(&self) -> usize {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        arch::without_interrupts(|| {
+            if arch::ticks() >= self.deadline {
+                irq_latency::consumed(Irq::Timer);
+                return Poll::Ready(());
+            }
+
+            // Don't register again when polled spuriously, unless the timer
+            // interrupt already took the waker.
+            let mut sleepers = SLEEPERS.lock();
+            let registered = sleepers.iter()
+                .any(|(deadline, waker)| *deadline == self.deadline && waker.will_wake(cx.waker()));
+            if !registered {
+                sleepers.push((self.deadline, cx.waker().clone()));
+                drop(sleepers);
+                self.waker = Some(cx.waker().clone());
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let Some(registered) = self.waker.take() else {
+            return;
+        };
+
+        arch::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            if let Some(index) = sleepers.iter()
+                .position(|(deadline, waker)| *deadline == self.deadline && waker.will_wake(&registered)) {
+                sleepers.swap_remove(index);
+            }
+        });
+    }
+}
+
+/// Run the future until it completes, or until the duration passed, in which
+/// case the future is dropped.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut deadline = sleep(duration);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut deadline).poll(cx).map(|()| Err(Elapsed))
+    }).await
+}
+
+/// Ticks every period, see [`interval`].
+pub struct Interval {
+    period: usize,
+    next: usize,
+}
+
+/// Tick every period from now, the first time after one period. The ticks
+/// are at fixed times, so the time it takes to handle one doesn't add up,
+/// and the ticks that were missed are skipped instead of coming in a burst.
+pub fn interval(period: Duration) -> Interval {
+    let period = duration_to_ticks(period).max(1);
+    Interval {
+        period,
+        next: arch::ticks() + period,
+    }
+}
+
+impl Interval {
+    /// Wait for the next tick.
+    pub async fn tick(&mut self) {
+        sleep_until(self.next).await;
+
+        let late = arch::ticks() - self.next;
+        self.next += (late / self.period + 1) * self.period;
+    }
+}
+
+/// Whether a sleep with the deadline is registered, for the self-tests.
+pub(crate) fn is_registered(deadline: usize) -> bool {
+    arch::without_interrupts(|| SLEEPERS.lock().iter().any(|(registered, _)| *registered == deadline))
+}
+
+/// Called by the timer interrupt handler with the new tick count, to wake the
+/// sleepers of which the deadline passed.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_tick(ticks: usize) {
+    // The list is being modified by the interrupted code, try again next tick.
+    let Some(mut sleepers) = SLEEPERS.try_lock() else {
+        return;
+    };
+
+    let mut idx = 0;
+    while idx < sleepers.len() {
+        if sleepers[idx].0 <= ticks {
+            let (_, waker) = sleepers.swap_remove(idx);
+            waker.wake();
+            irq_latency::woke_consumer(Irq::Timer);
+        } else {
+            idx += 1;
+        }
+    }
+}
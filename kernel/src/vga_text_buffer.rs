@@ -1,14 +1,25 @@
-use core::{default, fmt, ptr::slice_from_raw_parts_mut};
+use alloc::{string::String, vec::Vec};
+use core::{default, fmt, ptr::slice_from_raw_parts_mut, str::FromStr, sync::atomic::{AtomicBool, Ordering}};
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
 use lazy_static::lazy_static;
 use noto_sans_mono_bitmap::{FontWeight, RasterHeight, RasterizedChar};
 
-use crate::serial_println;
+use crate::{serial_println, sync::IrqSpinlock};
 
 static EMPTY: &[u8] = &[];
 
+/// The number of bytes of printed text that are kept to draw again, which is
+/// a couple of screens.
+const HISTORY_CAPACITY: usize = 16 * 1024;
+
+/// Set while printing to the framebuffer. If printing panics, the panic
+/// handler logs while the writer is still locked, so the framebuffer is
+/// skipped from then on, instead of deadlocking (the log still reaches the
+/// serial port).
+static RENDERING: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
-    pub static ref WRITER: spin::Mutex<Writer> = spin::Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
         info: FrameBufferInfo {
             byte_len: 0,
             width: 0,
@@ -23,6 +34,11 @@ lazy_static! {
         framebuffer: unsafe { &mut *slice_from_raw_parts_mut(EMPTY.as_ptr() as *mut _, 0) },
         color: Color::White,
         state: Default::default(),
+        font_size: FontSize::Normal,
+        bold: false,
+        history: History::new(),
+        reserved_top: 0,
+        held: false,
     });
 }
 
@@ -75,28 +91,63 @@ impl Color {
 use noto_sans_mono_bitmap::get_raster_width;
 
 
-fn get_char_raster(c: char) -> RasterizedChar {
-    fn get(c: char) -> Option<RasterizedChar> {
-        noto_sans_mono_bitmap::get_raster(
-            c,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
-    }
+fn get_char_raster(c: char, weight: FontWeight, height: RasterHeight) -> RasterizedChar {
+    let get = |c| noto_sans_mono_bitmap::get_raster(c, weight, height);
     get(c).unwrap_or_else(|| get(font_constants::BACKUP_CHAR).expect("Should get raster of backup char."))
 }
 
 mod font_constants {
-    use super::*;
-
     pub const LINE_SPACING: usize = 2;
     pub const LETTER_SPACING: usize = 0;
     pub const BORDER_PADDING: usize = 1;
 
-    pub const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
-    pub const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
     pub const BACKUP_CHAR: char = '�';
-    pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+}
+
+/// The sizes of the console font, of which the names refer to the height in
+/// pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontSize {
+    Normal,
+    Large,
+    Huge,
+}
+
+impl FontSize {
+    #[must_use]
+    pub const fn raster_height(&self) -> RasterHeight {
+        match self {
+            Self::Normal => RasterHeight::Size16,
+            Self::Large => RasterHeight::Size24,
+            Self::Huge => RasterHeight::Size32,
+        }
+    }
+
+    /// Width of a character cell, which is the same for every weight, since
+    /// the font is monospaced.
+    #[must_use]
+    pub const fn char_width(&self) -> usize {
+        get_raster_width(FontWeight::Regular, self.raster_height())
+    }
+
+    #[must_use]
+    pub const fn char_height(&self) -> usize {
+        self.raster_height().val()
+    }
+}
+
+/// Parses the height in pixels: `16`, `24` or `32`.
+impl FromStr for FontSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(Self::Normal),
+            "24" => Ok(Self::Large),
+            "32" => Ok(Self::Huge),
+            _ => Err(()),
+        }
+    }
 }
 
 pub struct Writer {
@@ -107,6 +158,82 @@ pub struct Writer {
     y_pos: usize,
     color: Color,
     state: WriterState,
+    font_size: FontSize,
+    bold: bool,
+    history: History,
+
+    /// The rows of pixels at the top that are kept free of text, see
+    /// [`Writer::reserve_top`].
+    reserved_top: usize,
+
+    /// Whether the printed text is only kept, see [`Writer::hold`].
+    held: bool,
+}
+
+/// The text printed to the console, so it can be drawn again after the
+/// framebuffer is reconfigured. A fixed-size ring, since printing mustn't
+/// allocate.
+struct History {
+    bytes: [u8; HISTORY_CAPACITY],
+    head: usize,
+    wrapped: bool,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; HISTORY_CAPACITY],
+            head: 0,
+            wrapped: false,
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.bytes[self.head] = byte;
+            self.head += 1;
+            if self.head == HISTORY_CAPACITY {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+    }
+
+    /// Remove the last character, unless it ends a line, like a backspace.
+    fn pop_char(&mut self) {
+        let mut index = self.head;
+        loop {
+            index = match index.checked_sub(1) {
+                Some(index) => index,
+                None if self.wrapped => HISTORY_CAPACITY - 1,
+                None => return,
+            };
+
+            if self.bytes[index] == b'\n' {
+                return;
+            }
+
+            // Continuation bytes of UTF-8 are `10xxxxxx`.
+            if self.bytes[index] & 0xC0 != 0x80 {
+                break;
+            }
+        }
+
+        self.head = index;
+    }
+
+    /// The text in chronological order. Once wrapped, the oldest line is cut
+    /// off, so it starts at the next line.
+    fn text(&self) -> String {
+        let mut bytes = Vec::with_capacity(HISTORY_CAPACITY);
+        if self.wrapped {
+            let oldest = &self.bytes[self.head..];
+            let start = oldest.iter().position(|byte| *byte == b'\n').map_or(oldest.len(), |index| index + 1);
+            bytes.extend_from_slice(&oldest[start..]);
+        }
+        bytes.extend_from_slice(&self.bytes[..self.head]);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -118,6 +245,7 @@ enum WriterState {
     SecondCode(char),
     Finishing(char, char),
     Color(Color),
+    Bold,
 }
 
 impl WriterState {
@@ -144,7 +272,7 @@ impl WriterState {
             }
 
             Self::FirstCode => {
-                if ch == '0' {
+                if ch == '0' || ch == '1' {
                     *self = Self::Finishing(ch, ch);
                 } else {
                     *self = Self::SecondCode(ch);
@@ -168,6 +296,11 @@ impl WriterState {
                     return false;
                 }
 
+                if *first == '1' {
+                    *self = Self::Bold;
+                    return false;
+                }
+
                 if *first != '3' {
                     *self = Self::Normal;
                     return false;
@@ -191,7 +324,7 @@ impl WriterState {
                 false
             }
 
-            Self::Color(..) => true,
+            Self::Color(..) | Self::Bold => true,
         }
     }
 }
@@ -206,6 +339,123 @@ impl Writer {
         self.clear();
     }
 
+    /// Switch to another mode of the same framebuffer, e.g. after a display
+    /// driver changed the resolution, and draw the last lines of the text
+    /// that fit again, wrapped to the new width. Returns `false` when the
+    /// mode doesn't fit in the buffer.
+    pub fn reconfigure(&mut self, info: FrameBufferInfo) -> bool {
+        if info.byte_len > self.framebuffer.len() {
+            return false;
+        }
+
+        serial_println!("FB: reconfigured to {}x{}", info.width, info.height);
+        self.info = info;
+        self.redraw_history();
+        true
+    }
+
+    /// Keep the given number of rows of pixels at the top of the screen free
+    /// of text, e.g. for the status bar, and draw the text below it again.
+    pub fn reserve_top(&mut self, height: usize) {
+        self.reserved_top = height;
+        if self.is_available() {
+            crate::arch::string::fill(self.framebuffer, 0);
+            self.redraw_history();
+        }
+    }
+
+    pub fn reserved_top(&self) -> usize {
+        self.reserved_top
+    }
+
+    /// Stop drawing the printed text, while e.g. the editor owns the screen.
+    /// The text is still kept, and drawn again by [`Writer::release`].
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    /// Clear the screen and draw the text again, after [`Writer::hold`].
+    pub fn release(&mut self) {
+        self.held = false;
+        if self.is_available() {
+            crate::arch::string::fill(self.framebuffer, 0);
+            self.redraw_history();
+        }
+    }
+
+    /// Clear the screen and draw the last lines of the text that fit.
+    fn redraw_history(&mut self) {
+        if self.held {
+            return;
+        }
+
+        self.clear();
+
+        // The attributes of the part that is cut off don't apply anymore.
+        self.color = Color::White;
+        self.bold = false;
+        self.state = WriterState::Normal;
+
+        let text = self.history.text();
+        let start = self.visible_start(&text);
+        self.render(&text[start..]);
+    }
+
+    /// Draw into the buffer instead, as the given number of rows at the top of
+    /// the screen, and copy them to the framebuffer at once afterwards, so
+    /// they don't flicker while they're drawn. The buffer is kept by the
+    /// caller, so it's only allocated once.
+    pub fn draw_buffered<F: FnOnce(&mut Self)>(&mut self, buffer: &mut Vec<u8>, height: usize, f: F) {
+        let height = height.min(self.height());
+        let len = (height * self.info.stride * self.info.bytes_per_pixel).min(self.framebuffer.len());
+        buffer.resize(len, 0);
+
+        // The buffer outlives the drawing, after which the framebuffer is put
+        // back.
+        let back = unsafe { &mut *slice_from_raw_parts_mut(buffer.as_mut_ptr(), len) };
+        let front = core::mem::replace(&mut self.framebuffer, back);
+        let full_height = core::mem::replace(&mut self.info.height, height);
+
+        f(self);
+
+        self.info.height = full_height;
+        self.framebuffer = front;
+        crate::arch::string::copy(&mut self.framebuffer[..len], &buffer[..len]);
+    }
+
+    /// The number of bytes the framebuffer can hold, which limits the modes
+    /// it can be reconfigured to.
+    pub fn buffer_len(&self) -> usize {
+        self.framebuffer.len()
+    }
+
+    /// Where the lines of the text start that fit on the screen.
+    fn visible_start(&self, text: &str) -> usize {
+        let columns = self.columns().max(1);
+
+        // The last row is kept free, since the text would clear the screen
+        // when it reaches the bottom.
+        let mut rows = self.rows().saturating_sub(1);
+        let mut start = text.len();
+        for line in text.rsplit('\n') {
+            let needed = visible_length(line).div_ceil(columns).max(1);
+            if needed > rows {
+                break;
+            }
+
+            rows -= needed;
+            start -= line.len();
+            if start == 0 {
+                return 0;
+            }
+
+            // The newline before the line.
+            start -= 1;
+        }
+
+        (start + 1).min(text.len())
+    }
+
     pub fn set_fb(&mut self, fb: &'static FrameBuffer) {
         self.set_buffer(fb.buffer());
         self.info = fb.info();
@@ -214,7 +464,7 @@ impl Writer {
     }
 
     fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + font_constants::LINE_SPACING;
+        self.y_pos += self.font_size.char_height() + font_constants::LINE_SPACING;
         self.carriage_return()
     }
 
@@ -222,26 +472,136 @@ impl Writer {
         self.x_pos = font_constants::BORDER_PADDING;
     }
 
+    /// Clear the text, which leaves the reserved rows at the top alone.
     pub fn clear(&mut self) {
         self.x_pos = font_constants::BORDER_PADDING;
-        self.y_pos = font_constants::BORDER_PADDING;
-        self.framebuffer.fill(0);
+        self.y_pos = font_constants::BORDER_PADDING + self.reserved_top;
+
+        let start = (self.reserved_top * self.info.stride * self.info.bytes_per_pixel).min(self.framebuffer.len());
+        crate::arch::string::fill(&mut self.framebuffer[start..], 0);
     }
 
-    fn width(&self) -> usize {
+    /// Whether a framebuffer was supplied by the bootloader.
+    pub fn is_available(&self) -> bool {
+        !self.framebuffer.is_empty()
+    }
+
+    pub fn width(&self) -> usize {
         self.info.width
     }
 
-    fn height(&self) -> usize {
+    pub fn height(&self) -> usize {
         self.info.height
     }
 
+    /// The width in pixels of the given string when rendered.
+    pub fn text_width(&self, s: &str) -> usize {
+        s.chars().count() * (self.font_size.char_width() + font_constants::LETTER_SPACING)
+    }
+
+    pub fn text_height(&self) -> usize {
+        self.font_size.char_height()
+    }
+
+    /// Change the size of the font, which clears the screen since the
+    /// character grid changes.
+    pub fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        if self.is_available() {
+            self.clear();
+        }
+    }
+
+    /// The number of characters that fit on a line.
+    pub fn columns(&self) -> usize {
+        self.width().saturating_sub(font_constants::BORDER_PADDING * 2)
+            / (self.font_size.char_width() + font_constants::LETTER_SPACING)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.height().saturating_sub(font_constants::BORDER_PADDING * 2 + self.reserved_top)
+            / (self.font_size.char_height() + font_constants::LINE_SPACING)
+    }
+
+    /// Fill a rectangle with a solid color, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        if x >= x_end || y >= y_end {
+            return;
+        }
+
+        // Only the first row is drawn pixel by pixel, the others are copies.
+        self.with_color(color, |this| {
+            for x in x..x_end {
+                this.write_pixel(x, y, 0xFF);
+            }
+        });
+
+        let stride = self.info.stride * self.info.bytes_per_pixel;
+        let start = y * stride + x * self.info.bytes_per_pixel;
+        let len = (x_end - x) * self.info.bytes_per_pixel;
+        for row in y + 1..y_end {
+            let offset = row * stride + x * self.info.bytes_per_pixel;
+            if offset + len > self.framebuffer.len() {
+                break;
+            }
+
+            let (above, below) = self.framebuffer.split_at_mut(offset);
+            crate::arch::string::copy(&mut below[..len], &above[start..start + len]);
+        }
+    }
+
+    /// Draw a string at the given pixel position without touching the cursor.
+    pub fn draw_str_at(&mut self, x: usize, y: usize, s: &str, color: Color) {
+        if y.saturating_add(self.text_height()) > self.height() {
+            return;
+        }
+
+        let (x_pos, y_pos) = (self.x_pos, self.y_pos);
+        self.x_pos = x;
+        self.y_pos = y;
+
+        self.with_color(color, |this| {
+            for c in s.chars() {
+                if this.x_pos.saturating_add(this.font_size.char_width()) > this.width() {
+                    break;
+                }
+                this.write_rendered_char(this.rasterize(c));
+            }
+        });
+
+        self.x_pos = x_pos;
+        self.y_pos = y_pos;
+    }
+
+    fn with_color<F: FnOnce(&mut Self)>(&mut self, color: Color, f: F) {
+        let previous = self.color;
+        self.color = color;
+        f(self);
+        self.color = previous;
+    }
+
     fn write_char(&mut self, c: char) {
         if !self.state.feed(c) {
 
-            if let WriterState::Color(color) = self.state {
-                self.state = WriterState::Normal;
-                self.color = color;
+            match self.state {
+                WriterState::Color(color) => {
+                    self.state = WriterState::Normal;
+                    self.color = color;
+
+                    // ESC[0m resets all attributes.
+                    if color == Color::White {
+                        self.bold = false;
+                    }
+                }
+
+                WriterState::Bold => {
+                    self.state = WriterState::Normal;
+                    self.bold = true;
+                }
+
+                _ => (),
             }
 
             return;
@@ -251,20 +611,25 @@ impl Writer {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
             c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
+                let new_xpos = self.x_pos + self.font_size.char_width();
                 if new_xpos >= self.width() {
                     self.newline();
                 }
                 let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + font_constants::BORDER_PADDING;
+                    self.y_pos + self.font_size.char_height() + font_constants::BORDER_PADDING;
                 if new_ypos >= self.height() {
                     self.clear();
                 }
-                self.write_rendered_char(get_char_raster(c));
+                self.write_rendered_char(self.rasterize(c));
             }
         }
     }
 
+    fn rasterize(&self, c: char) -> RasterizedChar {
+        let weight = if self.bold { FontWeight::Bold } else { FontWeight::Regular };
+        get_char_raster(c, weight, self.font_size.raster_height())
+    }
+
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
         for (y, row) in rendered_char.raster().iter().enumerate() {
             for (x, byte) in row.iter().enumerate() {
@@ -275,23 +640,53 @@ impl Writer {
         self.x_pos += self.last_width + font_constants::LETTER_SPACING;
     }
 
+    /// Erase the previous character, which doesn't go back to the previous
+    /// line.
     pub fn backspace(&mut self) {
-        self.x_pos -= self.last_width;
+        if self.held {
+            self.history.pop_char();
+            return;
+        }
+
+        let x_pos = self.x_pos.saturating_sub(self.last_width).max(font_constants::BORDER_PADDING);
+        if x_pos == self.x_pos {
+            return;
+        }
+
+        self.x_pos = x_pos;
         self.write_char(' ');
-        self.x_pos -= self.last_width;
+        self.x_pos = x_pos;
+        self.history.pop_char();
     }
 
+    /// Write a pixel, ignoring coordinates outside of the screen (or the
+    /// buffer, in case the bootloader reported inconsistent dimensions).
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
         let pixel_offset = y * self.info.stride + x;
         let color = self.get_color(intensity);
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * bytes_per_pixel;
-        self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
-            .copy_from_slice(&color[..bytes_per_pixel]);
-        let _ = unsafe { core::ptr::read_volatile(&self.framebuffer[byte_offset]) };
+        let bytes_per_pixel = self.info.bytes_per_pixel.min(color.len());
+        let byte_offset = pixel_offset * self.info.bytes_per_pixel;
+
+        let Some(pixel) = self.framebuffer.get_mut(byte_offset..byte_offset + bytes_per_pixel) else {
+            return;
+        };
+
+        pixel.copy_from_slice(&color[..bytes_per_pixel]);
+        let _ = unsafe { core::ptr::read_volatile(&pixel[0]) };
     }
 
     fn write_string(&mut self, s: &str) {
+        self.history.push_str(s);
+        if !self.held {
+            self.render(s);
+        }
+    }
+
+    fn render(&mut self, s: &str) {
         for c in s.chars() {
             self.write_char(c);
         }
@@ -311,13 +706,29 @@ impl Writer {
             PixelFormat::Bgr => [color[2], color[1], color[0], color[3]],
             PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
             other => {
+                // Rendering mustn't panic, since we're likely called by the
+                // logger, so just assume RGB.
+                serial_println!("FB: pixel format {other:?} isn't supported, assuming RGB");
                 self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
+                color
             }
         }
     }
 }
 
+/// The number of characters of the line that are drawn, i.e. without the
+/// escape sequences.
+fn visible_length(line: &str) -> usize {
+    let mut state = WriterState::Normal;
+    line.chars().filter(|c| {
+        let visible = state.feed(*c);
+        if matches!(state, WriterState::Color(..) | WriterState::Bold) {
+            state = WriterState::Normal;
+        }
+        visible
+    }).count()
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -327,7 +738,7 @@ impl fmt::Write for Writer {
 
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_text_buffer::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::meta::Console::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
@@ -338,10 +749,35 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    crate::arch::without_interrupts(|| {
+        print_to_framebuffer(args);
+        crate::meta::Console::append_capture(args);
+    });
+}
+
+/// Print to the framebuffer only, without capturing the output (which
+/// allocates). Returns whether the output was printed, i.e. the framebuffer
+/// wasn't busy.
+pub fn print_to_framebuffer(args: fmt::Arguments) -> bool {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    if RENDERING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    let printed = match WRITER.try_lock() {
+        // A formatting error is the fault of a `Display` implementation,
+        // which isn't worth panicking over.
+        Some(mut writer) => {
+            // Whatever holds the screen won't get to release it anymore, so
+            // the output is drawn on top of it.
+            writer.held = false;
+            _ = writer.write_fmt(args);
+            true
+        }
+        None => false,
+    };
+
+    RENDERING.store(false, Ordering::Release);
+    printed
 }
@@ -1,7 +1,18 @@
 // Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
 // All Rights Reserved.
 
-use std::path::PathBuf;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The directory to pack into the initial ramdisk, which the kernel unpacks
+/// into its root file system.
+const INITRD_VARIABLE: &str = "NOCCIOLO_INITRD";
+
+/// The programs of `user/demos`, which are packed into `/bin` of the initial
+/// ramdisk.
+const PROGRAMS: &[&str] = &["echo", "hello"];
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -13,16 +24,90 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_NOCCIOLO_KERNEL_nocciolo-kernel").unwrap());
 
+    println!("cargo:rerun-if-changed=build.rs");
+    let programs: Vec<_> = PROGRAMS.iter()
+        .map(|name| {
+            let variable = format!("CARGO_BIN_FILE_NOCCIOLO_DEMOS_{name}");
+            (*name, PathBuf::from(std::env::var_os(variable).unwrap()))
+        })
+        .collect();
+
+    println!("cargo:rerun-if-env-changed={INITRD_VARIABLE}");
+    let directory = std::env::var_os(INITRD_VARIABLE).map(|directory| {
+        // Relative to the workspace, where `cargo run` is used.
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(directory);
+        println!("cargo:rerun-if-changed={}", directory.display());
+        directory
+    });
+
+    let initrd = out_dir.join("initrd.cpio");
+    write_cpio(&programs, directory.as_deref(), &initrd).unwrap();
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
+    let mut uefi = bootloader::UefiBoot::new(&kernel);
+    uefi.set_ramdisk(initrd.clone());
+    uefi.create_disk_image(&uefi_path).unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path).unwrap();
+    let mut bios = bootloader::BiosBoot::new(&kernel);
+    bios.set_ramdisk(initrd.clone());
+    bios.create_disk_image(&bios_path).unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=KERNEL={}", kernel.display());
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Pack the programs into `bin`, and the directory, if any, with the paths
+/// relative to it, as a cpio archive in the "newc" format, which is what the
+/// kernel understands.
+fn write_cpio(programs: &[(&str, PathBuf)], directory: Option<&Path>, path: &Path) -> std::io::Result<()> {
+    let mut archive = Vec::new();
+    let mut inode = 2;
+    add_entry(&mut archive, "bin", 0o040755, &[], inode);
+    for (name, program) in programs {
+        inode += 1;
+        add_entry(&mut archive, &format!("bin/{name}"), 0o100755, &std::fs::read(program)?, inode);
+    }
+
+    if let Some(directory) = directory {
+        add_directory(&mut archive, directory, "", &mut inode)?;
+    }
+    add_entry(&mut archive, "TRAILER!!!", 0, &[], 0);
+    std::fs::File::create(path)?.write_all(&archive)
+}
+
+fn add_directory(archive: &mut Vec<u8>, directory: &Path, prefix: &str, inode: &mut u32) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        *inode += 1;
+        if file_type.is_dir() {
+            add_entry(archive, &name, 0o040755, &[], *inode);
+            add_directory(archive, &entry.path(), &format!("{name}/"), inode)?;
+        } else if file_type.is_file() {
+            add_entry(archive, &name, 0o100644, &std::fs::read(entry.path())?, *inode);
+        }
+    }
+    Ok(())
+}
+
+fn add_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8], inode: u32) {
+    let fields = [inode, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
@@ -2,7 +2,77 @@
 // All Rights Reserved.
 
 use std::process::Command;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
+use std::time::{Duration, Instant};
+
+/// The socket of the second serial port, on which the kernel's guest agent
+/// listens.
+const AGENT_SOCKET: &str = "target/agent.sock";
+
+/// A set of devices to attach, selected with `--profile <name>`, to exercise
+/// the kernel on different machine configurations.
+struct Profile {
+    name: &'static str,
+    args: &'static [&'static str],
+
+    /// The PCI devices (`vendor:device`) the kernel should find, passed to it
+    /// as the fw_cfg file `expected_devices`.
+    expected_devices: &'static [&'static str],
+}
+
+/// The ext2 disk image made by `mkext2`, which is attached when it exists.
+const EXT2_IMAGE: &str = "target/ext2.img";
+
+/// The disk image of the NVMe drive of the `server` profile.
+const NVME_IMAGE: &str = "target/nvme.img";
+
+/// The disk images of the virtio disks of the `virtio` profile, the first
+/// through the legacy interface and the second through the modern one.
+const VIRTIO_BLK_IMAGES: [&str; 2] = ["target/virtio-blk0.img", "target/virtio-blk1.img"];
+
+/// The images that are made empty when a profile uses them and they don't
+/// exist yet.
+const BLANK_IMAGES: [&str; 3] = [NVME_IMAGE, VIRTIO_BLK_IMAGES[0], VIRTIO_BLK_IMAGES[1]];
+
+const PROFILES: &[Profile] = &[
+    Profile {
+        name: "minimal",
+        args: &["-nic", "none"],
+        expected_devices: &["8086:1237", "8086:7000"],
+    },
+    Profile {
+        name: "desktop",
+        args: &[
+            "-device", "qemu-xhci", "-device", "usb-kbd", "-device", "usb-tablet",
+            "-audiodev", "none,id=audio0", "-device", "intel-hda", "-device", "hda-duplex,audiodev=audio0",
+        ],
+        expected_devices: &["8086:1237", "8086:7000", "8086:100e", "1b36:000d", "8086:2668"],
+    },
+    Profile {
+        name: "server",
+        args: &[
+            "-nic", "user,model=e1000", "-nic", "user,model=e1000",
+            "-drive", "file=target/nvme.img,if=none,id=nvme0,format=raw", "-device", "nvme,serial=nocciolo,drive=nvme0",
+        ],
+        expected_devices: &["8086:1237", "8086:7000", "8086:100e", "8086:100e", "1b36:0010"],
+    },
+    Profile {
+        name: "virtio",
+        args: &[
+            "-device", "virtio-serial-pci",
+            "-chardev", "socket,id=agent,path=target/agent-virtio.sock,server=on,wait=off",
+            "-device", "virtserialport,chardev=agent,name=org.nocciolo.agent",
+            "-chardev", "file,id=report,path=target/report.json",
+            "-device", "virtserialport,chardev=report,name=org.nocciolo.report",
+            "-chardev", "file,id=console,path=target/virtio-console.log",
+            "-device", "virtconsole,chardev=console",
+            "-drive", "file=target/virtio-blk0.img,if=none,id=blk0,format=raw", "-device", "virtio-blk-pci,drive=blk0",
+            "-drive", "file=target/virtio-blk1.img,if=none,id=blk1,format=raw",
+            "-device", "virtio-blk-pci,drive=blk1,disable-legacy=on",
+        ],
+        expected_devices: &["8086:1237", "8086:7000", "8086:100e", "1af4:1003", "1af4:1001", "1af4:1042"],
+    },
+];
 
 fn main() -> Result<(), std::io::Error> {
     let mut cmd;
@@ -24,6 +94,11 @@ fn main() -> Result<(), std::io::Error> {
             let bios_path = env!("BIOS_PATH");
 
             cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
+            attach_config_disk(&mut cmd)?;
+            attach_ext2_disk(&mut cmd);
+            if !add_profile(&mut cmd)? {
+                return Ok(());
+            }
         }
 
         Some("uefi") => {
@@ -32,6 +107,11 @@ fn main() -> Result<(), std::io::Error> {
 
             cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
             cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
+            attach_config_disk(&mut cmd)?;
+            attach_ext2_disk(&mut cmd);
+            if !add_profile(&mut cmd)? {
+                return Ok(());
+            }
         }
 
         Some("info") => {
@@ -74,6 +154,73 @@ fn main() -> Result<(), std::io::Error> {
             cmd.args([dir]);
         }
 
+        Some("mkext2") => {
+            let Some(dir) = std::env::args().nth(2) else {
+                println!("OS> Usage: mkext2 <directory> [size]");
+                return Ok(());
+            };
+
+            if !does_command_exist("mke2fs") {
+                println!("OS> CLI tool `mke2fs` not found, install e2fsprogs");
+                return Ok(());
+            }
+
+            let size = std::env::args().nth(3).unwrap_or_else(|| "8M".into());
+            let status = Command::new("mke2fs")
+                .args(["-q", "-F", "-t", "ext2", "-L", "nocciolo", "-d", &dir, EXT2_IMAGE, &size])
+                .status()?;
+            if status.success() {
+                println!("OS> Created {EXT2_IMAGE} from {dir}, which is mounted at /mnt");
+            }
+            return Ok(());
+        }
+
+        Some("pcap") => {
+            let Some(log) = std::env::args().nth(2) else {
+                println!("OS> Usage: pcap <serial log> [output]");
+                return Ok(());
+            };
+
+            let output = std::env::args().nth(3).unwrap_or_else(|| "target/capture.pcap".into());
+            let count = extract_pcap(&log, &output)?;
+            println!("OS> Extracted {count} pcap records to {output}");
+            return Ok(());
+        }
+
+        Some("core") => {
+            let Some(log) = std::env::args().nth(2) else {
+                println!("OS> Usage: core <serial log> [output]");
+                return Ok(());
+            };
+
+            let output = std::env::args().nth(3).unwrap_or_else(|| "target/core".into());
+            let size = extract_core(&log, &output)?;
+            println!("OS> Extracted a core dump of {size} bytes to {output}");
+            return Ok(());
+        }
+
+        Some("symbolize") => {
+            let Some(log) = std::env::args().nth(2) else {
+                println!("OS> Usage: symbolize <serial log>");
+                return Ok(());
+            };
+
+            symbolize(&log)?;
+            return Ok(());
+        }
+
+        Some("agent") => {
+            match std::env::args().nth(2).as_deref() {
+                Some("wait") => {
+                    let timeout = std::env::args().nth(3).and_then(|s| s.parse().ok()).unwrap_or(60);
+                    wait_for_boot(Duration::from_secs(timeout))?;
+                }
+                Some(command @ ("ping" | "status" | "report" | "help" | "bench" | "latency" | "shutdown")) => println!("OS> {}", query_agent(command)?),
+                _ => println!("OS> Usage: agent <ping|status|report|help|bench|latency|shutdown|wait [seconds]>"),
+            }
+            return Ok(());
+        }
+
         Some("bochs") => {
             let dir = format!("{}/../tools/", env!("CARGO_MANIFEST_DIR"));
             std::env::set_current_dir(dir)?;
@@ -123,14 +270,331 @@ fn create_qemu_cmd() -> Command {
 
     if std::env::args().nth(2) == Some("monitor".into()) {
         cmd.args(["-monitor", "stdio"]);
+        cmd.args(["-serial", "vc"]);
     } else {
         // Attach serial output to stdio
         cmd.args(["-serial", "stdio"]);
     }
 
+    // The second serial port is the guest agent channel, see `cargo run agent`.
+    cmd.args(["-serial", &format!("unix:{AGENT_SOCKET},server=on,wait=off")]);
+
+    add_fw_cfg_files(&mut cmd);
+
     cmd
 }
 
+/// Pass the `--fw-cfg <name>=<value>` options to the kernel as the fw_cfg
+/// file `opt/nocciolo/<name>`. The value is passed as is, or when it starts
+/// with an `@`, it is the path of the file to pass:
+/// ```shell
+/// cargo run uefi --fw-cfg cmdline="nosplash panic=reboot" --fw-cfg fixture=@tests/fixture.bin
+/// ```
+fn add_fw_cfg_files(cmd: &mut Command) {
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("--fw-cfg") {
+            Some("") => args.next(),
+            Some(option) => option.strip_prefix('=').map(String::from),
+            None => continue,
+        };
+
+        let Some((name, value)) = option.as_deref().and_then(|option| option.split_once('=')) else {
+            println!("OS> Invalid fw_cfg option `{arg}`, expected --fw-cfg <name>=<value>");
+            continue;
+        };
+
+        // Commas are the separators of QEMU options, and are escaped by doubling them.
+        let name = format!("opt/nocciolo/{name}").replace(',', ",,");
+        match value.strip_prefix('@') {
+            Some(path) => cmd.args(["-fw_cfg", &format!("name={name},file={}", path.replace(',', ",,"))]),
+            None => cmd.args(["-fw_cfg", &format!("name={name},string={}", value.replace(',', ",,"))]),
+        };
+    }
+}
+
+/// Attach the disk the kernel persists its configuration store to, creating a
+/// blank one on first use, which the `cfgformat` shell command claims.
+fn attach_config_disk(cmd: &mut Command) -> Result<(), std::io::Error> {
+    const SIZE: u64 = 64 * 1024;
+
+    let path = "target/config.img";
+    if !std::path::Path::new(path).exists() {
+        std::fs::File::create(path)?.set_len(SIZE)?;
+    }
+
+    cmd.arg("-drive").arg(format!("format=raw,file={path},index=1"));
+    Ok(())
+}
+
+/// Attach the ext2 disk made by `mkext2` as the third ATA disk, if it exists.
+fn attach_ext2_disk(cmd: &mut Command) {
+    if std::path::Path::new(EXT2_IMAGE).exists() {
+        cmd.arg("-drive").arg(format!("format=raw,file={EXT2_IMAGE},index=2"));
+    }
+}
+
+/// Attach the devices of the profile selected with `--profile <name>`, if any.
+/// Returns `false` when the profile doesn't exist.
+fn add_profile(cmd: &mut Command) -> Result<bool, std::io::Error> {
+    let mut args = std::env::args().skip(2);
+    let Some(name) = args.find(|arg| arg == "--profile").and_then(|_| args.next()) else {
+        return Ok(true);
+    };
+
+    let Some(profile) = PROFILES.iter().find(|profile| profile.name == name) else {
+        let names: Vec<_> = PROFILES.iter().map(|profile| profile.name).collect();
+        println!("OS> Unknown profile `{name}`, expected one of: {}", names.join(", "));
+        return Ok(false);
+    };
+
+    for image in BLANK_IMAGES {
+        if profile.args.iter().any(|arg| arg.contains(image)) && !std::path::Path::new(image).exists() {
+            std::fs::File::create(image)?.set_len(16 * 1024 * 1024)?;
+        }
+    }
+
+    cmd.args(profile.args);
+    cmd.args(["-fw_cfg", &format!("name=opt/nocciolo/expected_devices,string={}", profile.expected_devices.join(",,"))]);
+    Ok(true)
+}
+
+/// Extract the packet capture the kernel streamed over the serial port (with
+/// `pcap=serial`) from a log of the serial output, e.g. from
+/// `cargo run uefi | tee target/serial.log`. Every `@pcap <hex>` line is a
+/// part of the pcap file, the first being the file header.
+fn extract_pcap(log: &str, output: &str) -> Result<usize, std::io::Error> {
+    const MARKER: &str = "@pcap ";
+
+    let log = std::fs::read_to_string(log)?;
+    let mut data = Vec::new();
+    let mut count = 0;
+
+    for line in log.lines() {
+        // The marker might be preceded by other output on the same line.
+        let Some(index) = line.find(MARKER) else {
+            continue;
+        };
+
+        let hex = line[index + MARKER.len()..].trim();
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect();
+
+        match bytes {
+            Some(bytes) => {
+                // A new file header is written for every stream or dump, so
+                // only keep the last capture.
+                if bytes.starts_with(&0xA1B2_C3D4u32.to_le_bytes()) {
+                    data.clear();
+                    count = 0;
+                } else {
+                    count += 1;
+                }
+                data.extend_from_slice(&bytes);
+            }
+            None => println!("OS> Skipping malformed pcap line: {line}"),
+        }
+    }
+
+    std::fs::write(output, data)?;
+    Ok(count)
+}
+
+/// Extract the last core dump the kernel wrote, for a process that faulted or
+/// with the `coredump` shell command, from a log of the serial output. Every `@core <hex>` line is a
+/// part of the ELF file.
+fn extract_core(log: &str, output: &str) -> Result<usize, std::io::Error> {
+    const MARKER: &str = "@core ";
+
+    let log = std::fs::read_to_string(log)?;
+    let mut data = Vec::new();
+
+    for line in log.lines() {
+        let Some(index) = line.find(MARKER) else {
+            continue;
+        };
+
+        let hex = line[index + MARKER.len()..].trim();
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect();
+
+        match bytes {
+            Some(bytes) => {
+                // Every dump starts with the ELF header.
+                if bytes.starts_with(b"\x7fELF") {
+                    data.clear();
+                }
+                data.extend_from_slice(&bytes);
+            }
+            None => println!("OS> Skipping malformed core line: {line}"),
+        }
+    }
+
+    std::fs::write(output, &data)?;
+    Ok(data.len())
+}
+
+/// The line the kernel logs with the difference between its runtime
+/// addresses and those in the ELF file.
+const LOAD_BIAS_MARKER: &str = "Kernel load bias ";
+
+/// Precedes the build id in the boot banner of the log, see `meta::version`.
+const BUILD_ID_MARKER: &str = "build-id ";
+
+/// Precedes the build id in the kernel ELF.
+const ELF_BUILD_ID_MARKER: &[u8] = b"NOCCIOLO build-id ";
+
+/// Print a log of the serial output with the kernel addresses in it (e.g. of
+/// a panic or fault report) annotated with their function and source line,
+/// using `addr2line` and the debug info of the kernel ELF. This also works
+/// when the kernel couldn't resolve them itself, e.g. before its symbols were
+/// loaded.
+fn symbolize(log: &str) -> Result<(), std::io::Error> {
+    let Some(tool) = ["llvm-addr2line", "addr2line"].into_iter().find(|tool| does_command_exist(tool)) else {
+        println!("OS> CLI tool `addr2line` not found, install LLVM or GNU binutils");
+        return Ok(());
+    };
+
+    let log = std::fs::read_to_string(log)?;
+
+    // Resolving the addresses of another build gives plausible nonsense.
+    let elf_build_id = elf_build_id(&std::fs::read(env!("KERNEL"))?);
+    for line in log.lines() {
+        let Some(build_id) = log_build_id(line) else {
+            continue;
+        };
+
+        if elf_build_id.as_deref() != Some(build_id) {
+            println!("OS> The log is of build {build_id}, but the kernel ELF is build {}, rebuild that version first",
+                elf_build_id.as_deref().unwrap_or("unknown"));
+            return Ok(());
+        }
+    }
+
+    // The bias of the last boot in the log applies to the addresses after it.
+    let mut bias = 0;
+    let mut addresses = Vec::new();
+    for line in log.lines() {
+        if let Some(index) = line.find(LOAD_BIAS_MARKER) {
+            bias = parse_address(line[index + LOAD_BIAS_MARKER.len()..].trim()).unwrap_or(0);
+            continue;
+        }
+
+        for word in address_words(line) {
+            if let Some(address) = parse_address(word).and_then(|address| address.checked_sub(bias)) {
+                addresses.push(address);
+            }
+        }
+    }
+
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    let output = Command::new(tool)
+        .args(["--functions", "--demangle", "-e", env!("KERNEL")])
+        .args(addresses.iter().map(|address| format!("{address:#x}")))
+        .output()?;
+
+    // Every address gets two lines: the function, and the file and line.
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut locations = std::collections::HashMap::new();
+    for (address, pair) in addresses.iter().zip(output.lines().collect::<Vec<_>>().chunks(2)) {
+        if let [function, location] = pair {
+            if *function != "??" {
+                locations.insert(*address, format!("{function} at {location}"));
+            }
+        }
+    }
+
+    bias = 0;
+    for line in log.lines() {
+        if let Some(index) = line.find(LOAD_BIAS_MARKER) {
+            bias = parse_address(line[index + LOAD_BIAS_MARKER.len()..].trim()).unwrap_or(0);
+        }
+
+        let resolved: Vec<_> = address_words(line)
+            .filter_map(|word| locations.get(&parse_address(word)?.checked_sub(bias)?))
+            .collect();
+
+        println!("{line}");
+        for location in resolved {
+            println!("    -> {location}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The build id in the kernel ELF, which is embedded as a marker string.
+fn elf_build_id(elf: &[u8]) -> Option<String> {
+    let start = elf.windows(ELF_BUILD_ID_MARKER.len()).position(|window| window == ELF_BUILD_ID_MARKER)?
+        + ELF_BUILD_ID_MARKER.len();
+    let end = elf[start..].iter().position(|byte| *byte == 0)? + start;
+    String::from_utf8(elf[start..end].to_vec()).ok()
+}
+
+/// The build id in a boot banner (or panic message) of the log.
+fn log_build_id(line: &str) -> Option<&str> {
+    let start = line.find(BUILD_ID_MARKER)? + BUILD_ID_MARKER.len();
+    let id = &line[start..];
+    let end = id.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(id.len());
+    Some(&id[..end]).filter(|id| !id.is_empty())
+}
+
+/// The words of the line that look like addresses, e.g. `0xffff800000012345`.
+fn address_words(line: &str) -> impl Iterator<Item = &str> {
+    line.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| word.starts_with("0x") && word.len() > 2 + 4)
+}
+
+fn parse_address(word: &str) -> Option<u64> {
+    u64::from_str_radix(word.strip_prefix("0x")?, 16).ok()
+}
+
+/// Send a command to the guest agent of the running kernel, returning the
+/// response.
+fn query_agent(command: &str) -> Result<String, std::io::Error> {
+    let mut stream = std::os::unix::net::UnixStream::connect(AGENT_SOCKET)?;
+    // The benchmarks take a while.
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    writeln!(stream, "{command}")?;
+
+    let mut lines = BufReader::new(stream).lines();
+    loop {
+        let Some(line) = lines.next().transpose()? else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+
+        // The announcement of the agent when it starts isn't a response.
+        if line != "booted" {
+            return Ok(line);
+        }
+    }
+}
+
+/// Wait until the kernel finished booting, i.e. its guest agent answers.
+fn wait_for_boot(timeout: Duration) -> Result<(), std::io::Error> {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        if let Ok(response) = query_agent("ping") {
+            if response == "pong" {
+                println!("OS> Kernel booted after {:.1}s", start.elapsed().as_secs_f32());
+                return Ok(());
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(500));
+    }
+
+    println!("OS> Kernel didn't finish booting within {}s", timeout.as_secs());
+    Err(std::io::ErrorKind::TimedOut.into())
+}
+
 fn create_lldb_command() -> Result<Command, std::io::Error> {
     let mut cmd = Command::new("lldb");
 
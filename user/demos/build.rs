@@ -0,0 +1,16 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Links the demos as static executables in the user region of the kernel,
+//! which doesn't relocate them. The debug info is left out, so they fit in a
+//! file of the ramfs the initrd is unpacked into.
+
+/// The start of the user region, `USER_REGION` in `kernel/src/arch/x86_64/memory.rs`.
+const IMAGE_BASE: u64 = 0x4000_0000_0000;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-link-arg-bins=--no-pie");
+    println!("cargo:rustc-link-arg-bins=--image-base={IMAGE_BASE:#x}");
+    println!("cargo:rustc-link-arg-bins=--strip-debug");
+}
@@ -0,0 +1,43 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Prints the keys typed at the console back, until Ctrl+D.
+
+#![no_std]
+#![no_main]
+
+use nocciolo_runtime::{entry, println, syscall};
+
+entry!(main);
+
+/// What Ctrl+D reads as.
+const END_OF_TRANSMISSION: u8 = 0x04;
+
+fn main() {
+    println!("Type something, Ctrl+D to stop.");
+
+    let mut buffer = [0; 64];
+    loop {
+        let count = match syscall::read(&mut buffer) {
+            Ok(count) => count,
+            Err(e) => {
+                println!("Failed to read: {e:?}");
+                syscall::exit(1);
+            }
+        };
+
+        let input = &buffer[..count];
+        let end = input.iter().position(|&byte| byte == END_OF_TRANSMISSION);
+        let text = &input[..end.unwrap_or(count)];
+
+        // The other control characters, e.g. backspace, aren't printable.
+        for chunk in text.split(|&byte| byte.is_ascii_control() && byte != b'\n') {
+            _ = syscall::write_all(chunk);
+        }
+
+        if end.is_some() {
+            println!();
+            return;
+        }
+    }
+}
@@ -0,0 +1,15 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! Greets the console, and exits.
+
+#![no_std]
+#![no_main]
+
+use nocciolo_runtime::{entry, println, syscall};
+
+entry!(main);
+
+fn main() {
+    println!("Hello, world! It's {} ms since boot.", syscall::time().as_millis());
+}
@@ -0,0 +1,100 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The runtime of the programs that run as processes of the kernel: the
+//! start code, the [system calls](syscall), [`print!`] and [`println!`], and
+//! a panic handler that prints the message and exits with status 101.
+//!
+//! A program is a `#![no_std]`, `#![no_main]` binary for
+//! `x86_64-unknown-none`, which names its main function with [`entry!`]:
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! nocciolo_runtime::entry!(main);
+//!
+//! fn main() {
+//!     nocciolo_runtime::println!("Hello, world!");
+//! }
+//! ```
+//!
+//! The kernel only loads static executables within the user region, so the
+//! program must be linked with `--no-pie` and an image base in it, see the
+//! build script of the demos.
+
+#![no_std]
+
+pub mod syscall;
+
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+
+/// The status a program that panicked exits with.
+pub const PANIC_STATUS: u64 = 101;
+
+// The kernel enters with the stack pointer 16-byte aligned, while a function
+// expects it to be 8 bytes off, after the return address.
+global_asm!(r#"
+.global _start
+_start:
+    xor rbp, rbp
+    and rsp, -16
+    call {start}
+    ud2
+"#, start = sym start);
+
+extern "C" fn start() -> ! {
+    extern "Rust" {
+        fn __nocciolo_main();
+    }
+
+    unsafe { __nocciolo_main() };
+    syscall::exit(0)
+}
+
+/// Name the function the program starts with, which exits with status zero
+/// when it returns.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "__nocciolo_main"]
+        fn __nocciolo_main() {
+            let main: fn() = $main;
+            main()
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// The console, written with the `write` system call.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        syscall::write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    _ = Console.write_fmt(args);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{info}");
+    syscall::exit(PANIC_STATUS)
+}
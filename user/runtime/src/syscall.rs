@@ -0,0 +1,106 @@
+// Copyright (C) 2024 Tristan Gerritsen <tristan@thewoosh.org>
+// All Rights Reserved.
+
+//! The system calls of the kernel, with the numbers and the error codes of
+//! `kernel/src/syscall.rs`.
+
+use core::{arch::asm, time::Duration};
+
+const EXIT: u64 = 0;
+const WRITE: u64 = 1;
+const SLEEP: u64 = 2;
+const TIME: u64 = 3;
+const READ: u64 = 4;
+
+/// The errors of the system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The kernel doesn't have the call.
+    UnknownCall,
+
+    /// A buffer isn't mapped in the user region, or not writable.
+    BadAddress,
+
+    /// An argument is out of range.
+    InvalidArgument,
+
+    /// An error code this runtime doesn't know.
+    Other(i64),
+}
+
+impl Error {
+    fn from_code(code: i64) -> Self {
+        match code {
+            1 => Self::UnknownCall,
+            2 => Self::BadAddress,
+            3 => Self::InvalidArgument,
+            code => Self::Other(code),
+        }
+    }
+}
+
+/// End the process with the status.
+pub fn exit(status: u64) -> ! {
+    unsafe { syscall(EXIT, status, 0) };
+    unreachable!("the exit system call returned")
+}
+
+/// Print the bytes to the console, as UTF-8, returning how many were printed,
+/// which can be fewer than given.
+pub fn write(bytes: &[u8]) -> Result<usize, Error> {
+    result(unsafe { syscall(WRITE, bytes.as_ptr() as u64, bytes.len() as u64) }).map(|count| count as usize)
+}
+
+/// Print all of the bytes to the console.
+pub fn write_all(mut bytes: &[u8]) -> Result<(), Error> {
+    while !bytes.is_empty() {
+        let count = write(bytes)?;
+        bytes = &bytes[count..];
+    }
+    Ok(())
+}
+
+/// Wait for the keys typed at the console, returning the number of bytes of
+/// UTF-8 put in the buffer. Return is a line feed, and Ctrl with a letter its
+/// control character, e.g. 4 for Ctrl+D.
+pub fn read(buffer: &mut [u8]) -> Result<usize, Error> {
+    result(unsafe { syscall(READ, buffer.as_mut_ptr() as u64, buffer.len() as u64) }).map(|count| count as usize)
+}
+
+/// Let the other threads run for at least the duration, of at most a day.
+pub fn sleep(duration: Duration) -> Result<(), Error> {
+    result(unsafe { syscall(SLEEP, duration.as_millis() as u64, 0) }).map(|_| ())
+}
+
+/// The time since the kernel booted, in milliseconds.
+pub fn time() -> Duration {
+    Duration::from_millis(unsafe { syscall(TIME, 0, 0) } as u64)
+}
+
+fn result(value: i64) -> Result<i64, Error> {
+    if value < 0 {
+        Err(Error::from_code(-value))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Make the call, which overwrites RCX and R11, and clears the argument
+/// registers and R9.
+unsafe fn syscall(number: u64, first: u64, second: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        inlateout("rdi") first => _,
+        inlateout("rsi") second => _,
+        lateout("rdx") _,
+        lateout("r10") _,
+        lateout("r8") _,
+        lateout("r9") _,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}